@@ -0,0 +1,288 @@
+/*
+This module contains WebAssembly instruction sequences for the two primitives on values of any type:
+- AnyEq, AnyNeq
+
+Neither of them allocates any memory.
+*/
+
+use super::string_prim_inst;
+use ir::VarType;
+use wasmgen::ExprBuilder;
+use wasmgen::Scratch;
+use wasmgen::ValType;
+
+// net wasm stack [data_1(i64), tag_1(i32), data_2(i64), tag_2(i32)] -> [ret(i32)]
+pub fn encode_any_eq(scratch: &mut Scratch, expr_builder: &mut ExprBuilder) {
+    // Algorithm:
+    /*
+    if tag_1 == tag_2 {
+        if tag_1 == Number {
+            return f64(data_1) == f64(data_2);
+        } else if tag_1 == String {
+            return string_eq(i32(data_1), i32(data_2));
+        } else if tag_1 == Func {
+            return data_1 == data_2; // the table index and the closure
+        } else {
+            // Unassigned, Undefined and Null have no data, and the high bytes of the other data are not used
+            return i32(data_1) == i32(data_2) || tag_1 == Unassigned || tag_1 == Undefined || tag_1 == Null;
+        }
+    } else {
+        return false;
+    }
+    */
+
+    let data_1 = scratch.push_i64();
+    let tag_1 = scratch.push_i32();
+    let data_2 = scratch.push_i64();
+    let tag_2 = scratch.push_i32();
+
+    // net wasm stack: [data_1(i64), tag_1(i32), data_2(i64), tag_2(i32)] -> []
+    expr_builder.local_set(tag_2);
+    expr_builder.local_set(data_2);
+    expr_builder.local_set(tag_1);
+    expr_builder.local_set(data_1);
+
+    // net wasm stack: [] -> [ret(i32)]
+    expr_builder.local_get(tag_1);
+    expr_builder.local_get(tag_2);
+    expr_builder.i32_eq();
+    expr_builder.if_(&[ValType::I32]);
+    {
+        expr_builder.local_get(tag_1);
+        expr_builder.i32_const(VarType::Number.tag());
+        expr_builder.i32_eq();
+        expr_builder.if_(&[ValType::I32]);
+        {
+            expr_builder.local_get(data_1);
+            expr_builder.f64_reinterpret_i64();
+            expr_builder.local_get(data_2);
+            expr_builder.f64_reinterpret_i64();
+            expr_builder.f64_eq();
+        }
+        expr_builder.else_();
+        {
+            expr_builder.local_get(tag_1);
+            expr_builder.i32_const(VarType::String.tag());
+            expr_builder.i32_eq();
+            expr_builder.if_(&[ValType::I32]);
+            {
+                expr_builder.local_get(data_1);
+                expr_builder.i32_wrap_i64();
+                expr_builder.local_get(data_2);
+                expr_builder.i32_wrap_i64();
+                string_prim_inst::encode_string_eq(scratch, expr_builder);
+            }
+            expr_builder.else_();
+            {
+                expr_builder.local_get(tag_1);
+                expr_builder.i32_const(VarType::Func.tag());
+                expr_builder.i32_eq();
+                expr_builder.if_(&[ValType::I32]);
+                {
+                    expr_builder.local_get(data_1);
+                    expr_builder.local_get(data_2);
+                    expr_builder.i64_eq();
+                }
+                expr_builder.else_();
+                {
+                    expr_builder.local_get(data_1);
+                    expr_builder.i32_wrap_i64();
+                    expr_builder.local_get(data_2);
+                    expr_builder.i32_wrap_i64();
+                    expr_builder.i32_eq();
+                    expr_builder.local_get(tag_1);
+                    expr_builder.i32_const(VarType::Unassigned.tag());
+                    expr_builder.i32_eq();
+                    expr_builder.i32_or();
+                    expr_builder.local_get(tag_1);
+                    expr_builder.i32_const(VarType::Undefined.tag());
+                    expr_builder.i32_eq();
+                    expr_builder.i32_or();
+                    expr_builder.local_get(tag_1);
+                    expr_builder.i32_const(VarType::Null.tag());
+                    expr_builder.i32_eq();
+                    expr_builder.i32_or();
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+    }
+    expr_builder.else_();
+    {
+        expr_builder.i32_const(0);
+    }
+    expr_builder.end();
+
+    scratch.pop_i32();
+    scratch.pop_i64();
+    scratch.pop_i32();
+    scratch.pop_i64();
+}
+
+// net wasm stack [data_1(i64), tag_1(i32), data_2(i64), tag_2(i32)] -> [ret(i32)]
+pub fn encode_any_ne(scratch: &mut Scratch, expr_builder: &mut ExprBuilder) {
+    encode_any_eq(scratch, expr_builder);
+    expr_builder.i32_eqz();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_runner::*;
+    use super::super::Options;
+
+    // local#0 and local#1 are distinct structs, local#2 and local#3 are distinct arrays,
+    // and local#0.0:0 holds local#2, so that it can also be read back from memory
+    fn compare_program(prim_inst: &str, lhs: &str, rhs: &str) -> String {
+        format!(
+            r#"
+            struct#0 = (any);
+            func#0 (any) -> any {{
+              (var:any local#0)
+            }}
+            func#1 (struct#0) -> any {{
+              (var:any local#0.0:0)
+            }}
+            func#2 () -> any {{
+              (decl:any struct#0 (struct:struct#0 struct#0)
+                (decl:any struct#0 (struct:struct#0 struct#0)
+                  (decl:any array (prim:array array_new)
+                    (decl:any array (prim:array array_new)
+                      (seq:any
+                        (assign:undefined local#0.0:0 (var:array local#2))
+                        (assign:undefined local#1.0:0 (var:array local#3))
+                        (prim:boolean {} (call:any func#0 {}) (call:any func#0 {})))))))
+            }}
+            entry func#2;
+            "#,
+            prim_inst, lhs, rhs
+        )
+    }
+
+    fn any_eq(lhs: &str, rhs: &str) -> Result<Value, Failure> {
+        run_ir_all_levels(&compare_program("any_eq", lhs, rhs), Options::default())
+    }
+
+    #[test]
+    fn primitives_compared_by_value() {
+        let cases = [
+            ("(number:number 1.5)", "(number:number 1.5)", true),
+            ("(number:number 1.5)", "(number:number 2.5)", false),
+            ("(boolean:boolean true)", "(boolean:boolean true)", true),
+            ("(boolean:boolean true)", "(boolean:boolean false)", false),
+            (
+                "(string:string \"ab\")",
+                "(prim:string string_add (string:string \"a\") (string:string \"b\"))",
+                true,
+            ),
+            ("(string:string \"ab\")", "(string:string \"ba\")", false),
+            ("(undefined:undefined)", "(undefined:undefined)", true),
+            ("(null:null)", "(null:null)", true),
+        ];
+        for (lhs, rhs, expected) in cases.iter() {
+            assert_eq!(
+                any_eq(lhs, rhs),
+                Ok(Value::Boolean(*expected)),
+                "{} {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn different_types_never_equal() {
+        let cases = [
+            ("(number:number 1.0)", "(string:string \"1\")"),
+            ("(number:number 0.0)", "(boolean:boolean false)"),
+            ("(undefined:undefined)", "(null:null)"),
+            ("(null:null)", "(var:array local#2)"),
+            ("(var:struct#0 local#0)", "(var:array local#2)"),
+        ];
+        for (lhs, rhs) in cases.iter() {
+            assert_eq!(
+                any_eq(lhs, rhs),
+                Ok(Value::Boolean(false)),
+                "{} {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn objects_compared_by_identity() {
+        let cases = [
+            ("(var:struct#0 local#0)", "(var:struct#0 local#0)", true),
+            ("(var:struct#0 local#0)", "(var:struct#0 local#1)", false),
+            ("(var:array local#2)", "(var:array local#2)", true),
+            ("(var:array local#2)", "(var:array local#3)", false),
+            // read back from memory
+            ("(var:any local#0.0:0)", "(var:array local#2)", true),
+            ("(var:any local#0.0:0)", "(var:any local#1.0:0)", false),
+        ];
+        for (lhs, rhs, expected) in cases.iter() {
+            assert_eq!(
+                any_eq(lhs, rhs),
+                Ok(Value::Boolean(*expected)),
+                "{} {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn funcs_compared_by_target_and_closure() {
+        let cases = [
+            (
+                "(func:func [func#0] (undefined:undefined))",
+                "(func:func [func#0] (undefined:undefined))",
+                true,
+            ),
+            (
+                "(func:func [func#0] (undefined:undefined))",
+                "(func:func [func#2] (undefined:undefined))",
+                false,
+            ),
+            (
+                "(func:func [func#1:closure] (var:struct#0 local#0))",
+                "(func:func [func#1:closure] (var:struct#0 local#0))",
+                true,
+            ),
+            (
+                "(func:func [func#1:closure] (var:struct#0 local#0))",
+                "(func:func [func#1:closure] (var:struct#0 local#1))",
+                false,
+            ),
+        ];
+        for (lhs, rhs, expected) in cases.iter() {
+            assert_eq!(
+                any_eq(lhs, rhs),
+                Ok(Value::Boolean(*expected)),
+                "{} {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn any_neq_is_negation() {
+        let text = compare_program(
+            "any_neq",
+            "(var:struct#0 local#0)",
+            "(var:struct#0 local#0)",
+        );
+        assert_eq!(
+            run_ir_all_levels(&text, Options::default()),
+            Ok(Value::Boolean(false))
+        );
+        let text = compare_program("any_neq", "(var:array local#2)", "(var:array local#3)");
+        assert_eq!(
+            run_ir_all_levels(&text, Options::default()),
+            Ok(Value::Boolean(true))
+        );
+    }
+}
@@ -0,0 +1,657 @@
+/*
+This module contains WebAssembly instruction sequences for the four array primitives:
+- ArrayNew, ArrayLength, ArrayGet, ArraySet
+
+An array is a fixed-size header that points to a separately allocated buffer of elements,
+so that the array can grow without changing its identity:
+- header: [len(i32), buf(i32)]
+- buffer: [capacity(i32), element(Any) * capacity]
+`buf` is -1 if no buffer has been allocated yet (i.e. the capacity is zero).
+Elements at or beyond `len` are always Undefined, so that holes read as undefined (as in js-slang).
+(This holds because `len` never decreases, and newly allocated buffers are filled with Undefined.)
+
+ArrayNew and ArraySet will allocate memory.
+ArrayLength and ArrayGet do not allocate any memory.
+*/
+
+use super::gc::HeapManager;
+use super::mutcontext::MutContext;
+use super::var_conv::*;
+use ir::VarType;
+use wasmgen::ExprBuilder;
+use wasmgen::FuncIdx;
+use wasmgen::LocalIdx;
+use wasmgen::MemArg;
+use wasmgen::MemIdx;
+
+// Writing to an index at or above this will trap.
+// It ensures that the byte size of a buffer always fits in an i32.
+const MAX_ARRAY_LENGTH: u32 = 1 << 26;
+
+// The smallest capacity of a newly allocated buffer.
+const MIN_ARRAY_CAPACITY: u32 = 4;
+
+// Creates a new empty array.
+// net wasm stack [] -> [arr(i32)]
+pub fn encode_array_new<H: HeapManager>(
+    heap: &H,
+    mutctx: &mut MutContext,
+    expr_builder: &mut ExprBuilder,
+) {
+    // the heap manager will write an empty header (len = 0, buf = -1)
+    mutctx.heap_encode_fixed_allocation(heap, VarType::Array, expr_builder);
+}
+
+// net wasm stack [arr(i32)] -> [len(f64)]
+pub fn encode_array_length(expr_builder: &mut ExprBuilder) {
+    expr_builder.i32_load(MemArg::new4(0));
+    expr_builder.f64_convert_i32_u();
+}
+
+// Checks if `idx` is an integer in the range [0, bound).  (NaN is rejected too, since all comparisons with NaN are false.)
+// `encode_bound` should have net wasm stack [] -> [bound(f64)].
+// net wasm stack [] -> [cond(i32)]
+fn encode_is_valid_index<F: FnOnce(&mut ExprBuilder)>(
+    idx: LocalIdx,
+    encode_bound: F,
+    expr_builder: &mut ExprBuilder,
+) {
+    // idx >= 0
+    expr_builder.local_get(idx);
+    expr_builder.f64_const(0.0);
+    expr_builder.f64_ge();
+
+    // idx < bound
+    expr_builder.local_get(idx);
+    encode_bound(expr_builder);
+    expr_builder.f64_lt();
+    expr_builder.i32_and();
+
+    // trunc(idx) == idx
+    expr_builder.local_get(idx);
+    expr_builder.f64_trunc();
+    expr_builder.local_get(idx);
+    expr_builder.f64_eq();
+    expr_builder.i32_and();
+}
+
+// Reads an element from the array.
+// Returns undefined if the index is not an integer in [0, len), as in js-slang.
+// net wasm stack [arr(i32), idx(f64)] -> [data(i64), tag(i32)]
+pub fn encode_array_get(mutctx: &mut MutContext, expr_builder: &mut ExprBuilder) {
+    // Algorithm:
+    /*
+    if (is_valid_index(idx, arr->len)) {
+        return *(arr->buf + 4 + i32(idx) * sizeof(Any));
+    } else {
+        return undefined;
+    }
+    */
+
+    let any_size = size_in_memory(VarType::Any) as i32;
+
+    mutctx.with_scratch_i32(|mutctx, arr| {
+        mutctx.with_scratch_f64(|mutctx, idx| {
+            mutctx.with_scratch_i32(|mutctx, tag| {
+                mutctx.with_scratch_i64(|_mutctx, data| {
+                    // net wasm stack: [arr(i32), idx(f64)] -> []
+                    expr_builder.local_set(idx);
+                    expr_builder.local_set(arr);
+
+                    // net wasm stack: [] -> [cond(i32)]
+                    encode_is_valid_index(
+                        idx,
+                        |expr_builder| {
+                            expr_builder.local_get(arr);
+                            expr_builder.i32_load(MemArg::new4(0));
+                            expr_builder.f64_convert_i32_u();
+                        },
+                        expr_builder,
+                    );
+
+                    // net wasm stack: [cond(i32)] -> []
+                    expr_builder.if_(&[]);
+                    {
+                        // let ptr = arr->buf + i32(idx) * sizeof(Any);
+                        // (we reuse the `arr` local for `ptr`)
+                        expr_builder.local_get(arr);
+                        expr_builder.i32_load(MemArg::new4(4));
+                        expr_builder.local_get(idx);
+                        expr_builder.i32_trunc_f64_u();
+                        expr_builder.i32_const(any_size);
+                        expr_builder.i32_mul();
+                        expr_builder.i32_add();
+                        expr_builder.local_tee(arr);
+
+                        // note: elements start at offset 4 of the buffer, after the capacity
+                        expr_builder.i32_load(MemArg::new4(4));
+                        expr_builder.local_set(tag);
+                        expr_builder.local_get(arr);
+                        expr_builder.i64_load(MemArg::new4(8));
+                        expr_builder.local_set(data);
+                    }
+                    expr_builder.else_();
+                    {
+                        expr_builder.i32_const(VarType::Undefined.tag());
+                        expr_builder.local_set(tag);
+                        expr_builder.i64_const(0);
+                        expr_builder.local_set(data);
+                    }
+                    expr_builder.end();
+
+                    // net wasm stack: [] -> [data(i64), tag(i32)]
+                    expr_builder.local_get(data);
+                    expr_builder.local_get(tag);
+                });
+            });
+        });
+    });
+}
+
+// Writes an element to the array, growing the array if necessary.
+// Any holes created are filled with undefined.
+// Traps if the index is not an integer in [0, MAX_ARRAY_LENGTH).
+// net wasm stack [arr(i32), idx(f64), data(i64), tag(i32)] -> []
+pub fn encode_array_set<H: HeapManager>(
+    memidx: MemIdx,
+    heap: &H,
+    error_func: FuncIdx,
+    use_wasm_bulk_memory_feature: bool,
+    mutctx: &mut MutContext,
+    expr_builder: &mut ExprBuilder,
+) {
+    // Algorithm:
+    /*
+    if (!is_valid_index(idx, MAX_ARRAY_LENGTH)) trap(ERROR_CODE_ARRAY_INDEX);
+    let i = i32(idx);
+    if (i >= arr->len) {
+        let capacity = arr->buf == -1 ? 0 : *(arr->buf);
+        if (i >= capacity) {
+            let new_capacity = max(i + 1, capacity * 2, MIN_ARRAY_CAPACITY);
+            let new_buf = new_array_buffer(new_capacity * sizeof(Any)); // capacity will already be written; `arr` and `val` are gc roots
+            let it = new_buf + 4;
+            let it_mid = it + arr->len * sizeof(Any);
+            let it_end = it + new_capacity * sizeof(Any);
+            memcpy(it, arr->buf + 4, arr->len * sizeof(Any)); // note: if arr->len == 0, then nothing is copied (even though arr->buf might be -1)
+            it = it_mid;
+            do {
+                it->tag = Undefined;
+                it += sizeof(Any);
+            } while (it != it_end); // note: new_capacity > i >= arr->len, so this loop runs at least once
+            arr->buf = new_buf;
+            write_barrier(arr);
+        }
+        arr->len = i + 1;
+    }
+    *(arr->buf + 4 + i * sizeof(Any)) = val;
+    write_barrier(arr->buf);
+    */
+
+    let any_size = size_in_memory(VarType::Any) as i32;
+
+    mutctx.with_uninitialized_shadow_local(VarType::Array, |mutctx, arr_local| {
+        mutctx.with_uninitialized_shadow_local(VarType::Any, |mutctx, val_local| {
+            mutctx.with_scratch_f64(|mutctx, idx| {
+                mutctx.with_scratch_i32(|mutctx, i| {
+                    let arr: LocalIdx = mutctx.wasm_local_slice(arr_local)[0];
+
+                    // net wasm stack: [arr(i32), idx(f64), data(i64), tag(i32)] -> []
+                    encode_store_local(
+                        mutctx.wasm_local_slice(val_local),
+                        VarType::Any,
+                        VarType::Any,
+                        expr_builder,
+                    );
+                    expr_builder.local_set(idx);
+                    expr_builder.local_set(arr);
+
+                    // if (!is_valid_index(idx, MAX_ARRAY_LENGTH)) trap(ERROR_CODE_ARRAY_INDEX);
+                    // net wasm stack: [] -> []
+                    encode_is_valid_index(
+                        idx,
+                        |expr_builder| expr_builder.f64_const(MAX_ARRAY_LENGTH as f64),
+                        expr_builder,
+                    );
+                    expr_builder.i32_eqz();
+                    expr_builder.if_(&[]);
+                    {
+                        expr_builder.i32_const(ir::error::ERROR_CODE_ARRAY_INDEX as i32);
+                        expr_builder.i32_const(0);
+                        expr_builder.i32_const(0);
+                        expr_builder.i32_const(0);
+                        expr_builder.i32_const(0);
+                        expr_builder.i32_const(0);
+                        expr_builder.i32_const(0);
+                        expr_builder.call(error_func);
+                        expr_builder.unreachable();
+                    }
+                    expr_builder.end();
+
+                    // let i = i32(idx);
+                    // net wasm stack: [] -> [i(i32)]
+                    expr_builder.local_get(idx);
+                    expr_builder.i32_trunc_f64_u();
+                    expr_builder.local_tee(i);
+
+                    // if (i >= arr->len)
+                    // net wasm stack: [i(i32)] -> []
+                    expr_builder.local_get(arr);
+                    expr_builder.i32_load(MemArg::new4(0));
+                    expr_builder.i32_ge_u();
+                    expr_builder.if_(&[]);
+                    {
+                        mutctx.with_scratch_i32(|mutctx, capacity| {
+                            // let capacity = arr->buf == -1 ? 0 : *(arr->buf);
+                            // net wasm stack: [] -> []
+                            expr_builder.local_get(arr);
+                            expr_builder.i32_load(MemArg::new4(4));
+                            expr_builder.local_tee(capacity);
+                            expr_builder.i32_const(-1);
+                            expr_builder.i32_eq();
+                            expr_builder.if_(&[wasmgen::ValType::I32]);
+                            expr_builder.i32_const(0);
+                            expr_builder.else_();
+                            expr_builder.local_get(capacity);
+                            expr_builder.i32_load(MemArg::new4(0));
+                            expr_builder.end();
+                            expr_builder.local_tee(capacity);
+
+                            // if (i >= capacity)
+                            // net wasm stack: [capacity(i32)] -> []
+                            expr_builder.local_get(i);
+                            expr_builder.i32_le_u();
+                            expr_builder.if_(&[]);
+                            {
+                                encode_grow_buffer(
+                                    memidx,
+                                    heap,
+                                    use_wasm_bulk_memory_feature,
+                                    arr,
+                                    i,
+                                    capacity,
+                                    mutctx,
+                                    expr_builder,
+                                );
+                            }
+                            expr_builder.end();
+                        });
+
+                        // arr->len = i + 1;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(arr);
+                        expr_builder.local_get(i);
+                        expr_builder.i32_const(1);
+                        expr_builder.i32_add();
+                        expr_builder.i32_store(MemArg::new4(0));
+                    }
+                    expr_builder.end();
+
+                    // *(arr->buf + 4 + i * sizeof(Any)) = val;
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(arr);
+                    expr_builder.i32_load(MemArg::new4(4));
+                    expr_builder.local_get(i);
+                    expr_builder.i32_const(any_size);
+                    expr_builder.i32_mul();
+                    expr_builder.i32_add();
+                    encode_load_local(
+                        mutctx.wasm_local_slice(val_local),
+                        VarType::Any,
+                        VarType::Any,
+                        expr_builder,
+                    );
+                    encode_store_memory(
+                        4,
+                        VarType::Any,
+                        VarType::Any,
+                        mutctx.scratch_mut(),
+                        expr_builder,
+                    );
+
+                    // write_barrier(arr->buf);
+                    // net wasm stack: [] -> []
+                    heap.encode_write_barrier(
+                        VarType::Any,
+                        |expr_builder| {
+                            expr_builder.local_get(arr);
+                            expr_builder.i32_load(MemArg::new4(4));
+                        },
+                        mutctx.scratch_mut(),
+                        expr_builder,
+                    );
+                });
+            });
+        });
+    });
+}
+
+// Replaces the buffer of `arr` with a larger one that can fit index `i`.
+// `arr` must be a shadow local so that the GC can update it.
+// net wasm stack [] -> []
+fn encode_grow_buffer<H: HeapManager>(
+    memidx: MemIdx,
+    heap: &H,
+    use_wasm_bulk_memory_feature: bool,
+    arr: LocalIdx,
+    i: LocalIdx,
+    capacity: LocalIdx,
+    mutctx: &mut MutContext,
+    expr_builder: &mut ExprBuilder,
+) {
+    let any_size = size_in_memory(VarType::Any) as i32;
+
+    mutctx.with_scratch_i32(|mutctx, new_capacity| {
+        mutctx.with_scratch_i32(|mutctx, new_buf| {
+            mutctx.with_scratch_i32(|mutctx, it| {
+                mutctx.with_scratch_i32(|mutctx, it_mid| {
+                    mutctx.with_scratch_i32(|mutctx, it_end| {
+                        // let new_capacity = max(i + 1, capacity * 2, MIN_ARRAY_CAPACITY);
+                        // net wasm stack: [] -> []
+                        {
+                            expr_builder.local_get(capacity);
+                            expr_builder.i32_const(1);
+                            expr_builder.i32_shl();
+                            expr_builder.local_tee(new_capacity);
+                            expr_builder.i32_const(MIN_ARRAY_CAPACITY as i32);
+                            expr_builder.local_get(new_capacity);
+                            expr_builder.i32_const(MIN_ARRAY_CAPACITY as i32);
+                            expr_builder.i32_gt_u();
+                            expr_builder.select();
+                            expr_builder.local_tee(new_capacity);
+                            expr_builder.local_get(i);
+                            expr_builder.i32_const(1);
+                            expr_builder.i32_add();
+                            expr_builder.local_get(new_capacity);
+                            expr_builder.local_get(i);
+                            expr_builder.i32_gt_u();
+                            expr_builder.select();
+                            expr_builder.local_set(new_capacity);
+                        }
+
+                        // let new_buf = new_array_buffer(new_capacity * sizeof(Any));
+                        // let it = new_buf + 4;
+                        // net wasm stack: [] -> [new_buf(i32)]
+                        expr_builder.local_get(new_capacity);
+                        expr_builder.i32_const(any_size);
+                        expr_builder.i32_mul();
+                        mutctx.heap_encode_dynamic_allocation(heap, VarType::Array, expr_builder);
+                        expr_builder.local_tee(new_buf);
+                        expr_builder.i32_const(4);
+                        expr_builder.i32_add();
+                        expr_builder.local_set(it);
+
+                        // let it_mid = it + arr->len * sizeof(Any);
+                        // note: `arr` must be reloaded after allocating, since the GC might have moved it (mutctx has already done that for us)
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(it);
+                        expr_builder.local_get(arr);
+                        expr_builder.i32_load(MemArg::new4(0));
+                        expr_builder.i32_const(any_size);
+                        expr_builder.i32_mul();
+                        expr_builder.i32_add();
+                        expr_builder.local_set(it_mid);
+
+                        // let it_end = it + new_capacity * sizeof(Any);
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(it);
+                        expr_builder.local_get(new_capacity);
+                        expr_builder.i32_const(any_size);
+                        expr_builder.i32_mul();
+                        expr_builder.i32_add();
+                        expr_builder.local_set(it_end);
+
+                        // memcpy(it, arr->buf + 4, arr->len * sizeof(Any));
+                        // it = it_mid;
+                        // net wasm stack: [] -> []
+                        if use_wasm_bulk_memory_feature {
+                            expr_builder.local_get(it);
+                            expr_builder.local_get(arr);
+                            expr_builder.i32_load(MemArg::new4(4));
+                            expr_builder.i32_const(4);
+                            expr_builder.i32_add();
+                            expr_builder.local_get(it_mid);
+                            expr_builder.local_get(it);
+                            expr_builder.i32_sub();
+                            expr_builder.memory_copy(memidx, memidx);
+                            expr_builder.local_get(it_mid);
+                            expr_builder.local_set(it);
+                        } else {
+                            mutctx.with_scratch_i32(|_mutctx, src| {
+                                expr_builder.local_get(arr);
+                                expr_builder.i32_load(MemArg::new4(4));
+                                expr_builder.i32_const(4);
+                                expr_builder.i32_add();
+                                expr_builder.local_set(src);
+
+                                // while it != it_mid {
+                                //     *it = *src;
+                                //     it += sizeof(Any);
+                                //     src += sizeof(Any);
+                                // }
+                                expr_builder.local_get(it);
+                                expr_builder.local_get(it_mid);
+                                expr_builder.i32_ne();
+                                expr_builder.if_(&[]);
+                                {
+                                    expr_builder.loop_(&[]);
+                                    {
+                                        expr_builder.local_get(it);
+                                        expr_builder.local_get(src);
+                                        expr_builder.i32_load(MemArg::new4(0));
+                                        expr_builder.i32_store(MemArg::new4(0));
+                                        expr_builder.local_get(it);
+                                        expr_builder.local_get(src);
+                                        expr_builder.i64_load(MemArg::new4(4));
+                                        expr_builder.i64_store(MemArg::new4(4));
+                                        expr_builder.local_get(src);
+                                        expr_builder.i32_const(any_size);
+                                        expr_builder.i32_add();
+                                        expr_builder.local_set(src);
+                                        expr_builder.local_get(it);
+                                        expr_builder.i32_const(any_size);
+                                        expr_builder.i32_add();
+                                        expr_builder.local_tee(it);
+                                        expr_builder.local_get(it_mid);
+                                        expr_builder.i32_ne();
+                                        expr_builder.br_if(0);
+                                    }
+                                    expr_builder.end();
+                                }
+                                expr_builder.end();
+                            });
+                        }
+
+                        // do {
+                        //     it->tag = Undefined;
+                        //     it += sizeof(Any);
+                        // } while (it != it_end);
+                        // net wasm stack: [] -> []
+                        expr_builder.loop_(&[]);
+                        {
+                            expr_builder.local_get(it);
+                            expr_builder.i32_const(VarType::Undefined.tag());
+                            expr_builder.i32_store(MemArg::new4(0));
+                            expr_builder.local_get(it);
+                            expr_builder.i32_const(any_size);
+                            expr_builder.i32_add();
+                            expr_builder.local_tee(it);
+                            expr_builder.local_get(it_end);
+                            expr_builder.i32_ne();
+                            expr_builder.br_if(0);
+                        }
+                        expr_builder.end();
+
+                        // arr->buf = new_buf;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(arr);
+                        expr_builder.local_get(new_buf);
+                        expr_builder.i32_store(MemArg::new4(4));
+
+                        // write_barrier(arr);
+                        // net wasm stack: [] -> []
+                        heap.encode_write_barrier(
+                            VarType::Array,
+                            |expr_builder| expr_builder.local_get(arr),
+                            mutctx.scratch_mut(),
+                            expr_builder,
+                        );
+                    });
+                });
+            });
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_runner::*;
+    use super::super::{HeapManagerKind, Options};
+
+    // const xs = []; for (let i = 0; i < 100; i = i + 1) { xs[i] = i * 10; } <result>
+    // where `result` can use xs as local#0
+    fn filled_array_program(result: &str) -> String {
+        format!(
+            r#"
+            func#0 (array number number) -> undefined {{
+              (if:undefined (prim:boolean number_lt (var:number local#1) (var:number local#2))
+                (seq:undefined
+                  (prim:undefined array_set (var:array local#0) (var:number local#1)
+                    (prim:number number_mul (var:number local#1) (number:number 10.0)))
+                  (call:undefined func#0 (var:array local#0)
+                    (prim:number number_add (var:number local#1) (number:number 1.0)) (var:number local#2)))
+                (undefined:undefined))
+            }}
+            func#1 () -> any {{
+              (decl:any array (prim:array array_new)
+                (seq:any
+                  (call:undefined func#0 (var:array local#0) (number:number 0.0) (number:number 100.0))
+                  {}))
+            }}
+            entry func#1;
+            "#,
+            result
+        )
+    }
+
+    #[test]
+    fn array_grows_when_written_in_order() {
+        let text =
+            filled_array_program("(prim:any array_get (var:array local#0) (number:number 57.0))");
+        assert_eq!(
+            run_ir_all_levels(&text, Options::default()),
+            Ok(Value::Number(570.0))
+        );
+        let text = filled_array_program("(prim:number array_length (var:array local#0))");
+        assert_eq!(
+            run_ir_all_levels(&text, Options::default()),
+            Ok(Value::Number(100.0))
+        );
+    }
+
+    #[test]
+    fn array_grows_past_twice_its_capacity() {
+        // xs[1000] = 5; the elements in between are holes
+        let write = "(prim:undefined array_set (var:array local#0) (number:number 1000.0) (number:number 5.0))";
+        let text = filled_array_program(&format!(
+            "(seq:any {} (prim:number array_length (var:array local#0)))",
+            write
+        ));
+        assert_eq!(
+            run_ir_all_levels(&text, Options::default()),
+            Ok(Value::Number(1001.0))
+        );
+        let text = filled_array_program(&format!(
+            "(seq:any {} (prim:any array_get (var:array local#0) (number:number 1000.0)))",
+            write
+        ));
+        assert_eq!(
+            run_ir_all_levels(&text, Options::default()),
+            Ok(Value::Number(5.0))
+        );
+        let text = filled_array_program(&format!(
+            "(seq:any {} (prim:any array_get (var:array local#0) (number:number 500.0)))",
+            write
+        ));
+        assert_eq!(
+            run_ir_all_levels(&text, Options::default()),
+            Ok(Value::Undefined)
+        );
+    }
+
+    #[test]
+    fn array_read_out_of_bounds() {
+        for index in &["100.0", "-1.0", "2.5", "1e300"] {
+            let text = filled_array_program(&format!(
+                "(prim:any array_get (var:array local#0) (number:number {}))",
+                index
+            ));
+            assert_eq!(
+                run_ir_all_levels(&text, Options::default()),
+                Ok(Value::Undefined),
+                "at index {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn array_write_out_of_bounds() {
+        for index in &["-1.0", "2.5", "67108864.0"] {
+            let text = filled_array_program(&format!(
+                "(seq:any (prim:undefined array_set (var:array local#0) (number:number {}) (null:null)) (undefined:undefined))",
+                index
+            ));
+            assert_eq!(
+                run_ir_all_levels(&text, Options::default()),
+                Err(Failure::Error(ir::error::ERROR_CODE_ARRAY_INDEX)),
+                "at index {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn array_elements_survive_collections() {
+        // const xs = [""]; for (let i = 1; i <= 3000; i = i + 1) { xs[i] = xs[i - 1] + "x"; } xs[3000];
+        // (every element is a string on the heap, and the buffer is reallocated as the array grows)
+        let text = r#"
+            func#0 () -> any {
+              (decl:any array (prim:array array_new)
+                (decl:any number (number:number 1.0)
+                  (seq:any
+                    (prim:undefined array_set (var:array local#0) (number:number 0.0) (string:string ""))
+                    (loop:undefined
+                      (if:undefined (prim:boolean number_le (var:number local#1) (number:number 3000.0))
+                        (seq:!
+                          (typecast:undefined string narrow
+                            (prim:any array_get (var:array local#0) (prim:number number_sub (var:number local#1) (number:number 1.0)))
+                            (prim:undefined array_set (var:array local#0) (var:number local#1)
+                              (prim:string string_add (var:string local#2) (string:string "x")))
+                            (trap:! 0x13 0:1:1-1:2))
+                          (assign:undefined local#1 (prim:number number_add (var:number local#1) (number:number 1.0)))
+                          (break:! 0 (undefined:undefined)))
+                        (undefined:undefined)))
+                    (prim:any array_get (var:array local#0) (number:number 3000.0)))))
+            }
+            entry func#0;
+        "#;
+        for heap_manager in &[
+            HeapManagerKind::Cheney,
+            HeapManagerKind::Generational,
+            HeapManagerKind::MarkSweep,
+        ] {
+            let options = Options::new_builder()
+                .with_heap_manager(*heap_manager)
+                .build()
+                .unwrap();
+            let (result, stats) = run_ir_with_stats(text, "-O2", options);
+            assert_eq!(
+                result,
+                Ok(Value::String("x".repeat(3000))),
+                "with {:?}",
+                heap_manager
+            );
+            assert!(stats.collections > 0, "with {:?}", heap_manager);
+        }
+    }
+}
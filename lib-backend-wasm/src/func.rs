@@ -6,10 +6,13 @@ use super::gc::HeapManager;
  */
 use wasmgen::Scratch;
 
+use crate::any_prim_inst;
+use crate::array_prim_inst;
 use crate::global_var::*;
 use crate::multi_value_polyfill;
 use crate::pre_traverse::ShiftedStringPool;
 use crate::string_prim_inst;
+use crate::tail_call::Trampoline;
 use crate::Options;
 
 use super::opt_var_conv::*;
@@ -21,14 +24,22 @@ use boolinator::*;
 
 use std::collections::HashMap;
 
-struct EncodeContext<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, Heap: HeapManager> {
+struct EncodeContext<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, 'j, 'k, Heap: HeapManager> {
     // Local to this function
     return_type: Option<ir::VarType>,
+    allow_tail_calls: bool, // false for functions called directly by the embedder (i.e. the entry point), since they cannot handle pending tail calls
+    liveness: Option<&'j ir::liveness::Liveness<'j>>, // liveness of locals at each call (None if not encoding an ir function, in which case all locals are assumed to be live)
+    stack_frame: Option<&'j StackFrame>, // closure environments that this function allocates on the stack (None if there are none)
+
+    // Local to this expression
+    tail_position: bool, // whether the value of this expr is returned directly from the function (so an Appl here can be a tail call)
 
     // Global for whole program
     struct_types: &'a [Box<[ir::VarType]>],
     struct_field_byte_offsets: &'b [Box<[u32]>], // has same sizes as `struct_types`, but instead stores the byte offset of each field from the beginning of the struct
     ir_signature_list: &'c [Signature], // mapping from ir::FuncIdx, for callers to check the param type or return type
+    may_allocate: &'k ir::may_allocate::MayAllocate, // which funcs might allocate memory, so callers know whether they need to save the gc roots
+    escape: &'k ir::escape::Escape<'k>, // which closure environments are allocated on the stack, and which tail calls must be made as ordinary calls
     wasm_funcidxs: &'d [wasmgen::FuncIdx], // mapping from ir::FuncIdx to wasmgen::FuncIdx (used when we need to invoke a DirectAppl), this includes imports too
 
     // Global var management (does not include special globals like the stackptr)
@@ -43,21 +54,65 @@ struct EncodeContext<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, Heap: HeapManager> {
     string_pool: &'i ShiftedStringPool,
     error_func: wasmgen::FuncIdx, // imported function to call to error out (e.g. runtime type errors)
     options: Options,             // Compilation options (it implements Copy)
+    trampoline: Option<Trampoline>, // for tail calls, if the wasm tail call proposal is not enabled
 }
 
 // Have to implement Copy and Clone manually, because #[derive(Copy, Clone)] doesn't work for generic types like Heap
-impl<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, Heap: HeapManager> Copy
-    for EncodeContext<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, Heap>
+impl<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, 'j, 'k, Heap: HeapManager> Copy
+    for EncodeContext<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, 'j, 'k, Heap>
 {
 }
-impl<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, Heap: HeapManager> Clone
-    for EncodeContext<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, Heap>
+impl<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, 'j, 'k, Heap: HeapManager> Clone
+    for EncodeContext<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, 'i, 'j, 'k, Heap>
 {
     fn clone(&self) -> Self {
         *self
     }
 }
 
+/**
+ * The part of the stack that a function reserves when it is called, to hold the closure environments that don't escape.
+ * The stackptr is moved down by `size` bytes at the start of the function, and moved back up when the function returns or makes a tail call,
+ * so it stays at the bottom of the frame while the function body runs.
+ */
+struct StackFrame {
+    size: u32,                                        // in bytes
+    envs: Box<[(*const ir::Expr, ir::VarType, u32)]>, // the Declaration, type and byte offset (from the stackptr) of each environment
+}
+
+impl StackFrame {
+    fn new<H: HeapManager>(envs: &[&ir::Expr], heap: &H) -> Option<StackFrame> {
+        if envs.is_empty() {
+            return None;
+        }
+        let mut size: u32 = 0;
+        let envs = envs
+            .iter()
+            .map(|env| match &env.kind {
+                ir::ExprKind::Declaration {
+                    local,
+                    init: _,
+                    contained_expr: _,
+                } => {
+                    let offset = size;
+                    size += heap.stack_allocation_size(*local);
+                    (*env as *const ir::Expr, *local, offset)
+                }
+                _ => panic!("ICE: IR->Wasm: closure environment must be a Declaration"),
+            })
+            .collect();
+        Some(StackFrame { size, envs })
+    }
+
+    // Returns the byte offset (from the stackptr) of the environment declared by the given Declaration, if it is on this stack frame.
+    fn offset(&self, expr: &ir::Expr) -> Option<u32> {
+        self.envs
+            .iter()
+            .find(|(env, _, _)| std::ptr::eq(*env, expr))
+            .map(|(_, _, offset)| *offset)
+    }
+}
+
 /**
  * Wraps the WasmModule, to only allow operations that are allows while encoding a function body.
  * (Because a raw WasmModule might allow a lot of other things which shouldn't be done midway encoding the function body.)
@@ -98,6 +153,8 @@ pub struct Signature {
 
 pub fn encode_funcs<'a, Heap: HeapManager>(
     ir_signature_list: &[Signature], // direct mapping from ir::FuncIdx: includes both imports and funcs
+    may_allocate: &ir::may_allocate::MayAllocate,
+    escape: &ir::escape::Escape,
     ir_funcs: &[ir::Func],
     ir_struct_types: &[Box<[ir::VarType]>],
     ir_struct_field_byte_offsets: &[Box<[u32]>],
@@ -123,6 +180,28 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
 
     let (thunk_list, thunk_map) = thunk_sv.into_parts();
 
+    // the trampoline is used to make tail calls without growing the wasm stack
+    let trampoline: Option<Trampoline> = (!options.wasm_tail_call).as_some_from(|| {
+        // the results of a thunk are `encode_result(Some(ir::VarType::Any))`, but the trampoline needs them to be static
+        let thunk_results: &'static [wasmgen::ValType] = if options.wasm_multi_value {
+            &[wasmgen::ValType::I64, wasmgen::ValType::I32]
+        } else {
+            &[]
+        };
+        debug_assert!(
+            *thunk_results == *encode_result(Some(ir::VarType::Any), options.wasm_multi_value)
+        );
+        let thunk_typeidx = wasm_module.insert_type_into(wasmgen::FuncType::new(
+            Box::new([
+                wasmgen::ValType::I32,
+                wasmgen::ValType::I32,
+                wasmgen::ValType::I32,
+            ]),
+            thunk_results.into(),
+        ));
+        Trampoline::new(thunk_typeidx, thunk_results, wasm_module)
+    });
+
     // reserve space for the indirect function table
     let tableidx = wasm_module.get_or_add_table();
     let thunk_table_offset = wasm_module.reserve_table_elements(tableidx, thunk_list.len() as u32);
@@ -160,6 +239,7 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
         .copied()
         .chain(registry_list.iter().map(|x| x.funcidx))
         .collect();
+    let num_imports: usize = wasm_funcidxs.len() - registry_list.len();
 
     let new_thunk_map: HashMap<Box<[ir::OverloadEntry]>, u32> = thunk_map
         .into_iter()
@@ -185,9 +265,15 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
                 let scratch: Scratch = Scratch::new(locals_builder);
                 let ctx = EncodeContext {
                     return_type: Some(ir::VarType::Any),
+                    allow_tail_calls: false,
+                    liveness: None,
+                    stack_frame: None,
+                    tail_position: false,
                     struct_types: ir_struct_types,
                     struct_field_byte_offsets: ir_struct_field_byte_offsets,
                     ir_signature_list: ir_signature_list,
+                    may_allocate,
+                    escape,
                     wasm_funcidxs: &wasm_funcidxs,
                     globals: global_var_manager,
                     stackptr: globalidx_stackptr,
@@ -198,6 +284,7 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
                     string_pool: string_pool,
                     error_func: error_func,
                     options: options,
+                    trampoline,
                 };
                 let mut mutctx = MutContext::new(
                     scratch,
@@ -227,14 +314,24 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
         .enumerate()
         .for_each(|(ir_funcidx, (ir_func, mut code_builder))| {
             let registry: &WasmRegistry = &registry_list[ir_funcidx];
+            // the embedder calls the entry point directly, so it cannot make tail calls
+            let allow_tail_calls = num_imports + ir_funcidx != ir_entry_point_funcidx;
             {
                 let (locals_builder, expr_builder) = code_builder.split();
                 let scratch: Scratch = Scratch::new(locals_builder);
+                let liveness = ir::liveness::analyze_func(ir_func);
+                let stack_frame = StackFrame::new(escape.envs(num_imports + ir_funcidx), heap);
                 let ctx = EncodeContext {
                     return_type: ir_func.result,
+                    allow_tail_calls,
+                    liveness: Some(&liveness),
+                    stack_frame: stack_frame.as_ref(),
+                    tail_position: allow_tail_calls && ir_func.result == Some(ir::VarType::Any),
                     struct_types: ir_struct_types,
                     struct_field_byte_offsets: ir_struct_field_byte_offsets,
                     ir_signature_list: ir_signature_list,
+                    may_allocate,
+                    escape,
                     wasm_funcidxs: &wasm_funcidxs,
                     globals: global_var_manager,
                     stackptr: globalidx_stackptr,
@@ -245,6 +342,7 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
                     string_pool: string_pool,
                     error_func: error_func,
                     options: options,
+                    trampoline,
                 };
                 let mut mutctx = MutContext::new(
                     scratch,
@@ -253,10 +351,13 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
                     &registry.param_types,
                     ModuleEncodeWrapper { wasm_module },
                 );
+                encode_reserve_stack_frame(ctx, &mut mutctx, expr_builder);
                 let wasm_reachable = encode_expr(&ir_func.expr, ctx, &mut mutctx, expr_builder);
 
-                if let Some(vartype) = ir_func.expr.vartype {
-                    assert!(wasm_reachable);
+                if !wasm_reachable {
+                    // the function body ended with a tail call (or wasm already knows that this point is unreachable)
+                } else if let Some(vartype) = ir_func.expr.vartype {
+                    encode_release_stack_frame(ctx, expr_builder);
                     encode_return_calling_conv(
                         ir_func.result.unwrap(),
                         vartype,
@@ -265,10 +366,9 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
                         mutctx.scratch_mut(),
                         expr_builder,
                     );
-                } else if wasm_reachable {
+                } else {
                     expr_builder.unreachable();
                 }
-                // if !wasm_reachable then wasm knows that this point is unreachable, so we don't need to emit the `unreachable` instruction
 
                 // append the end instruction to end of the function
                 expr_builder.end();
@@ -280,7 +380,40 @@ pub fn encode_funcs<'a, Heap: HeapManager>(
     // encode the entry point
     // Note: this is not the wasm start function (the wasm start function is invoked immediately on instantiation, before exported functions are callable)
     // By our convention this function is exported as "main"
-    wasm_module.export_func(wasm_funcidxs[ir_entry_point_funcidx], "main".to_string());
+    // The host reads the result from the unprotected stack, so if the entry point returns it on the wasm stack instead (because of multi-value),
+    // then we export a wrapper that moves it there.
+    let entry_result: ir::VarType = ir_signature_list[ir_entry_point_funcidx].result.unwrap();
+    let main_funcidx: wasmgen::FuncIdx =
+        if options.wasm_multi_value && encode_vartype(entry_result).len() > 1 {
+            // [] -> []
+            let wasm_functype = wasmgen::FuncType::new(Box::new([]), Box::new([]));
+            let (_, main_funcidx) = wasm_module.register_func(&wasm_functype);
+            let mut code_builder = wasmgen::CodeBuilder::new(wasm_functype);
+            {
+                let (locals_builder, expr_builder) = code_builder.split();
+                let mut scratch: Scratch = Scratch::new(locals_builder);
+
+                // net wasm stack: [] -> [entry_result]
+                expr_builder.call(wasm_funcidxs[ir_entry_point_funcidx]);
+
+                // net wasm stack: [entry_result] -> []
+                encode_return_calling_conv(
+                    entry_result,
+                    entry_result,
+                    false,
+                    globalidx_stackptr,
+                    &mut scratch,
+                    expr_builder,
+                );
+
+                expr_builder.end();
+            }
+            wasm_module.commit_func(main_funcidx, code_builder);
+            main_funcidx
+        } else {
+            wasm_funcidxs[ir_entry_point_funcidx]
+        };
+    wasm_module.export_func(main_funcidx, "main".to_string());
 }
 
 // returns (wasm_param_valtypes, wasm_param_map, param_map)
@@ -321,9 +454,10 @@ fn encode_result(
     ir_results: Option<ir::VarType>,
     use_wasm_multi_value_feature: bool,
 ) -> Box<[wasmgen::ValType]> {
+    // reversed, since encode_vartype() starts from the top of the wasm stack (like encode_param_list())
     let ret: Box<[wasmgen::ValType]> = ir_results
         .into_iter()
-        .flat_map(|ir_result| encode_vartype(ir_result).iter())
+        .flat_map(|ir_result| encode_vartype(ir_result).iter().rev())
         .copied()
         .collect();
     if ret.len() <= 1 || use_wasm_multi_value_feature {
@@ -354,18 +488,23 @@ fn encode_drop_value(ir_vartype: ir::VarType, expr_builder: &mut wasmgen::ExprBu
             expr_builder.drop();
             expr_builder.drop();
         }
-        ir::VarType::Number | ir::VarType::Boolean | ir::VarType::String => expr_builder.drop(),
+        ir::VarType::Number | ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
+            expr_builder.drop()
+        }
         ir::VarType::StructT { typeidx: _ } => expr_builder.drop(),
-        ir::VarType::Undefined => {}
+        ir::VarType::Undefined | ir::VarType::Null => {}
         ir::VarType::Unassigned => panic!("Unassigned variable must not exist on the stack"),
     }
 }
 
 // together, pre+expr+post fns should have net wasm stack: [] -> []
 // the pre and post fns should be read and understood together
+// `struct_localidx` is the shadow local (of the vartype returned by target_struct_vartype()) that the pre fn saves the address of the target struct into, if the target is in a struct.
+// (It has to be a local known to the GC, because `expr` might allocate memory and move the struct.)
 fn encode_target_addr_pre<H: HeapManager>(
     target: &ir::TargetExpr,
     _incoming_vartype: ir::VarType,
+    struct_localidx: usize,
     ctx: EncodeContext<H>,
     mutctx: &mut MutContext,
     expr_builder: &mut wasmgen::ExprBuilder,
@@ -400,10 +539,10 @@ fn encode_target_addr_pre<H: HeapManager>(
                 None => {}
                 Some(struct_field) => {
                     // we are actually writing to linear memory
-                    // so we place the address of the target struct on the stack
+                    // so we save the address of the target struct in the shadow local
                     // (note: the address is to the struct, not the variable within the struct,
                     // the post fn encodes the offset using the memarg immediate)
-                    // net wasm stack: [] -> [ptr]
+                    // net wasm stack: [] -> []
 
                     // For Source1, structs can only arise from closures and local variables, so their types must already be known at compilation time.
                     assert!(
@@ -417,6 +556,9 @@ fn encode_target_addr_pre<H: HeapManager>(
                     expr_builder.global_get(ctx.globals.wasm_global_slice(*globalidx)[0]);
 
                     follow_nested_struct(struct_field, ctx, expr_builder);
+
+                    // net wasm stack: [struct_ptr] -> []
+                    expr_builder.local_set(mutctx.wasm_local_slice(struct_localidx)[0]);
                 }
             }
         }
@@ -425,10 +567,10 @@ fn encode_target_addr_pre<H: HeapManager>(
                 None => {}
                 Some(struct_field) => {
                     // we are actually writing to linear memory
-                    // so we place the address of the target struct on the stack
+                    // so we save the address of the target struct in the shadow local
                     // (note: the address is to the struct, not the variable within the struct,
                     // the post fn encodes the offset using the memarg immediate)
-                    // net wasm stack: [] -> [ptr]
+                    // net wasm stack: [] -> []
 
                     // For Source1, structs can only arise from closures and local variables, so their types must already be known at compilation time.
                     assert!(
@@ -442,6 +584,9 @@ fn encode_target_addr_pre<H: HeapManager>(
                     expr_builder.local_get(mutctx.named_wasm_local_slice(*localidx)[0]);
 
                     follow_nested_struct(struct_field, ctx, expr_builder);
+
+                    // net wasm stack: [struct_ptr] -> []
+                    expr_builder.local_set(mutctx.wasm_local_slice(struct_localidx)[0]);
                 }
             }
         }
@@ -450,6 +595,7 @@ fn encode_target_addr_pre<H: HeapManager>(
 fn encode_target_addr_post<H: HeapManager>(
     target: &ir::TargetExpr,
     incoming_vartype: ir::VarType,
+    struct_localidx: usize,
     ctx: EncodeContext<H>,
     mutctx: &mut MutContext,
     expr_builder: &mut wasmgen::ExprBuilder,
@@ -469,6 +615,69 @@ fn encode_target_addr_post<H: HeapManager>(
         }
     }
 
+    // writes the incoming value to the target struct saved by the pre fn, and then encodes the write barrier
+    // net wasm stack: [<incoming_vartype>] -> []
+    fn encode_store_struct_field<H: HeapManager>(
+        struct_field: &ir::StructField,
+        incoming_vartype: ir::VarType,
+        struct_localidx: usize,
+        ctx: EncodeContext<H>,
+        mutctx: &mut MutContext,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        let (offset, ir_dest_vartype) = get_innermost_offset(struct_field, ctx);
+        let wasm_struct_localidx: wasmgen::LocalIdx = mutctx.wasm_local_slice(struct_localidx)[0];
+        let scratch: &mut Scratch = mutctx.scratch_mut();
+
+        // We need to retrieve the incoming value(s) into locals so that we can put the pointer under it on the stack.
+
+        // temporary storage for incoming value
+        let incoming_val: Box<[wasmgen::LocalIdx]> = encode_vartype(incoming_vartype)
+            .iter()
+            .copied()
+            .map(|valtype| scratch.push(valtype))
+            .collect();
+
+        // net wasm stack: [<incoming_vartype>] -> [struct_ptr, <incoming_vartype>]
+        encode_store_local(
+            &incoming_val,
+            incoming_vartype,
+            incoming_vartype,
+            expr_builder,
+        );
+        expr_builder.local_get(wasm_struct_localidx);
+        encode_load_local(
+            &incoming_val,
+            incoming_vartype,
+            incoming_vartype,
+            expr_builder,
+        );
+
+        // delete temporary storage for incoming value... (backwards because it is a stack)
+        encode_vartype(incoming_vartype)
+            .iter()
+            .copied()
+            .rev()
+            .for_each(|valtype| scratch.pop(valtype));
+
+        // net wasm stack: [struct_ptr, <incoming_vartype>] -> []
+        encode_store_memory(
+            offset,
+            ir_dest_vartype,
+            incoming_vartype,
+            scratch,
+            expr_builder,
+        );
+
+        // net wasm stack: [] -> []
+        ctx.heap.encode_write_barrier(
+            incoming_vartype,
+            |expr_builder| expr_builder.local_get(wasm_struct_localidx),
+            scratch,
+            expr_builder,
+        );
+    }
+
     match target {
         ir::TargetExpr::Global { globalidx, next } => {
             match next {
@@ -481,14 +690,13 @@ fn encode_target_addr_post<H: HeapManager>(
                     );
                 }
                 Some(struct_field) => {
-                    // net wasm stack: [ptr, <incoming_vartype>] -> []
-
-                    let (offset, ir_dest_vartype) = get_innermost_offset(struct_field, ctx);
-                    encode_store_memory(
-                        offset,
-                        ir_dest_vartype,
+                    // net wasm stack: [<incoming_vartype>] -> []
+                    encode_store_struct_field(
+                        struct_field,
                         incoming_vartype,
-                        mutctx.scratch_mut(),
+                        struct_localidx,
+                        ctx,
+                        mutctx,
                         expr_builder,
                     );
                 }
@@ -505,14 +713,13 @@ fn encode_target_addr_post<H: HeapManager>(
                     );
                 }
                 Some(struct_field) => {
-                    // net wasm stack: [ptr, <incoming_vartype>] -> []
-
-                    let (offset, ir_dest_vartype) = get_innermost_offset(struct_field, ctx);
-                    encode_store_memory(
-                        offset,
-                        ir_dest_vartype,
+                    // net wasm stack: [<incoming_vartype>] -> []
+                    encode_store_struct_field(
+                        struct_field,
                         incoming_vartype,
-                        mutctx.scratch_mut(),
+                        struct_localidx,
+                        ctx,
+                        mutctx,
                         expr_builder,
                     );
                 }
@@ -521,6 +728,26 @@ fn encode_target_addr_post<H: HeapManager>(
     }
 }
 
+// Returns the vartype of the struct that contains the target, or None if the target is not in a struct.
+fn target_struct_vartype(target: &ir::TargetExpr) -> Option<ir::VarType> {
+    fn innermost_struct_vartype(sf: &ir::StructField) -> ir::VarType {
+        if let Some(inner_struct_field) = &sf.next {
+            innermost_struct_vartype(inner_struct_field)
+        } else {
+            ir::VarType::StructT {
+                typeidx: sf.typeidx,
+            }
+        }
+    }
+
+    match target {
+        ir::TargetExpr::Global { globalidx: _, next }
+        | ir::TargetExpr::Local { localidx: _, next } => {
+            next.as_deref().map(innermost_struct_vartype)
+        }
+    }
+}
+
 // Returns false if WebAssembly knows that the expr returns Void, true otherwise
 // // Note: This is subset of IR::Expr that are Void, for example if-statements are never Void in WebAssembly, even though both branches return Void.
 // net wasm stack: [] -> [<irvartype>] where `<irvartype>` is a valid encoding of an object with IR type expr.vartype
@@ -530,6 +757,12 @@ fn encode_expr<H: HeapManager>(
     mutctx: &mut MutContext,
     expr_builder: &mut wasmgen::ExprBuilder,
 ) -> bool {
+    // subexprs are not in tail position, unless we explicitly pass `tail_ctx` to them
+    let tail_ctx = ctx;
+    let ctx = EncodeContext {
+        tail_position: false,
+        ..ctx
+    };
     match &expr.kind {
         ir::ExprKind::PrimUndefined => {
             // encodes the 'undefined' value
@@ -540,6 +773,15 @@ fn encode_expr<H: HeapManager>(
             // Don't do anything, because undefined is encoded as <nothing>
             true
         }
+        ir::ExprKind::PrimNull => {
+            // encodes the 'null' value
+            assert!(
+                expr.vartype == Some(ir::VarType::Null),
+                "ICE: IR->Wasm: PrimNull does not have type null"
+            );
+            // Don't do anything, because null is encoded as <nothing>
+            true
+        }
         ir::ExprKind::PrimNumber { val } => {
             // encodes a literal number
             assert!(
@@ -663,7 +905,7 @@ fn encode_expr<H: HeapManager>(
                                     }
                                     // net wasm stack: [] -> [<true_expr.vartype>]
                                     let wasm_reachable =
-                                        encode_expr(true_expr, ctx, mutctx, expr_builder);
+                                        encode_expr(true_expr, tail_ctx, mutctx, expr_builder);
                                     // [<true_expr.vartype>] -> [<expr.vartype>]
                                     encode_opt_result_widening_operation(
                                         expr.vartype,
@@ -679,7 +921,7 @@ fn encode_expr<H: HeapManager>(
                             |mutctx: &mut MutContext, expr_builder: &mut wasmgen::ExprBuilder| {
                                 // net wasm stack: [] -> [<false_expr.vartype>]
                                 let wasm_reachable =
-                                    encode_expr(false_expr, ctx, mutctx, expr_builder);
+                                    encode_expr(false_expr, tail_ctx, mutctx, expr_builder);
                                 // [<false_expr.vartype>] -> [<expr.vartype>]
                                 encode_opt_result_widening_operation(
                                     expr.vartype,
@@ -714,7 +956,8 @@ fn encode_expr<H: HeapManager>(
                         expr_builder,
                         |mutctx, expr_builder| {
                             // net wasm stack: [] -> [<true_expr.vartype>]
-                            let wasm_reachable = encode_expr(true_expr, ctx, mutctx, expr_builder);
+                            let wasm_reachable =
+                                encode_expr(true_expr, tail_ctx, mutctx, expr_builder);
                             // [<true_expr.vartype>] -> [<expr.vartype>]
                             encode_opt_result_widening_operation(
                                 expr.vartype,
@@ -728,7 +971,7 @@ fn encode_expr<H: HeapManager>(
                             |mutctx: &mut MutContext, expr_builder: &mut wasmgen::ExprBuilder| {
                                 // net wasm stack: [] -> [<false_expr.vartype>]
                                 let wasm_reachable =
-                                    encode_expr(false_expr, ctx, mutctx, expr_builder);
+                                    encode_expr(false_expr, tail_ctx, mutctx, expr_builder);
                                 // [<false_expr.vartype>] -> [<expr.vartype>]
                                 encode_opt_result_widening_operation(
                                     expr.vartype,
@@ -764,21 +1007,36 @@ fn encode_expr<H: HeapManager>(
             args,
             location,
         } => {
+            // a call that might receive a closure environment on our stack frame can't be a tail call
+            let tail_call = tail_ctx.tail_position && !ctx.escape.ordinary_call(expr);
             // encodes an indirect function call
             encode_appl(
                 expr.vartype,
                 func,
                 args,
                 location,
+                tail_call,
+                ctx.liveness
+                    .and_then(|liveness| liveness.live_after_call(expr)),
                 ctx,
                 mutctx,
                 expr_builder,
             );
-            true
+            // if it is a tail call, wasm knows that we have already returned from this function
+            !tail_call
         }
         ir::ExprKind::DirectAppl { funcidx, args } => {
             // encodes a function call
-            encode_direct_appl(expr.vartype, *funcidx, args, ctx, mutctx, expr_builder);
+            encode_direct_appl(
+                expr.vartype,
+                *funcidx,
+                args,
+                ctx.liveness
+                    .and_then(|liveness| liveness.live_after_call(expr)),
+                ctx,
+                mutctx,
+                expr_builder,
+            );
             true
         }
         ir::ExprKind::Conditional {
@@ -806,7 +1064,7 @@ fn encode_expr<H: HeapManager>(
                     expr_builder,
                     |mutctx, expr_builder| {
                         // net wasm stack: [] -> [<true_expr.vartype>]
-                        let wasm_reachable = encode_expr(true_expr, ctx, mutctx, expr_builder);
+                        let wasm_reachable = encode_expr(true_expr, tail_ctx, mutctx, expr_builder);
                         // net wasm stack: [<true_expr.vartype>] -> [<expr.vartype>]
                         encode_opt_result_widening_operation(
                             expr.vartype,
@@ -819,7 +1077,8 @@ fn encode_expr<H: HeapManager>(
                     (!false_expr.is_prim_undefined()).as_some(
                         |mutctx: &mut MutContext, expr_builder: &mut wasmgen::ExprBuilder| {
                             // net wasm stack: [] -> [<false_expr.vartype>]
-                            let wasm_reachable = encode_expr(false_expr, ctx, mutctx, expr_builder);
+                            let wasm_reachable =
+                                encode_expr(false_expr, tail_ctx, mutctx, expr_builder);
                             // net wasm stack: [<false_expr.vartype>] -> [<expr.vartype>]
                             encode_opt_result_widening_operation(
                                 expr.vartype,
@@ -845,8 +1104,27 @@ fn encode_expr<H: HeapManager>(
                     init_expr.vartype.is_some(),
                     "ICE: IR->Wasm: init expr of a declaration cannot be noreturn"
                 );
-                // net wasm stack: [] -> [<init_expr.vartype>]
-                encode_expr(init_expr, ctx, mutctx, expr_builder);
+                match ctx
+                    .stack_frame
+                    .and_then(|stack_frame| stack_frame.offset(expr))
+                {
+                    Some(offset) => {
+                        // this closure environment lives on our stack frame, instead of the heap
+                        // net wasm stack: [] -> [<init_expr.vartype>]
+                        expr_builder.global_get(ctx.stackptr);
+                        expr_builder.i32_const(offset as i32);
+                        expr_builder.i32_add();
+                        ctx.heap.encode_stack_allocation(
+                            *local,
+                            mutctx.scratch_mut(),
+                            expr_builder,
+                        );
+                    }
+                    None => {
+                        // net wasm stack: [] -> [<init_expr.vartype>]
+                        encode_expr(init_expr, ctx, mutctx, expr_builder);
+                    }
+                }
                 mutctx.with_uninitialized_named_local(*local, |mutctx, named_localidx| {
                     // net wasm stack: [<init_expr.vartype>] -> []
                     encode_store_local(
@@ -856,7 +1134,7 @@ fn encode_expr<H: HeapManager>(
                         expr_builder,
                     );
                     // net wasm stack: [] -> [<contained_expr.vartype>]
-                    encode_expr(contained_expr, ctx, mutctx, expr_builder)
+                    encode_expr(contained_expr, tail_ctx, mutctx, expr_builder)
                 })
             } else {
                 mutctx.with_named_local(
@@ -864,7 +1142,7 @@ fn encode_expr<H: HeapManager>(
                     ctx.heap,
                     expr_builder,
                     |mutctx, expr_builder, _| {
-                        encode_expr(contained_expr, ctx, mutctx, expr_builder)
+                        encode_expr(contained_expr, tail_ctx, mutctx, expr_builder)
                     },
                 )
             }
@@ -876,12 +1154,31 @@ fn encode_expr<H: HeapManager>(
         } => {
             if let Some(actual_vartype) = rhs_expr.vartype {
                 // Note: JavaScript uses left-to-right evaluation order, so following of pointers should be done in encode_target_addr_pre().
-                // encode stuff needed before expr (e.g. compute addresses):
-                encode_target_addr_pre(target, actual_vartype, ctx, mutctx, expr_builder);
-                // encode the expr (net wasm stack: [] -> [<actual_vartype>] where `<actual_vartype>` is a valid encoding of actual_vartype)
-                encode_expr(rhs_expr, ctx, mutctx, expr_builder);
-                // write the value from the stack to the target
-                encode_target_addr_post(target, actual_vartype, ctx, mutctx, expr_builder);
+                mutctx.with_uninitialized_shadow_locals(
+                    target_struct_vartype(target).as_slice(),
+                    |mutctx, struct_localidx| {
+                        // encode stuff needed before expr (e.g. compute addresses):
+                        encode_target_addr_pre(
+                            target,
+                            actual_vartype,
+                            struct_localidx,
+                            ctx,
+                            mutctx,
+                            expr_builder,
+                        );
+                        // encode the expr (net wasm stack: [] -> [<actual_vartype>] where `<actual_vartype>` is a valid encoding of actual_vartype)
+                        encode_expr(rhs_expr, ctx, mutctx, expr_builder);
+                        // write the value from the stack to the target
+                        encode_target_addr_post(
+                            target,
+                            actual_vartype,
+                            struct_localidx,
+                            ctx,
+                            mutctx,
+                            expr_builder,
+                        );
+                    },
+                );
                 true
             } else {
                 panic!("ICE: IR->Wasm: expression in assignment statement cannot be Void");
@@ -898,20 +1195,34 @@ fn encode_expr<H: HeapManager>(
                     match ctx.return_type {
                         None => panic!("ICE: IR->Wasm: cannot have return expression in a function that returns Void"),
                         Some(ret_type) => {
+                            // the inner expr is in tail position if it can be a tail call
+                            // (tail calls use the uniform calling convention, so the function must return Any)
+                            let inner_ctx = EncodeContext {
+                                tail_position: ctx.allow_tail_calls
+                                    && ret_type == ir::VarType::Any,
+                                ..ctx
+                            };
+
                             // net wasm stack: [] -> [<expr.vartype>]
-                            encode_expr(inner_expr, ctx, mutctx, expr_builder);
-
-                            // net wasm stack: [<expr.vartype>] -> [<return_calling_conv(ctx.return_type.unwrap())>]
-                            encode_return_calling_conv(
-                                ret_type,
-                                inner_type,
-                                ctx.options.wasm_multi_value,
-                                ctx.stackptr,
-                                mutctx.scratch_mut(),
-                                expr_builder,
-                            );
-                            // return the value on the stack (or in the unprotected stack) (which now has the correct type)
-                            expr_builder.return_();
+                            let wasm_reachable =
+                                encode_expr(inner_expr, inner_ctx, mutctx, expr_builder);
+
+                            // if the inner expr ended with a tail call, we have already returned
+                            if wasm_reachable {
+                                // net wasm stack: [] -> []
+                                encode_release_stack_frame(ctx, expr_builder);
+                                // net wasm stack: [<expr.vartype>] -> [<return_calling_conv(ctx.return_type.unwrap())>]
+                                encode_return_calling_conv(
+                                    ret_type,
+                                    inner_type,
+                                    ctx.options.wasm_multi_value,
+                                    ctx.stackptr,
+                                    mutctx.scratch_mut(),
+                                    expr_builder,
+                                );
+                                // return the value on the stack (or in the unprotected stack) (which now has the correct type)
+                                expr_builder.return_();
+                            }
                         }
                     };
                 }
@@ -957,19 +1268,57 @@ fn encode_expr<H: HeapManager>(
         }
         ir::ExprKind::Block { expr: inner_expr } => {
             // register that a Break can land here
+            // (no Break can land on a Void block (e.g. from inlining a func that never returns), because it would need to carry a Void value,
+            // but it still needs a landing so that the Breaks inside it count the frames correctly, so we give it one that carries no values)
+            let landing_vartype = expr.vartype.unwrap_or(ir::VarType::Undefined);
             multi_value_polyfill::block(
                 encode_opt_vartype(expr.vartype),
                 ctx.options.wasm_multi_value,
                 mutctx,
                 expr_builder,
                 |mutctx, landing_ctx, expr_builder| {
-                    mutctx.with_landing(expr.vartype.unwrap(), landing_ctx, |mutctx| {
+                    mutctx.with_landing(landing_vartype, landing_ctx, |mutctx| {
+                        // net wasm stack: [] -> [<inner_expr.vartype>]
+                        let wasm_reachable =
+                            encode_expr(inner_expr, tail_ctx, mutctx, expr_builder);
+                        // net wasm stack: [<inner_expr.vartype>] -> [<expr.vartype>]
+                        // (if inner_expr is noreturn but wasm doesn't know it, e.g. a Conditional whose branches both Break, this tells wasm that it is unreachable)
+                        encode_opt_result_widening_operation(
+                            expr.vartype,
+                            inner_expr.vartype,
+                            wasm_reachable,
+                            mutctx.scratch_mut(),
+                            expr_builder,
+                        );
+                    })
+                },
+            );
+
+            if expr.vartype.is_none() {
+                // nothing lands on a Void block, so the end of the block is unreachable too
+                expr_builder.unreachable();
+                false
+            } else {
+                // returns true, because WebAssembly never regards a block as stack-polymorphic even if it is actually the case
+                true
+            }
+        }
+        ir::ExprKind::Loop { expr: inner_expr } => {
+            // register that a Break can land here
+            // Breaks that target a loop jump to the start of the loop, and do not carry any values (so the landing has Undefined type)
+            multi_value_polyfill::loop_(
+                encode_opt_vartype(expr.vartype),
+                ctx.options.wasm_multi_value,
+                mutctx,
+                expr_builder,
+                |mutctx, expr_builder| {
+                    mutctx.with_landing(ir::VarType::Undefined, &[], |mutctx| {
                         encode_expr(inner_expr, ctx, mutctx, expr_builder);
                     })
                 },
             );
 
-            // returns true, because WebAssembly never regards a block as stack-polymorphic even if it is actually the case
+            // returns true, because WebAssembly never regards a loop as stack-polymorphic even if it is actually the case
             true
         }
         ir::ExprKind::Sequence { content } => {
@@ -996,7 +1345,7 @@ fn encode_expr<H: HeapManager>(
                         panic!("ICE: IR->Wasm: Void expression can only be the last expression in a sequence");
                     }
                 }
-                encode_expr(last, ctx, mutctx, expr_builder)
+                encode_expr(last, tail_ctx, mutctx, expr_builder)
             }
         }
         ir::ExprKind::Trap { code, location } => {
@@ -1229,6 +1578,31 @@ fn encode_prim_inst<H: HeapManager>(
         ir::PrimInst::StringLe => {
             string_prim_inst::encode_string_le(mutctx.scratch_mut(), expr_builder);
         }
+        ir::PrimInst::ArrayNew => {
+            array_prim_inst::encode_array_new(ctx.heap, mutctx, expr_builder);
+        }
+        ir::PrimInst::ArrayLength => {
+            array_prim_inst::encode_array_length(expr_builder);
+        }
+        ir::PrimInst::ArrayGet => {
+            array_prim_inst::encode_array_get(mutctx, expr_builder);
+        }
+        ir::PrimInst::ArraySet => {
+            array_prim_inst::encode_array_set(
+                ctx.memidx,
+                ctx.heap,
+                ctx.error_func,
+                ctx.options.wasm_bulk_memory,
+                mutctx,
+                expr_builder,
+            );
+        }
+        ir::PrimInst::AnyEq => {
+            any_prim_inst::encode_any_eq(mutctx.scratch_mut(), expr_builder);
+        }
+        ir::PrimInst::AnyNeq => {
+            any_prim_inst::encode_any_ne(mutctx.scratch_mut(), expr_builder);
+        }
     }
 }
 
@@ -1237,11 +1611,88 @@ fn encode_prim_inst<H: HeapManager>(
 // and the callee must have all params of type Any, and return type must also be Any.
 // (to use more specific types, we must know the target function at compilation time, and hence use the DirectAppl)
 // net wasm stack: [] -> [<return_type>]
+// Moves the stackptr down to make space for the stack frame, and initializes the closure environments in it
+// (so that the GC never sees uninitialized memory when it walks the stack).
+// net wasm stack: [] -> []
+fn encode_reserve_stack_frame<H: HeapManager>(
+    ctx: EncodeContext<H>,
+    mutctx: &mut MutContext,
+    expr_builder: &mut wasmgen::ExprBuilder,
+) {
+    if let Some(stack_frame) = ctx.stack_frame {
+        expr_builder.global_get(ctx.stackptr);
+        expr_builder.i32_const(stack_frame.size as i32);
+        expr_builder.i32_sub();
+        expr_builder.global_set(ctx.stackptr);
+        for (_, ir_vartype, offset) in stack_frame.envs.iter() {
+            expr_builder.global_get(ctx.stackptr);
+            expr_builder.i32_const(*offset as i32);
+            expr_builder.i32_add();
+            ctx.heap
+                .encode_stack_allocation(*ir_vartype, mutctx.scratch_mut(), expr_builder);
+            expr_builder.drop();
+        }
+    }
+}
+
+// Moves the stackptr back up to where it was when this function was called.
+// net wasm stack: [] -> []
+fn encode_release_stack_frame<H: HeapManager>(
+    ctx: EncodeContext<H>,
+    expr_builder: &mut wasmgen::ExprBuilder,
+) {
+    if let Some(stack_frame) = ctx.stack_frame {
+        expr_builder.global_get(ctx.stackptr);
+        expr_builder.i32_const(stack_frame.size as i32);
+        expr_builder.i32_add();
+        expr_builder.global_set(ctx.stackptr);
+    }
+}
+
+// Moves the args of a tail call (which are just below the stackptr) to just below the stack frame, so that they are in the right place after the stack frame is released.
+// The destination is above the source, so we copy the last arg first.
+// net wasm stack: [] -> []
+fn encode_move_args_above_stack_frame<H: HeapManager>(
+    num_args: u32,
+    stack_frame: &StackFrame,
+    ctx: EncodeContext<H>,
+    scratch: &mut Scratch,
+    expr_builder: &mut wasmgen::ExprBuilder,
+) {
+    let size = size_in_memory(ir::VarType::Any);
+    let localidx_stackptr = scratch.push_i32();
+    expr_builder.global_get(ctx.stackptr);
+    expr_builder.local_set(localidx_stackptr);
+    for i in 1..=num_args {
+        let src = -((i * size) as i32);
+        let dest = src + stack_frame.size as i32;
+        // net wasm stack: [] -> []
+        for (offset, is_tag) in [(4, false), (0, true)] {
+            expr_builder.local_get(localidx_stackptr);
+            expr_builder.i32_const(dest + offset);
+            expr_builder.i32_add();
+            expr_builder.local_get(localidx_stackptr);
+            expr_builder.i32_const(src + offset);
+            expr_builder.i32_add();
+            if is_tag {
+                expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+            } else {
+                expr_builder.i64_load(wasmgen::MemArg::new4(0));
+                expr_builder.i64_store(wasmgen::MemArg::new4(0));
+            }
+        }
+    }
+    scratch.pop_i32();
+}
+
 fn encode_appl<H: HeapManager>(
     return_type: Option<ir::VarType>,
     func_expr: &ir::Expr,
     args: &[ir::Expr],
     location: &ir::SourceLocation,
+    tail_call: bool, // whether this Appl is in tail position (the func_expr and args never are)
+    live_locals: Option<&ir::liveness::LiveSet>, // named locals that are live after the call (None if unknown)
     ctx: EncodeContext<H>,
     mutctx: &mut MutContext,
     expr_builder: &mut wasmgen::ExprBuilder,
@@ -1292,45 +1743,69 @@ fn encode_appl<H: HeapManager>(
         // net wasm stack: [] -> [tableidx]
         expr_builder.local_get(mutctx.wasm_local_slice(localidx_func)[0]);
 
-        // todo!(For optimisation, heap_encode_prologue_epilogue should only be called if the callee might allocate)
+        // the type of all thunks (i.e. the uniform calling convention)
+        let thunk_typeidx: wasmgen::TypeIdx =
+            mutctx
+                .module_wrapper()
+                .add_wasm_type(wasmgen::FuncType::new(
+                    Box::new([
+                        wasmgen::ValType::I32,
+                        wasmgen::ValType::I32,
+                        wasmgen::ValType::I32,
+                    ]),
+                    encode_result(Some(ir::VarType::Any), ctx.options.wasm_multi_value),
+                ));
+
+        if tail_call {
+            // This is a tail call, so we do not need to keep any locals alive.
+            // But we need to give up our stack frame, and move the args to where the callee expects them.
+            if let Some(stack_frame) = ctx.stack_frame {
+                // net wasm stack: [] -> []
+                encode_move_args_above_stack_frame(
+                    args.len() as u32,
+                    stack_frame,
+                    ctx,
+                    mutctx.scratch_mut(),
+                    expr_builder,
+                );
+                encode_release_stack_frame(ctx, expr_builder);
+            }
+            if ctx.options.wasm_tail_call {
+                expr_builder.return_call_indirect(thunk_typeidx, wasmgen::TableIdx { idx: 0 });
+            } else {
+                // let the trampoline in our caller make the call
+                ctx.trampoline.unwrap().encode_tail_call(expr_builder);
+            }
+            return;
+        }
+
         // Note: encode_args_to_call_function should be *before* encode_local_roots_prologue, since the args themselves might make function calls.
-        if true {
+        if ctx.may_allocate.indirect_call() {
             // This function might allocate memory, so we need to store the locals in the gc_roots stack first.
 
             // call the function with gc prologue and epilogue
-            mutctx.heap_encode_prologue_epilogue(ctx.heap, expr_builder, |mutctx, expr_builder| {
-                // call the function (indirectly, using uniform calling convention)
-                expr_builder.call_indirect(
-                    mutctx
-                        .module_wrapper()
-                        .add_wasm_type(wasmgen::FuncType::new(
-                            Box::new([
-                                wasmgen::ValType::I32,
-                                wasmgen::ValType::I32,
-                                wasmgen::ValType::I32,
-                            ]),
-                            encode_result(Some(ir::VarType::Any), ctx.options.wasm_multi_value),
-                        )),
-                    wasmgen::TableIdx { idx: 0 },
-                );
-            });
+            mutctx.heap_encode_prologue_epilogue(
+                ctx.heap,
+                live_locals,
+                expr_builder,
+                |_mutctx, expr_builder| {
+                    // call the function (indirectly, using uniform calling convention)
+                    expr_builder.call_indirect(thunk_typeidx, wasmgen::TableIdx { idx: 0 });
+                    // make any pending tail calls
+                    if let Some(trampoline) = ctx.trampoline {
+                        trampoline.encode_post_call(expr_builder);
+                    }
+                },
+            );
         } else {
             // This function is guaranteed not to allocate memory, so we don't need to put the locals on the gc_roots stack.
 
             // call the function (indirectly)
-            expr_builder.call_indirect(
-                mutctx
-                    .module_wrapper()
-                    .add_wasm_type(wasmgen::FuncType::new(
-                        Box::new([
-                            wasmgen::ValType::I32,
-                            wasmgen::ValType::I32,
-                            wasmgen::ValType::I32,
-                        ]),
-                        encode_result(Some(ir::VarType::Any), ctx.options.wasm_multi_value),
-                    )),
-                wasmgen::TableIdx { idx: 0 },
-            );
+            expr_builder.call_indirect(thunk_typeidx, wasmgen::TableIdx { idx: 0 });
+            // make any pending tail calls
+            if let Some(trampoline) = ctx.trampoline {
+                trampoline.encode_post_call(expr_builder);
+            }
         }
 
         // fetch return values from the location prescribed by the calling convention back to the stack
@@ -1352,6 +1827,7 @@ fn encode_direct_appl<H: HeapManager>(
     return_type: Option<ir::VarType>,
     funcidx: ir::FuncIdx,
     args: &[ir::Expr],
+    live_locals: Option<&ir::liveness::LiveSet>, // named locals that are live after the call (None if unknown)
     ctx: EncodeContext<H>,
     mutctx: &mut MutContext,
     expr_builder: &mut wasmgen::ExprBuilder,
@@ -1367,21 +1843,33 @@ fn encode_direct_appl<H: HeapManager>(
     // Encode all the arguments
     encode_args_to_call_function(&signature.params, args, ctx, mutctx, expr_builder);
 
-    // todo!(For optimisation, heap_encode_prologue_epilogue should only be called if the callee might allocate)
     // Note: encode_args_to_call_function should be *before* encode_local_roots_prologue, since the args themselves might make function calls.
-    if true {
+    if ctx.may_allocate.func(funcidx) {
         // This function might allocate memory, so we need to store the locals in the gc_roots stack first.
 
         // call the function with gc prologue and epilogue
-        mutctx.heap_encode_prologue_epilogue(ctx.heap, expr_builder, |_mutctx, expr_builder| {
-            // call the function
-            expr_builder.call(ctx.wasm_funcidxs[funcidx]);
-        });
+        mutctx.heap_encode_prologue_epilogue(
+            ctx.heap,
+            live_locals,
+            expr_builder,
+            |_mutctx, expr_builder| {
+                // call the function
+                expr_builder.call(ctx.wasm_funcidxs[funcidx]);
+                // make any pending tail calls (only functions returning Any can make tail calls)
+                if let (Some(trampoline), Some(ir::VarType::Any)) = (ctx.trampoline, return_type) {
+                    trampoline.encode_post_call(expr_builder);
+                }
+            },
+        );
     } else {
         // This function is guaranteed not to allocate memory, so we don't need to put the locals on the gc_roots stack.
 
         // call the function
         expr_builder.call(ctx.wasm_funcidxs[funcidx]);
+        // make any pending tail calls (only functions returning Any can make tail calls)
+        if let (Some(trampoline), Some(ir::VarType::Any)) = (ctx.trampoline, return_type) {
+            trampoline.encode_post_call(expr_builder);
+        }
     }
 
     // fetch return values from the location prescribed by the calling convention back to the stack
@@ -1406,14 +1894,77 @@ fn encode_args_to_call_function<H: HeapManager>(
     mutctx: &mut MutContext,
     expr_builder: &mut wasmgen::ExprBuilder,
 ) {
-    // todo! there might be a bug here - if a GC event happens midway encoding args, the old args that are references will break.
-    // See encode_args_to_call_indirect_function() for reference.
-    //
     assert!(
         expected_param_types.len() == args.len(),
         "expected_param_types and args must be same length when encoding args to call function"
     );
-    for (expected_type, arg) in expected_param_types.iter().zip(args.iter()) {
+
+    // If a GC event happens midway encoding args, the earlier args that are references will break.
+    // So if some arg might allocate, we stash all args up to (and including) the last such arg in shadow locals,
+    // which the GC knows about, and only push them onto the stack after all of them have been evaluated.
+    // The remaining args are guaranteed not to allocate, so they can be evaluated directly onto the stack.
+    // See encode_args_to_call_indirect_function() for reference.
+    let num_spilled_args: usize = match args.iter().rposition(|arg| ctx.may_allocate.expr(arg)) {
+        Some(idx)
+            if args[..idx]
+                .iter()
+                .any(|arg| vartype_may_hold_pointer(arg.vartype)) =>
+        {
+            idx + 1
+        }
+        _ => 0,
+    };
+    let (spilled_args, direct_args) = args.split_at(num_spilled_args);
+
+    // net wasm stack: [] -> []
+    let localidxs: Vec<usize> = spilled_args
+        .iter()
+        .map(|arg| {
+            let arg_vartype = arg.vartype.expect("argument type cannot be Void");
+            // net wasm stack: [] -> [<arg.vartype>]
+            encode_expr(arg, ctx, mutctx, expr_builder);
+            let localidx = mutctx.add_uninitialized_shadow_local(arg_vartype);
+            // net wasm stack: [<arg.vartype>] -> []
+            encode_store_local(
+                mutctx.wasm_local_slice(localidx),
+                arg_vartype,
+                arg_vartype,
+                expr_builder,
+            );
+            localidx
+        })
+        .collect();
+
+    // net wasm stack: [] -> [<expected_param_types[0]>, <expected_param_types[1]>, ...]
+    for ((expected_type, arg), localidx) in expected_param_types
+        .iter()
+        .zip(spilled_args.iter())
+        .zip(localidxs.iter().copied())
+    {
+        let arg_vartype = arg.vartype.unwrap();
+        // net wasm stack: [] -> [<arg.vartype>]
+        encode_load_local(
+            mutctx.wasm_local_slice(localidx),
+            arg_vartype,
+            arg_vartype,
+            expr_builder,
+        );
+        // net wasm stack: [<arg.vartype>] -> [<expected_type>]
+        encode_widening_operation(
+            *expected_type,
+            arg_vartype,
+            mutctx.scratch_mut(),
+            expr_builder,
+        );
+    }
+    for arg in spilled_args.iter().rev() {
+        mutctx.remove_shadow_local(arg.vartype.unwrap());
+    }
+
+    for (expected_type, arg) in expected_param_types[num_spilled_args..]
+        .iter()
+        .zip(direct_args.iter())
+    {
         if let Some(unwrapped_arg_vartype) = arg.vartype {
             // net wasm stack: [] -> [<arg.vartype>]
             encode_expr(arg, ctx, mutctx, expr_builder);
@@ -1430,6 +1981,19 @@ fn encode_args_to_call_function<H: HeapManager>(
     }
 }
 
+// Returns true if a value of the given vartype might be a pointer into the heap.
+fn vartype_may_hold_pointer(vartype: Option<ir::VarType>) -> bool {
+    match vartype {
+        None
+        | Some(ir::VarType::Unassigned)
+        | Some(ir::VarType::Undefined)
+        | Some(ir::VarType::Null)
+        | Some(ir::VarType::Number)
+        | Some(ir::VarType::Boolean) => false,
+        Some(_) => true,
+    }
+}
+
 // This function prepares subexpressions when calling an indirect function.
 // It is like encode_args_to_call_function(), but instead it calls a function indirectly,
 // and uses the uniform calling convention for it.
@@ -2048,3 +2612,152 @@ fn encode_post_appl_calling_conv(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_runner::*;
+    use super::super::HeapManagerKind;
+    use super::super::Options;
+
+    #[test]
+    fn inlined_noreturn_call() {
+        // head(5);
+        let text = r#"
+            struct#0 = (any any);
+            func#0 (any) -> any {
+              (typecast:any struct#0 narrow (var:any local#0) (var:any local#1.0:0) (trap:! 0x1b 0:1:1-1:2))
+            }
+            func#1 () -> any {
+              (appl:any 0:1:1-1:2 (func:func [func#0] (undefined:undefined)) (number:number 5.0))
+            }
+            entry func#1;
+        "#;
+        assert_eq!(
+            run_ir_all_levels(text, Options::default()),
+            Err(Failure::Error(ir::error::ERROR_CODE_HEAD_PARAM_TYPE))
+        );
+    }
+
+    #[test]
+    fn break_out_of_void_block() {
+        // the inner block is Void because nothing lands on it, but the Break inside it still has to count it
+        let text = r#"
+            func#0 () -> any {
+              (block:any
+                (block:!
+                  (break:! 1 (number:number 3.0))))
+            }
+            entry func#0;
+        "#;
+        assert_eq!(
+            run_ir_all_levels(text, Options::default()),
+            Ok(Value::Number(3.0))
+        );
+    }
+
+    // a self tail call 10000 deep, which is deeper than the call stack of the interpreter
+    const DEEP_TAIL_RECURSION: &str = r#"
+        func#0 (any) -> any {
+          (typecast:any number narrow (var:any local#0)
+            (if:any
+              (prim:boolean number_eq (var:number local#1) (number:number 0.0))
+              (string:string "done")
+              (appl:any 0:1:1-1:2
+                (func:func [func#0] (undefined:undefined))
+                (prim:number number_sub (var:number local#1) (number:number 1.0))))
+            (trap:! 0x11 0:1:1-1:2))
+        }
+        func#1 () -> any {
+          (call:any func#0 (number:number 10000.0))
+        }
+        entry func#1;
+    "#;
+
+    #[test]
+    fn deep_tail_recursion_with_trampoline() {
+        assert_eq!(
+            run_ir_all_levels(DEEP_TAIL_RECURSION, Options::default()),
+            Ok(Value::String("done".to_string()))
+        );
+    }
+
+    #[test]
+    fn deep_tail_recursion_with_return_call() {
+        let options = Options::new_builder()
+            .with_wasm_tail_call(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            run_ir_all_levels(DEEP_TAIL_RECURSION, options),
+            Ok(Value::String("done".to_string()))
+        );
+    }
+
+    #[test]
+    fn stack_env_survives_collections() {
+        // function garbage(n) { for (let i = 0; i < n; i = i + 1) { g = s = s + "x"; } }
+        // const env = { 0: (garbage(10), g) };
+        // garbage(2000);
+        // apply(n => (garbage(n), env[0] + "!"), 2000);
+        // The environment of the closure does not escape, so it is allocated on the stack,
+        // and the string it holds must be updated by every collection, including those
+        // triggered by the closure itself.
+        let text = r#"
+            struct#0 = (any);
+            global#0 = any;
+            func#0 (number) -> undefined {
+              (decl:undefined string (string:string "")
+                (decl:undefined number (number:number 0.0)
+                  (loop:undefined
+                    (if:undefined (prim:boolean number_lt (var:number local#2) (var:number local#0))
+                      (seq:!
+                        (assign:undefined local#1 (prim:string string_add (var:string local#1) (string:string "x")))
+                        (assign:undefined global#0 (var:string local#1))
+                        (assign:undefined local#2 (prim:number number_add (var:number local#2) (number:number 1.0)))
+                        (break:! 0 (undefined:undefined)))
+                      (undefined:undefined)))))
+            }
+            func#1 (struct#0 any) -> any {
+              (typecast:any number narrow (var:any local#1)
+                (seq:any
+                  (call:undefined func#0 (var:number local#2))
+                  (typecast:any string narrow (var:any local#0.0:0)
+                    (prim:string string_add (var:string local#3) (string:string "!"))
+                    (trap:! 0x13 0:1:1-1:2)))
+                (trap:! 0x13 0:1:1-1:2))
+            }
+            func#2 (any any) -> any {
+              (appl:any 0:1:1-1:2
+                (typecast:func func narrow (var:any local#0) (var:func local#2) (trap:! 0x16 0:1:1-1:2))
+                (var:any local#1))
+            }
+            func#3 () -> any {
+              (decl:any struct#0 (struct:struct#0 struct#0)
+                (seq:any
+                  (call:undefined func#0 (number:number 10.0))
+                  (assign:undefined local#0.0:0 (var:any global#0))
+                  (call:undefined func#0 (number:number 2000.0))
+                  (call:any func#2 (func:func [func#1:closure] (var:struct#0 local#0)) (number:number 2000.0))))
+            }
+            entry func#3;
+        "#;
+        for &heap_manager in &[
+            HeapManagerKind::Cheney,
+            HeapManagerKind::Generational,
+            HeapManagerKind::MarkSweep,
+        ] {
+            let options = Options::new_builder()
+                .with_heap_manager(heap_manager)
+                .build()
+                .unwrap();
+            assert_eq!(
+                run_ir_all_levels(text, options),
+                Ok(Value::String("xxxxxxxxxx!".to_string())),
+                "with {:?}",
+                heap_manager
+            );
+            let (_, stats) = run_ir_with_stats(text, "-O2", options);
+            assert!(stats.collections > 0, "with {:?}", heap_manager);
+        }
+    }
+}
@@ -4,6 +4,7 @@ use super::WASM_PAGE_BITS;
 
 // returns the base table element index from which indirect access should be calculated (i.e. the "table offset")
 // e.g. if we want to access copy_children_$i, we should call_indirect with index = (table_offset+i)
+// `copy_end`: if not None, objects at or above this index (in page units) are left where they are
 pub fn make_copy_children_elements(
     wasm_module: &mut wasmgen::WasmModule,
     struct_types: &[Box<[ir::VarType]>],
@@ -13,6 +14,7 @@ pub fn make_copy_children_elements(
     copy_indirect_table_offset: u32,
     copy_funcs: &[Option<wasmgen::FuncIdx>],
     heap_begin: u32,
+    copy_end: Option<u32>,
 ) -> u32 {
     // make the string version of copy_children
     // it doesn't call any other function; just returns the ptr past-the-end of the string
@@ -58,6 +60,7 @@ pub fn make_copy_children_elements(
         copy_indirect_table_offset: u32,
         copy_funcs: &[Option<wasmgen::FuncIdx>],
         heap_begin: u32,
+        copy_end: Option<u32>,
     ) -> wasmgen::FuncIdx {
         let functype = wasmgen::FuncType::new(
             Box::new([wasmgen::ValType::I32]),
@@ -101,6 +104,7 @@ pub fn make_copy_children_elements(
                         }
                         ir::VarType::Unassigned => {}
                         ir::VarType::Undefined => {}
+                        ir::VarType::Null => {}
                         ir::VarType::Number => {}
                         ir::VarType::Boolean => {}
                         ir::VarType::String => {
@@ -113,6 +117,7 @@ pub fn make_copy_children_elements(
                                 tableidx,
                                 copy_funcs[ir::VarType::String.tag() as usize].unwrap(),
                                 heap_begin,
+                                copy_end,
                                 true,
                             );
                         }
@@ -188,6 +193,20 @@ pub fn make_copy_children_elements(
                             scratch.pop_i32();
                             scratch.pop_i32();
                         }
+                        ir::VarType::Array => {
+                            // net wasm stack: [] -> []
+                            gen(
+                                expr_builder,
+                                &mut scratch,
+                                localidx_param,
+                                byte_offset,
+                                tableidx,
+                                copy_funcs[ir::VarType::Array.tag() as usize].unwrap(),
+                                heap_begin,
+                                copy_end,
+                                false,
+                            );
+                        }
                         ir::VarType::StructT { typeidx } => {
                             // net wasm stack: [] -> []
                             gen(
@@ -199,91 +218,161 @@ pub fn make_copy_children_elements(
                                 copy_funcs[ir::VarType::StructT { typeidx }.tag() as usize]
                                     .unwrap(),
                                 heap_begin,
+                                copy_end,
                                 false,
                             );
                         }
                     }
                 });
 
-            fn gen(
-                expr_builder: &mut wasmgen::ExprBuilder,
-                scratch: &mut Scratch,
-                localidx_param: wasmgen::LocalIdx,
-                byte_offset: u32,
-                tableidx: wasmgen::TableIdx,
-                copy_func: wasmgen::FuncIdx,
-                heap_begin: u32,
-                is_string: bool,
-            ) {
-                /*
-                if (ptr != -1 && (f is not String || ptr > heap_begin * WASM_PAGE_SIZE)) {
-                    if (*(ptr-4)) & I32_MIN { // already copied (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
-                        f.ptr = (*(ptr-4)) << 1; // we store the ptr in the tag, but shifted right by one bit position (valid since ptr are all multiple of 4)
-                    } else {
-                        f.ptr = copy_${tag of f}(f.ptr);
-                    }
-                }
-                */
-                let localidx_ptr = scratch.push_i32(); // from_any_data(data)
-                let localidx_val = scratch.push_i32(); // *(from_any_data(data)-4)
-
-                // net wasm stack: [] -> [ptr(i32)]
-                expr_builder.local_get(localidx_param);
-                expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset));
-                expr_builder.local_tee(localidx_ptr);
-
-                // net wasm stack: [ptr(i32)] -> [cond(i32)]
-                expr_builder.i32_const(-1);
-                expr_builder.i32_ne();
-                if is_string {
-                    expr_builder.local_get(localidx_ptr);
-                    expr_builder.i32_const((heap_begin << WASM_PAGE_BITS) as i32);
-                    expr_builder.i32_gt_u();
-                    expr_builder.i32_and();
-                }
+            // net wasm stack: [] -> [i32(ptr to past-the-end)]
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(struct_size as i32);
+            expr_builder.i32_add();
 
-                // net wasm stack: [cond(i32)] -> []
-                expr_builder.if_(&[]);
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    }
+
+    // make the array version of copy_children
+    // the array header is [len(i32), buf(i32)], and we only need to copy the buffer
+    fn make_array_function(
+        wasm_module: &mut wasmgen::WasmModule,
+        tableidx: wasmgen::TableIdx,
+        copy_array_buffer_func: wasmgen::FuncIdx,
+        heap_begin: u32,
+        copy_end: Option<u32>,
+    ) -> wasmgen::FuncIdx {
+        let functype = wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        );
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+            let mut scratch = Scratch::new(locals_builder);
+
+            // net wasm stack: [] -> []
+            gen(
+                expr_builder,
+                &mut scratch,
+                localidx_param,
+                4,
+                tableidx,
+                copy_array_buffer_func,
+                heap_begin,
+                copy_end,
+                false,
+            );
+
+            // net wasm stack: [] -> [i32(ptr to past-the-end)]
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(8);
+            expr_builder.i32_add();
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    }
+
+    // make the array buffer version of copy_children
+    // the array buffer is [capacity(i32), element(Any) * capacity]
+    fn make_array_buffer_function(
+        wasm_module: &mut wasmgen::WasmModule,
+        tableidx: wasmgen::TableIdx,
+        copy_indirect_table_offset: u32,
+    ) -> wasmgen::FuncIdx {
+        let functype = wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        );
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+            let mut scratch = Scratch::new(locals_builder);
+
+            let any_size = crate::var_conv::size_in_memory(ir::VarType::Any) as i32;
+
+            // Algorithm:
+            /*
+            let it = ptr + 4;
+            let it_end = it + (*ptr) * sizeof(Any);
+            while (it != it_end) {
+                it->data = (*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + it->tag))(it->data);
+                it += sizeof(Any);
+            }
+            return it_end;
+            */
+
+            let localidx_it = scratch.push_i32();
+            let localidx_it_end = scratch.push_i32();
+
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(4);
+            expr_builder.i32_add();
+            expr_builder.local_tee(localidx_it);
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.i32_const(any_size);
+            expr_builder.i32_mul();
+            expr_builder.i32_add();
+            expr_builder.local_set(localidx_it_end);
+
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_it);
+            expr_builder.local_get(localidx_it_end);
+            expr_builder.i32_ne();
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
                 {
-                    // net wasm stack: [] -> [param(i32)]
-                    expr_builder.local_get(localidx_param);
-
-                    // net wasm stack: [] -> [ptr_minus_4(i32)]
-                    expr_builder.local_get(localidx_ptr);
-                    expr_builder.i32_const(4);
-                    expr_builder.i32_sub();
-
-                    // net wasm stack: [ptr_minus_4(i32)] -> [val(i32)]
-                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
-                    expr_builder.local_tee(localidx_val);
-
-                    // net wasm stack: [val(i32)] -> [cond(i32)]
-                    expr_builder.i32_const(i32::min_value());
-                    expr_builder.i32_and();
-
-                    // net wasm stack: [cond(i32)] -> [ret(i32)]
-                    expr_builder.if_(&[wasmgen::ValType::I32]);
-                    expr_builder.local_get(localidx_val);
-                    expr_builder.i32_const(1);
-                    expr_builder.i32_shl();
-                    expr_builder.else_();
-                    expr_builder.local_get(localidx_ptr);
-                    expr_builder.call(copy_func);
-                    expr_builder.end();
-
-                    // net wasm stack: [param(i32), ret(i32)] -> []
-                    expr_builder.i32_store(wasmgen::MemArg::new4(byte_offset));
+                    // it->data = (*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + it->tag))(it->data);
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i64_load(wasmgen::MemArg::new4(4)); // the `data` of the Any is at offset 4
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0)); // the `tag` of the Any is at offset 0
+                    if copy_indirect_table_offset != 0 {
+                        expr_builder.i32_const(copy_indirect_table_offset as i32);
+                        expr_builder.i32_add();
+                    }
+                    expr_builder.call_indirect(
+                        wasm_module.insert_type_into(wasmgen::FuncType::new(
+                            Box::new([wasmgen::ValType::I64]),
+                            Box::new([wasmgen::ValType::I64]),
+                        )),
+                        tableidx,
+                    );
+                    expr_builder.i64_store(wasmgen::MemArg::new4(4));
+
+                    // it += sizeof(Any);
+                    // net wasm stack: [] -> [cond(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(any_size);
+                    expr_builder.i32_add();
+                    expr_builder.local_tee(localidx_it);
+                    expr_builder.local_get(localidx_it_end);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
                 }
                 expr_builder.end();
-
-                scratch.pop_i32();
-                scratch.pop_i32();
             }
+            expr_builder.end();
 
             // net wasm stack: [] -> [i32(ptr to past-the-end)]
-            expr_builder.local_get(localidx_param);
-            expr_builder.i32_const(struct_size as i32);
-            expr_builder.i32_add();
+            expr_builder.local_get(localidx_it_end);
+
+            scratch.pop_i32();
+            scratch.pop_i32();
 
             expr_builder.end(); // return it
         }
@@ -293,7 +382,7 @@ pub fn make_copy_children_elements(
 
     let copy_children_table_offset: u32 = wasm_module.reserve_table_elements(
         tableidx,
-        (ir::NUM_PRIMITIVE_TAG_TYPES + struct_types.len()) as u32,
+        (ir::NUM_PRIMITIVE_TAG_TYPES + struct_types.len() + 1) as u32, // the last element is for the array buffer
     );
 
     // Note: some reserved table elements are left uncommitted.  They will automatically trap if called at runtime.  (If that happens, then the compiler has a bug.)
@@ -304,6 +393,26 @@ pub fn make_copy_children_elements(
         copy_children_table_offset + ir::VarType::String.tag() as u32,
         Box::new([funcidx_string]),
     );
+    let array_buffer_tag: usize = ir::NUM_PRIMITIVE_TAG_TYPES + struct_types.len();
+    let funcidx_array: wasmgen::FuncIdx = make_array_function(
+        wasm_module,
+        tableidx,
+        copy_funcs[array_buffer_tag].unwrap(),
+        heap_begin,
+        copy_end,
+    );
+    wasm_module.commit_table_elements(
+        tableidx,
+        copy_children_table_offset + ir::VarType::Array.tag() as u32,
+        Box::new([funcidx_array]),
+    );
+    let funcidx_array_buffer: wasmgen::FuncIdx =
+        make_array_buffer_function(wasm_module, tableidx, copy_indirect_table_offset);
+    wasm_module.commit_table_elements(
+        tableidx,
+        copy_children_table_offset + array_buffer_tag as u32,
+        Box::new([funcidx_array_buffer]),
+    );
     let funcidxs_structs: Box<[wasmgen::FuncIdx]> = struct_types
         .iter()
         .zip(struct_field_byte_offsets.iter())
@@ -318,6 +427,7 @@ pub fn make_copy_children_elements(
                 copy_indirect_table_offset,
                 copy_funcs,
                 heap_begin,
+                copy_end,
             )
         })
         .collect();
@@ -329,3 +439,86 @@ pub fn make_copy_children_elements(
 
     copy_children_table_offset
 }
+
+// Encodes copy_field_impl_$i for a field of pointer type at `byte_offset` from the object pointed to by `localidx_param`.
+fn gen(
+    expr_builder: &mut wasmgen::ExprBuilder,
+    scratch: &mut Scratch,
+    localidx_param: wasmgen::LocalIdx,
+    byte_offset: u32,
+    tableidx: wasmgen::TableIdx,
+    copy_func: wasmgen::FuncIdx,
+    heap_begin: u32,
+    copy_end: Option<u32>,
+    is_string: bool,
+) {
+    /*
+    if (ptr != -1 && (f is not String || ptr > heap_begin * WASM_PAGE_SIZE) && (copy_end is None || ptr < copy_end * WASM_PAGE_SIZE)) {
+        if (*(ptr-4)) & I32_MIN { // already copied (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
+            f.ptr = (*(ptr-4)) << 1; // we store the ptr in the tag, but shifted right by one bit position (valid since ptr are all multiple of 4)
+        } else {
+            f.ptr = copy_${tag of f}(f.ptr);
+        }
+    }
+    */
+    let localidx_ptr = scratch.push_i32(); // from_any_data(data)
+    let localidx_val = scratch.push_i32(); // *(from_any_data(data)-4)
+
+    // net wasm stack: [] -> [ptr(i32)]
+    expr_builder.local_get(localidx_param);
+    expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset));
+    expr_builder.local_tee(localidx_ptr);
+
+    // net wasm stack: [ptr(i32)] -> [cond(i32)]
+    expr_builder.i32_const(-1);
+    expr_builder.i32_ne();
+    if is_string {
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const((heap_begin << WASM_PAGE_BITS) as i32);
+        expr_builder.i32_gt_u();
+        expr_builder.i32_and();
+    }
+    if let Some(copy_end) = copy_end {
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const((copy_end << WASM_PAGE_BITS) as i32);
+        expr_builder.i32_lt_u();
+        expr_builder.i32_and();
+    }
+
+    // net wasm stack: [cond(i32)] -> []
+    expr_builder.if_(&[]);
+    {
+        // net wasm stack: [] -> [param(i32)]
+        expr_builder.local_get(localidx_param);
+
+        // net wasm stack: [] -> [ptr_minus_4(i32)]
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const(4);
+        expr_builder.i32_sub();
+
+        // net wasm stack: [ptr_minus_4(i32)] -> [val(i32)]
+        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+        expr_builder.local_tee(localidx_val);
+
+        // net wasm stack: [val(i32)] -> [cond(i32)]
+        expr_builder.i32_const(i32::min_value());
+        expr_builder.i32_and();
+
+        // net wasm stack: [cond(i32)] -> [ret(i32)]
+        expr_builder.if_(&[wasmgen::ValType::I32]);
+        expr_builder.local_get(localidx_val);
+        expr_builder.i32_const(1);
+        expr_builder.i32_shl();
+        expr_builder.else_();
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.call(copy_func);
+        expr_builder.end();
+
+        // net wasm stack: [param(i32), ret(i32)] -> []
+        expr_builder.i32_store(wasmgen::MemArg::new4(byte_offset));
+    }
+    expr_builder.end();
+
+    scratch.pop_i32();
+    scratch.pop_i32();
+}
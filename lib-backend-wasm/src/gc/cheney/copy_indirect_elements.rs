@@ -3,12 +3,14 @@ use wasmgen::Scratch;
 
 // returns the base table element index from which indirect access should be calculated (i.e. the "table offset")
 // e.g. if we want to access copy_indirect_$i, we should call_indirect with index = (table_offset+i)
+// `copy_end`: if not None, objects at or above this index (in page units) are left where they are
 pub fn make_copy_indirect_elements(
     wasm_module: &mut wasmgen::WasmModule,
     num_structs: usize,
     copy_funcs: &[Option<wasmgen::FuncIdx>],
     tableidx: wasmgen::TableIdx,
     heap_begin: u32,
+    copy_end: Option<u32>,
 ) -> u32 {
     /*
     // copy_indirect_$i shall exist for all types (except Any)
@@ -23,16 +25,16 @@ pub fn make_copy_indirect_elements(
                     return make_func(f.idx, i32_wrap_i64((*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + *(f.closure-4)))(i64_extend_i32(f.closure))));
                 }
             }
-        } else if constexpr $i is not a ptr (i.e. not StructT or string) {
+        } else if constexpr $i is not a ptr (i.e. not StructT, Array or String) {
             // NO-OP
         } else {
-            if (ptr != -1 && (f is not String || ptr > heap_begin * WASM_PAGE_SIZE)) {
+            if (ptr != -1 && (f is not String or StructT || ptr > heap_begin * WASM_PAGE_SIZE)) {
                 if (*(ptr-4)) & I32_MIN { // already copied (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
                     return to_any_data((*(ptr-4)) << 1); // we store the ptr in the tag, but shifted right by one bit position (valid since ptr are all multiple of 4)
                 } else {
                     return to_any_data(copy_$i(from_any_data(data)));
                 }
-            }
+            } else return data;
         }
     }
     */
@@ -162,11 +164,14 @@ pub fn make_copy_indirect_elements(
     }
 
     // `copy_func` is the function copy_$i.
+    // `may_be_outside_heap` should be true if the object might be in global data (for strings) or on the stack (for structs).
+    // `copy_end` is the past-the-end of the memory that objects are copied out of (in page units), or None if it extends to the end of the heap.
     fn make_struct_function(
         wasm_module: &mut wasmgen::WasmModule,
         copy_func: wasmgen::FuncIdx,
         heap_begin: u32,
-        is_string: bool,
+        copy_end: Option<u32>,
+        may_be_outside_heap: bool,
     ) -> wasmgen::FuncIdx {
         let functype = wasmgen::FuncType::new(
             Box::new([wasmgen::ValType::I64]),
@@ -181,13 +186,13 @@ pub fn make_copy_indirect_elements(
 
             /*
             // Algorithm:
-            if (ptr != -1 && (f is not String || ptr > heap_begin * WASM_PAGE_SIZE)) {
+            if (ptr != -1 && (!may_be_outside_heap || ptr > heap_begin * WASM_PAGE_SIZE) && (copy_end is None || ptr < copy_end * WASM_PAGE_SIZE)) {
                 if (*(ptr-4)) & I32_MIN { // already copied (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
                     return to_any_data((*(ptr-4)) << 1); // we store the ptr in the tag, but shifted right by one bit position (valid since ptr are all multiple of 4)
                 } else {
                     return to_any_data(copy_$i(from_any_data(data)));
                 }
-            } else return -1;
+            } else return data;
             */
 
             let localidx_ptr = scratch.push_i32(); // from_any_data(data)
@@ -201,12 +206,18 @@ pub fn make_copy_indirect_elements(
             // net wasm stack: [ptr(i32)] -> [cond(i32)]
             expr_builder.i32_const(-1);
             expr_builder.i32_ne();
-            if is_string {
+            if may_be_outside_heap {
                 expr_builder.local_get(localidx_ptr);
                 expr_builder.i32_const((heap_begin << WASM_PAGE_BITS) as i32);
                 expr_builder.i32_gt_u();
                 expr_builder.i32_and();
             }
+            if let Some(copy_end) = copy_end {
+                expr_builder.local_get(localidx_ptr);
+                expr_builder.i32_const((copy_end << WASM_PAGE_BITS) as i32);
+                expr_builder.i32_lt_u();
+                expr_builder.i32_and();
+            }
 
             // net wasm stack: [cond(i32)] -> [actual_ret(i64)]
             expr_builder.if_(&[wasmgen::ValType::I64]);
@@ -239,7 +250,8 @@ pub fn make_copy_indirect_elements(
             }
             expr_builder.else_();
             {
-                expr_builder.i64_const(((((-1) as i32) as u32) as u64) as i64); // weird conversion so that the high bits will be zero, not technically necessary since we are allowed to have arbitrary high bits, but it is nicer.
+                // the object is not on the heap (or it is a nullptr), so it stays where it is
+                expr_builder.local_get(localidx_param);
             }
             expr_builder.end();
 
@@ -262,9 +274,19 @@ pub fn make_copy_indirect_elements(
         wasm_module,
         copy_funcs[ir::VarType::String.tag() as usize].unwrap(),
         heap_begin,
+        copy_end,
         true,
     );
 
+    let array_funcidx: wasmgen::FuncIdx = make_struct_function(
+        wasm_module,
+        copy_funcs[ir::VarType::Array.tag() as usize].unwrap(),
+        heap_begin,
+        copy_end,
+        false,
+    );
+
+    // Note: there is no element for the array buffer, since it can never be stored in an Any.
     let copy_indirect_elements: Box<[wasmgen::FuncIdx]> = std::iter::empty()
         .chain(std::iter::once(no_op_funcidx)) // Unassigned
         .chain(std::iter::once(no_op_funcidx)) // Undefined
@@ -272,12 +294,15 @@ pub fn make_copy_indirect_elements(
         .chain(std::iter::once(no_op_funcidx)) // Boolean
         .chain(std::iter::once(string_funcidx)) // String
         .chain(std::iter::once(func_funcidx)) // Func
+        .chain(std::iter::once(no_op_funcidx)) // Null
+        .chain(std::iter::once(array_funcidx)) // Array
         .chain((0..num_structs).map(|n| {
             make_struct_function(
                 wasm_module,
                 copy_funcs[ir::NUM_PRIMITIVE_TAG_TYPES + n].unwrap(),
                 heap_begin,
-                false,
+                copy_end,
+                true,
             )
         }))
         .collect();
@@ -1,8 +1,12 @@
+use crate::gc::GcStats;
 use crate::global_var::GlobalVarManagerRef;
 use wasmgen::Scratch;
 
 use super::WASM_PAGE_BITS;
+use super::WASM_PAGE_SIZE;
 
+// Encodes do_cheney(), which copies all live objects in (heap_begin, end of heap) into the semispace that is not in use, and grows the memory if necessary.
+// `space_begin`: the lowest index of the two semispaces (and so the lowest index that objects are copied to), in page units.  It must be at least `heap_begin`.
 pub fn make_do_cheney<'a>(
     wasm_module: &mut wasmgen::WasmModule,
     tableidx: wasmgen::TableIdx,
@@ -15,363 +19,11 @@ pub fn make_do_cheney<'a>(
     globalidx_gc_roots_stack_ptr: wasmgen::GlobalIdx,
     copy_funcs: &[Option<wasmgen::FuncIdx>],
     global_var_manager: GlobalVarManagerRef<'a>,
+    globalidx_stackptr: wasmgen::GlobalIdx,
+    stack_end: u32,
     heap_begin: u32,
+    space_begin: u32,
 ) -> wasmgen::FuncIdx {
-    // Guaranteed to synchronise localidx_free_mem_ptr and globalidx_free_mem_ptr before returning.
-    // net wasm stack: [] -> []
-    fn generate_common_portion<'a>(
-        wasm_module: &mut wasmgen::WasmModule,
-        tableidx: wasmgen::TableIdx,
-        copy_indirect_table_offset: u32,
-        copy_children_table_offset: u32,
-        globalidx_free_mem_ptr: wasmgen::GlobalIdx,
-        localidx_free_mem_ptr: wasmgen::LocalIdx,
-        localidx_gc_roots_stack_base_ptr: wasmgen::LocalIdx,
-        localidx_gc_roots_stack_ptr: wasmgen::LocalIdx,
-        copy_funcs: &[Option<wasmgen::FuncIdx>],
-        global_var_manager: GlobalVarManagerRef<'a>,
-        heap_begin: u32,
-        expr_builder: &mut wasmgen::ExprBuilder,
-        scratch: &mut Scratch,
-    ) {
-        let localidx_scan = scratch.push_i32();
-
-        // let scan = free_mem_ptr;
-        // net wasm stack: [] -> []
-        expr_builder.local_get(localidx_free_mem_ptr);
-        expr_builder.local_set(localidx_scan);
-
-        // net wasm stack: [] -> []
-        {
-            // Pseudocode:
-            // for each global g {
-            //     copy_field_impl_$i(&mut g);
-            // }
-            for (ir_vartype, wasm_globalidxs) in global_var_manager {
-                // note: similar to copying struct fields in copy_children_elements()
-                match ir_vartype {
-                    ir::VarType::Any => {
-                        // f.data = (*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + f.tag))(f.data);
-                        // net wasm stack: [] -> []
-                        expr_builder.global_get(wasm_globalidxs[1]); // the `data` of the Any
-                        expr_builder.global_get(wasm_globalidxs[0]); // the `tag` of the Any
-                        if copy_indirect_table_offset != 0 {
-                            expr_builder.i32_const(copy_indirect_table_offset as i32);
-                            expr_builder.i32_add();
-                        }
-                        expr_builder.call_indirect(
-                            wasm_module.insert_type_into(wasmgen::FuncType::new(
-                                Box::new([wasmgen::ValType::I64]),
-                                Box::new([wasmgen::ValType::I64]),
-                            )),
-                            tableidx,
-                        );
-                        expr_builder.global_set(wasm_globalidxs[1]); // store the `data` of the Any
-                    }
-                    ir::VarType::Unassigned => {}
-                    ir::VarType::Undefined => {}
-                    ir::VarType::Number => {}
-                    ir::VarType::Boolean => {}
-                    ir::VarType::String => {
-                        // net wasm stack: [] -> []
-                        gen(
-                            expr_builder,
-                            scratch,
-                            wasm_globalidxs[0],
-                            tableidx,
-                            copy_funcs[ir::VarType::String.tag() as usize].unwrap(),
-                            heap_begin,
-                            true,
-                        );
-                    }
-                    ir::VarType::Func => {
-                        /*
-                        if (f.closure != -1) {
-                            if (*(f.closure-4)) & I32_MIN {
-                                f.closure = (*(f.closure-4)) << 1; // we don't know the closure type, but we point to the new one anyway.
-                            } else {
-                                f.closure = i32_wrap_i64((*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + *(f.closure-4)))(i64_extend_i32(f.closure)));
-                            }
-                        }
-                        */
-                        let localidx_closure = scratch.push_i32(); // f.closure
-                        let localidx_val = scratch.push_i32(); // *(f.closure-4)
-
-                        // net wasm stack: [] -> [f.closure(i32)]
-                        expr_builder.global_get(wasm_globalidxs[1]); // the `closure` of the Func
-                        expr_builder.local_tee(localidx_closure);
-
-                        // net wasm stack: [closure(i32)] -> [cond(i32)]
-                        expr_builder.i32_const(-1);
-                        expr_builder.i32_ne();
-
-                        // net wasm stack: [cond(i32)] -> []
-                        expr_builder.if_(&[]);
-                        {
-                            // net wasm stack: [] -> [closure_minus_4(i32)]
-                            expr_builder.local_get(localidx_closure);
-                            expr_builder.i32_const(4);
-                            expr_builder.i32_sub();
-
-                            // net wasm stack: [closure_minus_4(i32)] -> [val(i32)]
-                            expr_builder.i32_load(wasmgen::MemArg::new4(0));
-                            expr_builder.local_tee(localidx_val);
-
-                            // net wasm stack: [val(i32)] -> [cond(i32)]
-                            expr_builder.i32_const(i32::min_value());
-                            expr_builder.i32_and();
-
-                            // net wasm stack: [cond(i32)] -> [new_closure(i32)]
-                            expr_builder.if_(&[wasmgen::ValType::I32]);
-                            expr_builder.local_get(localidx_val);
-                            expr_builder.i32_const(1);
-                            expr_builder.i32_shl();
-                            expr_builder.else_();
-                            expr_builder.local_get(localidx_closure);
-                            expr_builder.i64_extend_i32_u();
-                            expr_builder.local_get(localidx_val);
-                            if copy_indirect_table_offset != 0 {
-                                expr_builder.i32_const(copy_indirect_table_offset as i32);
-                                expr_builder.i32_add();
-                            }
-                            expr_builder.call_indirect(
-                                wasm_module.insert_type_into(wasmgen::FuncType::new(
-                                    Box::new([wasmgen::ValType::I64]),
-                                    Box::new([wasmgen::ValType::I64]),
-                                )),
-                                tableidx,
-                            );
-                            expr_builder.i32_wrap_i64();
-                            expr_builder.end();
-
-                            // net wasm stack: [new_closure(i32)] -> []
-                            expr_builder.global_set(wasm_globalidxs[1]);
-                        }
-                        expr_builder.end();
-
-                        scratch.pop_i32();
-                        scratch.pop_i32();
-                    }
-                    ir::VarType::StructT { typeidx } => {
-                        // net wasm stack: [] -> []
-                        gen(
-                            expr_builder,
-                            scratch,
-                            wasm_globalidxs[0],
-                            tableidx,
-                            copy_funcs[ir::VarType::StructT { typeidx }.tag() as usize].unwrap(),
-                            heap_begin,
-                            false,
-                        );
-                    }
-                }
-            }
-
-            fn gen(
-                expr_builder: &mut wasmgen::ExprBuilder,
-                scratch: &mut Scratch,
-                wasm_globalidx: wasmgen::GlobalIdx,
-                tableidx: wasmgen::TableIdx,
-                copy_func: wasmgen::FuncIdx,
-                heap_begin: u32,
-                is_string: bool,
-            ) {
-                /*
-                if (ptr != -1 && (f is not String || ptr > heap_begin * WASM_PAGE_SIZE)) {
-                    if (*(ptr-4)) & I32_MIN { // already copied (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
-                        f.ptr = (*(ptr-4)) << 1; // we store the ptr in the tag, but shifted right by one bit position (valid since ptr are all multiple of 4)
-                    } else {
-                        f.ptr = copy_${tag of f}(f.ptr);
-                    }
-                }
-                */
-                let localidx_ptr = scratch.push_i32(); // from_any_data(data)
-                let localidx_val = scratch.push_i32(); // *(from_any_data(data)-4)
-
-                // net wasm stack: [] -> [ptr(i32)]
-                expr_builder.global_get(wasm_globalidx);
-                expr_builder.local_tee(localidx_ptr);
-
-                // net wasm stack: [ptr(i32)] -> [cond(i32)]
-                expr_builder.i32_const(-1);
-                expr_builder.i32_ne();
-                if is_string {
-                    expr_builder.local_get(localidx_ptr);
-                    expr_builder.i32_const((heap_begin << WASM_PAGE_BITS) as i32);
-                    expr_builder.i32_gt_u();
-                    expr_builder.i32_and();
-                }
-
-                // net wasm stack: [cond(i32)] -> []
-                expr_builder.if_(&[]);
-                {
-                    // net wasm stack: [] -> [ptr_minus_4(i32)]
-                    expr_builder.local_get(localidx_ptr);
-                    expr_builder.i32_const(4);
-                    expr_builder.i32_sub();
-
-                    // net wasm stack: [ptr_minus_4(i32)] -> [val(i32)]
-                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
-                    expr_builder.local_tee(localidx_val);
-
-                    // net wasm stack: [val(i32)] -> [cond(i32)]
-                    expr_builder.i32_const(i32::min_value());
-                    expr_builder.i32_and();
-
-                    // net wasm stack: [cond(i32)] -> [ret(i32)]
-                    expr_builder.if_(&[wasmgen::ValType::I32]);
-                    expr_builder.local_get(localidx_val);
-                    expr_builder.i32_const(1);
-                    expr_builder.i32_shl();
-                    expr_builder.else_();
-                    expr_builder.local_get(localidx_ptr);
-                    expr_builder.call(copy_func);
-                    expr_builder.end();
-
-                    // net wasm stack: [ret(i32)] -> []
-                    expr_builder.global_set(wasm_globalidx);
-                }
-                expr_builder.end();
-
-                scratch.pop_i32();
-                scratch.pop_i32();
-            }
-        }
-
-        // net wasm stack: [] -> []
-        {
-            let localidx_gc_roots_it = scratch.push_i32();
-
-            // net wasm stack: [] -> [gc_roots_it(i32)]
-            expr_builder.local_get(localidx_gc_roots_stack_base_ptr);
-            expr_builder.local_tee(localidx_gc_roots_it);
-
-            // while loop turns into this:
-            /*
-            if gc_roots_it != gc_roots_stack_ptr {
-                do {
-                    ...
-                } while (gc_roots_it != gc_roots_stack_ptr);
-            }
-            */
-
-            // net wasm stack: [gc_roots_it(i32)] -> [cond(i32)]
-            expr_builder.local_get(localidx_gc_roots_stack_ptr);
-            expr_builder.i32_ne();
-
-            // net wasm stack: [cond(i32)] -> []
-            expr_builder.if_(&[]);
-            {
-                // net wasm stack: [] -> []
-                expr_builder.loop_(&[]);
-                {
-                    // let f = *gc_roots_it;
-                    // f.data = (*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + f.tag))(f.data);
-                    // net wasm stack: [] -> []
-                    expr_builder.local_get(localidx_gc_roots_it);
-                    expr_builder.local_get(localidx_gc_roots_it);
-                    expr_builder.i64_load(wasmgen::MemArg::new4(4)); // load f.data
-                    expr_builder.local_get(localidx_gc_roots_it);
-                    expr_builder.i32_load(wasmgen::MemArg::new4(0)); // load f.tag
-                    if copy_indirect_table_offset != 0 {
-                        expr_builder.i32_const(copy_indirect_table_offset as i32);
-                        expr_builder.i32_add();
-                    }
-                    expr_builder.call_indirect(
-                        wasm_module.insert_type_into(wasmgen::FuncType::new(
-                            Box::new([wasmgen::ValType::I64]),
-                            Box::new([wasmgen::ValType::I64]),
-                        )),
-                        tableidx,
-                    );
-                    expr_builder.i64_store(wasmgen::MemArg::new4(4)); // store f.data
-
-                    // gc_roots_it += 12;
-                    // net wasm stack: [] -> [gc_roots_it(i32)]
-                    expr_builder.local_get(localidx_gc_roots_it);
-                    expr_builder.i32_const(12);
-                    expr_builder.i32_add();
-                    expr_builder.local_tee(localidx_gc_roots_it);
-
-                    // do a conditional jump back
-                    // net wasm stack: [gc_roots_it(i32)] -> []
-                    expr_builder.local_get(localidx_gc_roots_stack_ptr);
-                    expr_builder.i32_ne();
-                    expr_builder.br_if(0);
-                }
-                expr_builder.end();
-            }
-            expr_builder.end();
-
-            // reload free_mem_ptr
-            // net wasm stack: [] -> []
-            expr_builder.global_get(globalidx_free_mem_ptr);
-            expr_builder.local_set(localidx_free_mem_ptr);
-
-            scratch.pop_i32();
-        }
-
-        {
-            // Pseudocode:
-            /*
-            while (scan != free_mem_ptr) {
-                scan = (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *scan))(scan+4);
-            }
-            */
-            // while loop turns into this:
-            /*
-            if scan != free_mem_ptr {
-                do {
-                    ...
-                } while (scan != free_mem_ptr);
-            }
-            */
-
-            // net wasm stack: [] -> [cond(i32)]
-            expr_builder.local_get(localidx_scan);
-            expr_builder.local_get(localidx_free_mem_ptr);
-            expr_builder.i32_ne();
-
-            // net wasm stack: [cond(i32)] -> []
-            expr_builder.if_(&[]);
-            {
-                // net wasm stack: [] -> []
-                expr_builder.loop_(&[]);
-                {
-                    // net wasm stack: [] -> [scan(i32)]
-                    expr_builder.local_get(localidx_scan);
-                    expr_builder.i32_const(4);
-                    expr_builder.i32_add();
-                    expr_builder.local_get(localidx_scan);
-                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
-                    if copy_children_table_offset != 0 {
-                        expr_builder.i32_const(copy_children_table_offset as i32);
-                        expr_builder.i32_add();
-                    }
-                    expr_builder.call_indirect(
-                        wasm_module.insert_type_into(wasmgen::FuncType::new(
-                            Box::new([wasmgen::ValType::I32]),
-                            Box::new([wasmgen::ValType::I32]),
-                        )),
-                        tableidx,
-                    );
-                    expr_builder.local_tee(localidx_scan);
-
-                    // need to reload free_mem_ptr from global, because the called function might have changed it.
-                    // net wasm stack: [scan(i32)] -> []
-                    expr_builder.global_get(globalidx_free_mem_ptr);
-                    expr_builder.local_tee(localidx_free_mem_ptr);
-                    expr_builder.i32_ne();
-                    expr_builder.br_if(0);
-                }
-                expr_builder.end();
-            }
-            expr_builder.end();
-        }
-
-        scratch.pop_i32();
-    }
-
     let functype = wasmgen::FuncType::new(
         Box::new([wasmgen::ValType::I32]),
         Box::new([wasmgen::ValType::I32]),
@@ -435,7 +87,10 @@ pub fn make_do_cheney<'a>(
                 localidx_gc_roots_stack_ptr,
                 copy_funcs,
                 global_var_manager,
+                globalidx_stackptr,
+                stack_end,
                 heap_begin,
+                None,
                 expr_builder,
                 &mut scratch,
             );
@@ -460,7 +115,7 @@ pub fn make_do_cheney<'a>(
         {
             let localidx_free_mem_ptr = scratch.push_i32();
 
-            let constant_base_mem_ptr: u32 = heap_begin << WASM_PAGE_BITS;
+            let constant_base_mem_ptr: u32 = space_begin << WASM_PAGE_BITS;
 
             // end_mem_ptr = (gc_roots_stack_base_ptr >> 1) + (base_mem_ptr >> 1);
             // net wasm stack: [] -> []
@@ -490,7 +145,10 @@ pub fn make_do_cheney<'a>(
                 localidx_gc_roots_stack_ptr,
                 copy_funcs,
                 global_var_manager,
+                globalidx_stackptr,
+                stack_end,
                 heap_begin,
+                None,
                 expr_builder,
                 &mut scratch,
             );
@@ -720,3 +378,543 @@ pub fn make_do_cheney<'a>(
     wasm_module.commit_func(func_idx, code_builder);
     func_idx
 }
+
+// Copies everything reachable from the roots, starting to copy at `localidx_free_mem_ptr`.
+// `copy_indirect_table_offset` and `copy_children_table_offset` should come from tables made with the same `heap_begin` and `copy_end`.
+// Guaranteed to synchronise localidx_free_mem_ptr and globalidx_free_mem_ptr before returning.
+// net wasm stack: [] -> []
+pub fn generate_common_portion<'a>(
+    wasm_module: &mut wasmgen::WasmModule,
+    tableidx: wasmgen::TableIdx,
+    copy_indirect_table_offset: u32,
+    copy_children_table_offset: u32,
+    globalidx_free_mem_ptr: wasmgen::GlobalIdx,
+    localidx_free_mem_ptr: wasmgen::LocalIdx,
+    localidx_gc_roots_stack_base_ptr: wasmgen::LocalIdx,
+    localidx_gc_roots_stack_ptr: wasmgen::LocalIdx,
+    copy_funcs: &[Option<wasmgen::FuncIdx>],
+    global_var_manager: GlobalVarManagerRef<'a>,
+    globalidx_stackptr: wasmgen::GlobalIdx,
+    stack_end: u32,
+    heap_begin: u32,
+    copy_end: Option<u32>,
+    expr_builder: &mut wasmgen::ExprBuilder,
+    scratch: &mut Scratch,
+) {
+    let localidx_scan = scratch.push_i32();
+
+    // let scan = free_mem_ptr;
+    // net wasm stack: [] -> []
+    expr_builder.local_get(localidx_free_mem_ptr);
+    expr_builder.local_set(localidx_scan);
+
+    // net wasm stack: [] -> []
+    {
+        // Pseudocode:
+        // for each global g {
+        //     copy_field_impl_$i(&mut g);
+        // }
+        for (ir_vartype, wasm_globalidxs) in global_var_manager {
+            // note: similar to copying struct fields in copy_children_elements()
+            match ir_vartype {
+                ir::VarType::Any => {
+                    // f.data = (*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + f.tag))(f.data);
+                    // net wasm stack: [] -> []
+                    expr_builder.global_get(wasm_globalidxs[1]); // the `data` of the Any
+                    expr_builder.global_get(wasm_globalidxs[0]); // the `tag` of the Any
+                    if copy_indirect_table_offset != 0 {
+                        expr_builder.i32_const(copy_indirect_table_offset as i32);
+                        expr_builder.i32_add();
+                    }
+                    expr_builder.call_indirect(
+                        wasm_module.insert_type_into(wasmgen::FuncType::new(
+                            Box::new([wasmgen::ValType::I64]),
+                            Box::new([wasmgen::ValType::I64]),
+                        )),
+                        tableidx,
+                    );
+                    expr_builder.global_set(wasm_globalidxs[1]); // store the `data` of the Any
+                }
+                ir::VarType::Unassigned => {}
+                ir::VarType::Undefined => {}
+                ir::VarType::Null => {}
+                ir::VarType::Number => {}
+                ir::VarType::Boolean => {}
+                ir::VarType::String => {
+                    // net wasm stack: [] -> []
+                    gen(
+                        expr_builder,
+                        scratch,
+                        wasm_globalidxs[0],
+                        tableidx,
+                        copy_funcs[ir::VarType::String.tag() as usize].unwrap(),
+                        heap_begin,
+                        copy_end,
+                        true,
+                    );
+                }
+                ir::VarType::Func => {
+                    /*
+                    if (f.closure != -1) {
+                        if (*(f.closure-4)) & I32_MIN {
+                            f.closure = (*(f.closure-4)) << 1; // we don't know the closure type, but we point to the new one anyway.
+                        } else {
+                            f.closure = i32_wrap_i64((*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + *(f.closure-4)))(i64_extend_i32(f.closure)));
+                        }
+                    }
+                    */
+                    let localidx_closure = scratch.push_i32(); // f.closure
+                    let localidx_val = scratch.push_i32(); // *(f.closure-4)
+
+                    // net wasm stack: [] -> [f.closure(i32)]
+                    expr_builder.global_get(wasm_globalidxs[1]); // the `closure` of the Func
+                    expr_builder.local_tee(localidx_closure);
+
+                    // net wasm stack: [closure(i32)] -> [cond(i32)]
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_ne();
+
+                    // net wasm stack: [cond(i32)] -> []
+                    expr_builder.if_(&[]);
+                    {
+                        // net wasm stack: [] -> [closure_minus_4(i32)]
+                        expr_builder.local_get(localidx_closure);
+                        expr_builder.i32_const(4);
+                        expr_builder.i32_sub();
+
+                        // net wasm stack: [closure_minus_4(i32)] -> [val(i32)]
+                        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                        expr_builder.local_tee(localidx_val);
+
+                        // net wasm stack: [val(i32)] -> [cond(i32)]
+                        expr_builder.i32_const(i32::min_value());
+                        expr_builder.i32_and();
+
+                        // net wasm stack: [cond(i32)] -> [new_closure(i32)]
+                        expr_builder.if_(&[wasmgen::ValType::I32]);
+                        expr_builder.local_get(localidx_val);
+                        expr_builder.i32_const(1);
+                        expr_builder.i32_shl();
+                        expr_builder.else_();
+                        expr_builder.local_get(localidx_closure);
+                        expr_builder.i64_extend_i32_u();
+                        expr_builder.local_get(localidx_val);
+                        if copy_indirect_table_offset != 0 {
+                            expr_builder.i32_const(copy_indirect_table_offset as i32);
+                            expr_builder.i32_add();
+                        }
+                        expr_builder.call_indirect(
+                            wasm_module.insert_type_into(wasmgen::FuncType::new(
+                                Box::new([wasmgen::ValType::I64]),
+                                Box::new([wasmgen::ValType::I64]),
+                            )),
+                            tableidx,
+                        );
+                        expr_builder.i32_wrap_i64();
+                        expr_builder.end();
+
+                        // net wasm stack: [new_closure(i32)] -> []
+                        expr_builder.global_set(wasm_globalidxs[1]);
+                    }
+                    expr_builder.end();
+
+                    scratch.pop_i32();
+                    scratch.pop_i32();
+                }
+                ir::VarType::Array => {
+                    // net wasm stack: [] -> []
+                    gen(
+                        expr_builder,
+                        scratch,
+                        wasm_globalidxs[0],
+                        tableidx,
+                        copy_funcs[ir::VarType::Array.tag() as usize].unwrap(),
+                        heap_begin,
+                        copy_end,
+                        false,
+                    );
+                }
+                ir::VarType::StructT { typeidx } => {
+                    // net wasm stack: [] -> []
+                    gen(
+                        expr_builder,
+                        scratch,
+                        wasm_globalidxs[0],
+                        tableidx,
+                        copy_funcs[ir::VarType::StructT { typeidx }.tag() as usize].unwrap(),
+                        heap_begin,
+                        copy_end,
+                        false,
+                    );
+                }
+            }
+        }
+
+        fn gen(
+            expr_builder: &mut wasmgen::ExprBuilder,
+            scratch: &mut Scratch,
+            wasm_globalidx: wasmgen::GlobalIdx,
+            tableidx: wasmgen::TableIdx,
+            copy_func: wasmgen::FuncIdx,
+            heap_begin: u32,
+            copy_end: Option<u32>,
+            is_string: bool,
+        ) {
+            /*
+            if (ptr != -1 && (f is not String || ptr > heap_begin * WASM_PAGE_SIZE) && (copy_end is None || ptr < copy_end * WASM_PAGE_SIZE)) {
+                if (*(ptr-4)) & I32_MIN { // already copied (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
+                    f.ptr = (*(ptr-4)) << 1; // we store the ptr in the tag, but shifted right by one bit position (valid since ptr are all multiple of 4)
+                } else {
+                    f.ptr = copy_${tag of f}(f.ptr);
+                }
+            }
+            */
+            let localidx_ptr = scratch.push_i32(); // from_any_data(data)
+            let localidx_val = scratch.push_i32(); // *(from_any_data(data)-4)
+
+            // net wasm stack: [] -> [ptr(i32)]
+            expr_builder.global_get(wasm_globalidx);
+            expr_builder.local_tee(localidx_ptr);
+
+            // net wasm stack: [ptr(i32)] -> [cond(i32)]
+            expr_builder.i32_const(-1);
+            expr_builder.i32_ne();
+            if is_string {
+                expr_builder.local_get(localidx_ptr);
+                expr_builder.i32_const((heap_begin << WASM_PAGE_BITS) as i32);
+                expr_builder.i32_gt_u();
+                expr_builder.i32_and();
+            }
+            if let Some(copy_end) = copy_end {
+                expr_builder.local_get(localidx_ptr);
+                expr_builder.i32_const((copy_end << WASM_PAGE_BITS) as i32);
+                expr_builder.i32_lt_u();
+                expr_builder.i32_and();
+            }
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                // net wasm stack: [] -> [ptr_minus_4(i32)]
+                expr_builder.local_get(localidx_ptr);
+                expr_builder.i32_const(4);
+                expr_builder.i32_sub();
+
+                // net wasm stack: [ptr_minus_4(i32)] -> [val(i32)]
+                expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                expr_builder.local_tee(localidx_val);
+
+                // net wasm stack: [val(i32)] -> [cond(i32)]
+                expr_builder.i32_const(i32::min_value());
+                expr_builder.i32_and();
+
+                // net wasm stack: [cond(i32)] -> [ret(i32)]
+                expr_builder.if_(&[wasmgen::ValType::I32]);
+                expr_builder.local_get(localidx_val);
+                expr_builder.i32_const(1);
+                expr_builder.i32_shl();
+                expr_builder.else_();
+                expr_builder.local_get(localidx_ptr);
+                expr_builder.call(copy_func);
+                expr_builder.end();
+
+                // net wasm stack: [ret(i32)] -> []
+                expr_builder.global_set(wasm_globalidx);
+            }
+            expr_builder.end();
+
+            scratch.pop_i32();
+            scratch.pop_i32();
+        }
+    }
+
+    // net wasm stack: [] -> []
+    {
+        let localidx_gc_roots_it = scratch.push_i32();
+
+        // net wasm stack: [] -> [gc_roots_it(i32)]
+        expr_builder.local_get(localidx_gc_roots_stack_base_ptr);
+        expr_builder.local_tee(localidx_gc_roots_it);
+
+        // while loop turns into this:
+        /*
+        if gc_roots_it != gc_roots_stack_ptr {
+            do {
+                ...
+            } while (gc_roots_it != gc_roots_stack_ptr);
+        }
+        */
+
+        // net wasm stack: [gc_roots_it(i32)] -> [cond(i32)]
+        expr_builder.local_get(localidx_gc_roots_stack_ptr);
+        expr_builder.i32_ne();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // net wasm stack: [] -> []
+            expr_builder.loop_(&[]);
+            {
+                // let f = *gc_roots_it;
+                // f.data = (*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + f.tag))(f.data);
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_gc_roots_it);
+                expr_builder.local_get(localidx_gc_roots_it);
+                expr_builder.i64_load(wasmgen::MemArg::new4(4)); // load f.data
+                expr_builder.local_get(localidx_gc_roots_it);
+                expr_builder.i32_load(wasmgen::MemArg::new4(0)); // load f.tag
+                if copy_indirect_table_offset != 0 {
+                    expr_builder.i32_const(copy_indirect_table_offset as i32);
+                    expr_builder.i32_add();
+                }
+                expr_builder.call_indirect(
+                    wasm_module.insert_type_into(wasmgen::FuncType::new(
+                        Box::new([wasmgen::ValType::I64]),
+                        Box::new([wasmgen::ValType::I64]),
+                    )),
+                    tableidx,
+                );
+                expr_builder.i64_store(wasmgen::MemArg::new4(4)); // store f.data
+
+                // gc_roots_it += 12;
+                // net wasm stack: [] -> [gc_roots_it(i32)]
+                expr_builder.local_get(localidx_gc_roots_it);
+                expr_builder.i32_const(12);
+                expr_builder.i32_add();
+                expr_builder.local_tee(localidx_gc_roots_it);
+
+                // do a conditional jump back
+                // net wasm stack: [gc_roots_it(i32)] -> []
+                expr_builder.local_get(localidx_gc_roots_stack_ptr);
+                expr_builder.i32_ne();
+                expr_builder.br_if(0);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        scratch.pop_i32();
+    }
+
+    // net wasm stack: [] -> []
+    {
+        // Pseudocode:
+        /*
+        let stack_it = stackptr;
+        while (stack_it != stack_end * WASM_PAGE_SIZE) {
+            stack_it = (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *stack_it))(stack_it+4);
+        }
+        */
+        // while loop turns into this:
+        /*
+        if stack_it != stack_end * WASM_PAGE_SIZE {
+            do {
+                ...
+            } while (stack_it != stack_end * WASM_PAGE_SIZE);
+        }
+        */
+        let localidx_stack_it = scratch.push_i32();
+
+        // net wasm stack: [] -> [cond(i32)]
+        expr_builder.global_get(globalidx_stackptr);
+        expr_builder.local_tee(localidx_stack_it);
+        expr_builder.i32_const((stack_end * WASM_PAGE_SIZE) as i32);
+        expr_builder.i32_ne();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // net wasm stack: [] -> []
+            expr_builder.loop_(&[]);
+            {
+                // net wasm stack: [] -> [stack_it(i32)]
+                expr_builder.local_get(localidx_stack_it);
+                expr_builder.i32_const(4);
+                expr_builder.i32_add();
+                expr_builder.local_get(localidx_stack_it);
+                expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                if copy_children_table_offset != 0 {
+                    expr_builder.i32_const(copy_children_table_offset as i32);
+                    expr_builder.i32_add();
+                }
+                expr_builder.call_indirect(
+                    wasm_module.insert_type_into(wasmgen::FuncType::new(
+                        Box::new([wasmgen::ValType::I32]),
+                        Box::new([wasmgen::ValType::I32]),
+                    )),
+                    tableidx,
+                );
+                expr_builder.local_tee(localidx_stack_it);
+
+                // net wasm stack: [stack_it(i32)] -> []
+                expr_builder.i32_const((stack_end * WASM_PAGE_SIZE) as i32);
+                expr_builder.i32_ne();
+                expr_builder.br_if(0);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        // reload free_mem_ptr
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_free_mem_ptr);
+        expr_builder.local_set(localidx_free_mem_ptr);
+
+        scratch.pop_i32();
+    }
+
+    {
+        // Pseudocode:
+        /*
+        while (scan != free_mem_ptr) {
+            scan = (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *scan))(scan+4);
+        }
+        */
+        // while loop turns into this:
+        /*
+        if scan != free_mem_ptr {
+            do {
+                ...
+            } while (scan != free_mem_ptr);
+        }
+        */
+
+        // net wasm stack: [] -> [cond(i32)]
+        expr_builder.local_get(localidx_scan);
+        expr_builder.local_get(localidx_free_mem_ptr);
+        expr_builder.i32_ne();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // net wasm stack: [] -> []
+            expr_builder.loop_(&[]);
+            {
+                // net wasm stack: [] -> [scan(i32)]
+                expr_builder.local_get(localidx_scan);
+                expr_builder.i32_const(4);
+                expr_builder.i32_add();
+                expr_builder.local_get(localidx_scan);
+                expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                if copy_children_table_offset != 0 {
+                    expr_builder.i32_const(copy_children_table_offset as i32);
+                    expr_builder.i32_add();
+                }
+                expr_builder.call_indirect(
+                    wasm_module.insert_type_into(wasmgen::FuncType::new(
+                        Box::new([wasmgen::ValType::I32]),
+                        Box::new([wasmgen::ValType::I32]),
+                    )),
+                    tableidx,
+                );
+                expr_builder.local_tee(localidx_scan);
+
+                // need to reload free_mem_ptr from global, because the called function might have changed it.
+                // net wasm stack: [scan(i32)] -> []
+                expr_builder.global_get(globalidx_free_mem_ptr);
+                expr_builder.local_tee(localidx_free_mem_ptr);
+                expr_builder.i32_ne();
+                expr_builder.br_if(0);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+    }
+
+    scratch.pop_i32();
+}
+
+// Encodes instructions to compute the number of bytes in the allocated space (i.e. the part of the semispace in use that is before `free_mem_ptr`).
+// This relies on the semispace in use ending at `end_mem_ptr` (see the invariant in gc::cheney::Cheney), and both semispaces having the same size.
+// `space_begin`: the same as in make_do_cheney().
+// net wasm stack: [] -> [i32(bytes)]
+pub fn encode_bytes_in_use(
+    globalidx_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_end_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_base_ptr: wasmgen::GlobalIdx,
+    space_begin: u32,
+    expr_builder: &mut wasmgen::ExprBuilder,
+) {
+    // free_mem_ptr - end_mem_ptr + ((gc_roots_stack_base_ptr - space_begin * WASM_PAGE_SIZE) >> 1)
+    expr_builder.global_get(globalidx_free_mem_ptr);
+    expr_builder.global_get(globalidx_end_mem_ptr);
+    expr_builder.i32_sub();
+    expr_builder.global_get(globalidx_gc_roots_stack_base_ptr);
+    expr_builder.i32_const((space_begin * WASM_PAGE_SIZE) as i32);
+    expr_builder.i32_sub();
+    expr_builder.i32_const(1);
+    expr_builder.i32_shr_u();
+    expr_builder.i32_add();
+}
+
+// Encodes do_cheney_with_stats(), which calls do_cheney() and updates the gc stats.
+// `counted_mem_ptr`: global that stores the free_mem_ptr up to which the bytes allocated have been added to the gc stats.
+// `verify_heap_funcidx`: if not None, the verify_heap() function (see verify_heap::make_verify_heap()) to call after do_cheney().
+// It has the same signature as do_cheney().
+pub fn make_do_cheney_with_stats(
+    wasm_module: &mut wasmgen::WasmModule,
+    do_cheney_funcidx: wasmgen::FuncIdx,
+    globalidx_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_end_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_base_ptr: wasmgen::GlobalIdx,
+    globalidx_counted_mem_ptr: wasmgen::GlobalIdx,
+    stats: &GcStats,
+    space_begin: u32,
+    verify_heap_funcidx: Option<wasmgen::FuncIdx>,
+) -> wasmgen::FuncIdx {
+    /*
+    fn do_cheney_with_stats(bytes_required: i32) -> i32 {
+        stats.bytes_allocated += free_mem_ptr - counted_mem_ptr;
+        let ret = do_cheney(bytes_required);
+        if constexpr (verify_heap_funcidx is not None) verify_heap();
+        stats.collections += 1;
+        stats.bytes_surviving = bytes_in_use();
+        stats.heap_grown(); // do_cheney() might have grown the memory
+        counted_mem_ptr = free_mem_ptr;
+        return ret;
+    }
+    */
+
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32]),
+        Box::new([wasmgen::ValType::I32]),
+    );
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (_locals_builder, expr_builder) = code_builder.split();
+        let localidx_bytes_required = wasmgen::LocalIdx { idx: 0 };
+
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_free_mem_ptr);
+        expr_builder.global_get(globalidx_counted_mem_ptr);
+        expr_builder.i32_sub();
+        stats.encode_add_allocated(expr_builder);
+
+        // net wasm stack: [] -> [ret(i32)]
+        expr_builder.local_get(localidx_bytes_required);
+        expr_builder.call(do_cheney_funcidx);
+
+        // net wasm stack: [ret(i32)] -> [ret(i32)]
+        if let Some(verify_heap_funcidx) = verify_heap_funcidx {
+            expr_builder.call(verify_heap_funcidx);
+        }
+
+        // net wasm stack: [ret(i32)] -> [ret(i32)]
+        encode_bytes_in_use(
+            globalidx_free_mem_ptr,
+            globalidx_end_mem_ptr,
+            globalidx_gc_roots_stack_base_ptr,
+            space_begin,
+            expr_builder,
+        );
+        stats.encode_collection(expr_builder);
+        stats.encode_heap_grown(expr_builder);
+        expr_builder.global_get(globalidx_free_mem_ptr);
+        expr_builder.global_set(globalidx_counted_mem_ptr);
+
+        expr_builder.end(); // return ret
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
@@ -1,3 +1,4 @@
+use super::GcStats;
 use super::HeapManager;
 use super::WASM_PAGE_BITS;
 use super::WASM_PAGE_SIZE;
@@ -5,10 +6,11 @@ use crate::global_var::GlobalVarManagerRef;
 use crate::var_conv::*;
 use wasmgen::Scratch;
 
-mod copy_children_elements;
-mod copy_funcs;
-mod copy_indirect_elements;
-mod do_cheney;
+pub(super) mod copy_children_elements;
+pub(super) mod copy_funcs;
+pub(super) mod copy_indirect_elements;
+pub(super) mod do_cheney;
+pub(super) mod verify_heap;
 
 #[cfg(feature = "wasmtest")]
 pub mod wasmtest;
@@ -31,6 +33,13 @@ pub mod wasmtest;
  * After a GC run that changes the heap from higher-half to lower-half, the algorithm will check if (free_space >= allocated_space).
  * * If not, it will grow the memory and move gc_roots rightward in order to ensure (free_space >= allocated_space).
  * When allocating memory, a tag is placed at *(ptr-4) to specify the type of content being contained there.  It is used by the BFS in do_cheney() to call indirectly the correct function.
+ * The buffer that holds the elements of an array is given the tag (NUM_PRIMITIVE_TAG_TYPES + struct_types.len()), which is not a VarType::tag(), since the buffer can only be referenced from its array header.
+ *
+ * Closure environments that don't escape may be allocated on the stack instead (see encode_stack_allocation()), with a tag too.
+ * They are never copied, but the GC walks the objects in [stackptr, stack_end) to copy their children.
+ * Nothing on the heap points to them, so only the gc_roots might point to them.
+ *
+ * For debugging the GC, new() can be asked to check the heap after every collection, which traps with ERROR_CODE_HEAP_VERIFICATION if it finds a dangling pointer or an unknown tag.
  *
  * Two functions will be generated for each type:
  * * Direct function
@@ -48,14 +57,18 @@ pub struct Cheney<'a, 'b, 'c> {
     gc_roots_stack_base_ptr: wasmgen::GlobalIdx, // Global that stores pointer to beginning of gc_roots stack
     gc_roots_stack_ptr: wasmgen::GlobalIdx, // Global that stores pointer to past-the-end of gc_roots stack
     heap_begin: u32,                        // in page units
-    do_cheney_funcidx: wasmgen::FuncIdx,    // funcidx of do_cheney() function
-    error_func: wasmgen::FuncIdx,           // function to call when out of memory
+    do_cheney_funcidx: wasmgen::FuncIdx, // funcidx of do_cheney_with_stats() function (which calls do_cheney())
+    error_func: wasmgen::FuncIdx,        // function to call when out of memory
+    stats: GcStats,                      // counters for the gc stats
+    counted_mem_ptr: wasmgen::GlobalIdx, // Global that stores the free_mem_ptr up to which the bytes allocated have been counted in `stats`
 }
 
-// Note: Currently  MEM_INITIAL_USABLE_SIZE * 2 should be at least as large as the gc_roots size (1 << 4).
+// Note: Currently  MEM_INITIAL_USABLE_SIZE * 2 should be at least as large as the gc_roots size (GC_ROOTS_SIZE, plus one leftover page).
 //   Otherwise, we must rewrite the part in do_cheney() to move the gc_stack with move_backward() instead of move().
-const MEM_INITIAL_USABLE_SIZE: u32 = 1 << 4; // the allocated_space+free_space
-const MEM_INITIAL_HEAP_SIZE: u32 = MEM_INITIAL_USABLE_SIZE * 2 + (1 << 4); // 2 MiB of initial heap space (1 MiB usable at a time) and 1 MiB of gc_roots stack space
+// A larger initial heap (see `heap_initial_end` in new()) only increases the usable size, since the gc_roots stack never needs more space.
+const GC_ROOTS_SIZE: u32 = 1 << 4; // 1 MiB of gc_roots stack space
+const MEM_INITIAL_USABLE_SIZE: u32 = 1 << 4; // the allocated_space+free_space (for the smallest initial heap)
+const MEM_INITIAL_HEAP_SIZE: u32 = MEM_INITIAL_USABLE_SIZE * 2 + GC_ROOTS_SIZE; // 2 MiB of initial heap space (1 MiB usable at a time) and 1 MiB of gc_roots stack space
 
 impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
     // Constructs a new Cheney GC, and initializes it appropriately.
@@ -65,13 +78,16 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
         struct_field_byte_offsets: &'b [Box<[u32]>],
         struct_sizes: &'c [u32],
         memidx: wasmgen::MemIdx,
+        stackptr: wasmgen::GlobalIdx, // stores the stackptr, objects in [stackptr, stack_end) are gc roots too
+        stack_end: u32,               // in page units
         heap_begin: u32,
         heap_initial_end: u32,
         global_var_manager: GlobalVarManagerRef<'d>, // stores global vars that are gc roots too
         error_func: wasmgen::FuncIdx,
+        verify_heap: bool, // whether to check the heap after every collection (see verify_heap::make_verify_heap())
         wasm_module: &mut wasmgen::WasmModule,
     ) -> Self {
-        assert!(heap_begin + MEM_INITIAL_HEAP_SIZE == heap_initial_end);
+        assert!(heap_begin + MEM_INITIAL_HEAP_SIZE <= heap_initial_end);
         /*
         // copy_children_$i shall only exist for pointer types (i.e. types that reside on heap), i.e. StructT or String.
         Encoded function (for struct):
@@ -118,16 +134,16 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
                         return make_func(f.idx, i32_wrap_i64((*(GC_TABLE_PTR_COPY_INDIRECT_OFFSET + *(f.closure-4)))(i64_extend_i32(f.closure))));
                     }
                 } else return make_func(f.idx, -1);
-            } else if constexpr $i is not a ptr (i.e. not StructT or string) {
+            } else if constexpr $i is not a ptr (i.e. not StructT, Array or String) {
                 // NO-OP
             } else {
-                if (ptr != -1 && (f is not String || ptr > heap_begin * WASM_PAGE_SIZE)) { // '-1' means not yet assigned pointer, 'ptr <= heap_begin' means it is from global data or unprotected stack (if ptr == heap_begin then it is a zero-sized type that is not GC'ed).
+                if (ptr != -1 && (f is not String or StructT || ptr > heap_begin * WASM_PAGE_SIZE)) { // '-1' means not yet assigned pointer, 'ptr <= heap_begin' means it is from global data or the stack (if ptr == heap_begin then it is a zero-sized type that is not GC'ed).
                     if (*(ptr-4)) & I32_MIN { // already copied (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
                         return to_any_data((*(ptr-4)) << 1); // we store the ptr in the tag, but shifted right by one bit position (valid since ptr are all multiple of 4)
                     } else {
                         return to_any_data(copy_$i(from_any_data(data)));
                     }
-                } else return -1;
+                } else return data;
             }
         }
         // copy_$i shall only exist for pointer types (i.e. types that reside on heap), i.e. StructT or String.
@@ -164,6 +180,12 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
                     gc_roots_it += 12; // 12 is the size of Any
                 }
 
+                // objects on the stack are roots too (they are never copied, but their children are)
+                let stack_it = stackptr;
+                while (stack_it != stack_end * WASM_PAGE_SIZE) {
+                    stack_it = (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *stack_it))(stack_it+4);
+                }
+
                 // note: must reload free_mem_ptr after every iteration, it is modified in the function call
                 while (scan != free_mem_ptr) {
                     scan = (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *scan))(scan+4);
@@ -193,6 +215,12 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
                     gc_roots_it += 12; // 12 is the size of Any
                 }
 
+                // objects on the stack are roots too (they are never copied, but their children are)
+                let stack_it = stackptr;
+                while (stack_it != stack_end * WASM_PAGE_SIZE) {
+                    stack_it = (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *stack_it))(stack_it+4);
+                }
+
                 // note: must reload free_mem_ptr every iteration, it is modified in the function call
                 while (scan != free_mem_ptr) {
                     scan = (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *scan))(scan+4);
@@ -229,25 +257,28 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
         Any does not have copy_$i and copy_indirect_$i (since copy_indirect_$i is suppose to indirectly determine the type of the any)
         */
 
+        // if the number of pages left after the gc_roots stack is odd, the extra page goes to the gc_roots stack
+        let initial_usable_size: u32 = (heap_initial_end - heap_begin - GC_ROOTS_SIZE) >> 1;
+
         let free_mem_ptr: wasmgen::GlobalIdx =
             wasm_module.add_i32_global(wasmgen::Mut::Var, (heap_begin * WASM_PAGE_SIZE) as i32);
         let end_mem_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(
             wasmgen::Mut::Var,
-            ((heap_begin + MEM_INITIAL_USABLE_SIZE) * WASM_PAGE_SIZE) as i32,
+            ((heap_begin + initial_usable_size) * WASM_PAGE_SIZE) as i32,
         );
         let gc_roots_stack_base_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(
             wasmgen::Mut::Var,
-            ((heap_begin + MEM_INITIAL_USABLE_SIZE * 2) * WASM_PAGE_SIZE) as i32,
+            ((heap_begin + initial_usable_size * 2) * WASM_PAGE_SIZE) as i32,
         );
         let gc_roots_stack_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(
             wasmgen::Mut::Var,
-            ((heap_begin + MEM_INITIAL_USABLE_SIZE * 2) * WASM_PAGE_SIZE) as i32,
+            ((heap_begin + initial_usable_size * 2) * WASM_PAGE_SIZE) as i32,
         );
 
         // copy_$i functions, indexed by VarType::tag().
         let copy_funcs: Box<[Option<wasmgen::FuncIdx>]> =
             copy_funcs::make_copy_funcs(wasm_module, struct_sizes, free_mem_ptr);
-        assert!(copy_funcs.len() == ir::NUM_PRIMITIVE_TAG_TYPES + struct_sizes.len());
+        assert!(copy_funcs.len() == ir::NUM_PRIMITIVE_TAG_TYPES + struct_sizes.len() + 1);
 
         let tableidx: wasmgen::TableIdx = wasm_module.get_or_add_table();
 
@@ -257,6 +288,7 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
             &copy_funcs,
             tableidx,
             heap_begin,
+            None,
         );
 
         let copy_children_table_offset: u32 = copy_children_elements::make_copy_children_elements(
@@ -268,6 +300,7 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
             copy_indirect_table_offset,
             &copy_funcs,
             heap_begin,
+            None,
         );
 
         let do_cheney_funcidx: wasmgen::FuncIdx = do_cheney::make_do_cheney(
@@ -282,7 +315,46 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
             gc_roots_stack_ptr,
             &copy_funcs,
             global_var_manager,
+            stackptr,
+            stack_end,
+            heap_begin,
+            heap_begin,
+        );
+
+        let stats: GcStats = GcStats::new(memidx, heap_begin, heap_initial_end, wasm_module);
+        let counted_mem_ptr: wasmgen::GlobalIdx =
+            wasm_module.add_i32_global(wasmgen::Mut::Var, (heap_begin * WASM_PAGE_SIZE) as i32);
+
+        let verify_heap_funcidx: Option<wasmgen::FuncIdx> = if verify_heap {
+            Some(verify_heap::make_verify_heap(
+                wasm_module,
+                struct_types,
+                struct_field_byte_offsets,
+                struct_sizes,
+                free_mem_ptr,
+                end_mem_ptr,
+                gc_roots_stack_base_ptr,
+                gc_roots_stack_ptr,
+                stackptr,
+                stack_end,
+                heap_begin,
+                heap_begin,
+                error_func,
+            ))
+        } else {
+            None
+        };
+
+        let do_cheney_with_stats_funcidx: wasmgen::FuncIdx = do_cheney::make_do_cheney_with_stats(
+            wasm_module,
+            do_cheney_funcidx,
+            free_mem_ptr,
+            end_mem_ptr,
+            gc_roots_stack_base_ptr,
+            counted_mem_ptr,
+            &stats,
             heap_begin,
+            verify_heap_funcidx,
         );
 
         Cheney {
@@ -295,8 +367,10 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
             gc_roots_stack_base_ptr: gc_roots_stack_base_ptr,
             gc_roots_stack_ptr: gc_roots_stack_ptr,
             heap_begin: heap_begin,
-            do_cheney_funcidx: do_cheney_funcidx,
+            do_cheney_funcidx: do_cheney_with_stats_funcidx,
             error_func: error_func,
+            stats,
+            counted_mem_ptr,
         }
     }
 
@@ -312,7 +386,8 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
                 ir::VarType::Unassigned
                 | ir::VarType::Undefined
                 | ir::VarType::Number
-                | ir::VarType::Boolean => false,
+                | ir::VarType::Boolean
+                | ir::VarType::Null => false,
                 _ => true,
             })
             .collect()
@@ -430,6 +505,43 @@ impl<'a, 'b, 'c> Cheney<'a, 'b, 'c> {
         expr_builder.i32_const(4);
         expr_builder.i32_add();
     }
+
+    // Helper function to write Undefined to all Any fields in the struct
+    // and write nullptr (i.e. -1) to all String, Func::closure, StructT
+    // todo!: String should eventually be set to an empty string in the constant string pool.... on not?
+    // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+    fn encode_struct_init(
+        &self,
+        typeidx: usize,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
+        expr_builder.local_tee(localidx_ptr);
+        self.struct_types[typeidx]
+            .iter()
+            .zip(self.struct_field_byte_offsets[typeidx].iter())
+            .for_each(|(ir_vartype, byte_offset)| match ir_vartype {
+                ir::VarType::Any => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(ir::VarType::Unassigned.tag());
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
+                }
+                ir::VarType::String | ir::VarType::Array | ir::VarType::StructT { typeidx: _ } => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
+                }
+                ir::VarType::Func => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset + 4));
+                    // Note: "+4" above to access the closure
+                }
+                _ => {}
+            });
+        scratch.pop_i32();
+    }
 }
 
 fn wasm_local_slice<'a>(
@@ -441,7 +553,7 @@ fn wasm_local_slice<'a>(
 }
 
 impl<'a, 'b, 'c> HeapManager for Cheney<'a, 'b, 'c> {
-    // Returns the initial number of pages required by this heap HeapManager.
+    // Returns the minimum initial number of pages required by this heap HeapManager (which is also the default).
     fn initial_heap_size() -> u32 {
         MEM_INITIAL_HEAP_SIZE
     }
@@ -476,37 +588,75 @@ impl<'a, 'b, 'c> HeapManager for Cheney<'a, 'b, 'c> {
                     expr_builder,
                 );
 
-                // Write Undefined to all Any fields in the struct
-                // and write nullptr (i.e. -1) to all String, Func::closure, StructT
-                // todo!: String should eventually be set to an empty string in the constant string pool.... on not?
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
+            }
+            ir::VarType::Array => {
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.i32_const(8 + 4);
+                    },
+                    ir_vartype.tag(),
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // Write an empty array header (len = 0, buf = nullptr)
                 // net wasm stack: [i32(ptr)] -> [i32(ptr)]
                 {
                     let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
                     expr_builder.local_tee(localidx_ptr);
-                    self.struct_types[typeidx]
-                        .iter()
-                        .zip(self.struct_field_byte_offsets[typeidx].iter())
-                        .for_each(|(ir_vartype, byte_offset)| match ir_vartype {
-                            ir::VarType::Any => {
-                                expr_builder.local_get(localidx_ptr);
-                                expr_builder.i32_const(ir::VarType::Unassigned.tag());
-                                expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
-                            }
-                            ir::VarType::String | ir::VarType::StructT { typeidx: _ } => {
-                                expr_builder.local_get(localidx_ptr);
-                                expr_builder.i32_const(-1);
-                                expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
-                            }
-                            ir::VarType::Func => {
-                                expr_builder.local_get(localidx_ptr);
-                                expr_builder.i32_const(-1);
-                                expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset + 4));
-                                // Note: "+4" above to access the closure
-                            }
-                            _ => {}
-                        });
+                    expr_builder.i32_const(0);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(4));
+                    expr_builder.local_get(localidx_ptr);
+                    scratch.pop_i32();
+                }
+            }
+            _ => panic!("incorrect VarType, expected StructT or Array"),
+        }
+    }
+
+    // Returns the number of bytes needed to initialize the given struct type on the stack (including the tag).
+    fn stack_allocation_size(&self, ir_vartype: ir::VarType) -> u32 {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => self.struct_sizes[typeidx] + 4,
+            _ => panic!("incorrect VarType, expected StructT"),
+        }
+    }
+
+    // Encodes instructions to initialize the given struct type in a chunk of memory on the stack.
+    // The tag is written like for heap objects, so that the GC can walk the stack to find the children of these objects.
+    // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+    fn encode_stack_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => {
+                // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+                {
+                    let localidx_chunk: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_chunk);
+                    expr_builder.i32_const(ir_vartype.tag());
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_chunk);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_add();
                     scratch.pop_i32();
                 }
+
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
             }
             _ => panic!("incorrect VarType, expected StructT"),
         }
@@ -569,7 +719,45 @@ impl<'a, 'b, 'c> HeapManager for Cheney<'a, 'b, 'c> {
                 scratch.pop_i32();
                 scratch.pop_i32();
             }
-            _ => panic!("incorrect VarType, expected String"),
+            ir::VarType::Array => {
+                // this is the buffer of elements, not the array header
+                let localidx_num_bytes: wasmgen::LocalIdx = scratch.push_i32();
+
+                // net wasm stack: [i32(num_bytes)] -> []
+                expr_builder.local_set(localidx_num_bytes);
+
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.local_get(localidx_num_bytes);
+                        expr_builder.i32_const(8);
+                        expr_builder.i32_add();
+                    },
+                    (ir::NUM_PRIMITIVE_TAG_TYPES + self.struct_sizes.len()) as i32, // the array buffer tag
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // write the capacity
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ret);
+                    expr_builder.local_get(localidx_ret);
+                    expr_builder.local_get(localidx_num_bytes);
+                    expr_builder.i32_const(size_in_memory(ir::VarType::Any) as i32);
+                    expr_builder.i32_div_u();
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    scratch.pop_i32();
+                }
+
+                scratch.pop_i32();
+            }
+            _ => panic!("incorrect VarType, expected String or Array"),
         }
     }
 
@@ -709,6 +897,31 @@ impl<'a, 'b, 'c> HeapManager for Cheney<'a, 'b, 'c> {
         }
     }
 
+    // Cheney traces the whole heap on every collection, so it doesn't need a write barrier.
+    // net wasm stack: [] -> []
+    fn encode_write_barrier<F: FnOnce(&mut wasmgen::ExprBuilder)>(
+        &self,
+        _ir_vartype: ir::VarType,
+        _encode_obj: F,
+        _scratch: &mut Scratch,
+        _expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+    }
+
+    // The bytes allocated since the last collection are counted from free_mem_ptr.
+    fn encode_write_stats(&self, ptr: u32, expr_builder: &mut wasmgen::ExprBuilder) {
+        self.stats.encode_write(
+            ptr,
+            |expr_builder| {
+                // net wasm stack: [] -> [i32(num_bytes)]
+                expr_builder.global_get(self.free_mem_ptr);
+                expr_builder.global_get(self.counted_mem_ptr);
+                expr_builder.i32_sub();
+            },
+            expr_builder,
+        );
+    }
+
     // Encodes instructions to initialize locals that could potentially go onto the gc_roots stack.
     // `local_types` and `local_map` should have equal length, containing just those locals that should be initialized.
     // `wasm_local_map` should not be sliced by the caller, because we need to preserve the indexing so that `local_map` will refer to the correct indices in `wasm_local_map`.
@@ -724,7 +937,7 @@ impl<'a, 'b, 'c> HeapManager for Cheney<'a, 'b, 'c> {
             local_types.iter().copied().zip(local_map.iter().copied())
         {
             match ir_vartype {
-                ir::VarType::String | ir::VarType::StructT { typeidx: _ } => {
+                ir::VarType::String | ir::VarType::Array | ir::VarType::StructT { typeidx: _ } => {
                     expr_builder.i32_const(-1);
                     expr_builder.local_set(wasm_local_map[wasm_local_map_index]);
                 }
@@ -0,0 +1,1050 @@
+use wasmgen::Scratch;
+
+use super::WASM_PAGE_BITS;
+use super::WASM_PAGE_SIZE;
+
+// Encodes verify_heap(), which checks that the heap is consistent after do_cheney() has returned, and raises ERROR_CODE_HEAP_VERIFICATION otherwise.
+// It is only meant for debugging the GC, since it walks the whole semispace in use after every collection.
+// The following are checked:
+// * Every object in the semispace in use (i.e. the semispace that do_cheney() copied to) has a tag of a heap object (String, Array, StructT or the array buffer).
+// * Every pointer in those objects, in the objects on the stack, and in the gc_roots stack lands on the start of an object in the semispace in use
+//   (so it does not refer to the other semispace, which is garbage after the collection), and that object has the tag required by the type of the pointer.
+//   Pointers that are -1 (unassigned) or at most heap_begin * WASM_PAGE_SIZE (global data, the stack, or zero-sized structs) are not checked.
+// * Every Any (including those in the gc_roots stack) has a tag that is a VarType::tag().
+// The offending pointer or tag is passed as the `detail` of the error.
+// The semispace that is not in use is overwritten (it is used as a bitmap that marks the start of each object), which is fine since it is garbage until the next collection.
+// `space_begin`: the same as in make_do_cheney().
+pub fn make_verify_heap(
+    wasm_module: &mut wasmgen::WasmModule,
+    struct_types: &[Box<[ir::VarType]>],
+    struct_field_byte_offsets: &[Box<[u32]>],
+    struct_sizes: &[u32],
+    globalidx_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_end_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_base_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_ptr: wasmgen::GlobalIdx,
+    globalidx_stackptr: wasmgen::GlobalIdx,
+    stack_end: u32,
+    heap_begin: u32,
+    space_begin: u32,
+    error_func: wasmgen::FuncIdx,
+) -> wasmgen::FuncIdx {
+    /*
+    fn verify_heap() {
+        let space_size = (gc_roots_stack_base_ptr - space_begin * WASM_PAGE_SIZE) >> 1;
+        space_ptr = end_mem_ptr - space_size; // rmb to write back to global
+        // the semispace not in use is garbage until the next collection, so we use it for the bitmap (which needs one bit for every 4 bytes)
+        bitmap_ptr = space_ptr == space_begin * WASM_PAGE_SIZE ? end_mem_ptr : space_begin * WASM_PAGE_SIZE; // rmb to write back to global
+
+        // clear the bitmap
+        let it = bitmap_ptr;
+        let it_end = bitmap_ptr + (space_size >> 5);
+        do {
+            *it = 0;
+            it += 4;
+        } while (it != it_end);
+
+        // mark the start of every object
+        let scan = space_ptr;
+        while (scan != free_mem_ptr) {
+            let tag = *scan;
+            if (tag != String && (tag < Array || tag > array_buffer_tag)) fail(tag);
+            mark(scan + 4);
+            scan = (*(object_end_table_offset + tag))(scan + 4);
+            if (scan > free_mem_ptr) fail(scan);
+        }
+
+        // check the children of every object
+        let scan = space_ptr;
+        while (scan != free_mem_ptr) {
+            scan = (*(verify_children_table_offset + *scan))(scan + 4);
+        }
+
+        // check the children of the objects on the stack
+        let stack_it = stackptr;
+        while (stack_it != stack_end * WASM_PAGE_SIZE) {
+            stack_it = (*(verify_children_table_offset + *stack_it))(stack_it + 4);
+        }
+
+        // check the gc roots
+        let gc_roots_it = gc_roots_stack_base_ptr;
+        while (gc_roots_it != gc_roots_stack_ptr) {
+            verify_any(gc_roots_it->tag, gc_roots_it->data);
+            gc_roots_it += 12; // 12 is the size of Any
+        }
+    }
+    */
+
+    let globalidx_space_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(wasmgen::Mut::Var, 0);
+    let globalidx_bitmap_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(wasmgen::Mut::Var, 0);
+
+    let array_buffer_tag: u32 = (ir::NUM_PRIMITIVE_TAG_TYPES + struct_types.len()) as u32;
+
+    let fail_funcidx: wasmgen::FuncIdx = make_fail_func(wasm_module, error_func);
+    let verify_ptr_funcidx: wasmgen::FuncIdx = make_verify_ptr(
+        wasm_module,
+        globalidx_free_mem_ptr,
+        globalidx_space_ptr,
+        globalidx_bitmap_ptr,
+        array_buffer_tag,
+        heap_begin,
+        fail_funcidx,
+    );
+    let verify_any_funcidx: wasmgen::FuncIdx = make_verify_any(
+        wasm_module,
+        verify_ptr_funcidx,
+        array_buffer_tag,
+        fail_funcidx,
+    );
+
+    let tableidx: wasmgen::TableIdx = wasm_module.get_or_add_table();
+    let object_end_table_offset: u32 =
+        make_object_end_elements(wasm_module, struct_sizes, tableidx);
+    let verify_children_table_offset: u32 = make_verify_children_elements(
+        wasm_module,
+        struct_types,
+        struct_field_byte_offsets,
+        struct_sizes,
+        tableidx,
+        verify_ptr_funcidx,
+        verify_any_funcidx,
+    );
+
+    let functype = wasmgen::FuncType::new(Box::new([]), Box::new([]));
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let mut scratch = Scratch::new(locals_builder);
+
+        let constant_base_mem_ptr: u32 = space_begin << WASM_PAGE_BITS;
+        let object_functype = wasm_module.insert_type_into(wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        ));
+
+        let localidx_space_size = scratch.push_i32();
+
+        // let space_size = (gc_roots_stack_base_ptr - base_mem_ptr) >> 1;
+        // space_ptr = end_mem_ptr - space_size;
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_end_mem_ptr);
+        expr_builder.global_get(globalidx_gc_roots_stack_base_ptr);
+        expr_builder.i32_const(constant_base_mem_ptr as i32);
+        expr_builder.i32_sub();
+        expr_builder.i32_const(1);
+        expr_builder.i32_shr_u();
+        expr_builder.local_tee(localidx_space_size);
+        expr_builder.i32_sub();
+        expr_builder.global_set(globalidx_space_ptr);
+
+        // bitmap_ptr = space_ptr == base_mem_ptr ? end_mem_ptr : base_mem_ptr;
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_end_mem_ptr);
+        expr_builder.i32_const(constant_base_mem_ptr as i32);
+        expr_builder.global_get(globalidx_space_ptr);
+        expr_builder.i32_const(constant_base_mem_ptr as i32);
+        expr_builder.i32_eq();
+        expr_builder.select();
+        expr_builder.global_set(globalidx_bitmap_ptr);
+
+        // clear the bitmap
+        // (the semispace is a whole number of pages, so the bitmap is never empty)
+        // net wasm stack: [] -> []
+        {
+            let localidx_it = scratch.push_i32();
+            let localidx_it_end = scratch.push_i32();
+
+            // let it = bitmap_ptr;
+            // let it_end = bitmap_ptr + (space_size >> 5);
+            // net wasm stack: [] -> []
+            expr_builder.global_get(globalidx_bitmap_ptr);
+            expr_builder.local_tee(localidx_it);
+            expr_builder.local_get(localidx_space_size);
+            expr_builder.i32_const(5);
+            expr_builder.i32_shr_u();
+            expr_builder.i32_add();
+            expr_builder.local_set(localidx_it_end);
+
+            expr_builder.loop_(&[]);
+            {
+                // *it = 0;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_it);
+                expr_builder.i32_const(0);
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+
+                // it += 4;
+                // net wasm stack: [] -> [it(i32)]
+                expr_builder.local_get(localidx_it);
+                expr_builder.i32_const(4);
+                expr_builder.i32_add();
+                expr_builder.local_tee(localidx_it);
+
+                // while (it != it_end);
+                // net wasm stack: [it(i32)] -> []
+                expr_builder.local_get(localidx_it_end);
+                expr_builder.i32_ne();
+                expr_builder.br_if(0);
+            }
+            expr_builder.end();
+
+            scratch.pop_i32();
+            scratch.pop_i32();
+        }
+
+        scratch.pop_i32();
+
+        // mark the start of every object
+        // net wasm stack: [] -> []
+        {
+            let localidx_scan = scratch.push_i32();
+            let localidx_tag = scratch.push_i32();
+
+            // while loop turns into this:
+            /*
+            if (scan != free_mem_ptr) {
+                do {
+                    ...
+                } while (scan != free_mem_ptr);
+            }
+            */
+
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.global_get(globalidx_space_ptr);
+            expr_builder.local_tee(localidx_scan);
+            expr_builder.global_get(globalidx_free_mem_ptr);
+            expr_builder.i32_ne();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // let tag = *scan;
+                    // if (tag != String && (tag < Array || tag > array_buffer_tag)) fail(tag);
+                    // we actually do (tag != String && (tag - Array) > (array_buffer_tag - Array)), using unsigned comparison
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_scan);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    expr_builder.local_tee(localidx_tag);
+                    expr_builder.i32_const(ir::VarType::String.tag());
+                    expr_builder.i32_ne();
+                    expr_builder.local_get(localidx_tag);
+                    expr_builder.i32_const(ir::VarType::Array.tag());
+                    expr_builder.i32_sub();
+                    expr_builder.i32_const(array_buffer_tag as i32 - ir::VarType::Array.tag());
+                    expr_builder.i32_gt_u();
+                    expr_builder.i32_and();
+                    expr_builder.if_(&[]);
+                    expr_builder.local_get(localidx_tag);
+                    expr_builder.call(fail_funcidx);
+                    expr_builder.end();
+
+                    // mark(scan + 4);
+                    // net wasm stack: [] -> []
+                    encode_mark(
+                        localidx_scan,
+                        globalidx_space_ptr,
+                        globalidx_bitmap_ptr,
+                        &mut scratch,
+                        expr_builder,
+                    );
+
+                    // scan = (*(object_end_table_offset + tag))(scan + 4);
+                    // net wasm stack: [] -> [scan(i32)]
+                    expr_builder.local_get(localidx_scan);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_add();
+                    expr_builder.local_get(localidx_tag);
+                    if object_end_table_offset != 0 {
+                        expr_builder.i32_const(object_end_table_offset as i32);
+                        expr_builder.i32_add();
+                    }
+                    expr_builder.call_indirect(object_functype, tableidx);
+                    expr_builder.local_tee(localidx_scan);
+
+                    // if (scan > free_mem_ptr) fail(scan);
+                    // net wasm stack: [scan(i32)] -> []
+                    expr_builder.global_get(globalidx_free_mem_ptr);
+                    expr_builder.i32_gt_u();
+                    expr_builder.if_(&[]);
+                    expr_builder.local_get(localidx_scan);
+                    expr_builder.call(fail_funcidx);
+                    expr_builder.end();
+
+                    // while (scan != free_mem_ptr);
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_scan);
+                    expr_builder.global_get(globalidx_free_mem_ptr);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+
+            scratch.pop_i32();
+            scratch.pop_i32();
+        }
+
+        // check the children of every object, and then of the objects on the stack
+        // net wasm stack: [] -> []
+        encode_verify_objects(
+            |expr_builder| expr_builder.global_get(globalidx_space_ptr),
+            |expr_builder| expr_builder.global_get(globalidx_free_mem_ptr),
+            tableidx,
+            verify_children_table_offset,
+            object_functype,
+            &mut scratch,
+            expr_builder,
+        );
+        encode_verify_objects(
+            |expr_builder| expr_builder.global_get(globalidx_stackptr),
+            |expr_builder| expr_builder.i32_const((stack_end * WASM_PAGE_SIZE) as i32),
+            tableidx,
+            verify_children_table_offset,
+            object_functype,
+            &mut scratch,
+            expr_builder,
+        );
+
+        // check the gc roots
+        // net wasm stack: [] -> []
+        {
+            let localidx_gc_roots_it = scratch.push_i32();
+
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.global_get(globalidx_gc_roots_stack_base_ptr);
+            expr_builder.local_tee(localidx_gc_roots_it);
+            expr_builder.global_get(globalidx_gc_roots_stack_ptr);
+            expr_builder.i32_ne();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // verify_any(gc_roots_it->tag, gc_roots_it->data);
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_gc_roots_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_gc_roots_it);
+                    expr_builder.i64_load(wasmgen::MemArg::new4(4));
+                    expr_builder.call(verify_any_funcidx);
+
+                    // gc_roots_it += 12;
+                    // net wasm stack: [] -> [gc_roots_it(i32)]
+                    expr_builder.local_get(localidx_gc_roots_it);
+                    expr_builder.i32_const(12);
+                    expr_builder.i32_add();
+                    expr_builder.local_tee(localidx_gc_roots_it);
+
+                    // while (gc_roots_it != gc_roots_stack_ptr);
+                    // net wasm stack: [gc_roots_it(i32)] -> []
+                    expr_builder.global_get(globalidx_gc_roots_stack_ptr);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+
+            scratch.pop_i32();
+        }
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
+
+// Encodes instructions to mark the object whose tag is at `localidx_tag_ptr` in the bitmap.
+// Algorithm:
+/*
+let offset = tag_ptr - space_ptr;
+*(bitmap_ptr + ((offset >> 5) & (~3))) |= 1 << ((offset >> 2) & 31);
+*/
+// net wasm stack: [] -> []
+fn encode_mark(
+    localidx_tag_ptr: wasmgen::LocalIdx,
+    globalidx_space_ptr: wasmgen::GlobalIdx,
+    globalidx_bitmap_ptr: wasmgen::GlobalIdx,
+    scratch: &mut Scratch,
+    expr_builder: &mut wasmgen::ExprBuilder,
+) {
+    let localidx_offset = scratch.push_i32();
+    let localidx_word_ptr = scratch.push_i32();
+
+    // net wasm stack: [] -> []
+    expr_builder.local_get(localidx_tag_ptr);
+    expr_builder.global_get(globalidx_space_ptr);
+    expr_builder.i32_sub();
+    expr_builder.local_set(localidx_offset);
+
+    // net wasm stack: [] -> [word_ptr(i32), word(i32)]
+    expr_builder.global_get(globalidx_bitmap_ptr);
+    expr_builder.local_get(localidx_offset);
+    expr_builder.i32_const(5);
+    expr_builder.i32_shr_u();
+    expr_builder.i32_const(-4);
+    expr_builder.i32_and();
+    expr_builder.i32_add();
+    expr_builder.local_tee(localidx_word_ptr);
+    expr_builder.local_get(localidx_word_ptr);
+    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+
+    // note: wasm shifts are modulo 32, so we don't need the `& 31`
+    // net wasm stack: [word_ptr(i32), word(i32)] -> []
+    expr_builder.i32_const(1);
+    expr_builder.local_get(localidx_offset);
+    expr_builder.i32_const(2);
+    expr_builder.i32_shr_u();
+    expr_builder.i32_shl();
+    expr_builder.i32_or();
+    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+
+    scratch.pop_i32();
+    scratch.pop_i32();
+}
+
+// Encodes instructions to call verify_children_$i on every object in [begin, end), where the tag of each object is at the start of the object.
+// `encode_begin` and `encode_end` should have net wasm stack [] -> [ptr(i32)].
+// Algorithm:
+/*
+let it = begin;
+while (it != end) {
+    it = (*(verify_children_table_offset + *it))(it + 4);
+}
+*/
+// net wasm stack: [] -> []
+fn encode_verify_objects<F: Fn(&mut wasmgen::ExprBuilder), G: Fn(&mut wasmgen::ExprBuilder)>(
+    encode_begin: F,
+    encode_end: G,
+    tableidx: wasmgen::TableIdx,
+    verify_children_table_offset: u32,
+    object_functype: wasmgen::TypeIdx,
+    scratch: &mut Scratch,
+    expr_builder: &mut wasmgen::ExprBuilder,
+) {
+    let localidx_it = scratch.push_i32();
+
+    // net wasm stack: [] -> [cond(i32)]
+    encode_begin(expr_builder);
+    expr_builder.local_tee(localidx_it);
+    encode_end(expr_builder);
+    expr_builder.i32_ne();
+
+    // net wasm stack: [cond(i32)] -> []
+    expr_builder.if_(&[]);
+    {
+        expr_builder.loop_(&[]);
+        {
+            // net wasm stack: [] -> [it(i32)]
+            expr_builder.local_get(localidx_it);
+            expr_builder.i32_const(4);
+            expr_builder.i32_add();
+            expr_builder.local_get(localidx_it);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            if verify_children_table_offset != 0 {
+                expr_builder.i32_const(verify_children_table_offset as i32);
+                expr_builder.i32_add();
+            }
+            expr_builder.call_indirect(object_functype, tableidx);
+            expr_builder.local_tee(localidx_it);
+
+            // net wasm stack: [it(i32)] -> []
+            encode_end(expr_builder);
+            expr_builder.i32_ne();
+            expr_builder.br_if(0);
+        }
+        expr_builder.end();
+    }
+    expr_builder.end();
+
+    scratch.pop_i32();
+}
+
+// Encodes fail(detail: i32), which raises ERROR_CODE_HEAP_VERIFICATION and never returns.
+fn make_fail_func(
+    wasm_module: &mut wasmgen::WasmModule,
+    error_func: wasmgen::FuncIdx,
+) -> wasmgen::FuncIdx {
+    let functype = wasmgen::FuncType::new(Box::new([wasmgen::ValType::I32]), Box::new([]));
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (_locals_builder, expr_builder) = code_builder.split();
+        let localidx_detail = wasmgen::LocalIdx { idx: 0 };
+
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(ir::error::ERROR_CODE_HEAP_VERIFICATION as i32);
+        expr_builder.local_get(localidx_detail);
+        expr_builder.i32_const(0);
+        expr_builder.i32_const(0);
+        expr_builder.i32_const(0);
+        expr_builder.i32_const(0);
+        expr_builder.i32_const(0);
+        expr_builder.call(error_func);
+        expr_builder.unreachable();
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
+
+// Encodes verify_ptr(ptr: i32, expected_tag: i32), which checks a pointer stored in a field or an Any.
+// `expected_tag` is -1 if the pointer can point to any struct (i.e. it is the closure of a Func).
+fn make_verify_ptr(
+    wasm_module: &mut wasmgen::WasmModule,
+    globalidx_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_space_ptr: wasmgen::GlobalIdx,
+    globalidx_bitmap_ptr: wasmgen::GlobalIdx,
+    array_buffer_tag: u32,
+    heap_begin: u32,
+    fail_funcidx: wasmgen::FuncIdx,
+) -> wasmgen::FuncIdx {
+    /*
+    fn verify_ptr(ptr: i32, expected_tag: i32) {
+        if (ptr == -1 || ptr <= heap_begin * WASM_PAGE_SIZE) return; // not yet assigned, or not on the heap
+        let offset = ptr - 4 - space_ptr; // offset of the tag from the start of the semispace in use
+        if ((ptr & 3) != 0 || offset >= free_mem_ptr - space_ptr) fail(ptr); // note: unsigned comparison, so this also catches pointers before space_ptr
+        if (!((*(bitmap_ptr + ((offset >> 5) & (~3))) >> ((offset >> 2) & 31)) & 1)) fail(ptr); // not the start of an object
+        let tag = *(ptr - 4);
+        if (expected_tag == -1 ? (tag < NUM_PRIMITIVE_TAG_TYPES || tag >= array_buffer_tag) : tag != expected_tag) fail(ptr);
+    }
+    */
+
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32, wasmgen::ValType::I32]),
+        Box::new([]),
+    );
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let localidx_ptr = wasmgen::LocalIdx { idx: 0 };
+        let localidx_expected_tag = wasmgen::LocalIdx { idx: 1 };
+        let mut scratch = Scratch::new(locals_builder);
+
+        let localidx_offset = scratch.push_i32();
+        let localidx_tag = scratch.push_i32();
+
+        // if (ptr == -1 || ptr <= heap_begin * WASM_PAGE_SIZE) return;
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const(-1);
+        expr_builder.i32_eq();
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const((heap_begin << WASM_PAGE_BITS) as i32);
+        expr_builder.i32_le_u();
+        expr_builder.i32_or();
+        expr_builder.if_(&[]);
+        expr_builder.return_();
+        expr_builder.end();
+
+        // let offset = ptr - 4 - space_ptr;
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const(4);
+        expr_builder.i32_sub();
+        expr_builder.global_get(globalidx_space_ptr);
+        expr_builder.i32_sub();
+        expr_builder.local_set(localidx_offset);
+
+        // if ((ptr & 3) != 0 || offset >= free_mem_ptr - space_ptr) fail(ptr);
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const(3);
+        expr_builder.i32_and();
+        expr_builder.local_get(localidx_offset);
+        expr_builder.global_get(globalidx_free_mem_ptr);
+        expr_builder.global_get(globalidx_space_ptr);
+        expr_builder.i32_sub();
+        expr_builder.i32_ge_u();
+        expr_builder.i32_or();
+        expr_builder.if_(&[]);
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.call(fail_funcidx);
+        expr_builder.end();
+
+        // if (!((*(bitmap_ptr + ((offset >> 5) & (~3))) >> ((offset >> 2) & 31)) & 1)) fail(ptr);
+        // note: wasm shifts are modulo 32, so we don't need the `& 31`
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_bitmap_ptr);
+        expr_builder.local_get(localidx_offset);
+        expr_builder.i32_const(5);
+        expr_builder.i32_shr_u();
+        expr_builder.i32_const(-4);
+        expr_builder.i32_and();
+        expr_builder.i32_add();
+        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+        expr_builder.local_get(localidx_offset);
+        expr_builder.i32_const(2);
+        expr_builder.i32_shr_u();
+        expr_builder.i32_shr_u();
+        expr_builder.i32_const(1);
+        expr_builder.i32_and();
+        expr_builder.i32_eqz();
+        expr_builder.if_(&[]);
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.call(fail_funcidx);
+        expr_builder.end();
+
+        // let tag = *(ptr - 4);
+        // if (expected_tag == -1 ? (tag < NUM_PRIMITIVE_TAG_TYPES || tag >= array_buffer_tag) : tag != expected_tag) fail(ptr);
+        // we actually do select((tag - NUM_PRIMITIVE_TAG_TYPES) >= (array_buffer_tag - NUM_PRIMITIVE_TAG_TYPES), tag != expected_tag, expected_tag == -1), using unsigned comparison
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const(4);
+        expr_builder.i32_sub();
+        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+        expr_builder.local_tee(localidx_tag);
+        expr_builder.i32_const(ir::NUM_PRIMITIVE_TAG_TYPES as i32);
+        expr_builder.i32_sub();
+        expr_builder.i32_const(array_buffer_tag as i32 - ir::NUM_PRIMITIVE_TAG_TYPES as i32);
+        expr_builder.i32_ge_u();
+        expr_builder.local_get(localidx_tag);
+        expr_builder.local_get(localidx_expected_tag);
+        expr_builder.i32_ne();
+        expr_builder.local_get(localidx_expected_tag);
+        expr_builder.i32_const(-1);
+        expr_builder.i32_eq();
+        expr_builder.select();
+        expr_builder.if_(&[]);
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.call(fail_funcidx);
+        expr_builder.end();
+
+        scratch.pop_i32();
+        scratch.pop_i32();
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
+
+// Encodes verify_any(tag: i32, data: i64), which checks an Any.
+fn make_verify_any(
+    wasm_module: &mut wasmgen::WasmModule,
+    verify_ptr_funcidx: wasmgen::FuncIdx,
+    array_buffer_tag: u32,
+    fail_funcidx: wasmgen::FuncIdx,
+) -> wasmgen::FuncIdx {
+    /*
+    fn verify_any(tag: i32, data: i64) {
+        if (tag >= array_buffer_tag) fail(tag); // note: unsigned comparison, the array buffer can't be stored in an Any
+        if (tag == Func) {
+            verify_ptr(i32_wrap_i64(data >> 32), -1); // the closure
+        } else if (tag == String || tag >= Array) {
+            verify_ptr(i32_wrap_i64(data), tag);
+        }
+    }
+    */
+
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32, wasmgen::ValType::I64]),
+        Box::new([]),
+    );
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (_locals_builder, expr_builder) = code_builder.split();
+        let localidx_tag = wasmgen::LocalIdx { idx: 0 };
+        let localidx_data = wasmgen::LocalIdx { idx: 1 };
+
+        // if (tag >= array_buffer_tag) fail(tag);
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_tag);
+        expr_builder.i32_const(array_buffer_tag as i32);
+        expr_builder.i32_ge_u();
+        expr_builder.if_(&[]);
+        expr_builder.local_get(localidx_tag);
+        expr_builder.call(fail_funcidx);
+        expr_builder.end();
+
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_tag);
+        expr_builder.i32_const(ir::VarType::Func.tag());
+        expr_builder.i32_eq();
+        expr_builder.if_(&[]);
+        {
+            // verify_ptr(i32_wrap_i64(data >> 32), -1);
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_data);
+            expr_builder.i64_const(32);
+            expr_builder.i64_shr_u();
+            expr_builder.i32_wrap_i64();
+            expr_builder.i32_const(-1);
+            expr_builder.call(verify_ptr_funcidx);
+        }
+        expr_builder.else_();
+        {
+            // if (tag == String || tag >= Array)
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.local_get(localidx_tag);
+            expr_builder.i32_const(ir::VarType::String.tag());
+            expr_builder.i32_eq();
+            expr_builder.local_get(localidx_tag);
+            expr_builder.i32_const(ir::VarType::Array.tag());
+            expr_builder.i32_ge_u();
+            expr_builder.i32_or();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                // verify_ptr(i32_wrap_i64(data), tag);
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_data);
+                expr_builder.i32_wrap_i64();
+                expr_builder.local_get(localidx_tag);
+                expr_builder.call(verify_ptr_funcidx);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
+
+// Encodes object_end_$i(ptr: i32) -> i32 for every heap object type, which returns the ptr to past-the-end of the object (which is the tag of the next object).
+// returns the base table element index from which indirect access should be calculated (i.e. the "table offset"), in the same way as make_copy_children_elements().
+fn make_object_end_elements(
+    wasm_module: &mut wasmgen::WasmModule,
+    struct_sizes: &[u32],
+    tableidx: wasmgen::TableIdx,
+) -> u32 {
+    // `encode_obj_end` should have net wasm stack [] -> [obj_end(i32)], where `obj_end` is the past-the-end pointer of the object given by the param.
+    fn make_function<F: FnOnce(wasmgen::LocalIdx, &mut wasmgen::ExprBuilder)>(
+        wasm_module: &mut wasmgen::WasmModule,
+        encode_obj_end: F,
+    ) -> wasmgen::FuncIdx {
+        let functype = wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        );
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (_locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+            encode_obj_end(localidx_param, expr_builder);
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    }
+
+    // object_end_$i for fixed-size objects, keyed by size (like in make_copy_funcs()).
+    let mut fixed_size_funcs = std::collections::hash_map::HashMap::<u32, wasmgen::FuncIdx>::new();
+    let mut get_fixed_size_func = |wasm_module: &mut wasmgen::WasmModule, size: u32| {
+        *(fixed_size_funcs.entry(size).or_insert_with(|| {
+            make_function(wasm_module, |localidx_param, expr_builder| {
+                expr_builder.local_get(localidx_param);
+                expr_builder.i32_const(size as i32);
+                expr_builder.i32_add();
+            })
+        }))
+    };
+
+    let object_end_table_offset: u32 = wasm_module.reserve_table_elements(
+        tableidx,
+        (ir::NUM_PRIMITIVE_TAG_TYPES + struct_sizes.len() + 1) as u32, // the last element is for the array buffer
+    );
+
+    // Note: the reserved table elements for types that are not on the heap are left uncommitted, since verify_heap() checks the tag first.
+
+    // the string contains its length in bytes (excluding the tag) at *ptr
+    // Algorithm: return ((ptr + *ptr + 7) & (-4)); // same as in make_copy_children_elements()
+    let funcidx_string: wasmgen::FuncIdx =
+        make_function(wasm_module, |localidx_param, expr_builder| {
+            expr_builder.local_get(localidx_param);
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.i32_add();
+            expr_builder.i32_const(7);
+            expr_builder.i32_add();
+            expr_builder.i32_const(-4);
+            expr_builder.i32_and();
+        });
+    wasm_module.commit_table_elements(
+        tableidx,
+        object_end_table_offset + ir::VarType::String.tag() as u32,
+        Box::new([funcidx_string]),
+    );
+
+    // the array header is [len(i32), buf(i32)]
+    let funcidx_array: wasmgen::FuncIdx = get_fixed_size_func(wasm_module, 8);
+    let funcidxs_structs: Box<[wasmgen::FuncIdx]> = struct_sizes
+        .iter()
+        .map(|size| get_fixed_size_func(wasm_module, *size))
+        .collect();
+
+    // the array buffer is [capacity(i32), element(Any) * capacity]
+    // Algorithm: return ptr + 4 + (*ptr) * sizeof(Any);
+    let funcidx_array_buffer: wasmgen::FuncIdx =
+        make_function(wasm_module, |localidx_param, expr_builder| {
+            expr_builder.local_get(localidx_param);
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.i32_const(crate::var_conv::size_in_memory(ir::VarType::Any) as i32);
+            expr_builder.i32_mul();
+            expr_builder.i32_add();
+            expr_builder.i32_const(4);
+            expr_builder.i32_add();
+        });
+
+    // Array, then all the structs, then the array buffer, are contiguous
+    wasm_module.commit_table_elements(
+        tableidx,
+        object_end_table_offset + ir::VarType::Array.tag() as u32,
+        std::iter::once(funcidx_array)
+            .chain(funcidxs_structs.iter().copied())
+            .chain(std::iter::once(funcidx_array_buffer))
+            .collect(),
+    );
+
+    object_end_table_offset
+}
+
+// Encodes verify_children_$i(ptr: i32) -> i32 for every heap object type, which checks all the pointers and Anys in the object, and returns the ptr to past-the-end of the object.
+// returns the base table element index from which indirect access should be calculated (i.e. the "table offset"), in the same way as make_copy_children_elements().
+fn make_verify_children_elements(
+    wasm_module: &mut wasmgen::WasmModule,
+    struct_types: &[Box<[ir::VarType]>],
+    struct_field_byte_offsets: &[Box<[u32]>],
+    struct_sizes: &[u32],
+    tableidx: wasmgen::TableIdx,
+    verify_ptr_funcidx: wasmgen::FuncIdx,
+    verify_any_funcidx: wasmgen::FuncIdx,
+) -> u32 {
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32]),
+        Box::new([wasmgen::ValType::I32]),
+    );
+    let array_buffer_tag: usize = ir::NUM_PRIMITIVE_TAG_TYPES + struct_types.len();
+
+    let verify_children_table_offset: u32 =
+        wasm_module.reserve_table_elements(tableidx, (array_buffer_tag + 1) as u32);
+
+    // Note: the reserved table elements for types that are not on the heap are left uncommitted, since verify_heap() checks the tag first.
+
+    // the string has no children
+    // Algorithm: return ((ptr + *ptr + 7) & (-4));
+    let funcidx_string: wasmgen::FuncIdx = {
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype.clone());
+        {
+            let (_locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+
+            // net wasm stack: [] -> [ret(i32)]
+            expr_builder.local_get(localidx_param);
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.i32_add();
+            expr_builder.i32_const(7);
+            expr_builder.i32_add();
+            expr_builder.i32_const(-4);
+            expr_builder.i32_and();
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    };
+    wasm_module.commit_table_elements(
+        tableidx,
+        verify_children_table_offset + ir::VarType::String.tag() as u32,
+        Box::new([funcidx_string]),
+    );
+
+    // the array header is [len(i32), buf(i32)]
+    // Algorithm:
+    /*
+    verify_ptr(*(ptr + 4), array_buffer_tag);
+    return ptr + 8;
+    */
+    let funcidx_array: wasmgen::FuncIdx = {
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype.clone());
+        {
+            let (_locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(4));
+            expr_builder.i32_const(array_buffer_tag as i32);
+            expr_builder.call(verify_ptr_funcidx);
+
+            // net wasm stack: [] -> [ret(i32)]
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(8);
+            expr_builder.i32_add();
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    };
+
+    let funcidxs_structs: Box<[wasmgen::FuncIdx]> = struct_types
+        .iter()
+        .zip(struct_field_byte_offsets.iter())
+        .zip(struct_sizes.iter().copied())
+        .map(|((ir_vartypes, byte_offsets), struct_size)| {
+            let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+            let mut code_builder = wasmgen::CodeBuilder::new(functype.clone());
+            {
+                let (_locals_builder, expr_builder) = code_builder.split();
+                let localidx_param = wasmgen::LocalIdx { idx: 0 };
+
+                // net wasm stack: [] -> []
+                for (ir_vartype, byte_offset) in ir_vartypes
+                    .iter()
+                    .copied()
+                    .zip(byte_offsets.iter().copied())
+                {
+                    match ir_vartype {
+                        ir::VarType::Any => {
+                            // verify_any(f.tag, f.data);
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset));
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i64_load(wasmgen::MemArg::new4(byte_offset + 4));
+                            expr_builder.call(verify_any_funcidx);
+                        }
+                        ir::VarType::Unassigned
+                        | ir::VarType::Undefined
+                        | ir::VarType::Null
+                        | ir::VarType::Number
+                        | ir::VarType::Boolean => {}
+                        ir::VarType::Func => {
+                            // verify_ptr(f.closure, -1);
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset + 4)); // the `closure` of the Func is at offset 4
+                            expr_builder.i32_const(-1);
+                            expr_builder.call(verify_ptr_funcidx);
+                        }
+                        ir::VarType::String
+                        | ir::VarType::Array
+                        | ir::VarType::StructT { typeidx: _ } => {
+                            // verify_ptr(f, tag of f);
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset));
+                            expr_builder.i32_const(ir_vartype.tag());
+                            expr_builder.call(verify_ptr_funcidx);
+                        }
+                    }
+                }
+
+                // net wasm stack: [] -> [ret(i32)]
+                expr_builder.local_get(localidx_param);
+                expr_builder.i32_const(struct_size as i32);
+                expr_builder.i32_add();
+
+                expr_builder.end(); // return it
+            }
+            wasm_module.commit_func(func_idx, code_builder);
+            func_idx
+        })
+        .collect();
+
+    // the array buffer is [capacity(i32), element(Any) * capacity]
+    // Algorithm:
+    /*
+    let it = ptr + 4;
+    let it_end = it + (*ptr) * sizeof(Any);
+    while (it != it_end) {
+        verify_any(it->tag, it->data);
+        it += sizeof(Any);
+    }
+    return it_end;
+    */
+    let funcidx_array_buffer: wasmgen::FuncIdx = {
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype.clone());
+        {
+            let (locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+            let mut scratch = Scratch::new(locals_builder);
+
+            let any_size = crate::var_conv::size_in_memory(ir::VarType::Any) as i32;
+
+            let localidx_it = scratch.push_i32();
+            let localidx_it_end = scratch.push_i32();
+
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(4);
+            expr_builder.i32_add();
+            expr_builder.local_tee(localidx_it);
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.i32_const(any_size);
+            expr_builder.i32_mul();
+            expr_builder.i32_add();
+            expr_builder.local_set(localidx_it_end);
+
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.local_get(localidx_it);
+            expr_builder.local_get(localidx_it_end);
+            expr_builder.i32_ne();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // verify_any(it->tag, it->data);
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i64_load(wasmgen::MemArg::new4(4));
+                    expr_builder.call(verify_any_funcidx);
+
+                    // it += sizeof(Any);
+                    // net wasm stack: [] -> [cond(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(any_size);
+                    expr_builder.i32_add();
+                    expr_builder.local_tee(localidx_it);
+                    expr_builder.local_get(localidx_it_end);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+
+            // net wasm stack: [] -> [ret(i32)]
+            expr_builder.local_get(localidx_it_end);
+
+            scratch.pop_i32();
+            scratch.pop_i32();
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    };
+
+    // Array, then all the structs, then the array buffer, are contiguous
+    wasm_module.commit_table_elements(
+        tableidx,
+        verify_children_table_offset + ir::VarType::Array.tag() as u32,
+        std::iter::once(funcidx_array)
+            .chain(funcidxs_structs.iter().copied())
+            .chain(std::iter::once(funcidx_array_buffer))
+            .collect(),
+    );
+
+    verify_children_table_offset
+}
@@ -1,4 +1,5 @@
 use super::*;
+use crate::global_var::GlobalVarManager;
 use wasm_test_harness::*;
 
 pub fn wasmtest<C: TestContext>(c: &mut C) {
@@ -20,14 +21,20 @@ pub fn wasmtest<C: TestContext>(c: &mut C) {
         let struct_field_byte_offsets: [Box<[u32]>; 1] = [Box::new([0, 12, 24])];
         let struct_sizes: [u32; 1] = [28];
         let mem = wasm_module.add_unbounded_memory(MEM_INITIAL_HEAP_SIZE);
+        let stackptr = wasm_module.add_i32_global(wasmgen::Mut::Var, 0); // there is no stack
+        let global_var_manager = GlobalVarManager::default();
         let cheney = Cheney::new(
             &struct_types,
             &struct_field_byte_offsets,
             &struct_sizes,
             mem,
+            stackptr,
+            0,
             0,
             MEM_INITIAL_HEAP_SIZE,
+            global_var_manager.deref(),
             error_func,
+            true,
             wasm_module,
         );
 
@@ -154,14 +161,20 @@ pub fn wasmtest<C: TestContext>(c: &mut C) {
         let struct_field_byte_offsets: [Box<[u32]>; 1] = [Box::new([0, 12, 24])];
         let struct_sizes: [u32; 1] = [28];
         let mem = wasm_module.add_unbounded_memory(MEM_INITIAL_HEAP_SIZE);
+        let stackptr = wasm_module.add_i32_global(wasmgen::Mut::Var, 0); // there is no stack
+        let global_var_manager = GlobalVarManager::default();
         let cheney = Cheney::new(
             &struct_types,
             &struct_field_byte_offsets,
             &struct_sizes,
             mem,
+            stackptr,
+            0,
             0,
             MEM_INITIAL_HEAP_SIZE,
+            global_var_manager.deref(),
             error_func,
+            true,
             wasm_module,
         );
 
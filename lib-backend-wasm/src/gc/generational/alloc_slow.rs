@@ -0,0 +1,291 @@
+use super::do_cheney;
+use super::GcStats;
+use super::WASM_PAGE_SIZE;
+
+// Encodes alloc_slow(), which is called when an object doesn't fit in the free space of the nursery.
+// It runs a collection if necessary, and then allocates the object and writes its tag.
+// `size`: number of bytes we want (including the tag!)
+// Returns the ptr to the object (just after the tag).  Raises an out-of-memory error if it can't find enough space.
+pub fn make_alloc_slow(
+    wasm_module: &mut wasmgen::WasmModule,
+    globalidx_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_remembered_set_ptr: wasmgen::GlobalIdx,
+    globalidx_mature_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_mature_end_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_base_ptr: wasmgen::GlobalIdx,
+    do_cheney_funcidx: wasmgen::FuncIdx,
+    do_minor_funcidx: wasmgen::FuncIdx,
+    stats: &GcStats,
+    heap_begin: u32,
+    nursery_end: u32,
+    mature_begin: u32,
+    error_func: wasmgen::FuncIdx,
+) -> wasmgen::FuncIdx {
+    /*
+    fn alloc_slow(size: i32, tag: i32) -> i32 {
+        if (size > NURSERY_SIZE * WASM_PAGE_SIZE) {
+            // too large for the nursery, so we allocate it in the mature space directly
+            if (mature_end_mem_ptr - mature_free_mem_ptr < size + NURSERY_SIZE * WASM_PAGE_SIZE) {
+                stats.bytes_allocated += free_mem_ptr - heap_begin * WASM_PAGE_SIZE;
+                if (!do_cheney(size + NURSERY_SIZE * WASM_PAGE_SIZE)) abort();
+                stats.heap_grown(); // do_cheney() might have grown the memory
+                free_mem_ptr = heap_begin * WASM_PAGE_SIZE;
+                remembered_set_ptr = nursery_end * WASM_PAGE_SIZE + 4;
+                stats.collections += 1;
+                stats.bytes_surviving = mature_bytes_in_use();
+            }
+            stats.bytes_allocated += size;
+            let ret = mature_free_mem_ptr;
+            *ret = tag;
+            mature_free_mem_ptr += size;
+            ret += 4;
+            // the caller will initialize the object without the write barrier, so we add it to the remembered set now
+            if (remembered_set_ptr != remembered_set_end * WASM_PAGE_SIZE) {
+                *remembered_set_ptr = ret;
+                remembered_set_ptr += 4;
+            }
+            return ret;
+        }
+        if (remembered_set_ptr == remembered_set_end * WASM_PAGE_SIZE || mature_end_mem_ptr - mature_free_mem_ptr < (free_mem_ptr - heap_begin * WASM_PAGE_SIZE) + NURSERY_SIZE * WASM_PAGE_SIZE) {
+            // the remembered set might have overflowed, or the mature space might not have enough space for everything in the nursery
+            stats.bytes_allocated += free_mem_ptr - heap_begin * WASM_PAGE_SIZE;
+            if (!do_cheney(NURSERY_SIZE * WASM_PAGE_SIZE)) abort();
+            stats.heap_grown(); // do_cheney() might have grown the memory
+            free_mem_ptr = heap_begin * WASM_PAGE_SIZE;
+            remembered_set_ptr = nursery_end * WASM_PAGE_SIZE + 4;
+        } else {
+            stats.bytes_allocated += free_mem_ptr - heap_begin * WASM_PAGE_SIZE;
+            do_minor();
+        }
+        stats.collections += 1;
+        stats.bytes_surviving = mature_bytes_in_use();
+        // the nursery is now empty
+        free_mem_ptr = heap_begin * WASM_PAGE_SIZE + size;
+        *(heap_begin * WASM_PAGE_SIZE) = tag;
+        return heap_begin * WASM_PAGE_SIZE + 4;
+    }
+    Note: the remembered set ends where the mature space begins.
+    Note: asking do_cheney() for an extra NURSERY_SIZE maintains the invariant that the mature space always has that much free space.
+    Note: The objects in the nursery are only added to stats.bytes_allocated when the nursery is emptied (see Generational::encode_write_stats()).
+    mature_bytes_in_use() is do_cheney::encode_bytes_in_use() for the mature space (the nursery is empty after every collection).
+    */
+
+    let nursery_begin_ptr: i32 = (heap_begin * WASM_PAGE_SIZE) as i32;
+    let nursery_size_bytes: i32 = ((nursery_end - heap_begin) * WASM_PAGE_SIZE) as i32;
+    let remembered_set_begin_ptr: i32 = (nursery_end * WASM_PAGE_SIZE + 4) as i32;
+    let remembered_set_end_ptr: i32 = (super::REMEMBERED_SET_SIZE * WASM_PAGE_SIZE) as i32
+        + (nursery_end * WASM_PAGE_SIZE) as i32;
+
+    // stats.bytes_allocated += free_mem_ptr - heap_begin * WASM_PAGE_SIZE;
+    // net wasm stack: [] -> []
+    let encode_count_nursery = |expr_builder: &mut wasmgen::ExprBuilder| {
+        expr_builder.global_get(globalidx_free_mem_ptr);
+        expr_builder.i32_const(nursery_begin_ptr);
+        expr_builder.i32_sub();
+        stats.encode_add_allocated(expr_builder);
+    };
+
+    // stats.collections += 1;
+    // stats.bytes_surviving = mature_bytes_in_use();
+    // net wasm stack: [] -> []
+    let encode_count_collection = |expr_builder: &mut wasmgen::ExprBuilder| {
+        do_cheney::encode_bytes_in_use(
+            globalidx_mature_free_mem_ptr,
+            globalidx_mature_end_mem_ptr,
+            globalidx_gc_roots_stack_base_ptr,
+            mature_begin,
+            expr_builder,
+        );
+        stats.encode_collection(expr_builder);
+    };
+
+    // net wasm stack: [] -> []
+    let encode_major_collection =
+        |bytes_required: &dyn Fn(&mut wasmgen::ExprBuilder),
+         expr_builder: &mut wasmgen::ExprBuilder| {
+            // net wasm stack: [] -> []
+            encode_count_nursery(expr_builder);
+
+            // if (!do_cheney(bytes_required)) abort();
+            // net wasm stack: [] -> []
+            bytes_required(expr_builder);
+            expr_builder.call(do_cheney_funcidx);
+            expr_builder.i32_eqz();
+            expr_builder.if_(&[]);
+            {
+                // out of memory... raise an error
+                expr_builder.i32_const(ir::error::ERROR_CODE_OUT_OF_MEMORY as i32);
+                expr_builder.i32_const(0);
+                expr_builder.i32_const(0);
+                expr_builder.i32_const(0);
+                expr_builder.i32_const(0);
+                expr_builder.i32_const(0);
+                expr_builder.i32_const(0);
+                expr_builder.call(error_func);
+                expr_builder.unreachable();
+            }
+            expr_builder.end();
+
+            // stats.heap_grown();
+            // net wasm stack: [] -> []
+            stats.encode_heap_grown(expr_builder);
+
+            // do_cheney() has moved everything out of the nursery, so the remembered set is no longer needed
+            // net wasm stack: [] -> []
+            expr_builder.i32_const(nursery_begin_ptr);
+            expr_builder.global_set(globalidx_free_mem_ptr);
+            expr_builder.i32_const(remembered_set_begin_ptr);
+            expr_builder.global_set(globalidx_remembered_set_ptr);
+        };
+
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32, wasmgen::ValType::I32]),
+        Box::new([wasmgen::ValType::I32]),
+    );
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let localidx_size = wasmgen::LocalIdx { idx: 0 };
+        let localidx_tag = wasmgen::LocalIdx { idx: 1 };
+        let localidx_ret = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_remembered_set_ptr = locals_builder.add(wasmgen::ValType::I32);
+
+        // if (size > NURSERY_SIZE * WASM_PAGE_SIZE)
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_size);
+        expr_builder.i32_const(nursery_size_bytes);
+        expr_builder.i32_gt_u();
+        expr_builder.if_(&[]);
+        {
+            // if (mature_end_mem_ptr - mature_free_mem_ptr < size + NURSERY_SIZE * WASM_PAGE_SIZE)
+            // net wasm stack: [] -> []
+            expr_builder.global_get(globalidx_mature_end_mem_ptr);
+            expr_builder.global_get(globalidx_mature_free_mem_ptr);
+            expr_builder.i32_sub();
+            expr_builder.local_get(localidx_size);
+            expr_builder.i32_const(nursery_size_bytes);
+            expr_builder.i32_add();
+            expr_builder.i32_lt_u();
+            expr_builder.if_(&[]);
+            {
+                // net wasm stack: [] -> []
+                encode_major_collection(
+                    &|expr_builder| {
+                        expr_builder.local_get(localidx_size);
+                        expr_builder.i32_const(nursery_size_bytes);
+                        expr_builder.i32_add();
+                    },
+                    expr_builder,
+                );
+                encode_count_collection(expr_builder);
+            }
+            expr_builder.end();
+
+            // stats.bytes_allocated += size;
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_size);
+            stats.encode_add_allocated(expr_builder);
+
+            // let ret = mature_free_mem_ptr;
+            // *ret = tag;
+            // mature_free_mem_ptr += size;
+            // ret += 4;
+            // net wasm stack: [] -> []
+            expr_builder.global_get(globalidx_mature_free_mem_ptr);
+            expr_builder.local_tee(localidx_ret);
+            expr_builder.local_get(localidx_tag);
+            expr_builder.i32_store(wasmgen::MemArg::new4(0));
+            expr_builder.local_get(localidx_ret);
+            expr_builder.local_get(localidx_size);
+            expr_builder.i32_add();
+            expr_builder.global_set(globalidx_mature_free_mem_ptr);
+            expr_builder.local_get(localidx_ret);
+            expr_builder.i32_const(4);
+            expr_builder.i32_add();
+            expr_builder.local_set(localidx_ret);
+
+            // if (remembered_set_ptr != remembered_set_end * WASM_PAGE_SIZE) { *remembered_set_ptr = ret; remembered_set_ptr += 4; }
+            // net wasm stack: [] -> []
+            expr_builder.global_get(globalidx_remembered_set_ptr);
+            expr_builder.local_tee(localidx_remembered_set_ptr);
+            expr_builder.i32_const(remembered_set_end_ptr);
+            expr_builder.i32_ne();
+            expr_builder.if_(&[]);
+            {
+                expr_builder.local_get(localidx_remembered_set_ptr);
+                expr_builder.local_get(localidx_ret);
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                expr_builder.local_get(localidx_remembered_set_ptr);
+                expr_builder.i32_const(4);
+                expr_builder.i32_add();
+                expr_builder.global_set(globalidx_remembered_set_ptr);
+            }
+            expr_builder.end();
+
+            // return ret;
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_ret);
+            expr_builder.return_();
+        }
+        expr_builder.end();
+
+        // prepare condition for if-stmt
+        // (remembered_set_ptr == remembered_set_end * WASM_PAGE_SIZE || mature_end_mem_ptr - mature_free_mem_ptr < (free_mem_ptr - heap_begin * WASM_PAGE_SIZE) + NURSERY_SIZE * WASM_PAGE_SIZE)
+        // net wasm stack: [] -> [cond(i32)]
+        expr_builder.global_get(globalidx_remembered_set_ptr);
+        expr_builder.i32_const(remembered_set_end_ptr);
+        expr_builder.i32_eq();
+        expr_builder.global_get(globalidx_mature_end_mem_ptr);
+        expr_builder.global_get(globalidx_mature_free_mem_ptr);
+        expr_builder.i32_sub();
+        expr_builder.global_get(globalidx_free_mem_ptr);
+        expr_builder.i32_const(nursery_begin_ptr);
+        expr_builder.i32_sub();
+        expr_builder.i32_const(nursery_size_bytes);
+        expr_builder.i32_add();
+        expr_builder.i32_lt_u();
+        expr_builder.i32_or();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // net wasm stack: [] -> []
+            encode_major_collection(
+                &|expr_builder| expr_builder.i32_const(nursery_size_bytes),
+                expr_builder,
+            );
+        }
+        expr_builder.else_();
+        {
+            // net wasm stack: [] -> []
+            encode_count_nursery(expr_builder);
+            expr_builder.call(do_minor_funcidx);
+        }
+        expr_builder.end();
+
+        // net wasm stack: [] -> []
+        encode_count_collection(expr_builder);
+
+        // free_mem_ptr = heap_begin * WASM_PAGE_SIZE + size;
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(nursery_begin_ptr);
+        expr_builder.local_get(localidx_size);
+        expr_builder.i32_add();
+        expr_builder.global_set(globalidx_free_mem_ptr);
+
+        // *(heap_begin * WASM_PAGE_SIZE) = tag;
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(nursery_begin_ptr);
+        expr_builder.local_get(localidx_tag);
+        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+
+        // return heap_begin * WASM_PAGE_SIZE + 4;
+        // net wasm stack: [] -> [ret(i32)]
+        expr_builder.i32_const(nursery_begin_ptr + 4);
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
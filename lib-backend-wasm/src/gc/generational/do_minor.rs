@@ -0,0 +1,173 @@
+use super::do_cheney;
+use super::WASM_PAGE_SIZE;
+use crate::global_var::GlobalVarManagerRef;
+use wasmgen::Scratch;
+
+// Encodes do_minor(), which copies all live objects in the nursery to the mature space, and then empties the nursery and the remembered set.
+// The caller must ensure that the free space in the mature space is at least as large as the used space in the nursery.
+// `copy_indirect_table_offset` and `copy_children_table_offset` should come from tables that only copy objects in the nursery.
+pub fn make_do_minor<'a>(
+    wasm_module: &mut wasmgen::WasmModule,
+    tableidx: wasmgen::TableIdx,
+    copy_indirect_table_offset: u32,
+    copy_children_table_offset: u32,
+    globalidx_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_remembered_set_ptr: wasmgen::GlobalIdx,
+    globalidx_mature_free_mem_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_base_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_ptr: wasmgen::GlobalIdx,
+    copy_funcs: &[Option<wasmgen::FuncIdx>],
+    global_var_manager: GlobalVarManagerRef<'a>,
+    globalidx_stackptr: wasmgen::GlobalIdx,
+    stack_end: u32,
+    heap_begin: u32,
+    nursery_end: u32,
+) -> wasmgen::FuncIdx {
+    /*
+    fn do_minor() {
+        let mature_free_mem_ptr = mature_free_mem_ptr; // the objects from here onwards will be scanned
+
+        // objects in the remembered set are roots too (they are never copied, but their children are)
+        let it = nursery_end * WASM_PAGE_SIZE + 4;
+        while (it != remembered_set_ptr) {
+            let obj = *it;
+            (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *(obj-4)))(obj);
+            it += 4;
+        }
+
+        // the same as the common section of do_cheney(), copying from the globals, gc_roots and objects on the stack
+        // and then scanning from `mature_free_mem_ptr`
+
+        free_mem_ptr = heap_begin * WASM_PAGE_SIZE;
+        remembered_set_ptr = nursery_end * WASM_PAGE_SIZE + 4;
+    }
+    */
+
+    let functype = wasmgen::FuncType::new(Box::new([]), Box::new([]));
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let mut scratch = Scratch::new(locals_builder);
+
+        let localidx_mature_free_mem_ptr = scratch.push_i32();
+        let localidx_gc_roots_stack_base_ptr = scratch.push_i32();
+        let localidx_gc_roots_stack_ptr = scratch.push_i32();
+
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_mature_free_mem_ptr);
+        expr_builder.local_set(localidx_mature_free_mem_ptr);
+        expr_builder.global_get(globalidx_gc_roots_stack_base_ptr);
+        expr_builder.local_set(localidx_gc_roots_stack_base_ptr);
+        expr_builder.global_get(globalidx_gc_roots_stack_ptr);
+        expr_builder.local_set(localidx_gc_roots_stack_ptr);
+
+        // net wasm stack: [] -> []
+        {
+            // while loop turns into this:
+            /*
+            if it != remembered_set_ptr {
+                do {
+                    ...
+                } while (it != remembered_set_ptr);
+            }
+            */
+            let localidx_it = scratch.push_i32();
+            let localidx_remembered_set_ptr = scratch.push_i32();
+
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.i32_const((nursery_end * WASM_PAGE_SIZE + 4) as i32);
+            expr_builder.local_tee(localidx_it);
+            expr_builder.global_get(globalidx_remembered_set_ptr);
+            expr_builder.local_tee(localidx_remembered_set_ptr);
+            expr_builder.i32_ne();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                // net wasm stack: [] -> []
+                expr_builder.loop_(&[]);
+                {
+                    let localidx_obj = scratch.push_i32();
+
+                    // (*(GC_TABLE_PTR_COPY_CHILDREN_OFFSET + *(obj-4)))(obj);
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    expr_builder.local_tee(localidx_obj);
+                    expr_builder.local_get(localidx_obj);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_sub();
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    if copy_children_table_offset != 0 {
+                        expr_builder.i32_const(copy_children_table_offset as i32);
+                        expr_builder.i32_add();
+                    }
+                    expr_builder.call_indirect(
+                        wasm_module.insert_type_into(wasmgen::FuncType::new(
+                            Box::new([wasmgen::ValType::I32]),
+                            Box::new([wasmgen::ValType::I32]),
+                        )),
+                        tableidx,
+                    );
+                    expr_builder.drop();
+
+                    // it += 4;
+                    // net wasm stack: [] -> [it(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_add();
+                    expr_builder.local_tee(localidx_it);
+
+                    // do a conditional jump back
+                    // net wasm stack: [it(i32)] -> []
+                    expr_builder.local_get(localidx_remembered_set_ptr);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
+
+                    scratch.pop_i32();
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+
+            scratch.pop_i32();
+            scratch.pop_i32();
+        }
+
+        // net wasm stack: [] -> []
+        do_cheney::generate_common_portion(
+            wasm_module,
+            tableidx,
+            copy_indirect_table_offset,
+            copy_children_table_offset,
+            globalidx_mature_free_mem_ptr,
+            localidx_mature_free_mem_ptr,
+            localidx_gc_roots_stack_base_ptr,
+            localidx_gc_roots_stack_ptr,
+            copy_funcs,
+            global_var_manager,
+            globalidx_stackptr,
+            stack_end,
+            heap_begin,
+            Some(nursery_end),
+            expr_builder,
+            &mut scratch,
+        );
+
+        // empty the nursery and the remembered set
+        // net wasm stack: [] -> []
+        expr_builder.i32_const((heap_begin * WASM_PAGE_SIZE) as i32);
+        expr_builder.global_set(globalidx_free_mem_ptr);
+        expr_builder.i32_const((nursery_end * WASM_PAGE_SIZE + 4) as i32);
+        expr_builder.global_set(globalidx_remembered_set_ptr);
+
+        scratch.pop_i32();
+        scratch.pop_i32();
+        scratch.pop_i32();
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
@@ -0,0 +1,1013 @@
+use super::cheney::copy_children_elements;
+use super::cheney::copy_funcs;
+use super::cheney::copy_indirect_elements;
+use super::cheney::do_cheney;
+use super::GcStats;
+use super::HeapManager;
+use super::WASM_PAGE_SIZE;
+use crate::global_var::GlobalVarManagerRef;
+use crate::var_conv::*;
+use wasmgen::Scratch;
+
+mod alloc_slow;
+mod do_minor;
+
+/**
+ * Generational is a GC implementation with two generations.
+ * New objects are allocated in the nursery, and objects that survive a collection are promoted to the mature space.
+ * Most objects die young, so most collections only need to copy the few live objects out of the nursery (a minor collection),
+ * instead of the whole live heap (a major collection).
+ *
+ * Layout of heap:
+ * [.....(nursery).....|.....(remembered set).....|.....(mature space).....|.....(gc roots).....]
+ * `nursery`: where encode_fixed_allocation() and encode_dynamic_allocation() put new objects.  It has a fixed size, and it is empty after every collection.
+ * `remembered set`: pointers to the objects in the mature space that might point into the nursery.  It has a fixed size.
+ *   The first slot always contains 0, so that the write barrier can compare with the previous slot without checking for an empty set.
+ * `mature space`: the two semispaces of a Cheney GC (see gc::cheney::Cheney for the layout), holding all objects that survived a collection.
+ *   Objects too large for the nursery are allocated here directly, and are added to the remembered set.
+ * `gc_roots`: the same as gc::cheney::Cheney.
+ *
+ * When the nursery is full, alloc_slow() runs either a minor collection or a major collection.
+ * The minor collection (in do_minor()) copies the live objects in the nursery to the mature space,
+ * using the globals, gc_roots, objects on the stack, and the objects in the remembered set as roots.
+ * The major collection (in do_cheney()) copies all live objects in the nursery and the mature space to the other semispace.
+ * A major collection is needed if the remembered set is full, or if a minor collection might not have enough space in the mature space.
+ * Invariant: the free space in the mature space is at least the size of the nursery, so that a major collection always fits in the other semispace.
+ *
+ * The write barrier (see encode_write_barrier()) adds objects in the mature space to the remembered set when a pointer is stored into them.
+ * The tag at *(ptr-4) and the copy functions are the same as gc::cheney::Cheney, but the minor collection uses another set of tables that only copies objects in the nursery.
+ */
+pub struct Generational<'a, 'b, 'c> {
+    struct_types: &'a [Box<[ir::VarType]>], // types of the fields of each struct type
+    struct_field_byte_offsets: &'b [Box<[u32]>], // byte offsets of the fields of each struct type (each Box has same lengths as that of `struct_types`)
+    struct_sizes: &'c [u32], // map from typeidx to struct_sizes.  Note: typeidx is not VarType::tag()!  It is the typeidx used in VarType::StructT
+    free_mem_ptr: wasmgen::GlobalIdx, // Global that stores pointer to start of free space in the nursery
+    remembered_set_ptr: wasmgen::GlobalIdx, // Global that stores pointer to past-the-end of the remembered set
+    gc_roots_stack_ptr: wasmgen::GlobalIdx, // Global that stores pointer to past-the-end of gc_roots stack
+    heap_begin: u32,                        // in page units
+    alloc_slow_funcidx: wasmgen::FuncIdx,   // funcidx of alloc_slow() function
+    stats: GcStats,                         // counters for the gc stats
+}
+
+const NURSERY_SIZE: u32 = 1 << 2; // 256 KiB of nursery
+const REMEMBERED_SET_SIZE: u32 = 1; // 64 KiB of remembered set (16383 objects, since the first slot is always 0)
+
+// Note: Currently MEM_INITIAL_MATURE_USABLE_SIZE * 2 should be at least as large as the gc_roots size (GC_ROOTS_SIZE, plus one leftover page), for the same reason as in gc::cheney::Cheney.
+// A larger initial heap (see `heap_initial_end` in new()) only increases the mature space.
+const GC_ROOTS_SIZE: u32 = 1 << 4; // 1 MiB of gc_roots stack space
+const MEM_INITIAL_MATURE_USABLE_SIZE: u32 = 1 << 4; // the allocated_space+free_space of the mature space (for the smallest initial heap)
+const MEM_INITIAL_HEAP_SIZE: u32 =
+    NURSERY_SIZE + REMEMBERED_SET_SIZE + MEM_INITIAL_MATURE_USABLE_SIZE * 2 + GC_ROOTS_SIZE; // the mature space has 2 MiB (1 MiB usable at a time), and 1 MiB of gc_roots stack space
+
+impl<'a, 'b, 'c> Generational<'a, 'b, 'c> {
+    // Constructs a new generational GC, and initializes it appropriately.
+    pub fn new<'d>(
+        struct_types: &'a [Box<[ir::VarType]>],
+        struct_field_byte_offsets: &'b [Box<[u32]>],
+        struct_sizes: &'c [u32],
+        memidx: wasmgen::MemIdx,
+        stackptr: wasmgen::GlobalIdx, // stores the stackptr, objects in [stackptr, stack_end) are gc roots too
+        stack_end: u32,               // in page units
+        heap_begin: u32,
+        heap_initial_end: u32,
+        global_var_manager: GlobalVarManagerRef<'d>, // stores global vars that are gc roots too
+        error_func: wasmgen::FuncIdx,
+        wasm_module: &mut wasmgen::WasmModule,
+    ) -> Self {
+        assert!(heap_begin + MEM_INITIAL_HEAP_SIZE <= heap_initial_end);
+
+        let nursery_end: u32 = heap_begin + NURSERY_SIZE;
+        let mature_begin: u32 = nursery_end + REMEMBERED_SET_SIZE;
+        // if the number of pages left after the gc_roots stack is odd, the extra page goes to the gc_roots stack
+        let initial_mature_usable_size: u32 =
+            (heap_initial_end - mature_begin - GC_ROOTS_SIZE) >> 1;
+
+        let free_mem_ptr: wasmgen::GlobalIdx =
+            wasm_module.add_i32_global(wasmgen::Mut::Var, (heap_begin * WASM_PAGE_SIZE) as i32);
+        let remembered_set_ptr: wasmgen::GlobalIdx = wasm_module
+            .add_i32_global(wasmgen::Mut::Var, (nursery_end * WASM_PAGE_SIZE + 4) as i32);
+        let mature_free_mem_ptr: wasmgen::GlobalIdx =
+            wasm_module.add_i32_global(wasmgen::Mut::Var, (mature_begin * WASM_PAGE_SIZE) as i32);
+        let mature_end_mem_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(
+            wasmgen::Mut::Var,
+            ((mature_begin + initial_mature_usable_size) * WASM_PAGE_SIZE) as i32,
+        );
+        let gc_roots_stack_base_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(
+            wasmgen::Mut::Var,
+            ((mature_begin + initial_mature_usable_size * 2) * WASM_PAGE_SIZE) as i32,
+        );
+        let gc_roots_stack_ptr: wasmgen::GlobalIdx = wasm_module.add_i32_global(
+            wasmgen::Mut::Var,
+            ((mature_begin + initial_mature_usable_size * 2) * WASM_PAGE_SIZE) as i32,
+        );
+
+        // copy_$i functions, indexed by VarType::tag().
+        // Both kinds of collections copy objects into the mature space.
+        let copy_funcs: Box<[Option<wasmgen::FuncIdx>]> =
+            copy_funcs::make_copy_funcs(wasm_module, struct_sizes, mature_free_mem_ptr);
+        assert!(copy_funcs.len() == ir::NUM_PRIMITIVE_TAG_TYPES + struct_sizes.len() + 1);
+
+        let tableidx: wasmgen::TableIdx = wasm_module.get_or_add_table();
+
+        // tables for the major collection, which copies everything on the heap
+        let copy_indirect_table_offset: u32 = copy_indirect_elements::make_copy_indirect_elements(
+            wasm_module,
+            struct_sizes.len(),
+            &copy_funcs,
+            tableidx,
+            heap_begin,
+            None,
+        );
+        let copy_children_table_offset: u32 = copy_children_elements::make_copy_children_elements(
+            wasm_module,
+            struct_types,
+            struct_field_byte_offsets,
+            struct_sizes,
+            tableidx,
+            copy_indirect_table_offset,
+            &copy_funcs,
+            heap_begin,
+            None,
+        );
+
+        // tables for the minor collection, which only copies objects in the nursery
+        let minor_copy_indirect_table_offset: u32 =
+            copy_indirect_elements::make_copy_indirect_elements(
+                wasm_module,
+                struct_sizes.len(),
+                &copy_funcs,
+                tableidx,
+                heap_begin,
+                Some(nursery_end),
+            );
+        let minor_copy_children_table_offset: u32 =
+            copy_children_elements::make_copy_children_elements(
+                wasm_module,
+                struct_types,
+                struct_field_byte_offsets,
+                struct_sizes,
+                tableidx,
+                minor_copy_indirect_table_offset,
+                &copy_funcs,
+                heap_begin,
+                Some(nursery_end),
+            );
+
+        // the major collection is a Cheney collection of the mature space, which also empties the nursery
+        let do_cheney_funcidx: wasmgen::FuncIdx = do_cheney::make_do_cheney(
+            wasm_module,
+            tableidx,
+            copy_indirect_table_offset,
+            copy_children_table_offset,
+            memidx,
+            mature_free_mem_ptr,
+            mature_end_mem_ptr,
+            gc_roots_stack_base_ptr,
+            gc_roots_stack_ptr,
+            &copy_funcs,
+            global_var_manager,
+            stackptr,
+            stack_end,
+            heap_begin,
+            mature_begin,
+        );
+
+        let do_minor_funcidx: wasmgen::FuncIdx = do_minor::make_do_minor(
+            wasm_module,
+            tableidx,
+            minor_copy_indirect_table_offset,
+            minor_copy_children_table_offset,
+            free_mem_ptr,
+            remembered_set_ptr,
+            mature_free_mem_ptr,
+            gc_roots_stack_base_ptr,
+            gc_roots_stack_ptr,
+            &copy_funcs,
+            global_var_manager,
+            stackptr,
+            stack_end,
+            heap_begin,
+            nursery_end,
+        );
+
+        let stats: GcStats = GcStats::new(memidx, heap_begin, heap_initial_end, wasm_module);
+
+        let alloc_slow_funcidx: wasmgen::FuncIdx = alloc_slow::make_alloc_slow(
+            wasm_module,
+            free_mem_ptr,
+            remembered_set_ptr,
+            mature_free_mem_ptr,
+            mature_end_mem_ptr,
+            gc_roots_stack_base_ptr,
+            do_cheney_funcidx,
+            do_minor_funcidx,
+            &stats,
+            heap_begin,
+            nursery_end,
+            mature_begin,
+            error_func,
+        );
+
+        Generational {
+            struct_types,
+            struct_field_byte_offsets,
+            struct_sizes,
+            free_mem_ptr,
+            remembered_set_ptr,
+            gc_roots_stack_ptr,
+            heap_begin,
+            alloc_slow_funcidx,
+            stats,
+        }
+    }
+
+    fn filter_roots(
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+    ) -> Box<[(ir::VarType, usize)]> {
+        local_types
+            .iter()
+            .copied()
+            .zip(local_map.iter().copied())
+            .filter(|(ir_vartype, _)| is_ptr_vartype(*ir_vartype))
+            .collect()
+    }
+
+    // Helper function used to encode heap allocation.
+    // `f` should be a function that has net wasm stack [] -> [i32(size)], it pushes the bytes required (including tag) on the stack.
+    // net wasm stack: [] -> [i32(ptr)]
+    fn encode_allocation<F: Fn(&mut wasmgen::ExprBuilder)>(
+        &self,
+        encode_size: F,
+        tag: i32,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        // Algorithm:
+        /*
+        if (nursery_end * WASM_PAGE_SIZE - free_mem_ptr < size) {
+            for local in local_roots {
+                if(local is Any, String, Func, or StructT) {
+                    *gc_roots_stack_ptr = to_any(local);
+                    gc_roots_stack_ptr += 12;
+                }
+            }
+            // note: `alloc_slow` might move objects, so all cache must be reloaded after calling it.
+            ret = alloc_slow(size, tag);
+            for local in local_roots.reversed() {
+                if(local is Any, String, Func, or StructT) {
+                    gc_roots_stack_ptr -= 12;
+                    local = from_any(*gc_roots_stack_ptr);
+                }
+            }
+        } else {
+            ret = free_mem_ptr;
+            *ret = tag;
+            free_mem_ptr += size;
+            ret += 4;
+        }
+        */
+
+        // (nursery_end * WASM_PAGE_SIZE - free_mem_ptr < size)
+        // net wasm stack: [] -> [cond(i32)]
+        expr_builder.i32_const(((self.heap_begin + NURSERY_SIZE) * WASM_PAGE_SIZE) as i32);
+        expr_builder.global_get(self.free_mem_ptr);
+        expr_builder.i32_sub();
+        encode_size(expr_builder);
+        expr_builder.i32_lt_u();
+
+        // net wasm stack: [cond(i32)] -> [res(i32)]
+        expr_builder.if_(&[wasmgen::ValType::I32]);
+        {
+            let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+
+            // save the new values of all the filtered roots on the gc_roots stack
+            // net wasm stack: [] -> []
+            self.encode_local_roots_prologue(
+                local_types,
+                local_map,
+                wasm_local_map,
+                scratch,
+                expr_builder,
+            );
+
+            // net wasm stack: [] -> []
+            encode_size(expr_builder);
+            expr_builder.i32_const(tag);
+            expr_builder.call(self.alloc_slow_funcidx);
+            expr_builder.local_set(localidx_ret);
+
+            // load back the new values of all the filtered roots
+            // net wasm stack: [] -> []
+            self.encode_local_roots_epilogue(
+                local_types,
+                local_map,
+                wasm_local_map,
+                scratch,
+                expr_builder,
+            );
+
+            // net wasm stack: [] -> [res(i32)]
+            expr_builder.local_get(localidx_ret);
+
+            scratch.pop_i32();
+        }
+        expr_builder.else_();
+        {
+            // net wasm stack: [] -> [res(i32)]
+            expr_builder.global_get(self.free_mem_ptr);
+            expr_builder.global_get(self.free_mem_ptr);
+            expr_builder.i32_const(tag);
+            expr_builder.i32_store(wasmgen::MemArg::new4(0));
+            expr_builder.global_get(self.free_mem_ptr);
+            encode_size(expr_builder);
+            expr_builder.i32_add();
+            expr_builder.global_set(self.free_mem_ptr);
+            expr_builder.i32_const(4);
+            expr_builder.i32_add();
+        }
+        expr_builder.end();
+    }
+
+    // Helper function to write Undefined to all Any fields in the struct
+    // and write nullptr (i.e. -1) to all String, Func::closure, StructT
+    // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+    fn encode_struct_init(
+        &self,
+        typeidx: usize,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
+        expr_builder.local_tee(localidx_ptr);
+        self.struct_types[typeidx]
+            .iter()
+            .zip(self.struct_field_byte_offsets[typeidx].iter())
+            .for_each(|(ir_vartype, byte_offset)| match ir_vartype {
+                ir::VarType::Any => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(ir::VarType::Unassigned.tag());
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
+                }
+                ir::VarType::String | ir::VarType::Array | ir::VarType::StructT { typeidx: _ } => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
+                }
+                ir::VarType::Func => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset + 4));
+                    // Note: "+4" above to access the closure
+                }
+                _ => {}
+            });
+        scratch.pop_i32();
+    }
+}
+
+// Returns true if values of the given type might contain a pointer (i.e. Any and all pointer types).
+fn is_ptr_vartype(ir_vartype: ir::VarType) -> bool {
+    !matches!(
+        ir_vartype,
+        ir::VarType::Unassigned
+            | ir::VarType::Undefined
+            | ir::VarType::Number
+            | ir::VarType::Boolean
+            | ir::VarType::Null
+    )
+}
+
+fn wasm_local_slice(
+    ir_vartype: ir::VarType,
+    wasm_local_map_idx: usize,
+    wasm_local_map: &[wasmgen::LocalIdx],
+) -> &[wasmgen::LocalIdx] {
+    &wasm_local_map[wasm_local_map_idx..(wasm_local_map_idx + encode_vartype(ir_vartype).len())]
+}
+
+impl<'a, 'b, 'c> HeapManager for Generational<'a, 'b, 'c> {
+    // Returns the minimum initial number of pages required by this heap HeapManager (which is also the default).
+    fn initial_heap_size() -> u32 {
+        MEM_INITIAL_HEAP_SIZE
+    }
+
+    // Encodes instructions to get a chunk of memory suitable for the given struct type specified by ir_vartype.
+    // It is guaranteed to be 4-byte aligned.
+    // net wasm stack: [] -> [i32(ptr)]
+    fn encode_fixed_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => {
+                let size = self.struct_sizes[typeidx];
+                assert!((size & 3) == 0, "struct size must be multiple of 4");
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.i32_const((size + 4) as i32);
+                    },
+                    ir_vartype.tag(),
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
+            }
+            ir::VarType::Array => {
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.i32_const(8 + 4);
+                    },
+                    ir_vartype.tag(),
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // Write an empty array header (len = 0, buf = nullptr)
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ptr);
+                    expr_builder.i32_const(0);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(4));
+                    expr_builder.local_get(localidx_ptr);
+                    scratch.pop_i32();
+                }
+            }
+            _ => panic!("incorrect VarType, expected StructT or Array"),
+        }
+    }
+
+    // Returns the number of bytes needed to initialize the given struct type on the stack (including the tag).
+    fn stack_allocation_size(&self, ir_vartype: ir::VarType) -> u32 {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => self.struct_sizes[typeidx] + 4,
+            _ => panic!("incorrect VarType, expected StructT"),
+        }
+    }
+
+    // Encodes instructions to initialize the given struct type in a chunk of memory on the stack.
+    // The tag is written like for heap objects, so that the GC can walk the stack to find the children of these objects.
+    // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+    fn encode_stack_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => {
+                // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+                {
+                    let localidx_chunk: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_chunk);
+                    expr_builder.i32_const(ir_vartype.tag());
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_chunk);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_add();
+                    scratch.pop_i32();
+                }
+
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
+            }
+            _ => panic!("incorrect VarType, expected StructT"),
+        }
+    }
+
+    // Encodes instructions to get a chunk of memory for an string/array of unknown size.  See `encode_fixed_allocation` for more detauls.
+    // The size need not be a multiple of 4.
+    // net wasm stack: [i32(num_bytes)] -> [i32(ptr)]
+    fn encode_dynamic_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::String => {
+                let localidx_str_len: wasmgen::LocalIdx = scratch.push_i32();
+                let localidx_mem_size: wasmgen::LocalIdx = scratch.push_i32();
+
+                {
+                    expr_builder.local_tee(localidx_str_len);
+                }
+
+                // Algorithm: mem_size = ((num_bytes + 11) & (~3))   // equivalent to (4 + round_up_to_multiple_of_4(num_bytes))
+                // net wasm stack: [i32(num_bytes)] -> []
+                expr_builder.i32_const(11);
+                expr_builder.i32_add();
+                expr_builder.i32_const(-4); // equivalent to (~3) in two's complement
+                expr_builder.i32_and();
+                expr_builder.local_set(localidx_mem_size);
+
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.local_get(localidx_mem_size);
+                    },
+                    ir_vartype.tag(),
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // write the string length
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ret);
+                    expr_builder.local_get(localidx_ret);
+                    expr_builder.local_get(localidx_str_len);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    scratch.pop_i32();
+                }
+
+                scratch.pop_i32();
+                scratch.pop_i32();
+            }
+            ir::VarType::Array => {
+                // this is the buffer of elements, not the array header
+                let localidx_num_bytes: wasmgen::LocalIdx = scratch.push_i32();
+
+                // net wasm stack: [i32(num_bytes)] -> []
+                expr_builder.local_set(localidx_num_bytes);
+
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.local_get(localidx_num_bytes);
+                        expr_builder.i32_const(8);
+                        expr_builder.i32_add();
+                    },
+                    (ir::NUM_PRIMITIVE_TAG_TYPES + self.struct_sizes.len()) as i32, // the array buffer tag
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // write the capacity
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ret);
+                    expr_builder.local_get(localidx_ret);
+                    expr_builder.local_get(localidx_num_bytes);
+                    expr_builder.i32_const(size_in_memory(ir::VarType::Any) as i32);
+                    expr_builder.i32_div_u();
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    scratch.pop_i32();
+                }
+
+                scratch.pop_i32();
+            }
+            _ => panic!("incorrect VarType, expected String or Array"),
+        }
+    }
+
+    type RootsStackHandle = ();
+
+    // Encodes instructions to push local variables to gc_roots stack.
+    // This should be called before a function which might allocate memory is called.
+    // It should be paired with a call to `encode_local_roots_elilogue()`.
+    // net wasm stack: [] -> []
+    fn encode_local_roots_prologue(
+        &self,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) -> Self::RootsStackHandle {
+        let filtered_roots: Box<[(ir::VarType, usize)]> =
+            Self::filter_roots(local_types, local_map);
+
+        // if there are no roots to add, then we don't need to load the gc_roots_stack_ptr.
+        // net wasm stack: [] -> []
+        if !filtered_roots.is_empty() {
+            let localidx_gc_roots_stack_ptr = scratch.push_i32();
+
+            // net wasm stack: [] -> [gc_roots_stack_ptr(i32)]
+            expr_builder.global_get(self.gc_roots_stack_ptr);
+
+            for (ir_vartype, index) in filtered_roots.iter().copied() {
+                // net wasm stack: [gc_roots_stack_ptr(i32)] -> []
+                expr_builder.local_tee(localidx_gc_roots_stack_ptr);
+                encode_load_local(
+                    wasm_local_slice(ir_vartype, index, wasm_local_map),
+                    ir_vartype,
+                    ir_vartype,
+                    expr_builder,
+                );
+                encode_store_memory(0, ir::VarType::Any, ir_vartype, scratch, expr_builder);
+
+                // net wasm stack: [] -> [gc_roots_stack_ptr(i32)]
+                expr_builder.local_get(localidx_gc_roots_stack_ptr);
+                expr_builder.i32_const(12);
+                expr_builder.i32_add();
+            }
+
+            // net wasm stack: [gc_roots_stack_ptr(i32)] -> []
+            expr_builder.global_set(self.gc_roots_stack_ptr);
+
+            scratch.pop_i32();
+        }
+    }
+
+    // Encodes instructions to pop local variables from gc_roots stack.
+    // This should be called after a function which might allocate memory is called.
+    // It should be paired with a call to `encode_local_roots_prologue()`.
+    // net wasm stack: [] -> []
+    fn encode_local_roots_epilogue(
+        &self,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        let filtered_roots: Box<[(ir::VarType, usize)]> =
+            Self::filter_roots(local_types, local_map);
+
+        if !filtered_roots.is_empty() {
+            let localidx_gc_roots_stack_ptr = scratch.push_i32();
+
+            // net wasm stack: [] -> [gc_roots_stack_ptr(i32)]
+            expr_builder.global_get(self.gc_roots_stack_ptr);
+
+            for (ir_vartype, index) in filtered_roots.iter().cloned().rev() {
+                // net wasm stack: [gc_roots_stack_ptr(i32)] -> [gc_roots_stack_ptr(i32)]
+                expr_builder.i32_const(12);
+                expr_builder.i32_sub();
+
+                // net wasm stack: [gc_roots_stack_ptr(i32)] -> []
+                expr_builder.local_tee(localidx_gc_roots_stack_ptr);
+                encode_load_memory(0, ir::VarType::Any, ir_vartype, scratch, expr_builder);
+                encode_store_local(
+                    wasm_local_slice(ir_vartype, index, wasm_local_map),
+                    ir_vartype,
+                    ir_vartype,
+                    expr_builder,
+                );
+
+                // net wasm stack: [] -> [gc_roots_stack_ptr(i32)]
+                expr_builder.local_get(localidx_gc_roots_stack_ptr);
+            }
+
+            // net wasm stack: [gc_roots_stack_ptr(i32)] -> []
+            expr_builder.global_set(self.gc_roots_stack_ptr);
+
+            scratch.pop_i32();
+        }
+    }
+
+    // Encodes instructions to read a local variable from an arbitary position in the gc_roots stack, relative to the past-the-top position.
+    // net wasm stack: [] -> []
+    fn encode_local_root_read(
+        &self,
+        _local_root: (ir::VarType, wasmgen::LocalIdx),
+        _handle: Self::RootsStackHandle,
+        _scratch: &mut Scratch,
+        _expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        todo!();
+    }
+
+    // Encodes instructions to write a local variable to an arbitary position in the gc_roots stack, relative to the past-the-top position.
+    // net wasm stack: [] -> []
+    fn encode_local_root_write(
+        &self,
+        _local_root: (ir::VarType, wasmgen::LocalIdx),
+        _handle: Self::RootsStackHandle,
+        _scratch: &mut Scratch,
+        _expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        todo!();
+    }
+
+    // We allow Undefined (which is encoded as the nullptr value),
+    // and any reference type (i.e. strings and structs)
+    // net wasm stack: [<closure_irvartype>] -> [i32(closure)]
+    fn encode_closure_conversion(
+        &self,
+        vartype: ir::VarType,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match vartype {
+            ir::VarType::Undefined => expr_builder.i32_const(-1),
+            ir::VarType::String | ir::VarType::StructT { typeidx: _ } => {}
+            _ => panic!("VarType is not undefined and also not a reference type"),
+        }
+    }
+
+    // Adds the object to the remembered set if it is in the mature space, so that the next minor collection will copy the children of the object.
+    // If the remembered set is full, the object isn't added, and the next collection will be a major collection instead.
+    // Values that can't contain a pointer don't need a write barrier.
+    // net wasm stack: [] -> []
+    fn encode_write_barrier<F: FnOnce(&mut wasmgen::ExprBuilder)>(
+        &self,
+        ir_vartype: ir::VarType,
+        encode_obj: F,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        if !is_ptr_vartype(ir_vartype) {
+            return;
+        }
+
+        // Algorithm:
+        /*
+        if (obj >= mature_begin * WASM_PAGE_SIZE) {
+            let it = remembered_set_ptr;
+            if (it != remembered_set_end * WASM_PAGE_SIZE && *(it-4) != obj) { // don't add the same object twice in a row
+                *it = obj;
+                remembered_set_ptr = it + 4;
+            }
+        }
+        */
+
+        let remembered_set_end: u32 = self.heap_begin + NURSERY_SIZE + REMEMBERED_SET_SIZE;
+        let mature_begin: u32 = remembered_set_end;
+
+        let localidx_obj: wasmgen::LocalIdx = scratch.push_i32();
+        let localidx_it: wasmgen::LocalIdx = scratch.push_i32();
+
+        // net wasm stack: [] -> [cond(i32)]
+        encode_obj(expr_builder);
+        expr_builder.local_tee(localidx_obj);
+        expr_builder.i32_const((mature_begin * WASM_PAGE_SIZE) as i32);
+        expr_builder.i32_ge_u();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.global_get(self.remembered_set_ptr);
+            expr_builder.local_tee(localidx_it);
+            expr_builder.i32_const((remembered_set_end * WASM_PAGE_SIZE) as i32);
+            expr_builder.i32_ne();
+            expr_builder.local_get(localidx_it);
+            expr_builder.i32_const(4);
+            expr_builder.i32_sub();
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.local_get(localidx_obj);
+            expr_builder.i32_ne();
+            expr_builder.i32_and();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                expr_builder.local_get(localidx_it);
+                expr_builder.local_get(localidx_obj);
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                expr_builder.local_get(localidx_it);
+                expr_builder.i32_const(4);
+                expr_builder.i32_add();
+                expr_builder.global_set(self.remembered_set_ptr);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        scratch.pop_i32();
+        scratch.pop_i32();
+    }
+
+    // The objects in the nursery are counted from free_mem_ptr, since alloc_slow() only counts them when the nursery is emptied.
+    fn encode_write_stats(&self, ptr: u32, expr_builder: &mut wasmgen::ExprBuilder) {
+        self.stats.encode_write(
+            ptr,
+            |expr_builder| {
+                // net wasm stack: [] -> [i32(num_bytes)]
+                expr_builder.global_get(self.free_mem_ptr);
+                expr_builder.i32_const((self.heap_begin * WASM_PAGE_SIZE) as i32);
+                expr_builder.i32_sub();
+            },
+            expr_builder,
+        );
+    }
+
+    // Encodes instructions to initialize locals that could potentially go onto the gc_roots stack.
+    // `local_types` and `local_map` should have equal length, containing just those locals that should be initialized.
+    // `wasm_local_map` should not be sliced by the caller, because we need to preserve the indexing so that `local_map` will refer to the correct indices in `wasm_local_map`.
+    fn encode_local_roots_init(
+        &self,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        _scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        for (ir_vartype, wasm_local_map_index) in
+            local_types.iter().copied().zip(local_map.iter().copied())
+        {
+            match ir_vartype {
+                ir::VarType::String | ir::VarType::Array | ir::VarType::StructT { typeidx: _ } => {
+                    expr_builder.i32_const(-1);
+                    expr_builder.local_set(wasm_local_map[wasm_local_map_index]);
+                }
+                ir::VarType::Func => {
+                    expr_builder.i32_const(-1);
+                    expr_builder.local_set(wasm_local_map[wasm_local_map_index + 1]);
+                    // Note: "+1" above to access the closure
+                }
+                ir::VarType::Any => {
+                    expr_builder.i32_const(ir::VarType::Unassigned.tag());
+                    expr_builder.local_set(wasm_local_map[wasm_local_map_index]);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_runner::*;
+    use crate::{HeapManagerKind, Options};
+
+    // function garbage(n) { for (let i = 0; i < n; i = i + 1) { g = { 0: -1 }; } }
+    // function main() { <main> }
+    // where struct#0 is { 0: any } and struct#1 is { 0: number, 1: any }.
+    // The garbage has its field written, so that it overwrites whatever was in the nursery before.
+    fn garbage_program(main: &str) -> String {
+        format!(
+            r#"
+            struct#0 = (any);
+            struct#1 = (number any);
+            global#0 = any;
+            global#1 = any;
+            func#0 (number) -> undefined {{
+              (decl:undefined number (number:number 0.0)
+                (loop:undefined
+                  (if:undefined (prim:boolean number_lt (var:number local#1) (var:number local#0))
+                    (seq:!
+                      (decl:undefined struct#0 (struct:struct#0 struct#0)
+                        (seq:undefined
+                          (assign:undefined local#2.0:0 (number:number -1.0))
+                          (assign:undefined global#0 (var:struct#0 local#2))))
+                      (assign:undefined local#1 (prim:number number_add (var:number local#1) (number:number 1.0)))
+                      (break:! 0 (undefined:undefined)))
+                    (undefined:undefined))))
+            }}
+            func#1 () -> any {{
+              {}
+            }}
+            entry func#1;
+            "#,
+            main
+        )
+    }
+
+    fn generational() -> Options {
+        Options::new_builder()
+            .with_heap_manager(HeapManagerKind::Generational)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn mature_object_keeps_young_objects_alive() {
+        // const box = { 0: null }; g1 = box; garbage(20000);
+        // for (let i = 0; i < 1000; i = i + 1) { box[0] = { 0: i, 1: box[0] }; garbage(100); }
+        // <sum of the list at box[0]>
+        // The box is promoted by the first collection, and then only points to young objects,
+        // which are only reachable through the remembered set.
+        let text = garbage_program(
+            r#"
+            (decl:any struct#0 (struct:struct#0 struct#0)
+              (seq:any
+                (assign:undefined global#1 (var:struct#0 local#0))
+                (assign:undefined local#0.0:0 (null:null))
+                (call:undefined func#0 (number:number 20000.0))
+                (decl:undefined number (number:number 0.0)
+                  (loop:undefined
+                    (if:undefined (prim:boolean number_lt (var:number local#1) (number:number 1000.0))
+                      (decl:! struct#1 (struct:struct#1 struct#1)
+                        (seq:!
+                          (assign:undefined local#2.1:0 (var:number local#1))
+                          (assign:undefined local#2.1:1 (var:any local#0.0:0))
+                          (assign:undefined local#0.0:0 (var:struct#1 local#2))
+                          (call:undefined func#0 (number:number 100.0))
+                          (assign:undefined local#1 (prim:number number_add (var:number local#1) (number:number 1.0)))
+                          (break:! 0 (undefined:undefined))))
+                      (undefined:undefined))))
+                (decl:any any (var:any local#0.0:0)
+                  (decl:any number (number:number 0.0)
+                    (seq:any
+                      (loop:undefined
+                        (typecast:undefined struct#1 narrow (var:any local#1)
+                          (seq:!
+                            (assign:undefined local#2 (prim:number number_add (var:number local#2) (var:number local#3.1:0)))
+                            (assign:undefined local#1 (var:any local#3.1:1))
+                            (break:! 0 (undefined:undefined)))
+                          (undefined:undefined)))
+                      (var:number local#2))))))
+            "#,
+        );
+        assert_eq!(
+            run_ir_all_levels(&text, generational()),
+            Ok(Value::Number(499500.0))
+        );
+        let (_, stats) = run_ir_with_stats(&text, "-O2", generational());
+        assert!(stats.collections > 1);
+    }
+
+    #[test]
+    fn remembered_set_overflow() {
+        // const xs = []; for (let i = 0; i < 20000; i = i + 1) { xs[i] = { 0: undefined }; } garbage(20000);
+        // const y = { 0: 7 }; for (let i = 0; i < 20000; i = i + 1) { xs[i][0] = y; } garbage(40000);
+        // <sum of xs[i][0][0]>
+        // The buffer of xs is too large for the nursery, so it is allocated in the mature space.
+        // All the elements of xs are promoted before they point to y, which overflows the remembered set,
+        // so the next collection has to be a major collection.
+        let text = garbage_program(
+            r#"
+            (decl:any array (prim:array array_new)
+              (seq:any
+                (decl:undefined number (number:number 0.0)
+                  (loop:undefined
+                    (if:undefined (prim:boolean number_lt (var:number local#1) (number:number 20000.0))
+                      (seq:!
+                        (prim:undefined array_set (var:array local#0) (var:number local#1) (struct:struct#0 struct#0))
+                        (assign:undefined local#1 (prim:number number_add (var:number local#1) (number:number 1.0)))
+                        (break:! 0 (undefined:undefined)))
+                      (undefined:undefined))))
+                (call:undefined func#0 (number:number 20000.0))
+                (decl:undefined struct#1 (struct:struct#1 struct#1)
+                  (seq:undefined
+                    (assign:undefined local#1.1:0 (number:number 7.0))
+                    (decl:undefined number (number:number 0.0)
+                      (loop:undefined
+                        (if:undefined (prim:boolean number_lt (var:number local#2) (number:number 20000.0))
+                          (seq:!
+                            (typecast:undefined struct#0 narrow (prim:any array_get (var:array local#0) (var:number local#2))
+                              (assign:undefined local#3.0:0 (var:struct#1 local#1))
+                              (trap:! 0x13 0:1:1-1:2))
+                            (assign:undefined local#2 (prim:number number_add (var:number local#2) (number:number 1.0)))
+                            (break:! 0 (undefined:undefined)))
+                          (undefined:undefined))))))
+                (call:undefined func#0 (number:number 40000.0))
+                (decl:any number (number:number 0.0)
+                  (decl:any number (number:number 0.0)
+                    (seq:any
+                      (loop:undefined
+                        (if:undefined (prim:boolean number_lt (var:number local#2) (number:number 20000.0))
+                          (seq:!
+                            (typecast:undefined struct#0 narrow (prim:any array_get (var:array local#0) (var:number local#2))
+                              (typecast:undefined struct#1 narrow (var:any local#3.0:0)
+                                (assign:undefined local#1 (prim:number number_add (var:number local#1) (var:number local#4.1:0)))
+                                (trap:! 0x13 0:1:1-1:2))
+                              (trap:! 0x13 0:1:1-1:2))
+                            (assign:undefined local#2 (prim:number number_add (var:number local#2) (number:number 1.0)))
+                            (break:! 0 (undefined:undefined)))
+                          (undefined:undefined)))
+                      (var:number local#1))))))
+            "#,
+        );
+        assert_eq!(
+            run_ir_all_levels(&text, generational()),
+            Ok(Value::Number(140000.0))
+        );
+        // with a larger mature space, the overflow is the only reason for a major collection
+        let options = Options::new_builder()
+            .with_heap_manager(HeapManagerKind::Generational)
+            .with_heap_initial_pages(256)
+            .build()
+            .unwrap();
+        assert_eq!(run_ir(&text, "-O2", options), Ok(Value::Number(140000.0)));
+    }
+}
@@ -1,3 +1,4 @@
+use super::GcStats;
 use super::WASM_PAGE_BITS;
 use super::WASM_PAGE_SIZE;
 use wasmgen::Scratch;
@@ -21,9 +22,11 @@ pub struct Leaky<'a, 'b, 'c> {
     end_mem_ptr: wasmgen::GlobalIdx, // Global that stores pointer to past-the-end of heap
     heap_begin: u32,         // in page units
     error_func: wasmgen::FuncIdx, // function to call when out of memory
+    stats: GcStats, // counters for the gc stats (there are no collections, so only bytes_allocated and peak_heap_pages change)
 }
 
-const MEM_INITIAL_HEAP_SIZE: u32 = 1 << 4; // 1 MiB of initial heap space
+const MEM_INITIAL_HEAP_SIZE: u32 = 1 << 4; // 1 MiB of initial heap space (at least)
+const TAG_SIZE: u32 = 4; // objects don't have tags here, but the gc stats count one for each object, like the other heap managers
 
 impl<'a, 'b, 'c> Leaky<'a, 'b, 'c> {
     // Constructs a new leaky GC, and initializes it appropriately.
@@ -37,7 +40,7 @@ impl<'a, 'b, 'c> Leaky<'a, 'b, 'c> {
         error_func: wasmgen::FuncIdx,
         wasm_module: &mut wasmgen::WasmModule,
     ) -> Self {
-        assert!(heap_begin + MEM_INITIAL_HEAP_SIZE == heap_initial_end);
+        assert!(heap_begin + MEM_INITIAL_HEAP_SIZE <= heap_initial_end);
         Leaky {
             struct_types: struct_types,
             struct_field_byte_offsets: struct_field_byte_offsets,
@@ -51,6 +54,7 @@ impl<'a, 'b, 'c> Leaky<'a, 'b, 'c> {
             ),
             heap_begin: heap_begin,
             error_func: error_func,
+            stats: GcStats::new(memidx, heap_begin, heap_initial_end, wasm_module),
         }
     }
 
@@ -72,7 +76,9 @@ impl<'a, 'b, 'c> Leaky<'a, 'b, 'c> {
         //     let opt_pages = curr_pages > required_pages ? curr_pages : required_pages;
         //     if(memory_grow(opt_pages) == -1) trap(ERR_OUT_OF_MEMORY);
         //     end_mem_ptr += opt_pages << WASM_PAGE_BITS;
+        //     stats.heap_grown();
         // }
+        // stats.bytes_allocated += TAG_SIZE;
         // return ret;
 
         let localidx_free_mem_ptr: wasmgen::LocalIdx = scratch.push_i32();
@@ -151,6 +157,9 @@ impl<'a, 'b, 'c> Leaky<'a, 'b, 'c> {
             expr_builder.i32_add();
             expr_builder.global_set(self.end_mem_ptr);
 
+            // net wasm stack: [] -> []
+            self.stats.encode_heap_grown(expr_builder);
+
             scratch.pop_i32();
             scratch.pop_i32();
             scratch.pop_i32();
@@ -158,6 +167,11 @@ impl<'a, 'b, 'c> Leaky<'a, 'b, 'c> {
         // END IF
         expr_builder.end();
 
+        // the object itself is counted by encode_write_stats(), but it doesn't know how many objects there are
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(TAG_SIZE as i32);
+        self.stats.encode_add_allocated(expr_builder);
+
         // write the free_mem_ptr back:
         // net wasm stack (for following 2 instructions) : [] -> []
         expr_builder.local_get(localidx_free_mem_ptr);
@@ -166,10 +180,33 @@ impl<'a, 'b, 'c> Leaky<'a, 'b, 'c> {
         scratch.pop_i32();
         scratch.pop_i32();
     }
+
+    // Helper function to write Undefined to all Any fields in the struct
+    // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+    fn encode_struct_init(
+        &self,
+        typeidx: usize,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
+        expr_builder.local_tee(localidx_ptr);
+        self.struct_types[typeidx]
+            .iter()
+            .zip(self.struct_field_byte_offsets[typeidx].iter())
+            .for_each(|(ir_vartype, byte_offset)| {
+                if *ir_vartype == ir::VarType::Any {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(ir::VarType::Unassigned.tag());
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
+                }
+            });
+        scratch.pop_i32();
+    }
 }
 
 impl<'a, 'b, 'c> super::HeapManager for Leaky<'a, 'b, 'c> {
-    // Returns the initial number of pages required by this heap HeapManager.
+    // Returns the minimum initial number of pages required by this heap HeapManager (which is also the default).
     fn initial_heap_size() -> u32 {
         MEM_INITIAL_HEAP_SIZE
     }
@@ -200,24 +237,59 @@ impl<'a, 'b, 'c> super::HeapManager for Leaky<'a, 'b, 'c> {
                     expr_builder,
                 );
 
-                // Write Undefined to all Any fields in the struct
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
+            }
+            ir::VarType::Array => {
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.i32_const(8);
+                    },
+                    scratch,
+                    expr_builder,
+                );
+
+                // Write an empty array header (len = 0, buf = nullptr)
                 // net wasm stack: [i32(ptr)] -> [i32(ptr)]
                 {
                     let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
                     expr_builder.local_tee(localidx_ptr);
-                    self.struct_types[typeidx]
-                        .iter()
-                        .zip(self.struct_field_byte_offsets[typeidx].iter())
-                        .for_each(|(ir_vartype, byte_offset)| {
-                            if *ir_vartype == ir::VarType::Any {
-                                expr_builder.local_get(localidx_ptr);
-                                expr_builder.i32_const(ir::VarType::Unassigned.tag());
-                                expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
-                            }
-                        });
+                    expr_builder.i32_const(0);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(4));
+                    expr_builder.local_get(localidx_ptr);
                     scratch.pop_i32();
                 }
             }
+            _ => panic!("incorrect VarType, expected StructT or Array"),
+        }
+    }
+
+    // Returns the number of bytes needed to initialize the given struct type on the stack.
+    fn stack_allocation_size(&self, ir_vartype: ir::VarType) -> u32 {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => self.struct_sizes[typeidx],
+            _ => panic!("incorrect VarType, expected StructT"),
+        }
+    }
+
+    // Encodes instructions to initialize the given struct type in a chunk of memory on the stack.
+    // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+    fn encode_stack_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => {
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
+            }
             _ => panic!("incorrect VarType, expected StructT"),
         }
     }
@@ -277,7 +349,42 @@ impl<'a, 'b, 'c> super::HeapManager for Leaky<'a, 'b, 'c> {
                 scratch.pop_i32();
                 scratch.pop_i32();
             }
-            _ => panic!("incorrect VarType, expected String"),
+            ir::VarType::Array => {
+                // this is the buffer of elements, not the array header
+                let localidx_num_bytes: wasmgen::LocalIdx = scratch.push_i32();
+
+                // net wasm stack: [i32(num_bytes)] -> []
+                expr_builder.local_set(localidx_num_bytes);
+
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.local_get(localidx_num_bytes);
+                        expr_builder.i32_const(4);
+                        expr_builder.i32_add();
+                    },
+                    scratch,
+                    expr_builder,
+                );
+
+                // write the capacity
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ret);
+                    expr_builder.local_get(localidx_ret);
+                    expr_builder.local_get(localidx_num_bytes);
+                    expr_builder
+                        .i32_const(crate::var_conv::size_in_memory(ir::VarType::Any) as i32);
+                    expr_builder.i32_div_u();
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    scratch.pop_i32();
+                }
+
+                scratch.pop_i32();
+            }
+            _ => panic!("incorrect VarType, expected String or Array"),
         }
     }
 
@@ -298,6 +405,20 @@ impl<'a, 'b, 'c> super::HeapManager for Leaky<'a, 'b, 'c> {
         // Do nothing - because our memory manager will never collect garbage.  The garbage will leak.
     }
 
+    // Everything in the heap before free_mem_ptr has been allocated.
+    fn encode_write_stats(&self, ptr: u32, expr_builder: &mut wasmgen::ExprBuilder) {
+        self.stats.encode_write(
+            ptr,
+            |expr_builder| {
+                // net wasm stack: [] -> [i32(num_bytes)]
+                expr_builder.global_get(self.free_mem_ptr);
+                expr_builder.i32_const((self.heap_begin * WASM_PAGE_SIZE) as i32);
+                expr_builder.i32_sub();
+            },
+            expr_builder,
+        );
+    }
+
     // Encodes instructions to pop local variables from gc_roots stack.
     // This should be called after a function which might allocate memory is called.
     // It should be paired with a call to `encode_local_roots_prologue()`.
@@ -350,6 +471,16 @@ impl<'a, 'b, 'c> super::HeapManager for Leaky<'a, 'b, 'c> {
         }
     }
 
+    fn encode_write_barrier<F: FnOnce(&mut wasmgen::ExprBuilder)>(
+        &self,
+        _ir_vartype: ir::VarType,
+        _encode_obj: F,
+        _scratch: &mut Scratch,
+        _expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        // Do nothing - because our memory manager will never collect garbage.  The garbage will leak.
+    }
+
     fn encode_local_roots_init(
         &self,
         _local_types: &[ir::VarType],
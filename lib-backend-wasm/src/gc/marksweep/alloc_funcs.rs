@@ -0,0 +1,521 @@
+use super::GcStats;
+use super::WASM_PAGE_BITS;
+use super::WASM_PAGE_SIZE;
+use super::{FREE_LISTS_SIZE, FREE_TAG, MAX_SMALL_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+// Encodes alloc_fast(), which takes a chunk from the free lists without running a collection, and writes the tag of the object.
+// `size`: number of bytes we want (including the tag!)
+// Returns the ptr to the object (just after the tag), or 0 if no free chunk is large enough.
+// The size is added to stats.bytes_allocated when the allocation succeeds.
+pub fn make_alloc_fast(
+    wasm_module: &mut wasmgen::WasmModule,
+    stats: &GcStats,
+    heap_begin: u32,
+) -> wasmgen::FuncIdx {
+    /*
+    fn alloc_fast(size: i32, tag: i32) -> i32 {
+        let chunk_size = max((size + 4 + 7) & (-8), MIN_CHUNK_SIZE); // add the chunk size field, and round up to a multiple of 8
+        if (chunk_size <= MAX_SMALL_CHUNK_SIZE) {
+            let c = *(heap_begin * WASM_PAGE_SIZE + (chunk_size >> 1));
+            if (c != 0) {
+                *(heap_begin * WASM_PAGE_SIZE + (chunk_size >> 1)) = *(c+8);
+                stats.bytes_allocated += size;
+                *(c+4) = tag;
+                return c + 8;
+            }
+        }
+        // first fit in the list of large chunks (which also supplies small chunks when their own list is empty)
+        let prev = heap_begin * WASM_PAGE_SIZE; // where the pointer to `c` is stored
+        let c = *prev;
+        while (c != 0) {
+            let remaining = *c - chunk_size;
+            if (remaining >= 0) {
+                if (remaining >= MIN_CHUNK_SIZE) {
+                    // split the chunk, and take the end of it so that the rest of it stays in the list
+                    *c = remaining;
+                    c += remaining;
+                    *c = chunk_size;
+                } else {
+                    // take the whole chunk
+                    *prev = *(c+8);
+                }
+                stats.bytes_allocated += size;
+                *(c+4) = tag;
+                return c + 8;
+            }
+            prev = c + 8;
+            c = *prev;
+        }
+        return 0;
+    }
+    */
+
+    let heap_begin_ptr: i32 = (heap_begin * WASM_PAGE_SIZE) as i32;
+
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32, wasmgen::ValType::I32]),
+        Box::new([wasmgen::ValType::I32]),
+    );
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let localidx_size = wasmgen::LocalIdx { idx: 0 };
+        let localidx_tag = wasmgen::LocalIdx { idx: 1 };
+        let localidx_chunk_size = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_c = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_prev = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_remaining = locals_builder.add(wasmgen::ValType::I32);
+
+        // let chunk_size = max((size + 4 + 7) & (-8), MIN_CHUNK_SIZE);
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_size);
+        expr_builder.i32_const(4 + 7);
+        expr_builder.i32_add();
+        expr_builder.i32_const(-8);
+        expr_builder.i32_and();
+        expr_builder.local_tee(localidx_chunk_size);
+        expr_builder.i32_const(MIN_CHUNK_SIZE as i32);
+        expr_builder.local_get(localidx_chunk_size);
+        expr_builder.i32_const(MIN_CHUNK_SIZE as i32);
+        expr_builder.i32_ge_u();
+        expr_builder.select();
+        expr_builder.local_set(localidx_chunk_size);
+
+        // if (chunk_size <= MAX_SMALL_CHUNK_SIZE)
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_chunk_size);
+        expr_builder.i32_const(MAX_SMALL_CHUNK_SIZE as i32);
+        expr_builder.i32_le_u();
+        expr_builder.if_(&[]);
+        {
+            // let c = *(heap_begin * WASM_PAGE_SIZE + (chunk_size >> 1));
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.local_get(localidx_chunk_size);
+            expr_builder.i32_const(1);
+            expr_builder.i32_shr_u();
+            expr_builder.i32_load(wasmgen::MemArg::new4(heap_begin_ptr as u32));
+            expr_builder.local_tee(localidx_c);
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                // *(heap_begin * WASM_PAGE_SIZE + (chunk_size >> 1)) = *(c+8);
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_chunk_size);
+                expr_builder.i32_const(1);
+                expr_builder.i32_shr_u();
+                expr_builder.local_get(localidx_c);
+                expr_builder.i32_load(wasmgen::MemArg::new4(8));
+                expr_builder.i32_store(wasmgen::MemArg::new4(heap_begin_ptr as u32));
+
+                // stats.bytes_allocated += size;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_size);
+                stats.encode_add_allocated(expr_builder);
+
+                // *(c+4) = tag;
+                // return c + 8;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_c);
+                expr_builder.local_get(localidx_tag);
+                expr_builder.i32_store(wasmgen::MemArg::new4(4));
+                expr_builder.local_get(localidx_c);
+                expr_builder.i32_const(8);
+                expr_builder.i32_add();
+                expr_builder.return_();
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        // let prev = heap_begin * WASM_PAGE_SIZE;
+        // let c = *prev;
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(heap_begin_ptr);
+        expr_builder.local_tee(localidx_prev);
+        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+        expr_builder.local_set(localidx_c);
+
+        // net wasm stack: [] -> []
+        expr_builder.block(&[]);
+        {
+            expr_builder.loop_(&[]);
+            {
+                // if (c == 0) break;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_c);
+                expr_builder.i32_eqz();
+                expr_builder.br_if(1);
+
+                // let remaining = *c - chunk_size;
+                // net wasm stack: [] -> [cond(i32)]
+                expr_builder.local_get(localidx_c);
+                expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                expr_builder.local_get(localidx_chunk_size);
+                expr_builder.i32_sub();
+                expr_builder.local_tee(localidx_remaining);
+                expr_builder.i32_const(0);
+                expr_builder.i32_ge_s();
+
+                // net wasm stack: [cond(i32)] -> []
+                expr_builder.if_(&[]);
+                {
+                    // net wasm stack: [] -> [cond(i32)]
+                    expr_builder.local_get(localidx_remaining);
+                    expr_builder.i32_const(MIN_CHUNK_SIZE as i32);
+                    expr_builder.i32_ge_s();
+
+                    // net wasm stack: [cond(i32)] -> []
+                    expr_builder.if_(&[]);
+                    {
+                        // *c = remaining;
+                        // c += remaining;
+                        // *c = chunk_size;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_c);
+                        expr_builder.local_get(localidx_remaining);
+                        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                        expr_builder.local_get(localidx_c);
+                        expr_builder.local_get(localidx_remaining);
+                        expr_builder.i32_add();
+                        expr_builder.local_tee(localidx_c);
+                        expr_builder.local_get(localidx_chunk_size);
+                        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    }
+                    expr_builder.else_();
+                    {
+                        // *prev = *(c+8);
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_prev);
+                        expr_builder.local_get(localidx_c);
+                        expr_builder.i32_load(wasmgen::MemArg::new4(8));
+                        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    }
+                    expr_builder.end();
+
+                    // stats.bytes_allocated += size;
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_size);
+                    stats.encode_add_allocated(expr_builder);
+
+                    // *(c+4) = tag;
+                    // return c + 8;
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_c);
+                    expr_builder.local_get(localidx_tag);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(4));
+                    expr_builder.local_get(localidx_c);
+                    expr_builder.i32_const(8);
+                    expr_builder.i32_add();
+                    expr_builder.return_();
+                }
+                expr_builder.end();
+
+                // prev = c + 8;
+                // c = *prev;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_c);
+                expr_builder.i32_const(8);
+                expr_builder.i32_add();
+                expr_builder.local_tee(localidx_prev);
+                expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                expr_builder.local_set(localidx_c);
+
+                expr_builder.br(0);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        // return 0;
+        // net wasm stack: [] -> [ret(i32)]
+        expr_builder.i32_const(0);
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
+
+// Encodes alloc_slow(), which is called when alloc_fast() fails.
+// It runs a collection, grows the memory if necessary, and then allocates the object and writes its tag.
+// `size`: number of bytes we want (including the tag!)
+// Returns the ptr to the object (just after the tag).  Raises an out-of-memory error if it can't find enough space.
+pub fn make_alloc_slow(
+    wasm_module: &mut wasmgen::WasmModule,
+    memidx: wasmgen::MemIdx,
+    globalidx_chunks_end_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_base_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_ptr: wasmgen::GlobalIdx,
+    alloc_fast_funcidx: wasmgen::FuncIdx,
+    do_mark_sweep_funcidx: wasmgen::FuncIdx,
+    stats: &GcStats,
+    heap_begin: u32,
+    error_func: wasmgen::FuncIdx,
+) -> wasmgen::FuncIdx {
+    /*
+    fn alloc_slow(size: i32, tag: i32) -> i32 {
+        let free_bytes = do_mark_sweep();
+        stats.collections += 1;
+        stats.bytes_surviving = (chunks_end_ptr - heap_begin * WASM_PAGE_SIZE - FREE_LISTS_SIZE) - free_bytes;
+        let ret = alloc_fast(size, tag);
+        // grow the memory if the object still doesn't fit, or if more than half of the chunks are in use (so that collections don't happen too often)
+        if (ret == 0 || free_bytes < (chunks_end_ptr - heap_begin * WASM_PAGE_SIZE) >> 1) {
+            // the chunks at least double, and they grow by at least enough to fit the object
+            let delta_chunks = round_up_to_multiple_of_4_pages((chunks_end_ptr - heap_begin * WASM_PAGE_SIZE) + size + 4 + 4);
+            let request_delta = delta_chunks + (delta_chunks >> 2); // the mark stack needs to grow by a quarter of that
+            if (memory_grow(request_delta >> WASM_PAGE_BITS) != -1) {
+                move the gc_roots stack rightward by request_delta (see gc::cheney::do_cheney);
+                // the new space (which begins with the old mark stack) becomes a free chunk
+                *chunks_end_ptr = delta_chunks;
+                *(chunks_end_ptr+4) = FREE_TAG;
+                *(chunks_end_ptr+8) = *(heap_begin * WASM_PAGE_SIZE);
+                *(heap_begin * WASM_PAGE_SIZE) = chunks_end_ptr;
+                chunks_end_ptr += delta_chunks;
+                stats.heap_grown();
+                if (ret == 0) ret = alloc_fast(size, tag);
+            }
+        }
+        if (ret == 0) abort();
+        return ret;
+    }
+    Note: request_delta is always more than the gc_roots size, so moving the gc_roots stack never overwrites entries that haven't been moved yet.
+    */
+
+    let heap_begin_ptr: i32 = (heap_begin * WASM_PAGE_SIZE) as i32;
+    let four_pages: i32 = (WASM_PAGE_SIZE << 2) as i32;
+
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32, wasmgen::ValType::I32]),
+        Box::new([wasmgen::ValType::I32]),
+    );
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let localidx_size = wasmgen::LocalIdx { idx: 0 };
+        let localidx_tag = wasmgen::LocalIdx { idx: 1 };
+        let localidx_free_bytes = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_ret = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_chunks_end_ptr = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_delta_chunks = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_request_delta = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_it = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_it_end = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_tmp = locals_builder.add(wasmgen::ValType::I32);
+
+        // let free_bytes = do_mark_sweep();
+        // net wasm stack: [] -> []
+        expr_builder.call(do_mark_sweep_funcidx);
+        expr_builder.local_set(localidx_free_bytes);
+
+        // stats.collections += 1;
+        // stats.bytes_surviving = (chunks_end_ptr - heap_begin * WASM_PAGE_SIZE - FREE_LISTS_SIZE) - free_bytes;
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_chunks_end_ptr);
+        expr_builder.i32_const(heap_begin_ptr + FREE_LISTS_SIZE as i32);
+        expr_builder.i32_sub();
+        expr_builder.local_get(localidx_free_bytes);
+        expr_builder.i32_sub();
+        stats.encode_collection(expr_builder);
+
+        // let ret = alloc_fast(size, tag);
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_size);
+        expr_builder.local_get(localidx_tag);
+        expr_builder.call(alloc_fast_funcidx);
+        expr_builder.local_set(localidx_ret);
+
+        // (ret == 0 || free_bytes < (chunks_end_ptr - heap_begin * WASM_PAGE_SIZE) >> 1)
+        // net wasm stack: [] -> [cond(i32)]
+        expr_builder.local_get(localidx_ret);
+        expr_builder.i32_eqz();
+        expr_builder.local_get(localidx_free_bytes);
+        expr_builder.global_get(globalidx_chunks_end_ptr);
+        expr_builder.local_tee(localidx_chunks_end_ptr);
+        expr_builder.i32_const(heap_begin_ptr);
+        expr_builder.i32_sub();
+        expr_builder.i32_const(1);
+        expr_builder.i32_shr_u();
+        expr_builder.i32_lt_u();
+        expr_builder.i32_or();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // let delta_chunks = ((chunks_end_ptr - heap_begin * WASM_PAGE_SIZE) + size + 4 + 4 + (four_pages - 1)) & (-four_pages);
+            // let request_delta = delta_chunks + (delta_chunks >> 2);
+            // net wasm stack: [] -> [request_delta(i32)]
+            expr_builder.local_get(localidx_chunks_end_ptr);
+            expr_builder.i32_const(heap_begin_ptr);
+            expr_builder.i32_sub();
+            expr_builder.local_get(localidx_size);
+            expr_builder.i32_add();
+            expr_builder.i32_const(4 + 4 + (four_pages - 1));
+            expr_builder.i32_add();
+            expr_builder.i32_const(-four_pages);
+            expr_builder.i32_and();
+            expr_builder.local_tee(localidx_delta_chunks);
+            expr_builder.local_get(localidx_delta_chunks);
+            expr_builder.i32_const(2);
+            expr_builder.i32_shr_u();
+            expr_builder.i32_add();
+            expr_builder.local_tee(localidx_request_delta);
+
+            // (memory_grow(request_delta >> WASM_PAGE_BITS) != -1)
+            // net wasm stack: [request_delta(i32)] -> [cond(i32)]
+            expr_builder.i32_const(WASM_PAGE_BITS as i32);
+            expr_builder.i32_shr_u();
+            expr_builder.memory_grow(memidx);
+            expr_builder.i32_const(-1);
+            expr_builder.i32_ne();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                // let it = gc_roots_stack_base_ptr;
+                // let it_end = gc_roots_stack_ptr;
+                // gc_roots_stack_base_ptr += request_delta;
+                // let tmp = gc_roots_stack_base_ptr;
+                // net wasm stack: [] -> []
+                expr_builder.global_get(globalidx_gc_roots_stack_ptr);
+                expr_builder.local_set(localidx_it_end);
+                expr_builder.global_get(globalidx_gc_roots_stack_base_ptr);
+                expr_builder.local_tee(localidx_it);
+                expr_builder.local_get(localidx_request_delta);
+                expr_builder.i32_add();
+                expr_builder.local_tee(localidx_tmp);
+                expr_builder.global_set(globalidx_gc_roots_stack_base_ptr);
+
+                /*
+                while (it != it_end) {
+                    tmp->tag = it->tag;
+                    tmp->data = it->data;
+                    it += 12;
+                    tmp += 12;
+                }
+                */
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_it);
+                expr_builder.local_get(localidx_it_end);
+                expr_builder.i32_ne();
+                expr_builder.if_(&[]);
+                {
+                    expr_builder.loop_(&[]);
+                    {
+                        // tmp->tag = it->tag;
+                        // tmp->data = it->data;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_tmp);
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                        expr_builder.local_get(localidx_tmp);
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.i64_load(wasmgen::MemArg::new4(4));
+                        expr_builder.i64_store(wasmgen::MemArg::new4(4));
+
+                        // tmp += 12;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_tmp);
+                        expr_builder.i32_const(12);
+                        expr_builder.i32_add();
+                        expr_builder.local_set(localidx_tmp);
+
+                        // it += 12;
+                        // net wasm stack: [] -> [cond(i32)]
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.i32_const(12);
+                        expr_builder.i32_add();
+                        expr_builder.local_tee(localidx_it);
+                        expr_builder.local_get(localidx_it_end);
+                        expr_builder.i32_ne();
+                        expr_builder.br_if(0);
+                    }
+                    expr_builder.end();
+                }
+                expr_builder.end();
+
+                // gc_roots_stack_ptr = tmp;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_tmp);
+                expr_builder.global_set(globalidx_gc_roots_stack_ptr);
+
+                // *chunks_end_ptr = delta_chunks;
+                // *(chunks_end_ptr+4) = FREE_TAG;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_chunks_end_ptr);
+                expr_builder.local_get(localidx_delta_chunks);
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                expr_builder.local_get(localidx_chunks_end_ptr);
+                expr_builder.i32_const(FREE_TAG);
+                expr_builder.i32_store(wasmgen::MemArg::new4(4));
+
+                // *(chunks_end_ptr+8) = *(heap_begin * WASM_PAGE_SIZE);
+                // *(heap_begin * WASM_PAGE_SIZE) = chunks_end_ptr;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_chunks_end_ptr);
+                expr_builder.i32_const(heap_begin_ptr);
+                expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                expr_builder.i32_store(wasmgen::MemArg::new4(8));
+                expr_builder.i32_const(heap_begin_ptr);
+                expr_builder.local_get(localidx_chunks_end_ptr);
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+
+                // chunks_end_ptr += delta_chunks;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_chunks_end_ptr);
+                expr_builder.local_get(localidx_delta_chunks);
+                expr_builder.i32_add();
+                expr_builder.global_set(globalidx_chunks_end_ptr);
+
+                // stats.heap_grown();
+                // net wasm stack: [] -> []
+                stats.encode_heap_grown(expr_builder);
+
+                // if (ret == 0) ret = alloc_fast(size, tag);
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_ret);
+                expr_builder.i32_eqz();
+                expr_builder.if_(&[]);
+                {
+                    expr_builder.local_get(localidx_size);
+                    expr_builder.local_get(localidx_tag);
+                    expr_builder.call(alloc_fast_funcidx);
+                    expr_builder.local_set(localidx_ret);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        // if (ret == 0) abort();
+        // net wasm stack: [] -> []
+        expr_builder.local_get(localidx_ret);
+        expr_builder.i32_eqz();
+        expr_builder.if_(&[]);
+        {
+            // out of memory... raise an error
+            expr_builder.i32_const(ir::error::ERROR_CODE_OUT_OF_MEMORY as i32);
+            expr_builder.i32_const(0);
+            expr_builder.i32_const(0);
+            expr_builder.i32_const(0);
+            expr_builder.i32_const(0);
+            expr_builder.i32_const(0);
+            expr_builder.i32_const(0);
+            expr_builder.call(error_func);
+            expr_builder.unreachable();
+        }
+        expr_builder.end();
+
+        // return ret;
+        // net wasm stack: [] -> [ret(i32)]
+        expr_builder.local_get(localidx_ret);
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
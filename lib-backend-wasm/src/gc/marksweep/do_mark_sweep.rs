@@ -0,0 +1,425 @@
+use crate::global_var::GlobalVarManagerRef;
+
+use super::WASM_PAGE_SIZE;
+use super::{FREE_LISTS_SIZE, FREE_TAG, MAX_SMALL_CHUNK_SIZE};
+
+// Encodes do_mark_sweep(), which marks everything reachable from the roots, and then rebuilds the free lists from all the unmarked chunks.
+// Adjacent unmarked chunks are coalesced into one free chunk.
+// Returns the total size of the free chunks, in bytes.
+pub fn make_do_mark_sweep<'a>(
+    wasm_module: &mut wasmgen::WasmModule,
+    tableidx: wasmgen::TableIdx,
+    mark_children_table_offset: u32,
+    mark_funcidx: wasmgen::FuncIdx,
+    mark_any_funcidx: wasmgen::FuncIdx,
+    globalidx_chunks_end_ptr: wasmgen::GlobalIdx,
+    globalidx_mark_stack_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_base_ptr: wasmgen::GlobalIdx,
+    globalidx_gc_roots_stack_ptr: wasmgen::GlobalIdx,
+    global_var_manager: GlobalVarManagerRef<'a>,
+    globalidx_stackptr: wasmgen::GlobalIdx,
+    stack_end: u32,
+    heap_begin: u32,
+) -> wasmgen::FuncIdx {
+    let functype = wasmgen::FuncType::new(Box::new([]), Box::new([wasmgen::ValType::I32]));
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let localidx_chunks_end_ptr = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_it = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_it_end = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_tag = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_run = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_run_size = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_slot = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_free_bytes = locals_builder.add(wasmgen::ValType::I32);
+
+        let heap_begin_ptr: i32 = (heap_begin * WASM_PAGE_SIZE) as i32;
+
+        // the mark stack begins where the chunks end
+        // mark_stack_ptr = chunks_end_ptr;
+        // net wasm stack: [] -> []
+        expr_builder.global_get(globalidx_chunks_end_ptr);
+        expr_builder.local_tee(localidx_chunks_end_ptr);
+        expr_builder.global_set(globalidx_mark_stack_ptr);
+
+        // net wasm stack: [] -> []
+        {
+            // Pseudocode:
+            // for each global g {
+            //     mark_field_impl_$i(g);
+            // }
+            for (ir_vartype, wasm_globalidxs) in global_var_manager {
+                // note: similar to marking struct fields in mark_children_elements()
+                match ir_vartype {
+                    ir::VarType::Any => {
+                        // mark_any(g.tag, g.data);
+                        // net wasm stack: [] -> []
+                        expr_builder.global_get(wasm_globalidxs[0]); // the `tag` of the Any
+                        expr_builder.global_get(wasm_globalidxs[1]); // the `data` of the Any
+                        expr_builder.call(mark_any_funcidx);
+                    }
+                    ir::VarType::Unassigned => {}
+                    ir::VarType::Undefined => {}
+                    ir::VarType::Null => {}
+                    ir::VarType::Number => {}
+                    ir::VarType::Boolean => {}
+                    ir::VarType::Func => {
+                        // mark(g.closure);
+                        // net wasm stack: [] -> []
+                        expr_builder.global_get(wasm_globalidxs[1]); // the `closure` of the Func
+                        expr_builder.call(mark_funcidx);
+                    }
+                    ir::VarType::String
+                    | ir::VarType::Array
+                    | ir::VarType::StructT { typeidx: _ } => {
+                        // mark(g);
+                        // net wasm stack: [] -> []
+                        expr_builder.global_get(wasm_globalidxs[0]);
+                        expr_builder.call(mark_funcidx);
+                    }
+                }
+            }
+        }
+
+        // net wasm stack: [] -> []
+        {
+            // Pseudocode:
+            /*
+            let it = gc_roots_stack_base_ptr;
+            let it_end = gc_roots_stack_ptr;
+            while (it != it_end) {
+                mark_any(it->tag, it->data);
+                it += 12;
+            }
+            */
+
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.global_get(globalidx_gc_roots_stack_ptr);
+            expr_builder.local_set(localidx_it_end);
+            expr_builder.global_get(globalidx_gc_roots_stack_base_ptr);
+            expr_builder.local_tee(localidx_it);
+            expr_builder.local_get(localidx_it_end);
+            expr_builder.i32_ne();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // mark_any(it->tag, it->data);
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0)); // load it->tag
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i64_load(wasmgen::MemArg::new4(4)); // load it->data
+                    expr_builder.call(mark_any_funcidx);
+
+                    // it += 12;
+                    // net wasm stack: [] -> [cond(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(12);
+                    expr_builder.i32_add();
+                    expr_builder.local_tee(localidx_it);
+                    expr_builder.local_get(localidx_it_end);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+        }
+
+        // net wasm stack: [] -> []
+        {
+            // Pseudocode:
+            /*
+            let it = stackptr;
+            while (it != stack_end * WASM_PAGE_SIZE) {
+                it = (*(GC_TABLE_PTR_MARK_CHILDREN_OFFSET + *it))(it+4);
+            }
+            */
+            // Note: objects on the stack are never marked themselves, since mark() ignores everything below the heap.
+
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.global_get(globalidx_stackptr);
+            expr_builder.local_tee(localidx_it);
+            expr_builder.i32_const((stack_end * WASM_PAGE_SIZE) as i32);
+            expr_builder.i32_ne();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // net wasm stack: [] -> [it(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_add();
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    if mark_children_table_offset != 0 {
+                        expr_builder.i32_const(mark_children_table_offset as i32);
+                        expr_builder.i32_add();
+                    }
+                    expr_builder.call_indirect(
+                        wasm_module.insert_type_into(wasmgen::FuncType::new(
+                            Box::new([wasmgen::ValType::I32]),
+                            Box::new([wasmgen::ValType::I32]),
+                        )),
+                        tableidx,
+                    );
+                    expr_builder.local_tee(localidx_it);
+
+                    // net wasm stack: [it(i32)] -> []
+                    expr_builder.i32_const((stack_end * WASM_PAGE_SIZE) as i32);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+        }
+
+        // net wasm stack: [] -> []
+        {
+            // Pseudocode:
+            /*
+            while (mark_stack_ptr != chunks_end_ptr) {
+                mark_stack_ptr -= 4;
+                let obj = *mark_stack_ptr;
+                (*(GC_TABLE_PTR_MARK_CHILDREN_OFFSET + ((*(obj-4)) & I32_MAX)))(obj); // the object is already marked, so we remove the mark bit from the tag
+            }
+            */
+            // Note: mark_children_$i() pushes onto the mark stack too, so we write back mark_stack_ptr before calling it.
+
+            // net wasm stack: [] -> []
+            expr_builder.block(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // net wasm stack: [] -> []
+                    expr_builder.global_get(globalidx_mark_stack_ptr);
+                    expr_builder.local_tee(localidx_it);
+                    expr_builder.local_get(localidx_chunks_end_ptr);
+                    expr_builder.i32_eq();
+                    expr_builder.br_if(1);
+
+                    // net wasm stack: [] -> [obj(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_sub();
+                    expr_builder.local_tee(localidx_it);
+                    expr_builder.global_set(globalidx_mark_stack_ptr);
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    expr_builder.local_tee(localidx_it);
+
+                    // net wasm stack: [obj(i32)] -> []
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_sub();
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                    expr_builder.i32_const(i32::MAX);
+                    expr_builder.i32_and();
+                    if mark_children_table_offset != 0 {
+                        expr_builder.i32_const(mark_children_table_offset as i32);
+                        expr_builder.i32_add();
+                    }
+                    expr_builder.call_indirect(
+                        wasm_module.insert_type_into(wasmgen::FuncType::new(
+                            Box::new([wasmgen::ValType::I32]),
+                            Box::new([wasmgen::ValType::I32]),
+                        )),
+                        tableidx,
+                    );
+                    expr_builder.drop();
+
+                    expr_builder.br(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+        }
+
+        // clear all the free lists
+        // net wasm stack: [] -> []
+        for offset in (0..FREE_LISTS_SIZE).step_by(4) {
+            expr_builder.i32_const(heap_begin_ptr);
+            expr_builder.i32_const(0);
+            expr_builder.i32_store(wasmgen::MemArg::new4(offset));
+        }
+
+        // net wasm stack: [] -> []
+        {
+            // Pseudocode:
+            /*
+            let free_bytes = 0;
+            let it = heap_begin * WASM_PAGE_SIZE + FREE_LISTS_SIZE;
+            while (it != chunks_end_ptr) {
+                let tag = *(it+4);
+                if (tag & I32_MIN) {
+                    // live object, so we just remove the mark
+                    *(it+4) = tag & I32_MAX;
+                    it += *it;
+                } else {
+                    // coalesce this chunk with all the unmarked chunks after it
+                    let run = it;
+                    do {
+                        it += *it;
+                    } while (it != chunks_end_ptr && !((*(it+4)) & I32_MIN));
+                    let run_size = it - run;
+                    *run = run_size;
+                    *(run+4) = FREE_TAG;
+                    free_bytes += run_size;
+                    let slot = heap_begin * WASM_PAGE_SIZE + (run_size <= MAX_SMALL_CHUNK_SIZE ? (run_size >> 1) : 0);
+                    *(run+8) = *slot;
+                    *slot = run;
+                }
+            }
+            return free_bytes;
+            */
+            // Note: the mark stack is just after the chunks, so reading *(chunks_end_ptr+4) is harmless and we don't need to short-circuit.
+
+            // net wasm stack: [] -> []
+            expr_builder.i32_const(0);
+            expr_builder.local_set(localidx_free_bytes);
+            expr_builder.i32_const(heap_begin_ptr + FREE_LISTS_SIZE as i32);
+            expr_builder.local_set(localidx_it);
+
+            // net wasm stack: [] -> []
+            expr_builder.block(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.local_get(localidx_chunks_end_ptr);
+                    expr_builder.i32_eq();
+                    expr_builder.br_if(1);
+
+                    // (tag & I32_MIN), which is equivalent to (tag < 0)
+                    // net wasm stack: [] -> [cond(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(4));
+                    expr_builder.local_tee(localidx_tag);
+                    expr_builder.i32_const(0);
+                    expr_builder.i32_lt_s();
+
+                    // net wasm stack: [cond(i32)] -> []
+                    expr_builder.if_(&[]);
+                    {
+                        // *(it+4) = tag & I32_MAX;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.local_get(localidx_tag);
+                        expr_builder.i32_const(i32::MAX);
+                        expr_builder.i32_and();
+                        expr_builder.i32_store(wasmgen::MemArg::new4(4));
+
+                        // it += *it;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                        expr_builder.i32_add();
+                        expr_builder.local_set(localidx_it);
+                    }
+                    expr_builder.else_();
+                    {
+                        // let run = it;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.local_set(localidx_run);
+
+                        // do { ... } while (...);
+                        // net wasm stack: [] -> []
+                        expr_builder.loop_(&[]);
+                        {
+                            // it += *it;
+                            // net wasm stack: [] -> []
+                            expr_builder.local_get(localidx_it);
+                            expr_builder.local_get(localidx_it);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                            expr_builder.i32_add();
+                            expr_builder.local_set(localidx_it);
+
+                            // (it != chunks_end_ptr && !((*(it+4)) & I32_MIN)), which is equivalent to (it != chunks_end_ptr & (*(it+4)) >= 0)
+                            // net wasm stack: [] -> []
+                            expr_builder.local_get(localidx_it);
+                            expr_builder.local_get(localidx_chunks_end_ptr);
+                            expr_builder.i32_ne();
+                            expr_builder.local_get(localidx_it);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(4));
+                            expr_builder.i32_const(0);
+                            expr_builder.i32_ge_s();
+                            expr_builder.i32_and();
+                            expr_builder.br_if(0);
+                        }
+                        expr_builder.end();
+
+                        // let run_size = it - run;
+                        // *run = run_size;
+                        // *(run+4) = FREE_TAG;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_run);
+                        expr_builder.local_get(localidx_it);
+                        expr_builder.local_get(localidx_run);
+                        expr_builder.i32_sub();
+                        expr_builder.local_tee(localidx_run_size);
+                        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                        expr_builder.local_get(localidx_run);
+                        expr_builder.i32_const(FREE_TAG);
+                        expr_builder.i32_store(wasmgen::MemArg::new4(4));
+
+                        // free_bytes += run_size;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_free_bytes);
+                        expr_builder.local_get(localidx_run_size);
+                        expr_builder.i32_add();
+                        expr_builder.local_set(localidx_free_bytes);
+
+                        // let slot = heap_begin * WASM_PAGE_SIZE + (run_size <= MAX_SMALL_CHUNK_SIZE ? (run_size >> 1) : 0);
+                        // net wasm stack: [] -> []
+                        expr_builder.i32_const(heap_begin_ptr);
+                        expr_builder.local_get(localidx_run_size);
+                        expr_builder.i32_const(1);
+                        expr_builder.i32_shr_u();
+                        expr_builder.i32_const(0);
+                        expr_builder.local_get(localidx_run_size);
+                        expr_builder.i32_const(MAX_SMALL_CHUNK_SIZE as i32);
+                        expr_builder.i32_le_u();
+                        expr_builder.select();
+                        expr_builder.i32_add();
+                        expr_builder.local_set(localidx_slot);
+
+                        // *(run+8) = *slot;
+                        // *slot = run;
+                        // net wasm stack: [] -> []
+                        expr_builder.local_get(localidx_run);
+                        expr_builder.local_get(localidx_slot);
+                        expr_builder.i32_load(wasmgen::MemArg::new4(0));
+                        expr_builder.i32_store(wasmgen::MemArg::new4(8));
+                        expr_builder.local_get(localidx_slot);
+                        expr_builder.local_get(localidx_run);
+                        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    }
+                    expr_builder.end();
+
+                    expr_builder.br(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+
+            // return free_bytes;
+            // net wasm stack: [] -> [free_bytes(i32)]
+            expr_builder.local_get(localidx_free_bytes);
+        }
+
+        expr_builder.end(); // return it
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
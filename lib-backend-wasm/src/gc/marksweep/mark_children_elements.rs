@@ -0,0 +1,278 @@
+// returns the base table element index from which indirect access should be calculated (i.e. the "table offset")
+// e.g. if we want to access mark_children_$i, we should call_indirect with index = (table_offset+i)
+// mark_children_$i(ptr) marks all the children of the object at `ptr`, and returns the ptr past-the-end of the object.
+pub fn make_mark_children_elements(
+    wasm_module: &mut wasmgen::WasmModule,
+    struct_types: &[Box<[ir::VarType]>],
+    struct_field_byte_offsets: &[Box<[u32]>],
+    struct_sizes: &[u32],
+    tableidx: wasmgen::TableIdx,
+    mark_funcidx: wasmgen::FuncIdx,
+    mark_any_funcidx: wasmgen::FuncIdx,
+) -> u32 {
+    // make the string version of mark_children
+    // it doesn't call any other function; just returns the ptr past-the-end of the string
+    fn make_string_function(wasm_module: &mut wasmgen::WasmModule) -> wasmgen::FuncIdx {
+        let functype = wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        );
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (_locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+
+            // Algorithm: return ((ptr + *ptr + 7) & (-4)); // see gc::cheney::copy_children_elements
+
+            // net wasm stack: [] -> [ret(i32)]
+            expr_builder.local_get(localidx_param);
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.i32_add();
+            expr_builder.i32_const(7);
+            expr_builder.i32_add();
+            expr_builder.i32_const(-4);
+            expr_builder.i32_and();
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    }
+
+    // make the struct version of mark_children
+    fn make_struct_function(
+        wasm_module: &mut wasmgen::WasmModule,
+        ir_vartypes: &[ir::VarType],
+        byte_offsets: &[u32],
+        struct_size: u32,
+        mark_funcidx: wasmgen::FuncIdx,
+        mark_any_funcidx: wasmgen::FuncIdx,
+    ) -> wasmgen::FuncIdx {
+        let functype = wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        );
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (_locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+
+            ir_vartypes
+                .iter()
+                .cloned()
+                .zip(byte_offsets.iter().cloned())
+                .for_each(|(ir_vartype, byte_offset)| {
+                    // note: similar to marking global roots in do_mark_sweep()
+                    match ir_vartype {
+                        ir::VarType::Any => {
+                            // mark_any(f.tag, f.data);
+                            // net wasm stack: [] -> []
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset)); // the `tag` of the Any is at offset 0
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i64_load(wasmgen::MemArg::new4(byte_offset + 4)); // the `data` of the Any is at offset 4
+                            expr_builder.call(mark_any_funcidx);
+                        }
+                        ir::VarType::Unassigned => {}
+                        ir::VarType::Undefined => {}
+                        ir::VarType::Null => {}
+                        ir::VarType::Number => {}
+                        ir::VarType::Boolean => {}
+                        ir::VarType::Func => {
+                            // mark(f.closure);
+                            // net wasm stack: [] -> []
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset + 4)); // the `closure` of the Func is at offset 4
+                            expr_builder.call(mark_funcidx);
+                        }
+                        ir::VarType::String
+                        | ir::VarType::Array
+                        | ir::VarType::StructT { typeidx: _ } => {
+                            // mark(f);
+                            // net wasm stack: [] -> []
+                            expr_builder.local_get(localidx_param);
+                            expr_builder.i32_load(wasmgen::MemArg::new4(byte_offset));
+                            expr_builder.call(mark_funcidx);
+                        }
+                    }
+                });
+
+            // net wasm stack: [] -> [i32(ptr to past-the-end)]
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(struct_size as i32);
+            expr_builder.i32_add();
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    }
+
+    // make the array version of mark_children
+    // the array header is [len(i32), buf(i32)], and we only need to mark the buffer
+    fn make_array_function(
+        wasm_module: &mut wasmgen::WasmModule,
+        mark_funcidx: wasmgen::FuncIdx,
+    ) -> wasmgen::FuncIdx {
+        let functype = wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        );
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (_locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+
+            // mark(ptr->buf);
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(4));
+            expr_builder.call(mark_funcidx);
+
+            // net wasm stack: [] -> [i32(ptr to past-the-end)]
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(8);
+            expr_builder.i32_add();
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    }
+
+    // make the array buffer version of mark_children
+    // the array buffer is [capacity(i32), element(Any) * capacity]
+    fn make_array_buffer_function(
+        wasm_module: &mut wasmgen::WasmModule,
+        mark_any_funcidx: wasmgen::FuncIdx,
+    ) -> wasmgen::FuncIdx {
+        let functype = wasmgen::FuncType::new(
+            Box::new([wasmgen::ValType::I32]),
+            Box::new([wasmgen::ValType::I32]),
+        );
+        let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (locals_builder, expr_builder) = code_builder.split();
+            let localidx_param = wasmgen::LocalIdx { idx: 0 };
+            let localidx_it = locals_builder.add(wasmgen::ValType::I32);
+            let localidx_it_end = locals_builder.add(wasmgen::ValType::I32);
+
+            let any_size = crate::var_conv::size_in_memory(ir::VarType::Any) as i32;
+
+            // Algorithm:
+            /*
+            let it = ptr + 4;
+            let it_end = it + (*ptr) * sizeof(Any);
+            while (it != it_end) {
+                mark_any(it->tag, it->data);
+                it += sizeof(Any);
+            }
+            return it_end;
+            */
+
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_const(4);
+            expr_builder.i32_add();
+            expr_builder.local_tee(localidx_it);
+            expr_builder.local_get(localidx_param);
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.i32_const(any_size);
+            expr_builder.i32_mul();
+            expr_builder.i32_add();
+            expr_builder.local_set(localidx_it_end);
+
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_it);
+            expr_builder.local_get(localidx_it_end);
+            expr_builder.i32_ne();
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    // mark_any(it->tag, it->data);
+                    // net wasm stack: [] -> []
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_load(wasmgen::MemArg::new4(0)); // the `tag` of the Any is at offset 0
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i64_load(wasmgen::MemArg::new4(4)); // the `data` of the Any is at offset 4
+                    expr_builder.call(mark_any_funcidx);
+
+                    // it += sizeof(Any);
+                    // net wasm stack: [] -> [cond(i32)]
+                    expr_builder.local_get(localidx_it);
+                    expr_builder.i32_const(any_size);
+                    expr_builder.i32_add();
+                    expr_builder.local_tee(localidx_it);
+                    expr_builder.local_get(localidx_it_end);
+                    expr_builder.i32_ne();
+                    expr_builder.br_if(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+
+            // net wasm stack: [] -> [i32(ptr to past-the-end)]
+            expr_builder.local_get(localidx_it_end);
+
+            expr_builder.end(); // return it
+        }
+        wasm_module.commit_func(func_idx, code_builder);
+        func_idx
+    }
+
+    let mark_children_table_offset: u32 = wasm_module.reserve_table_elements(
+        tableidx,
+        (ir::NUM_PRIMITIVE_TAG_TYPES + struct_types.len() + 1) as u32, // the last element is for the array buffer
+    );
+
+    // Note: some reserved table elements are left uncommitted.  They will automatically trap if called at runtime.  (If that happens, then the compiler has a bug.)
+
+    let funcidx_string: wasmgen::FuncIdx = make_string_function(wasm_module);
+    wasm_module.commit_table_elements(
+        tableidx,
+        mark_children_table_offset + ir::VarType::String.tag() as u32,
+        Box::new([funcidx_string]),
+    );
+    let funcidx_array: wasmgen::FuncIdx = make_array_function(wasm_module, mark_funcidx);
+    wasm_module.commit_table_elements(
+        tableidx,
+        mark_children_table_offset + ir::VarType::Array.tag() as u32,
+        Box::new([funcidx_array]),
+    );
+    let array_buffer_tag: usize = ir::NUM_PRIMITIVE_TAG_TYPES + struct_types.len();
+    let funcidx_array_buffer: wasmgen::FuncIdx =
+        make_array_buffer_function(wasm_module, mark_any_funcidx);
+    wasm_module.commit_table_elements(
+        tableidx,
+        mark_children_table_offset + array_buffer_tag as u32,
+        Box::new([funcidx_array_buffer]),
+    );
+    let funcidxs_structs: Box<[wasmgen::FuncIdx]> = struct_types
+        .iter()
+        .zip(struct_field_byte_offsets.iter())
+        .zip(struct_sizes.iter().cloned())
+        .map(|((ir_vartypes, byte_offsets), struct_size)| {
+            make_struct_function(
+                wasm_module,
+                ir_vartypes,
+                byte_offsets,
+                struct_size,
+                mark_funcidx,
+                mark_any_funcidx,
+            )
+        })
+        .collect();
+    wasm_module.commit_table_elements(
+        tableidx,
+        mark_children_table_offset + ir::NUM_PRIMITIVE_TAG_TYPES as u32,
+        funcidxs_structs,
+    );
+
+    mark_children_table_offset
+}
@@ -0,0 +1,163 @@
+use super::WASM_PAGE_SIZE;
+
+// Encodes mark(), which marks an object and pushes it on the mark stack if it hasn't been marked yet.
+// The pointer may also be -1 (not yet assigned), or point below the heap (global data or the stack), in which case it is ignored.
+// net wasm stack: [i32(ptr)] -> []
+pub fn make_mark(
+    wasm_module: &mut wasmgen::WasmModule,
+    globalidx_mark_stack_ptr: wasmgen::GlobalIdx,
+    heap_begin: u32,
+) -> wasmgen::FuncIdx {
+    /*
+    fn mark(ptr: i32) {
+        if (ptr != -1 && ptr > heap_begin * WASM_PAGE_SIZE) {
+            let tag = *(ptr-4);
+            if (!(tag & I32_MIN)) { // not marked yet (we multiplex the MSB of the tag field, since there shouldn't be more than 2^31 types)
+                *(ptr-4) = tag | I32_MIN;
+                *mark_stack_ptr = ptr;
+                mark_stack_ptr += 4;
+            }
+        }
+    }
+    */
+
+    let functype = wasmgen::FuncType::new(Box::new([wasmgen::ValType::I32]), Box::new([]));
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (locals_builder, expr_builder) = code_builder.split();
+        let localidx_ptr = wasmgen::LocalIdx { idx: 0 };
+        let localidx_tag = locals_builder.add(wasmgen::ValType::I32);
+        let localidx_mark_stack_ptr = locals_builder.add(wasmgen::ValType::I32);
+
+        // net wasm stack: [] -> [cond(i32)]
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const(-1);
+        expr_builder.i32_ne();
+        expr_builder.local_get(localidx_ptr);
+        expr_builder.i32_const((heap_begin * WASM_PAGE_SIZE) as i32);
+        expr_builder.i32_gt_u();
+        expr_builder.i32_and();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // (!(tag & I32_MIN)), which is equivalent to (tag >= 0)
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.local_get(localidx_ptr);
+            expr_builder.i32_const(4);
+            expr_builder.i32_sub();
+            expr_builder.i32_load(wasmgen::MemArg::new4(0));
+            expr_builder.local_tee(localidx_tag);
+            expr_builder.i32_const(0);
+            expr_builder.i32_ge_s();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                // *(ptr-4) = tag | I32_MIN;
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_ptr);
+                expr_builder.i32_const(4);
+                expr_builder.i32_sub();
+                expr_builder.local_get(localidx_tag);
+                expr_builder.i32_const(i32::MIN);
+                expr_builder.i32_or();
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+
+                // *mark_stack_ptr = ptr;
+                // mark_stack_ptr += 4;
+                // net wasm stack: [] -> []
+                expr_builder.global_get(globalidx_mark_stack_ptr);
+                expr_builder.local_tee(localidx_mark_stack_ptr);
+                expr_builder.local_get(localidx_ptr);
+                expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                expr_builder.local_get(localidx_mark_stack_ptr);
+                expr_builder.i32_const(4);
+                expr_builder.i32_add();
+                expr_builder.global_set(globalidx_mark_stack_ptr);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
+
+// Encodes mark_any(), which marks the object referred to by an Any (if any).
+// net wasm stack: [i32(tag), i64(data)] -> []
+pub fn make_mark_any(
+    wasm_module: &mut wasmgen::WasmModule,
+    mark_funcidx: wasmgen::FuncIdx,
+) -> wasmgen::FuncIdx {
+    /*
+    fn mark_any(tag: i32, data: i64) {
+        if (tag == Func) {
+            mark(i32_wrap_i64(data >> 32)); // the closure
+        } else if (tag == String || tag == Array || tag >= NUM_PRIMITIVE_TAG_TYPES) {
+            mark(i32_wrap_i64(data));
+        }
+    }
+    */
+
+    let functype = wasmgen::FuncType::new(
+        Box::new([wasmgen::ValType::I32, wasmgen::ValType::I64]),
+        Box::new([]),
+    );
+    let (_type_idx, func_idx) = wasm_module.register_func(&functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(functype);
+    {
+        let (_locals_builder, expr_builder) = code_builder.split();
+        let localidx_tag = wasmgen::LocalIdx { idx: 0 };
+        let localidx_data = wasmgen::LocalIdx { idx: 1 };
+
+        // net wasm stack: [] -> [cond(i32)]
+        expr_builder.local_get(localidx_tag);
+        expr_builder.i32_const(ir::VarType::Func.tag());
+        expr_builder.i32_eq();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // net wasm stack: [] -> []
+            expr_builder.local_get(localidx_data);
+            expr_builder.i64_const(32);
+            expr_builder.i64_shr_u();
+            expr_builder.i32_wrap_i64();
+            expr_builder.call(mark_funcidx);
+        }
+        expr_builder.else_();
+        {
+            // net wasm stack: [] -> [cond(i32)]
+            expr_builder.local_get(localidx_tag);
+            expr_builder.i32_const(ir::VarType::String.tag());
+            expr_builder.i32_eq();
+            expr_builder.local_get(localidx_tag);
+            expr_builder.i32_const(ir::VarType::Array.tag());
+            expr_builder.i32_eq();
+            expr_builder.i32_or();
+            expr_builder.local_get(localidx_tag);
+            expr_builder.i32_const(ir::NUM_PRIMITIVE_TAG_TYPES as i32);
+            expr_builder.i32_ge_u();
+            expr_builder.i32_or();
+
+            // net wasm stack: [cond(i32)] -> []
+            expr_builder.if_(&[]);
+            {
+                // net wasm stack: [] -> []
+                expr_builder.local_get(localidx_data);
+                expr_builder.i32_wrap_i64();
+                expr_builder.call(mark_funcidx);
+            }
+            expr_builder.end();
+        }
+        expr_builder.end();
+
+        expr_builder.end();
+    }
+    wasm_module.commit_func(func_idx, code_builder);
+    func_idx
+}
@@ -0,0 +1,786 @@
+use super::GcStats;
+use super::HeapManager;
+use super::WASM_PAGE_BITS;
+use super::WASM_PAGE_SIZE;
+use crate::global_var::GlobalVarManagerRef;
+use crate::var_conv::*;
+use wasmgen::Scratch;
+
+mod alloc_funcs;
+mod do_mark_sweep;
+mod mark_children_elements;
+mod mark_funcs;
+
+/**
+ * MarkSweep is a GC implementation that never moves objects.
+ * It marks everything reachable from the roots, and then sweeps the unmarked chunks into free lists.
+ * Since objects stay where they are, pointers held by the host (e.g. strings returned from "allocate_string") remain valid across collections.
+ *
+ * Layout of heap:
+ * [.....(free lists).....|.....(chunks).....|.....(mark stack).....|.....(gc roots).....]
+ * `free lists`: the head of the list of large chunks, followed by the head of the list for each small chunk size (see alloc_fast()).  A head is 0 if the list is empty.
+ * `chunks`: every object lives in a chunk, which is [size(i32), tag(i32), payload...], where `size` is the size of the whole chunk (a multiple of 8, at least MIN_CHUNK_SIZE).
+ *   The chunks tile this region, so the sweep can walk all of them.
+ *   The tag is at *(ptr-4) like in the other GCs, and its MSB is the mark bit (there shouldn't be more than 2^31 types).
+ *   A free chunk has the tag FREE_TAG, and the next free chunk of the same list in the first word of its payload.
+ * `mark stack`: objects that have been marked but whose children have not been marked yet.
+ *   Its size is always a quarter of the size of (free lists + chunks), which is enough since every object is pushed at most once and every chunk is at least 16 bytes.
+ * `gc_roots`: the same as gc::cheney::Cheney.
+ *
+ * When no free chunk is large enough, alloc_slow() runs a collection (see do_mark_sweep()).
+ * If the object still doesn't fit, or if less than half of the chunks are free after the collection, alloc_slow() grows the memory,
+ * moves the gc_roots rightward, and turns the new space into a free chunk.
+ *
+ * The buffer that holds the elements of an array gets the same tag as in gc::cheney::Cheney.
+ * Objects allocated on the stack (see encode_stack_allocation()) are never marked, but the GC walks the objects in [stackptr, stack_end) to mark their children.
+ */
+pub struct MarkSweep<'a, 'b, 'c> {
+    struct_types: &'a [Box<[ir::VarType]>], // types of the fields of each struct type
+    struct_field_byte_offsets: &'b [Box<[u32]>], // byte offsets of the fields of each struct type (each Box has same lengths as that of `struct_types`)
+    struct_sizes: &'c [u32], // map from typeidx to struct_sizes.  Note: typeidx is not VarType::tag()!  It is the typeidx used in VarType::StructT
+    gc_roots_stack_ptr: wasmgen::GlobalIdx, // Global that stores pointer to past-the-end of gc_roots stack
+    alloc_fast_funcidx: wasmgen::FuncIdx,   // funcidx of alloc_fast() function
+    alloc_slow_funcidx: wasmgen::FuncIdx,   // funcidx of alloc_slow() function
+    stats: GcStats,                         // counters for the gc stats
+}
+
+const MIN_CHUNK_SIZE: u32 = 16; // room for the size, the tag, and the next free chunk
+const MAX_SMALL_CHUNK_SIZE: u32 = 256; // chunks up to this size have a free list for each size
+const FREE_LISTS_SIZE: u32 = (MAX_SMALL_CHUNK_SIZE >> 1) + 8; // the head for chunk size `s` is at offset (s >> 1), and the head for large chunks is at offset 0
+const FREE_TAG: i32 = i32::MAX; // tag of free chunks (it doesn't have the mark bit, so free chunks are swept like dead objects)
+
+// Note: Currently MEM_INITIAL_CHUNKS_SIZE * 5 / 4 should be at least as large as the gc_roots size (GC_ROOTS_SIZE, plus up to four leftover pages), so that alloc_slow() can move the gc_roots stack without overlapping.
+// MEM_INITIAL_CHUNKS_SIZE should also be a multiple of 4, so that the mark stack is a whole number of pages.
+// A larger initial heap (see `heap_initial_end` in new()) only increases the chunks (and the mark stack along with it).
+const GC_ROOTS_SIZE: u32 = 1 << 4; // 1 MiB of gc_roots stack space
+const MEM_INITIAL_CHUNKS_SIZE: u32 = 1 << 4; // the free lists and the chunks (for the smallest initial heap)
+const MEM_INITIAL_HEAP_SIZE: u32 =
+    MEM_INITIAL_CHUNKS_SIZE + (MEM_INITIAL_CHUNKS_SIZE >> 2) + GC_ROOTS_SIZE; // 1 MiB of chunks, 256 KiB of mark stack, and 1 MiB of gc_roots stack space
+
+impl<'a, 'b, 'c> MarkSweep<'a, 'b, 'c> {
+    // Constructs a new mark-sweep GC, and initializes it appropriately.
+    pub fn new<'d>(
+        struct_types: &'a [Box<[ir::VarType]>],
+        struct_field_byte_offsets: &'b [Box<[u32]>],
+        struct_sizes: &'c [u32],
+        memidx: wasmgen::MemIdx,
+        stackptr: wasmgen::GlobalIdx, // stores the stackptr, objects in [stackptr, stack_end) are gc roots too
+        stack_end: u32,               // in page units
+        heap_begin: u32,
+        heap_initial_end: u32,
+        global_var_manager: GlobalVarManagerRef<'d>, // stores global vars that are gc roots too
+        error_func: wasmgen::FuncIdx,
+        wasm_module: &mut wasmgen::WasmModule,
+    ) -> Self {
+        assert!(heap_begin + MEM_INITIAL_HEAP_SIZE <= heap_initial_end);
+
+        // the largest multiple of 4 pages whose chunks and mark stack fit before the gc_roots stack, and any leftover pages go to the gc_roots stack
+        let initial_chunks_size: u32 =
+            ((heap_initial_end - heap_begin - GC_ROOTS_SIZE) * 4 / 5) & !3;
+        let chunks_end: u32 = heap_begin + initial_chunks_size;
+        let gc_roots_begin: u32 = chunks_end + (initial_chunks_size >> 2);
+
+        let chunks_end_ptr: wasmgen::GlobalIdx =
+            wasm_module.add_i32_global(wasmgen::Mut::Var, (chunks_end * WASM_PAGE_SIZE) as i32);
+        let mark_stack_ptr: wasmgen::GlobalIdx =
+            wasm_module.add_i32_global(wasmgen::Mut::Var, (chunks_end * WASM_PAGE_SIZE) as i32);
+        let gc_roots_stack_base_ptr: wasmgen::GlobalIdx =
+            wasm_module.add_i32_global(wasmgen::Mut::Var, (gc_roots_begin * WASM_PAGE_SIZE) as i32);
+        let gc_roots_stack_ptr: wasmgen::GlobalIdx =
+            wasm_module.add_i32_global(wasmgen::Mut::Var, (gc_roots_begin * WASM_PAGE_SIZE) as i32);
+
+        // Initially, all the chunks are in one free chunk, which is the only element of the list of large chunks.
+        {
+            let heap_begin_ptr: u32 = heap_begin * WASM_PAGE_SIZE;
+            let first_chunk_ptr: u32 = heap_begin_ptr + FREE_LISTS_SIZE;
+            let first_chunk_size: u32 = chunks_end * WASM_PAGE_SIZE - first_chunk_ptr;
+            let mut data: Vec<u8> = vec![0; (FREE_LISTS_SIZE + 12) as usize];
+            data[0..4].copy_from_slice(&first_chunk_ptr.to_le_bytes());
+            data[(FREE_LISTS_SIZE as usize)..(FREE_LISTS_SIZE as usize + 4)]
+                .copy_from_slice(&first_chunk_size.to_le_bytes());
+            data[(FREE_LISTS_SIZE as usize + 4)..(FREE_LISTS_SIZE as usize + 8)]
+                .copy_from_slice(&FREE_TAG.to_le_bytes());
+            wasm_module.add_data(memidx, heap_begin_ptr, &data);
+        }
+
+        let mark_funcidx: wasmgen::FuncIdx =
+            mark_funcs::make_mark(wasm_module, mark_stack_ptr, heap_begin);
+        let mark_any_funcidx: wasmgen::FuncIdx =
+            mark_funcs::make_mark_any(wasm_module, mark_funcidx);
+
+        let tableidx: wasmgen::TableIdx = wasm_module.get_or_add_table();
+
+        let mark_children_table_offset: u32 = mark_children_elements::make_mark_children_elements(
+            wasm_module,
+            struct_types,
+            struct_field_byte_offsets,
+            struct_sizes,
+            tableidx,
+            mark_funcidx,
+            mark_any_funcidx,
+        );
+
+        let do_mark_sweep_funcidx: wasmgen::FuncIdx = do_mark_sweep::make_do_mark_sweep(
+            wasm_module,
+            tableidx,
+            mark_children_table_offset,
+            mark_funcidx,
+            mark_any_funcidx,
+            chunks_end_ptr,
+            mark_stack_ptr,
+            gc_roots_stack_base_ptr,
+            gc_roots_stack_ptr,
+            global_var_manager,
+            stackptr,
+            stack_end,
+            heap_begin,
+        );
+
+        let stats: GcStats = GcStats::new(memidx, heap_begin, heap_initial_end, wasm_module);
+
+        let alloc_fast_funcidx: wasmgen::FuncIdx =
+            alloc_funcs::make_alloc_fast(wasm_module, &stats, heap_begin);
+
+        let alloc_slow_funcidx: wasmgen::FuncIdx = alloc_funcs::make_alloc_slow(
+            wasm_module,
+            memidx,
+            chunks_end_ptr,
+            gc_roots_stack_base_ptr,
+            gc_roots_stack_ptr,
+            alloc_fast_funcidx,
+            do_mark_sweep_funcidx,
+            &stats,
+            heap_begin,
+            error_func,
+        );
+
+        MarkSweep {
+            struct_types,
+            struct_field_byte_offsets,
+            struct_sizes,
+            gc_roots_stack_ptr,
+            alloc_fast_funcidx,
+            alloc_slow_funcidx,
+            stats,
+        }
+    }
+
+    fn filter_roots(
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+    ) -> Box<[(ir::VarType, usize)]> {
+        local_types
+            .iter()
+            .copied()
+            .zip(local_map.iter().copied())
+            .filter(|(ir_vartype, _)| {
+                !matches!(
+                    ir_vartype,
+                    ir::VarType::Unassigned
+                        | ir::VarType::Undefined
+                        | ir::VarType::Number
+                        | ir::VarType::Boolean
+                        | ir::VarType::Null
+                )
+            })
+            .collect()
+    }
+
+    // Helper function used to encode heap allocation.
+    // `f` should be a function that has net wasm stack [] -> [i32(size)], it pushes the bytes required (including tag) on the stack.
+    // net wasm stack: [] -> [i32(ptr)]
+    fn encode_allocation<F: Fn(&mut wasmgen::ExprBuilder)>(
+        &self,
+        encode_size: F,
+        tag: i32,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        // Algorithm:
+        /*
+        ret = alloc_fast(size, tag);
+        if (ret == 0) {
+            for local in local_roots {
+                if(local is Any, String, Func, or StructT) {
+                    *gc_roots_stack_ptr = to_any(local);
+                    gc_roots_stack_ptr += 12;
+                }
+            }
+            // note: `alloc_slow` doesn't move objects, so the locals don't need to be reloaded.
+            ret = alloc_slow(size, tag);
+            gc_roots_stack_ptr -= 12 * (number of locals pushed);
+        }
+        */
+
+        let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+
+        // net wasm stack: [] -> [cond(i32)]
+        encode_size(expr_builder);
+        expr_builder.i32_const(tag);
+        expr_builder.call(self.alloc_fast_funcidx);
+        expr_builder.local_tee(localidx_ret);
+        expr_builder.i32_eqz();
+
+        // net wasm stack: [cond(i32)] -> []
+        expr_builder.if_(&[]);
+        {
+            // save the values of all the filtered roots on the gc_roots stack
+            // net wasm stack: [] -> []
+            self.encode_local_roots_prologue(
+                local_types,
+                local_map,
+                wasm_local_map,
+                scratch,
+                expr_builder,
+            );
+
+            // net wasm stack: [] -> []
+            encode_size(expr_builder);
+            expr_builder.i32_const(tag);
+            expr_builder.call(self.alloc_slow_funcidx);
+            expr_builder.local_set(localidx_ret);
+
+            // pop all the filtered roots
+            // net wasm stack: [] -> []
+            self.encode_local_roots_epilogue(
+                local_types,
+                local_map,
+                wasm_local_map,
+                scratch,
+                expr_builder,
+            );
+        }
+        expr_builder.end();
+
+        // net wasm stack: [] -> [res(i32)]
+        expr_builder.local_get(localidx_ret);
+
+        scratch.pop_i32();
+    }
+
+    // Helper function to write Undefined to all Any fields in the struct
+    // and write nullptr (i.e. -1) to all String, Func::closure, StructT
+    // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+    fn encode_struct_init(
+        &self,
+        typeidx: usize,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
+        expr_builder.local_tee(localidx_ptr);
+        self.struct_types[typeidx]
+            .iter()
+            .zip(self.struct_field_byte_offsets[typeidx].iter())
+            .for_each(|(ir_vartype, byte_offset)| match ir_vartype {
+                ir::VarType::Any => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(ir::VarType::Unassigned.tag());
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
+                }
+                ir::VarType::String | ir::VarType::Array | ir::VarType::StructT { typeidx: _ } => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset));
+                }
+                ir::VarType::Func => {
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(*byte_offset + 4));
+                    // Note: "+4" above to access the closure
+                }
+                _ => {}
+            });
+        scratch.pop_i32();
+    }
+}
+
+fn wasm_local_slice(
+    ir_vartype: ir::VarType,
+    wasm_local_map_idx: usize,
+    wasm_local_map: &[wasmgen::LocalIdx],
+) -> &[wasmgen::LocalIdx] {
+    &wasm_local_map[wasm_local_map_idx..(wasm_local_map_idx + encode_vartype(ir_vartype).len())]
+}
+
+impl<'a, 'b, 'c> HeapManager for MarkSweep<'a, 'b, 'c> {
+    // Returns the minimum initial number of pages required by this heap HeapManager (which is also the default).
+    fn initial_heap_size() -> u32 {
+        MEM_INITIAL_HEAP_SIZE
+    }
+
+    // Encodes instructions to get a chunk of memory suitable for the given struct type specified by ir_vartype.
+    // It is guaranteed to be 4-byte aligned.
+    // net wasm stack: [] -> [i32(ptr)]
+    fn encode_fixed_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => {
+                let size = self.struct_sizes[typeidx];
+                assert!((size & 3) == 0, "struct size must be multiple of 4");
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.i32_const((size + 4) as i32);
+                    },
+                    ir_vartype.tag(),
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
+            }
+            ir::VarType::Array => {
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.i32_const(8 + 4);
+                    },
+                    ir_vartype.tag(),
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // Write an empty array header (len = 0, buf = nullptr)
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ptr);
+                    expr_builder.i32_const(0);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_ptr);
+                    expr_builder.i32_const(-1);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(4));
+                    expr_builder.local_get(localidx_ptr);
+                    scratch.pop_i32();
+                }
+            }
+            _ => panic!("incorrect VarType, expected StructT or Array"),
+        }
+    }
+
+    // Returns the number of bytes needed to initialize the given struct type on the stack (including the tag).
+    fn stack_allocation_size(&self, ir_vartype: ir::VarType) -> u32 {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => self.struct_sizes[typeidx] + 4,
+            _ => panic!("incorrect VarType, expected StructT"),
+        }
+    }
+
+    // Encodes instructions to initialize the given struct type in a chunk of memory on the stack.
+    // The tag is written like for heap objects, so that the GC can walk the stack to find the children of these objects.
+    // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+    fn encode_stack_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::StructT { typeidx } => {
+                // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+                {
+                    let localidx_chunk: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_chunk);
+                    expr_builder.i32_const(ir_vartype.tag());
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    expr_builder.local_get(localidx_chunk);
+                    expr_builder.i32_const(4);
+                    expr_builder.i32_add();
+                    scratch.pop_i32();
+                }
+
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                self.encode_struct_init(typeidx, scratch, expr_builder);
+            }
+            _ => panic!("incorrect VarType, expected StructT"),
+        }
+    }
+
+    // Encodes instructions to get a chunk of memory for an string/array of unknown size.  See `encode_fixed_allocation` for more detauls.
+    // The size need not be a multiple of 4.
+    // net wasm stack: [i32(num_bytes)] -> [i32(ptr)]
+    fn encode_dynamic_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match ir_vartype {
+            ir::VarType::String => {
+                let localidx_str_len: wasmgen::LocalIdx = scratch.push_i32();
+                let localidx_mem_size: wasmgen::LocalIdx = scratch.push_i32();
+
+                {
+                    expr_builder.local_tee(localidx_str_len);
+                }
+
+                // Algorithm: mem_size = ((num_bytes + 11) & (~3))   // equivalent to (4 + round_up_to_multiple_of_4(num_bytes))
+                // net wasm stack: [i32(num_bytes)] -> []
+                expr_builder.i32_const(11);
+                expr_builder.i32_add();
+                expr_builder.i32_const(-4); // equivalent to (~3) in two's complement
+                expr_builder.i32_and();
+                expr_builder.local_set(localidx_mem_size);
+
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.local_get(localidx_mem_size);
+                    },
+                    ir_vartype.tag(),
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // write the string length
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ret);
+                    expr_builder.local_get(localidx_ret);
+                    expr_builder.local_get(localidx_str_len);
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    scratch.pop_i32();
+                }
+
+                scratch.pop_i32();
+                scratch.pop_i32();
+            }
+            ir::VarType::Array => {
+                // this is the buffer of elements, not the array header
+                let localidx_num_bytes: wasmgen::LocalIdx = scratch.push_i32();
+
+                // net wasm stack: [i32(num_bytes)] -> []
+                expr_builder.local_set(localidx_num_bytes);
+
+                // net wasm stack: [] -> [i32(ptr)]
+                self.encode_allocation(
+                    |expr_builder| {
+                        // net wasm stack: [] -> [i32(size)]
+                        expr_builder.local_get(localidx_num_bytes);
+                        expr_builder.i32_const(8);
+                        expr_builder.i32_add();
+                    },
+                    (ir::NUM_PRIMITIVE_TAG_TYPES + self.struct_sizes.len()) as i32, // the array buffer tag
+                    local_types,
+                    local_map,
+                    wasm_local_map,
+                    scratch,
+                    expr_builder,
+                );
+
+                // write the capacity
+                // net wasm stack: [i32(ptr)] -> [i32(ptr)]
+                {
+                    let localidx_ret: wasmgen::LocalIdx = scratch.push_i32();
+                    expr_builder.local_tee(localidx_ret);
+                    expr_builder.local_get(localidx_ret);
+                    expr_builder.local_get(localidx_num_bytes);
+                    expr_builder.i32_const(size_in_memory(ir::VarType::Any) as i32);
+                    expr_builder.i32_div_u();
+                    expr_builder.i32_store(wasmgen::MemArg::new4(0));
+                    scratch.pop_i32();
+                }
+
+                scratch.pop_i32();
+            }
+            _ => panic!("incorrect VarType, expected String or Array"),
+        }
+    }
+
+    type RootsStackHandle = ();
+
+    // Encodes instructions to push local variables to gc_roots stack.
+    // This should be called before a function which might allocate memory is called.
+    // It should be paired with a call to `encode_local_roots_elilogue()`.
+    // net wasm stack: [] -> []
+    fn encode_local_roots_prologue(
+        &self,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) -> Self::RootsStackHandle {
+        let filtered_roots: Box<[(ir::VarType, usize)]> =
+            Self::filter_roots(local_types, local_map);
+
+        // if there are no roots to add, then we don't need to load the gc_roots_stack_ptr.
+        // net wasm stack: [] -> []
+        if !filtered_roots.is_empty() {
+            let localidx_gc_roots_stack_ptr = scratch.push_i32();
+
+            // net wasm stack: [] -> [gc_roots_stack_ptr(i32)]
+            expr_builder.global_get(self.gc_roots_stack_ptr);
+
+            for (ir_vartype, index) in filtered_roots.iter().copied() {
+                // net wasm stack: [gc_roots_stack_ptr(i32)] -> []
+                expr_builder.local_tee(localidx_gc_roots_stack_ptr);
+                encode_load_local(
+                    wasm_local_slice(ir_vartype, index, wasm_local_map),
+                    ir_vartype,
+                    ir_vartype,
+                    expr_builder,
+                );
+                encode_store_memory(0, ir::VarType::Any, ir_vartype, scratch, expr_builder);
+
+                // net wasm stack: [] -> [gc_roots_stack_ptr(i32)]
+                expr_builder.local_get(localidx_gc_roots_stack_ptr);
+                expr_builder.i32_const(12);
+                expr_builder.i32_add();
+            }
+
+            // net wasm stack: [gc_roots_stack_ptr(i32)] -> []
+            expr_builder.global_set(self.gc_roots_stack_ptr);
+
+            scratch.pop_i32();
+        }
+    }
+
+    // Encodes instructions to pop local variables from gc_roots stack.
+    // This should be called after a function which might allocate memory is called.
+    // It should be paired with a call to `encode_local_roots_prologue()`.
+    // Objects are never moved, so the locals still have the right values and we only need to pop them.
+    // net wasm stack: [] -> []
+    fn encode_local_roots_epilogue(
+        &self,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        _wasm_local_map: &[wasmgen::LocalIdx],
+        _scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        let filtered_roots: Box<[(ir::VarType, usize)]> =
+            Self::filter_roots(local_types, local_map);
+
+        // net wasm stack: [] -> []
+        if !filtered_roots.is_empty() {
+            expr_builder.global_get(self.gc_roots_stack_ptr);
+            expr_builder.i32_const((filtered_roots.len() * 12) as i32);
+            expr_builder.i32_sub();
+            expr_builder.global_set(self.gc_roots_stack_ptr);
+        }
+    }
+
+    // Encodes instructions to read a local variable from an arbitary position in the gc_roots stack, relative to the past-the-top position.
+    // net wasm stack: [] -> []
+    fn encode_local_root_read(
+        &self,
+        _local_root: (ir::VarType, wasmgen::LocalIdx),
+        _handle: Self::RootsStackHandle,
+        _scratch: &mut Scratch,
+        _expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        todo!();
+    }
+
+    // Encodes instructions to write a local variable to an arbitary position in the gc_roots stack, relative to the past-the-top position.
+    // net wasm stack: [] -> []
+    fn encode_local_root_write(
+        &self,
+        _local_root: (ir::VarType, wasmgen::LocalIdx),
+        _handle: Self::RootsStackHandle,
+        _scratch: &mut Scratch,
+        _expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        todo!();
+    }
+
+    // We allow Undefined (which is encoded as the nullptr value),
+    // and any reference type (i.e. strings and structs)
+    // net wasm stack: [<closure_irvartype>] -> [i32(closure)]
+    fn encode_closure_conversion(
+        &self,
+        vartype: ir::VarType,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        match vartype {
+            ir::VarType::Undefined => expr_builder.i32_const(-1),
+            ir::VarType::String | ir::VarType::StructT { typeidx: _ } => {}
+            _ => panic!("VarType is not undefined and also not a reference type"),
+        }
+    }
+
+    // MarkSweep traces the whole heap on every collection, so it doesn't need a write barrier.
+    // net wasm stack: [] -> []
+    fn encode_write_barrier<F: FnOnce(&mut wasmgen::ExprBuilder)>(
+        &self,
+        _ir_vartype: ir::VarType,
+        _encode_obj: F,
+        _scratch: &mut Scratch,
+        _expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+    }
+
+    // alloc_fast() counts every allocation, so there is nothing pending.
+    fn encode_write_stats(&self, ptr: u32, expr_builder: &mut wasmgen::ExprBuilder) {
+        self.stats
+            .encode_write(ptr, |expr_builder| expr_builder.i32_const(0), expr_builder);
+    }
+
+    // Encodes instructions to initialize locals that could potentially go onto the gc_roots stack.
+    // `local_types` and `local_map` should have equal length, containing just those locals that should be initialized.
+    // `wasm_local_map` should not be sliced by the caller, because we need to preserve the indexing so that `local_map` will refer to the correct indices in `wasm_local_map`.
+    fn encode_local_roots_init(
+        &self,
+        local_types: &[ir::VarType],
+        local_map: &[usize],
+        wasm_local_map: &[wasmgen::LocalIdx],
+        _scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        for (ir_vartype, wasm_local_map_index) in
+            local_types.iter().copied().zip(local_map.iter().copied())
+        {
+            match ir_vartype {
+                ir::VarType::String | ir::VarType::Array | ir::VarType::StructT { typeidx: _ } => {
+                    expr_builder.i32_const(-1);
+                    expr_builder.local_set(wasm_local_map[wasm_local_map_index]);
+                }
+                ir::VarType::Func => {
+                    expr_builder.i32_const(-1);
+                    expr_builder.local_set(wasm_local_map[wasm_local_map_index + 1]);
+                    // Note: "+1" above to access the closure
+                }
+                ir::VarType::Any => {
+                    expr_builder.i32_const(ir::VarType::Unassigned.tag());
+                    expr_builder.local_set(wasm_local_map[wasm_local_map_index]);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_runner::*;
+    use crate::{HeapManagerKind, Options};
+
+    fn marksweep() -> Options {
+        Options::new_builder()
+            .with_heap_manager(HeapManagerKind::MarkSweep)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn free_chunks_are_reused() {
+        // const xs = []; let s = "";
+        // for (let i = 0; i < 30000; i = i + 1) { if (i % 300 === 0) s = ""; s = s + "x"; if (i % 1000 === 999) xs[xs.length] = s; }
+        // <the concatenation of xs>
+        // The strings have sizes from every free list (including the list of large chunks),
+        // and only a few of them are kept, so the freed chunks are enough for the rest.
+        let text = r#"
+            func#0 () -> any {
+              (decl:any array (prim:array array_new)
+                (decl:any string (string:string "")
+                  (decl:any number (number:number 0.0)
+                    (seq:any
+                      (loop:undefined
+                        (if:undefined (prim:boolean number_lt (var:number local#2) (number:number 30000.0))
+                          (seq:!
+                            (if:undefined (prim:boolean number_eq (prim:number number_rem (var:number local#2) (number:number 300.0)) (number:number 0.0))
+                              (assign:undefined local#1 (string:string ""))
+                              (undefined:undefined))
+                            (assign:undefined local#1 (prim:string string_add (var:string local#1) (string:string "x")))
+                            (if:undefined (prim:boolean number_eq (prim:number number_rem (var:number local#2) (number:number 1000.0)) (number:number 999.0))
+                              (prim:undefined array_set (var:array local#0) (prim:number array_length (var:array local#0)) (var:string local#1))
+                              (undefined:undefined))
+                            (assign:undefined local#2 (prim:number number_add (var:number local#2) (number:number 1.0)))
+                            (break:! 0 (undefined:undefined)))
+                          (undefined:undefined)))
+                      (assign:undefined local#1 (string:string ""))
+                      (assign:undefined local#2 (number:number 0.0))
+                      (loop:undefined
+                        (if:undefined (prim:boolean number_lt (var:number local#2) (prim:number array_length (var:array local#0)))
+                          (typecast:! string narrow (prim:any array_get (var:array local#0) (var:number local#2))
+                            (seq:!
+                              (assign:undefined local#1 (prim:string string_add (var:string local#1) (var:string local#3)))
+                              (assign:undefined local#2 (prim:number number_add (var:number local#2) (number:number 1.0)))
+                              (break:! 0 (undefined:undefined)))
+                            (trap:! 0x13 0:1:1-1:2))
+                          (undefined:undefined)))
+                      (var:string local#1)))))
+            }
+            entry func#0;
+        "#;
+        // the kept strings have lengths 300, 100, 200, 300, ... (30 of them)
+        let expected = Ok(Value::String("x".repeat(6000)));
+        assert_eq!(run_ir_all_levels(text, marksweep()), expected);
+        let (result, stats) = run_ir_with_stats(text, "-O2", marksweep());
+        assert_eq!(result, expected);
+        assert!(stats.collections > 1);
+        assert_eq!(
+            stats.peak_heap_pages,
+            HeapManagerKind::MarkSweep.initial_heap_size()
+        );
+    }
+
+    #[test]
+    fn heap_grows_when_most_objects_survive() {
+        // let list = null; for (let i = 0; i < 50000; i = i + 1) { list = { 0: i, 1: list }; } <sum of list>
+        // The list doesn't fit in the initial chunks, so the heap has to grow (and the mark stack with it).
+        let text = r#"
+            struct#0 = (number any);
+            func#0 () -> any {
+              (decl:any any (null:null)
+                (decl:any number (number:number 0.0)
+                  (seq:any
+                    (loop:undefined
+                      (if:undefined (prim:boolean number_lt (var:number local#1) (number:number 50000.0))
+                        (decl:! struct#0 (struct:struct#0 struct#0)
+                          (seq:!
+                            (assign:undefined local#2.0:0 (var:number local#1))
+                            (assign:undefined local#2.0:1 (var:any local#0))
+                            (assign:undefined local#0 (var:struct#0 local#2))
+                            (assign:undefined local#1 (prim:number number_add (var:number local#1) (number:number 1.0)))
+                            (break:! 0 (undefined:undefined))))
+                        (undefined:undefined)))
+                    (assign:undefined local#1 (number:number 0.0))
+                    (loop:undefined
+                      (typecast:undefined struct#0 narrow (var:any local#0)
+                        (seq:!
+                          (assign:undefined local#1 (prim:number number_add (var:number local#1) (var:number local#2.0:0)))
+                          (assign:undefined local#0 (var:any local#2.0:1))
+                          (break:! 0 (undefined:undefined)))
+                        (undefined:undefined)))
+                    (var:number local#1))))
+            }
+            entry func#0;
+        "#;
+        let expected = Ok(Value::Number(1249975000.0));
+        assert_eq!(run_ir_all_levels(text, marksweep()), expected);
+        let (result, stats) = run_ir_with_stats(text, "-O2", marksweep());
+        assert_eq!(result, expected);
+        assert!(stats.collections > 0);
+        assert!(stats.peak_heap_pages > HeapManagerKind::MarkSweep.initial_heap_size());
+    }
+}
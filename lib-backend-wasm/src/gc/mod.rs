@@ -1,5 +1,7 @@
 pub mod cheney;
+pub mod generational;
 pub mod leaky;
+pub mod marksweep;
 
 use crate::WASM_PAGE_BITS;
 use crate::WASM_PAGE_SIZE;
@@ -14,25 +16,29 @@ pub trait HeapManager {
     // Constructs a new HeapManager.
     // This function might add things to the wasm_module (e.g. globals) for use by the GC.
     // `heap_begin`: the lowest index of the heap, in WASM_PAGE_SIZE
-    // `heap_initial_end`: initial past-the-end (highest) index of the heap, in WASM_PAGE_SIZE
-    // The memory is guaranteed to be constructed in unbounded mode (but might still fail with -1 if the host refuses to give more memory)
+    // `heap_initial_end`: initial past-the-end (highest) index of the heap, in WASM_PAGE_SIZE (the heap is at least `initial_heap_size()` pages, but might be larger)
+    // The memory might have a maximum size (see Options), so memory.grow might fail with -1 (it might also fail if the host refuses to give more memory)
     // Note: Some (or maybe most) GCs will maintain a stack called `gc_roots`, where locals that contain pointers will be pushed before calling another function and popped after that function returns.
     // * This allows the GC to know what the roots are, when it needs to run.
     // * This `gc_roots` stack is an implementation detail of the GC.  External code should not assume its existence.
     // `struct_sizes` must be all multiples of 4 bytes.
+    // `stackptr`: global that stores the stackptr (see encode_stack_allocation())
+    // `stack_end`: past-the-end (highest) index of the stack, in WASM_PAGE_SIZE
     /*
     fn new(
         struct_types: &'a [Box<[ir::VarType]>],
         struct_field_byte_offsets: &'b [Box<[u32]>],
         struct_sizes: &'c [u32],
         memidx: wasmgen::MemIdx,
+        stackptr: wasmgen::GlobalIdx,
+        stack_end: u32,
         heap_begin: u32,
         heap_initial_end: u32,
         wasm_module: &mut wasmgen::WasmModule,
     ) -> Self;
     */
 
-    // Returns the initial number of pages required by this heap HeapManager.
+    // Returns the minimum initial number of pages required by this heap HeapManager (which is also the default).
     fn initial_heap_size() -> u32;
 
     // Encodes instructions to get a chunk of memory suitable for the given vartype.
@@ -61,6 +67,7 @@ pub trait HeapManager {
     );
 
     // Encodes instructions to get a chunk of memory for an string/array of unknown size.  See `encode_fixed_allocation` for more details.
+    // For Array, this allocates the buffer of elements (not the array header), `num_bytes` is the total size of the elements, and the capacity will be written at the start of the buffer.  The caller must initialize all the elements before the next allocation.
     // The size need not be a multiple of 4.  (But the allocator will round up to nearest 4-byte boundary.)
     //
     // This function generates code equivalent to, but possibly more efficient to doing this:
@@ -79,6 +86,23 @@ pub trait HeapManager {
         expr_builder: &mut wasmgen::ExprBuilder,
     );
 
+    // Returns the number of bytes that encode_stack_allocation() needs for an object of the given vartype (a multiple of 4).
+    // `ir_vartype`: vartype of the object we want (must be StructT).
+    fn stack_allocation_size(&self, ir_vartype: ir::VarType) -> u32;
+
+    // Encodes instructions to initialize an object of the given vartype in a chunk of `stack_allocation_size()` bytes on the stack, as if it was freshly allocated.
+    // The chunk must be in [stackptr, stack_end), and the objects in that range must be packed together (so the GC can walk through them).
+    // The object must never be referenced from the heap or from global variables, and it must be initialized again before it is reused.
+    // This does not allocate anything on the heap.
+    // `ir_vartype`: vartype of the object we want (must be StructT).
+    // net wasm stack: [i32(chunk)] -> [i32(ptr)]
+    fn encode_stack_allocation(
+        &self,
+        ir_vartype: ir::VarType,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    );
+
     type RootsStackHandle;
 
     // Encodes instructions to push local variables to gc_roots stack.
@@ -146,6 +170,24 @@ pub trait HeapManager {
         expr_builder: &mut wasmgen::ExprBuilder,
     );
 
+    // Encodes the write barrier, which must come after every store of a value of type `ir_vartype` into a field of an object allocated by this HeapManager (i.e. a struct, an array header, or an array buffer).
+    // It lets a GC that doesn't trace the whole heap on every collection (e.g. a generational GC) find pointers that were stored into objects it isn't tracing.
+    // The object might be on the stack (see encode_stack_allocation()) instead of on the heap.
+    // `encode_obj` should have net wasm stack [] -> [i32(ptr)], where `ptr` is the object that was written to.  GCs that don't need a write barrier will not call it.
+    // net wasm stack: [] -> []
+    fn encode_write_barrier<F: FnOnce(&mut wasmgen::ExprBuilder)>(
+        &self,
+        ir_vartype: ir::VarType,
+        encode_obj: F,
+        scratch: &mut Scratch,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    );
+
+    // Encodes instructions to write the counters of this HeapManager into the gc stats struct at `ptr` (see GC_STATS_SIZE in lib.rs).
+    // It must not allocate, since the host may call it after the program has run out of memory.
+    // net wasm stack: [] -> []
+    fn encode_write_stats(&self, ptr: u32, expr_builder: &mut wasmgen::ExprBuilder);
+
     // Encodes instructions to initialize locals that could potentially go onto the gc_roots stack.
     // For Cheney, this would set all pointers to -1.  Anys are set to unassigned (Note: although wasm zero-initializes things, the local variable might be reused (due to the way Scratch works), so make any assumptions on the existing value.).
     // This is necessary because the first memory allocation might happen before these locals are initialized.
@@ -160,3 +202,196 @@ pub trait HeapManager {
         expr_builder: &mut wasmgen::ExprBuilder,
     );
 }
+
+/**
+ * The counters that heap managers keep for the exported `gc_stats` function (see GC_STATS_SIZE in lib.rs).
+ * They are in wasm globals, so that they are cheap to update.
+ */
+pub struct GcStats {
+    collections: wasmgen::GlobalIdx, // i32: number of collections so far
+    peak_heap_pages: wasmgen::GlobalIdx, // i32: largest size of the heap so far (in WASM_PAGE_SIZE), updated by encode_heap_grown()
+    bytes_allocated: wasmgen::GlobalIdx, // i64: bytes allocated so far (including tags), except those that the heap manager counts lazily (see encode_write())
+    bytes_surviving: wasmgen::GlobalIdx, // i32: bytes in use on the heap right after the most recent collection
+    memidx: wasmgen::MemIdx,
+    heap_begin: u32,
+}
+
+impl GcStats {
+    pub fn new(
+        memidx: wasmgen::MemIdx,
+        heap_begin: u32,
+        heap_initial_end: u32,
+        wasm_module: &mut wasmgen::WasmModule,
+    ) -> Self {
+        GcStats {
+            collections: wasm_module.add_i32_global(wasmgen::Mut::Var, 0),
+            peak_heap_pages: wasm_module
+                .add_i32_global(wasmgen::Mut::Var, (heap_initial_end - heap_begin) as i32),
+            bytes_allocated: wasm_module.add_i64_global(wasmgen::Mut::Var, 0),
+            bytes_surviving: wasm_module.add_i32_global(wasmgen::Mut::Var, 0),
+            memidx,
+            heap_begin,
+        }
+    }
+
+    // Encodes instructions to add to the number of bytes allocated.
+    // net wasm stack: [i32(num_bytes)] -> []
+    pub fn encode_add_allocated(&self, expr_builder: &mut wasmgen::ExprBuilder) {
+        expr_builder.i64_extend_i32_u();
+        expr_builder.global_get(self.bytes_allocated);
+        expr_builder.i64_add();
+        expr_builder.global_set(self.bytes_allocated);
+    }
+
+    // Encodes instructions to count a collection that leaves `bytes_surviving` bytes in use on the heap.
+    // net wasm stack: [i32(bytes_surviving)] -> []
+    pub fn encode_collection(&self, expr_builder: &mut wasmgen::ExprBuilder) {
+        expr_builder.global_set(self.bytes_surviving);
+        expr_builder.global_get(self.collections);
+        expr_builder.i32_const(1);
+        expr_builder.i32_add();
+        expr_builder.global_set(self.collections);
+    }
+
+    // Encodes instructions to record the size of the heap, which must be done whenever the heap manager might have grown the memory.
+    // peak_heap_pages = max(peak_heap_pages, memory.size - heap_begin);
+    // net wasm stack: [] -> []
+    pub fn encode_heap_grown(&self, expr_builder: &mut wasmgen::ExprBuilder) {
+        let encode_heap_pages = |expr_builder: &mut wasmgen::ExprBuilder| {
+            expr_builder.memory_size(self.memidx);
+            expr_builder.i32_const(self.heap_begin as i32);
+            expr_builder.i32_sub();
+        };
+        encode_heap_pages(expr_builder);
+        expr_builder.global_get(self.peak_heap_pages);
+        encode_heap_pages(expr_builder);
+        expr_builder.global_get(self.peak_heap_pages);
+        expr_builder.i32_gt_u();
+        expr_builder.select();
+        expr_builder.global_set(self.peak_heap_pages);
+    }
+
+    // Encodes instructions to write the counters into the gc stats struct at `ptr`.
+    // `encode_pending` should have net wasm stack [] -> [i32(num_bytes)], where `num_bytes` is the number of bytes allocated that have not been added by encode_add_allocated() yet.
+    // (Bump allocators can count the bytes allocated since the last collection from their free pointer, so that allocation doesn't need to update the counter.)
+    // net wasm stack: [] -> []
+    pub fn encode_write<F: FnOnce(&mut wasmgen::ExprBuilder)>(
+        &self,
+        ptr: u32,
+        encode_pending: F,
+        expr_builder: &mut wasmgen::ExprBuilder,
+    ) {
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(ptr as i32);
+        expr_builder.global_get(self.collections);
+        expr_builder.i32_store(wasmgen::MemArg::new4(0));
+
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(ptr as i32);
+        expr_builder.global_get(self.peak_heap_pages);
+        expr_builder.i32_store(wasmgen::MemArg::new4(4));
+
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(ptr as i32);
+        encode_pending(expr_builder);
+        expr_builder.i64_extend_i32_u();
+        expr_builder.global_get(self.bytes_allocated);
+        expr_builder.i64_add();
+        expr_builder.i64_store(wasmgen::MemArg::new8(8));
+
+        // net wasm stack: [] -> []
+        expr_builder.i32_const(ptr as i32);
+        expr_builder.global_get(self.bytes_surviving);
+        expr_builder.i64_extend_i32_u();
+        expr_builder.i64_store(wasmgen::MemArg::new8(16));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_runner::*;
+    use crate::{HeapManagerKind, Options};
+
+    #[test]
+    fn stress() {
+        // function make(d) { return d === 0 ? null : { 0: make(d - 1), 1: make(d - 1) }; }
+        // function count(t) { return t === null ? 0 : 1 + count(t[0]) + count(t[1]); }
+        // const old = make(12); const kept = []; let total = 0;
+        // for (let i = 0; i < 100; i = i + 1) { const t = make(10); total = total + count(t); if (i % 10 === 0) kept[i / 10] = t; }
+        // for (let i = 0; i < kept.length; i = i + 1) { total = total + count(kept[i]); }
+        // total + count(old)
+        // Most trees die young, while some live until the end, and the collections happen in the middle of building a tree.
+        let text = r#"
+            struct#0 = (any any);
+            func#0 (number) -> any {
+              (if:any (prim:boolean number_eq (var:number local#0) (number:number 0.0))
+                (null:null)
+                (decl:any struct#0 (struct:struct#0 struct#0)
+                  (seq:any
+                    (assign:undefined local#1.0:0 (call:any func#0 (prim:number number_sub (var:number local#0) (number:number 1.0))))
+                    (assign:undefined local#1.0:1 (call:any func#0 (prim:number number_sub (var:number local#0) (number:number 1.0))))
+                    (var:struct#0 local#1))))
+            }
+            func#1 (any) -> number {
+              (typecast:number struct#0 narrow (var:any local#0)
+                (prim:number number_add (number:number 1.0)
+                  (prim:number number_add (call:number func#1 (var:any local#1.0:0)) (call:number func#1 (var:any local#1.0:1))))
+                (number:number 0.0))
+            }
+            func#2 () -> any {
+              (decl:any any (call:any func#0 (number:number 12.0))
+                (decl:any array (prim:array array_new)
+                  (decl:any number (number:number 0.0)
+                    (decl:any number (number:number 0.0)
+                      (seq:any
+                        (loop:undefined
+                          (if:undefined (prim:boolean number_lt (var:number local#2) (number:number 100.0))
+                            (decl:! any (call:any func#0 (number:number 10.0))
+                              (seq:!
+                                (assign:undefined local#3 (prim:number number_add (var:number local#3) (call:number func#1 (var:any local#4))))
+                                (if:undefined (prim:boolean number_eq (prim:number number_rem (var:number local#2) (number:number 10.0)) (number:number 0.0))
+                                  (prim:undefined array_set (var:array local#1) (prim:number number_div (var:number local#2) (number:number 10.0)) (var:any local#4))
+                                  (undefined:undefined))
+                                (assign:undefined local#2 (prim:number number_add (var:number local#2) (number:number 1.0)))
+                                (break:! 0 (undefined:undefined))))
+                            (undefined:undefined)))
+                        (assign:undefined local#2 (number:number 0.0))
+                        (loop:undefined
+                          (if:undefined (prim:boolean number_lt (var:number local#2) (prim:number array_length (var:array local#1)))
+                            (seq:!
+                              (assign:undefined local#3 (prim:number number_add (var:number local#3) (call:number func#1 (prim:any array_get (var:array local#1) (var:number local#2)))))
+                              (assign:undefined local#2 (prim:number number_add (var:number local#2) (number:number 1.0)))
+                              (break:! 0 (undefined:undefined)))
+                            (undefined:undefined)))
+                        (prim:number number_add (var:number local#3) (call:number func#1 (var:any local#0))))))))
+            }
+            entry func#2;
+        "#;
+        // 110 trees of depth 10 and one of depth 12
+        let expected = Ok(Value::Number(116625.0));
+        for &heap_manager in &[
+            HeapManagerKind::Cheney,
+            HeapManagerKind::Leaky,
+            HeapManagerKind::Generational,
+            HeapManagerKind::MarkSweep,
+        ] {
+            let options = Options::new_builder()
+                .with_heap_manager(heap_manager)
+                .build()
+                .unwrap();
+            let (result, stats) = run_ir_with_stats(text, "-O2", options);
+            assert_eq!(result, expected, "with {:?}", heap_manager);
+            assert_eq!(
+                stats.collections > 0,
+                heap_manager != HeapManagerKind::Leaky,
+                "with {:?}",
+                heap_manager
+            );
+        }
+        let options = Options::new_builder()
+            .with_heap_verifier(true)
+            .build()
+            .unwrap();
+        assert_eq!(run_ir(text, "-O2", options), expected);
+    }
+}
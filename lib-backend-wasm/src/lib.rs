@@ -45,17 +45,23 @@
  * We divide the memory as such (from 0 (left) to memory.size (right)):
  * [.....(stack).....|.....(global data).....|.....(heap).....]
  * stack: Grows leftward (toward smaller indices), so that a stack overflow will trigger a hard error (instead of silently overwritting our global data).  Contains stuff owned by a function, that needs to have its address taken.
+ *        Closure environments that don't escape (see `ir::escape`) are allocated here, in a frame that the function reserves when it is called and releases when it returns (or makes a tail call).
+ *        The GC walks the frames to find the pointers to the heap, so everything in [global#0, end of stack) must be an object initialized by the heap manager.
  * global data: Bulk data needed by the whole program.  Stores things like string constants (for pooling).  Size of this partition depends on the program being compiled.
+ *              It starts with the gc stats struct (see GC_STATS_SIZE), so that the struct has a fixed address.
  * heap:  Managed by the GC.  Memory can be increased on the right side with wasm memory.grow instruction.  Only the GC knows how to read the stuff inside here.
  * There is one pre-added global:
  * * global#0 is the stack pointer (points to the last memory address that is filled).
  * * * Note: By convention, arguments and return values on the stack go **on top** of the stack pointer.
  * * * So if we have a 12-byte value on the stack that is a return value, it will be at location (global#0 - 12).
+ * * * `main` returns its result in this way, and since the top of the stack depends on the stack size, the host gets its address from the exported `result_ptr` function.
  * * The GC might add more globals.  So the funcs should not make any assumption about the starting globalidx that they can use.
  */
 use ir;
 use wasmgen;
 
+mod any_prim_inst;
+mod array_prim_inst;
 mod func;
 mod gc;
 mod global_var;
@@ -64,15 +70,23 @@ mod mutcontext;
 mod opt_var_conv;
 mod pre_traverse;
 mod string_prim_inst;
+mod tail_call;
+#[cfg(test)]
+mod test_runner;
 mod var_conv;
 
 use gc::cheney::Cheney;
+use gc::generational::Generational;
 use gc::leaky::Leaky;
+use gc::marksweep::MarkSweep;
 use gc::HeapManager;
 
 use projstd::iter::*;
+use projstd::searchablevec::SearchableVec;
 use projstd::tuple::*;
 
+use std::collections::HashMap;
+
 use wasmgen::Scratch;
 
 const IR_FUNCIDX_TABLE_OFFSET: u32 = 0; // If ir::FuncIdx == x, then wasmgen::TableIdx == IR_FUNCIDX_TABLE_OFFSET + x as u32
@@ -81,14 +95,289 @@ const WASM_PAGE_SIZE: u32 = 65536;
 const WASM_PAGE_BITS: u32 = WASM_PAGE_SIZE.trailing_zeros();
 
 // In units of WASM_PAGE_SIZE
-const MEM_STACK_SIZE: u32 = 1 << 4; // 1 MiB of stack space
+const MEM_STACK_SIZE: u32 = 1 << 4; // 1 MiB of stack space (the default)
+const MEM_MAX_SIZE: u32 = 1 << 16; // 4 GiB, the most that a wasm32 linear memory can have
+const MEM_GLOBAL_DATA_MAX_SIZE: u32 = 1 << 8; // 16 MiB, the space that Options::build() leaves for the global data of the program
+
+// The gc stats struct, which is filled in by the exported `gc_stats` function (it returns the address of the struct).
+// The host may call `gc_stats` at any time, even after the program has raised an error.
+// It is at the start of the global data, so it is at address 1 MiB with the default stack size.
+// Layout: [collections(u32), peak_heap_pages(u32), bytes_allocated(u64), bytes_surviving(u64)]
+// `peak_heap_pages` is the largest size of the heap so far in WASM_PAGE_SIZE (the heap manager records it whenever it grows the memory).
+// `bytes_allocated` includes the tags of the objects (4 bytes each, which Leaky counts even though it doesn't store them).
+// `bytes_surviving` is the number of bytes in use on the heap right after the most recent collection.
+const GC_STATS_SIZE: u32 = 24;
 
-// Struct containing compilation options
-#[derive(Default, Copy, Clone)]
+/**
+ * Compilation options.
+ * Options::default() gives the options that the host code expects (see `with_stack_pages()`), without any WebAssembly proposals.
+ * Use Options::new_builder() to customize them.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Options {
     wasm_multi_value: bool, // Whether we can generate code that uses the WebAssembly multi-valued returns proposal
     wasm_bulk_memory: bool, // Whether we can generate code that uses the WebAssembly bulk memory proposal
     wasm_tail_call: bool, // Whether we can generate code that uses the WebAssembly tail call proposal
+    heap_manager: HeapManagerKind, // The garbage collector used by the generated code
+    stack_size: u32,      // Size of the stack, in WASM_PAGE_SIZE
+    heap_initial_size: u32, // Initial size of the heap, in WASM_PAGE_SIZE (at least heap_manager.initial_heap_size())
+    heap_max_size: Option<u32>, // Maximum size of the heap, in WASM_PAGE_SIZE (None means that the memory is unbounded)
+    heap_verifier: bool, // Whether the generated code checks the heap after every garbage collection (only for HeapManagerKind::Cheney)
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            wasm_multi_value: false,
+            wasm_bulk_memory: false,
+            wasm_tail_call: false,
+            heap_manager: HeapManagerKind::default(),
+            stack_size: MEM_STACK_SIZE,
+            heap_initial_size: HeapManagerKind::default().initial_heap_size(),
+            heap_max_size: None,
+            heap_verifier: false,
+        }
+    }
+}
+
+impl Options {
+    /**
+     * Returns a builder that starts from the default options.
+     */
+    pub fn new_builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+}
+
+/**
+ * Builder for Options, which checks that the options are consistent when build() is called.
+ * The heap sizes are only checked against the heap manager in build(), so the setters may be called in any order.
+ */
+#[derive(Default, Copy, Clone, Debug)]
+pub struct OptionsBuilder {
+    wasm_multi_value: bool,
+    wasm_bulk_memory: bool,
+    wasm_tail_call: bool,
+    heap_manager: HeapManagerKind,
+    stack_size: Option<u32>,        // None means MEM_STACK_SIZE
+    heap_initial_size: Option<u32>, // None means heap_manager.initial_heap_size()
+    heap_max_size: Option<u32>,
+    heap_verifier: bool,
+}
+
+impl OptionsBuilder {
+    /**
+     * Allows the generated code to use the WebAssembly multi-value proposal.
+     */
+    pub fn with_wasm_multi_value(mut self, enable: bool) -> Self {
+        self.wasm_multi_value = enable;
+        self
+    }
+    /**
+     * Allows the generated code to use the WebAssembly bulk memory operations proposal.
+     */
+    pub fn with_wasm_bulk_memory(mut self, enable: bool) -> Self {
+        self.wasm_bulk_memory = enable;
+        self
+    }
+    /**
+     * Allows the generated code to use the WebAssembly tail call proposal.
+     */
+    pub fn with_wasm_tail_call(mut self, enable: bool) -> Self {
+        self.wasm_tail_call = enable;
+        self
+    }
+    /**
+     * Sets the garbage collector used by the generated code.
+     */
+    pub fn with_heap_manager(mut self, heap_manager: HeapManagerKind) -> Self {
+        self.heap_manager = heap_manager;
+        self
+    }
+    /**
+     * Sets the size of the stack, in pages of 64 KiB.
+     */
+    pub fn with_stack_pages(mut self, pages: u32) -> Self {
+        self.stack_size = Some(pages);
+        self
+    }
+    /**
+     * Sets the initial size of the heap, in pages of 64 KiB.
+     * It must be at least the minimum required by the heap manager (which is also the default).
+     */
+    pub fn with_heap_initial_pages(mut self, pages: u32) -> Self {
+        self.heap_initial_size = Some(pages);
+        self
+    }
+    /**
+     * Sets the maximum size of the heap, in pages of 64 KiB.
+     * The program fails with an out of memory error if it needs more than that.
+     * By default, the heap can grow until the host refuses to give more memory.
+     */
+    pub fn with_heap_max_pages(mut self, pages: u32) -> Self {
+        self.heap_max_size = Some(pages);
+        self
+    }
+    /**
+     * Makes the generated code check the heap after every garbage collection, and raise ERROR_CODE_HEAP_VERIFICATION if it is corrupted.
+     * This is slow, and is only meant for debugging the garbage collector.  It is only supported by HeapManagerKind::Cheney.
+     */
+    pub fn with_heap_verifier(mut self, enable: bool) -> Self {
+        self.heap_verifier = enable;
+        self
+    }
+
+    /**
+     * Applies a command-line style flag, which is one of:
+     * --wasm-multi-value, --wasm-bulk-memory, --wasm-tail-call,
+     * --heap-manager=<cheney|leaky|generational|marksweep>,
+     * --stack-pages=<n>, --heap-initial-pages=<n>, --heap-max-pages=<n>, --verify-heap
+     */
+    pub fn with_flag(self, flag: &str) -> Result<Self, OptionsError> {
+        let (name, value): (&str, Option<&str>) = match flag.find('=') {
+            Some(pos) => (&flag[..pos], Some(&flag[pos + 1..])),
+            None => (flag, None),
+        };
+        let bad_flag = || OptionsError::BadFlag(flag.to_owned());
+        let parse_pages = |value: Option<&str>| -> Result<u32, OptionsError> {
+            value
+                .and_then(|v| v.parse::<u32>().ok())
+                .ok_or_else(bad_flag)
+        };
+        match (name, value) {
+            ("--wasm-multi-value", None) => Ok(self.with_wasm_multi_value(true)),
+            ("--wasm-bulk-memory", None) => Ok(self.with_wasm_bulk_memory(true)),
+            ("--wasm-tail-call", None) => Ok(self.with_wasm_tail_call(true)),
+            ("--heap-manager", Some(v)) => HeapManagerKind::from_name(v)
+                .map(|heap_manager| self.with_heap_manager(heap_manager))
+                .ok_or_else(bad_flag),
+            ("--stack-pages", _) => parse_pages(value).map(|pages| self.with_stack_pages(pages)),
+            ("--heap-initial-pages", _) => {
+                parse_pages(value).map(|pages| self.with_heap_initial_pages(pages))
+            }
+            ("--heap-max-pages", _) => {
+                parse_pages(value).map(|pages| self.with_heap_max_pages(pages))
+            }
+            ("--verify-heap", None) => Ok(self.with_heap_verifier(true)),
+            _ => Err(bad_flag()),
+        }
+    }
+
+    /**
+     * Checks the options and returns them.
+     */
+    pub fn build(self) -> Result<Options, OptionsError> {
+        let minimum: u32 = self.heap_manager.initial_heap_size();
+        let stack_size: u32 = self.stack_size.unwrap_or(MEM_STACK_SIZE);
+        let heap_initial_size: u32 = self.heap_initial_size.unwrap_or(minimum);
+        if stack_size == 0 {
+            return Err(OptionsError::EmptyStack);
+        }
+        if heap_initial_size < minimum {
+            return Err(OptionsError::HeapTooSmall { minimum });
+        }
+        if let Some(heap_max_size) = self.heap_max_size {
+            if heap_max_size < heap_initial_size {
+                return Err(OptionsError::HeapMaxBelowInitial);
+            }
+        }
+        if self.heap_verifier && self.heap_manager != HeapManagerKind::Cheney {
+            return Err(OptionsError::HeapVerifierUnsupported);
+        }
+        // the global data depends on the program, so we leave MEM_GLOBAL_DATA_MAX_SIZE for it
+        // (and the memory must be smaller than 4 GiB, so that every address up to the end of the memory fits in an i32)
+        if stack_size as u64 + MEM_GLOBAL_DATA_MAX_SIZE as u64 + heap_initial_size as u64
+            >= MEM_MAX_SIZE as u64
+        {
+            return Err(OptionsError::MemoryTooLarge);
+        }
+        Ok(Options {
+            wasm_multi_value: self.wasm_multi_value,
+            wasm_bulk_memory: self.wasm_bulk_memory,
+            wasm_tail_call: self.wasm_tail_call,
+            heap_manager: self.heap_manager,
+            stack_size,
+            heap_initial_size,
+            heap_max_size: self.heap_max_size,
+            heap_verifier: self.heap_verifier,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionsError {
+    EmptyStack,                    // the stack needs at least one page
+    HeapTooSmall { minimum: u32 }, // the initial heap is smaller than what the heap manager needs
+    HeapMaxBelowInitial,           // the maximum heap size is smaller than the initial heap size
+    MemoryTooLarge, // the stack, the global data and the initial heap don't fit in a wasm32 linear memory
+    BadFlag(String), // with_flag() was given an unknown or malformed flag
+    HeapVerifierUnsupported, // the heap verifier was enabled for a heap manager other than Cheney
+}
+
+impl std::fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OptionsError::EmptyStack => f.write_str("the stack must have at least one page"),
+            OptionsError::HeapTooSmall { minimum } => write!(
+                f,
+                "the initial heap must have at least {} pages for this heap manager",
+                minimum
+            ),
+            OptionsError::HeapMaxBelowInitial => {
+                f.write_str("the maximum heap size must not be smaller than the initial heap size")
+            }
+            OptionsError::MemoryTooLarge => {
+                f.write_str("the stack and the initial heap must fit in 4 GiB of memory, with room for the global data")
+            }
+            OptionsError::BadFlag(flag) => write!(f, "unknown or malformed flag \"{}\"", flag),
+            OptionsError::HeapVerifierUnsupported => {
+                f.write_str("the heap verifier is only supported by the cheney heap manager")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+/**
+ * The garbage collectors (i.e. implementations of HeapManager) that the backend can generate.
+ */
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapManagerKind {
+    // Copies all live objects to the other semispace on every collection (see gc::cheney::Cheney)
+    #[default]
+    Cheney,
+    // Never frees memory (see gc::leaky::Leaky)
+    Leaky,
+    // Only copies the live objects in the nursery on most collections (see gc::generational::Generational)
+    Generational,
+    // Never moves objects, and reuses the space of dead objects through free lists (see gc::marksweep::MarkSweep)
+    MarkSweep,
+}
+
+impl HeapManagerKind {
+    /**
+     * Returns the heap manager with the given name (one of "cheney", "leaky", "generational" or "marksweep").
+     */
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cheney" => Some(HeapManagerKind::Cheney),
+            "leaky" => Some(HeapManagerKind::Leaky),
+            "generational" => Some(HeapManagerKind::Generational),
+            "marksweep" => Some(HeapManagerKind::MarkSweep),
+            _ => None,
+        }
+    }
+
+    // Returns the minimum (and default) initial number of pages required by the heap manager.
+    fn initial_heap_size(self) -> u32 {
+        match self {
+            HeapManagerKind::Cheney => Cheney::initial_heap_size(),
+            HeapManagerKind::Leaky => Leaky::initial_heap_size(),
+            HeapManagerKind::Generational => Generational::initial_heap_size(),
+            HeapManagerKind::MarkSweep => MarkSweep::initial_heap_size(),
+        }
+    }
 }
 
 /**
@@ -154,9 +443,12 @@ fn encode_program(ir_program: &ir::Program, options: Options) -> wasmgen::WasmMo
         }))
         .collect();
 
+    // the stack pointer starts at the end of the stack, which is also where the global data starts
+    // (this can't overflow, since build() keeps the whole memory below 4 GiB; wasm treats the i32 address as unsigned)
+    let stack_end: u32 = options.stack_size << WASM_PAGE_BITS;
+
     // add stack ptr
-    let globalidx_stackptr =
-        wasm_module.add_i32_global(wasmgen::Mut::Var, (MEM_STACK_SIZE * WASM_PAGE_SIZE) as i32);
+    let globalidx_stackptr = wasm_module.add_i32_global(wasmgen::Mut::Var, stack_end as i32);
 
     // add ir global vars
     let global_var_manager =
@@ -193,39 +485,53 @@ fn encode_program(ir_program: &ir::Program, options: Options) -> wasmgen::WasmMo
     } = pre_traverse::pre_traverse_funcs(&ir_program.funcs);
 
     let (shifted_string_pool, pool_data) =
-        string_pool.into_shifted_and_buffer(MEM_STACK_SIZE << WASM_PAGE_BITS);
+        string_pool.into_shifted_and_buffer(stack_end + GC_STATS_SIZE);
 
     assert!(pool_data.len() & 3 == 0); // assert that it is at 4-byte boundary
 
     // make static data for appl locations
     let (appl_data, appl_data_encoder) = pre_traverse::make_appl_location_static_data(
         appl_location_sv,
-        (MEM_STACK_SIZE << WASM_PAGE_BITS) + pool_data.len() as u32,
+        stack_end + GC_STATS_SIZE + pool_data.len() as u32,
     );
 
     assert!(appl_data.len() & 3 == 0); // assert that it is at 4-byte boundary
 
     // in terms of WASM_PAGE_SIZE (rounded up to nearest page boundary)
     let globals_num_pages: u32 =
-        ((pool_data.len() + appl_data.len()) as u32 + (WASM_PAGE_SIZE - 1)) >> WASM_PAGE_BITS;
+        (GC_STATS_SIZE + (pool_data.len() + appl_data.len()) as u32 + (WASM_PAGE_SIZE - 1))
+            >> WASM_PAGE_BITS;
 
-    // add linear memory
-    let memidx: wasmgen::MemIdx = encode_mem(
-        MEM_STACK_SIZE + globals_num_pages + Cheney::initial_heap_size(),
-        &mut wasm_module,
+    // in terms of WASM_PAGE_SIZE
+    let heap_begin: u32 = options.stack_size + globals_num_pages;
+    // build() only checked that the stack and the heap fit with MEM_GLOBAL_DATA_MAX_SIZE of global data
+    assert!(
+        globals_num_pages <= MEM_GLOBAL_DATA_MAX_SIZE,
+        "The program has too much constant data"
     );
-    /*let memidx: wasmgen::MemIdx = encode_mem(
-        MEM_STACK_SIZE + globals_num_pages + Cheney::initial_heap_size(),
-        &mut wasm_module,
-    );*/
+    let heap_initial_end: u32 = heap_begin + options.heap_initial_size;
+
+    // the maximum is clamped, since the global data might take up some of the space that the options allowed
+    let mem_max: Option<u32> = options
+        .heap_max_size
+        .map(|heap_max_size| std::cmp::min(heap_begin.saturating_add(heap_max_size), MEM_MAX_SIZE));
+
+    // add linear memory
+    let memidx: wasmgen::MemIdx = encode_mem(heap_initial_end, mem_max, &mut wasm_module);
 
     // export the memory (so that the host can read the return value)
     wasm_module.export_mem(memidx, "linear_memory".to_string());
 
+    // export the address of the return value
+    encode_result_ptr_export(
+        stack_end - var_conv::size_in_memory(ir::VarType::Any),
+        &mut wasm_module,
+    );
+
     // initialize pool data
     encode_static_data(
         &pool_data,
-        MEM_STACK_SIZE << WASM_PAGE_BITS,
+        stack_end + GC_STATS_SIZE,
         memidx,
         &mut wasm_module,
     );
@@ -233,58 +539,190 @@ fn encode_program(ir_program: &ir::Program, options: Options) -> wasmgen::WasmMo
     // initialize appl data
     encode_static_data(
         &appl_data,
-        (MEM_STACK_SIZE << WASM_PAGE_BITS) + pool_data.len() as u32,
+        stack_end + GC_STATS_SIZE + pool_data.len() as u32,
         memidx,
         &mut wasm_module,
     );
 
     // garbage collector
-    let heap = Cheney::new(
-        &ir_program.struct_types,
-        &struct_field_byte_offsets,
-        &struct_sizes,
-        memidx,
-        MEM_STACK_SIZE + globals_num_pages,
-        MEM_STACK_SIZE + globals_num_pages + Cheney::initial_heap_size(),
-        global_var_manager.deref(),
-        error_func,
-        &mut wasm_module,
-    );
-    /*let heap = Cheney::new(
-        &ir_program.struct_types,
-        &struct_field_byte_offsets,
-        &struct_sizes,
-        memidx,
-        MEM_STACK_SIZE + globals_num_pages,
-        MEM_STACK_SIZE + globals_num_pages + Cheney::initial_heap_size(),
-        error_func,
-        &mut wasm_module,
-    );*/
+    match options.heap_manager {
+        HeapManagerKind::Cheney => {
+            let heap = Cheney::new(
+                &ir_program.struct_types,
+                &struct_field_byte_offsets,
+                &struct_sizes,
+                memidx,
+                globalidx_stackptr,
+                options.stack_size,
+                heap_begin,
+                heap_initial_end,
+                global_var_manager.deref(),
+                error_func,
+                options.heap_verifier,
+                &mut wasm_module,
+            );
+            encode_program_with_heap(
+                ir_program,
+                &heap,
+                &signature_list,
+                &struct_field_byte_offsets,
+                imported_funcs,
+                global_var_manager.deref(),
+                globalidx_stackptr,
+                memidx,
+                thunk_sv,
+                appl_data_encoder,
+                &shifted_string_pool,
+                error_func,
+                options,
+                &mut wasm_module,
+            );
+        }
+        HeapManagerKind::Leaky => {
+            let heap = Leaky::new(
+                &ir_program.struct_types,
+                &struct_field_byte_offsets,
+                &struct_sizes,
+                memidx,
+                heap_begin,
+                heap_initial_end,
+                error_func,
+                &mut wasm_module,
+            );
+            encode_program_with_heap(
+                ir_program,
+                &heap,
+                &signature_list,
+                &struct_field_byte_offsets,
+                imported_funcs,
+                global_var_manager.deref(),
+                globalidx_stackptr,
+                memidx,
+                thunk_sv,
+                appl_data_encoder,
+                &shifted_string_pool,
+                error_func,
+                options,
+                &mut wasm_module,
+            );
+        }
+        HeapManagerKind::Generational => {
+            let heap = Generational::new(
+                &ir_program.struct_types,
+                &struct_field_byte_offsets,
+                &struct_sizes,
+                memidx,
+                globalidx_stackptr,
+                options.stack_size,
+                heap_begin,
+                heap_initial_end,
+                global_var_manager.deref(),
+                error_func,
+                &mut wasm_module,
+            );
+            encode_program_with_heap(
+                ir_program,
+                &heap,
+                &signature_list,
+                &struct_field_byte_offsets,
+                imported_funcs,
+                global_var_manager.deref(),
+                globalidx_stackptr,
+                memidx,
+                thunk_sv,
+                appl_data_encoder,
+                &shifted_string_pool,
+                error_func,
+                options,
+                &mut wasm_module,
+            );
+        }
+        HeapManagerKind::MarkSweep => {
+            let heap = MarkSweep::new(
+                &ir_program.struct_types,
+                &struct_field_byte_offsets,
+                &struct_sizes,
+                memidx,
+                globalidx_stackptr,
+                options.stack_size,
+                heap_begin,
+                heap_initial_end,
+                global_var_manager.deref(),
+                error_func,
+                &mut wasm_module,
+            );
+            encode_program_with_heap(
+                ir_program,
+                &heap,
+                &signature_list,
+                &struct_field_byte_offsets,
+                imported_funcs,
+                global_var_manager.deref(),
+                globalidx_stackptr,
+                memidx,
+                thunk_sv,
+                appl_data_encoder,
+                &shifted_string_pool,
+                error_func,
+                options,
+                &mut wasm_module,
+            );
+        }
+    }
+
+    wasm_module
+}
 
+// Encodes everything in the program that depends on the heap manager (i.e. the funcs and the heap allocation exports).
+fn encode_program_with_heap<H: HeapManager>(
+    ir_program: &ir::Program,
+    heap: &H,
+    signature_list: &[func::Signature],
+    struct_field_byte_offsets: &[Box<[u32]>],
+    imported_funcs: Box<[wasmgen::FuncIdx]>,
+    global_var_manager: global_var::GlobalVarManagerRef,
+    globalidx_stackptr: wasmgen::GlobalIdx,
+    memidx: wasmgen::MemIdx,
+    thunk_sv: SearchableVec<Box<[ir::OverloadEntry]>>,
+    appl_data_encoder: HashMap<ir::SourceLocation, u32>,
+    string_pool: &pre_traverse::ShiftedStringPool,
+    error_func: wasmgen::FuncIdx,
+    options: Options,
+    wasm_module: &mut wasmgen::WasmModule,
+) {
     // Encode a bridging function to allocate strings so that the host
     // can call it to allocate a returned string.
-    encode_heap_alloc_exports(&heap, &mut wasm_module);
+    encode_heap_alloc_exports(heap, wasm_module);
+
+    // Encode the function that fills in the gc stats struct, so that the host can read them.
+    encode_gc_stats_export(heap, options.stack_size << WASM_PAGE_BITS, wasm_module);
+
+    // find the funcs that can never allocate, so calling them doesn't need the gc prologue and epilogue
+    let may_allocate = ir::may_allocate::analyze_program(ir_program);
+
+    // find the closure environments that can be allocated on the stack instead of the heap
+    let escape = ir::escape::analyze_program(ir_program);
 
     func::encode_funcs(
-        &signature_list, // for checking types of params and results only
+        signature_list, // for checking types of params and results only
+        &may_allocate,
+        &escape,
         &ir_program.funcs,
         &ir_program.struct_types,
-        &struct_field_byte_offsets,
+        struct_field_byte_offsets,
         imported_funcs,
         ir_program.entry_point,
-        global_var_manager.deref(),
+        global_var_manager,
         globalidx_stackptr,
         memidx,
         thunk_sv,
         appl_data_encoder,
-        &heap,
-        &shifted_string_pool,
+        heap,
+        string_pool,
         error_func,
         options,
-        &mut wasm_module,
+        wasm_module,
     );
-
-    wasm_module
 }
 
 fn translate_import_params(ivts: &[ir::ImportValType]) -> Box<[ir::VarType]> {
@@ -320,8 +758,16 @@ fn encode_import_param(ivt: ir::ImportValType) -> &'static [wasmgen::ValType] {
 
 // encodes the linear memory
 // currently it will not reserve any space for global memory
-fn encode_mem(num_pages: u32, wasm_module: &mut wasmgen::WasmModule) -> wasmgen::MemIdx {
-    wasm_module.add_unbounded_memory(num_pages)
+// `max_num_pages` is None if the memory should be unbounded
+fn encode_mem(
+    num_pages: u32,
+    max_num_pages: Option<u32>,
+    wasm_module: &mut wasmgen::WasmModule,
+) -> wasmgen::MemIdx {
+    match max_num_pages {
+        Some(max_num_pages) => wasm_module.add_bounded_memory(num_pages, max_num_pages),
+        None => wasm_module.add_unbounded_memory(num_pages),
+    }
 }
 
 fn encode_static_data(
@@ -367,7 +813,324 @@ fn encode_heap_alloc_exports<H: HeapManager>(heap: &H, wasm_module: &mut wasmgen
     wasm_module.export_func(string_alloc_funcidx, "allocate_string".to_string());
 }
 
+/**
+ * Encodes the `gc_stats` function, which fills in the gc stats struct at `ptr` (see GC_STATS_SIZE) and returns `ptr`.
+ */
+fn encode_gc_stats_export<H: HeapManager>(
+    heap: &H,
+    ptr: u32,
+    wasm_module: &mut wasmgen::WasmModule,
+) {
+    // [] -> [i32(ptr)]
+    let wasm_functype = wasmgen::FuncType::new(Box::new([]), Box::new([wasmgen::ValType::I32]));
+    let (_, gc_stats_funcidx) = wasm_module.register_func(&wasm_functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(wasm_functype);
+    {
+        let (_locals_builder, expr_builder) = code_builder.split();
+
+        // the counters kept by the heap manager
+        heap.encode_write_stats(ptr, expr_builder);
+
+        expr_builder.i32_const(ptr as i32);
+        expr_builder.end();
+    }
+    wasm_module.commit_func(gc_stats_funcidx, code_builder);
+    wasm_module.export_func(gc_stats_funcidx, "gc_stats".to_string());
+}
+
+/**
+ * Encodes the `result_ptr` function, which returns `ptr`, the address where `main` puts its result (an Any).
+ */
+fn encode_result_ptr_export(ptr: u32, wasm_module: &mut wasmgen::WasmModule) {
+    // [] -> [i32(ptr)]
+    let wasm_functype = wasmgen::FuncType::new(Box::new([]), Box::new([wasmgen::ValType::I32]));
+    let (_, result_ptr_funcidx) = wasm_module.register_func(&wasm_functype);
+    let mut code_builder = wasmgen::CodeBuilder::new(wasm_functype);
+    {
+        let (_locals_builder, expr_builder) = code_builder.split();
+        expr_builder.i32_const(ptr as i32);
+        expr_builder.end();
+    }
+    wasm_module.commit_func(result_ptr_funcidx, code_builder);
+    wasm_module.export_func(result_ptr_funcidx, "result_ptr".to_string());
+}
+
 #[cfg(feature = "wasmtest")]
 pub fn wasmtest<C: wasm_test_harness::TestContext>(c: &mut C) {
     gc::cheney::wasmtest::wasmtest(c);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::test_runner::*;
+    use super::*;
+
+    #[test]
+    fn entry_result_after_devirtualising() {
+        // function g() { return 1; } function f() { return g() * 100; } f();
+        let text = r#"
+            global#0 = any;
+            func#0 () -> any {
+              (number:number 1.0)
+            }
+            func#1 () -> any {
+              (seq:any
+                (assign:undefined global#0 (func:func [func#0] (undefined:undefined)))
+                (typecast:any number narrow
+                  (appl:any 0:1:1-1:2
+                    (typecast:func func narrow (var:any global#0) (var:func local#0) (trap:! 0x16 0:1:1-1:2)))
+                  (prim:number number_mul (var:number local#0) (number:number 100.0))
+                  (trap:! 0x13 0:1:1-1:2)))
+            }
+            entry func#1;
+        "#;
+        assert_eq!(
+            run_ir_all_levels(text, Options::default()),
+            Ok(Value::Number(100.0))
+        );
+    }
+
+    #[test]
+    fn entry_result_after_inlining_closure() {
+        // const x = 1; const g = () => x; g() * 100;
+        let text = r#"
+            struct#0 = (any);
+            func#0 (struct#0) -> any {
+              (var:any local#0.0:0)
+            }
+            func#1 () -> any {
+              (decl:any struct#0 (struct:struct#0 struct#0)
+                (seq:any
+                  (assign:undefined local#0.0:0 (number:number 1.0))
+                  (typecast:any number narrow
+                    (appl:any 0:1:1-1:2 (func:func [func#0:closure] (var:struct#0 local#0)))
+                    (prim:number number_mul (var:number local#1) (number:number 100.0))
+                    (trap:! 0x13 0:1:1-1:2))))
+            }
+            entry func#1;
+        "#;
+        assert_eq!(
+            run_ir_all_levels(text, Options::default()),
+            Ok(Value::Number(100.0))
+        );
+    }
+
+    // function sum(n) { return n === 0 ? 0 : n + sum(n - 1); } sum(100);
+    const RECURSIVE_SUM: &str = r#"
+        global#0 = any;
+        func#0 (any) -> any {
+          (typecast:any number narrow (var:any local#0)
+            (if:any (prim:boolean number_eq (var:number local#1) (number:number 0.0))
+              (number:number 0.0)
+              (typecast:any number narrow
+                (appl:any 0:1:1-1:2
+                  (typecast:func func narrow (var:any global#0) (var:func local#2) (trap:! 0x16 0:1:1-1:2))
+                  (prim:number number_sub (var:number local#1) (number:number 1.0)))
+                (prim:number number_add (var:number local#1) (var:number local#2))
+                (trap:! 0x13 0:1:1-1:2)))
+            (trap:! 0x11 0:1:1-1:2))
+        }
+        func#1 () -> any {
+          (seq:any
+            (assign:undefined global#0 (func:func [func#0] (undefined:undefined)))
+            (appl:any 0:2:1-2:2
+              (typecast:func func narrow (var:any global#0) (var:func local#0) (trap:! 0x16 0:2:1-2:2))
+              (number:number 100.0)))
+        }
+        entry func#1;
+    "#;
+
+    #[test]
+    fn entry_result_of_recursive_func() {
+        assert_eq!(
+            run_ir_all_levels(RECURSIVE_SUM, Options::default()),
+            Ok(Value::Number(5050.0))
+        );
+    }
+
+    #[test]
+    fn entry_result_with_non_default_stack_size() {
+        let options = Options::new_builder().with_stack_pages(3).build().unwrap();
+        assert_eq!(
+            run_ir_all_levels(RECURSIVE_SUM, options),
+            Ok(Value::Number(5050.0))
+        );
+    }
+
+    #[test]
+    fn entry_result_with_multi_value() {
+        let options = Options::new_builder()
+            .with_wasm_multi_value(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            run_ir_all_levels(RECURSIVE_SUM, options),
+            Ok(Value::Number(5050.0))
+        );
+        let options = Options::new_builder()
+            .with_wasm_multi_value(true)
+            .with_wasm_tail_call(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            run_ir_all_levels(RECURSIVE_SUM, options),
+            Ok(Value::Number(5050.0))
+        );
+    }
+
+    #[test]
+    fn options_must_fit_in_memory() {
+        assert_eq!(
+            Options::new_builder().with_stack_pages(1 << 16).build(),
+            Err(OptionsError::MemoryTooLarge)
+        );
+        assert_eq!(
+            Options::new_builder()
+                .with_heap_initial_pages((1 << 16) - MEM_STACK_SIZE)
+                .build(),
+            Err(OptionsError::MemoryTooLarge)
+        );
+        assert_eq!(
+            Options::new_builder()
+                .with_stack_pages(u32::MAX)
+                .with_heap_initial_pages(u32::MAX)
+                .build(),
+            Err(OptionsError::MemoryTooLarge)
+        );
+        assert!(Options::new_builder()
+            .with_stack_pages((1 << 15) - 1)
+            .build()
+            .is_ok());
+    }
+
+    // function f(s, n) { return n === 0 ? s : f(s + "abcd", n - 1); } f("", 1000);
+    const STRING_CONCAT_LOOP: &str = r#"
+        func#0 (string number) -> string {
+          (if:string (prim:boolean number_eq (var:number local#1) (number:number 0.0))
+            (var:string local#0)
+            (call:string func#0
+              (prim:string string_add (var:string local#0) (string:string "abcd"))
+              (prim:number number_sub (var:number local#1) (number:number 1.0))))
+        }
+        func#1 () -> any {
+          (call:string func#0 (string:string "") (number:number 1000.0))
+        }
+        entry func#1;
+    "#;
+
+    #[test]
+    fn gc_stats_count_tags_with_every_heap_manager() {
+        let run = |heap_manager: HeapManagerKind| {
+            let options = Options::new_builder()
+                .with_heap_manager(heap_manager)
+                .build()
+                .unwrap();
+            run_ir_with_stats(STRING_CONCAT_LOOP, "-O2", options)
+        };
+        let (result, leaky_stats) = run(HeapManagerKind::Leaky);
+        assert_eq!(result, Ok(Value::String("abcd".repeat(1000))));
+        assert_eq!(leaky_stats.collections, 0);
+        let (result, cheney_stats) = run(HeapManagerKind::Cheney);
+        assert_eq!(result, Ok(Value::String("abcd".repeat(1000))));
+        assert!(cheney_stats.collections > 0);
+        assert_eq!(leaky_stats.bytes_allocated, cheney_stats.bytes_allocated);
+    }
+
+    #[test]
+    fn gc_stats_peak_heap_pages() {
+        // Leaky never frees anything, so the heap has to grow
+        let options = Options::new_builder()
+            .with_heap_manager(HeapManagerKind::Leaky)
+            .build()
+            .unwrap();
+        let (_, stats) = run_ir_with_stats(STRING_CONCAT_LOOP, "-O2", options);
+        assert!(stats.peak_heap_pages > options.heap_initial_size);
+    }
+
+    #[test]
+    fn field_read_before_assignment() {
+        // x is captured by a closure (so it is in a struct), and read before it is assigned: const y = x; x = 5; y + 1;
+        let text = r#"
+            struct#0 = (any);
+            global#0 = any;
+            func#0 () -> any {
+              (decl:any struct#0 (struct:struct#0 struct#0)
+                (seq:any
+                  (assign:undefined global#0 (var:struct#0 local#0))
+                  (decl:any any (var:any local#0.0:0)
+                    (seq:any
+                      (assign:undefined local#0.0:0 (number:number 5.0))
+                      (typecast:any number narrow (var:any local#1)
+                        (prim:number number_add (var:number local#2) (number:number 1.0))
+                        (trap:! 0x13 0:1:2-1:3))))))
+            }
+            entry func#0;
+        "#;
+        assert_eq!(
+            run_ir_all_levels(text, Options::default()),
+            Err(Failure::Error(
+                ir::error::ERROR_CODE_BINARY_OPERATOR_PARAM_TYPE
+            ))
+        );
+    }
+
+    #[test]
+    fn overloads_matched_from_back_to_front() {
+        // null === null, where the args are only known to be Any, and (any any) is the fallback overload of ===
+        let text = r#"
+            func#0 (any any) -> boolean {
+              (boolean:boolean false)
+            }
+            func#1 (null null) -> boolean {
+              (boolean:boolean true)
+            }
+            func#2 (any) -> any {
+              (var:any local#0)
+            }
+            func#3 () -> any {
+              (appl:any 0:1:1-1:2
+                (func:func [func#0 func#1] (undefined:undefined))
+                (call:any func#2 (null:null))
+                (call:any func#2 (null:null)))
+            }
+            entry func#3;
+        "#;
+        assert_eq!(
+            run_ir_all_levels(text, Options::default()),
+            Ok(Value::Boolean(true))
+        );
+    }
+
+    #[test]
+    fn read_after_failed_overload_check() {
+        // x === null ? "null" : x, where x is only known to be Any (since it is either a string or a number)
+        // the check for the (null null) overload falls through to the (any any) overload, so x is not known to be null afterwards
+        let text = r#"
+            func#0 (any any) -> boolean {
+              (boolean:boolean false)
+            }
+            func#1 (null null) -> boolean {
+              (boolean:boolean true)
+            }
+            func#2 (any) -> any {
+              (if:any
+                (typecast:boolean boolean narrow
+                  (appl:any 0:1:1-1:2 (func:func [func#0 func#1] (undefined:undefined)) (var:any local#0) (null:null))
+                  (var:boolean local#1)
+                  (trap:! 0x14 0:1:1-1:2))
+                (string:string "null")
+                (var:any local#0))
+            }
+            func#3 () -> any {
+              (seq:any
+                (call:any func#2 (string:string "s"))
+                (call:any func#2 (number:number 5.0)))
+            }
+            entry func#3;
+        "#;
+        assert_eq!(
+            run_ir_all_levels(text, Options::default()),
+            Ok(Value::Number(5.0))
+        );
+    }
+}
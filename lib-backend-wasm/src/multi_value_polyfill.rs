@@ -3,6 +3,12 @@ use wasmgen::ExprBuilder;
 use wasmgen::Scratch;
 use wasmgen::ValType;
 
+// The valtypes given to the functions here start from the top of the wasm stack (like encode_vartype()),
+// but the result types of a wasm block start from the bottom of the stack.
+fn blocktype(valtypes: &[ValType]) -> Box<[ValType]> {
+    valtypes.iter().rev().copied().collect()
+}
+
 // Encodes an if-stmt (with 'else' part), abstracting over the issues relating to lack of multi-value support by spawning new locals if necessary
 // net wasm stack [i32 cond] -> [valtypes...]
 pub fn if_<
@@ -18,7 +24,7 @@ pub fn if_<
 ) {
     if use_multi_value || valtypes.len() <= 1 {
         // net wasm stack [i32 cond] -> [valtypes...]
-        expr_builder.if_(valtypes);
+        expr_builder.if_(&blocktype(valtypes));
         {
             // net wasm stack [] -> [valtypes...]
             true_encoder(mutctx, expr_builder);
@@ -71,7 +77,7 @@ pub fn if_without_else<F: FnOnce(&mut MutContext, &mut ExprBuilder)>(
 ) {
     if use_multi_value || valtypes.len() <= 1 {
         // net wasm stack [i32 cond] -> [valtypes...]
-        expr_builder.if_(valtypes);
+        expr_builder.if_(&blocktype(valtypes));
         {
             // net wasm stack [] -> [valtypes...]
             true_encoder(mutctx, expr_builder);
@@ -140,7 +146,7 @@ pub fn block<F: FnOnce(&mut MutContext, &[wasmgen::LocalIdx], &mut ExprBuilder)>
 ) {
     if use_multi_value || valtypes.len() <= 1 {
         // net wasm stack [i32 cond] -> [valtypes...]
-        expr_builder.block(valtypes);
+        expr_builder.block(&blocktype(valtypes));
         {
             // net wasm stack [] -> [valtypes...]
             inner_encoder(mutctx, &[], expr_builder);
@@ -170,6 +176,48 @@ pub fn block<F: FnOnce(&mut MutContext, &[wasmgen::LocalIdx], &mut ExprBuilder)>
     }
 }
 
+// Encodes a loop, abstracting over the issues relating to lack of multi-value support by spawning new locals if necessary
+// The valtypes are the result of the loop (when it falls through the end); branches to the loop do not take any values.
+// net wasm stack [] -> [valtypes...]
+pub fn loop_<F: FnOnce(&mut MutContext, &mut ExprBuilder)>(
+    valtypes: &[ValType],
+    use_multi_value: bool,
+    mutctx: &mut MutContext,
+    expr_builder: &mut ExprBuilder,
+    inner_encoder: F,
+) {
+    if use_multi_value || valtypes.len() <= 1 {
+        // net wasm stack [] -> [valtypes...]
+        expr_builder.loop_(&blocktype(valtypes));
+        {
+            // net wasm stack [] -> [valtypes...]
+            inner_encoder(mutctx, expr_builder);
+        }
+        expr_builder.end();
+    } else {
+        // we don't have multi-value enabled, but we have more than one value.  The last value (deepest in the stack) is left onto the stack, but everything else goes into locals.
+        let (rest, last) = valtypes.split_at(valtypes.len() - 1);
+
+        // make temporary variables for them
+        mutctx.with_scratches(rest, |mutctx, tmp_locals| {
+            expr_builder.loop_(last);
+            {
+                // net wasm stack [] -> [valtypes...]
+                inner_encoder(mutctx, expr_builder);
+                // net wasm stack [valtypes...] -> [last]
+                tmp_locals.iter().copied().for_each(|localidx| {
+                    expr_builder.local_set(localidx);
+                });
+            }
+            expr_builder.end();
+            // net wasm stack [last] -> [valtypes...]
+            tmp_locals.iter().copied().rev().for_each(|localidx| {
+                expr_builder.local_get(localidx);
+            });
+        });
+    }
+}
+
 pub fn break_(
     landing_idx: usize,
     landing_ctx: &[wasmgen::LocalIdx],
@@ -413,6 +413,10 @@ impl<'a, 'b> MutContext<'a, 'b> {
             expr_builder,
         );
     }
+    /**
+     * Saves the locals to the gc roots stack, runs the callback (which usually calls a function that might allocate memory), and restores the locals.
+     * If `live_named_locals` is given, named locals that are not in it will not be saved (shadow locals are always saved).
+     */
     pub fn heap_encode_prologue_epilogue<
         H: HeapManager,
         R,
@@ -420,13 +424,37 @@ impl<'a, 'b> MutContext<'a, 'b> {
     >(
         &mut self,
         heap: &H,
+        live_named_locals: Option<&ir::liveness::LiveSet>,
         expr_builder: &mut ExprBuilder,
         f: F,
     ) -> R {
+        // split off the named locals that are dead after the callback
+        let mut is_root = vec![true; self.local_types.len()];
+        if let Some(live_named_locals) = live_named_locals {
+            for (named_idx, idx) in self.named_local_map.iter().copied().enumerate() {
+                if !live_named_locals.contains(named_idx) {
+                    is_root[idx] = false;
+                }
+            }
+        }
+        let mut root_types: Vec<ir::VarType> = Vec::new();
+        let mut root_map: Vec<usize> = Vec::new();
+        let mut dead_types: Vec<ir::VarType> = Vec::new();
+        let mut dead_map: Vec<usize> = Vec::new();
+        for (idx, is_root) in is_root.into_iter().enumerate() {
+            if is_root {
+                root_types.push(self.local_types[idx]);
+                root_map.push(self.local_map[idx]);
+            } else {
+                dead_types.push(self.local_types[idx]);
+                dead_map.push(self.local_map[idx]);
+            }
+        }
+
         // encode local roots prologue
         heap.encode_local_roots_prologue(
-            &self.local_types,
-            &self.local_map,
+            &root_types,
+            &root_map,
             &self.wasm_local_map,
             &mut self.scratch,
             expr_builder,
@@ -437,8 +465,18 @@ impl<'a, 'b> MutContext<'a, 'b> {
 
         // encode local roots prologue
         heap.encode_local_roots_epilogue(
-            &self.local_types,
-            &self.local_map,
+            &root_types,
+            &root_map,
+            &self.wasm_local_map,
+            &mut self.scratch,
+            expr_builder,
+        );
+
+        // the dead locals might now contain dangling pointers (if the gc moved things), so reset them
+        // (they will be overwritten before being read, but they might still be saved to the gc roots stack before that)
+        heap.encode_local_roots_init(
+            &dead_types,
+            &dead_map,
             &self.wasm_local_map,
             &mut self.scratch,
             expr_builder,
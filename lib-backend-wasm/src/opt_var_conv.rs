@@ -11,7 +11,8 @@ pub fn encode_opt_vartype(ir_opt_vartype: Option<ir::VarType>) -> &'static [wasm
 // Used when widening the result of an expression to fit the argument of the next one.
 // `wasm_reachable`: whether WebAssembly thinks that the variable is reachable (this is not necessarily the same as whether the IR Expr has unreachable vartype).
 // Note: If source_type is None (i.e. unreachable), then the resultant expression will be unreachable to WebAssembly.  No widening operation will be encoded (except perhaps a single `unreachable` instruction).
-// After this function is run, WebAssembly's notion of unreachability will be equivalent to whether source_type is None.
+// Note: If the expression was a tail call, then WebAssembly knows that it is unreachable even though source_type is not None.  Nothing will be encoded in this case.
+// After this function is run, WebAssembly's notion of unreachability will be equivalent to whether source_type is None (or the expression was a tail call).
 pub fn encode_opt_result_widening_operation(
     target_type: Option<ir::VarType>,
     source_type: Option<ir::VarType>,
@@ -21,10 +22,10 @@ pub fn encode_opt_result_widening_operation(
 ) {
     if let Some(actual_source_type) = source_type {
         // We are not unreachable... so we should encode the widening operation
-        assert!(
-            wasm_reachable,
-            "WebAssembly must think that we are reachable if we are actaully reachable"
-        );
+        // (unless we have already returned from the function by making a tail call)
+        if !wasm_reachable {
+            return;
+        }
         if let Some(actual_target_type) = target_type {
             encode_widening_operation(
                 actual_target_type,
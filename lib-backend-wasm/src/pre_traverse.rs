@@ -60,6 +60,7 @@ fn pre_traverse_expr(expr: &ir::Expr, res: &mut TraverseResult) {
 fn pre_traverse_expr_kind(expr_kind: &ir::ExprKind, res: &mut TraverseResult) {
     match expr_kind {
         ir::ExprKind::PrimUndefined
+        | ir::ExprKind::PrimNull
         | ir::ExprKind::PrimNumber { val: _ }
         | ir::ExprKind::PrimBoolean { val: _ }
         | ir::ExprKind::PrimStructT { typeidx: _ } => {}
@@ -116,7 +117,8 @@ fn pre_traverse_expr_kind(expr_kind: &ir::ExprKind, res: &mut TraverseResult) {
             num_frames: _,
             expr,
         }
-        | ir::ExprKind::Block { expr } => pre_traverse_expr(expr, res),
+        | ir::ExprKind::Block { expr }
+        | ir::ExprKind::Loop { expr } => pre_traverse_expr(expr, res),
         ir::ExprKind::Sequence { content } => {
             pre_traverse_exprs(content, res);
         }
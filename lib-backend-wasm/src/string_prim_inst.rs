@@ -659,8 +659,11 @@ pub fn encode_string_add<H: HeapManager>(
     return string_new;
     */
 
-    mutctx.with_scratch_i32(|mutctx, string_1| {
-        mutctx.with_scratch_i32(|mutctx, string_2| {
+    // string_1 and string_2 are in shadow locals, because new_string() might move them
+    mutctx.with_uninitialized_shadow_local(VarType::String, |mutctx, string_1_local| {
+        mutctx.with_uninitialized_shadow_local(VarType::String, |mutctx, string_2_local| {
+            let string_1: LocalIdx = mutctx.wasm_local_slice(string_1_local)[0];
+            let string_2: LocalIdx = mutctx.wasm_local_slice(string_2_local)[0];
             mutctx.with_scratch_i32(|mutctx, len_1| {
                 mutctx.with_scratch_i32(|mutctx, len_2| {
                     mutctx.with_scratch_i32(|mutctx, string_new| {
@@ -0,0 +1,142 @@
+/**
+ * Contains the trampoline used to make proper tail calls when the WebAssembly tail call proposal is not available.
+ *
+ * An indirect call in tail position does not call the thunk directly.
+ * Instead, it stores everything needed for the call_indirect in globals, sets `pending`, and returns immediately
+ * (with dummy return values if the return value is on the wasm stack).
+ * Every call site of a function that returns Any then checks `pending` after the call returns,
+ * and if it is set, makes the pending call from the trampoline loop, repeating until no call is pending.
+ * Hence a chain of tail calls runs in constant stack space.
+ *
+ * Note: Nothing may allocate between setting the globals and making the pending call,
+ * because the GC does not know that the closure stored in the global is a root.
+ */
+
+#[derive(Copy, Clone)]
+pub struct Trampoline {
+    pending: wasmgen::GlobalIdx, // i32: 1 if a tail call is waiting to be made, 0 otherwise
+    closure: wasmgen::GlobalIdx, // i32: the closure to pass to the thunk
+    num_args: wasmgen::GlobalIdx, // i32: the number of args on the unprotected stack
+    callerid: wasmgen::GlobalIdx, // i32: the caller id (location of the SourceLocation of the Appl)
+    tableidx: wasmgen::GlobalIdx, // i32: the thunk to call
+    funcidx: wasmgen::FuncIdx,   // the trampoline function
+    results: &'static [wasmgen::ValType], // the wasm results of a thunk (i.e. `encode_result(Some(Any))`)
+}
+
+impl Trampoline {
+    // Adds the globals and encodes the trampoline function.
+    // `thunk_typeidx` is the wasm type of all thunks, and `results` are the wasm results of that type.
+    // The trampoline has type [results...] -> [results...]; it returns its params unchanged if there is no pending call.
+    pub fn new(
+        thunk_typeidx: wasmgen::TypeIdx,
+        results: &'static [wasmgen::ValType],
+        wasm_module: &mut wasmgen::WasmModule,
+    ) -> Self {
+        let pending = wasm_module.add_i32_global(wasmgen::Mut::Var, 0);
+        let closure = wasm_module.add_i32_global(wasmgen::Mut::Var, 0);
+        let num_args = wasm_module.add_i32_global(wasmgen::Mut::Var, 0);
+        let callerid = wasm_module.add_i32_global(wasmgen::Mut::Var, 0);
+        let tableidx = wasm_module.add_i32_global(wasmgen::Mut::Var, 0);
+
+        let functype = wasmgen::FuncType::new(results.into(), results.into());
+        let (_type_idx, funcidx) = wasm_module.register_func(&functype);
+        let mut code_builder = wasmgen::CodeBuilder::new(functype);
+        {
+            let (_locals_builder, expr_builder) = code_builder.split();
+
+            // Algorithm
+            /*
+            if (pending) {
+                do {
+                    pending = 0;
+                    results = call_indirect(closure, num_args, callerid, tableidx);
+                } while (pending);
+            }
+            return results;
+            */
+            expr_builder.global_get(pending);
+            expr_builder.if_(&[]);
+            {
+                expr_builder.loop_(&[]);
+                {
+                    expr_builder.i32_const(0);
+                    expr_builder.global_set(pending);
+
+                    // net wasm stack: [] -> [results...]
+                    expr_builder.global_get(closure);
+                    expr_builder.global_get(num_args);
+                    expr_builder.global_get(callerid);
+                    expr_builder.global_get(tableidx);
+                    expr_builder.call_indirect(thunk_typeidx, wasmgen::TableIdx { idx: 0 });
+
+                    // save the results in the params (the params are not needed any more)
+                    // net wasm stack: [results...] -> []
+                    for i in (0..results.len()).rev() {
+                        expr_builder.local_set(wasmgen::LocalIdx { idx: i as u32 });
+                    }
+
+                    expr_builder.global_get(pending);
+                    expr_builder.br_if(0);
+                }
+                expr_builder.end();
+            }
+            expr_builder.end();
+
+            // net wasm stack: [] -> [results...]
+            for i in 0..results.len() {
+                expr_builder.local_get(wasmgen::LocalIdx { idx: i as u32 });
+            }
+
+            expr_builder.end();
+        }
+        wasm_module.commit_func(funcidx, code_builder);
+
+        Trampoline {
+            pending,
+            closure,
+            num_args,
+            callerid,
+            tableidx,
+            funcidx,
+            results,
+        }
+    }
+
+    // Makes a tail call by handing it over to the trampoline of the nearest caller, and returns from the current function.
+    // The current function must return Any.
+    // net wasm stack: [i32(closure), i32(num_args), i32(callerid), i32(tableidx)] -> [stack-polymorphic]
+    pub fn encode_tail_call(&self, expr_builder: &mut wasmgen::ExprBuilder) {
+        expr_builder.global_set(self.tableidx);
+        expr_builder.global_set(self.callerid);
+        expr_builder.global_set(self.num_args);
+        expr_builder.global_set(self.closure);
+        expr_builder.i32_const(1);
+        expr_builder.global_set(self.pending);
+
+        // the return values will be ignored by the trampoline
+        for valtype in self.results {
+            match *valtype {
+                wasmgen::ValType::I32 => expr_builder.i32_const(0),
+                wasmgen::ValType::I64 => expr_builder.i64_const(0),
+                wasmgen::ValType::F32 => expr_builder.f32_const(0.0),
+                wasmgen::ValType::F64 => expr_builder.f64_const(0.0),
+            };
+        }
+        expr_builder.return_();
+    }
+
+    // Runs the pending tail calls (if any) after calling a function that returns Any.
+    // This should be encoded before the GC epilogue of the call.
+    // net wasm stack: [return_calling_conv(Any)] -> [return_calling_conv(Any)]
+    pub fn encode_post_call(&self, expr_builder: &mut wasmgen::ExprBuilder) {
+        if self.results.is_empty() {
+            // the return value is on the unprotected stack, so we can avoid the function call if nothing is pending
+            expr_builder.global_get(self.pending);
+            expr_builder.if_(&[]);
+            expr_builder.call(self.funcidx);
+            expr_builder.end();
+        } else {
+            expr_builder.call(self.funcidx);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+/**
+ * Runs compiled programs in an embedded wasm interpreter (wasmi), for the tests of this crate.
+ * The result is read from linear memory in the same way as the host code (sourceror-driver) does,
+ * so the tests check what a user of the compiler would actually see.
+ * Programs may not have any imports other than those generated by the backend.
+ */
+use super::*;
+use std::convert::TryInto;
+use wasmgen::WasmSerialize;
+
+/**
+ * The value that the host reads after the entry point returns.
+ */
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Unassigned,
+    Undefined,
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Func,
+    Null,
+    Array,
+    StructT(u32), // the tag
+}
+
+/**
+ * The outcome of a program that did not run to completion.
+ */
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    Error(u32),   // the code that was passed to the `error` import (see ir::error)
+    Trap(String), // a wasm trap, e.g. stack overflow
+}
+
+/**
+ * The gc stats struct (see GC_STATS_SIZE), as the host reads it after the program has run.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub collections: u32,
+    pub peak_heap_pages: u32,
+    pub bytes_allocated: u64,
+    pub bytes_surviving: u64,
+}
+
+/**
+ * Compiles the program (after running the given optimisation pipeline, e.g. "-O2", see ir::opt::PassManager)
+ * and runs its entry point.
+ */
+pub fn run_ir(text: &str, pipeline: &str, options: Options) -> Result<Value, Failure> {
+    run_ir_with_stats(text, pipeline, options).0
+}
+
+/**
+ * Like `run_ir`, but also returns the gc stats.
+ */
+pub fn run_ir_with_stats(
+    text: &str,
+    pipeline: &str,
+    options: Options,
+) -> (Result<Value, Failure>, Stats) {
+    let program = ir::text::parse_program(text).unwrap();
+    let program = ir::opt::PassManager::from_name(pipeline)
+        .unwrap()
+        .run(program);
+    run_program_with_stats(&program, options)
+}
+
+/**
+ * Like `run_ir`, but checks that the result is the same with all the optimisation levels, and returns it.
+ */
+pub fn run_ir_all_levels(text: &str, options: Options) -> Result<Value, Failure> {
+    let result = run_ir(text, "-O0", options);
+    for pipeline in &["-O1", "-O2"] {
+        assert_eq!(run_ir(text, pipeline, options), result, "at {}", pipeline);
+    }
+    result
+}
+
+/**
+ * Compiles the program and runs its entry point.
+ */
+pub fn run_program(program: &ir::Program, options: Options) -> Result<Value, Failure> {
+    run_program_with_stats(program, options).0
+}
+
+/**
+ * Like `run_program`, but also returns the gc stats.
+ */
+pub fn run_program_with_stats(
+    program: &ir::Program,
+    options: Options,
+) -> (Result<Value, Failure>, Stats) {
+    let mut bytes: Vec<u8> = Vec::new();
+    run_backend(program, options).wasm_serialize(&mut bytes);
+
+    let mut config = wasmi::Config::default();
+    config.wasm_tail_call(true);
+    config.wasm_multi_value(options.wasm_multi_value);
+    let engine = wasmi::Engine::new(&config);
+    let module = wasmi::Module::new(&engine, &bytes[..]).unwrap();
+    let mut store = wasmi::Store::new(&engine, None);
+    let mut linker = <wasmi::Linker<Option<u32>>>::new(&engine);
+    linker
+        .func_wrap(
+            "core",
+            "error",
+            |mut caller: wasmi::Caller<'_, Option<u32>>,
+             code: u32,
+             _detail: u32,
+             _file: u32,
+             _start_line: u32,
+             _start_column: u32,
+             _end_line: u32,
+             _end_column: u32|
+             -> Result<(), wasmi::Error> {
+                *caller.data_mut() = Some(code);
+                Err(wasmi::Error::new("runtime error"))
+            },
+        )
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+    let main = instance.get_typed_func::<(), ()>(&store, "main").unwrap();
+    let outcome = main.call(&mut store, ());
+
+    // the host may read the gc stats even if the program failed
+    let stats_addr = instance
+        .get_typed_func::<(), u32>(&store, "gc_stats")
+        .unwrap()
+        .call(&mut store, ())
+        .unwrap() as usize;
+    let result_addr = instance
+        .get_typed_func::<(), u32>(&store, "result_ptr")
+        .unwrap()
+        .call(&mut store, ())
+        .unwrap() as usize;
+    let mem = instance
+        .get_memory(&store, "linear_memory")
+        .unwrap()
+        .data(&store);
+    let read_u32 = |addr: usize| u32::from_le_bytes(mem[addr..addr + 4].try_into().unwrap());
+    let read_u64 = |addr: usize| u64::from_le_bytes(mem[addr..addr + 8].try_into().unwrap());
+    let stats = Stats {
+        collections: read_u32(stats_addr),
+        peak_heap_pages: read_u32(stats_addr + 4),
+        bytes_allocated: read_u64(stats_addr + 8),
+        bytes_surviving: read_u64(stats_addr + 16),
+    };
+    if let Err(err) = outcome {
+        let failure = match store.data() {
+            Some(code) => Failure::Error(*code),
+            None => Failure::Trap(err.to_string()),
+        };
+        return (Err(failure), stats);
+    }
+
+    let data_addr = result_addr + 4;
+    let value = match read_u32(result_addr) {
+        0 => Value::Unassigned,
+        1 => Value::Undefined,
+        2 => Value::Number(f64::from_le_bytes(
+            mem[data_addr..data_addr + 8].try_into().unwrap(),
+        )),
+        3 => Value::Boolean(read_u32(data_addr) != 0),
+        4 => {
+            let ptr = read_u32(data_addr) as usize;
+            let len = read_u32(ptr) as usize;
+            Value::String(String::from_utf8(mem[ptr + 4..ptr + 4 + len].to_vec()).unwrap())
+        }
+        5 => Value::Func,
+        6 => Value::Null,
+        7 => Value::Array,
+        tag => Value::StructT(tag),
+    };
+    (Ok(value), stats)
+}
@@ -4,10 +4,10 @@ pub fn encode_vartype(ir_vartype: ir::VarType) -> &'static [wasmgen::ValType] {
     match ir_vartype {
         ir::VarType::Any => &[wasmgen::ValType::I32, wasmgen::ValType::I64],
         ir::VarType::Unassigned => panic!("ICE: IR->Wasm: Unassigned type may not be encoded"),
-        ir::VarType::Undefined => &[],
+        ir::VarType::Undefined | ir::VarType::Null => &[],
         ir::VarType::Number => &[wasmgen::ValType::F64],
         ir::VarType::Boolean => &[wasmgen::ValType::I32],
-        ir::VarType::String => &[wasmgen::ValType::I32],
+        ir::VarType::String | ir::VarType::Array => &[wasmgen::ValType::I32],
         ir::VarType::Func => &[wasmgen::ValType::I32, wasmgen::ValType::I32],
         ir::VarType::StructT { typeidx: _ } => &[wasmgen::ValType::I32],
     }
@@ -28,7 +28,10 @@ pub fn encode_store_local(
                 expr_builder.local_set(wasm_localidx[0]);
                 expr_builder.local_set(wasm_localidx[1]);
             }
-            ir::VarType::Number | ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Number
+            | ir::VarType::Boolean
+            | ir::VarType::String
+            | ir::VarType::Array => {
                 assert!(wasm_localidx.len() == 1);
                 expr_builder.local_set(wasm_localidx[0]);
             }
@@ -36,7 +39,7 @@ pub fn encode_store_local(
                 assert!(wasm_localidx.len() == 1);
                 expr_builder.local_set(wasm_localidx[0]);
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 assert!(wasm_localidx.len() == 0);
             }
             ir::VarType::Unassigned => {
@@ -50,7 +53,7 @@ pub fn encode_store_local(
             ir::VarType::Any => {
                 panic!("ICE");
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 expr_builder.i32_const(ir_source_vartype.tag());
                 expr_builder.local_set(wasm_localidx[0]);
             }
@@ -63,7 +66,7 @@ pub fn encode_store_local(
                 expr_builder.i64_reinterpret_f64(); // convert f64 to i64
                 expr_builder.local_set(wasm_localidx[1]);
             }
-            ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
                 expr_builder.i32_const(ir_source_vartype.tag());
                 expr_builder.local_set(wasm_localidx[0]);
                 expr_builder.i64_extend_i32_u(); // convert i32 to i64
@@ -110,7 +113,10 @@ pub fn encode_store_global(
                 expr_builder.global_set(wasm_globalidx[0]);
                 expr_builder.global_set(wasm_globalidx[1]);
             }
-            ir::VarType::Number | ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Number
+            | ir::VarType::Boolean
+            | ir::VarType::String
+            | ir::VarType::Array => {
                 assert!(wasm_globalidx.len() == 1);
                 expr_builder.global_set(wasm_globalidx[0]);
             }
@@ -118,7 +124,7 @@ pub fn encode_store_global(
                 assert!(wasm_globalidx.len() == 1);
                 expr_builder.global_set(wasm_globalidx[0]);
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 assert!(wasm_globalidx.len() == 0);
             }
             ir::VarType::Unassigned => {
@@ -132,7 +138,7 @@ pub fn encode_store_global(
             ir::VarType::Any => {
                 panic!("ICE");
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 expr_builder.i32_const(ir_source_vartype.tag());
                 expr_builder.global_set(wasm_globalidx[0]);
             }
@@ -145,7 +151,7 @@ pub fn encode_store_global(
                 expr_builder.i64_reinterpret_f64(); // convert f64 to i64
                 expr_builder.global_set(wasm_globalidx[1]);
             }
-            ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
                 expr_builder.i32_const(ir_source_vartype.tag());
                 expr_builder.global_set(wasm_globalidx[0]);
                 expr_builder.i64_extend_i32_u(); // convert i32 to i64
@@ -206,14 +212,14 @@ pub fn encode_store_memory(
             ir::VarType::Unassigned => {
                 panic!("ICE: IR->Wasm: Cannot assign from unassigned value");
             }
-            ir::VarType::Undefined => {}
+            ir::VarType::Undefined | ir::VarType::Null => {}
             ir::VarType::Number => {
                 expr_builder.f64_store(wasmgen::MemArg::new4(wasm_struct_offset));
             }
             ir::VarType::Boolean => {
                 expr_builder.i32_store(wasmgen::MemArg::new4(wasm_struct_offset));
             }
-            ir::VarType::String => {
+            ir::VarType::String | ir::VarType::Array => {
                 expr_builder.i32_store(wasmgen::MemArg::new4(wasm_struct_offset));
             }
             ir::VarType::Func => {
@@ -245,7 +251,7 @@ pub fn encode_store_memory(
             ir::VarType::Unassigned => {
                 panic!("ICE: IR->Wasm: Cannot assign from unassigned value");
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 expr_builder.i32_const(ir_source_vartype.tag());
                 expr_builder.i32_store(wasmgen::MemArg::new4(wasm_struct_offset));
             }
@@ -262,7 +268,7 @@ pub fn encode_store_memory(
                 scratch.pop_i32();
                 scratch.pop_f64();
             }
-            ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
                 let localidx_val: wasmgen::LocalIdx = scratch.push_i32();
                 let localidx_ptr: wasmgen::LocalIdx = scratch.push_i32();
                 expr_builder.local_set(localidx_val);
@@ -328,7 +334,10 @@ pub fn encode_load_local(
                 expr_builder.local_get(wasm_localidx[1]);
                 expr_builder.local_get(wasm_localidx[0]);
             }
-            ir::VarType::Number | ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Number
+            | ir::VarType::Boolean
+            | ir::VarType::String
+            | ir::VarType::Array => {
                 assert!(wasm_localidx.len() == 1);
                 expr_builder.local_get(wasm_localidx[0]);
             }
@@ -336,7 +345,7 @@ pub fn encode_load_local(
                 assert!(wasm_localidx.len() == 1);
                 expr_builder.local_get(wasm_localidx[0]);
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 assert!(wasm_localidx.len() == 0);
             }
             ir::VarType::Unassigned => {
@@ -350,7 +359,7 @@ pub fn encode_load_local(
             ir::VarType::Any => {
                 panic!("ICE");
             }
-            ir::VarType::Undefined => {}
+            ir::VarType::Undefined | ir::VarType::Null => {}
             ir::VarType::Unassigned => {
                 panic!("ICE: IR->Wasm: Cannot load from unassigned local");
             }
@@ -358,7 +367,7 @@ pub fn encode_load_local(
                 expr_builder.local_get(wasm_localidx[1]);
                 expr_builder.f64_reinterpret_i64(); // convert i64 to f64
             }
-            ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
                 expr_builder.local_get(wasm_localidx[1]);
                 expr_builder.i32_wrap_i64(); // convert i64 to i32
             }
@@ -398,7 +407,10 @@ pub fn encode_load_global(
                 expr_builder.global_get(wasm_globalidx[1]);
                 expr_builder.global_get(wasm_globalidx[0]);
             }
-            ir::VarType::Number | ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Number
+            | ir::VarType::Boolean
+            | ir::VarType::String
+            | ir::VarType::Array => {
                 assert!(wasm_globalidx.len() == 1);
                 expr_builder.global_get(wasm_globalidx[0]);
             }
@@ -406,7 +418,7 @@ pub fn encode_load_global(
                 assert!(wasm_globalidx.len() == 1);
                 expr_builder.global_get(wasm_globalidx[0]);
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 assert!(wasm_globalidx.len() == 0);
             }
             ir::VarType::Unassigned => {
@@ -420,7 +432,7 @@ pub fn encode_load_global(
             ir::VarType::Any => {
                 panic!("ICE");
             }
-            ir::VarType::Undefined => {}
+            ir::VarType::Undefined | ir::VarType::Null => {}
             ir::VarType::Unassigned => {
                 panic!("ICE: IR->Wasm: Cannot load from unassigned global");
             }
@@ -428,7 +440,7 @@ pub fn encode_load_global(
                 expr_builder.global_get(wasm_globalidx[1]);
                 expr_builder.f64_reinterpret_i64(); // convert i64 to f64
             }
-            ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
                 expr_builder.global_get(wasm_globalidx[1]);
                 expr_builder.i32_wrap_i64(); // convert i64 to i32
             }
@@ -470,7 +482,7 @@ pub fn encode_load_memory(
                 expr_builder.i32_load(wasmgen::MemArg::new4(wasm_struct_offset));
                 scratch.pop_i32();
             }
-            ir::VarType::Undefined => {}
+            ir::VarType::Undefined | ir::VarType::Null => {}
             ir::VarType::Unassigned => {
                 panic!("ICE: IR->Wasm: Cannot load from unassigned memory");
             }
@@ -480,7 +492,7 @@ pub fn encode_load_memory(
             ir::VarType::Boolean => {
                 expr_builder.i32_load(wasmgen::MemArg::new4(wasm_struct_offset));
             }
-            ir::VarType::String => {
+            ir::VarType::String | ir::VarType::Array => {
                 expr_builder.i32_load(wasmgen::MemArg::new4(wasm_struct_offset));
             }
             ir::VarType::Func => {
@@ -503,11 +515,11 @@ pub fn encode_load_memory(
             ir::VarType::Unassigned => {
                 panic!("ICE: IR->Wasm: Cannot load from unassigned memory");
             }
-            ir::VarType::Undefined => {}
+            ir::VarType::Undefined | ir::VarType::Null => {}
             ir::VarType::Number => {
                 expr_builder.f64_load(wasmgen::MemArg::new4(wasm_struct_offset + 4));
             }
-            ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
                 expr_builder.i32_load(wasmgen::MemArg::new4(wasm_struct_offset + 4));
                 // note: high bytes of memory not used
             }
@@ -544,7 +556,7 @@ pub fn encode_widening_operation(
             ir::VarType::Any => {
                 panic!("ICE");
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 expr_builder.i64_const(0); // unused data
                 expr_builder.i32_const(source_type.tag());
             }
@@ -555,7 +567,7 @@ pub fn encode_widening_operation(
                 expr_builder.i64_reinterpret_f64(); // convert f64 to i64
                 expr_builder.i32_const(source_type.tag());
             }
-            ir::VarType::Boolean | ir::VarType::String => {
+            ir::VarType::Boolean | ir::VarType::String | ir::VarType::Array => {
                 expr_builder.i64_extend_i32_u(); // convert i32 to i64
                 expr_builder.i32_const(source_type.tag());
             }
@@ -608,7 +620,7 @@ pub fn encode_narrowing_operation<F: FnOnce(&mut wasmgen::ExprBuilder)>(
             ir::VarType::Any => {
                 panic!("ICE");
             }
-            ir::VarType::Undefined => {
+            ir::VarType::Undefined | ir::VarType::Null => {
                 expr_builder.drop(); // i64(data) unused
             }
             ir::VarType::Unassigned => {
@@ -617,7 +629,10 @@ pub fn encode_narrowing_operation<F: FnOnce(&mut wasmgen::ExprBuilder)>(
             ir::VarType::Number => {
                 expr_builder.f64_reinterpret_i64(); // convert i64 to f64
             }
-            ir::VarType::Boolean | ir::VarType::String | ir::VarType::StructT { typeidx: _ } => {
+            ir::VarType::Boolean
+            | ir::VarType::String
+            | ir::VarType::Array
+            | ir::VarType::StructT { typeidx: _ } => {
                 expr_builder.i32_wrap_i64(); // convert i64 to i32
             }
             ir::VarType::Func => {
@@ -650,7 +665,7 @@ pub fn encode_unchecked_local_conv_any_narrowing(
         ir::VarType::Any => {
             panic!("ICE: IR->Wasm: Cannot TypeCast from Any to Any");
         }
-        ir::VarType::Undefined => {}
+        ir::VarType::Undefined | ir::VarType::Null => {}
         ir::VarType::Unassigned => {
             panic!("ICE: IR->Wasm: Cannot TypeCast from Any to Unassigned");
         }
@@ -660,7 +675,10 @@ pub fn encode_unchecked_local_conv_any_narrowing(
             expr_builder.f64_reinterpret_i64(); // convert i64 to f64
             expr_builder.local_set(wasm_dest_localidx[0]);
         }
-        ir::VarType::Boolean | ir::VarType::String | ir::VarType::StructT { typeidx: _ } => {
+        ir::VarType::Boolean
+        | ir::VarType::String
+        | ir::VarType::Array
+        | ir::VarType::StructT { typeidx: _ } => {
             assert!(wasm_dest_localidx.len() == 1);
             expr_builder.local_get(wasm_source_localidx);
             expr_builder.i32_wrap_i64(); // convert i64 to i32
@@ -684,10 +702,10 @@ pub fn size_in_memory(ir_vartype: ir::VarType) -> u32 {
     match ir_vartype {
         ir::VarType::Any => 4 + 8,
         ir::VarType::Unassigned => 0,
-        ir::VarType::Undefined => 0,
+        ir::VarType::Undefined | ir::VarType::Null => 0,
         ir::VarType::Number => 8,
         ir::VarType::Boolean => 4,
-        ir::VarType::String => 4,
+        ir::VarType::String | ir::VarType::Array => 4,
         ir::VarType::Func => 4 + 4,
         ir::VarType::StructT { typeidx: _ } => 4,
     }
@@ -21,6 +21,20 @@ const MUL: &str = "*";
 const DIV: &str = "/";
 const MOD: &str = "%";
 
+// Pairs and lists (Source §2)
+const PAIR: &str = "pair";
+const HEAD: &str = "head";
+const TAIL: &str = "tail";
+const IS_PAIR: &str = "is_pair";
+const IS_NULL: &str = "is_null";
+
+// Arrays (Source §3)
+// ARRAY_GET and ARRAY_SET are not valid identifiers, so they can only be used internally by the frontend (for a[i] and a[i] = v)
+pub const ARRAY_GET: &str = "[]";
+pub const ARRAY_SET: &str = "[]=";
+const ARRAY_LENGTH: &str = "array_length";
+const IS_ARRAY: &str = "is_array";
+
 pub fn resolve_unary_operator(es_op: &str) -> Option<&'static str> {
     match es_op {
         "-" => Some(UNARY_MINUS),
@@ -76,8 +90,23 @@ pub fn state_with_builtins(
     register_comparison_op(LE, ir::PrimInst::NumberLe, ir::PrimInst::StringLe, &mut name_ctx, &mut parse_ctx, ir_program);
     register_comparison_op(GT, ir::PrimInst::NumberGt, ir::PrimInst::StringGt, &mut name_ctx, &mut parse_ctx, ir_program);
     register_comparison_op(GE, ir::PrimInst::NumberGe, ir::PrimInst::StringGe, &mut name_ctx, &mut parse_ctx, ir_program);
-    register_equality_op(EQ, true, ir::PrimInst::NumberEq, ir::PrimInst::BooleanEq, ir::PrimInst::StringEq, &mut name_ctx, &mut parse_ctx, ir_program);
-    register_equality_op(NE, false, ir::PrimInst::NumberNeq, ir::PrimInst::BooleanNeq, ir::PrimInst::StringNeq, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_equality_op(EQ, true, ir::PrimInst::NumberEq, ir::PrimInst::BooleanEq, ir::PrimInst::StringEq, ir::PrimInst::AnyEq, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_equality_op(NE, false, ir::PrimInst::NumberNeq, ir::PrimInst::BooleanNeq, ir::PrimInst::StringNeq, ir::PrimInst::AnyNeq, &mut name_ctx, &mut parse_ctx, ir_program);
+
+    // a pair is a struct with two Any fields (the head and the tail), so the GC already knows how to deal with it
+    let pair_vartype = ir::VarType::StructT { typeidx: ir_program.struct_types.len() };
+    ir_program.struct_types.push(Box::new([ir::VarType::Any, ir::VarType::Any]));
+
+    register_pair_constructor(PAIR, pair_vartype, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_pair_accessor(HEAD, pair_vartype, 0, ir::error::ERROR_CODE_HEAD_PARAM_TYPE, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_pair_accessor(TAIL, pair_vartype, 1, ir::error::ERROR_CODE_TAIL_PARAM_TYPE, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_type_predicate(IS_PAIR, pair_vartype, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_type_predicate(IS_NULL, ir::VarType::Null, &mut name_ctx, &mut parse_ctx, ir_program);
+
+    register_prim_func(ARRAY_GET, ir::PrimInst::ArrayGet, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_prim_func(ARRAY_SET, ir::PrimInst::ArraySet, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_prim_func(ARRAY_LENGTH, ir::PrimInst::ArrayLength, &mut name_ctx, &mut parse_ctx, ir_program);
+    register_type_predicate(IS_ARRAY, ir::VarType::Array, &mut name_ctx, &mut parse_ctx, ir_program);
 
     (name_ctx, parse_ctx)
 }
@@ -167,14 +196,19 @@ fn make_binary_op_impl(
 }
 
 // write the actual function (we hope it gets inlined by the ir optimizer later)
-fn make_trivial_func_undefined_impl(ret: bool, ir_program: &mut ir::Program) -> ir::FuncIdx {
+// used for comparing two values of a unit type (e.g. undefined or null)
+fn make_trivial_binary_op_impl(
+    ir_param_vartype: ir::VarType,
+    ret: bool,
+    ir_program: &mut ir::Program,
+) -> ir::FuncIdx {
     let ir_expr = ir::Expr {
         vartype: Some(ir::VarType::Boolean),
         kind: ir::ExprKind::PrimBoolean { val: ret },
     };
 
     let funcidx = ir_program.add_func(ir::Func {
-        params: Box::new([ir::VarType::Undefined, ir::VarType::Undefined]),
+        params: Box::new([ir_param_vartype, ir_param_vartype]),
         result: Some(ir::VarType::Boolean),
         expr: ir_expr,
         signature_filter: Default::default(),
@@ -274,19 +308,22 @@ fn register_comparison_op(
 }
 
 // overloaded all primitive types
-// struct type might need to be supported later
+// the fallback overload on (Any, Any) handles values of different types, as well as funcs, arrays and structs (which are compared by identity)
 fn register_equality_op(
     name: &str,
     undefined_ret_val: bool,
     ir_priminst_number: ir::PrimInst,
     ir_priminst_boolean: ir::PrimInst,
     ir_priminst_string: ir::PrimInst,
-    /*ir_priminst_func: ir::PrimInst,*/ // for now, ir has not implemented it yet
+    ir_priminst_any: ir::PrimInst,
     name_ctx: &mut HashMap<String, PreVar>,
     parse_ctx: &mut ParseState,
     ir_program: &mut ir::Program,
 ) {
-    let funcidx_undefined = make_trivial_func_undefined_impl(undefined_ret_val, ir_program);
+    let funcidx_undefined =
+        make_trivial_binary_op_impl(ir::VarType::Undefined, undefined_ret_val, ir_program);
+    let funcidx_null =
+        make_trivial_binary_op_impl(ir::VarType::Null, undefined_ret_val, ir_program);
     let funcidx_number = make_binary_op_impl(
         ir_priminst_number,
         ir::VarType::Number,
@@ -305,15 +342,29 @@ fn register_equality_op(
         ir::VarType::Boolean,
         ir_program,
     );
-    //let funcidx_func = make_binary_op_impl(ir_priminst_func, ir::VarType::Func, ir::VarType::Boolean, ir_program);
+    let funcidx_any = make_binary_op_impl(
+        ir_priminst_any,
+        ir::VarType::Any,
+        ir::VarType::Boolean,
+        ir_program,
+    );
 
     // insert the necessary things into name_ctx and parse_ctx
     name_ctx.insert(name.to_owned(), PreVar::Direct);
     let mut overload_set = OverloadSet::new();
+    // overloads are resolved from back to front, so the fallback has to come first
+    overload_set.append((
+        Box::new([ir::VarType::Any, ir::VarType::Any]) as Box<[ir::VarType]>,
+        funcidx_any,
+    ));
     overload_set.append((
         Box::new([ir::VarType::Undefined, ir::VarType::Undefined]) as Box<[ir::VarType]>,
         funcidx_undefined,
     ));
+    overload_set.append((
+        Box::new([ir::VarType::Null, ir::VarType::Null]) as Box<[ir::VarType]>,
+        funcidx_null,
+    ));
     overload_set.append((
         Box::new([ir::VarType::Number, ir::VarType::Number]) as Box<[ir::VarType]>,
         funcidx_number,
@@ -329,3 +380,216 @@ fn register_equality_op(
     //overload_set.append((Box::new([ir::VarType::Func, ir::VarType::Func]), funcidx_func));
     parse_ctx.add_direct(name.to_owned(), overload_set);
 }
+
+fn make_param_expr(localidx: usize, ir_vartype: ir::VarType) -> ir::Expr {
+    ir::Expr {
+        vartype: Some(ir_vartype),
+        kind: ir::ExprKind::VarName {
+            source: ir::TargetExpr::Local {
+                localidx,
+                next: None,
+            },
+        },
+    }
+}
+
+// pair(x, y): allocates a new pair struct and writes the two params into it
+fn register_pair_constructor(
+    name: &str,
+    pair_vartype: ir::VarType,
+    name_ctx: &mut HashMap<String, PreVar>,
+    parse_ctx: &mut ParseState,
+    ir_program: &mut ir::Program,
+) {
+    let typeidx = match pair_vartype {
+        ir::VarType::StructT { typeidx } => typeidx,
+        _ => panic!("ICE: pair must be a struct"),
+    };
+
+    // local#2 is the newly allocated pair
+    let make_assign = |fieldidx: usize| ir::Expr {
+        vartype: Some(ir::VarType::Undefined),
+        kind: ir::ExprKind::Assign {
+            target: ir::TargetExpr::Local {
+                localidx: 2,
+                next: Some(Box::new(ir::StructField {
+                    typeidx,
+                    fieldidx,
+                    next: None,
+                })),
+            },
+            expr: Box::new(make_param_expr(fieldidx, ir::VarType::Any)),
+        },
+    };
+    let ir_expr = ir::Expr {
+        vartype: Some(pair_vartype),
+        kind: ir::ExprKind::Declaration {
+            local: pair_vartype,
+            init: Some(Box::new(ir::Expr {
+                vartype: Some(pair_vartype),
+                kind: ir::ExprKind::PrimStructT { typeidx },
+            })),
+            contained_expr: Box::new(ir::Expr {
+                vartype: Some(pair_vartype),
+                kind: ir::ExprKind::Sequence {
+                    content: vec![
+                        make_assign(0),
+                        make_assign(1),
+                        make_param_expr(2, pair_vartype),
+                    ],
+                },
+            }),
+        },
+    };
+
+    let funcidx = ir_program.add_func(ir::Func {
+        params: Box::new([ir::VarType::Any, ir::VarType::Any]),
+        result: Some(pair_vartype),
+        expr: ir_expr,
+        signature_filter: Default::default(),
+    });
+
+    // insert the necessary things into name_ctx and parse_ctx
+    name_ctx.insert(name.to_owned(), PreVar::Direct);
+    parse_ctx.add_direct(
+        name.to_owned(),
+        OverloadSet::from_single((Box::new([ir::VarType::Any, ir::VarType::Any]), funcidx)),
+    );
+}
+
+// head(p) and tail(p): reads the given field of the pair, or traps with the given error code if the param is not a pair
+fn register_pair_accessor(
+    name: &str,
+    pair_vartype: ir::VarType,
+    fieldidx: usize,
+    error_code: u32,
+    name_ctx: &mut HashMap<String, PreVar>,
+    parse_ctx: &mut ParseState,
+    ir_program: &mut ir::Program,
+) {
+    let typeidx = match pair_vartype {
+        ir::VarType::StructT { typeidx } => typeidx,
+        _ => panic!("ICE: pair must be a struct"),
+    };
+
+    let ir_expr = ir::Expr {
+        vartype: Some(ir::VarType::Any),
+        kind: ir::ExprKind::TypeCast {
+            test: Box::new(make_param_expr(0, ir::VarType::Any)),
+            expected: pair_vartype,
+            create_narrow_local: true,
+            true_expr: Box::new(ir::Expr {
+                vartype: Some(ir::VarType::Any),
+                kind: ir::ExprKind::VarName {
+                    source: ir::TargetExpr::Local {
+                        localidx: 1,
+                        next: Some(Box::new(ir::StructField {
+                            typeidx,
+                            fieldidx,
+                            next: None,
+                        })),
+                    },
+                },
+            }),
+            false_expr: Box::new(ir::Expr {
+                vartype: None,
+                kind: ir::ExprKind::Trap {
+                    code: error_code,
+                    location: Default::default(), // builtins do not know the location of the caller
+                },
+            }),
+        },
+    };
+
+    let funcidx = ir_program.add_func(ir::Func {
+        params: Box::new([ir::VarType::Any]),
+        result: Some(ir::VarType::Any),
+        expr: ir_expr,
+        signature_filter: Default::default(),
+    });
+
+    // insert the necessary things into name_ctx and parse_ctx
+    name_ctx.insert(name.to_owned(), PreVar::Direct);
+    parse_ctx.add_direct(
+        name.to_owned(),
+        OverloadSet::from_single((Box::new([ir::VarType::Any]), funcidx)),
+    );
+}
+
+// is_pair(x), is_null(x), etc.: returns true if and only if the param has the given type
+fn register_type_predicate(
+    name: &str,
+    ir_vartype: ir::VarType,
+    name_ctx: &mut HashMap<String, PreVar>,
+    parse_ctx: &mut ParseState,
+    ir_program: &mut ir::Program,
+) {
+    let ir_expr = ir::Expr {
+        vartype: Some(ir::VarType::Boolean),
+        kind: ir::ExprKind::TypeCast {
+            test: Box::new(make_param_expr(0, ir::VarType::Any)),
+            expected: ir_vartype,
+            create_narrow_local: false,
+            true_expr: Box::new(ir::Expr {
+                vartype: Some(ir::VarType::Boolean),
+                kind: ir::ExprKind::PrimBoolean { val: true },
+            }),
+            false_expr: Box::new(ir::Expr {
+                vartype: Some(ir::VarType::Boolean),
+                kind: ir::ExprKind::PrimBoolean { val: false },
+            }),
+        },
+    };
+
+    let funcidx = ir_program.add_func(ir::Func {
+        params: Box::new([ir::VarType::Any]),
+        result: Some(ir::VarType::Boolean),
+        expr: ir_expr,
+        signature_filter: Default::default(),
+    });
+
+    // insert the necessary things into name_ctx and parse_ctx
+    name_ctx.insert(name.to_owned(), PreVar::Direct);
+    parse_ctx.add_direct(
+        name.to_owned(),
+        OverloadSet::from_single((Box::new([ir::VarType::Any]), funcidx)),
+    );
+}
+
+// a function that just applies the given PrimInst to its params, with the param and result types given by the PrimInst's signature
+fn register_prim_func(
+    name: &str,
+    ir_priminst: ir::PrimInst,
+    name_ctx: &mut HashMap<String, PreVar>,
+    parse_ctx: &mut ParseState,
+    ir_program: &mut ir::Program,
+) {
+    let (ir_param_vartypes, ir_result_vartype) = ir_priminst.signature();
+
+    // write the actual function (we hope it gets inlined by the ir optimizer later)
+    let ir_expr = ir::Expr {
+        vartype: ir_result_vartype,
+        kind: ir::ExprKind::PrimAppl {
+            prim_inst: ir_priminst,
+            args: ir_param_vartypes
+                .iter()
+                .enumerate()
+                .map(|(localidx, ir_vartype)| make_param_expr(localidx, *ir_vartype))
+                .collect(),
+        },
+    };
+
+    let funcidx = ir_program.add_func(ir::Func {
+        params: ir_param_vartypes.into(),
+        result: ir_result_vartype,
+        expr: ir_expr,
+        signature_filter: Default::default(),
+    });
+
+    // insert the necessary things into name_ctx and parse_ctx
+    name_ctx.insert(name.to_owned(), PreVar::Direct);
+    parse_ctx.add_direct(
+        name.to_owned(),
+        OverloadSet::from_single((ir_param_vartypes.into(), funcidx)),
+    );
+}
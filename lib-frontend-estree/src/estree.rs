@@ -38,6 +38,8 @@ pub enum NodeKind {
     BreakStatement(BreakStatement),
     ContinueStatement(ContinueStatement),
     IfStatement(IfStatement),
+    WhileStatement(WhileStatement),
+    ForStatement(ForStatement),
     FunctionDeclaration(FunctionDeclaration),
     VariableDeclaration(VariableDeclaration),
     VariableDeclarator(VariableDeclarator),
@@ -50,6 +52,8 @@ pub enum NodeKind {
     LogicalExpression(LogicalExpression),
     ConditionalExpression(ConditionalExpression),
     CallExpression(CallExpression),
+    ArrayExpression(ArrayExpression),
+    MemberExpression(MemberExpression),
     ImportDeclaration(ImportDeclaration),
     ImportSpecifier(ImportSpecifier),
     ImportDefaultSpecifier(ImportDefaultSpecifier),
@@ -162,6 +166,24 @@ pub struct IfStatement {
     pub alternate: Option<Box<Node>>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct WhileStatement {
+    pub test: Box<Node>,
+    pub body: Box<Node>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForStatement {
+    pub init: Option<Box<Node>>,
+    pub test: Option<Box<Node>>,
+    pub update: Option<Box<Node>>,
+    pub body: Box<Node>,
+    #[serde(skip)]
+    pub address_taken_vars: Vec<usize>, // list of address-taken vars declared in the header, populated by pre_parse()
+    #[serde(skip)]
+    pub iter_address_taken_vars: Vec<usize>, // list of address-taken per-iteration copies of the header vars, populated by pre_parse()
+}
+
 #[derive(Deserialize, Debug)]
 pub struct FunctionDeclaration {
     pub id: Box<Node>,
@@ -256,6 +278,18 @@ pub struct CallExpression {
     pub arguments: Vec<Node>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ArrayExpression {
+    pub elements: Vec<Option<Node>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemberExpression {
+    pub object: Box<Node>,
+    pub property: Box<Node>,
+    pub computed: bool,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ImportDeclaration {
     pub specifiers: Vec<Node>,
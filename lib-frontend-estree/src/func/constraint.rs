@@ -40,6 +40,8 @@ fn convert_vartype(s: &str) -> Option<ir::VarType> {
         "boolean" => Some(ir::VarType::Boolean),
         "string" => Some(ir::VarType::String),
         "function" => Some(ir::VarType::Func),
+        "null" => Some(ir::VarType::Null),
+        "array" => Some(ir::VarType::Array),
         _ => None,
     }
 }
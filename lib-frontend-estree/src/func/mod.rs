@@ -32,6 +32,8 @@ pub enum ParseProgramError {
     SourceRestrictionBinaryOperatorError(String), // this binary operator is not allowed
     SourceRestrictionLogicalOperatorError(String), // this logical operator is not allowed
     SourceRestrictionAssignmentOperatorError(String), // this assignment operator is not allowed
+    BreakOutsideLoopError,         // break statement is not inside a loop
+    ContinueOutsideLoopError,      // continue statement is not inside a loop
 }
 
 impl std::fmt::Display for ParseProgramError {
@@ -95,6 +97,12 @@ impl std::fmt::Display for ParseProgramError {
                 "Source restriction: Compound assignment operator `{}' is not allowed",
                 op
             ),
+            ParseProgramError::BreakOutsideLoopError => {
+                write!(f, "Break statement must be inside a loop")
+            }
+            ParseProgramError::ContinueOutsideLoopError => {
+                write!(f, "Continue statement must be inside a loop")
+            }
         }
     }
 }
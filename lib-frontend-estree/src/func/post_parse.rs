@@ -952,6 +952,38 @@ fn post_parse_statement<I: Iterator<Item = (Node, HashMap<String, Option<String>
             )?,
             more_stmt_attr_iter,
         )),
+        NodeKind::BreakStatement(_) => Ok((
+            post_parse_break_statement(es_node.loc, parse_ctx, filename)?,
+            more_stmt_attr_iter,
+        )),
+        NodeKind::ContinueStatement(_) => Ok((
+            post_parse_continue_statement(es_node.loc, parse_ctx, filename)?,
+            more_stmt_attr_iter,
+        )),
+        NodeKind::WhileStatement(stmt) => Ok((
+            post_parse_while_statement(
+                stmt,
+                es_node.loc,
+                parse_ctx,
+                depth,
+                num_locals,
+                filename,
+                ir_program,
+            )?,
+            more_stmt_attr_iter,
+        )),
+        NodeKind::ForStatement(stmt) => Ok((
+            post_parse_for_statement(
+                stmt,
+                es_node.loc,
+                parse_ctx,
+                depth,
+                num_locals,
+                filename,
+                ir_program,
+            )?,
+            more_stmt_attr_iter,
+        )),
         NodeKind::FunctionDeclaration(func_decl) => {
             if attributes.get("direct").is_some() {
                 // direct func declarations do not generate any ir::Expr in the current context
@@ -1022,6 +1054,16 @@ fn post_parse_toplevel_statement(
         NodeKind::IfStatement(stmt) => {
             post_parse_if_statement(stmt, es_node.loc, parse_ctx, 0, 0, filename, ir_program)
         }
+        NodeKind::BreakStatement(_) => post_parse_break_statement(es_node.loc, parse_ctx, filename),
+        NodeKind::ContinueStatement(_) => {
+            post_parse_continue_statement(es_node.loc, parse_ctx, filename)
+        }
+        NodeKind::WhileStatement(stmt) => {
+            post_parse_while_statement(stmt, es_node.loc, parse_ctx, 0, 0, filename, ir_program)
+        }
+        NodeKind::ForStatement(stmt) => {
+            post_parse_for_statement(stmt, es_node.loc, parse_ctx, 0, 0, filename, ir_program)
+        }
         NodeKind::FunctionDeclaration(func_decl) => {
             if attributes.get("direct").is_some() {
                 // direct func declarations do not generate any ir::Expr in the current context
@@ -1181,44 +1223,20 @@ fn post_parse_if_statement(
     // Each branch is a BlockStatement, and hence returns Undefined.
     // also emits a type check to ensure that the conditional is boolean type
 
-    let cond_loc: ir::SourceLocation = as_ir_sl(&es_if.test.loc, 0 /*FILE*/);
-
     // We synthesise the typecheck to ensure that the condition is a boolean
     // then add the true_expr and false_expr
     Ok(ir::Expr {
         vartype: Some(ir::VarType::Undefined),
         kind: ir::ExprKind::Conditional {
-            cond: Box::new(ir::Expr {
-                vartype: Some(ir::VarType::Boolean),
-                kind: ir::ExprKind::TypeCast {
-                    test: Box::new(post_parse_expr(
-                        *es_if.test,
-                        parse_ctx,
-                        depth,
-                        num_locals,
-                        filename,
-                        ir_program,
-                    )?),
-                    expected: ir::VarType::Boolean,
-                    create_narrow_local: true,
-                    true_expr: Box::new(ir::Expr {
-                        vartype: Some(ir::VarType::Boolean),
-                        kind: ir::ExprKind::VarName {
-                            source: ir::TargetExpr::Local {
-                                localidx: num_locals,
-                                next: None,
-                            },
-                        },
-                    }),
-                    false_expr: Box::new(ir::Expr {
-                        vartype: None,
-                        kind: ir::ExprKind::Trap {
-                            code: ir::error::ERROR_CODE_IF_STATEMENT_CONDITION_TYPE,
-                            location: cond_loc,
-                        },
-                    }),
-                },
-            }),
+            cond: Box::new(post_parse_condition(
+                *es_if.test,
+                ir::error::ERROR_CODE_IF_STATEMENT_CONDITION_TYPE,
+                parse_ctx,
+                depth,
+                num_locals,
+                filename,
+                ir_program,
+            )?),
             true_expr: Box::new({
                 let (block_stmt, loc) = as_block_statement_with_loc(*es_if.consequent);
                 post_parse_block_statement(
@@ -1235,6 +1253,455 @@ fn post_parse_if_statement(
     })
 }
 
+fn post_parse_while_statement(
+    es_while: WhileStatement,
+    _loc: Option<esSL>,
+    parse_ctx: &mut ParseState,
+    depth: usize,
+    num_locals: usize, // current number of IR locals
+    filename: Option<&str>,
+    ir_program: &mut ir::Program,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    // Emits the ExprKind::Loop (wrapped in a ExprKind::Block, which is the target of BreakStatements).
+    // The loop body is a conditional: if the test is true, we run the block and then jump back to the start of the loop.
+    // Otherwise, the loop exits normally and returns undefined.
+    // ContinueStatements jump directly to the start of the loop.
+
+    let break_landing = parse_ctx.add_landing();
+    let loop_landing = parse_ctx.add_landing();
+    let loop_undo_ctx = parse_ctx.add_loop(break_landing, loop_landing);
+
+    let cond_expr = post_parse_condition(
+        *es_while.test,
+        ir::error::ERROR_CODE_LOOP_CONDITION_TYPE,
+        parse_ctx,
+        depth,
+        num_locals,
+        filename,
+        ir_program,
+    )?;
+    let body_expr = {
+        let (block_stmt, loc) = as_block_statement_with_loc(*es_while.body);
+        post_parse_block_statement(
+            block_stmt, loc, parse_ctx, depth, num_locals, filename, ir_program,
+        )?
+    };
+
+    parse_ctx.remove_loop(loop_undo_ctx);
+    parse_ctx.remove_landing();
+    parse_ctx.remove_landing();
+
+    Ok(make_loop(cond_expr, vec![body_expr]))
+}
+
+fn post_parse_for_statement(
+    es_for: ForStatement,
+    _loc: Option<esSL>,
+    parse_ctx: &mut ParseState,
+    depth: usize,
+    num_locals: usize, // current number of IR locals
+    filename: Option<&str>,
+    ir_program: &mut ir::Program,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    // The header scope contains the variable declared in the initializer (if any),
+    // and the iteration scope contains a copy of the header variable that is fresh for each iteration.
+    // (See pre_parse_for_statement() for more details.)
+    // We emit something like this:
+    /*
+    {
+        let i = <init>;
+        block { // break target
+            loop {
+                if (<test>) {
+                    {
+                        let i' = i;
+                        block { // continue target
+                            <body>
+                        }
+                        i = i';
+                    }
+                    <update>;
+                    continue;
+                }
+            }
+        }
+    }
+    */
+
+    let header_depth = depth + 1;
+    let iter_depth = depth + 2;
+
+    let ForStatement {
+        init,
+        test,
+        update,
+        body,
+        address_taken_vars,
+        iter_address_taken_vars,
+    } = es_for;
+    let es_init = *init.unwrap();
+    let es_test = *test.unwrap();
+    let es_update = *update.unwrap();
+    let (es_block, block_loc) = as_block_statement_with_loc(*body);
+
+    // the (header, iteration) varlocids of the variable declared in the initializer (if any)
+    let loop_varlocids: Vec<(VarLocId, VarLocId)> = match &es_init.kind {
+        NodeKind::VariableDeclaration(var_decl) => var_decl
+            .declarations
+            .iter()
+            .map(|decr_node| {
+                let varlocid =
+                    as_varlocid(as_id_ref(&as_var_decr_ref(decr_node).id).prevar.unwrap());
+                (
+                    varlocid,
+                    VarLocId {
+                        depth: iter_depth,
+                        index: varlocid.index,
+                    },
+                )
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let header_varlocids: Vec<VarLocId> = loop_varlocids.iter().map(|(h, _)| *h).collect();
+    let iter_varlocids: Vec<VarLocId> = loop_varlocids.iter().map(|(_, it)| *it).collect();
+
+    post_parse_loop_scope(
+        &header_varlocids,
+        &address_taken_vars,
+        parse_ctx,
+        num_locals,
+        ir_program,
+        |parse_ctx, num_locals, ir_program| {
+            let init_expr = match es_init.kind {
+                NodeKind::VariableDeclaration(var_decl) => {
+                    let es_var_decr =
+                        as_var_decr(var_decl.declarations.into_iter().next().unwrap());
+                    let varlocid = as_varlocid(as_id(*es_var_decr.id).prevar.unwrap());
+                    ir::Expr {
+                        vartype: Some(ir::VarType::Undefined),
+                        kind: ir::ExprKind::Assign {
+                            target: parse_ctx.get_target(&varlocid).unwrap().clone(),
+                            expr: Box::new(post_parse_expr(
+                                *es_var_decr.init.unwrap(),
+                                parse_ctx,
+                                header_depth,
+                                num_locals,
+                                filename,
+                                ir_program,
+                            )?),
+                        },
+                    }
+                }
+                _ => post_parse_expr(
+                    es_init,
+                    parse_ctx,
+                    header_depth,
+                    num_locals,
+                    filename,
+                    ir_program,
+                )?,
+            };
+
+            let break_landing = parse_ctx.add_landing();
+            parse_ctx.add_landing(); // the loop itself
+
+            let cond_expr = post_parse_condition(
+                es_test,
+                ir::error::ERROR_CODE_LOOP_CONDITION_TYPE,
+                parse_ctx,
+                header_depth,
+                num_locals,
+                filename,
+                ir_program,
+            )?;
+
+            // copies the value of each variable in `sources` to the corresponding variable in `dests`
+            let make_copies = |parse_ctx: &ParseState, sources: &[VarLocId], dests: &[VarLocId]| {
+                sources
+                    .iter()
+                    .zip(dests.iter())
+                    .map(|(source, dest)| ir::Expr {
+                        vartype: Some(ir::VarType::Undefined),
+                        kind: ir::ExprKind::Assign {
+                            target: parse_ctx.get_target(dest).unwrap().clone(),
+                            expr: Box::new(ir::Expr {
+                                vartype: Some(ir::VarType::Any),
+                                kind: ir::ExprKind::VarName {
+                                    source: parse_ctx.get_target(source).unwrap().clone(),
+                                },
+                            }),
+                        },
+                    })
+                    .collect::<Vec<ir::Expr>>()
+            };
+
+            let iter_expr = post_parse_loop_scope(
+                &iter_varlocids,
+                &iter_address_taken_vars,
+                parse_ctx,
+                num_locals,
+                ir_program,
+                |parse_ctx, num_locals, ir_program| {
+                    let mut sequence = make_copies(parse_ctx, &header_varlocids, &iter_varlocids);
+                    let continue_landing = parse_ctx.add_landing();
+                    let loop_undo_ctx = parse_ctx.add_loop(break_landing, continue_landing);
+                    let body_expr = post_parse_block_statement(
+                        es_block, block_loc, parse_ctx, iter_depth, num_locals, filename,
+                        ir_program,
+                    )?;
+                    parse_ctx.remove_loop(loop_undo_ctx);
+                    parse_ctx.remove_landing();
+                    sequence.push(ir::Expr {
+                        vartype: Some(ir::VarType::Undefined),
+                        kind: ir::ExprKind::Block {
+                            expr: Box::new(body_expr),
+                        },
+                    });
+                    sequence.append(&mut make_copies(
+                        parse_ctx,
+                        &iter_varlocids,
+                        &header_varlocids,
+                    ));
+                    Ok(sequence)
+                },
+            )?;
+
+            let update_expr = post_parse_expr(
+                es_update,
+                parse_ctx,
+                header_depth,
+                num_locals,
+                filename,
+                ir_program,
+            )?;
+
+            parse_ctx.remove_landing();
+            parse_ctx.remove_landing();
+
+            Ok(vec![
+                init_expr,
+                make_loop(cond_expr, vec![iter_expr, update_expr]),
+            ])
+        },
+    )
+}
+
+/**
+ * Emits the scope for the given variables, which are declared without any initializer.
+ * Variables in `address_taken_vars` are put into a new struct, while the others become locals.
+ * `f` should return the content of the scope, which will be put into a Sequence that returns undefined.
+ * This is used for the implicit scopes of a ForStatement.
+ */
+fn post_parse_loop_scope<
+    F: FnOnce(
+        &mut ParseState,
+        usize,
+        &mut ir::Program,
+    ) -> Result<Vec<ir::Expr>, CompileMessage<ParseProgramError>>,
+>(
+    varlocids: &[VarLocId],
+    address_taken_vars: &[usize],
+    parse_ctx: &mut ParseState,
+    num_locals: usize, // current number of IR locals
+    ir_program: &mut ir::Program,
+    f: F,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    // the local declarations that we need to make (outermost first)
+    let mut locals: Vec<(ir::VarType, Option<ir::Expr>)> = Vec::new();
+    let mut target_expr_entries: Vec<(VarLocId, ir::TargetExpr)> = Vec::new();
+
+    if !address_taken_vars.is_empty() {
+        // synthesise the struct for the address taken vars
+        let struct_idx = ir_program.struct_types.len();
+        ir_program.struct_types.push(
+            address_taken_vars
+                .iter()
+                .map(|_| ir::VarType::Any)
+                .collect(),
+        );
+        for varlocid in varlocids {
+            if let Some(ct) = address_taken_vars
+                .iter()
+                .position(|idx| *idx == varlocid.index)
+            {
+                target_expr_entries.push((
+                    *varlocid,
+                    ir::TargetExpr::Local {
+                        localidx: num_locals,
+                        next: Some(Box::new(ir::StructField {
+                            typeidx: struct_idx,
+                            fieldidx: ct,
+                            next: None,
+                        })),
+                    },
+                ));
+            }
+        }
+        locals.push((
+            ir::VarType::StructT {
+                typeidx: struct_idx,
+            },
+            Some(ir::Expr {
+                vartype: Some(ir::VarType::StructT {
+                    typeidx: struct_idx,
+                }),
+                kind: ir::ExprKind::PrimStructT {
+                    typeidx: struct_idx,
+                },
+            }),
+        ));
+    }
+    for varlocid in varlocids {
+        if !address_taken_vars.contains(&varlocid.index) {
+            target_expr_entries.push((
+                *varlocid,
+                ir::TargetExpr::Local {
+                    localidx: num_locals + locals.len(),
+                    next: None,
+                },
+            ));
+            locals.push((ir::VarType::Any, None));
+        }
+    }
+
+    // add these vars to the parse_ctx
+    let target_undo_ctx = parse_ctx.add_targets(target_expr_entries.into_boxed_slice());
+
+    let mut sequence = f(parse_ctx, num_locals + locals.len(), ir_program)?;
+    sequence.push(make_prim_undefined());
+
+    // remove the vars from the parse_ctx
+    parse_ctx.remove_targets(target_undo_ctx);
+
+    Ok(locals.into_iter().rev().fold(
+        ir::Expr {
+            vartype: Some(ir::VarType::Undefined),
+            kind: ir::ExprKind::Sequence { content: sequence },
+        },
+        |contained_expr, (local, init)| ir::Expr {
+            vartype: Some(ir::VarType::Undefined),
+            kind: ir::ExprKind::Declaration {
+                local,
+                init: init.map(Box::new),
+                contained_expr: Box::new(contained_expr),
+            },
+        },
+    ))
+}
+
+/**
+ * Emits a loop that runs `body` repeatedly for as long as `cond_expr` is true.
+ * `cond_expr` must be of Boolean type (usually generated from post_parse_condition()), and the loop returns undefined.
+ * The loop is wrapped in a Block, so that a Break can exit the loop.  So the caller should have added two landings (the Block and the Loop) when emitting `body`.
+ */
+fn make_loop(cond_expr: ir::Expr, mut body: Vec<ir::Expr>) -> ir::Expr {
+    body.push(make_break(0));
+    ir::Expr {
+        vartype: Some(ir::VarType::Undefined),
+        kind: ir::ExprKind::Block {
+            expr: Box::new(ir::Expr {
+                vartype: Some(ir::VarType::Undefined),
+                kind: ir::ExprKind::Loop {
+                    expr: Box::new(ir::Expr {
+                        vartype: Some(ir::VarType::Undefined),
+                        kind: ir::ExprKind::Conditional {
+                            cond: Box::new(cond_expr),
+                            true_expr: Box::new(ir::Expr {
+                                vartype: None,
+                                kind: ir::ExprKind::Sequence { content: body },
+                            }),
+                            false_expr: Box::new(make_prim_undefined()),
+                        },
+                    }),
+                },
+            }),
+        },
+    }
+}
+
+fn post_parse_break_statement(
+    loc: Option<esSL>,
+    parse_ctx: &ParseState,
+    filename: Option<&str>,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    match parse_ctx.get_break_num_frames() {
+        Some(num_frames) => Ok(make_break(num_frames)),
+        None => Err(CompileMessage::new_error(
+            loc.into_sl(filename).to_owned(),
+            ParseProgramError::BreakOutsideLoopError,
+        )),
+    }
+}
+
+fn post_parse_continue_statement(
+    loc: Option<esSL>,
+    parse_ctx: &ParseState,
+    filename: Option<&str>,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    match parse_ctx.get_continue_num_frames() {
+        Some(num_frames) => Ok(make_break(num_frames)),
+        None => Err(CompileMessage::new_error(
+            loc.into_sl(filename).to_owned(),
+            ParseProgramError::ContinueOutsideLoopError,
+        )),
+    }
+}
+
+// Loops always return undefined, so all Breaks that we emit carry undefined
+fn make_break(num_frames: usize) -> ir::Expr {
+    ir::Expr {
+        vartype: None,
+        kind: ir::ExprKind::Break {
+            num_frames,
+            expr: Box::new(make_prim_undefined()),
+        },
+    }
+}
+
+/**
+ * Emits the condition of an IfStatement or a loop, with a typecheck to ensure that it is a boolean.
+ * If the typecheck fails, it traps with the given error code.
+ */
+fn post_parse_condition(
+    es_test: Node,
+    error_code: u32,
+    parse_ctx: &mut ParseState,
+    depth: usize,
+    num_locals: usize, // current number of IR locals
+    filename: Option<&str>,
+    ir_program: &mut ir::Program,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    let cond_loc: ir::SourceLocation = as_ir_sl(&es_test.loc, 0 /*FILE*/);
+
+    Ok(ir::Expr {
+        vartype: Some(ir::VarType::Boolean),
+        kind: ir::ExprKind::TypeCast {
+            test: Box::new(post_parse_expr(
+                es_test, parse_ctx, depth, num_locals, filename, ir_program,
+            )?),
+            expected: ir::VarType::Boolean,
+            create_narrow_local: true,
+            true_expr: Box::new(ir::Expr {
+                vartype: Some(ir::VarType::Boolean),
+                kind: ir::ExprKind::VarName {
+                    source: ir::TargetExpr::Local {
+                        localidx: num_locals,
+                        next: None,
+                    },
+                },
+            }),
+            false_expr: Box::new(ir::Expr {
+                vartype: None,
+                kind: ir::ExprKind::Trap {
+                    code: error_code,
+                    location: cond_loc,
+                },
+            }),
+        },
+    })
+}
+
 fn post_parse_direct_func_decl(
     es_func_decl: FunctionDeclaration,
     loc: Option<esSL>,
@@ -1659,6 +2126,24 @@ fn post_parse_expr(
             filename,
             ir_program,
         ),
+        NodeKind::ArrayExpression(array_expr) => post_parse_array_expr(
+            array_expr,
+            es_expr.loc,
+            parse_ctx,
+            depth,
+            num_locals,
+            filename,
+            ir_program,
+        ),
+        NodeKind::MemberExpression(member_expr) => post_parse_member_expr(
+            member_expr,
+            es_expr.loc,
+            parse_ctx,
+            depth,
+            num_locals,
+            filename,
+            ir_program,
+        ),
         _ => pppanic(),
     }
 }
@@ -1755,6 +2240,10 @@ fn post_parse_literal(
             vartype: Some(ir::VarType::Number),
             kind: ir::ExprKind::PrimNumber { val: number_val },
         }),
+        LiteralValue::Null => Ok(ir::Expr {
+            vartype: Some(ir::VarType::Null),
+            kind: ir::ExprKind::PrimNull,
+        }),
         _ => pppanic(),
     }
 }
@@ -1869,6 +2358,23 @@ fn post_parse_assign_expr(
             ParseProgramError::SourceRestrictionAssignmentOperatorError(es_assign_expr.operator),
         ));
     }
+    if let NodeKind::MemberExpression(member_expr) = es_assign_expr.left.kind {
+        // an array element assignment, i.e. a[i] = v, is a call to the builtin array setter (that returns undefined)
+        return post_parse_direct_call_helper(
+            builtins::ARRAY_SET,
+            Box::new([
+                *member_expr.object,
+                *member_expr.property,
+                *es_assign_expr.right,
+            ]),
+            loc,
+            parse_ctx,
+            depth,
+            num_locals,
+            filename,
+            ir_program,
+        );
+    }
     // an assignment expr, that returns undefined
     let varlocid = as_varlocid(as_id(*es_assign_expr.left).prevar.unwrap());
     Ok(ir::Expr {
@@ -1887,6 +2393,112 @@ fn post_parse_assign_expr(
     })
 }
 
+fn post_parse_array_expr(
+    es_array_expr: ArrayExpression,
+    loc: Option<esSL>,
+    parse_ctx: &mut ParseState,
+    depth: usize,
+    num_locals: usize, // current number of IR locals
+    filename: Option<&str>,
+    ir_program: &mut ir::Program,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    // An array literal [a, b, ...] is converted to:
+    // { let arr = <new array>; arr[0] = a; arr[1] = b; ...; arr }
+    // where `arr` is a new local (at index `num_locals`).
+    let new_num_locals = num_locals + 1;
+    let make_arr_expr = || ir::Expr {
+        vartype: Some(ir::VarType::Any),
+        kind: ir::ExprKind::VarName {
+            source: ir::TargetExpr::Local {
+                localidx: num_locals,
+                next: None,
+            },
+        },
+    };
+
+    let mut sequence: Vec<ir::Expr> = es_array_expr
+        .elements
+        .into_iter()
+        .enumerate()
+        .map(|(i, opt_elem)| {
+            // pre_parse() would have already ensured that there are no holes
+            let elem = opt_elem.unwrap();
+            Ok(ir::Expr {
+                vartype: Some(ir::VarType::Any),
+                kind: ir::ExprKind::Appl {
+                    func: Box::new(post_parse_direct_varname(
+                        builtins::ARRAY_SET,
+                        parse_ctx,
+                        depth,
+                        new_num_locals,
+                        filename,
+                        ir_program,
+                    )?),
+                    args: Box::new([
+                        make_arr_expr(),
+                        ir::Expr {
+                            vartype: Some(ir::VarType::Number),
+                            kind: ir::ExprKind::PrimNumber { val: i as f64 },
+                        },
+                        post_parse_expr(
+                            elem,
+                            parse_ctx,
+                            depth,
+                            new_num_locals,
+                            filename,
+                            ir_program,
+                        )?,
+                    ]),
+                    location: as_ir_sl(&loc, 0 /*FILE*/),
+                },
+            })
+        })
+        .collect::<Result<Vec<ir::Expr>, CompileMessage<ParseProgramError>>>()?;
+    sequence.push(make_arr_expr());
+
+    // Like other locals emitted by the frontend, `arr` has type Any; the optimizer will narrow it if possible.
+    Ok(ir::Expr {
+        vartype: Some(ir::VarType::Any),
+        kind: ir::ExprKind::Declaration {
+            local: ir::VarType::Any,
+            init: Some(Box::new(ir::Expr {
+                vartype: Some(ir::VarType::Array),
+                kind: ir::ExprKind::PrimAppl {
+                    prim_inst: ir::PrimInst::ArrayNew,
+                    args: Box::new([]),
+                },
+            })),
+            contained_expr: Box::new(ir::Expr {
+                vartype: Some(ir::VarType::Any),
+                kind: ir::ExprKind::Sequence { content: sequence },
+            }),
+        },
+    })
+}
+
+fn post_parse_member_expr(
+    es_member_expr: MemberExpression,
+    loc: Option<esSL>,
+    parse_ctx: &mut ParseState,
+    depth: usize,
+    num_locals: usize, // current number of IR locals
+    filename: Option<&str>,
+    ir_program: &mut ir::Program,
+) -> Result<ir::Expr, CompileMessage<ParseProgramError>> {
+    // an array element access, i.e. a[i], is a call to the builtin array getter
+    // pre_parse() would have already ensured that this is a computed member access
+    post_parse_direct_call_helper(
+        builtins::ARRAY_GET,
+        Box::new([*es_member_expr.object, *es_member_expr.property]),
+        loc,
+        parse_ctx,
+        depth,
+        num_locals,
+        filename,
+        ir_program,
+    )
+}
+
 fn post_parse_cond_expr(
     es_cond_expr: ConditionalExpression,
     loc: Option<esSL>,
@@ -331,6 +331,12 @@ fn pre_parse_statement(
         NodeKind::IfStatement(stmt) => {
             pre_parse_if_statement(stmt, &es_node.loc, name_ctx, depth, filename)
         }
+        NodeKind::WhileStatement(stmt) => {
+            pre_parse_while_statement(stmt, &es_node.loc, name_ctx, depth, filename)
+        }
+        NodeKind::ForStatement(stmt) => {
+            pre_parse_for_statement(stmt, &es_node.loc, name_ctx, depth, filename)
+        }
         NodeKind::FunctionDeclaration(func_decl) => {
             pre_parse_func_decl(func_decl, &es_node.loc, name_ctx, depth, filename)
         }
@@ -338,11 +344,19 @@ fn pre_parse_statement(
             pre_parse_var_decl(var_decl, &es_node.loc, name_ctx, depth, filename)
         }
         NodeKind::EmptyStatement(_) => Ok(BTreeMap::new()), // EmptyStatement does not use any variables
+        NodeKind::BreakStatement(BreakStatement { label: None })
+        | NodeKind::ContinueStatement(ContinueStatement { label: None }) => Ok(BTreeMap::new()), // post_parse() will check that it is inside a loop
+        NodeKind::BreakStatement(_) | NodeKind::ContinueStatement(_) => {
+            Err(CompileMessage::new_error(
+                es_node.loc.into_sl(filename).to_owned(),
+                ParseProgramError::SourceRestrictionError(
+                    "Labeled break and continue are not allowed",
+                ),
+            ))
+        }
         NodeKind::DebuggerStatement(_)
         | NodeKind::WithStatement(_)
-        | NodeKind::LabeledStatement(_)
-        | NodeKind::BreakStatement(_)
-        | NodeKind::ContinueStatement(_) => Err(CompileMessage::new_error(
+        | NodeKind::LabeledStatement(_) => Err(CompileMessage::new_error(
             es_node.loc.into_sl(filename).to_owned(),
             ParseProgramError::ESTreeError("This statement type is not allowed"),
         )),
@@ -382,9 +396,21 @@ fn pre_parse_expr_statement(
     name_ctx: &mut HashMap<String, PreVar>, // contains all names referenceable from outside the current sequence
     depth: usize,
     filename: Option<&str>,
+) -> Result<BTreeMap<VarLocId, Usage>, CompileMessage<ParseProgramError>> {
+    pre_parse_assign_or_expr(&mut es_expr_stmt.expression, name_ctx, depth, filename)
+}
+
+/**
+ * Pre-parse an expression that is allowed to be an AssignmentExpression
+ * (i.e. the expression of an ExpressionStatement, or the initializer or update of a ForStatement).
+ */
+fn pre_parse_assign_or_expr(
+    es_expr_node: &mut Node,
+    name_ctx: &mut HashMap<String, PreVar>, // contains all names referenceable from outside the current sequence
+    depth: usize,
+    filename: Option<&str>,
 ) -> Result<BTreeMap<VarLocId, Usage>, CompileMessage<ParseProgramError>> {
     // we have to detect the AssignmentExpression here, since in Source AssignmentExpression is not allowed to be nested.
-    let es_expr_node: &mut Node = &mut *es_expr_stmt.expression;
     if let NodeKind::AssignmentExpression(AssignmentExpression {
         operator,
         left,
@@ -399,8 +425,7 @@ fn pre_parse_expr_statement(
                 } => {
                     let rhs_expr = pre_parse_expr(&mut **right, name_ctx, depth, filename)?;
                     let resvar = *name_ctx.get(name.as_str()).unwrap();
-                    assert!(*prevar == Some(resvar)); // they should already have a prevar attached
-                                                      // note: this is probably a bug, they would not have prevar attached yet...
+                    *prevar = Some(resvar); // save the variable location
                     let varlocid = match resvar {
                         PreVar::Target(varlocid) => varlocid,
                         PreVar::Direct => panic!("ICE: Should be VarLocId"),
@@ -418,10 +443,21 @@ fn pre_parse_expr_statement(
                         ))
                     }
                 }
+                Node {
+                    loc,
+                    kind: NodeKind::MemberExpression(member_expr),
+                } => {
+                    // array element assignment, i.e. a[i] = v
+                    // JS evaluates 'a', then 'i', then 'v', before doing the actual assignment
+                    let lhs_expr =
+                        pre_parse_member_expr(member_expr, loc, name_ctx, depth, filename)?;
+                    let rhs_expr = pre_parse_expr(right, name_ctx, depth, filename)?;
+                    Ok(varusage::merge_series(lhs_expr, rhs_expr))
+                }
                 Node { loc, kind: _ } => Err(CompileMessage::new_error(
                     loc.into_sl(filename).to_owned(),
                     ParseProgramError::ESTreeError(
-                        "Expected ESTree Identifier at LHS of AssignmentExpression",
+                        "Expected ESTree Identifier or MemberExpression at LHS of AssignmentExpression",
                     ),
                 )),
             },
@@ -507,6 +543,180 @@ fn pre_parse_if_statement(
     }
 }
 
+fn pre_parse_while_statement(
+    es_while: &mut WhileStatement,
+    loc: &Option<esSL>,
+    name_ctx: &mut HashMap<String, PreVar>, // contains all names referenceable from outside the current sequence
+    depth: usize,
+    filename: Option<&str>,
+) -> Result<BTreeMap<VarLocId, Usage>, CompileMessage<ParseProgramError>> {
+    if let NodeKind::BlockStatement(es_block) = &mut es_while.body.kind {
+        // the test and the body are executed alternately, any number of times
+        let test = pre_parse_expr(&mut es_while.test, name_ctx, depth, filename)?;
+        let body =
+            pre_parse_block_statement(es_block, &es_while.body.loc, name_ctx, depth, filename)?;
+        Ok(varusage::wrap_loop(varusage::merge_series(test, body)))
+    } else {
+        Err(CompileMessage::new_error(
+            loc.into_sl(filename).to_owned(),
+            ParseProgramError::SourceRestrictionError("Body of while statement must be a block"),
+        ))
+    }
+}
+
+/**
+ * A ForStatement has two implicit scopes (in addition to the scope of its body):
+ * the header scope (at depth+1), which contains the variable declared in the initializer,
+ * and the iteration scope (at depth+2), which contains a fresh copy of that variable for each iteration.
+ * The test and update refer to the header variable, while the body refers to the per-iteration copy,
+ * which is copied in from the header variable before the body is executed and copied back out after it.
+ * This ensures that closures created in the body capture a different variable in each iteration, as required by JavaScript.
+ */
+fn pre_parse_for_statement(
+    es_for: &mut ForStatement,
+    loc: &Option<esSL>,
+    name_ctx: &mut HashMap<String, PreVar>, // contains all names referenceable from outside the current sequence
+    depth: usize,
+    filename: Option<&str>,
+) -> Result<BTreeMap<VarLocId, Usage>, CompileMessage<ParseProgramError>> {
+    let header_depth = depth + 1;
+    let iter_depth = depth + 2;
+
+    let (es_init, es_test, es_update) = match (
+        es_for.init.as_deref_mut(),
+        es_for.test.as_deref_mut(),
+        es_for.update.as_deref_mut(),
+    ) {
+        (Some(es_init), Some(es_test), Some(es_update)) => (es_init, es_test, es_update),
+        _ => {
+            return Err(CompileMessage::new_error(
+                loc.into_sl(filename).to_owned(),
+                ParseProgramError::SourceRestrictionError(
+                    "For statement must have an initializer, a test, and an update",
+                ),
+            ))
+        }
+    };
+    let es_block = if let NodeKind::BlockStatement(es_block) = &mut es_for.body.kind {
+        es_block
+    } else {
+        return Err(CompileMessage::new_error(
+            loc.into_sl(filename).to_owned(),
+            ParseProgramError::SourceRestrictionError("Body of for statement must be a block"),
+        ));
+    };
+
+    // Extracts the variable declared in the initializer (if any)
+    let header_decls: Vec<(String, PreVar)> = match &es_init.kind {
+        NodeKind::VariableDeclaration(var_decl)
+            if var_decl.kind == "let" && var_decl.declarations.len() == 1 =>
+        {
+            validate_and_extract_decls(
+                std::slice::from_ref(es_init),
+                header_depth,
+                &mut 0,
+                filename,
+            )?
+        }
+        NodeKind::AssignmentExpression(_) => Vec::new(),
+        _ => return Err(CompileMessage::new_error(
+            es_init.loc.into_sl(filename).to_owned(),
+            ParseProgramError::SourceRestrictionError(
+                "Initializer of for statement must be a single let declaration or an assignment",
+            ),
+        )),
+    };
+    if let NodeKind::AssignmentExpression(_) = &es_update.kind {
+    } else {
+        return Err(CompileMessage::new_error(
+            es_update.loc.into_sl(filename).to_owned(),
+            ParseProgramError::SourceRestrictionError(
+                "Update of for statement must be an assignment",
+            ),
+        ));
+    }
+
+    // The per-iteration copies have the same names and indices as the header variables, but are in the iteration scope
+    let header_iter_varlocids: Vec<(VarLocId, VarLocId)> = header_decls
+        .iter()
+        .map(|(_, prevar)| match prevar {
+            PreVar::Target(varlocid) => (
+                *varlocid,
+                VarLocId {
+                    depth: iter_depth,
+                    index: varlocid.index,
+                },
+            ),
+            PreVar::Direct => panic!("ICE: Should be VarLocId"),
+        })
+        .collect();
+    let iter_decls: Vec<(String, PreVar)> = header_decls
+        .iter()
+        .zip(header_iter_varlocids.iter())
+        .map(|((name, _), (_, iter_varlocid))| (name.clone(), PreVar::Target(*iter_varlocid)))
+        .collect();
+
+    let header_undo_ctx = name_ctx.add_scope(header_decls);
+
+    let init = if let NodeKind::VariableDeclaration(var_decl) = &mut es_init.kind {
+        pre_parse_var_decl(var_decl, &es_init.loc, name_ctx, header_depth, filename)?
+    } else {
+        pre_parse_assign_or_expr(es_init, name_ctx, header_depth, filename)?
+    };
+    let test = pre_parse_expr(es_test, name_ctx, header_depth, filename)?;
+
+    // the iteration scope
+    let mut iter = {
+        let iter_undo_ctx = name_ctx.add_scope(iter_decls);
+        let body =
+            pre_parse_block_statement(es_block, &es_for.body.loc, name_ctx, iter_depth, filename)?;
+        name_ctx.remove_scope(iter_undo_ctx);
+
+        let copy_in = header_iter_varlocids.iter().fold(
+            BTreeMap::new(),
+            |prev, (header_varlocid, iter_varlocid)| {
+                varusage::merge_series(
+                    prev,
+                    varusage::merge_series(
+                        varusage::from_used(*header_varlocid),
+                        varusage::from_modified(*iter_varlocid),
+                    ),
+                )
+            },
+        );
+        let copy_out = header_iter_varlocids.iter().fold(
+            BTreeMap::new(),
+            |prev, (header_varlocid, iter_varlocid)| {
+                varusage::merge_series(
+                    prev,
+                    varusage::merge_series(
+                        varusage::from_used(*iter_varlocid),
+                        varusage::from_modified(*header_varlocid),
+                    ),
+                )
+            },
+        );
+        varusage::merge_series(varusage::merge_series(copy_in, body), copy_out)
+    };
+    es_for.iter_address_taken_vars = split_off_address_taken_vars(&mut iter, iter_depth);
+
+    let update = pre_parse_assign_or_expr(es_update, name_ctx, header_depth, filename)?;
+
+    name_ctx.remove_scope(header_undo_ctx);
+
+    // the test, iteration and update are executed in series, any number of times
+    let mut ret_usages = varusage::merge_series(
+        init,
+        varusage::wrap_loop(varusage::merge_series(
+            varusage::merge_series(test, iter),
+            update,
+        )),
+    );
+    es_for.address_taken_vars = split_off_address_taken_vars(&mut ret_usages, header_depth);
+
+    Ok(ret_usages)
+}
+
 /**
  * This is a normal function declaration, not the direct kind.  So it is equivalent to a const declaration.
  */
@@ -781,13 +991,10 @@ fn pre_parse_expr(
             pre_parse_identifier_use(identifier, &es_expr.loc, name_ctx, depth, filename)
         }
         NodeKind::Literal(literal) => match literal.value {
-            LiteralValue::String(_) | LiteralValue::Boolean(_) | LiteralValue::Number(_) => {
-                Ok(BTreeMap::new())
-            }
-            LiteralValue::Null => Err(CompileMessage::new_error(
-                es_expr.loc.into_sl(filename).to_owned(),
-                ParseProgramError::SourceRestrictionError("Null literal not allowed"),
-            )),
+            LiteralValue::String(_)
+            | LiteralValue::Boolean(_)
+            | LiteralValue::Null
+            | LiteralValue::Number(_) => Ok(BTreeMap::new()),
             LiteralValue::RegExp => Err(CompileMessage::new_error(
                 es_expr.loc.into_sl(filename).to_owned(),
                 ParseProgramError::SourceRestrictionError("Regular expression not allowed"),
@@ -858,6 +1065,29 @@ fn pre_parse_expr(
                     })
                 })
         }
+        NodeKind::ArrayExpression(array_expr) => {
+            // array literal, i.e. [a, b, ...]
+            // JS requires left-to-right evaluation
+            let loc = &es_expr.loc;
+            array_expr.elements.iter_mut().try_fold(
+                BTreeMap::new(),
+                |prev, opt_elem| match opt_elem {
+                    Some(elem) => Ok(varusage::merge_series(
+                        prev,
+                        pre_parse_expr(elem, name_ctx, depth, filename)?,
+                    )),
+                    None => Err(CompileMessage::new_error(
+                        loc.into_sl(filename).to_owned(),
+                        ParseProgramError::SourceRestrictionError(
+                            "Array literal cannot have holes",
+                        ),
+                    )),
+                },
+            )
+        }
+        NodeKind::MemberExpression(member_expr) => {
+            pre_parse_member_expr(member_expr, &es_expr.loc, name_ctx, depth, filename)
+        }
         _ => Err(CompileMessage::new_error(
             es_expr.loc.into_sl(filename).to_owned(),
             ParseProgramError::ESTreeError("Expression node expected"),
@@ -865,6 +1095,29 @@ fn pre_parse_expr(
     }
 }
 
+fn pre_parse_member_expr(
+    member_expr: &mut MemberExpression,
+    loc: &Option<esSL>,
+    name_ctx: &mut HashMap<String, PreVar>,
+    depth: usize,
+    filename: Option<&str>,
+) -> Result<BTreeMap<VarLocId, Usage>, CompileMessage<ParseProgramError>> {
+    // member access, i.e. a[i]
+    // Source only allows computed member access (on arrays)
+    if !member_expr.computed {
+        return Err(CompileMessage::new_error(
+            loc.into_sl(filename).to_owned(),
+            ParseProgramError::SourceRestrictionError(
+                "Dot notation not allowed, use a[i] to access array elements",
+            ),
+        ));
+    }
+    // JS requires 'a' to be evaluated first, followed by 'i'
+    let object = pre_parse_expr(&mut member_expr.object, name_ctx, depth, filename)?;
+    let property = pre_parse_expr(&mut member_expr.property, name_ctx, depth, filename)?;
+    Ok(varusage::merge_series(object, property))
+}
+
 fn pre_parse_identifier_use(
     es_id: &mut Identifier,
     loc: &Option<esSL>,
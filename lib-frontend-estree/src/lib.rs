@@ -233,7 +233,548 @@ pub async fn run_frontend<
     };
     ir_program.entry_point = ir_program.add_func(ir_toplevel_func);
 
+    ir::debug_verify(&ir_program, "frontend");
+
     Ok(ir_program)
 }
 
 // END OF NEW THINGS
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    // Fails the test with the first message that the frontend logs.
+    #[derive(Copy, Clone)]
+    struct PanicLogger;
+
+    impl Logger for PanicLogger {
+        fn log<L: projstd::log::Loggable>(&self, content: L) {
+            panic!("{}", content.message());
+        }
+    }
+
+    // Collects the messages that the frontend logs.
+    struct CollectLogger<'a>(&'a std::cell::RefCell<Vec<String>>);
+
+    impl<'a> Logger for CollectLogger<'a> {
+        fn log<L: projstd::log::Loggable>(&self, content: L) {
+            self.0.borrow_mut().push(content.message());
+        }
+    }
+
+    struct NoImports;
+
+    impl ir::interp::Host for NoImports {
+        fn call_import(
+            &mut self,
+            import: &ir::Import,
+            _args: &[ir::interp::Value],
+        ) -> Result<ir::interp::Value, ir::interp::RunError> {
+            Err(ir::interp::RunError::Host(format!(
+                "unexpected import {}",
+                import.entity_name
+            )))
+        }
+    }
+
+    async fn fetch_nothing(_name: String) -> Option<String> {
+        None
+    }
+
+    // Compiles the ESTree program (with the given statements) and runs it with the IR interpreter.
+    fn run(body: Vec<Value>) -> Result<ir::interp::Value, ir::interp::RunError> {
+        let estree_str = json!({ "type": "Program", "body": body }).to_string();
+        let program =
+            futures::executor::block_on(run_frontend(estree_str, fetch_nothing, PanicLogger))
+                .unwrap();
+        ir::interp::run(&program, &mut NoImports)
+    }
+
+    // Compiles the ESTree program (with the given statements), which should fail, and returns the logged messages.
+    fn compile_errors(body: Vec<Value>) -> Vec<String> {
+        let estree_str = json!({ "type": "Program", "body": body }).to_string();
+        let messages = std::cell::RefCell::new(Vec::new());
+        let result = futures::executor::block_on(run_frontend(
+            estree_str,
+            fetch_nothing,
+            CollectLogger(&messages),
+        ));
+        assert!(result.is_err());
+        messages.into_inner()
+    }
+
+    // Helpers to build ESTree nodes (without locations, which the frontend doesn't need).
+    fn ident(name: &str) -> Value {
+        json!({ "type": "Identifier", "name": name })
+    }
+    fn num(value: f64) -> Value {
+        json!({ "type": "Literal", "value": value })
+    }
+    fn array(elements: Vec<Value>) -> Value {
+        json!({ "type": "ArrayExpression", "elements": elements })
+    }
+    fn member(object: Value, property: Value) -> Value {
+        json!({ "type": "MemberExpression", "object": object, "property": property, "computed": true })
+    }
+    fn null() -> Value {
+        json!({ "type": "Literal", "value": null })
+    }
+    fn call(callee: &str, arguments: Vec<Value>) -> Value {
+        json!({ "type": "CallExpression", "callee": ident(callee), "arguments": arguments })
+    }
+    fn boolean(value: bool) -> Value {
+        json!({ "type": "Literal", "value": value })
+    }
+    fn arrow(params: &[&str], body: Value) -> Value {
+        let params: Vec<Value> = params.iter().map(|param| ident(param)).collect();
+        json!({ "type": "ArrowFunctionExpression", "params": params, "body": body, "expression": true })
+    }
+    fn binary(operator: &str, left: Value, right: Value) -> Value {
+        json!({ "type": "BinaryExpression", "operator": operator, "left": left, "right": right })
+    }
+    fn assign(left: Value, right: Value) -> Value {
+        json!({ "type": "AssignmentExpression", "operator": "=", "left": left, "right": right })
+    }
+    fn declare(kind: &str, name: &str, init: Value) -> Value {
+        json!({
+            "type": "VariableDeclaration",
+            "kind": kind,
+            "declarations": [{ "type": "VariableDeclarator", "id": ident(name), "init": init }]
+        })
+    }
+    fn stmt(expression: Value) -> Value {
+        json!({ "type": "ExpressionStatement", "expression": expression })
+    }
+    fn while_loop(test: Value, body: Vec<Value>) -> Value {
+        json!({
+            "type": "WhileStatement",
+            "test": test,
+            "body": { "type": "BlockStatement", "body": body }
+        })
+    }
+    fn for_loop(init: Value, test: Value, update: Value, body: Vec<Value>) -> Value {
+        json!({
+            "type": "ForStatement",
+            "init": init,
+            "test": test,
+            "update": update,
+            "body": { "type": "BlockStatement", "body": body }
+        })
+    }
+    fn if_else(test: Value, consequent: Vec<Value>, alternate: Vec<Value>) -> Value {
+        json!({
+            "type": "IfStatement",
+            "test": test,
+            "consequent": { "type": "BlockStatement", "body": consequent },
+            "alternate": { "type": "BlockStatement", "body": alternate }
+        })
+    }
+    fn arrow_block(params: &[&str], body: Vec<Value>) -> Value {
+        let params: Vec<Value> = params.iter().map(|param| ident(param)).collect();
+        json!({
+            "type": "ArrowFunctionExpression",
+            "params": params,
+            "body": block(body),
+            "expression": false
+        })
+    }
+    fn block(body: Vec<Value>) -> Value {
+        json!({ "type": "BlockStatement", "body": body })
+    }
+    fn break_stmt() -> Value {
+        json!({ "type": "BreakStatement", "label": null })
+    }
+    fn continue_stmt() -> Value {
+        json!({ "type": "ContinueStatement", "label": null })
+    }
+
+    fn assert_number(result: Result<ir::interp::Value, ir::interp::RunError>, expected: f64) {
+        match result {
+            Ok(ir::interp::Value::Number(value)) if value == expected => {}
+            other => panic!("expected {}, got {:?}", expected, other),
+        }
+    }
+
+    fn assert_boolean(result: Result<ir::interp::Value, ir::interp::RunError>, expected: bool) {
+        match result {
+            Ok(ir::interp::Value::Boolean(value)) if value == expected => {}
+            other => panic!("expected {}, got {:?}", expected, other),
+        }
+    }
+
+    fn assert_trap(result: Result<ir::interp::Value, ir::interp::RunError>, expected: u32) {
+        match result {
+            Err(ir::interp::RunError::Trap { code, .. }) if code == expected => {}
+            other => panic!("expected trap {:#x}, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn array_literal_and_member_read() {
+        // const xs = [1, 2, 3]; xs[1];
+        let result = run(vec![
+            declare("const", "xs", array(vec![num(1.0), num(2.0), num(3.0)])),
+            stmt(member(ident("xs"), num(1.0))),
+        ]);
+        assert_number(result, 2.0);
+    }
+
+    #[test]
+    fn array_grows_with_member_writes() {
+        // const xs = []; let i = 0; while (i < 100) { xs[i] = i * 10; i = i + 1; } array_length(xs) + xs[57];
+        let result = run(vec![
+            declare("const", "xs", array(vec![])),
+            declare("let", "i", num(0.0)),
+            while_loop(
+                binary("<", ident("i"), num(100.0)),
+                vec![
+                    stmt(assign(
+                        member(ident("xs"), ident("i")),
+                        binary("*", ident("i"), num(10.0)),
+                    )),
+                    stmt(assign(ident("i"), binary("+", ident("i"), num(1.0)))),
+                ],
+            ),
+            stmt(binary(
+                "+",
+                call("array_length", vec![ident("xs")]),
+                member(ident("xs"), num(57.0)),
+            )),
+        ]);
+        assert_number(result, 670.0);
+    }
+
+    #[test]
+    fn array_holes_and_out_of_bounds_reads() {
+        // const xs = [1]; xs[5] = 6; array_length(xs);
+        let result = run(vec![
+            declare("const", "xs", array(vec![num(1.0)])),
+            stmt(assign(member(ident("xs"), num(5.0)), num(6.0))),
+            stmt(call("array_length", vec![ident("xs")])),
+        ]);
+        assert_number(result, 6.0);
+
+        // const xs = [1]; xs[5] = 6; xs[3];
+        let result = run(vec![
+            declare("const", "xs", array(vec![num(1.0)])),
+            stmt(assign(member(ident("xs"), num(5.0)), num(6.0))),
+            stmt(member(ident("xs"), num(3.0))),
+        ]);
+        assert!(matches!(result, Ok(ir::interp::Value::Undefined)));
+
+        // const xs = [1]; xs[7];
+        let result = run(vec![
+            declare("const", "xs", array(vec![num(1.0)])),
+            stmt(member(ident("xs"), num(7.0))),
+        ]);
+        assert!(matches!(result, Ok(ir::interp::Value::Undefined)));
+    }
+
+    #[test]
+    fn is_array() {
+        // is_array([1]);
+        let result = run(vec![stmt(call("is_array", vec![array(vec![num(1.0)])]))]);
+        assert!(matches!(result, Ok(ir::interp::Value::Boolean(true))));
+
+        // is_array(1);
+        let result = run(vec![stmt(call("is_array", vec![num(1.0)]))]);
+        assert!(matches!(result, Ok(ir::interp::Value::Boolean(false))));
+    }
+
+    #[test]
+    fn pair_head_and_tail() {
+        // head(tail(pair(1, pair(2, null))));
+        let list = call("pair", vec![num(1.0), call("pair", vec![num(2.0), null()])]);
+        let result = run(vec![stmt(call("head", vec![call("tail", vec![list])]))]);
+        assert_number(result, 2.0);
+
+        // tail(tail(pair(1, pair(2, null))));
+        let list = call("pair", vec![num(1.0), call("pair", vec![num(2.0), null()])]);
+        let result = run(vec![stmt(call("tail", vec![call("tail", vec![list])]))]);
+        assert!(matches!(result, Ok(ir::interp::Value::Null)));
+    }
+
+    #[test]
+    fn head_and_tail_of_non_pair() {
+        // head(1);
+        let result = run(vec![stmt(call("head", vec![num(1.0)]))]);
+        assert_trap(result, ir::error::ERROR_CODE_HEAD_PARAM_TYPE);
+
+        // tail(null);
+        let result = run(vec![stmt(call("tail", vec![null()]))]);
+        assert_trap(result, ir::error::ERROR_CODE_TAIL_PARAM_TYPE);
+    }
+
+    #[test]
+    fn is_pair_and_is_null() {
+        let cases = vec![
+            (
+                call("is_pair", vec![call("pair", vec![num(1.0), num(2.0)])]),
+                true,
+            ),
+            (call("is_pair", vec![null()]), false),
+            (call("is_null", vec![null()]), true),
+            (
+                call("is_null", vec![call("pair", vec![num(1.0), null()])]),
+                false,
+            ),
+            (call("is_null", vec![num(0.0)]), false),
+        ];
+        for (expr, expected) in cases {
+            assert_boolean(run(vec![stmt(expr)]), expected);
+        }
+    }
+
+    #[test]
+    fn strict_equality_of_objects() {
+        // const p = pair(1, 2); p === p;
+        let result = run(vec![
+            declare("const", "p", call("pair", vec![num(1.0), num(2.0)])),
+            stmt(binary("===", ident("p"), ident("p"))),
+        ]);
+        assert_boolean(result, true);
+
+        // const p = pair(1, 2); p !== p;
+        let result = run(vec![
+            declare("const", "p", call("pair", vec![num(1.0), num(2.0)])),
+            stmt(binary("!==", ident("p"), ident("p"))),
+        ]);
+        assert_boolean(result, false);
+
+        // pair(1, 2) === pair(1, 2);
+        let result = run(vec![stmt(binary(
+            "===",
+            call("pair", vec![num(1.0), num(2.0)]),
+            call("pair", vec![num(1.0), num(2.0)]),
+        ))]);
+        assert_boolean(result, false);
+
+        // const xs = [1]; xs === xs;
+        let result = run(vec![
+            declare("const", "xs", array(vec![num(1.0)])),
+            stmt(binary("===", ident("xs"), ident("xs"))),
+        ]);
+        assert_boolean(result, true);
+
+        // [1] === [1];
+        let result = run(vec![stmt(binary(
+            "===",
+            array(vec![num(1.0)]),
+            array(vec![num(1.0)]),
+        ))]);
+        assert_boolean(result, false);
+
+        // const f = x => x; f === f;
+        let result = run(vec![
+            declare("const", "f", arrow(&["x"], ident("x"))),
+            stmt(binary("===", ident("f"), ident("f"))),
+        ]);
+        assert_boolean(result, true);
+
+        // (x => x) === (x => x);
+        let result = run(vec![stmt(binary(
+            "===",
+            arrow(&["x"], ident("x")),
+            arrow(&["x"], ident("x")),
+        ))]);
+        assert_boolean(result, false);
+    }
+
+    #[test]
+    fn strict_equality_of_different_types() {
+        // 1 === "1"; pair(1, 2) === null; null === null;
+        let one = json!({ "type": "Literal", "value": "1" });
+        assert_boolean(run(vec![stmt(binary("===", num(1.0), one))]), false);
+        let p = call("pair", vec![num(1.0), num(2.0)]);
+        assert_boolean(run(vec![stmt(binary("===", p, null()))]), false);
+        assert_boolean(run(vec![stmt(binary("===", null(), null()))]), true);
+    }
+
+    // i = i + 1
+    fn increment(name: &str) -> Value {
+        assign(ident(name), binary("+", ident(name), num(1.0)))
+    }
+
+    #[test]
+    fn for_loop_sum() {
+        // let s = 0; for (let i = 0; i < 10; i = i + 1) { s = s + i; } s;
+        let result = run(vec![
+            declare("let", "s", num(0.0)),
+            for_loop(
+                declare("let", "i", num(0.0)),
+                binary("<", ident("i"), num(10.0)),
+                increment("i"),
+                vec![stmt(assign(
+                    ident("s"),
+                    binary("+", ident("s"), ident("i")),
+                ))],
+            ),
+            stmt(ident("s")),
+        ]);
+        assert_number(result, 45.0);
+    }
+
+    #[test]
+    fn while_loop_product() {
+        // let i = 0; let s = 1; while (i < 5) { s = s * 2; i = i + 1; } s;
+        let result = run(vec![
+            declare("let", "i", num(0.0)),
+            declare("let", "s", num(1.0)),
+            while_loop(
+                binary("<", ident("i"), num(5.0)),
+                vec![
+                    stmt(assign(ident("s"), binary("*", ident("s"), num(2.0)))),
+                    stmt(increment("i")),
+                ],
+            ),
+            stmt(ident("s")),
+        ]);
+        assert_number(result, 32.0);
+    }
+
+    #[test]
+    fn for_loop_closure_captures_iteration_binding() {
+        // let f = null; for (let i = 0; i < 3; i = i + 1) { if (i === 1) { f = () => i; } else {} } f();
+        let result = run(vec![
+            declare("let", "f", null()),
+            for_loop(
+                declare("let", "i", num(0.0)),
+                binary("<", ident("i"), num(3.0)),
+                increment("i"),
+                vec![if_else(
+                    binary("===", ident("i"), num(1.0)),
+                    vec![stmt(assign(ident("f"), arrow(&[], ident("i"))))],
+                    vec![],
+                )],
+            ),
+            stmt(call("f", vec![])),
+        ]);
+        assert_number(result, 1.0);
+    }
+
+    #[test]
+    fn loop_condition_must_be_boolean() {
+        // while (1) {}
+        let result = run(vec![while_loop(num(1.0), vec![])]);
+        assert_trap(result, ir::error::ERROR_CODE_LOOP_CONDITION_TYPE);
+
+        // for (let i = 0; i; i = i + 1) {}
+        let result = run(vec![for_loop(
+            declare("let", "i", num(0.0)),
+            ident("i"),
+            increment("i"),
+            vec![],
+        )]);
+        assert_trap(result, ir::error::ERROR_CODE_LOOP_CONDITION_TYPE);
+    }
+
+    #[test]
+    fn break_and_continue_in_nested_blocks() {
+        // let s = 0; let i = 0;
+        // while (true) { i = i + 1; { if (i > 10) { break; } else {} } if (i % 2 === 0) { { continue; } } else {} s = s + i; }
+        // s;
+        let result = run(vec![
+            declare("let", "s", num(0.0)),
+            declare("let", "i", num(0.0)),
+            while_loop(
+                boolean(true),
+                vec![
+                    stmt(increment("i")),
+                    block(vec![if_else(
+                        binary(">", ident("i"), num(10.0)),
+                        vec![break_stmt()],
+                        vec![],
+                    )]),
+                    if_else(
+                        binary("===", binary("%", ident("i"), num(2.0)), num(0.0)),
+                        vec![block(vec![continue_stmt()])],
+                        vec![],
+                    ),
+                    stmt(assign(ident("s"), binary("+", ident("s"), ident("i")))),
+                ],
+            ),
+            stmt(ident("s")),
+        ]);
+        assert_number(result, 25.0);
+    }
+
+    #[test]
+    fn continue_in_for_loop_runs_update() {
+        // let s = 0; for (let i = 0; i < 10; i = i + 1) { if (i % 2 === 0) { continue; } else {} s = s + i; } s;
+        let result = run(vec![
+            declare("let", "s", num(0.0)),
+            for_loop(
+                declare("let", "i", num(0.0)),
+                binary("<", ident("i"), num(10.0)),
+                increment("i"),
+                vec![
+                    if_else(
+                        binary("===", binary("%", ident("i"), num(2.0)), num(0.0)),
+                        vec![continue_stmt()],
+                        vec![],
+                    ),
+                    stmt(assign(ident("s"), binary("+", ident("s"), ident("i")))),
+                ],
+            ),
+            stmt(ident("s")),
+        ]);
+        assert_number(result, 25.0);
+
+        // the closure makes i a per-iteration binding, which has to be copied back before the update:
+        // let s = 0;
+        // for (let i = 0; i < 10; i = i + 1) { const g = () => i; if (i === 2) { i = 7; continue; } else {} s = s + g(); }
+        // s;
+        let result = run(vec![
+            declare("let", "s", num(0.0)),
+            for_loop(
+                declare("let", "i", num(0.0)),
+                binary("<", ident("i"), num(10.0)),
+                increment("i"),
+                vec![
+                    declare("const", "g", arrow(&[], ident("i"))),
+                    if_else(
+                        binary("===", ident("i"), num(2.0)),
+                        vec![stmt(assign(ident("i"), num(7.0))), continue_stmt()],
+                        vec![],
+                    ),
+                    stmt(assign(
+                        ident("s"),
+                        binary("+", ident("s"), call("g", vec![])),
+                    )),
+                ],
+            ),
+            stmt(ident("s")),
+        ]);
+        assert_number(result, 18.0);
+    }
+
+    #[test]
+    fn break_and_continue_outside_loop() {
+        // break;
+        assert_eq!(
+            compile_errors(vec![break_stmt()]),
+            vec!["Break statement must be inside a loop"]
+        );
+
+        // if (true) { continue; } else {}
+        assert_eq!(
+            compile_errors(vec![if_else(boolean(true), vec![continue_stmt()], vec![])]),
+            vec!["Continue statement must be inside a loop"]
+        );
+
+        // the loop does not extend into nested functions:
+        // while (true) { const f = () => { break; }; break; }
+        assert_eq!(
+            compile_errors(vec![while_loop(
+                boolean(true),
+                vec![
+                    declare("const", "f", arrow_block(&[], vec![break_stmt()])),
+                    break_stmt(),
+                ],
+            )]),
+            vec!["Break statement must be inside a loop"]
+        );
+    }
+}
@@ -7,6 +7,17 @@ use std::collections::HashMap;
 pub struct ParseState {
     targets: HashMap<VarLocId, ir::TargetExpr>, // for the Targets
     directs: VarCtx<String, OverloadSet<(Box<[ir::VarType]>, ir::FuncIdx)>>, // for the Directs
+    landings: Landings,                         // for Break and Continue
+}
+
+/**
+ * The jump landings (Blocks and Loops) enclosing the current statement in the current function.
+ * Landings are numbered from the outermost one in the function, starting from zero.
+ */
+#[derive(Default, Clone)]
+pub struct Landings {
+    num_landings: usize,                    // number of enclosing landings
+    innermost_loop: Option<(usize, usize)>, // (break landing, continue landing) of the innermost enclosing loop
 }
 
 // Undoable multiple targets
@@ -134,7 +145,8 @@ impl ParseState {
     }
 }
 
-type ClosureUndoCtx = HashMap<VarLocId, ir::TargetExpr>;
+// A closure cannot see the landings of its enclosing function, so we also reset the landings
+type ClosureUndoCtx = (HashMap<VarLocId, ir::TargetExpr>, Landings);
 impl ParseState {
     pub fn enter_closure(
         &mut self,
@@ -149,9 +161,54 @@ impl ParseState {
         for (varlocid, target_expr) in Vec::from(closed_targets) {
             new_targets.insert(varlocid, target_expr);
         }
-        std::mem::replace(&mut self.targets, new_targets)
+        (
+            std::mem::replace(&mut self.targets, new_targets),
+            std::mem::take(&mut self.landings),
+        )
     }
     pub fn leave_closure(&mut self, undo_ctx: ClosureUndoCtx) {
-        self.targets = undo_ctx;
+        let (targets, landings) = undo_ctx;
+        self.targets = targets;
+        self.landings = landings;
+    }
+}
+
+// Undoable landing (for each ir::ExprKind::Block or ir::ExprKind::Loop that we emit)
+impl ParseState {
+    // Returns the index of the new landing
+    pub fn add_landing(&mut self) -> usize {
+        self.landings.num_landings += 1;
+        self.landings.num_landings - 1
+    }
+    pub fn remove_landing(&mut self) {
+        self.landings.num_landings -= 1;
+    }
+}
+
+// Undoable loop (the landings should already have been added)
+type AddLoopUndoCtx = Option<(usize, usize)>;
+impl ParseState {
+    pub fn add_loop(&mut self, break_landing: usize, continue_landing: usize) -> AddLoopUndoCtx {
+        self.landings
+            .innermost_loop
+            .replace((break_landing, continue_landing))
+    }
+    pub fn remove_loop(&mut self, undo_ctx: AddLoopUndoCtx) {
+        self.landings.innermost_loop = undo_ctx;
+    }
+}
+
+// Get the num_frames of a ir::ExprKind::Break for a BreakStatement or ContinueStatement
+// (returns None if we are not in a loop)
+impl ParseState {
+    pub fn get_break_num_frames(&self) -> Option<usize> {
+        self.landings
+            .innermost_loop
+            .map(|(break_landing, _)| self.landings.num_landings - 1 - break_landing)
+    }
+    pub fn get_continue_num_frames(&self) -> Option<usize> {
+        self.landings
+            .innermost_loop
+            .map(|(_, continue_landing)| self.landings.num_landings - 1 - continue_landing)
     }
 }
@@ -1,7 +1,12 @@
 pub const ERROR_CODE_OUT_OF_MEMORY: u32 = 0x1;
+pub const ERROR_CODE_HEAP_VERIFICATION: u32 = 0x2;
 pub const ERROR_CODE_FUNCTION_PARAM_TYPE: u32 = 0x11;
 pub const ERROR_CODE_UNARY_OPERATOR_PARAM_TYPE: u32 = 0x12;
 pub const ERROR_CODE_BINARY_OPERATOR_PARAM_TYPE: u32 = 0x13;
 pub const ERROR_CODE_FUNCTION_APPLICATION_NOT_CALLABLE_TYPE: u32 = 0x16;
 pub const ERROR_CODE_IF_STATEMENT_CONDITION_TYPE: u32 = 0x17;
+pub const ERROR_CODE_LOOP_CONDITION_TYPE: u32 = 0x18;
 pub const ERROR_CODE_ACCESS_VAR_BEFORE_INIT: u32 = 0x1A;
+pub const ERROR_CODE_HEAD_PARAM_TYPE: u32 = 0x1B;
+pub const ERROR_CODE_TAIL_PARAM_TYPE: u32 = 0x1C;
+pub const ERROR_CODE_ARRAY_INDEX: u32 = 0x1D;
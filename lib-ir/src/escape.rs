@@ -0,0 +1,649 @@
+use super::*;
+
+use std::collections::HashSet;
+
+/**
+ * Interprocedural escape analysis of closure environments.
+ * Backends use this to allocate the environments that are only used within the call that creates them on the stack instead of the heap,
+ * which takes pressure off the GC (e.g. for a callback that is passed to a `map` helper).
+ *
+ * An environment is a Declaration of a struct local that is initialised by a new struct of the same type.
+ * It doesn't escape if its value is only used in these ways:
+ * - through its fields,
+ * - as the closure of a PrimFunc whose overloads don't let their closure param escape (the Func then holds the environment),
+ * - as the callee of an Appl,
+ * - as an arg of a call whose callees are known and don't let that param escape,
+ * - as the init of a Declaration or the test of a narrowing TypeCast (the new local then holds it),
+ * - as the value of a Declaration, TypeCast, Conditional, Block, Loop or Sequence (the parent expr then holds it).
+ *
+ * In particular, it is never assigned anywhere, so nothing on the heap can point to it, and it can't be carried to the next iteration of a Loop.
+ * A param doesn't escape under the same conditions, except that it may also be passed in a tail call.
+ *
+ * The func that creates an environment must stay on the stack while the environment is used,
+ * so an Appl in tail position that might receive the environment has to be made as an ordinary call.
+ * This is only allowed if the callees are known and can't call the func again through tail calls only,
+ * so that a chain of tail calls still runs in constant stack space.
+ */
+pub struct Escape<'a> {
+    envs: Box<[Vec<&'a Expr>]>, // indexed by FuncIdx (including imports), the Declarations of environments that the func may allocate on the stack
+    // Appls in tail position that must be made as ordinary calls
+    // (keyed by address, so the program must not be moved or modified while this struct exists, which is enforced by the lifetime)
+    ordinary_calls: HashSet<*const Expr>,
+}
+
+impl<'a> Escape<'a> {
+    /**
+     * Returns the Declarations of the environments that the given func may allocate on the stack, in the order they appear.
+     */
+    pub fn envs(&self, funcidx: FuncIdx) -> &[&'a Expr] {
+        &self.envs[funcidx]
+    }
+
+    /**
+     * Returns true if the given Appl in tail position must be made as an ordinary call,
+     * because it might receive an environment that is allocated on the stack by the current func.
+     */
+    pub fn ordinary_call(&self, expr: &Expr) -> bool {
+        self.ordinary_calls.contains(&(expr as *const Expr))
+    }
+}
+
+/**
+ * Computes which environments in the program don't escape.
+ */
+pub fn analyze_program(program: &Program) -> Escape<'_> {
+    let num_imports = program.imports.len();
+    let callees = Callees::new(program);
+
+    // find the params that might escape, assuming that none of them do at first
+    // (params that can't hold a Func or a struct are never looked at)
+    let mut params: Vec<Box<[bool]>> = program
+        .imports
+        .iter()
+        .map(|import| vec![true; import.params.len()].into_boxed_slice())
+        .chain(
+            program
+                .funcs
+                .iter()
+                .map(|func| vec![false; func.params.len()].into_boxed_slice()),
+        )
+        .collect();
+    loop {
+        let mut changed = false;
+        for (i, func) in program.funcs.iter().enumerate() {
+            for (paramidx, param) in func.params.iter().enumerate() {
+                if !params[num_imports + i][paramidx] && can_hold(*param) {
+                    let mut tracker = Tracker {
+                        callees: &callees,
+                        params: &params,
+                        tracked: (0..func.params.len()).map(|j| j == paramidx).collect(),
+                        env: None,
+                        tail_calls: Vec::new(),
+                    };
+                    if !matches!(tracker.visit(&func.expr, true), Ok(false)) {
+                        params[num_imports + i][paramidx] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // follow each environment through the func that declares it
+    let mut envs: Vec<Vec<&Expr>> = vec![Vec::new(); num_imports + program.funcs.len()];
+    let mut ordinary_calls: HashSet<*const Expr> = HashSet::new();
+    for (i, func) in program.funcs.iter().enumerate() {
+        let mut candidates: Vec<&Expr> = Vec::new();
+        visit_expr(&func.expr, true, func.params.len(), &mut |expr, _, _| {
+            if is_environment(expr) {
+                candidates.push(expr);
+            }
+        });
+        for env in candidates {
+            let mut tracker = Tracker {
+                callees: &callees,
+                params: &params,
+                tracked: vec![false; func.params.len()],
+                env: Some(env),
+                tail_calls: Vec::new(),
+            };
+            if matches!(tracker.visit(&func.expr, true), Ok(false))
+                && tracker.tail_calls.iter().all(|(_, targets)| {
+                    targets
+                        .as_ref()
+                        .is_some_and(|oes| !callees.reaches_by_tail_calls(oes, num_imports + i))
+                })
+            {
+                envs[num_imports + i].push(env);
+                ordinary_calls.extend(
+                    tracker
+                        .tail_calls
+                        .iter()
+                        .map(|(expr, _)| *expr as *const Expr),
+                );
+            }
+        }
+    }
+
+    Escape {
+        envs: envs.into_boxed_slice(),
+        ordinary_calls,
+    }
+}
+
+// What we know about the callees of Appls.
+struct Callees {
+    globals: Box<[Option<Vec<OverloadEntry>>]>, // the overloads that each global might contain (None if unknown)
+    tail_callees: Box<[Vec<FuncIdx>]>, // indexed by FuncIdx (including imports), the funcs that the func might call in tail position
+}
+
+impl Callees {
+    fn new(program: &Program) -> Callees {
+        let num_imports = program.imports.len();
+
+        // a global contains known overloads if it is only ever assigned PrimFuncs
+        let mut globals: Vec<Option<Vec<OverloadEntry>>> =
+            vec![Some(Vec::new()); program.globals.len()];
+        let mut address_taken: Vec<FuncIdx> = Vec::new();
+        for func in &program.funcs {
+            visit_expr(
+                &func.expr,
+                true,
+                func.params.len(),
+                &mut |expr, _, _| match &expr.kind {
+                    ExprKind::Assign {
+                        target:
+                            TargetExpr::Global {
+                                globalidx,
+                                next: None,
+                            },
+                        expr: rhs_expr,
+                    } => {
+                        let oes = &mut globals[*globalidx];
+                        match (&skip_declarations(rhs_expr).kind, oes.as_mut()) {
+                            (
+                                ExprKind::PrimFunc {
+                                    funcidxs,
+                                    closure: _,
+                                },
+                                Some(oes),
+                            ) => oes.extend(funcidxs.iter().copied()),
+                            _ => *oes = None,
+                        }
+                    }
+                    ExprKind::PrimFunc {
+                        funcidxs,
+                        closure: _,
+                    } => address_taken.extend(funcidxs.iter().map(|oe| oe.funcidx)),
+                    _ => {}
+                },
+            );
+        }
+        let mut callees = Callees {
+            globals: globals.into_boxed_slice(),
+            tail_callees: Default::default(),
+        };
+
+        // an unknown callee might be any func whose address is taken
+        let mut tail_callees: Vec<Vec<FuncIdx>> = vec![Vec::new(); num_imports];
+        for func in &program.funcs {
+            let mut funcidxs: Vec<FuncIdx> = Vec::new();
+            visit_expr(
+                &func.expr,
+                true,
+                func.params.len(),
+                &mut |expr, tail, num_locals| {
+                    if let ExprKind::Appl {
+                        func: func_expr,
+                        args: _,
+                        location: _,
+                    } = &expr.kind
+                    {
+                        if tail {
+                            match callees.resolve(func_expr, num_locals) {
+                                Some(oes) => funcidxs.extend(oes.iter().map(|oe| oe.funcidx)),
+                                None => funcidxs.extend(address_taken.iter().copied()),
+                            }
+                        }
+                    }
+                },
+            );
+            tail_callees.push(funcidxs);
+        }
+        callees.tail_callees = tail_callees.into_boxed_slice();
+        callees
+    }
+
+    // Returns the overloads that the callee of an Appl might contain, or None if unknown.
+    // `num_locals` is the number of locals in scope at the callee.
+    fn resolve(&self, func_expr: &Expr, num_locals: usize) -> Option<Vec<OverloadEntry>> {
+        match &func_expr.kind {
+            ExprKind::PrimFunc {
+                funcidxs,
+                closure: _,
+            } => Some(funcidxs.to_vec()),
+            ExprKind::VarName {
+                source:
+                    TargetExpr::Global {
+                        globalidx,
+                        next: None,
+                    },
+            } => self.globals[*globalidx].clone(),
+            // the frontend narrows the callee to a Func, and traps otherwise
+            ExprKind::TypeCast {
+                test,
+                expected: _,
+                create_narrow_local: true,
+                true_expr,
+                false_expr,
+            } if false_expr.vartype.is_none()
+                && matches!(
+                    true_expr.kind,
+                    ExprKind::VarName {
+                        source: TargetExpr::Local {
+                            localidx,
+                            next: None,
+                        },
+                    } if localidx == num_locals
+                ) =>
+            {
+                self.resolve(test, num_locals)
+            }
+            _ => None,
+        }
+    }
+
+    // Returns true if any of the given overloads might call the given func through tail calls only.
+    fn reaches_by_tail_calls(&self, oes: &[OverloadEntry], funcidx: FuncIdx) -> bool {
+        let mut visited: Vec<bool> = vec![false; self.tail_callees.len()];
+        let mut stack: Vec<FuncIdx> = oes.iter().map(|oe| oe.funcidx).collect();
+        while let Some(curr) = stack.pop() {
+            if curr == funcidx {
+                return true;
+            }
+            if !visited[curr] {
+                visited[curr] = true;
+                stack.extend(self.tail_callees[curr].iter().copied());
+            }
+        }
+        false
+    }
+}
+
+// Marker for a value that escapes.
+struct Escapes;
+
+// Follows a value (an environment, or a param) through a func.
+struct Tracker<'a, 'b> {
+    callees: &'b Callees,
+    params: &'b [Box<[bool]>], // indexed by FuncIdx (including imports), whether each param might escape
+    tracked: Vec<bool>,        // for each local in scope, whether it might hold the value
+    env: Option<&'a Expr>,     // the Declaration of the environment, if we are following one
+    tail_calls: Vec<(&'a Expr, Option<Vec<OverloadEntry>>)>, // the Appls in tail position that might receive the value, and their callees
+}
+
+impl<'a, 'b> Tracker<'a, 'b> {
+    // Returns true if the value of the expr might hold the value, or Err if the value escapes in the expr.
+    fn visit(&mut self, expr: &'a Expr, tail: bool) -> Result<bool, Escapes> {
+        let holds = self.visit_kind(expr, tail)?;
+        Ok(holds && expr.vartype.is_some_and(can_hold))
+    }
+
+    fn visit_kind(&mut self, expr: &'a Expr, tail: bool) -> Result<bool, Escapes> {
+        match &expr.kind {
+            ExprKind::PrimUndefined
+            | ExprKind::PrimNull
+            | ExprKind::PrimNumber { val: _ }
+            | ExprKind::PrimBoolean { val: _ }
+            | ExprKind::PrimString { val: _ }
+            | ExprKind::PrimStructT { typeidx: _ }
+            | ExprKind::Trap {
+                code: _,
+                location: _,
+            } => Ok(false),
+            ExprKind::VarName { source } => Ok(match source {
+                TargetExpr::Local {
+                    localidx,
+                    next: None,
+                } => self.tracked[*localidx],
+                _ => false,
+            }),
+            ExprKind::PrimFunc { funcidxs, closure } => {
+                if !self.visit(closure, false)? {
+                    return Ok(false);
+                }
+                if funcidxs
+                    .iter()
+                    .any(|oe| oe.has_closure_param && self.param_escapes(oe.funcidx, 0))
+                {
+                    return Err(Escapes);
+                }
+                Ok(true)
+            }
+            ExprKind::TypeCast {
+                test,
+                expected: _,
+                create_narrow_local,
+                true_expr,
+                false_expr,
+            } => {
+                let holds = self.visit(test, false)?;
+                if *create_narrow_local {
+                    self.tracked.push(holds);
+                }
+                let true_holds = self.visit(true_expr, tail)?;
+                if *create_narrow_local {
+                    self.tracked.pop();
+                }
+                let false_holds = self.visit(false_expr, tail)?;
+                Ok(true_holds || false_holds)
+            }
+            ExprKind::PrimAppl { prim_inst: _, args } => {
+                for arg in args.iter() {
+                    if self.visit(arg, false)? {
+                        return Err(Escapes);
+                    }
+                }
+                Ok(false)
+            }
+            ExprKind::Appl {
+                func,
+                args,
+                location: _,
+            } => {
+                let mut receives = self.visit(func, false)?;
+                let callees = self.callees.resolve(func, self.tracked.len());
+                for (i, arg) in args.iter().enumerate() {
+                    if self.visit(arg, false)? {
+                        receives = true;
+                        let escapes = callees.as_ref().is_none_or(|oes| {
+                            oes.iter().any(|oe| {
+                                self.param_escapes(oe.funcidx, i + oe.has_closure_param as usize)
+                            })
+                        });
+                        if escapes {
+                            return Err(Escapes);
+                        }
+                    }
+                }
+                if tail && receives {
+                    self.tail_calls.push((expr, callees));
+                }
+                Ok(false)
+            }
+            ExprKind::DirectAppl { funcidx, args } => {
+                for (i, arg) in args.iter().enumerate() {
+                    if self.visit(arg, false)? && self.param_escapes(*funcidx, i) {
+                        return Err(Escapes);
+                    }
+                }
+                Ok(false)
+            }
+            ExprKind::Conditional {
+                cond,
+                true_expr,
+                false_expr,
+            } => {
+                self.visit(cond, false)?;
+                let true_holds = self.visit(true_expr, tail)?;
+                let false_holds = self.visit(false_expr, tail)?;
+                Ok(true_holds || false_holds)
+            }
+            ExprKind::Declaration {
+                local: _,
+                init,
+                contained_expr,
+            } => {
+                let holds = match init {
+                    Some(init_expr) => self.visit(init_expr, false)?,
+                    None => false,
+                };
+                let is_env = self.env.is_some_and(|env| std::ptr::eq(env, expr));
+                self.tracked.push(holds || is_env);
+                let ret = self.visit(contained_expr, tail)?;
+                self.tracked.pop();
+                Ok(ret)
+            }
+            ExprKind::Assign {
+                target: _,
+                expr: inner_expr,
+            }
+            | ExprKind::Return { expr: inner_expr }
+            | ExprKind::Break {
+                num_frames: _,
+                expr: inner_expr,
+            } => {
+                if self.visit(inner_expr, matches!(expr.kind, ExprKind::Return { .. }))? {
+                    return Err(Escapes);
+                }
+                Ok(false)
+            }
+            ExprKind::Block { expr: inner_expr } => self.visit(inner_expr, tail),
+            ExprKind::Loop { expr: inner_expr } => self.visit(inner_expr, false),
+            ExprKind::Sequence { content } => match content.split_last() {
+                Some((last, others)) => {
+                    for inner_expr in others {
+                        self.visit(inner_expr, false)?;
+                    }
+                    self.visit(last, tail)
+                }
+                None => Ok(false),
+            },
+        }
+    }
+
+    fn param_escapes(&self, funcidx: FuncIdx, paramidx: usize) -> bool {
+        self.params[funcidx].get(paramidx).copied().unwrap_or(true)
+    }
+}
+
+// Returns true if a value of the given type might hold an environment.
+fn can_hold(vartype: VarType) -> bool {
+    matches!(
+        vartype,
+        VarType::Any | VarType::Func | VarType::StructT { typeidx: _ }
+    )
+}
+
+// Returns true if the expr is the Declaration of an environment, i.e. a struct local that is initialised by a new struct of the same type.
+fn is_environment(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Declaration {
+            local: VarType::StructT { typeidx },
+            init: Some(init_expr),
+            contained_expr: _,
+        } => {
+            matches!(init_expr.kind, ExprKind::PrimStructT { typeidx: init_typeidx } if init_typeidx == *typeidx)
+        }
+        _ => false,
+    }
+}
+
+// Returns the expr that produces the value of the given expr, looking through Declarations and Sequences.
+fn skip_declarations(expr: &Expr) -> &Expr {
+    match &expr.kind {
+        ExprKind::Declaration {
+            local: _,
+            init: _,
+            contained_expr,
+        } => skip_declarations(contained_expr),
+        ExprKind::Sequence { content } if !content.is_empty() => {
+            skip_declarations(content.last().unwrap())
+        }
+        _ => expr,
+    }
+}
+
+// Calls `f` on the given expr and all its subexprs, with whether the subexpr is in tail position and the number of locals in scope.
+// (This matches where the backend makes tail calls, except that we don't care whether the func returns Any.)
+fn visit_expr<'a>(
+    expr: &'a Expr,
+    tail: bool,
+    num_locals: usize,
+    f: &mut dyn FnMut(&'a Expr, bool, usize),
+) {
+    f(expr, tail, num_locals);
+    // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+    match &expr.kind {
+        ExprKind::PrimUndefined
+        | ExprKind::PrimNull
+        | ExprKind::PrimNumber { val: _ }
+        | ExprKind::PrimBoolean { val: _ }
+        | ExprKind::PrimString { val: _ }
+        | ExprKind::PrimStructT { typeidx: _ }
+        | ExprKind::VarName { source: _ }
+        | ExprKind::Trap {
+            code: _,
+            location: _,
+        } => {}
+        ExprKind::PrimFunc {
+            funcidxs: _,
+            closure: inner_expr,
+        }
+        | ExprKind::Break {
+            num_frames: _,
+            expr: inner_expr,
+        }
+        | ExprKind::Loop { expr: inner_expr }
+        | ExprKind::Assign {
+            target: _,
+            expr: inner_expr,
+        } => visit_expr(inner_expr, false, num_locals, f),
+        ExprKind::Return { expr: inner_expr } => visit_expr(inner_expr, true, num_locals, f),
+        ExprKind::Block { expr: inner_expr } => visit_expr(inner_expr, tail, num_locals, f),
+        ExprKind::TypeCast {
+            test,
+            expected: _,
+            create_narrow_local,
+            true_expr,
+            false_expr,
+        } => {
+            visit_expr(test, false, num_locals, f);
+            visit_expr(
+                true_expr,
+                tail,
+                num_locals + *create_narrow_local as usize,
+                f,
+            );
+            visit_expr(false_expr, tail, num_locals, f);
+        }
+        ExprKind::Conditional {
+            cond,
+            true_expr,
+            false_expr,
+        } => {
+            visit_expr(cond, false, num_locals, f);
+            visit_expr(true_expr, tail, num_locals, f);
+            visit_expr(false_expr, tail, num_locals, f);
+        }
+        ExprKind::PrimAppl {
+            prim_inst: _,
+            args: exprs,
+        }
+        | ExprKind::DirectAppl {
+            funcidx: _,
+            args: exprs,
+        } => {
+            for inner_expr in exprs.iter() {
+                visit_expr(inner_expr, false, num_locals, f);
+            }
+        }
+        ExprKind::Sequence { content } => {
+            if let Some((last, others)) = content.split_last() {
+                for inner_expr in others {
+                    visit_expr(inner_expr, false, num_locals, f);
+                }
+                visit_expr(last, tail, num_locals, f);
+            }
+        }
+        ExprKind::Appl {
+            func,
+            args,
+            location: _,
+        } => {
+            visit_expr(func, false, num_locals, f);
+            for inner_expr in args.iter() {
+                visit_expr(inner_expr, false, num_locals, f);
+            }
+        }
+        ExprKind::Declaration {
+            local: _,
+            init,
+            contained_expr,
+        } => {
+            if let Some(init_expr) = init {
+                visit_expr(init_expr, false, num_locals, f);
+            }
+            visit_expr(contained_expr, tail, num_locals + 1, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_environments_through_calls() {
+        // func#1 only calls its callback, func#2 stores its arg in a global,
+        // func#3 passes a closure to func#1, func#4 passes a closure to func#2,
+        // func#5 passes a closure to func#1 in a tail call
+        let text = r#"
+            struct#0 = (number);
+            global#0 = any;
+            global#1 = func;
+            func#0 (struct#0 any) -> any {
+              (var:number local#0.0:0)
+            }
+            func#1 (any any) -> any {
+              (appl:any 0:1:1-1:2
+                (typecast:func func narrow (var:any local#0) (var:func local#2) (trap:! 0x16 0:1:1-1:2))
+                (var:any local#1))
+            }
+            func#2 (any) -> any {
+              (seq:any
+                (assign:undefined global#0 (var:any local#0))
+                (undefined:undefined))
+            }
+            func#3 () -> any {
+              (decl:any struct#0 (struct:struct#0 struct#0)
+                (seq:any
+                  (call:any func#1 (func:func [func#0:closure] (var:struct#0 local#0)) (number:number 1.0))
+                  (undefined:undefined)))
+            }
+            func#4 () -> any {
+              (decl:any struct#0 (struct:struct#0 struct#0)
+                (seq:any
+                  (call:any func#2 (func:func [func#0:closure] (var:struct#0 local#0)))
+                  (undefined:undefined)))
+            }
+            func#5 () -> any {
+              (decl:any struct#0 (struct:struct#0 struct#0)
+                (appl:any 0:1:1-1:2 (var:func global#1) (func:func [func#0:closure] (var:struct#0 local#0)) (number:number 1.0)))
+            }
+            func#6 () -> any {
+              (seq:any
+                (assign:undefined global#1 (func:func [func#1] (undefined:undefined)))
+                (undefined:undefined))
+            }
+            entry func#6;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let escape = analyze_program(&program);
+        let result: Vec<usize> = (0..7).map(|funcidx| escape.envs(funcidx).len()).collect();
+        assert_eq!(result, vec![0, 0, 0, 1, 0, 1, 0]);
+        let (env, appl) = match &program.funcs[5].expr.kind {
+            ExprKind::Declaration {
+                local: _,
+                init: _,
+                contained_expr,
+            } => (&program.funcs[5].expr, &**contained_expr),
+            _ => unreachable!(),
+        };
+        assert!(std::ptr::eq(escape.envs(5)[0], env));
+        assert!(escape.ordinary_call(appl));
+    }
+}
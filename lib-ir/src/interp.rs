@@ -0,0 +1,739 @@
+/**
+ * A reference interpreter that executes a Program directly, without going through the backend.
+ * It is meant for differential testing: running a Program before and after optimisation (or comparing against the wasm produced by the backend)
+ * should produce the same result, the same calls to imports, and the same trap codes.
+ *
+ * Semantics follow the wasm backend where the IR leaves things open:
+ * * Appl picks the last overload whose param count and non-Any param types match the args exactly, and traps with ERROR_CODE_FUNCTION_PARAM_TYPE otherwise.
+ * * Locals and struct fields without an initializer start as Unassigned (or 0/false/undefined for Number/Boolean/Undefined).
+ * * NumberRem is computed as `a - trunc(a / b) * b`, and strings are compared bytewise.
+ * * Array indices must be integers; ArrayGet returns undefined for out-of-range indices, and ArraySet traps with ERROR_CODE_ARRAY_INDEX.
+ * * Calls in tail position (Appl and DirectAppl) do not use up call depth.
+ *
+ * Additionally, every value is checked against the static type of the expr that produced it,
+ * so that an optimisation that narrows a type unsoundly is reported as RunError::Internal instead of silently miscompiling.
+ *
+ * Each IR call uses a few Rust stack frames, so deeply recursive programs should be run on a thread with a large stack.
+ */
+use super::superset::Superset;
+use super::*;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Maximum number of nested (non-tail) calls before RunError::StackOverflow is returned.
+const MAX_CALL_DEPTH: usize = 10000;
+
+// Arrays indices at or above this will trap when written to (same as the wasm backend).
+const MAX_ARRAY_LENGTH: f64 = (1 << 26) as f64;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Unassigned,
+    Undefined,
+    Number(f64),
+    Boolean(bool),
+    String(Rc<str>),
+    Func {
+        funcidxs: Rc<[OverloadEntry]>,
+        closure: Box<Value>,
+    },
+    Null,
+    Array(Rc<RefCell<Vec<Value>>>),
+    StructT {
+        typeidx: usize,
+        fields: Rc<RefCell<Box<[Value]>>>,
+    },
+}
+
+impl Value {
+    // Returns the dynamic type of this value (i.e. its tag if it is stored in an Any).
+    pub fn vartype(&self) -> VarType {
+        match self {
+            Value::Unassigned => VarType::Unassigned,
+            Value::Undefined => VarType::Undefined,
+            Value::Number(_) => VarType::Number,
+            Value::Boolean(_) => VarType::Boolean,
+            Value::String(_) => VarType::String,
+            Value::Func { .. } => VarType::Func,
+            Value::Null => VarType::Null,
+            Value::Array(_) => VarType::Array,
+            Value::StructT { typeidx, .. } => VarType::StructT { typeidx: *typeidx },
+        }
+    }
+
+    // The value of a variable of the given type that was declared without an initializer.
+    fn default_for(vartype: VarType) -> Value {
+        match vartype {
+            VarType::Undefined => Value::Undefined,
+            VarType::Number => Value::Number(0.0),
+            VarType::Boolean => Value::Boolean(false),
+            _ => Value::Unassigned,
+        }
+    }
+
+    fn write_to(&self, f: &mut std::fmt::Formatter, depth: usize) -> std::fmt::Result {
+        match self {
+            Value::Unassigned => f.write_str("(unassigned)"),
+            Value::Undefined => f.write_str("undefined"),
+            Value::Number(val) => write!(f, "{}", val),
+            Value::Boolean(val) => write!(f, "{}", val),
+            Value::String(val) => write!(f, "{:?}", val),
+            Value::Func { .. } => f.write_str("(function)"),
+            Value::Null => f.write_str("null"),
+            Value::Array(elements) => {
+                // arrays may contain themselves, so we don't print deeply nested arrays
+                if depth == 0 {
+                    return f.write_str("[...]");
+                }
+                f.write_str("[")?;
+                for (i, element) in elements.borrow().iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?;
+                    }
+                    element.write_to(f, depth - 1)?;
+                }
+                f.write_str("]")
+            }
+            Value::StructT { typeidx, .. } => write!(f, "(struct#{})", typeidx),
+        }
+    }
+}
+
+/**
+ * Prints the value in a form that can be compared across different runs (pointer identity is not printed).
+ */
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write_to(f, 3)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunError {
+    Trap { code: u32, location: SourceLocation }, // a Trap expr, or a runtime error that the backend would report with the same code
+    Host(String),     // the host aborted the program (e.g. from an import)
+    StackOverflow,    // more than MAX_CALL_DEPTH nested calls
+    Internal(String), // the program is malformed (e.g. a value does not match its static type), which indicates a compiler bug
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RunError::Trap { code, location } => write!(
+                f,
+                "trap {:#x} at {}:{}:{}",
+                code, location.file, location.start.line, location.start.column
+            ),
+            RunError::Host(message) => write!(f, "aborted by host: {}", message),
+            RunError::StackOverflow => f.write_str("stack overflow"),
+            RunError::Internal(message) => write!(f, "ICE: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/**
+ * Provides the imported functions of a Program to the interpreter.
+ */
+pub trait Host {
+    // Calls the import with the given args, which are guaranteed to match the param types of the import.
+    // The returned value should match the result type of the import.
+    fn call_import(&mut self, import: &Import, args: &[Value]) -> Result<Value, RunError>;
+}
+
+/**
+ * Runs the entry point of the program, returning its result.
+ */
+pub fn run<H: Host>(program: &Program, host: &mut H) -> Result<Value, RunError> {
+    let mut interpreter = Interpreter {
+        program,
+        host,
+        globals: program
+            .globals
+            .iter()
+            .map(|vartype| Value::default_for(*vartype))
+            .collect(),
+        locals: Vec::new(),
+        call_depth: 0,
+    };
+    interpreter.call(program.entry_point, Vec::new())
+}
+
+// The ways that control can leave an expr, other than by evaluating to a value.
+enum Unwind {
+    Break { num_frames: usize, value: Value },
+    Return(Value),
+    TailCall { funcidx: FuncIdx, args: Vec<Value> },
+    Error(RunError),
+}
+
+impl From<RunError> for Unwind {
+    fn from(err: RunError) -> Self {
+        Unwind::Error(err)
+    }
+}
+
+type EvalResult = Result<Value, Unwind>;
+
+// A place that can be assigned to, resolved before the assigned expr is evaluated (like the wasm backend does).
+enum Place {
+    Local(usize),
+    Global(usize),
+    Field(Rc<RefCell<Box<[Value]>>>, usize),
+}
+
+struct Interpreter<'a, H: Host> {
+    program: &'a Program,
+    host: &'a mut H,
+    globals: Vec<Value>,
+    locals: Vec<Value>, // locals of the current function (params, followed by the locals in scope)
+    call_depth: usize,
+}
+
+fn internal_error(message: String) -> Unwind {
+    Unwind::Error(RunError::Internal(message))
+}
+
+impl<'a, H: Host> Interpreter<'a, H> {
+    // Calls a function (or import), following tail calls until a value is returned.
+    fn call(&mut self, mut funcidx: FuncIdx, mut args: Vec<Value>) -> Result<Value, RunError> {
+        if self.call_depth == MAX_CALL_DEPTH {
+            return Err(RunError::StackOverflow);
+        }
+        self.call_depth += 1;
+        let saved_locals = std::mem::take(&mut self.locals);
+        let ret = loop {
+            if let Some(import) = self.program.imports.get(funcidx) {
+                break self.host.call_import(import, &args);
+            }
+            let func = self.program.get_func(funcidx);
+            self.locals = args;
+            match self.eval(&func.expr, true) {
+                Ok(value) | Err(Unwind::Return(value)) => break Ok(value),
+                Err(Unwind::TailCall {
+                    funcidx: next_funcidx,
+                    args: next_args,
+                }) => {
+                    funcidx = next_funcidx;
+                    args = next_args;
+                }
+                Err(Unwind::Break { .. }) => {
+                    break Err(RunError::Internal(format!(
+                        "func#{}: Break out of the function",
+                        funcidx
+                    )))
+                }
+                Err(Unwind::Error(err)) => break Err(err),
+            }
+        };
+        self.locals = saved_locals;
+        self.call_depth -= 1;
+        ret
+    }
+
+    // Calls the function now, or hands it over to the caller if we are in tail position.
+    fn call_or_tail_call(&mut self, funcidx: FuncIdx, args: Vec<Value>, tail: bool) -> EvalResult {
+        if tail {
+            Err(Unwind::TailCall { funcidx, args })
+        } else {
+            Ok(self.call(funcidx, args)?)
+        }
+    }
+
+    fn eval_all(&mut self, exprs: &[Expr]) -> Result<Vec<Value>, Unwind> {
+        exprs.iter().map(|expr| self.eval(expr, false)).collect()
+    }
+
+    // Evaluates the expr, and checks that the value fits its static type.
+    // `tail` is true if the value of this expr will be returned from the current function.
+    fn eval(&mut self, expr: &Expr, tail: bool) -> EvalResult {
+        let value = self.eval_kind(expr, tail)?;
+        if let Some(vartype) = expr.vartype {
+            if !vartype.superset(&value.vartype()) {
+                return Err(internal_error(format!(
+                    "expr of type {:?} evaluated to a value of type {:?}: {}",
+                    vartype,
+                    value.vartype(),
+                    text::print_expr(expr).lines().next().unwrap_or("")
+                )));
+            }
+        }
+        Ok(value)
+    }
+
+    fn eval_kind(&mut self, expr: &Expr, tail: bool) -> EvalResult {
+        match &expr.kind {
+            ExprKind::PrimUndefined => Ok(Value::Undefined),
+            ExprKind::PrimNull => Ok(Value::Null),
+            ExprKind::PrimNumber { val } => Ok(Value::Number(*val)),
+            ExprKind::PrimBoolean { val } => Ok(Value::Boolean(*val)),
+            ExprKind::PrimString { val } => Ok(Value::String(val.as_str().into())),
+            ExprKind::PrimStructT { typeidx } => Ok(Value::StructT {
+                typeidx: *typeidx,
+                fields: Rc::new(RefCell::new(
+                    self.program.struct_types[*typeidx]
+                        .iter()
+                        .map(|vartype| Value::default_for(*vartype))
+                        .collect(),
+                )),
+            }),
+            ExprKind::PrimFunc { funcidxs, closure } => Ok(Value::Func {
+                funcidxs: funcidxs.iter().copied().collect(),
+                closure: Box::new(self.eval(closure, false)?),
+            }),
+            ExprKind::TypeCast {
+                test,
+                expected,
+                create_narrow_local,
+                true_expr,
+                false_expr,
+            } => {
+                let value = self.eval(test, false)?;
+                if value.vartype() == *expected {
+                    if *create_narrow_local {
+                        self.locals.push(value);
+                        let ret = self.eval(true_expr, tail);
+                        self.locals.pop();
+                        ret
+                    } else {
+                        self.eval(true_expr, tail)
+                    }
+                } else {
+                    self.eval(false_expr, tail)
+                }
+            }
+            ExprKind::VarName { source } => self.read_target(source),
+            ExprKind::PrimAppl { prim_inst, args } => {
+                let args = self.eval_all(args)?;
+                self.eval_prim_inst(*prim_inst, &args)
+            }
+            ExprKind::Appl {
+                func,
+                args,
+                location,
+            } => {
+                let func_value = self.eval(func, false)?;
+                let args = self.eval_all(args)?;
+                let (funcidxs, closure) = match func_value {
+                    Value::Func { funcidxs, closure } => (funcidxs, closure),
+                    other => {
+                        return Err(internal_error(format!(
+                            "cannot call a value of type {:?}",
+                            other.vartype()
+                        )))
+                    }
+                };
+                // overloads are matched from back to front
+                let chosen = funcidxs.iter().rev().find(|oe| {
+                    let params: &[VarType] = &self.program.get_func(oe.funcidx).params;
+                    let params = if oe.has_closure_param {
+                        &params[1..]
+                    } else {
+                        params
+                    };
+                    params.len() == args.len()
+                        && params
+                            .iter()
+                            .zip(args.iter())
+                            .all(|(param, arg)| *param == VarType::Any || *param == arg.vartype())
+                });
+                match chosen {
+                    Some(oe) => {
+                        let full_args = if oe.has_closure_param {
+                            std::iter::once(*closure).chain(args).collect()
+                        } else {
+                            args
+                        };
+                        self.call_or_tail_call(oe.funcidx, full_args, tail)
+                    }
+                    None => Err(RunError::Trap {
+                        code: error::ERROR_CODE_FUNCTION_PARAM_TYPE,
+                        location: *location,
+                    }
+                    .into()),
+                }
+            }
+            ExprKind::DirectAppl { funcidx, args } => {
+                let args = self.eval_all(args)?;
+                self.call_or_tail_call(*funcidx, args, tail)
+            }
+            ExprKind::Conditional {
+                cond,
+                true_expr,
+                false_expr,
+            } => match self.eval(cond, false)? {
+                Value::Boolean(true) => self.eval(true_expr, tail),
+                Value::Boolean(false) => self.eval(false_expr, tail),
+                other => Err(internal_error(format!(
+                    "condition has type {:?}",
+                    other.vartype()
+                ))),
+            },
+            ExprKind::Declaration {
+                local,
+                init,
+                contained_expr,
+            } => {
+                let value = match init {
+                    Some(init_expr) => self.eval(init_expr, false)?,
+                    None => Value::default_for(*local),
+                };
+                self.locals.push(value);
+                let ret = self.eval(contained_expr, tail);
+                self.locals.pop();
+                ret
+            }
+            ExprKind::Assign {
+                target,
+                expr: rhs_expr,
+            } => {
+                let place = self.resolve_place(target)?;
+                let value = self.eval(rhs_expr, false)?;
+                match place {
+                    Place::Local(localidx) => self.locals[localidx] = value,
+                    Place::Global(globalidx) => self.globals[globalidx] = value,
+                    Place::Field(fields, fieldidx) => fields.borrow_mut()[fieldidx] = value,
+                }
+                Ok(Value::Undefined)
+            }
+            ExprKind::Return { expr: inner_expr } => {
+                Err(Unwind::Return(self.eval(inner_expr, true)?))
+            }
+            ExprKind::Break {
+                num_frames,
+                expr: inner_expr,
+            } => Err(Unwind::Break {
+                num_frames: *num_frames,
+                value: self.eval(inner_expr, false)?,
+            }),
+            ExprKind::Block { expr: inner_expr } => match self.eval(inner_expr, tail) {
+                Err(Unwind::Break {
+                    num_frames: 0,
+                    value,
+                }) => Ok(value),
+                Err(Unwind::Break { num_frames, value }) => Err(Unwind::Break {
+                    num_frames: num_frames - 1,
+                    value,
+                }),
+                ret => ret,
+            },
+            ExprKind::Loop { expr: inner_expr } => loop {
+                match self.eval(inner_expr, tail) {
+                    Err(Unwind::Break { num_frames: 0, .. }) => {}
+                    Err(Unwind::Break { num_frames, value }) => {
+                        return Err(Unwind::Break {
+                            num_frames: num_frames - 1,
+                            value,
+                        })
+                    }
+                    ret => return ret,
+                }
+            },
+            ExprKind::Sequence { content } => match content.split_last() {
+                Some((last, others)) => {
+                    for inner_expr in others {
+                        self.eval(inner_expr, false)?;
+                    }
+                    self.eval(last, tail)
+                }
+                None => Ok(Value::Undefined),
+            },
+            ExprKind::Trap { code, location } => Err(RunError::Trap {
+                code: *code,
+                location: *location,
+            }
+            .into()),
+        }
+    }
+
+    fn resolve_place(&mut self, target: &TargetExpr) -> Result<Place, Unwind> {
+        let (root, mut next) = match target {
+            TargetExpr::Global { globalidx, next } => (Place::Global(*globalidx), next),
+            TargetExpr::Local { localidx, next } => (Place::Local(*localidx), next),
+        };
+        let mut place = root;
+        while let Some(field) = next {
+            let value = match &place {
+                Place::Local(localidx) => self.locals[*localidx].clone(),
+                Place::Global(globalidx) => self.globals[*globalidx].clone(),
+                Place::Field(fields, fieldidx) => fields.borrow()[*fieldidx].clone(),
+            };
+            match value {
+                Value::StructT { typeidx, fields } if typeidx == field.typeidx => {
+                    place = Place::Field(fields, field.fieldidx);
+                }
+                other => {
+                    return Err(internal_error(format!(
+                        "cannot access field of struct#{} from a value of type {:?}",
+                        field.typeidx,
+                        other.vartype()
+                    )))
+                }
+            }
+            next = &field.next;
+        }
+        Ok(place)
+    }
+
+    fn read_target(&mut self, target: &TargetExpr) -> EvalResult {
+        Ok(match self.resolve_place(target)? {
+            Place::Local(localidx) => self.locals[localidx].clone(),
+            Place::Global(globalidx) => self.globals[globalidx].clone(),
+            Place::Field(fields, fieldidx) => fields.borrow()[fieldidx].clone(),
+        })
+    }
+
+    fn eval_prim_inst(&mut self, prim_inst: PrimInst, args: &[Value]) -> EvalResult {
+        let ret = match (prim_inst, args) {
+            (PrimInst::NumberAdd, [Value::Number(a), Value::Number(b)]) => Value::Number(a + b),
+            (PrimInst::NumberSub, [Value::Number(a), Value::Number(b)]) => Value::Number(a - b),
+            (PrimInst::NumberMul, [Value::Number(a), Value::Number(b)]) => Value::Number(a * b),
+            (PrimInst::NumberDiv, [Value::Number(a), Value::Number(b)]) => Value::Number(a / b),
+            (PrimInst::NumberRem, [Value::Number(a), Value::Number(b)]) => {
+                Value::Number(a - (a / b).trunc() * b)
+            }
+            (PrimInst::NumberEq, [Value::Number(a), Value::Number(b)]) => Value::Boolean(a == b),
+            (PrimInst::NumberNeq, [Value::Number(a), Value::Number(b)]) => Value::Boolean(a != b),
+            (PrimInst::NumberGt, [Value::Number(a), Value::Number(b)]) => Value::Boolean(a > b),
+            (PrimInst::NumberLt, [Value::Number(a), Value::Number(b)]) => Value::Boolean(a < b),
+            (PrimInst::NumberGe, [Value::Number(a), Value::Number(b)]) => Value::Boolean(a >= b),
+            (PrimInst::NumberLe, [Value::Number(a), Value::Number(b)]) => Value::Boolean(a <= b),
+            (PrimInst::BooleanEq, [Value::Boolean(a), Value::Boolean(b)]) => Value::Boolean(a == b),
+            (PrimInst::BooleanNeq, [Value::Boolean(a), Value::Boolean(b)]) => {
+                Value::Boolean(a != b)
+            }
+            (PrimInst::BooleanAnd, [Value::Boolean(a), Value::Boolean(b)]) => {
+                Value::Boolean(*a && *b)
+            }
+            (PrimInst::BooleanOr, [Value::Boolean(a), Value::Boolean(b)]) => {
+                Value::Boolean(*a || *b)
+            }
+            (PrimInst::BooleanNot, [Value::Boolean(a)]) => Value::Boolean(!a),
+            (PrimInst::NumberNegate, [Value::Number(a)]) => Value::Number(-a),
+            (PrimInst::StringAdd, [Value::String(a), Value::String(b)]) => {
+                Value::String((a.to_string() + b).into())
+            }
+            (PrimInst::StringEq, [Value::String(a), Value::String(b)]) => Value::Boolean(a == b),
+            (PrimInst::StringNeq, [Value::String(a), Value::String(b)]) => Value::Boolean(a != b),
+            (PrimInst::StringGt, [Value::String(a), Value::String(b)]) => Value::Boolean(a > b),
+            (PrimInst::StringLt, [Value::String(a), Value::String(b)]) => Value::Boolean(a < b),
+            (PrimInst::StringGe, [Value::String(a), Value::String(b)]) => Value::Boolean(a >= b),
+            (PrimInst::StringLe, [Value::String(a), Value::String(b)]) => Value::Boolean(a <= b),
+            (PrimInst::ArrayNew, []) => Value::Array(Rc::new(RefCell::new(Vec::new()))),
+            (PrimInst::ArrayLength, [Value::Array(arr)]) => {
+                Value::Number(arr.borrow().len() as f64)
+            }
+            (PrimInst::ArrayGet, [Value::Array(arr), Value::Number(idx)]) => {
+                let arr = arr.borrow();
+                if is_valid_index(*idx, arr.len() as f64) {
+                    arr[*idx as usize].clone()
+                } else {
+                    Value::Undefined
+                }
+            }
+            (PrimInst::ArraySet, [Value::Array(arr), Value::Number(idx), val]) => {
+                if !is_valid_index(*idx, MAX_ARRAY_LENGTH) {
+                    return Err(RunError::Trap {
+                        code: error::ERROR_CODE_ARRAY_INDEX,
+                        location: Default::default(),
+                    }
+                    .into());
+                }
+                let mut arr = arr.borrow_mut();
+                let i = *idx as usize;
+                if i >= arr.len() {
+                    arr.resize(i + 1, Value::Undefined);
+                }
+                arr[i] = val.clone();
+                Value::Undefined
+            }
+            (PrimInst::AnyEq, [a, b]) => Value::Boolean(strict_equals(a, b)),
+            (PrimInst::AnyNeq, [a, b]) => Value::Boolean(!strict_equals(a, b)),
+            _ => {
+                return Err(internal_error(format!(
+                    "{:?} applied to args of types {:?}",
+                    prim_inst,
+                    args.iter().map(|arg| arg.vartype()).collect::<Vec<_>>()
+                )))
+            }
+        };
+        Ok(ret)
+    }
+}
+
+// Returns true if the values are equal under `===`, i.e. they have the same type and
+// funcs, arrays and structs are the same object (a func is the same if it has the same overloads and the same closure).
+fn strict_equals(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Unassigned, Value::Unassigned)
+        | (Value::Undefined, Value::Undefined)
+        | (Value::Null, Value::Null) => true,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::Boolean(a), Value::Boolean(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (
+            Value::Func {
+                funcidxs: funcidxs_a,
+                closure: closure_a,
+            },
+            Value::Func {
+                funcidxs: funcidxs_b,
+                closure: closure_b,
+            },
+        ) => funcidxs_a == funcidxs_b && strict_equals(closure_a, closure_b),
+        (Value::Array(a), Value::Array(b)) => Rc::ptr_eq(a, b),
+        (Value::StructT { fields: a, .. }, Value::StructT { fields: b, .. }) => Rc::ptr_eq(a, b),
+        _ => false,
+    }
+}
+
+// Returns true if `idx` is an integer in the range [0, bound).
+fn is_valid_index(idx: f64, bound: f64) -> bool {
+    idx >= 0.0 && idx < bound && idx.trunc() == idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the output of the `display` import.
+    #[derive(Default)]
+    struct RecordingHost {
+        output: Vec<String>,
+    }
+
+    impl Host for RecordingHost {
+        fn call_import(&mut self, import: &Import, args: &[Value]) -> Result<Value, RunError> {
+            match import.entity_name.as_str() {
+                "display" => {
+                    self.output.push(args[0].to_string());
+                    Ok(Value::Undefined)
+                }
+                _ => Err(RunError::Host(format!(
+                    "unknown import {}",
+                    import.entity_name
+                ))),
+            }
+        }
+    }
+
+    // sums 0..10 with a loop, displaying the result as a string
+    const SUM_PROGRAM: &str = r#"
+            import#0 = "misc" "display" (string) -> undefined;
+            func#1 (number number) -> number {
+              (prim:number number_add (var:number local#0) (var:number local#1))
+            }
+            func#2 () -> any {
+              (decl:any number (number:number 0.0)
+                (decl:any number (number:number 0.0)
+                  (seq:any
+                    (block:undefined
+                      (loop:!
+                        (if:!
+                          (prim:boolean number_lt (var:number local#1) (number:number 10.0))
+                          (seq:!
+                            (assign:undefined local#0 (call:number func#1 (var:number local#0) (var:number local#1)))
+                            (assign:undefined local#1 (prim:number number_add (var:number local#1) (number:number 1.0)))
+                            (break:! 0 (undefined:undefined)))
+                          (break:! 1 (undefined:undefined)))))
+                    (call:undefined func#0 (prim:string string_add (string:string "sum=") (string:string "45")))
+                    (var:number local#0))))
+            }
+            entry func#2;
+        "#;
+
+    fn run_program(program: &Program) -> (Result<String, RunError>, Vec<String>) {
+        let mut host = RecordingHost::default();
+        let ret = run(program, &mut host).map(|value| value.to_string());
+        (ret, host.output)
+    }
+
+    fn run_text(text: &str) -> (Result<String, RunError>, Vec<String>) {
+        run_program(&text::parse_program(text).unwrap())
+    }
+
+    #[test]
+    fn runs_loops_and_calls() {
+        let (ret, output) = run_text(SUM_PROGRAM);
+        assert_eq!(ret, Ok("45".to_owned()));
+        assert_eq!(output, vec!["\"sum=45\""]);
+    }
+
+    #[test]
+    fn optimization_preserves_behaviour() {
+        let program = text::parse_program(SUM_PROGRAM).unwrap();
+        let expected = run_program(&program);
+        assert_eq!(run_program(&opt::optimize_all(program)), expected);
+    }
+
+    #[test]
+    fn dispatches_overloads_and_traps() {
+        // func#0 is only callable with a number, so calling it with a string traps
+        let text = r#"
+            func#0 (number) -> any {
+              (var:number local#0)
+            }
+            func#1 () -> any {
+              (decl:any func (func:func [func#0] (undefined:undefined))
+                (seq:any
+                  (appl:any 0:1:1-1:2 (var:func local#0) (number:number 1.0))
+                  (appl:any 0:3:4-3:5 (var:func local#0) (string:string "x"))))
+            }
+            entry func#1;
+        "#;
+        let (ret, _) = run_text(text);
+        assert_eq!(
+            ret,
+            Err(RunError::Trap {
+                code: error::ERROR_CODE_FUNCTION_PARAM_TYPE,
+                location: SourceLocation {
+                    file: 0,
+                    start: Position { line: 3, column: 4 },
+                    end: Position { line: 3, column: 5 },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn tail_calls_use_constant_depth() {
+        // counts down from 100000 using a self tail call through a closure
+        let text = r#"
+            global#0 = any;
+            func#0 (any) -> any {
+              (typecast:any number narrow (var:any local#0)
+                (if:any
+                  (prim:boolean number_eq (var:number local#1) (number:number 0.0))
+                  (string:string "done")
+                  (appl:any 0:0:0-0:0
+                    (typecast:func func narrow (var:any global#0) (var:func local#2) (trap:! 0x16 0:0:0-0:0))
+                    (prim:number number_sub (var:number local#1) (number:number 1.0))))
+                (trap:! 0x11 0:0:0-0:0))
+            }
+            func#1 () -> any {
+              (seq:any
+                (assign:undefined global#0 (func:func [func#0] (undefined:undefined)))
+                (call:any func#0 (number:number 100000.0)))
+            }
+            entry func#1;
+        "#;
+        assert_eq!(run_text(text).0, Ok("\"done\"".to_owned()));
+    }
+
+    #[test]
+    fn reports_unsound_types() {
+        let text = r#"
+            func#0 () -> any {
+              (decl:any any (string:string "a")
+                (var:number local#0))
+            }
+            entry func#0;
+        "#;
+        match run_text(text).0 {
+            Err(RunError::Internal(_)) => {}
+            other => panic!("expected an internal error, got {:?}", other),
+        }
+    }
+}
@@ -19,22 +19,29 @@ use std::vec::Vec;
  * *
  * * Pre-generated functions can be something like `+(any, any) -> any`, which will internally query the type of its arguments and then forward it to the `Add` primitive or the builtin concat(string, string) function.
  * *
- * * todo!: For optimisation of eliding storage locals in gc roots during a function call, we need to have information about the lifetime of each local.  This IR should be modified to accomodate it.
- * * * Or we could do some control flow analysis to figure out when the value in a variable is actually useful.
- * * * * E.g. if the current statement never leads to any reads (before being overwritten), then this variable doesn't contain useful information.
- * * * It should also figure out if a variable is read but never written between two function calls, then we can know if we need to pop then push it back to the gc roots, or just tee it from the gc roots into locals.
- * * todo!: Also, functions should be annotated with a flag whether they might do heap allocations.
+ * * For optimisation of eliding storage locals in gc roots during a function call, the `liveness` module does control flow analysis to figure out when the value in a variable is actually useful.
+ * * * E.g. if the current statement never leads to any reads (before being overwritten), then this variable doesn't contain useful information.
+ * * * todo!: It should also figure out if a variable is read but never written between two function calls, then we can know if we need to pop then push it back to the gc roots, or just tee it from the gc roots into locals.
+ * * Also, whether each function might do heap allocations is computed by the `may_allocate` module.
  */
 pub mod error;
+pub mod escape;
+pub mod interp;
+pub mod liveness;
+pub mod may_allocate;
 pub mod opt;
 pub mod superset;
+pub mod text;
+mod verify;
 // mod primfunc;
 
+pub use verify::{debug_verify, verify, VerifyError};
+
 // If it stores value `func_idx`, then it refers to imports[func_idx] if (func_idx < imports.len())
 // or funcs[func_idx - imports.len()] otherwise.
 pub type FuncIdx = usize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Program {
     pub struct_types: Vec<Box<[VarType]>>, // stores the list of fields of all structs (i.e. objects) in the program (indexed with typeidx)
     pub imports: Box<[Import]>,            // list of imported functions
@@ -52,6 +59,8 @@ pub enum VarType {
     Boolean,
     String,                     // reference type
     Func,                       // holds a function ptr and a closure
+    Null,                       // the empty list (Source §2); like Undefined, it has no payload
+    Array,                      // reference type; a growable array of Any (Source §3)
     StructT { typeidx: usize }, // reference type; typeid starts from zero and should be in range [0, object_types.len()).
 }
 impl Default for VarType {
@@ -71,11 +80,13 @@ impl VarType {
             VarType::Boolean => 3,
             VarType::String => 4,
             VarType::Func => 5,
+            VarType::Null => 6,
+            VarType::Array => 7,
             VarType::StructT { typeidx } => (NUM_PRIMITIVE_TAG_TYPES + typeidx) as i32,
         }
     }
 }
-pub const NUM_PRIMITIVE_TAG_TYPES: usize = 6; // does not include Any
+pub const NUM_PRIMITIVE_TAG_TYPES: usize = 8; // does not include Any
 
 #[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Hash, Debug)]
 pub struct Import {
@@ -93,7 +104,7 @@ pub enum ImportValType {
     String, // compiles into i32(ptr) parameter, the host should look into our linear memory to figure out the length and the actual string content.
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Func {
     pub params: Box<[VarType]>, // list of function parameters (including closure)
     pub result: Option<VarType>, // if `None`, it means that this function never returns (e.g. it guarantees to trap or infinite loop, see the generated runtime error function)
@@ -146,6 +157,7 @@ pub struct OverloadEntry {
 #[derive(Debug, Clone)]
 pub enum ExprKind {
     PrimUndefined, // also functions as a "no-op"
+    PrimNull,      // e.g. `null`
     PrimNumber {
         val: f64,
     }, // e.g. `2`
@@ -216,11 +228,14 @@ pub enum ExprKind {
     }, // Return statmenent; has Void type
     Break {
         num_frames: usize, // the number of Blocks and Loops to jump out of; zero refers to the Block or Loop closest to this Break
-        expr: Box<Expr>, // the expr that the target Block should return, should not be noreturn, and should fit into the vartype of the target Block.
+        expr: Box<Expr>, // the expr that the target Block should return, should not be noreturn, and should fit into the vartype of the target Block.  If the target is a Loop, expr must have Undefined type (it is evaluated and then discarded).
     }, // jumps to the end of an enclosing Block or the beginning of an enclosing Loop; Break is noreturn
     Block {
         expr: Box<Expr>,
     }, // Jump landing for Break; type must be at least as wide as expr.vartype and all Breaks that target this block
+    Loop {
+        expr: Box<Expr>,
+    }, // Jump landing for Break, but a Break that targets this loop will restart the execution of expr (like a `continue`); if expr finishes normally, then the loop exits and returns the value of expr, so the type of the loop is the type of expr
     Sequence {
         content: Vec<Expr>,
     }, // returns the value of the last expression, or `undefined` if there are zero expressions
@@ -235,7 +250,7 @@ pub enum ExprKind {
 // this is subject to change
 // but the code that converts IR to Wasm needs to use this to generate the appropriate bytecode.
 
-#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug)]
 pub enum PrimInst {
     NumberAdd,
     NumberSub,
@@ -261,8 +276,14 @@ pub enum PrimInst {
     StringLt,
     StringGe,
     StringLe,
+    ArrayNew,
+    ArrayLength,
+    ArrayGet,
+    ArraySet,
+    AnyEq, // strict equality (like `===`): funcs, arrays and structs are equal only if they are the same object
+    AnyNeq,
 }
-pub const NUM_PRIM_INST: u8 = PrimInst::StringLe as u8 + 1;
+pub const NUM_PRIM_INST: u8 = PrimInst::AnyNeq as u8 + 1;
 
 // enum of pre-declared operators
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
@@ -416,6 +437,14 @@ impl PrimInst {
             | Self::StringLt
             | Self::StringGe
             | Self::StringLe => (&[VarType::String, VarType::String], Some(VarType::Boolean)),
+            Self::ArrayNew => (&[], Some(VarType::Array)),
+            Self::ArrayLength => (&[VarType::Array], Some(VarType::Number)),
+            Self::ArrayGet => (&[VarType::Array, VarType::Number], Some(VarType::Any)),
+            Self::ArraySet => (
+                &[VarType::Array, VarType::Number, VarType::Any],
+                Some(VarType::Undefined),
+            ),
+            Self::AnyEq | Self::AnyNeq => (&[VarType::Any, VarType::Any], Some(VarType::Boolean)),
         }
     }
 }
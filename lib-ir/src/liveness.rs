@@ -0,0 +1,280 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/**
+ * Liveness analysis of locals at function call sites.
+ * A local is live after a call if its current value might be read (before it is overwritten) after the call returns.
+ * Backends use this to avoid saving dead locals to the gc roots stack around calls that might allocate memory.
+ *
+ * Locals are numbered the same way as in the IR: params first, then each Declaration (or narrowing TypeCast) introduces the next index.
+ */
+#[derive(Default)]
+pub struct Liveness<'a> {
+    // map from Appl/DirectAppl expr to the set of locals that are live after it returns
+    // (keyed by address, so the func must not be moved or modified while this struct exists, which is enforced by the lifetime)
+    live_after_call: HashMap<*const Expr, LiveSet>,
+    _func: PhantomData<&'a Func>,
+}
+
+impl<'a> Liveness<'a> {
+    /**
+     * Returns the set of locals that are live after the given call (Appl or DirectAppl) returns.
+     * Returns None if the expr is not a call in the analysed function; the caller should then assume that every local is live.
+     */
+    pub fn live_after_call(&self, expr: &Expr) -> Option<&LiveSet> {
+        self.live_after_call.get(&(expr as *const Expr))
+    }
+}
+
+/**
+ * A set of local indices.
+ */
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct LiveSet {
+    content: Vec<bool>,
+}
+
+impl LiveSet {
+    pub fn contains(&self, localidx: usize) -> bool {
+        self.content.get(localidx).copied().unwrap_or(false)
+    }
+    fn insert(&mut self, localidx: usize) {
+        if self.content.len() <= localidx {
+            self.content.resize(localidx + 1, false);
+        }
+        self.content[localidx] = true;
+    }
+    fn remove(&mut self, localidx: usize) {
+        if let Some(x) = self.content.get_mut(localidx) {
+            *x = false;
+        }
+    }
+    fn union_with(&mut self, other: &LiveSet) {
+        if self.content.len() < other.content.len() {
+            self.content.resize(other.content.len(), false);
+        }
+        for (x, y) in self.content.iter_mut().zip(other.content.iter()) {
+            *x |= *y;
+        }
+    }
+}
+
+/**
+ * Computes the liveness of locals at every call in the given function.
+ */
+pub fn analyze_func(func: &Func) -> Liveness<'_> {
+    let mut analyzer = Analyzer {
+        num_locals: func.params.len(),
+        landings: Vec::new(),
+        live_after_call: HashMap::new(),
+    };
+    analyzer.live_before(&func.expr, LiveSet::default());
+    Liveness {
+        live_after_call: analyzer.live_after_call,
+        _func: PhantomData,
+    }
+}
+
+struct Analyzer {
+    num_locals: usize,      // number of locals currently in scope
+    landings: Vec<LiveSet>, // the live set at each landing point (innermost last); for a Loop this is the set at the start of the loop
+    live_after_call: HashMap<*const Expr, LiveSet>,
+}
+
+impl Analyzer {
+    // Returns the set of locals that are live before `expr`, given the set of locals that are live after it.
+    fn live_before(&mut self, expr: &Expr, live_after: LiveSet) -> LiveSet {
+        // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+        match &expr.kind {
+            ExprKind::PrimUndefined
+            | ExprKind::PrimNull
+            | ExprKind::PrimNumber { val: _ }
+            | ExprKind::PrimBoolean { val: _ }
+            | ExprKind::PrimString { val: _ }
+            | ExprKind::PrimStructT { typeidx: _ } => live_after,
+            ExprKind::Trap {
+                code: _,
+                location: _,
+            } => LiveSet::default(),
+            ExprKind::PrimFunc {
+                funcidxs: _,
+                closure,
+            } => self.live_before(closure, live_after),
+            ExprKind::TypeCast {
+                test,
+                expected: _,
+                create_narrow_local,
+                true_expr,
+                false_expr,
+            } => {
+                let mut live = if *create_narrow_local {
+                    let narrow_localidx = self.num_locals;
+                    self.num_locals += 1;
+                    let mut live_true = self.live_before(true_expr, live_after.clone());
+                    self.num_locals -= 1;
+                    live_true.remove(narrow_localidx);
+                    live_true
+                } else {
+                    self.live_before(true_expr, live_after.clone())
+                };
+                live.union_with(&self.live_before(false_expr, live_after));
+                self.live_before(test, live)
+            }
+            ExprKind::VarName { source } => {
+                let mut live = live_after;
+                if let TargetExpr::Local { localidx, next: _ } = source {
+                    live.insert(*localidx);
+                }
+                live
+            }
+            ExprKind::PrimAppl { prim_inst: _, args } => self.live_before_exprs(args, live_after),
+            ExprKind::Appl {
+                func,
+                args,
+                location: _,
+            } => {
+                self.live_after_call
+                    .insert(expr as *const Expr, live_after.clone());
+                let live = self.live_before_exprs(args, live_after);
+                self.live_before(func, live)
+            }
+            ExprKind::DirectAppl { funcidx: _, args } => {
+                self.live_after_call
+                    .insert(expr as *const Expr, live_after.clone());
+                self.live_before_exprs(args, live_after)
+            }
+            ExprKind::Conditional {
+                cond,
+                true_expr,
+                false_expr,
+            } => {
+                let mut live = self.live_before(true_expr, live_after.clone());
+                live.union_with(&self.live_before(false_expr, live_after));
+                self.live_before(cond, live)
+            }
+            ExprKind::Declaration {
+                local: _,
+                init,
+                contained_expr,
+            } => {
+                // the new local is always initialized at the declaration (by the init expr, or to a default value)
+                let localidx = self.num_locals;
+                self.num_locals += 1;
+                let mut live = self.live_before(contained_expr, live_after);
+                self.num_locals -= 1;
+                live.remove(localidx);
+                match init {
+                    Some(init_expr) => self.live_before(init_expr, live),
+                    None => live,
+                }
+            }
+            ExprKind::Assign {
+                target,
+                expr: rhs_expr,
+            } => {
+                let mut live = live_after;
+                match target {
+                    TargetExpr::Local {
+                        localidx,
+                        next: None,
+                    } => live.remove(*localidx),
+                    // writing to a field reads the local (conservatively, after the rhs is evaluated)
+                    TargetExpr::Local {
+                        localidx,
+                        next: Some(_),
+                    } => live.insert(*localidx),
+                    TargetExpr::Global {
+                        globalidx: _,
+                        next: _,
+                    } => {}
+                }
+                self.live_before(rhs_expr, live)
+            }
+            ExprKind::Return { expr: inner_expr } => {
+                self.live_before(inner_expr, LiveSet::default())
+            }
+            ExprKind::Break {
+                num_frames,
+                expr: inner_expr,
+            } => {
+                let live = self.landings[self.landings.len() - 1 - *num_frames].clone();
+                self.live_before(inner_expr, live)
+            }
+            ExprKind::Block { expr: inner_expr } => {
+                self.landings.push(live_after.clone());
+                let live = self.live_before(inner_expr, live_after);
+                self.landings.pop();
+                live
+            }
+            ExprKind::Loop { expr: inner_expr } => {
+                // iterate until the live set at the start of the loop stops growing
+                let mut live_start = LiveSet::default();
+                loop {
+                    self.landings.push(live_start.clone());
+                    let live = self.live_before(inner_expr, live_after.clone());
+                    self.landings.pop();
+                    if live == live_start {
+                        break live;
+                    }
+                    live_start = live;
+                }
+            }
+            ExprKind::Sequence { content } => self.live_before_exprs(content, live_after),
+        }
+    }
+
+    // Like live_before(), but for exprs that are evaluated in order.
+    fn live_before_exprs(&mut self, exprs: &[Expr], live_after: LiveSet) -> LiveSet {
+        exprs
+            .iter()
+            .rev()
+            .fold(live_after, |live, expr| self.live_before(expr, live))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_live_locals_after_calls() {
+        // local#1 is overwritten after the first call, but is read in the next iteration of the loop after the second call,
+        // and local#2 is never read after any call
+        let text = r#"
+            func#0 (boolean any any) -> any {
+              (seq:any
+                (call:any func#0 (var:boolean local#0) (var:any local#1) (var:any local#2))
+                (assign:undefined local#1 (undefined:undefined))
+                (loop:any
+                  (seq:any
+                    (call:any func#0 (var:boolean local#0) (var:any local#1) (var:any local#1))
+                    (if:any (var:boolean local#0)
+                      (break:! 0 (undefined:undefined))
+                      (var:any local#1)))))
+            }
+            entry func#0;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let func = &program.funcs[0];
+        let liveness = analyze_func(func);
+        let (first_call, second_call) = match &func.expr.kind {
+            ExprKind::Sequence { content } => match &content[2].kind {
+                ExprKind::Loop { expr } => match &expr.kind {
+                    ExprKind::Sequence { content: body } => (&content[0], &body[0]),
+                    _ => panic!(),
+                },
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+        let live_locals = |call: &Expr| -> Vec<usize> {
+            let live = liveness.live_after_call(call).unwrap();
+            (0..3).filter(|i| live.contains(*i)).collect()
+        };
+        assert_eq!(live_locals(first_call), vec![0]);
+        assert_eq!(live_locals(second_call), vec![0, 1]);
+        assert!(liveness.live_after_call(&func.expr).is_none());
+    }
+}
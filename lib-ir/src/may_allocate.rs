@@ -0,0 +1,268 @@
+use super::*;
+
+/**
+ * Interprocedural analysis of which funcs might allocate memory on the heap (and hence trigger the GC).
+ * Backends use this to avoid saving locals to the gc roots stack around calls that can never allocate.
+ *
+ * An import might allocate if it returns a string (because the host needs to allocate memory for the string).
+ * A func might allocate if it contains an allocating expr (a struct, or a string/array primitive that might need new memory),
+ * or if it calls a func that might allocate.
+ * For indirect calls (Appl), we conservatively assume that any func whose address is taken (in a PrimFunc) might be called.
+ */
+pub struct MayAllocate {
+    funcs: Box<[bool]>,  // indexed by FuncIdx (including imports)
+    indirect_call: bool, // whether an indirect call might allocate
+}
+
+impl MayAllocate {
+    /**
+     * Returns true if calling the given func might allocate memory.
+     */
+    pub fn func(&self, funcidx: FuncIdx) -> bool {
+        self.funcs[funcidx]
+    }
+
+    /**
+     * Returns true if an indirect call (Appl) might allocate memory.
+     */
+    pub fn indirect_call(&self) -> bool {
+        self.indirect_call
+    }
+
+    /**
+     * Returns true if evaluating the given expr might allocate memory.
+     */
+    pub fn expr(&self, expr: &Expr) -> bool {
+        let mut ret = false;
+        visit_expr(expr, &mut |expr| {
+            ret |= match &expr.kind {
+                ExprKind::DirectAppl { funcidx, args: _ } => self.func(*funcidx),
+                ExprKind::Appl {
+                    func: _,
+                    args: _,
+                    location: _,
+                } => self.indirect_call,
+                _ => expr_kind_allocates(&expr.kind),
+            };
+        });
+        ret
+    }
+}
+
+/**
+ * Computes which funcs in the program might allocate memory.
+ */
+pub fn analyze_program(program: &Program) -> MayAllocate {
+    let num_imports = program.imports.len();
+
+    // what each func does by itself
+    let mut summaries: Vec<FuncSummary> = Vec::with_capacity(program.funcs.len());
+    let mut address_taken: Vec<FuncIdx> = Vec::new();
+    for func in &program.funcs {
+        let mut summary = FuncSummary {
+            allocates: false,
+            has_indirect_call: false,
+            direct_callees: Vec::new(),
+        };
+        visit_expr(&func.expr, &mut |expr| match &expr.kind {
+            ExprKind::DirectAppl { funcidx, args: _ } => summary.direct_callees.push(*funcidx),
+            ExprKind::Appl {
+                func: _,
+                args: _,
+                location: _,
+            } => summary.has_indirect_call = true,
+            ExprKind::PrimFunc {
+                funcidxs,
+                closure: _,
+            } => address_taken.extend(funcidxs.iter().map(|oe| oe.funcidx)),
+            kind => summary.allocates |= expr_kind_allocates(kind),
+        });
+        summaries.push(summary);
+    }
+
+    // propagate through the call graph until nothing changes
+    let mut funcs: Vec<bool> = program
+        .imports
+        .iter()
+        .map(|import| import.result == ImportValType::String)
+        .chain(summaries.iter().map(|summary| summary.allocates))
+        .collect();
+    let mut indirect_call = false;
+    loop {
+        let mut changed = false;
+        if !indirect_call && address_taken.iter().any(|funcidx| funcs[*funcidx]) {
+            indirect_call = true;
+            changed = true;
+        }
+        for (i, summary) in summaries.iter().enumerate() {
+            if !funcs[num_imports + i]
+                && ((summary.has_indirect_call && indirect_call)
+                    || summary.direct_callees.iter().any(|funcidx| funcs[*funcidx]))
+            {
+                funcs[num_imports + i] = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    MayAllocate {
+        funcs: funcs.into_boxed_slice(),
+        indirect_call,
+    }
+}
+
+struct FuncSummary {
+    allocates: bool,              // whether the func itself contains an allocating expr
+    has_indirect_call: bool,      // whether the func contains an Appl
+    direct_callees: Vec<FuncIdx>, // funcs called by DirectAppl
+}
+
+// Returns true if the given expr itself (not including its subexprs) allocates memory.
+// Calls are handled separately.
+fn expr_kind_allocates(kind: &ExprKind) -> bool {
+    match kind {
+        ExprKind::PrimStructT { typeidx: _ } => true,
+        ExprKind::PrimAppl { prim_inst, args: _ } => matches!(
+            prim_inst,
+            PrimInst::StringAdd | PrimInst::ArrayNew | PrimInst::ArraySet
+        ),
+        _ => false,
+    }
+}
+
+// Calls `f` on the given expr and all its subexprs.
+fn visit_expr(expr: &Expr, f: &mut dyn FnMut(&Expr)) {
+    f(expr);
+    // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+    match &expr.kind {
+        ExprKind::PrimUndefined
+        | ExprKind::PrimNull
+        | ExprKind::PrimNumber { val: _ }
+        | ExprKind::PrimBoolean { val: _ }
+        | ExprKind::PrimString { val: _ }
+        | ExprKind::PrimStructT { typeidx: _ }
+        | ExprKind::VarName { source: _ }
+        | ExprKind::Trap {
+            code: _,
+            location: _,
+        } => {}
+        ExprKind::PrimFunc {
+            funcidxs: _,
+            closure: inner_expr,
+        }
+        | ExprKind::Return { expr: inner_expr }
+        | ExprKind::Break {
+            num_frames: _,
+            expr: inner_expr,
+        }
+        | ExprKind::Block { expr: inner_expr }
+        | ExprKind::Loop { expr: inner_expr }
+        | ExprKind::Assign {
+            target: _,
+            expr: inner_expr,
+        } => visit_expr(inner_expr, f),
+        ExprKind::TypeCast {
+            test: first,
+            expected: _,
+            create_narrow_local: _,
+            true_expr: second,
+            false_expr: third,
+        }
+        | ExprKind::Conditional {
+            cond: first,
+            true_expr: second,
+            false_expr: third,
+        } => {
+            visit_expr(first, f);
+            visit_expr(second, f);
+            visit_expr(third, f);
+        }
+        ExprKind::PrimAppl {
+            prim_inst: _,
+            args: exprs,
+        }
+        | ExprKind::DirectAppl {
+            funcidx: _,
+            args: exprs,
+        } => {
+            for inner_expr in exprs.iter() {
+                visit_expr(inner_expr, f);
+            }
+        }
+        ExprKind::Sequence { content } => {
+            for inner_expr in content.iter() {
+                visit_expr(inner_expr, f);
+            }
+        }
+        ExprKind::Appl {
+            func,
+            args,
+            location: _,
+        } => {
+            visit_expr(func, f);
+            for inner_expr in args.iter() {
+                visit_expr(inner_expr, f);
+            }
+        }
+        ExprKind::Declaration {
+            local: _,
+            init,
+            contained_expr,
+        } => {
+            if let Some(init_expr) = init {
+                visit_expr(init_expr, f);
+            }
+            visit_expr(contained_expr, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn propagates_through_calls() {
+        // func#2 is pure arithmetic, func#3 calls an allocating func, func#5 calls an import that returns a string,
+        // func#6 calls indirectly (and func#4 is address taken and allocates), func#7 only calls a non-allocating import
+        let text = r#"
+            import#0 = "misc" "display" (string) -> undefined;
+            import#1 = "misc" "prompt" (string) -> string;
+            func#2 (number) -> number {
+              (prim:number number_add (var:number local#0) (number:number 1.0))
+            }
+            func#3 () -> number {
+              (seq:number
+                (call:string func#4)
+                (call:number func#2 (number:number 1.0)))
+            }
+            func#4 () -> string {
+              (prim:string string_add (string:string "a") (string:string "b"))
+            }
+            func#5 () -> string {
+              (call:string func#1 (string:string "?"))
+            }
+            func#6 (func) -> any {
+              (appl:any 0:1:1-1:2 (var:func local#0))
+            }
+            func#7 () -> any {
+              (seq:any
+                (call:undefined func#0 (string:string "?"))
+                (func:func [func#4] (undefined:undefined)))
+            }
+            entry func#7;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let may_allocate = analyze_program(&program);
+        let result: Vec<bool> = (0..8).map(|funcidx| may_allocate.func(funcidx)).collect();
+        assert_eq!(
+            result,
+            vec![false, true, false, true, true, true, true, false]
+        );
+        assert!(may_allocate.indirect_call());
+        assert!(!may_allocate.expr(&program.funcs[0].expr));
+    }
+}
@@ -0,0 +1,742 @@
+use super::walk::{assigned_params, walk_expr};
+use super::*;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/**
+ * Discretionary optimisation that folds PrimAppls on constants, and reuses the values of PrimAppls that were already computed
+ * (i.e. constant folding and common subexpression elimination).
+ *
+ * Every local that is never assigned to (other than by its Declaration) holds the same value throughout its scope,
+ * so the value of a pure PrimAppl is determined by its PrimInst and the values of its args,
+ * if the args are constants, reads of such locals, or other pure PrimAppls.
+ * A PrimAppl is pure if it doesn't read or write memory that can change (so the array PrimInsts are not pure).
+ * If all the args are constants, the PrimAppl is replaced by the constant that it evaluates to.
+ * This follows the locals that are initialised to constants, since the frontend puts every operand of an operator into a local.
+ * Constants are folded with the same semantics as the runtime: f64 arithmetic, `%` as the remainder of truncating division (i.e. fmod),
+ * and strings compared by their UTF-8 bytes (i.e. by code point).
+ * For strings with characters outside the BMP (U+0000 to U+FFFF), this deliberately differs from JS, which compares UTF-16 code units.
+ *
+ * Otherwise, if a PrimAppl with the same value was already evaluated on every path to this one,
+ * the earlier PrimAppl stores its value in a fresh local, and this one is replaced by a read of that local.
+ * The fresh locals are declared (without initialisation) around the whole function body, so they are in scope everywhere.
+ * So a value computed in only one branch of a Conditional is not available after it,
+ * and a value computed in a Block is only available after it if it was computed before every Break to that Block.
+ * The value of a Block (or Declaration, or Sequence) also has a Key if it is the same on all the ways to leave it,
+ * which finds the constants in the result of a func that got inlined.
+ * The second return value is true if the program got changed, or false otherwise.
+ */
+pub fn optimize(mut program: Program) -> (Program, bool) {
+    let mut changed = false;
+    for func in &mut program.funcs {
+        changed |= optimize_func(func);
+    }
+    (program, changed)
+}
+
+// The value of a pure expr, in terms of constants and the locals that are never assigned to.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Number(u64), // the bits of the f64, so that NaN can be a key
+    Boolean(bool),
+    String(String),
+    Local(usize),
+    Prim(PrimInst, Box<[Key]>),
+}
+
+impl Key {
+    // Returns the number of locals that must be in scope for this value to be accessible.
+    fn depth(&self) -> usize {
+        match self {
+            Key::Number(_) | Key::Boolean(_) | Key::String(_) => 0,
+            Key::Local(localidx) => localidx + 1,
+            Key::Prim(_, args) => args.iter().map(Key::depth).max().unwrap_or(0),
+        }
+    }
+
+    // Converts the constant to an expr, or returns None if it is not a constant.
+    fn to_expr(&self) -> Option<Expr> {
+        let (vartype, kind) = match self {
+            Key::Number(bits) => (
+                VarType::Number,
+                ExprKind::PrimNumber {
+                    val: f64::from_bits(*bits),
+                },
+            ),
+            Key::Boolean(val) => (VarType::Boolean, ExprKind::PrimBoolean { val: *val }),
+            Key::String(val) => (VarType::String, ExprKind::PrimString { val: val.clone() }),
+            Key::Local(_) | Key::Prim(_, _) => return None,
+        };
+        Some(Expr {
+            vartype: Some(vartype),
+            kind,
+        })
+    }
+}
+
+// A PrimAppl whose value can be reused by later PrimAppls with the same Key.
+#[derive(Clone)]
+struct Available {
+    occurrence: usize, // index into Finder::occurrences
+    depth: usize,      // see Key::depth
+}
+
+fn optimize_func(func: &mut Func) -> bool {
+    let num_params = func.params.len();
+    let mut assigned: HashSet<*const Expr> = HashSet::new();
+    find_assigned(&func.expr, &mut vec![None; num_params], &mut assigned);
+    let params_assigned = assigned_params(func);
+
+    let mut finder = Finder {
+        assigned,
+        locals: params_assigned
+            .iter()
+            .enumerate()
+            .map(|(localidx, is_assigned)| {
+                if *is_assigned {
+                    None
+                } else {
+                    Some(Key::Local(localidx))
+                }
+            })
+            .collect(),
+        available: HashMap::new(),
+        landings: Vec::new(),
+        occurrences: Vec::new(),
+        reuses: BTreeMap::new(),
+        changed: false,
+    };
+    finder.visit(&mut func.expr);
+    if finder.reuses.is_empty() {
+        return finder.changed;
+    }
+
+    // give a fresh local to each PrimAppl whose value is reused
+    let defs: BTreeSet<usize> = finder.reuses.values().copied().collect();
+    let fresh_localidxs: HashMap<usize, usize> = defs
+        .iter()
+        .enumerate()
+        .map(|(i, occurrence)| (*occurrence, num_params + i))
+        .collect();
+    let mut actions: HashMap<*const Expr, Action> = HashMap::new();
+    for occurrence in &defs {
+        actions.insert(
+            finder.occurrences[*occurrence].0,
+            Action::Store(fresh_localidxs[occurrence]),
+        );
+    }
+    for (occurrence, def) in &finder.reuses {
+        actions.insert(
+            finder.occurrences[*occurrence].0,
+            Action::Load(fresh_localidxs[def]),
+        );
+    }
+
+    shift_locals(&mut func.expr, num_params, defs.len());
+    walk_expr(&mut func.expr, false, 0, &mut |expr, _, _| {
+        if let Some(action) = actions.get(&(expr as *const Expr)) {
+            let vartype = expr.vartype;
+            let read = |localidx| Expr {
+                vartype,
+                kind: ExprKind::VarName {
+                    source: TargetExpr::Local {
+                        localidx,
+                        next: None,
+                    },
+                },
+            };
+            *expr = match *action {
+                Action::Store(localidx) => Expr {
+                    vartype,
+                    kind: ExprKind::Sequence {
+                        content: vec![
+                            Expr {
+                                vartype: Some(VarType::Undefined),
+                                kind: ExprKind::Assign {
+                                    target: TargetExpr::Local {
+                                        localidx,
+                                        next: None,
+                                    },
+                                    expr: Box::new(std::mem::replace(expr, dummy_expr())),
+                                },
+                            },
+                            read(localidx),
+                        ],
+                    },
+                },
+                Action::Load(localidx) => read(localidx),
+            };
+        }
+    });
+    for occurrence in defs.iter().rev() {
+        let body = std::mem::replace(&mut func.expr, dummy_expr());
+        func.expr = Expr {
+            vartype: body.vartype,
+            kind: ExprKind::Declaration {
+                local: finder.occurrences[*occurrence].1,
+                init: None,
+                contained_expr: Box::new(body),
+            },
+        };
+    }
+    true
+}
+
+enum Action {
+    Store(usize), // evaluate the PrimAppl, and also store its value in the given local
+    Load(usize),  // replace the PrimAppl by a read of the given local
+}
+
+struct Finder {
+    assigned: HashSet<*const Expr>, // Declarations and narrowing TypeCasts whose local is assigned to
+    locals: Vec<Option<Key>>, // the value of each local in scope, or None if it is assigned to
+    available: HashMap<Key, Available>, // the values that were computed on every path to the current expr
+    landings: Vec<Option<Landing>>,     // the enclosing Blocks (or None for Loops)
+    occurrences: Vec<(*const Expr, VarType)>, // the PrimAppls that have a Key, in the order they were visited
+    reuses: BTreeMap<usize, usize>, // occurrence that can be replaced -> earlier occurrence with the same Key
+    changed: bool,
+}
+
+// What is known at the Breaks that jump to the end of a Block.
+struct Landing {
+    states: Vec<HashMap<Key, Available>>, // the available values at each Break
+    keys: Vec<Option<Key>>,               // the Key of the value of each Break
+}
+
+impl Finder {
+    // Folds the PrimAppls in the expr whose args are constants, and records the PrimAppls that can be reused.
+    // Returns the Key of the expr, if it has one.
+    fn visit(&mut self, expr: &mut Expr) -> Option<Key> {
+        let expr_ptr = expr as *const Expr;
+        // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+        match &mut expr.kind {
+            ExprKind::PrimUndefined
+            | ExprKind::PrimNull
+            | ExprKind::PrimStructT { typeidx: _ }
+            | ExprKind::Trap {
+                code: _,
+                location: _,
+            } => None,
+            ExprKind::PrimNumber { val } => Some(Key::Number(val.to_bits())),
+            ExprKind::PrimBoolean { val } => Some(Key::Boolean(*val)),
+            ExprKind::PrimString { val } => Some(Key::String(val.clone())),
+            ExprKind::VarName {
+                source:
+                    TargetExpr::Local {
+                        localidx,
+                        next: None,
+                    },
+            } => self.locals[*localidx].clone(),
+            ExprKind::VarName { source: _ } => None,
+            ExprKind::PrimFunc {
+                funcidxs: _,
+                closure: inner_expr,
+            }
+            | ExprKind::Assign {
+                target: _,
+                expr: inner_expr,
+            }
+            | ExprKind::Return { expr: inner_expr } => {
+                self.visit(inner_expr);
+                None
+            }
+            ExprKind::Break {
+                num_frames,
+                expr: inner_expr,
+            } => {
+                let key = self.visit(inner_expr);
+                let idx = self.landings.len() - 1 - *num_frames;
+                if let Some(landing) = &mut self.landings[idx] {
+                    landing.states.push(self.available.clone());
+                    landing.keys.push(key);
+                }
+                None
+            }
+            ExprKind::Block { expr: inner_expr } => {
+                let num_locals = self.locals.len();
+                self.landings.push(Some(Landing {
+                    states: Vec::new(),
+                    keys: Vec::new(),
+                }));
+                let key = self.visit(inner_expr);
+                let mut landing = self.landings.pop().unwrap().unwrap();
+                if inner_expr.vartype.is_some() {
+                    landing.states.push(std::mem::take(&mut self.available));
+                    landing.keys.push(key);
+                }
+                self.available = intersect(landing.states);
+                self.available
+                    .retain(|_, available| available.depth <= num_locals);
+                // the Block has a Key if all the ways to leave it give the same value
+                let first_key = landing.keys.first().cloned().flatten()?;
+                if first_key.depth() <= num_locals
+                    && landing
+                        .keys
+                        .iter()
+                        .all(|key| key.as_ref() == Some(&first_key))
+                {
+                    Some(first_key)
+                } else {
+                    None
+                }
+            }
+            ExprKind::Loop { expr: inner_expr } => {
+                // the values available before the loop are still available at the start of every iteration,
+                // and the loop only exits when the body finishes normally
+                self.landings.push(None);
+                self.visit(inner_expr);
+                self.landings.pop();
+                None
+            }
+            ExprKind::TypeCast {
+                test,
+                expected: _,
+                create_narrow_local,
+                true_expr,
+                false_expr,
+            } => {
+                let test_key = self.visit(test);
+                let before = self.available.clone();
+                let num_locals = self.locals.len();
+                if *create_narrow_local {
+                    // the narrowed local has the same value as the test
+                    let key = if self.assigned.contains(&expr_ptr) {
+                        None
+                    } else {
+                        Some(test_key.unwrap_or(Key::Local(num_locals)))
+                    };
+                    self.locals.push(key);
+                    self.visit(true_expr);
+                    self.locals.pop();
+                    self.available
+                        .retain(|_, available| available.depth <= num_locals);
+                } else {
+                    self.visit(true_expr);
+                }
+                let true_state = std::mem::replace(&mut self.available, before);
+                self.visit(false_expr);
+                self.join(true_state, true_expr, false_expr);
+                None
+            }
+            ExprKind::Conditional {
+                cond,
+                true_expr,
+                false_expr,
+            } => {
+                self.visit(cond);
+                let before = self.available.clone();
+                self.visit(true_expr);
+                let true_state = std::mem::replace(&mut self.available, before);
+                self.visit(false_expr);
+                self.join(true_state, true_expr, false_expr);
+                None
+            }
+            ExprKind::PrimAppl { prim_inst, args } => {
+                let first_occurrence = self.occurrences.len();
+                // visit all the args, even if some of them don't have a Key
+                let arg_keys: Vec<Option<Key>> =
+                    args.iter_mut().map(|arg| self.visit(arg)).collect();
+                let arg_keys: Box<[Key]> = arg_keys.into_iter().collect::<Option<_>>()?;
+                // the PrimAppl may only be replaced if its args have no side effects
+                if !is_pure(*prim_inst) || !args.iter().all(is_simple) {
+                    return None;
+                }
+                if let Some(key) = fold(*prim_inst, &arg_keys) {
+                    *expr = key.to_expr().unwrap();
+                    self.changed = true;
+                    return Some(key);
+                }
+                let key = Key::Prim(*prim_inst, arg_keys);
+                let occurrence = self.occurrences.len();
+                self.occurrences.push((expr_ptr, expr.vartype.unwrap()));
+                if let Some(def) = self
+                    .available
+                    .get(&key)
+                    .map(|available| available.occurrence)
+                {
+                    // the args won't be evaluated anymore, so nothing can reuse the PrimAppls in them
+                    self.reuses.split_off(&first_occurrence);
+                    self.available
+                        .retain(|_, available| available.occurrence < first_occurrence);
+                    self.reuses.insert(occurrence, def);
+                } else {
+                    let depth = key.depth();
+                    self.available
+                        .insert(key.clone(), Available { occurrence, depth });
+                }
+                Some(key)
+            }
+            ExprKind::Appl {
+                func,
+                args,
+                location: _,
+            } => {
+                self.visit(func);
+                for arg in args.iter_mut() {
+                    self.visit(arg);
+                }
+                None
+            }
+            ExprKind::DirectAppl { funcidx: _, args } => {
+                for arg in args.iter_mut() {
+                    self.visit(arg);
+                }
+                None
+            }
+            ExprKind::Declaration {
+                local: _,
+                init,
+                contained_expr,
+            } => {
+                let num_locals = self.locals.len();
+                let init_key = init.as_mut().and_then(|init_expr| self.visit(init_expr));
+                let key = if self.assigned.contains(&expr_ptr) {
+                    None
+                } else {
+                    Some(init_key.unwrap_or(Key::Local(num_locals)))
+                };
+                self.locals.push(key);
+                let key = self.visit(contained_expr);
+                self.locals.pop();
+                // values that depend on the local can't be reused outside its scope
+                self.available
+                    .retain(|_, available| available.depth <= num_locals);
+                key.filter(|key| key.depth() <= num_locals)
+            }
+            ExprKind::Sequence { content } => {
+                let mut key = None;
+                for inner_expr in content.iter_mut() {
+                    key = self.visit(inner_expr);
+                }
+                key
+            }
+        }
+    }
+
+    // Sets the available values after a Conditional or TypeCast, given the values available at the end of each branch.
+    // The current available values must be those at the end of the false branch.
+    fn join(&mut self, true_state: HashMap<Key, Available>, true_expr: &Expr, false_expr: &Expr) {
+        let false_state = std::mem::take(&mut self.available);
+        let states = std::iter::once((true_state, true_expr))
+            .chain(std::iter::once((false_state, false_expr)))
+            .filter(|(_, expr)| expr.vartype.is_some())
+            .map(|(state, _)| state)
+            .collect();
+        self.available = intersect(states);
+    }
+}
+
+// Returns the values that are available in all the given states.
+fn intersect(states: Vec<HashMap<Key, Available>>) -> HashMap<Key, Available> {
+    let mut states = states.into_iter();
+    let mut ret = states.next().unwrap_or_default();
+    for state in states {
+        ret.retain(|key, available| {
+            state
+                .get(key)
+                .is_some_and(|other| other.occurrence == available.occurrence)
+        });
+    }
+    ret
+}
+
+// Returns true if evaluating the expr has no side effects, assuming that it has a Key.
+fn is_simple(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::PrimNumber { .. }
+            | ExprKind::PrimBoolean { .. }
+            | ExprKind::PrimString { .. }
+            | ExprKind::VarName { .. }
+            | ExprKind::PrimAppl { .. }
+    )
+}
+
+/**
+ * Evaluates the PrimAppl with the given args at compile time, if all the args are constants.
+ * Propagate also uses this, so that constants are folded in the same way everywhere.
+ */
+pub(super) fn fold_constants(prim_inst: PrimInst, args: &[Expr]) -> Option<Expr> {
+    let arg_keys = args
+        .iter()
+        .map(|arg| match &arg.kind {
+            ExprKind::PrimNumber { val } => Some(Key::Number(val.to_bits())),
+            ExprKind::PrimBoolean { val } => Some(Key::Boolean(*val)),
+            ExprKind::PrimString { val } => Some(Key::String(val.clone())),
+            _ => None,
+        })
+        .collect::<Option<Box<[Key]>>>()?;
+    fold(prim_inst, &arg_keys).map(|key| key.to_expr().unwrap())
+}
+
+// Returns true if the PrimInst always gives the same result for the same args, and has no side effects.
+fn is_pure(prim_inst: PrimInst) -> bool {
+    !matches!(
+        prim_inst,
+        PrimInst::ArrayNew | PrimInst::ArrayLength | PrimInst::ArrayGet | PrimInst::ArraySet
+    )
+}
+
+// Evaluates the PrimInst, if all its args are constants.
+fn fold(prim_inst: PrimInst, args: &[Key]) -> Option<Key> {
+    let number = |val: f64| Key::Number(val.to_bits());
+    Some(match (prim_inst, args) {
+        (PrimInst::NumberNegate, [Key::Number(a)]) => number(-f64::from_bits(*a)),
+        (_, [Key::Number(a), Key::Number(b)]) => {
+            let (a, b) = (f64::from_bits(*a), f64::from_bits(*b));
+            match prim_inst {
+                PrimInst::NumberAdd => number(a + b),
+                PrimInst::NumberSub => number(a - b),
+                PrimInst::NumberMul => number(a * b),
+                PrimInst::NumberDiv => number(a / b),
+                PrimInst::NumberRem => number(a % b), // Rust's `%` on f64 is fmod, just like JS
+                PrimInst::NumberEq => Key::Boolean(a == b),
+                PrimInst::NumberNeq => Key::Boolean(a != b),
+                PrimInst::NumberGt => Key::Boolean(a > b),
+                PrimInst::NumberLt => Key::Boolean(a < b),
+                PrimInst::NumberGe => Key::Boolean(a >= b),
+                PrimInst::NumberLe => Key::Boolean(a <= b),
+                _ => return None,
+            }
+        }
+        (PrimInst::BooleanNot, [Key::Boolean(a)]) => Key::Boolean(!a),
+        (_, [Key::Boolean(a), Key::Boolean(b)]) => match prim_inst {
+            PrimInst::BooleanEq => Key::Boolean(a == b),
+            PrimInst::BooleanNeq => Key::Boolean(a != b),
+            PrimInst::BooleanAnd => Key::Boolean(*a && *b),
+            PrimInst::BooleanOr => Key::Boolean(*a || *b),
+            _ => return None,
+        },
+        (PrimInst::StringAdd, [Key::String(a), Key::String(b)]) => Key::String(a.clone() + b),
+        (_, [Key::String(a), Key::String(b)]) => {
+            // must match the runtime (which compares the UTF-8 bytes, i.e. the code points)
+            let ordering = a.as_bytes().cmp(b.as_bytes());
+            match prim_inst {
+                PrimInst::StringEq => Key::Boolean(ordering == Ordering::Equal),
+                PrimInst::StringNeq => Key::Boolean(ordering != Ordering::Equal),
+                PrimInst::StringGt => Key::Boolean(ordering == Ordering::Greater),
+                PrimInst::StringLt => Key::Boolean(ordering == Ordering::Less),
+                PrimInst::StringGe => Key::Boolean(ordering != Ordering::Less),
+                PrimInst::StringLe => Key::Boolean(ordering != Ordering::Greater),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    })
+}
+
+// Finds the Declarations and narrowing TypeCasts whose local is assigned to in its scope.
+// `scopes` has the Expr that declares each local in scope (or None for params).
+fn find_assigned(
+    expr: &Expr,
+    scopes: &mut Vec<Option<*const Expr>>,
+    assigned: &mut HashSet<*const Expr>,
+) {
+    // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+    match &expr.kind {
+        ExprKind::PrimUndefined
+        | ExprKind::PrimNull
+        | ExprKind::PrimNumber { val: _ }
+        | ExprKind::PrimBoolean { val: _ }
+        | ExprKind::PrimString { val: _ }
+        | ExprKind::PrimStructT { typeidx: _ }
+        | ExprKind::VarName { source: _ }
+        | ExprKind::Trap {
+            code: _,
+            location: _,
+        } => {}
+        ExprKind::Assign {
+            target,
+            expr: inner_expr,
+        } => {
+            if let TargetExpr::Local {
+                localidx,
+                next: None,
+            } = target
+            {
+                if let Some(scope) = scopes[*localidx] {
+                    assigned.insert(scope);
+                }
+            }
+            find_assigned(inner_expr, scopes, assigned);
+        }
+        ExprKind::PrimFunc {
+            funcidxs: _,
+            closure: inner_expr,
+        }
+        | ExprKind::Return { expr: inner_expr }
+        | ExprKind::Break {
+            num_frames: _,
+            expr: inner_expr,
+        }
+        | ExprKind::Block { expr: inner_expr }
+        | ExprKind::Loop { expr: inner_expr } => find_assigned(inner_expr, scopes, assigned),
+        ExprKind::TypeCast {
+            test,
+            expected: _,
+            create_narrow_local,
+            true_expr,
+            false_expr,
+        } => {
+            find_assigned(test, scopes, assigned);
+            if *create_narrow_local {
+                scopes.push(Some(expr as *const Expr));
+                find_assigned(true_expr, scopes, assigned);
+                scopes.pop();
+            } else {
+                find_assigned(true_expr, scopes, assigned);
+            }
+            find_assigned(false_expr, scopes, assigned);
+        }
+        ExprKind::Conditional {
+            cond,
+            true_expr,
+            false_expr,
+        } => {
+            find_assigned(cond, scopes, assigned);
+            find_assigned(true_expr, scopes, assigned);
+            find_assigned(false_expr, scopes, assigned);
+        }
+        ExprKind::PrimAppl {
+            prim_inst: _,
+            args: exprs,
+        }
+        | ExprKind::DirectAppl {
+            funcidx: _,
+            args: exprs,
+        } => {
+            for inner_expr in exprs.iter() {
+                find_assigned(inner_expr, scopes, assigned);
+            }
+        }
+        ExprKind::Appl {
+            func,
+            args,
+            location: _,
+        } => {
+            find_assigned(func, scopes, assigned);
+            for inner_expr in args.iter() {
+                find_assigned(inner_expr, scopes, assigned);
+            }
+        }
+        ExprKind::Declaration {
+            local: _,
+            init,
+            contained_expr,
+        } => {
+            if let Some(init_expr) = init {
+                find_assigned(init_expr, scopes, assigned);
+            }
+            scopes.push(Some(expr as *const Expr));
+            find_assigned(contained_expr, scopes, assigned);
+            scopes.pop();
+        }
+        ExprKind::Sequence { content } => {
+            for inner_expr in content.iter() {
+                find_assigned(inner_expr, scopes, assigned);
+            }
+        }
+    }
+}
+
+// Renumbers the locals that are not params, to make room for `num_new` locals right after the params.
+fn shift_locals(expr: &mut Expr, num_params: usize, num_new: usize) {
+    walk_expr(expr, false, 0, &mut |expr, _, _| match &mut expr.kind {
+        ExprKind::VarName {
+            source: TargetExpr::Local { localidx, next: _ },
+        }
+        | ExprKind::Assign {
+            target: TargetExpr::Local { localidx, next: _ },
+            expr: _,
+        } if *localidx >= num_params => *localidx += num_new,
+        _ => {}
+    });
+}
+
+fn dummy_expr() -> Expr {
+    Expr {
+        vartype: Some(VarType::Undefined),
+        kind: ExprKind::PrimUndefined,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_constants_and_reuses_values() {
+        // in func#0, local#1 is a constant, so both the number_mul compute the same value
+        let text = r#"
+            func#0 (number) -> number {
+              (decl:number number
+                (number:number 2.0)
+                (prim:number number_add
+                  (prim:number number_mul (var:number local#0) (var:number local#1))
+                  (prim:number number_mul (var:number local#0) (number:number 2.0))))
+            }
+            func#1 () -> any {
+              (decl:any string
+                (prim:string string_add (string:string "a") (string:string "b"))
+                (if:any
+                  (prim:boolean string_lt (var:string local#0) (string:string "b"))
+                  (prim:number number_rem (number:number -1.0) (number:number 1.0))
+                  (var:string local#0)))
+            }
+            entry func#0;
+        "#;
+        let expected = r#"
+            func#0 (number) -> number {
+              (decl:number number _
+                (decl:number number
+                  (number:number 2.0)
+                  (prim:number number_add
+                    (seq:number
+                      (assign:undefined local#1
+                        (prim:number number_mul (var:number local#0) (var:number local#2)))
+                      (var:number local#1))
+                    (var:number local#1))))
+            }
+            func#1 () -> any {
+              (decl:any string
+                (string:string "ab")
+                (if:any (boolean:boolean true) (number:number -0.0) (var:string local#0)))
+            }
+            entry func#0;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(
+            program.to_string(),
+            text::parse_program(expected).unwrap().to_string()
+        );
+
+        // running it again doesn't change anything
+        let (_, changed) = optimize(program);
+        assert!(!changed);
+    }
+
+    #[test]
+    fn folds_string_comparisons_in_code_point_order() {
+        // U+1F600 is a surrogate pair (starting with 0xD83D) in UTF-16, so it is before U+FF61 by code units
+        let text = r#"
+            func#0 () -> boolean {
+              (prim:boolean string_lt (string:string "\u{FF61}") (string:string "\u{1F600}"))
+            }
+            entry func#0;
+        "#;
+        let expected = r#"
+            func#0 () -> boolean {
+              (boolean:boolean true)
+            }
+            entry func#0;
+        "#;
+        let (program, changed) = optimize(text::parse_program(text).unwrap());
+        assert!(changed);
+        assert_eq!(
+            program.to_string(),
+            text::parse_program(expected).unwrap().to_string()
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use super::superset::Superset;
+use super::walk::walk_expr;
+use super::*;
+
+/**
+ * Discretionary optimisation to narrow the type of each struct field (e.g. a captured variable in a closure environment)
+ * to the union of the types of all the values that are assigned to it anywhere in the program.
+ * A captured counter is then stored as a raw Number instead of an Any.
+ * The reads of the narrowed fields are retyped, and we run propagate at the end of this pass so that the types of their parents are consistent.
+ *
+ * Only fields of type Any are narrowed, and only to Number or Boolean.
+ * Types that hold references are not allowed, because the fields of a newly allocated struct are not initialised until they are assigned,
+ * so the garbage collector must not treat them as references before that.
+ * (Undefined and Null have no payload, and the backend does not support fields of those types.)
+ * Fields that are never assigned are left alone.
+ *
+ * A narrowed field also loses the Unassigned tag that a new struct puts into its Any fields,
+ * which is what makes reading a variable before it is assigned trap.
+ * So we only narrow a field if it is assigned right after every struct of its type is created (see assigned_at_start),
+ * before anything can read it.
+ * The second return value is true if the program got changed, or false otherwise.
+ */
+pub fn optimize(mut program: Program) -> (Program, bool) {
+    // find the union of the types assigned to each field
+    let mut assigned: Vec<Box<[Option<VarType>]>> = program
+        .struct_types
+        .iter()
+        .map(|fields| vec![None; fields.len()].into_boxed_slice())
+        .collect();
+    for func in &mut program.funcs {
+        walk_expr(&mut func.expr, false, 0, &mut |expr, _, _| {
+            if let ExprKind::Assign {
+                target,
+                expr: inner_expr,
+            } = &expr.kind
+            {
+                if let Some(field) = last_field(target) {
+                    let vartype = &mut assigned[field.typeidx][field.fieldidx];
+                    *vartype = union_type(*vartype, inner_expr.vartype);
+                }
+            }
+        });
+    }
+
+    // find the fields that are assigned before they can be read
+    let mut initialised: Vec<Box<[bool]>> = program
+        .struct_types
+        .iter()
+        .map(|fields| vec![true; fields.len()].into_boxed_slice())
+        .collect();
+    let mut num_structs: Vec<usize> = vec![0; program.struct_types.len()];
+    let mut num_declared_structs: Vec<usize> = vec![0; program.struct_types.len()];
+    for func in &mut program.funcs {
+        walk_expr(
+            &mut func.expr,
+            false,
+            func.params.len(),
+            &mut |expr, _, num_locals| match &mut expr.kind {
+                ExprKind::PrimStructT { typeidx } => num_structs[*typeidx] += 1,
+                ExprKind::Declaration {
+                    local: VarType::StructT { typeidx },
+                    init: Some(init_expr),
+                    contained_expr,
+                } if matches!(init_expr.kind, ExprKind::PrimStructT { typeidx: init_typeidx } if init_typeidx == *typeidx) =>
+                {
+                    num_declared_structs[*typeidx] += 1;
+                    let assigned_fields =
+                        assigned_at_start(contained_expr, num_locals, initialised[*typeidx].len());
+                    for (init, assigned_field) in
+                        initialised[*typeidx].iter_mut().zip(assigned_fields.iter())
+                    {
+                        *init &= *assigned_field;
+                    }
+                }
+                _ => {}
+            },
+        );
+    }
+    // structs that are not created by a Declaration might be read before any assignment
+    for (typeidx, init) in initialised.iter_mut().enumerate() {
+        if num_structs[typeidx] != num_declared_structs[typeidx] {
+            init.iter_mut().for_each(|init| *init = false);
+        }
+    }
+
+    // narrow the fields
+    let mut changed = false;
+    for ((fields, assigned_fields), init) in program
+        .struct_types
+        .iter_mut()
+        .zip(assigned)
+        .zip(initialised)
+    {
+        for ((field, assigned_field), init) in fields
+            .iter_mut()
+            .zip(assigned_fields.iter())
+            .zip(init.iter())
+        {
+            if !*init {
+                continue;
+            }
+            if let (VarType::Any, Some(vartype)) = (*field, *assigned_field) {
+                if matches!(vartype, VarType::Number | VarType::Boolean) {
+                    *field = vartype;
+                    changed = true;
+                }
+            }
+        }
+    }
+    if !changed {
+        return (program, false);
+    }
+
+    // retype the reads of the narrowed fields
+    let struct_types = &program.struct_types;
+    for func in &mut program.funcs {
+        walk_expr(&mut func.expr, false, 0, &mut |expr, _, _| {
+            if let ExprKind::VarName { source } = &expr.kind {
+                if let Some(field) = last_field(source) {
+                    let field_type = struct_types[field.typeidx][field.fieldidx];
+                    if !field_type.superset(&expr.vartype.unwrap()) {
+                        expr.vartype = Some(field_type);
+                    }
+                }
+            }
+        });
+    }
+
+    // propagate the narrower types to the parents of the reads
+    let (program, _) = propagate::optimize(program);
+    (program, true)
+}
+
+// Returns, for each field of the struct in local #localidx, whether the expr assigns it before anything else happens,
+// i.e. with one of the Assigns at the start of a Sequence.
+// The values of those Assigns may not access the struct, except to make a closure from it
+// (which can't be called without reading the field that it is assigned to).
+fn assigned_at_start(expr: &mut Expr, localidx: usize, num_fields: usize) -> Box<[bool]> {
+    let mut ret = vec![false; num_fields].into_boxed_slice();
+    if let ExprKind::Sequence { content } = &mut expr.kind {
+        for inner_expr in content.iter_mut() {
+            let (fieldidx, value) = match &mut inner_expr.kind {
+                ExprKind::Assign {
+                    target:
+                        TargetExpr::Local {
+                            localidx: target_localidx,
+                            next: Some(field),
+                        },
+                    expr: value,
+                } if *target_localidx == localidx && field.next.is_none() => {
+                    (field.fieldidx, value)
+                }
+                _ => break,
+            };
+            if !is_closure_of_local(value, localidx) && is_accessed(value, localidx) {
+                break;
+            }
+            ret[fieldidx] = true;
+        }
+    }
+    ret
+}
+
+// Returns true if the expr just makes a closure from the given local.
+fn is_closure_of_local(expr: &Expr, localidx: usize) -> bool {
+    match &expr.kind {
+        ExprKind::PrimFunc {
+            funcidxs: _,
+            closure,
+        } => matches!(
+            closure.kind,
+            ExprKind::VarName {
+                source: TargetExpr::Local {
+                    localidx: source_localidx,
+                    next: None,
+                },
+            } if source_localidx == localidx
+        ),
+        _ => false,
+    }
+}
+
+// Returns true if the local (or any of its fields) is accessed in the expr.
+fn is_accessed(expr: &mut Expr, localidx: usize) -> bool {
+    let mut ret = false;
+    walk_expr(expr, false, 0, &mut |expr, _, _| match &expr.kind {
+        ExprKind::VarName {
+            source:
+                TargetExpr::Local {
+                    localidx: target_localidx,
+                    next: _,
+                },
+        }
+        | ExprKind::Assign {
+            target:
+                TargetExpr::Local {
+                    localidx: target_localidx,
+                    next: _,
+                },
+            expr: _,
+        } if *target_localidx == localidx => ret = true,
+        _ => {}
+    });
+    ret
+}
+
+// Returns the innermost struct field that the target refers to, or None if it refers to a variable.
+fn last_field(target: &TargetExpr) -> Option<&StructField> {
+    let mut next = match target {
+        TargetExpr::Global { globalidx: _, next } | TargetExpr::Local { localidx: _, next } => {
+            next.as_deref()?
+        }
+    };
+    while let Some(field) = next.next.as_deref() {
+        next = field;
+    }
+    Some(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrows_assigned_fields() {
+        // field 0 is only assigned numbers, but field 1 is assigned a string
+        let text = r#"
+            struct#0 = (any any);
+            func#0 () -> any {
+              (decl:any struct#0
+                (struct:struct#0 struct#0)
+                (seq:any
+                  (assign:undefined local#0.0:0 (number:number 1.0))
+                  (assign:undefined local#0.0:1 (string:string "a"))
+                  (assign:undefined local#0.0:0
+                    (prim:number number_add (number:number 2.0) (number:number 3.0)))
+                  (var:any local#0.0:0)))
+            }
+            entry func#0;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(&*program.struct_types[0], &[VarType::Number, VarType::Any]);
+
+        // running it again doesn't change anything
+        let (_, changed) = optimize(program);
+        assert!(!changed);
+    }
+
+    #[test]
+    fn keeps_fields_read_before_assignment() {
+        // the struct escapes into global#0 before field 0 is assigned, so field 0 might be read while it is still Unassigned,
+        // but field 0 of struct#1 is assigned right away (after a closure is made from the struct in field 1)
+        let text = r#"
+            struct#0 = (any);
+            struct#1 = (any func);
+            global#0 = any;
+            func#0 (struct#1) -> any {
+              (var:any local#0.1:0)
+            }
+            func#1 () -> any {
+              (decl:any struct#0
+                (struct:struct#0 struct#0)
+                (seq:any
+                  (assign:undefined global#0 (var:struct#0 local#0))
+                  (assign:undefined local#0.0:0 (number:number 5.0))
+                  (decl:any struct#1
+                    (struct:struct#1 struct#1)
+                    (seq:any
+                      (assign:undefined local#1.1:1 (func:func [func#0:closure] (var:struct#1 local#1)))
+                      (assign:undefined local#1.1:0 (number:number 6.0))
+                      (var:any local#0.0:0)))))
+            }
+            entry func#1;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(&*program.struct_types[0], &[VarType::Any]);
+        assert_eq!(&*program.struct_types[1], &[VarType::Number, VarType::Func]);
+    }
+}
@@ -14,7 +14,7 @@ const INLINING_MAX_ALLOWABLE_COST: usize = 40;
 /**
  * Discretionary optimisation to inline function calls where beneficial.
  * The second return value is true if the program got changed, or false otherwise.
- * The algorithm is designed to be stable - repeatedly running the algorithm will eventually reach a stable state, even with future devirtualisation.
+ * The algorithm is designed to be stable - repeatedly running the algorithm will eventually reach a stable state, even though propagate devirtualises indirect calls into direct calls (which may make more functions candidates for inlining).
  *
  * The algorithm works as follows:
  * 1. Let F be the set of functions that do not contain indirect calls (only such functions are ever considered, in order to guarantee stability).
@@ -107,11 +107,16 @@ pub fn optimize(mut program: Program) -> (Program, bool) {
 
                 if fp.parents.len() == 1 && !fp.has_indirect_calls {
                     // 3ai succeeds, we inline by 'moving' the current function into the caller
+                    // (it leaves behind a well-typed placeholder that returns undefined, since nothing calls it any more)
                     let (caller_funcidx, mut direct_call_expr, site) = fp.parents.pop().unwrap();
+                    let placeholder = Func {
+                        result: Some(VarType::Undefined),
+                        ..Func::new()
+                    };
                     inline_by_destructive_move(
                         unsafe { direct_call_expr.as_mut() },
                         site,
-                        std::mem::replace(program.get_func_mut(og_item.funcidx), Func::new()),
+                        std::mem::replace(program.get_func_mut(og_item.funcidx), placeholder),
                     );
                     changed = true;
                     update_caller_func(
@@ -262,11 +267,12 @@ impl PartialEq for CostPQItem {
 fn inline_by_destructive_move(direct_call_expr: &mut Expr, site: SiteProperties, func: Func) {
     let actual_args = std::mem::take(as_direct_appl_args(direct_call_expr));
     let tmp_expr = func.expr;
+    let result = func.result;
     *direct_call_expr = wrap_declarations(
         Vec::from(actual_args).into_iter(),
         &func.params,
         site,
-        |site| relabel_inline_func(tmp_expr, site).0,
+        |site| relabel_inline_func(tmp_expr, site, result).0,
     );
 }
 
@@ -278,7 +284,7 @@ fn inline_by_copy(direct_call_expr: &mut Expr, site: SiteProperties, func: &Func
         site,
         |site| {
             let expr = func.expr.clone();
-            relabel_inline_func(expr, site).0
+            relabel_inline_func(expr, site, func.result).0
         },
     );
 }
@@ -332,3 +338,53 @@ fn as_direct_appl_args(expr: &mut Expr) -> &mut Box<[Expr]> {
         panic!("Not a DirectAppl");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_funcs_in_signature_filters() {
+        // func#1 has only one direct call, but it is also reachable through the signature_filter of func#0
+        let text = r#"
+            func#0 (any) -> any filter (number) -> any = func#1 {
+              (typecast:any number narrow (var:any local#0)
+                (call:any func#1 (var:number local#1))
+                (var:any local#0))
+            }
+            func#1 (number) -> number {
+              (var:number local#0)
+            }
+            func#2 () -> any {
+              (call:any func#0 (number:number 3.0))
+            }
+            entry func#2;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, _) = optimize(program);
+        assert_eq!(&*program.funcs[1].params, &[VarType::Number]);
+        assert!(crate::verify::verify(&program).is_ok());
+    }
+
+    #[test]
+    fn moved_func_leaves_valid_placeholder() {
+        // func#0 has only one direct call, so it is moved into func#1
+        let text = r#"
+            func#0 (number) -> number {
+              (prim:number number_add (var:number local#0) (number:number 1.0))
+            }
+            func#1 () -> any {
+              (call:number func#0 (number:number 3.0))
+            }
+            entry func#1;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert!(matches!(
+            program.funcs[0].expr.kind,
+            ExprKind::PrimUndefined
+        ));
+        assert!(crate::verify::verify(&program).is_ok());
+    }
+}
@@ -9,6 +9,10 @@ pub(super) fn populate_properties_func(
     func: &mut Func,
     func_props: &mut [Option<FunctionProperties>],
 ) {
+    // funcs referenced by a signature_filter may be called from the backend's dispatch, so they can't be moved away
+    for (_, _, constrained_funcidx) in &func.signature_filter {
+        set_has_indirect_calls(&mut func_props[*constrained_funcidx]);
+    }
     populate_properties(
         funcidx,
         &mut func.expr,
@@ -25,7 +29,7 @@ fn populate_properties(
 ) {
     // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
     match &mut expr.kind {
-        ExprKind::PrimUndefined => {}
+        ExprKind::PrimUndefined | ExprKind::PrimNull => {}
         ExprKind::PrimNumber { val: _ }
         | ExprKind::PrimBoolean { val: _ }
         | ExprKind::PrimStructT { typeidx: _ }
@@ -121,6 +125,9 @@ fn populate_properties(
         ExprKind::Block { expr } => {
             populate_properties(funcidx, expr, func_props, site);
         }
+        ExprKind::Loop { expr } => {
+            populate_properties(funcidx, expr, func_props, site);
+        }
         ExprKind::Sequence { content } => {
             for expr in content {
                 populate_properties(funcidx, expr, func_props, site);
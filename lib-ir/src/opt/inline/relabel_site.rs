@@ -5,13 +5,17 @@ use crate::*;
  * Relabels all the locals and landings by the relative offset given by the site properties.
  * Note that we only need to rewrite Returns but not Breaks, because Breaks are relative.
  * site.num_landings()
- * Also wraps everything in a block so that returns can jump here.
+ * Also wraps everything in a block so that returns can jump here (so the block has the result type of the function).
  */
-pub(super) fn relabel_inline_func(mut expr: Expr, site: SiteProperties) -> (Expr, bool) {
+pub(super) fn relabel_inline_func(
+    mut expr: Expr,
+    site: SiteProperties,
+    result: Option<VarType>,
+) -> (Expr, bool) {
     let ret = relabel_site(&mut expr, site, 0);
     (
         Expr {
-            vartype: expr.vartype,
+            vartype: result,
             kind: ExprKind::Block {
                 expr: Box::new(expr),
             },
@@ -24,6 +28,7 @@ fn relabel_site(expr: &mut Expr, site: SiteProperties, num_landings: usize) -> b
     // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
     match &mut expr.kind {
         ExprKind::PrimUndefined
+        | ExprKind::PrimNull
         | ExprKind::PrimNumber { val: _ }
         | ExprKind::PrimBoolean { val: _ }
         | ExprKind::PrimStructT { typeidx: _ }
@@ -100,6 +105,7 @@ fn relabel_site(expr: &mut Expr, site: SiteProperties, num_landings: usize) -> b
             expr,
         } => relabel_site(&mut **expr, site, num_landings),
         ExprKind::Block { expr } => relabel_site(&mut **expr, site, num_landings + 1),
+        ExprKind::Loop { expr } => relabel_site(expr, site, num_landings + 1),
         ExprKind::Sequence { content } => content.iter_mut().fold(false, |prev, expr| {
             prev | relabel_site(expr, site, num_landings)
         }),
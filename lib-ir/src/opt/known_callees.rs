@@ -0,0 +1,347 @@
+use super::walk::walk_expr;
+use super::*;
+
+/**
+ * Rewrites Appls whose callee is a global that is known to contain a specific func, so that the callee becomes a PrimFunc.
+ * Propagate then devirtualises them into DirectAppls, just like any other Appl of a PrimFunc.
+ *
+ * A global contains a known func if it is assigned exactly once in the whole program, by the prefix of the entry point's body
+ * that does not make any calls, and the assigned value is a PrimFunc.
+ * No func can be called before that prefix has finished, so every Appl will see the assigned value.
+ * (This is what the frontend emits for top-level function declarations.)
+ * The closure of the PrimFunc is saved into a new global, so that the rewritten Appls can pass it explicitly.
+ *
+ * Appls in tail position are left alone, because the backend only makes tail calls through the uniform calling convention,
+ * so turning them into DirectAppls would make tail-recursive functions use up the stack.
+ *
+ * The return value is true if the program got changed, or false otherwise.
+ */
+pub fn devirtualize_known_callees(program: &mut Program) -> bool {
+    let entry_funcidx = program.entry_point - program.imports.len();
+
+    // count the assignments to each global, and find the globals that are used as the callee of a (non-tail) Appl
+    let mut num_assigns: Vec<usize> = vec![0; program.globals.len()];
+    let mut is_callee: Vec<bool> = vec![false; program.globals.len()];
+    for (i, func) in program.funcs.iter_mut().enumerate() {
+        let num_params = func.params.len();
+        walk_expr(
+            &mut func.expr,
+            i != entry_funcidx,
+            num_params,
+            &mut |expr, tail, num_locals| match &expr.kind {
+                ExprKind::Assign {
+                    target:
+                        TargetExpr::Global {
+                            globalidx,
+                            next: None,
+                        },
+                    expr: _,
+                } => num_assigns[*globalidx] += 1,
+                ExprKind::Appl {
+                    func,
+                    args: _,
+                    location: _,
+                } if !tail => {
+                    if let Some(globalidx) = callee_global(func, num_locals) {
+                        is_callee[globalidx] = true;
+                    }
+                }
+                _ => {}
+            },
+        );
+    }
+
+    // find the known funcs in the prefix of the entry point
+    let mut changed = false;
+    let mut known_funcs: Vec<Option<(Box<[OverloadEntry]>, Expr)>> =
+        vec![None; program.globals.len()];
+    if let ExprKind::Sequence { content } = &mut program.funcs[entry_funcidx].expr.kind {
+        for expr in content.iter_mut() {
+            if contains_call(expr) {
+                break;
+            }
+            if let ExprKind::Assign {
+                target:
+                    TargetExpr::Global {
+                        globalidx,
+                        next: None,
+                    },
+                expr: rhs_expr,
+            } = &mut expr.kind
+            {
+                let globalidx = *globalidx;
+                if num_assigns[globalidx] != 1 || !is_callee[globalidx] {
+                    continue;
+                }
+                // the closure might be declared in a local just outside the PrimFunc
+                let value = skip_declarations(rhs_expr);
+                if let ExprKind::PrimFunc { funcidxs, closure } = &mut value.kind {
+                    let closure_vartype = match closure.vartype {
+                        Some(vartype) => vartype,
+                        None => continue,
+                    };
+                    let closure_read: Expr = match &closure.kind {
+                        ExprKind::PrimUndefined => (**closure).clone(),
+                        ExprKind::Sequence {
+                            content: closure_content,
+                        } if closure_content.len() == 2
+                            && saved_closure_global(&closure_content[0], &closure_content[1])
+                                .is_some_and(|closure_globalidx| {
+                                    num_assigns[closure_globalidx] == 1
+                                }) =>
+                        {
+                            closure_content[1].clone()
+                        }
+                        _ => {
+                            // save the closure into a new global
+                            let closure_globalidx = program.globals.len();
+                            program.globals.push(closure_vartype);
+                            let closure_read = Expr {
+                                vartype: Some(closure_vartype),
+                                kind: ExprKind::VarName {
+                                    source: TargetExpr::Global {
+                                        globalidx: closure_globalidx,
+                                        next: None,
+                                    },
+                                },
+                            };
+                            let old_closure = std::mem::replace(&mut **closure, dummy_expr());
+                            **closure = Expr {
+                                vartype: Some(closure_vartype),
+                                kind: ExprKind::Sequence {
+                                    content: vec![
+                                        Expr {
+                                            vartype: Some(VarType::Undefined),
+                                            kind: ExprKind::Assign {
+                                                target: TargetExpr::Global {
+                                                    globalidx: closure_globalidx,
+                                                    next: None,
+                                                },
+                                                expr: Box::new(old_closure),
+                                            },
+                                        },
+                                        closure_read.clone(),
+                                    ],
+                                },
+                            };
+                            changed = true;
+                            closure_read
+                        }
+                    };
+                    known_funcs[globalidx] = Some((funcidxs.clone(), closure_read));
+                }
+            }
+        }
+    }
+
+    // rewrite the callees of the Appls
+    for (i, func) in program.funcs.iter_mut().enumerate() {
+        let num_params = func.params.len();
+        walk_expr(
+            &mut func.expr,
+            i != entry_funcidx,
+            num_params,
+            &mut |expr, tail, num_locals| {
+                if let ExprKind::Appl {
+                    func,
+                    args: _,
+                    location: _,
+                } = &mut expr.kind
+                {
+                    if tail {
+                        return;
+                    }
+                    if let Some((funcidxs, closure_read)) = callee_global(func, num_locals)
+                        .and_then(|globalidx| known_funcs[globalidx].as_ref())
+                    {
+                        **func = Expr {
+                            vartype: Some(VarType::Func),
+                            kind: ExprKind::PrimFunc {
+                                funcidxs: funcidxs.clone(),
+                                closure: Box::new(closure_read.clone()),
+                            },
+                        };
+                        changed = true;
+                    }
+                }
+            },
+        );
+    }
+
+    changed
+}
+
+// Returns the global that is read by the func expr of an Appl, if the func expr is just a read of a global (possibly narrowed to Func).
+// `num_locals` is the number of locals in scope at the Appl.
+fn callee_global(func: &Expr, num_locals: usize) -> Option<usize> {
+    match &func.kind {
+        ExprKind::VarName {
+            source:
+                TargetExpr::Global {
+                    globalidx,
+                    next: None,
+                },
+        } if func.vartype == Some(VarType::Func) => Some(*globalidx),
+        ExprKind::TypeCast {
+            test,
+            expected: VarType::Func,
+            create_narrow_local: true,
+            true_expr,
+            false_expr: _,
+        } => match (&test.kind, &true_expr.kind) {
+            (
+                ExprKind::VarName {
+                    source:
+                        TargetExpr::Global {
+                            globalidx,
+                            next: None,
+                        },
+                },
+                ExprKind::VarName {
+                    source:
+                        TargetExpr::Local {
+                            localidx,
+                            next: None,
+                        },
+                },
+            ) if *localidx == num_locals => Some(*globalidx),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Returns the global that the closure was saved into by a previous run, if `first` and `second` are the assignment and read of it.
+fn saved_closure_global(first: &Expr, second: &Expr) -> Option<usize> {
+    match (&first.kind, &second.kind) {
+        (
+            ExprKind::Assign {
+                target:
+                    TargetExpr::Global {
+                        globalidx,
+                        next: None,
+                    },
+                expr: _,
+            },
+            ExprKind::VarName {
+                source:
+                    TargetExpr::Global {
+                        globalidx: globalidx2,
+                        next: None,
+                    },
+            },
+        ) if globalidx == globalidx2 => Some(*globalidx),
+        _ => None,
+    }
+}
+
+// Returns the expr inside any Declarations that wrap it.
+fn skip_declarations(expr: &mut Expr) -> &mut Expr {
+    if let ExprKind::Declaration {
+        local: _,
+        init: _,
+        contained_expr: _,
+    } = &expr.kind
+    {
+        match &mut expr.kind {
+            ExprKind::Declaration {
+                local: _,
+                init: _,
+                contained_expr,
+            } => skip_declarations(contained_expr),
+            _ => unreachable!(),
+        }
+    } else {
+        expr
+    }
+}
+
+// Returns true if the expr might call a func.
+fn contains_call(expr: &mut Expr) -> bool {
+    let mut ret = false;
+    walk_expr(expr, false, 0, &mut |expr, _, _| {
+        ret |= matches!(
+            expr.kind,
+            ExprKind::Appl {
+                func: _,
+                args: _,
+                location: _,
+            } | ExprKind::DirectAppl {
+                funcidx: _,
+                args: _,
+            }
+        );
+    });
+    ret
+}
+
+fn dummy_expr() -> Expr {
+    Expr {
+        vartype: Some(VarType::Undefined),
+        kind: ExprKind::PrimUndefined,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_non_tail_appls() {
+        // the first Appl in func#0 is not in tail position, but the second one is
+        let text = r#"
+            struct#0 = ();
+            global#0 = any;
+            func#0 (struct#0 any) -> any {
+              (seq:any
+                (appl:any 0:1:1-1:2
+                  (typecast:func func narrow (var:any global#0) (var:func local#2) (trap:! 0x16 0:1:1-1:2))
+                  (var:any local#1))
+                (appl:any 0:2:1-2:2
+                  (typecast:func func narrow (var:any global#0) (var:func local#2) (trap:! 0x16 0:2:1-2:2))
+                  (var:any local#1)))
+            }
+            func#1 () -> any {
+              (seq:any
+                (assign:undefined global#0
+                  (decl:func struct#0
+                    (struct:struct#0 struct#0)
+                    (func:func [func#0:closure] (var:struct#0 local#0))))
+                (appl:any 0:3:1-3:2
+                  (typecast:func func narrow (var:any global#0) (var:func local#0) (trap:! 0x16 0:3:1-3:2))
+                  (number:number 1.0)))
+            }
+            entry func#1;
+        "#;
+        let mut program = text::parse_program(text).unwrap();
+        assert!(devirtualize_known_callees(&mut program));
+        assert_eq!(program.globals.len(), 2);
+        let callees: Vec<bool> = program
+            .funcs
+            .iter()
+            .flat_map(|func| match &func.expr.kind {
+                ExprKind::Sequence { content } => content.iter().skip(content.len() - 2),
+                _ => panic!(),
+            })
+            .filter_map(|expr| match &expr.kind {
+                ExprKind::Appl {
+                    func,
+                    args: _,
+                    location: _,
+                } => Some(matches!(
+                    func.kind,
+                    ExprKind::PrimFunc {
+                        funcidxs: _,
+                        closure: _
+                    }
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(callees, vec![true, false, true]);
+
+        // running it again reuses the global that holds the closure
+        assert!(!devirtualize_known_callees(&mut program));
+        assert_eq!(program.globals.len(), 2);
+    }
+}
@@ -1,83 +1,37 @@
+mod cse;
+mod fields;
 mod inline;
+mod known_callees;
 mod landing_context;
+mod pass_manager;
 mod propagate;
+mod prune;
+mod refine;
 mod relabeller;
+mod scalar;
+mod signatures;
+mod specialize;
 mod typecast;
 mod unreachable;
+mod walk;
 
 use super::*;
 
+pub use pass_manager::{Pass, PassManager, PassStats, Stage};
+
 /**
  * Main function to do mandatory optimizations for a program.
  * Mandatory optimizations are those that are required for the IR to function correctly.
  */
-pub fn optimize_mandatory(mut program: Program) -> Program {
-    let mut n: usize = 0;
-    const TOTAL: usize = 2;
-    loop {
-        {
-            let (new_program, changed) = unreachable::optimize(program);
-            program = new_program;
-            if changed {
-                n = 1;
-            } else {
-                n += 1;
-            }
-            if n == TOTAL {
-                break;
-            }
-        }
-        {
-            let (new_program, changed) = typecast::optimize(program);
-            program = new_program;
-            if changed {
-                n = 1;
-            } else {
-                n += 1;
-            }
-            if n == TOTAL {
-                break;
-            }
-        }
-    }
-
-    program
+pub fn optimize_mandatory(program: Program) -> Program {
+    PassManager::from_name("-O0").unwrap().run(program)
 }
 
 /**
  * Main function to do discretionary optimizations for a program.
  */
-pub fn optimize_all(mut program: Program) -> Program {
-    let mut n: usize = 0;
-    const TOTAL: usize = 2;
-    loop {
-        {
-            let (new_program, changed) = propagate::optimize(program);
-            program = new_program;
-            if changed {
-                n = 0;
-            } else {
-                n += 1;
-            }
-            if n == TOTAL {
-                break;
-            }
-        }
-        {
-            let (new_program, changed) = inline::optimize(program);
-            program = new_program;
-            if changed {
-                n = 0;
-            } else {
-                n += 1;
-            }
-            if n == TOTAL {
-                break;
-            }
-        }
-    }
-
-    program
+pub fn optimize_all(program: Program) -> Program {
+    PassManager::from_name("-O2").unwrap().run(program)
 }
 
 /**
@@ -0,0 +1,289 @@
+use super::*;
+
+use projstd::log::{Loggable, Logger, Severity, SourceLocationRef};
+use std::time::Duration;
+
+/**
+ * An optimisation pass that can be scheduled by the PassManager.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pass {
+    Unreachable,
+    Typecast,
+    Propagate,
+    Signatures,
+    Fields,
+    Inline,
+    Scalar,
+    Cse,
+    Specialize,
+    Prune,
+}
+
+impl Pass {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Unreachable => "unreachable",
+            Pass::Typecast => "typecast",
+            Pass::Propagate => "propagate",
+            Pass::Signatures => "signatures",
+            Pass::Fields => "fields",
+            Pass::Inline => "inline",
+            Pass::Scalar => "scalar",
+            Pass::Cse => "cse",
+            Pass::Specialize => "specialize",
+            Pass::Prune => "prune",
+        }
+    }
+
+    // Runs the pass, returning the new program and whether it got changed.
+    fn run(self, program: Program) -> (Program, bool) {
+        match self {
+            Pass::Unreachable => unreachable::optimize(program),
+            Pass::Typecast => typecast::optimize(program),
+            Pass::Propagate => propagate::optimize(program),
+            Pass::Signatures => signatures::optimize(program),
+            Pass::Fields => fields::optimize(program),
+            Pass::Inline => inline::optimize(program),
+            Pass::Scalar => scalar::optimize(program),
+            Pass::Cse => cse::optimize(program),
+            Pass::Specialize => specialize::optimize(program),
+            Pass::Prune => prune::optimize(program),
+        }
+    }
+}
+
+/**
+ * A step in a pipeline.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stage {
+    Once(Pass),          // run the pass once
+    Fixpoint(Vec<Pass>), // run the passes in order repeatedly, until none of them change the program (or the iteration cap is reached)
+}
+
+/**
+ * What a single pass did over the whole pipeline.
+ */
+#[derive(Debug, Clone)]
+pub struct PassStats {
+    pub pass: Pass,
+    pub runs: usize,            // number of times the pass was run
+    pub changes: usize,         // number of runs that changed the program
+    pub time: Option<Duration>, // total time spent in the pass (None if timing is unavailable, e.g. on wasm32)
+}
+
+impl Loggable for &PassStats {
+    fn severity(&self) -> Severity {
+        Severity::Info
+    }
+    fn location<'a>(&'a self) -> SourceLocationRef<'a> {
+        SourceLocationRef::entire_file(None)
+    }
+    fn message(&self) -> String {
+        match self.time {
+            Some(time) => format!(
+                "pass {}: {} runs, {} changed, {:.3} ms",
+                self.pass.name(),
+                self.runs,
+                self.changes,
+                time.as_secs_f64() * 1000.0
+            ),
+            None => format!(
+                "pass {}: {} runs, {} changed",
+                self.pass.name(),
+                self.runs,
+                self.changes
+            ),
+        }
+    }
+}
+
+/**
+ * Runs a pipeline of passes over a program, and records what each pass did.
+ */
+pub struct PassManager {
+    stages: Vec<Stage>,
+    max_iterations: usize, // maximum number of rounds of each Fixpoint stage
+    stats: Vec<PassStats>, // in order of the first run of each pass
+}
+
+const DEFAULT_MAX_ITERATIONS: usize = 100;
+
+impl PassManager {
+    pub fn new(stages: Vec<Stage>) -> Self {
+        Self {
+            stages,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            stats: Vec::new(),
+        }
+    }
+
+    /**
+     * Creates a pass manager for a named pipeline:
+     * `-O0` only runs the mandatory optimisations,
+     * `-O1` additionally propagates constants and types, infers function signatures and struct field types,
+     * replaces structs that are only accessed through their fields by locals, folds constants and reuses the values of common subexpressions,
+     * and removes unreachable functions,
+     * `-O2` additionally inlines functions, and specialises them for the argument types at their call sites (this is the default).
+     * Returns None if the name is not recognised.
+     */
+    pub fn from_name(name: &str) -> Option<Self> {
+        let stages = match name {
+            "-O0" => vec![Stage::Fixpoint(vec![Pass::Unreachable, Pass::Typecast])],
+            "-O1" => vec![
+                Stage::Fixpoint(vec![
+                    Pass::Propagate,
+                    Pass::Signatures,
+                    Pass::Fields,
+                    Pass::Scalar,
+                    Pass::Cse,
+                ]),
+                Stage::Once(Pass::Prune),
+            ],
+            "-O2" => vec![
+                Stage::Fixpoint(vec![
+                    Pass::Propagate,
+                    Pass::Signatures,
+                    Pass::Fields,
+                    Pass::Inline,
+                    Pass::Scalar,
+                    Pass::Cse,
+                    Pass::Specialize,
+                ]),
+                Stage::Once(Pass::Prune),
+            ],
+            _ => return None,
+        };
+        Some(Self::new(stages))
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn stats(&self) -> &[PassStats] {
+        &self.stats
+    }
+
+    /**
+     * Logs the statistics of every pass that was run.
+     */
+    pub fn log_stats<L: Logger>(&self, logger: &L) {
+        for pass_stats in &self.stats {
+            logger.log(pass_stats);
+        }
+    }
+
+    pub fn run(&mut self, mut program: Program) -> Program {
+        let stages = std::mem::take(&mut self.stages);
+        for stage in &stages {
+            match stage {
+                Stage::Once(pass) => {
+                    program = self.run_pass(*pass, program).0;
+                }
+                Stage::Fixpoint(passes) => {
+                    // stop when all the passes have been run consecutively without any changes
+                    let mut num_unchanged: usize = 0;
+                    'rounds: for _ in 0..self.max_iterations {
+                        for pass in passes {
+                            let (new_program, changed) = self.run_pass(*pass, program);
+                            program = new_program;
+                            if changed {
+                                num_unchanged = 0;
+                            } else {
+                                num_unchanged += 1;
+                            }
+                            if num_unchanged == passes.len() {
+                                break 'rounds;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.stages = stages;
+        program
+    }
+
+    fn run_pass(&mut self, pass: Pass, program: Program) -> (Program, bool) {
+        let start = now();
+        let (program, changed) = pass.run(program);
+        let elapsed = start.map(|start| start.elapsed());
+        debug_verify(&program, pass.name());
+
+        let pass_stats = match self.stats.iter_mut().find(|s| s.pass == pass) {
+            Some(pass_stats) => pass_stats,
+            None => {
+                self.stats.push(PassStats {
+                    pass,
+                    runs: 0,
+                    changes: 0,
+                    time: elapsed.map(|_| Duration::default()),
+                });
+                self.stats.last_mut().unwrap()
+            }
+        };
+        pass_stats.runs += 1;
+        if changed {
+            pass_stats.changes += 1;
+        }
+        if let (Some(time), Some(elapsed)) = (&mut pass_stats.time, elapsed) {
+            *time += elapsed;
+        }
+
+        (program, changed)
+    }
+}
+
+// std::time::Instant::now() panics on wasm32-unknown-unknown, so we don't time the passes there.
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> Option<std::time::Instant> {
+    Some(std::time::Instant::now())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn now() -> Option<std::time::Instant> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_stats() {
+        let text = r#"
+            func#0 () -> any {
+              (prim:number number_add (number:number 1.0) (number:number 2.0))
+            }
+            func#1 () -> number {
+              (number:number 0.0)
+            }
+            entry func#0;
+        "#;
+        let mut pass_manager = PassManager::from_name("-O2").unwrap();
+        let program = pass_manager.run(text::parse_program(text).unwrap());
+        assert_eq!(program.funcs.len(), 1);
+        let summary: Vec<(Pass, usize, usize)> = pass_manager
+            .stats()
+            .iter()
+            .map(|s| (s.pass, s.runs, s.changes))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Pass::Propagate, 2, 1),
+                (Pass::Signatures, 1, 0),
+                (Pass::Fields, 1, 0),
+                (Pass::Inline, 1, 0),
+                (Pass::Scalar, 1, 0),
+                (Pass::Cse, 1, 0),
+                (Pass::Specialize, 1, 0),
+                (Pass::Prune, 1, 1)
+            ]
+        );
+        assert!(PassManager::from_name("-O3").is_none());
+    }
+}
@@ -1,8 +1,12 @@
+use super::cse::fold_constants;
+use super::known_callees::devirtualize_known_callees;
 use super::landing_context::LandingContext;
+use super::refine::refine_locals;
 use super::relabeller::Relabeller;
 use super::superset::*;
 use super::union_type;
 use super::useful_update;
+use super::walk::walk_expr;
 use super::*;
 use itertools::Itertools;
 use projstd::iter::*;
@@ -10,57 +14,186 @@ use projstd::iter::*;
 /**
  * Discretionary optimisation to propagate all types and values as much as possible.
  * This does a superset of unreachable.rs and typecast.rs, so you don't need to use those if you use this optimization.
+ * Appls whose callee is known to be a specific func are devirtualised into DirectAppls (see known_callees.rs),
+ * except those in tail position, since the backend only makes tail calls through the uniform calling convention,
+ * and DirectAppls are redirected to a constrained version of the callee (from its signature_filter) if the args fit.
+ * Reads of locals are narrowed using the type checks that come before them in the control flow (see refine.rs).
+ * The result of the entry point is never narrowed, because the embedder reads it from the Any result slot.
  * The second return value is true if the program got changed, or false otherwise.
  */
 pub fn optimize(mut program: Program) -> (Program, bool) {
-    let mut changed = false;
-    let param_types: Box<[Box<[VarType]>]> = program
-        .imports
-        .iter()
-        .map(|import| import.params.iter().map(|ivt| (*ivt).into()).collect())
-        .chain(program.funcs.iter().map(|func| func.params.clone()))
-        .collect();
-    let result_types: Box<[Option<VarType>]> = program
-        .imports
-        .iter()
-        .map(|import| Some(import.result.into()))
-        .chain(program.funcs.iter().map(|func| func.result))
-        .collect();
-    for func in &mut program.funcs {
-        changed |= optimize_func(
+    let mut changed = devirtualize_known_callees(&mut program);
+    let entry_funcidx = program.entry_point - program.imports.len();
+    let param_types = make_param_types(&program);
+    let signature_filters = make_signature_filters(&program);
+    // the result types of funcs might get narrowed while optimising, so we repeat until they stop changing
+    // (otherwise the DirectAppls to those funcs would have a stale vartype)
+    loop {
+        let result_types: Box<[Option<VarType>]> = program
+            .imports
+            .iter()
+            .map(|import| Some(import.result.into()))
+            .chain(program.funcs.iter().map(|func| func.result))
+            .collect();
+        for (i, func) in program.funcs.iter_mut().enumerate() {
+            changed |= optimize_func(
+                func,
+                i == entry_funcidx,
+                Context {
+                    param_types: &param_types,
+                    result_types: &result_types,
+                    signature_filters: &signature_filters,
+                },
+            );
+        }
+        if program
+            .funcs
+            .iter()
+            .map(|func| func.result)
+            .eq(result_types[program.imports.len()..].iter().copied())
+        {
+            break;
+        }
+    }
+    changed |= update_signature_filters(&mut program);
+    (program, changed)
+}
+
+/**
+ * Optimises every func once, assuming that each FuncIdx (including imports) returns the given result type
+ * instead of its declared result.
+ * This is used by signatures.rs to try out narrower result types for recursive funcs.
+ * Afterwards, the result of each func is the type that its body actually returns, which might not fit the assumed result type
+ * (in which case the assumption was wrong, and the program must be discarded).
+ * Note that DirectAppls will have the assumed result type rather than the actual result type of the callee,
+ * so optimize() should be run again if the assumption was right.
+ */
+pub(super) fn optimize_assuming_results(program: &mut Program, result_types: &[Option<VarType>]) {
+    let param_types = make_param_types(program);
+    let signature_filters = make_signature_filters(program);
+    let entry_funcidx = program.entry_point - program.imports.len();
+    for (i, func) in program.funcs.iter_mut().enumerate() {
+        optimize_func(
             func,
+            i == entry_funcidx,
             Context {
                 param_types: &param_types,
-                result_types: &result_types,
+                result_types,
+                signature_filters: &signature_filters,
             },
         );
     }
-    (program, changed)
+}
+
+// Returns true if the expr just reads the given local.
+fn is_read_of_local(expr: &Expr, localidx: usize) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::VarName {
+            source: TargetExpr::Local {
+                localidx: source_localidx,
+                next: None,
+            },
+        } if source_localidx == localidx
+    )
+}
+
+fn make_param_types(program: &Program) -> Box<[Box<[VarType]>]> {
+    program
+        .imports
+        .iter()
+        .map(|import| import.params.iter().map(|ivt| (*ivt).into()).collect())
+        .chain(program.funcs.iter().map(|func| func.params.clone()))
+        .collect()
+}
+
+fn make_signature_filters(program: &Program) -> Box<[SignatureFilter]> {
+    program
+        .imports
+        .iter()
+        .map(|_| Box::default())
+        .chain(program.funcs.iter().map(|func| {
+            func.signature_filter
+                .iter()
+                .map(|(params, _, constrained_funcidx)| (params.clone(), *constrained_funcidx))
+                .collect()
+        }))
+        .collect()
 }
 
 #[derive(Copy, Clone)]
-struct Context<'a, 'b> {
+struct Context<'a, 'b, 'c> {
     param_types: &'a [Box<[VarType]>], // param type of each FuncIdx (including imports)
     result_types: &'b [Option<VarType>], // result type of each FuncIdx (including imports)
+    signature_filters: &'c [SignatureFilter], // signature_filter of each FuncIdx (including imports)
+}
+
+// (param_types, constrained_func) of each entry in the signature_filter of a func
+type SignatureFilter = Box<[(Box<[VarType]>, FuncIdx)]>;
+
+/**
+ * Updates the result types in the signature_filter of each func, since optimising might have narrowed the result types of the funcs.
+ * An entry is removed if the result of the constrained func no longer fits in the result of the func.
+ * The return value is true if the program got changed, or false otherwise.
+ */
+fn update_signature_filters(program: &mut Program) -> bool {
+    let num_imports = program.imports.len();
+    let result_types: Box<[Option<VarType>]> =
+        program.funcs.iter().map(|func| func.result).collect();
+    let mut changed = false;
+    for func in &mut program.funcs {
+        let func_result = func.result;
+        let old_len = func.signature_filter.len();
+        func.signature_filter
+            .retain_mut(|(_, result, constrained_funcidx)| {
+                match (
+                    func_result,
+                    result_types[*constrained_funcidx - num_imports],
+                ) {
+                    (Some(func_result), Some(constrained_result)) => {
+                        if func_result.superset(&constrained_result) {
+                            changed |= useful_update(result, constrained_result);
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    (Some(func_result), None) => {
+                        changed |= useful_update(result, func_result);
+                        true
+                    }
+                    (None, _) => false,
+                }
+            });
+        changed |= func.signature_filter.len() != old_len;
+    }
+    changed
 }
 
 /**
  * Optimises the function.
+ * If `is_entry` is true, the function is called by the embedder, so it can't make tail calls,
+ * and its result is left as is, because the embedder reads it from the Any result slot.
  * The return value is true if the function got changed, or false otherwise.
  */
-fn optimize_func(func: &mut Func, ctx: Context) -> bool {
+fn optimize_func(func: &mut Func, is_entry: bool, ctx: Context) -> bool {
+    let refined = refine_locals(func);
     let (ret, landing_vartype) = LandingContext::with_new_func(|landing_ctx| {
         optimize_expr(
             &mut func.expr,
+            !is_entry,
             &mut Relabeller::new_with_identities(0..func.params.len()),
             ctx,
             landing_ctx,
         )
     });
-    ret | useful_update(
-        &mut func.result,
-        union_type(func.expr.vartype, landing_vartype),
-    )
+    refined
+        | ret
+        | (!is_entry
+            && useful_update(
+                &mut func.result,
+                union_type(func.expr.vartype, landing_vartype),
+            ))
 }
 
 fn relabel_target(target: &mut TargetExpr, local_map: &mut Relabeller) -> bool {
@@ -79,10 +212,12 @@ fn relabel_target(target: &mut TargetExpr, local_map: &mut Relabeller) -> bool {
 
 /**
  * Optimises the expr.
+ * `tail` is true if the value of the expr is returned directly from the function (so an Appl there may be a tail call).
  * The return value is true if the function got changed, or false otherwise.
  */
 fn optimize_expr(
     expr: &mut Expr,
+    tail: bool,
     local_map: &mut Relabeller,
     ctx: Context,
     landing_ctx: &mut LandingContext,
@@ -93,6 +228,10 @@ fn optimize_expr(
             assert!(expr.vartype == Some(VarType::Undefined));
             false
         }
+        ExprKind::PrimNull => {
+            assert!(expr.vartype == Some(VarType::Null));
+            false
+        }
         ExprKind::PrimNumber { val: _ } => {
             assert!(expr.vartype == Some(VarType::Number));
             false
@@ -113,7 +252,7 @@ fn optimize_expr(
             funcidxs: _,
             closure,
         } => {
-            let ret = optimize_expr(&mut **closure, local_map, ctx, landing_ctx);
+            let ret = optimize_expr(&mut **closure, false, local_map, ctx, landing_ctx);
             if closure.vartype.is_none() {
                 let expr_tmp = std::mem::replace(&mut **closure, dummy_expr());
                 *expr = expr_tmp;
@@ -131,7 +270,7 @@ fn optimize_expr(
         } => {
             assert!(*expected != VarType::Any); // expected should never be any, otherwise we shouldn't have emitted this cast
             let cnl = *create_narrow_local;
-            let test_res = optimize_expr(&mut **test, local_map, ctx, landing_ctx);
+            let test_res = optimize_expr(&mut **test, false, local_map, ctx, landing_ctx);
             match test.vartype {
                 None => {
                     // test expr is noreturn
@@ -145,12 +284,18 @@ fn optimize_expr(
                         let ret = test_res
                             | if cnl {
                                 local_map.with_entry(|local_map, _, _| {
-                                    optimize_expr(&mut **true_expr, local_map, ctx, landing_ctx)
+                                    optimize_expr(
+                                        &mut **true_expr,
+                                        tail,
+                                        local_map,
+                                        ctx,
+                                        landing_ctx,
+                                    )
                                 })
                             } else {
-                                optimize_expr(&mut **true_expr, local_map, ctx, landing_ctx)
+                                optimize_expr(&mut **true_expr, tail, local_map, ctx, landing_ctx)
                             }
-                            | optimize_expr(&mut **false_expr, local_map, ctx, landing_ctx);
+                            | optimize_expr(&mut **false_expr, tail, local_map, ctx, landing_ctx);
                         ret | useful_update(
                             &mut expr.vartype,
                             union_type(true_expr.vartype, false_expr.vartype),
@@ -187,16 +332,22 @@ fn optimize_expr(
                             // only need the true branch
                             if cnl {
                                 local_map.with_entry(|local_map, _, _| {
-                                    optimize_expr(&mut **true_expr, local_map, ctx, landing_ctx);
+                                    optimize_expr(
+                                        &mut **true_expr,
+                                        tail,
+                                        local_map,
+                                        ctx,
+                                        landing_ctx,
+                                    );
                                 })
                             } else {
-                                optimize_expr(&mut **true_expr, local_map, ctx, landing_ctx);
+                                optimize_expr(&mut **true_expr, tail, local_map, ctx, landing_ctx);
                             }
                             let true_tmp = std::mem::replace(&mut **true_expr, dummy_expr());
                             write_expr(expr, vartype, test_tmp, true_tmp, cnl); // also sets expr.vartype appropriately
                         } else {
                             // only need the false branch
-                            optimize_expr(&mut **false_expr, local_map, ctx, landing_ctx);
+                            optimize_expr(&mut **false_expr, tail, local_map, ctx, landing_ctx);
                             let false_tmp = std::mem::replace(&mut **false_expr, dummy_expr());
                             write_expr(expr, vartype, test_tmp, false_tmp, false);
                             // also sets expr.vartype appropriately
@@ -211,7 +362,7 @@ fn optimize_expr(
         ExprKind::PrimAppl { prim_inst: _, args } => {
             let mut ret = false;
             for (i, arg) in args.iter_mut().enumerate() {
-                ret |= optimize_expr(arg, local_map, ctx, landing_ctx);
+                ret |= optimize_expr(arg, false, local_map, ctx, landing_ctx);
                 if arg.vartype.is_none() {
                     let mut tmp_args = std::mem::take(args).into_vec();
                     tmp_args.truncate(i + 1);
@@ -226,14 +377,14 @@ fn optimize_expr(
             args,
             location: _,
         } => {
-            let mut ret = optimize_expr(func, local_map, ctx, landing_ctx);
+            let mut ret = optimize_expr(func, false, local_map, ctx, landing_ctx);
             if func.vartype.is_none() {
                 let tmp_func = std::mem::replace(&mut **func, dummy_expr());
                 *expr = tmp_func;
                 true
             } else {
                 for (i, arg) in args.iter_mut().enumerate() {
-                    ret |= optimize_expr(arg, local_map, ctx, landing_ctx);
+                    ret |= optimize_expr(arg, false, local_map, ctx, landing_ctx);
                     if arg.vartype.is_none() {
                         let tmp_func = std::mem::replace(&mut **func, dummy_expr());
                         let mut tmp_args = std::mem::take(args).into_vec();
@@ -243,13 +394,21 @@ fn optimize_expr(
                         return true;
                     }
                 }
-                ret | try_devirtualize_appl(expr, local_map, ctx, landing_ctx) // note: inlining is not done in this optimization, because those heuristics are complicated
+                // devirtualising reoptimises the args to renumber their locals, which only works if the locals have not been renumbered yet,
+                // so we leave Appls inside the args of another Appl that is being devirtualised for the next run
+                // note: inlining is not done in this optimization, because those heuristics are complicated
+                // Appls in tail position are not devirtualised, because DirectAppls are never tail calls
+                if !tail && local_map.num_old() == local_map.num_new() {
+                    ret | try_devirtualize_appl(expr, local_map, ctx, landing_ctx)
+                } else {
+                    ret
+                }
             }
         }
         ExprKind::DirectAppl { funcidx, args } => {
             let mut ret = false;
             for (i, arg) in args.iter_mut().enumerate() {
-                ret |= optimize_expr(arg, local_map, ctx, landing_ctx);
+                ret |= optimize_expr(arg, false, local_map, ctx, landing_ctx);
                 if arg.vartype.is_none() {
                     let mut tmp_args = std::mem::take(args).into_vec();
                     tmp_args.truncate(i + 1);
@@ -257,6 +416,19 @@ fn optimize_expr(
                     return true;
                 }
             }
+            // call a constrained version of the func instead, if there is one that accepts these args
+            let signature_filter = &ctx.signature_filters[*funcidx];
+            if !signature_filter.is_empty() {
+                let arg_types: Box<[VarType]> =
+                    args.iter().map(|arg| arg.vartype.unwrap()).collect();
+                if let Some((_, constrained_funcidx)) = signature_filter
+                    .iter()
+                    .find(|(params, _)| params.superset(&arg_types))
+                {
+                    *funcidx = *constrained_funcidx;
+                    ret = true;
+                }
+            }
             ret | useful_update(&mut expr.vartype, ctx.result_types[*funcidx])
         }
         ExprKind::Conditional {
@@ -264,7 +436,7 @@ fn optimize_expr(
             true_expr,
             false_expr,
         } => {
-            let cond_res = optimize_expr(&mut **cond, local_map, ctx, landing_ctx);
+            let cond_res = optimize_expr(&mut **cond, false, local_map, ctx, landing_ctx);
             if cond.vartype.is_none() {
                 let expr_tmp = std::mem::replace(&mut **cond, dummy_expr());
                 *expr = expr_tmp;
@@ -275,13 +447,13 @@ fn optimize_expr(
                     // just keep and optimize the reachable branch
                     if cond_val {
                         // true_expr is reachable
-                        optimize_expr(&mut **true_expr, local_map, ctx, landing_ctx);
+                        optimize_expr(&mut **true_expr, tail, local_map, ctx, landing_ctx);
                         // just keep the true_expr (since the cond has no side-effects)
                         let expr_tmp = std::mem::replace(&mut **true_expr, dummy_expr());
                         *expr = expr_tmp;
                     } else {
                         // false_expr is reachable
-                        optimize_expr(&mut **false_expr, local_map, ctx, landing_ctx);
+                        optimize_expr(&mut **false_expr, tail, local_map, ctx, landing_ctx);
                         // just keep the false_expr (since the cond has no side-effects)
                         let expr_tmp = std::mem::replace(&mut **false_expr, dummy_expr());
                         *expr = expr_tmp;
@@ -291,8 +463,8 @@ fn optimize_expr(
                     // not a constant expr
                     // so we have to optimize both branches
                     let ret = cond_res
-                        | optimize_expr(&mut **true_expr, local_map, ctx, landing_ctx)
-                        | optimize_expr(&mut **false_expr, local_map, ctx, landing_ctx);
+                        | optimize_expr(&mut **true_expr, tail, local_map, ctx, landing_ctx)
+                        | optimize_expr(&mut **false_expr, tail, local_map, ctx, landing_ctx);
                     ret | useful_update(
                         &mut expr.vartype,
                         union_type(true_expr.vartype, false_expr.vartype),
@@ -301,12 +473,12 @@ fn optimize_expr(
             }
         }
         ExprKind::Declaration {
-            local: _,
+            local,
             init,
             contained_expr,
         } => {
             let (init_res, init_is_none) = if let Some(init_expr) = init {
-                let res = optimize_expr(&mut **init_expr, local_map, ctx, landing_ctx);
+                let res = optimize_expr(&mut **init_expr, false, local_map, ctx, landing_ctx);
                 (res, init_expr.vartype.is_none())
             } else {
                 (false, false)
@@ -316,10 +488,36 @@ fn optimize_expr(
                 *expr = expr_tmp;
                 true
             } else {
+                let narrow_res = match init {
+                    Some(init_expr) => try_narrow_local(
+                        local,
+                        init_expr.vartype.unwrap(),
+                        local_map.num_old(),
+                        contained_expr,
+                    ),
+                    None => false,
+                };
+                let mut new_localidx: usize = 0;
                 let real_res = init_res
-                    | local_map.with_entry(|local_map, _, _| {
-                        optimize_expr(&mut **contained_expr, local_map, ctx, landing_ctx)
+                    | narrow_res
+                    | local_map.with_entry(|local_map, _, new_idx| {
+                        new_localidx = new_idx;
+                        optimize_expr(&mut **contained_expr, tail, local_map, ctx, landing_ctx)
                     });
+                if let Some(init_expr) = init {
+                    if is_read_of_local(contained_expr, new_localidx)
+                        && contained_expr
+                            .vartype
+                            .unwrap()
+                            .superset(&init_expr.vartype.unwrap())
+                    {
+                        // the declaration just reads back the value it was initialised with
+                        // (the frontend does this for the func of direct calls), so we can use init directly
+                        let expr_tmp = std::mem::replace(&mut **init_expr, dummy_expr());
+                        *expr = expr_tmp;
+                        return true;
+                    }
+                }
                 real_res | useful_update(&mut expr.vartype, contained_expr.vartype)
             }
         }
@@ -329,7 +527,7 @@ fn optimize_expr(
         } => {
             assert!(expr.vartype == Some(VarType::Undefined));
             let ret = relabel_target(target, local_map)
-                | optimize_expr(&mut **expr2, local_map, ctx, landing_ctx);
+                | optimize_expr(&mut **expr2, false, local_map, ctx, landing_ctx);
             // If the RHS of assignment is none, then the assignment can't actually happen,
             // so we are just executing the RHS for its side-effects.
             if expr2.vartype.is_none() {
@@ -343,7 +541,7 @@ fn optimize_expr(
         }
         ExprKind::Return { expr: expr2 } => {
             assert!(expr.vartype == None);
-            let ret = optimize_expr(&mut **expr2, local_map, ctx, landing_ctx);
+            let ret = optimize_expr(&mut **expr2, true, local_map, ctx, landing_ctx);
             match expr2.vartype {
                 None => {
                     let expr_tmp = std::mem::replace(&mut **expr2, dummy_expr());
@@ -361,7 +559,7 @@ fn optimize_expr(
             expr: expr2,
         } => {
             assert!(expr.vartype == None);
-            let ret = optimize_expr(&mut **expr2, local_map, ctx, landing_ctx);
+            let ret = optimize_expr(&mut **expr2, false, local_map, ctx, landing_ctx);
             match expr2.vartype {
                 None => {
                     let expr_tmp = std::mem::replace(&mut **expr2, dummy_expr());
@@ -376,7 +574,7 @@ fn optimize_expr(
         }
         ExprKind::Block { expr: expr2 } => {
             let (ret, landing_vartype) = landing_ctx.with_landing(|landing_ctx| {
-                optimize_expr(&mut **expr2, local_map, ctx, landing_ctx)
+                optimize_expr(&mut **expr2, tail, local_map, ctx, landing_ctx)
             });
             if landing_vartype.is_none() {
                 // todo! reoptimise expr2 without this landing
@@ -389,19 +587,49 @@ fn optimize_expr(
                     union_type(expr2.vartype, landing_vartype),
                 ) // should be removed when we can eliminate unused landings
             } else {
-                ret | useful_update(
-                    &mut expr.vartype,
-                    union_type(expr2.vartype, landing_vartype),
-                )
+                let res = ret
+                    | useful_update(
+                        &mut expr.vartype,
+                        union_type(expr2.vartype, landing_vartype),
+                    );
+                if expr2.vartype.is_some() && expr2.vartype != expr.vartype {
+                    // the backend does not widen the value of the inner expr, so we widen it with a Break instead
+                    // (this happens when the inner expr got narrowed but the Breaks to this Block did not)
+                    let expr2_tmp = std::mem::replace(&mut **expr2, dummy_expr());
+                    **expr2 = Expr {
+                        vartype: None,
+                        kind: ExprKind::Break {
+                            num_frames: 0,
+                            expr: Box::new(expr2_tmp),
+                        },
+                    };
+                    true
+                } else {
+                    res
+                }
             }
         }
+        ExprKind::Loop { expr: expr2 } => {
+            // Breaks that target this loop restart the loop instead of exiting it,
+            // so they do not contribute to the type of the loop
+            let (ret, _) = landing_ctx.with_landing(|landing_ctx| {
+                optimize_expr(expr2, false, local_map, ctx, landing_ctx)
+            });
+            ret | useful_update(&mut expr.vartype, expr2.vartype)
+        }
         ExprKind::Sequence { content } => {
             let tmp_content = std::mem::take(content);
             let mut changed = false;
             let mut new_content = Vec::new();
             let tmp_content_len = tmp_content.len();
-            for mut expr2 in tmp_content {
-                changed |= optimize_expr(&mut expr2, local_map, ctx, landing_ctx);
+            for (i, mut expr2) in tmp_content.into_iter().enumerate() {
+                changed |= optimize_expr(
+                    &mut expr2,
+                    tail && i + 1 == tmp_content_len,
+                    local_map,
+                    ctx,
+                    landing_ctx,
+                );
                 let is_none = expr2.vartype.is_none();
                 new_content.push(expr2);
                 if is_none {
@@ -443,299 +671,68 @@ fn optimize_expr(
  * The return value is true if the expr got changed, or false otherwise.
  */
 fn try_const_eval(expr: &mut Expr) -> bool {
-    fn set_vartype(out: &mut Option<VarType>, new: VarType) -> bool {
-        useful_update(out, Some(new))
-    }
-    fn try_as_prim_number(expr: &Expr) -> Result<f64, ()> {
-        assert!(expr.vartype == Some(VarType::Number));
-        if let ExprKind::PrimNumber { val } = &expr.kind {
-            Ok(*val)
+    if let ExprKind::PrimAppl { prim_inst, args } = &mut expr.kind {
+        if let Some(folded) = fold_constants(*prim_inst, args) {
+            *expr = folded;
+            true
         } else {
-            Err(())
+            let (_, result) = prim_inst.signature();
+            useful_update(&mut expr.vartype, result)
         }
+    } else {
+        panic!("Expected PrimAppl");
     }
-    fn try_as_prim_boolean(expr: &Expr) -> Result<bool, ()> {
-        assert!(expr.vartype == Some(VarType::Boolean));
-        if let ExprKind::PrimBoolean { val } = &expr.kind {
-            Ok(*val)
-        } else {
-            Err(())
-        }
+}
+
+/**
+ * Narrows the type of a declared local to the type of its initializer, if the local is never assigned to.
+ * `localidx` is the (old) index of the local, and `contained_expr` is the (not yet optimised) scope of the local.
+ * The reads of the local in `contained_expr` are also narrowed, and optimising `contained_expr` will then propagate the narrower type.
+ * The return value is true if the local got narrowed, or false otherwise.
+ */
+fn try_narrow_local(
+    local: &mut VarType,
+    init_vartype: VarType,
+    localidx: usize,
+    contained_expr: &mut Expr,
+) -> bool {
+    if *local != VarType::Any || init_vartype == VarType::Any {
+        return false;
     }
-    fn try_as_prim_string(expr: &Expr) -> Result<&str, ()> {
-        assert!(expr.vartype == Some(VarType::String));
-        if let ExprKind::PrimString { val } = &expr.kind {
-            Ok(val.as_str())
-        } else {
-            Err(())
+    // locals declared inside contained_expr have larger indices, so every target with this localidx refers to this local
+    let mut is_assigned = false;
+    walk_expr(contained_expr, false, 0, &mut |expr, _, _| {
+        if let ExprKind::Assign {
+            target:
+                TargetExpr::Local {
+                    localidx: target_localidx,
+                    next: None,
+                },
+            expr: _,
+        } = &expr.kind
+        {
+            is_assigned |= *target_localidx == localidx;
         }
+    });
+    if is_assigned {
+        return false;
     }
-    if let ExprKind::PrimAppl { prim_inst, args } = &mut expr.kind {
-        match prim_inst {
-            PrimInst::NumberAdd => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_number(a + b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Number)
-                }
-            }
-            PrimInst::NumberSub => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_number(a - b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Number)
-                }
-            }
-            PrimInst::NumberMul => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_number(a * b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Number)
-                }
-            }
-            PrimInst::NumberDiv => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_number(a / b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Number)
-                }
-            }
-            PrimInst::NumberRem => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_number(a % b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Number)
-                }
-            }
-            PrimInst::NumberEq => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a == b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::NumberNeq => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a != b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::NumberGt => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a > b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::NumberLt => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a < b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::NumberGe => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a >= b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::NumberLe => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_number(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_number(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a <= b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::BooleanEq => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_boolean(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_boolean(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a == b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::BooleanNeq => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_boolean(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_boolean(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a != b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::BooleanAnd => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_boolean(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_boolean(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a & b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::BooleanOr => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_boolean(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_boolean(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a | b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::BooleanNot => {
-                assert!(args.len() == 1);
-                if let Ok(a) = try_as_prim_boolean(&args[0]) {
-                    *expr = make_prim_boolean(!a);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::NumberNegate => {
-                assert!(args.len() == 1);
-                if let Ok(a) = try_as_prim_number(&args[0]) {
-                    *expr = make_prim_number(-a);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Number)
-                }
-            }
-            PrimInst::StringAdd => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_string(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_string(&args[1])?)))
-                {
-                    *expr = make_prim_string(String::from(a) + b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::String)
-                }
-            }
-            PrimInst::StringEq => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_string(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_string(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a == b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::StringNeq => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_string(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_string(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a != b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::StringGt => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_string(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_string(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a > b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::StringLt => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_string(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_string(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a < b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::StringGe => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_string(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_string(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a >= b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
-            }
-            PrimInst::StringLe => {
-                assert!(args.len() == 2);
-                if let Ok((a, b)) = try_as_prim_string(&args[0])
-                    .and_then(|a| Ok((a, try_as_prim_string(&args[1])?)))
-                {
-                    *expr = make_prim_boolean(a <= b);
-                    true
-                } else {
-                    set_vartype(&mut expr.vartype, VarType::Boolean)
-                }
+    *local = init_vartype;
+    walk_expr(contained_expr, false, 0, &mut |expr, _, _| {
+        if let ExprKind::VarName {
+            source:
+                TargetExpr::Local {
+                    localidx: source_localidx,
+                    next: None,
+                },
+        } = &expr.kind
+        {
+            if *source_localidx == localidx {
+                expr.vartype = Some(init_vartype);
             }
         }
-    } else {
-        panic!("Expected PrimAppl");
-    }
+    });
+    true
 }
 
 /**
@@ -765,8 +762,8 @@ fn try_devirtualize_appl(
             let overloads = std::mem::take(funcidxs);
             let mut allowable_overloads: Vec<OverloadEntry> = Vec::new();
             // iterate in the reverse direction, since we match them from back to front
-            'outer: for overload in Vec::from(overloads).into_iter() {
-                let sig: &[VarType] = &ctx.param_types[overload.funcidx];
+            'outer: for overload in Vec::from(overloads).into_iter().rev() {
+                let sig: &[VarType] = overload_signature(overload, ctx);
                 if sig.len() != args.len() {
                     // wrong number of params, will never be matched
                     continue;
@@ -780,7 +777,7 @@ fn try_devirtualize_appl(
                 );
                 if let Some(restricted_sig) = opt_restricted_sig {
                     for allowable_overload in &allowable_overloads {
-                        if ctx.param_types[allowable_overload.funcidx].superset(&restricted_sig) {
+                        if overload_signature(*allowable_overload, ctx).superset(&restricted_sig) {
                             // this is not a useful overload
                             continue 'outer;
                         }
@@ -865,7 +862,7 @@ fn try_devirtualize_appl(
                                                 vartype: Some(
                                                     intersect_type(
                                                         args[i].vartype.unwrap(),
-                                                        ctx.param_types[oe.funcidx][i],
+                                                        overload_signature(oe, ctx)[i],
                                                     )
                                                     .unwrap(),
                                                 ),
@@ -901,7 +898,7 @@ fn try_devirtualize_appl(
                             .group_by(|oe| {
                                 intersect_type(
                                     args[idx].vartype.unwrap(),
-                                    ctx.param_types[oe.funcidx][idx],
+                                    overload_signature(*oe, ctx)[idx],
                                 )
                                 .unwrap()
                             })
@@ -954,7 +951,8 @@ fn try_devirtualize_appl(
                                 arg_localidxs[idx] = orig_arg_localidx;
                                 let tmp_seq = make_sequence_from_exprs(new_out);
                                 out.push(Expr {
-                                    vartype: tmp_seq.vartype,
+                                    // if the check fails, we fall through to the next overload
+                                    vartype: union_type(tmp_seq.vartype, Some(VarType::Undefined)),
                                     kind: ExprKind::TypeCast {
                                         test: Box::new(Expr {
                                             vartype: args[idx].vartype,
@@ -1036,7 +1034,7 @@ fn try_devirtualize_appl(
 
                             // reoptimize arg, to get the locals re-numbered
                             // hopefully this is not too slow (since each call can only be converted to direct once)
-                            optimize_expr(&mut arg, local_map, ctx, landing_ctx);
+                            optimize_expr(&mut arg, false, local_map, ctx, landing_ctx);
 
                             Expr {
                                 vartype: inner_expr.vartype,
@@ -1070,7 +1068,7 @@ fn try_devirtualize_appl(
                             Expr {
                                 vartype: wrapped_declarations_expr.vartype,
                                 kind: ExprKind::Declaration {
-                                    local: closure.vartype.unwrap(),
+                                    local: tmp_closure.vartype.unwrap(),
                                     init: Some(Box::new(tmp_closure)),
                                     contained_expr: Box::new(wrapped_declarations_expr),
                                 },
@@ -1136,6 +1134,18 @@ fn intersect_type(first: VarType, second: VarType) -> Option<VarType> {
     }
 }
 
+/**
+ * Returns the types of the params of an overload that are matched against the args of an Appl (i.e. excluding the closure param).
+ */
+fn overload_signature<'a>(oe: OverloadEntry, ctx: Context<'a, '_, '_>) -> &'a [VarType] {
+    let params: &[VarType] = &ctx.param_types[oe.funcidx];
+    if oe.has_closure_param {
+        &params[1..]
+    } else {
+        params
+    }
+}
+
 fn dummy_expr() -> Expr {
     Expr {
         vartype: Some(VarType::Undefined),
@@ -1150,23 +1160,61 @@ fn make_prim_undefined() -> Expr {
     }
 }
 
-fn make_prim_number(val: f64) -> Expr {
-    Expr {
-        vartype: Some(VarType::Number),
-        kind: ExprKind::PrimNumber { val: val },
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn make_prim_boolean(val: bool) -> Expr {
-    Expr {
-        vartype: Some(VarType::Boolean),
-        kind: ExprKind::PrimBoolean { val: val },
+    #[test]
+    fn keeps_entry_result() {
+        // after devirtualising the call to func#0, the body of func#1 only produces a number,
+        // but the embedder still reads the result of the entry point as an Any
+        let text = r#"
+            global#0 = any;
+            func#0 () -> any {
+              (number:number 1.0)
+            }
+            func#1 () -> any {
+              (seq:any
+                (assign:undefined global#0 (func:func [func#0] (undefined:undefined)))
+                (typecast:any number narrow
+                  (appl:any 0:1:1-1:2
+                    (typecast:func func narrow (var:any global#0) (var:func local#0) (trap:! 0x16 0:1:1-1:2)))
+                  (prim:number number_mul (var:number local#0) (number:number 100.0))
+                  (trap:! 0x13 0:1:1-1:2)))
+            }
+            entry func#1;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(program.funcs[0].result, Some(VarType::Number));
+        assert_eq!(program.funcs[1].result, Some(VarType::Any));
+        assert_eq!(program.funcs[1].expr.vartype, Some(VarType::Number));
     }
-}
 
-fn make_prim_string(val: String) -> Expr {
-    Expr {
-        vartype: Some(VarType::String),
-        kind: ExprKind::PrimString { val: val },
+    #[test]
+    fn keeps_tail_appl() {
+        // the Appl in func#0 is a tail call, so it must not become a DirectAppl
+        let text = r#"
+            func#0 (any) -> any {
+              (typecast:any number narrow (var:any local#0)
+                (if:any
+                  (prim:boolean number_eq (var:number local#1) (number:number 0.0))
+                  (string:string "done")
+                  (appl:any 0:1:1-1:2
+                    (func:func [func#0] (undefined:undefined))
+                    (prim:number number_sub (var:number local#1) (number:number 1.0))))
+                (trap:! 0x11 0:1:1-1:2))
+            }
+            func#1 () -> any {
+              (appl:any 0:1:1-1:2 (func:func [func#0] (undefined:undefined)) (number:number 5.0))
+            }
+            entry func#1;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, _) = optimize(program);
+        assert!(text::print_expr(&program.funcs[0].expr).contains("(appl:any"));
+        // the call in the entry point is not a tail call, so it is devirtualised
+        assert!(text::print_expr(&program.funcs[1].expr).contains("(call:any func#0"));
     }
 }
@@ -0,0 +1,345 @@
+use super::*;
+
+/**
+ * Removes all funcs, globals and struct types that are unreachable from the entry point, and renumbers the remaining ones.
+ * A func is reachable if it is the entry point, or if it is referenced by a DirectAppl, a PrimFunc overload, or a signature_filter entry of a reachable func.
+ * A global is reachable if it is used by a reachable func.
+ * A struct type is reachable if it is used anywhere in a reachable func or global, or as a field of a reachable struct type.
+ * Imports are never removed, since they are part of the interface with the host.
+ * The second return value is true if the program got changed, or false otherwise.
+ */
+pub fn optimize(mut program: Program) -> (Program, bool) {
+    let num_imports = program.imports.len();
+    let mut live_funcs: Vec<bool> = vec![false; num_imports + program.funcs.len()];
+    let mut live_globals: Vec<bool> = vec![false; program.globals.len()];
+    let mut live_structs: Vec<bool> = vec![false; program.struct_types.len()];
+
+    // mark all reachable funcs (and the globals and struct types that they use)
+    let mut func_worklist: Vec<FuncIdx> = vec![program.entry_point];
+    live_funcs[program.entry_point] = true;
+    while let Some(funcidx) = func_worklist.pop() {
+        if funcidx < num_imports {
+            continue;
+        }
+        visit_func(
+            &mut program.funcs[funcidx - num_imports],
+            &mut |index| match index {
+                Index::Func(funcidx) => {
+                    if !live_funcs[*funcidx] {
+                        live_funcs[*funcidx] = true;
+                        func_worklist.push(*funcidx);
+                    }
+                }
+                Index::Global(globalidx) => live_globals[*globalidx] = true,
+                Index::Struct(typeidx) => live_structs[*typeidx] = true,
+            },
+        );
+    }
+
+    // mark all struct types used by reachable globals and by the fields of reachable struct types
+    for (globalidx, vartype) in program.globals.iter_mut().enumerate() {
+        if live_globals[globalidx] {
+            visit_vartype(vartype, &mut |index| {
+                mark_struct(index, &mut live_structs);
+            });
+        }
+    }
+    loop {
+        let mut changed = false;
+        for (typeidx, fields) in program.struct_types.iter_mut().enumerate() {
+            if live_structs[typeidx] {
+                for field in fields.iter_mut() {
+                    visit_vartype(field, &mut |index| {
+                        changed |= mark_struct(index, &mut live_structs)
+                    });
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    if live_funcs[num_imports..].iter().all(|x| *x)
+        && live_globals.iter().all(|x| *x)
+        && live_structs.iter().all(|x| *x)
+    {
+        return (program, false);
+    }
+
+    // compute the new indices (imports keep their indices)
+    let func_map = make_index_map(&live_funcs[num_imports..], num_imports);
+    let global_map = make_index_map(&live_globals, 0);
+    let struct_map = make_index_map(&live_structs, 0);
+    let mut relabel = |index: Index| match index {
+        Index::Func(funcidx) => {
+            if *funcidx >= num_imports {
+                *funcidx = func_map[*funcidx - num_imports].unwrap();
+            }
+        }
+        Index::Global(globalidx) => *globalidx = global_map[*globalidx].unwrap(),
+        Index::Struct(typeidx) => *typeidx = struct_map[*typeidx].unwrap(),
+    };
+
+    // remove the dead things and relabel the rest
+    program.funcs = retain_live(
+        std::mem::take(&mut program.funcs),
+        &live_funcs[num_imports..],
+    );
+    for func in &mut program.funcs {
+        visit_func(func, &mut relabel);
+    }
+    relabel(Index::Func(&mut program.entry_point));
+    program.globals = retain_live(std::mem::take(&mut program.globals), &live_globals);
+    for vartype in &mut program.globals {
+        visit_vartype(vartype, &mut relabel);
+    }
+    program.struct_types = retain_live(std::mem::take(&mut program.struct_types), &live_structs);
+    for fields in &mut program.struct_types {
+        for field in fields.iter_mut() {
+            visit_vartype(field, &mut relabel);
+        }
+    }
+
+    (program, true)
+}
+
+// A reference to an index stored somewhere in the program.
+enum Index<'a> {
+    Func(&'a mut FuncIdx),
+    Global(&'a mut usize),
+    Struct(&'a mut usize),
+}
+
+// Marks the struct type as reachable, returning true if it was not previously marked.
+fn mark_struct(index: Index, live_structs: &mut [bool]) -> bool {
+    if let Index::Struct(typeidx) = index {
+        if !live_structs[*typeidx] {
+            live_structs[*typeidx] = true;
+            return true;
+        }
+    }
+    false
+}
+
+// Maps each old index to its new index (or None if it is dead).
+fn make_index_map(live: &[bool], offset: usize) -> Vec<Option<usize>> {
+    let mut next = offset;
+    live.iter()
+        .map(|is_live| {
+            if *is_live {
+                next += 1;
+                Some(next - 1)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn retain_live<T>(items: Vec<T>, live: &[bool]) -> Vec<T> {
+    items
+        .into_iter()
+        .zip(live.iter())
+        .filter_map(|(item, is_live)| if *is_live { Some(item) } else { None })
+        .collect()
+}
+
+fn visit_vartype(vartype: &mut VarType, f: &mut dyn FnMut(Index)) {
+    if let VarType::StructT { typeidx } = vartype {
+        f(Index::Struct(typeidx));
+    }
+}
+
+fn visit_opt_vartype(opt_vartype: &mut Option<VarType>, f: &mut dyn FnMut(Index)) {
+    if let Some(vartype) = opt_vartype {
+        visit_vartype(vartype, f);
+    }
+}
+
+fn visit_func(func: &mut Func, f: &mut dyn FnMut(Index)) {
+    for param in func.params.iter_mut() {
+        visit_vartype(param, f);
+    }
+    visit_opt_vartype(&mut func.result, f);
+    for (params, result, funcidx) in &mut func.signature_filter {
+        for param in params.iter_mut() {
+            visit_vartype(param, f);
+        }
+        visit_vartype(result, f);
+        f(Index::Func(funcidx));
+    }
+    visit_expr(&mut func.expr, f);
+}
+
+fn visit_target(target: &mut TargetExpr, f: &mut dyn FnMut(Index)) {
+    let mut next = match target {
+        TargetExpr::Global { globalidx, next } => {
+            f(Index::Global(globalidx));
+            next
+        }
+        TargetExpr::Local { localidx: _, next } => next,
+    };
+    while let Some(field) = next {
+        f(Index::Struct(&mut field.typeidx));
+        next = &mut field.next;
+    }
+}
+
+fn visit_expr(expr: &mut Expr, f: &mut dyn FnMut(Index)) {
+    visit_opt_vartype(&mut expr.vartype, f);
+    // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+    match &mut expr.kind {
+        ExprKind::PrimUndefined
+        | ExprKind::PrimNull
+        | ExprKind::PrimNumber { val: _ }
+        | ExprKind::PrimBoolean { val: _ }
+        | ExprKind::PrimString { val: _ }
+        | ExprKind::Trap {
+            code: _,
+            location: _,
+        } => {}
+        ExprKind::PrimStructT { typeidx } => f(Index::Struct(typeidx)),
+        ExprKind::PrimFunc { funcidxs, closure } => {
+            for oe in funcidxs.iter_mut() {
+                f(Index::Func(&mut oe.funcidx));
+            }
+            visit_expr(closure, f);
+        }
+        ExprKind::TypeCast {
+            test,
+            expected,
+            create_narrow_local: _,
+            true_expr,
+            false_expr,
+        } => {
+            visit_expr(test, f);
+            visit_vartype(expected, f);
+            visit_expr(true_expr, f);
+            visit_expr(false_expr, f);
+        }
+        ExprKind::VarName { source } => visit_target(source, f),
+        ExprKind::PrimAppl { prim_inst: _, args } => {
+            for arg in args.iter_mut() {
+                visit_expr(arg, f);
+            }
+        }
+        ExprKind::Appl {
+            func,
+            args,
+            location: _,
+        } => {
+            visit_expr(func, f);
+            for arg in args.iter_mut() {
+                visit_expr(arg, f);
+            }
+        }
+        ExprKind::DirectAppl { funcidx, args } => {
+            f(Index::Func(funcidx));
+            for arg in args.iter_mut() {
+                visit_expr(arg, f);
+            }
+        }
+        ExprKind::Conditional {
+            cond,
+            true_expr,
+            false_expr,
+        } => {
+            visit_expr(cond, f);
+            visit_expr(true_expr, f);
+            visit_expr(false_expr, f);
+        }
+        ExprKind::Declaration {
+            local,
+            init,
+            contained_expr,
+        } => {
+            visit_vartype(local, f);
+            if let Some(init_expr) = init {
+                visit_expr(init_expr, f);
+            }
+            visit_expr(contained_expr, f);
+        }
+        ExprKind::Assign { target, expr } => {
+            visit_target(target, f);
+            visit_expr(expr, f);
+        }
+        ExprKind::Return { expr }
+        | ExprKind::Break {
+            num_frames: _,
+            expr,
+        }
+        | ExprKind::Block { expr }
+        | ExprKind::Loop { expr } => visit_expr(expr, f),
+        ExprKind::Sequence { content } => {
+            for inner_expr in content.iter_mut() {
+                visit_expr(inner_expr, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_unreachable() {
+        let text = r#"
+            import#0 = "misc" "display" (string) -> undefined;
+            struct#0 = (number);
+            struct#1 = (struct#2);
+            struct#2 = (any);
+            global#0 = struct#0;
+            global#1 = struct#1;
+            func#1 () -> undefined {
+              (seq:undefined
+                (assign:undefined global#0 (struct:struct#0 struct#0))
+                (call:undefined func#0 (string:string "dead")))
+            }
+            func#2 (number) -> number
+              filter (number) -> number = func#4 {
+              (var:number local#0)
+            }
+            func#3 () -> any {
+              (seq:any
+                (assign:undefined global#1 (struct:struct#1 struct#1))
+                (func:func [func#2] (undefined:undefined)))
+            }
+            func#4 (number) -> number {
+              (var:number local#0)
+            }
+            entry func#3;
+        "#;
+        let (program, changed) = optimize(text::parse_program(text).unwrap());
+        assert!(changed);
+        assert_eq!(
+            text::print_program(&program),
+            text::print_program(
+                &text::parse_program(
+                    r#"
+                    struct#0 = (struct#1);
+                    struct#1 = (any);
+                    import#0 = "misc" "display" (string) -> undefined;
+                    global#0 = struct#0;
+                    entry func#2;
+                    func#1 (number) -> number
+                      filter (number) -> number = func#3 {
+                      (var:number local#0)
+                    }
+                    func#2 () -> any {
+                      (seq:any
+                        (assign:undefined global#0 (struct:struct#0 struct#0))
+                        (func:func [func#1] (undefined:undefined)))
+                    }
+                    func#3 (number) -> number {
+                      (var:number local#0)
+                    }
+                    "#
+                )
+                .unwrap()
+            )
+        );
+        assert!(!optimize(program).1);
+    }
+}
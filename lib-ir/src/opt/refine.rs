@@ -0,0 +1,518 @@
+use super::superset::Superset;
+use super::walk::walk_expr;
+use super::*;
+
+/**
+ * Narrows the vartype of each read of a local to the type that the local is known to have at that point of the control flow.
+ * This lets propagate remove the TypeCasts (and boxing) that are made redundant by an earlier check, e.g. in
+ * `if (!is_number(x)) { error(...); } return x + 1;`, `x` must be a Number after the `if`, since the true branch never finishes.
+ *
+ * We walk the func in evaluation order, keeping track of the refined type of each local in scope:
+ * 1. A TypeCast narrows the test local (and its copies) in its true branch.
+ * 2. An Assign sets the refined type of the local to the type of the assigned value.
+ * 3. A boolean expr (and a boolean local) remembers the refinements that hold if it is true and if it is false, for the branches of a Conditional.
+ * 4. At the end of a Conditional or Block, we take the union of the states from all the ways to get there, ignoring the ones that never finish.
+ * 5. A Loop forgets what it knows about the locals that are assigned in its body, so the state at the start of the body holds for every iteration.
+ *
+ * The frontend evaluates conditions through chains of Declarations, Blocks and boolean_not, so we follow copies of locals through those.
+ * The vartypes of the parents of the narrowed reads are not updated here; propagate does that afterwards.
+ * The return value is true if any read got narrowed, or false otherwise.
+ */
+pub(super) fn refine_locals(func: &mut Func) -> bool {
+    let mut walker = Walker {
+        declared: func.params.to_vec(),
+        landings: vec![(func.params.len(), Flow::unreachable())],
+        changed: false,
+    };
+    walker.walk(&mut func.expr, vec![Local::default(); func.params.len()]);
+    walker.changed
+}
+
+type Refinements = Vec<(usize, VarType)>;
+
+#[derive(Clone, Default, PartialEq)]
+struct Local {
+    vartype: Option<VarType>, // the refined type, or None if it is not known to be narrower than the declared type
+    copy_of: Option<usize>, // the local that this local was initialised from, if neither of them got assigned since then
+    implies: Option<(Option<Refinements>, Option<Refinements>)>, // for boolean locals, the refinements if it is true and if it is false (None if it can't have that value)
+}
+
+type State = Vec<Local>;
+
+struct Flow {
+    out: Option<State>, // the state after the expr finishes normally, or None if it can't finish normally
+    cond: Option<(Option<State>, Option<State>)>, // for boolean exprs, the states if the value is true and if it is false
+}
+
+impl Flow {
+    fn unreachable() -> Self {
+        Flow {
+            out: None,
+            cond: None,
+        }
+    }
+    fn plain(out: Option<State>) -> Self {
+        Flow { out, cond: None }
+    }
+    fn split(self) -> (Option<State>, Option<State>) {
+        match self.cond {
+            Some(cond) => cond,
+            None => (self.out.clone(), self.out),
+        }
+    }
+    fn map<F: Fn(State) -> State>(self, f: F) -> Self {
+        Flow {
+            out: self.out.map(&f),
+            cond: self
+                .cond
+                .map(|(when_true, when_false)| (when_true.map(&f), when_false.map(&f))),
+        }
+    }
+}
+
+struct Walker {
+    declared: Vec<VarType>,       // the declared type of each local in scope
+    landings: Vec<(usize, Flow)>, // for the func and each enclosing Block or Loop, the number of locals in scope and the union of the Breaks to it
+    changed: bool,
+}
+
+impl Walker {
+    fn walk(&mut self, expr: &mut Expr, state: State) -> Flow {
+        let flow = self.walk_kind(expr, state);
+        match expr.vartype {
+            None => Flow::unreachable(),
+            Some(VarType::Boolean) => flow,
+            Some(_) => Flow::plain(flow.out),
+        }
+    }
+
+    fn walk_kind(&mut self, expr: &mut Expr, mut state: State) -> Flow {
+        // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+        match &mut expr.kind {
+            ExprKind::PrimUndefined
+            | ExprKind::PrimNull
+            | ExprKind::PrimNumber { val: _ }
+            | ExprKind::PrimString { val: _ }
+            | ExprKind::PrimStructT { typeidx: _ } => Flow::plain(Some(state)),
+            ExprKind::PrimBoolean { val } => {
+                let cond = if *val {
+                    (Some(state.clone()), None)
+                } else {
+                    (None, Some(state.clone()))
+                };
+                Flow {
+                    out: Some(state),
+                    cond: Some(cond),
+                }
+            }
+            ExprKind::VarName {
+                source:
+                    TargetExpr::Local {
+                        localidx,
+                        next: None,
+                    },
+            } => {
+                let localidx = *localidx;
+                let vartype = self.vartype_of(&state, localidx);
+                if let Some(narrower) = narrowed(expr.vartype.unwrap(), vartype) {
+                    expr.vartype = Some(narrower);
+                    self.changed = true;
+                }
+                let cond = state[localidx]
+                    .implies
+                    .as_ref()
+                    .map(|(when_true, when_false)| {
+                        (
+                            self.refined(&state, when_true),
+                            self.refined(&state, when_false),
+                        )
+                    });
+                Flow {
+                    out: Some(state),
+                    cond,
+                }
+            }
+            ExprKind::VarName { source: _ } => Flow::plain(Some(state)),
+            ExprKind::PrimFunc {
+                funcidxs: _,
+                closure,
+            } => Flow::plain(self.walk(closure, state).out),
+            ExprKind::TypeCast {
+                test,
+                expected,
+                create_narrow_local,
+                true_expr,
+                false_expr,
+            } => {
+                let state = match self.walk(test, state).out {
+                    Some(state) => state,
+                    None => return Flow::unreachable(),
+                };
+                let test_localidx = local_read(test);
+                let mut true_state = state.clone();
+                if let Some(localidx) = test_localidx {
+                    self.refine(&mut true_state, localidx, *expected);
+                }
+                let true_flow = if *create_narrow_local {
+                    true_state.push(Local {
+                        vartype: None,
+                        copy_of: test_localidx,
+                        implies: None,
+                    });
+                    self.declared.push(*expected);
+                    let flow = self.walk(true_expr, true_state);
+                    self.declared.pop();
+                    let num_locals = state.len();
+                    flow.map(|s| self.truncate(s, num_locals))
+                } else {
+                    self.walk(true_expr, true_state)
+                };
+                let false_flow = self.walk(false_expr, state);
+                merge_flows(true_flow, false_flow)
+            }
+            ExprKind::PrimAppl { prim_inst, args } => {
+                let flow = self.walk_seq(args.iter_mut(), state);
+                if *prim_inst == PrimInst::BooleanNot {
+                    Flow {
+                        out: flow.out,
+                        cond: flow
+                            .cond
+                            .map(|(when_true, when_false)| (when_false, when_true)),
+                    }
+                } else {
+                    Flow::plain(flow.out)
+                }
+            }
+            ExprKind::Appl {
+                func,
+                args,
+                location: _,
+            } => Flow::plain(
+                self.walk_seq(std::iter::once(&mut **func).chain(args.iter_mut()), state)
+                    .out,
+            ),
+            ExprKind::DirectAppl { funcidx: _, args } => {
+                Flow::plain(self.walk_seq(args.iter_mut(), state).out)
+            }
+            ExprKind::Conditional {
+                cond,
+                true_expr,
+                false_expr,
+            } => {
+                let (when_true, when_false) = self.walk(cond, state).split();
+                let true_flow = self.walk_opt(true_expr, when_true);
+                let false_flow = self.walk_opt(false_expr, when_false);
+                merge_flows(true_flow, false_flow)
+            }
+            ExprKind::Declaration {
+                local,
+                init,
+                contained_expr,
+            } => {
+                let mut new_local = Local::default();
+                if let Some(init_expr) = init {
+                    let flow = self.walk(init_expr, state);
+                    state = match flow.out {
+                        Some(ref out) => out.clone(),
+                        None => return Flow::unreachable(),
+                    };
+                    new_local.vartype = narrowed(*local, init_expr.vartype.unwrap());
+                    new_local.copy_of = local_read(init_expr);
+                    new_local.implies = flow.cond.map(|(when_true, when_false)| {
+                        (implied(&when_true, &state), implied(&when_false, &state))
+                    });
+                }
+                let num_locals = state.len();
+                state.push(new_local);
+                self.declared.push(*local);
+                let flow = self.walk(contained_expr, state);
+                self.declared.pop();
+                flow.map(|s| self.truncate(s, num_locals))
+            }
+            ExprKind::Assign {
+                target,
+                expr: inner_expr,
+            } => {
+                let mut state = match self.walk(inner_expr, state).out {
+                    Some(state) => state,
+                    None => return Flow::unreachable(),
+                };
+                if let TargetExpr::Local {
+                    localidx,
+                    next: None,
+                } = target
+                {
+                    self.assign(&mut state, *localidx, inner_expr.vartype.unwrap());
+                }
+                Flow::plain(Some(state))
+            }
+            ExprKind::Return { expr: inner_expr } => {
+                self.walk(inner_expr, state);
+                Flow::unreachable()
+            }
+            ExprKind::Break {
+                num_frames,
+                expr: inner_expr,
+            } => {
+                let flow = self.walk(inner_expr, state);
+                let idx = self.landings.len() - 1 - *num_frames;
+                let num_locals = self.landings[idx].0;
+                let flow = flow.map(|s| self.truncate(s, num_locals));
+                let landing = std::mem::replace(&mut self.landings[idx].1, Flow::unreachable());
+                self.landings[idx].1 = merge_flows(landing, flow);
+                Flow::unreachable()
+            }
+            ExprKind::Block { expr: inner_expr } => {
+                self.landings.push((state.len(), Flow::unreachable()));
+                let flow = self.walk(inner_expr, state);
+                let (_, landing) = self.landings.pop().unwrap();
+                merge_flows(flow, landing)
+            }
+            ExprKind::Loop { expr: inner_expr } => {
+                // forget about the locals that might be different in the next iteration
+                let num_locals = state.len();
+                let mut assigned: Vec<usize> = Vec::new();
+                walk_expr(inner_expr, false, num_locals, &mut |expr, _, _| {
+                    if let ExprKind::Assign {
+                        target:
+                            TargetExpr::Local {
+                                localidx,
+                                next: None,
+                            },
+                        expr: _,
+                    } = &expr.kind
+                    {
+                        if *localidx < num_locals {
+                            assigned.push(*localidx);
+                        }
+                    }
+                });
+                for localidx in assigned {
+                    self.assign(&mut state, localidx, self.declared[localidx]);
+                }
+                // Breaks to the loop start the next iteration, so we don't need their states
+                self.landings.push((num_locals, Flow::unreachable()));
+                let flow = self.walk(inner_expr, state);
+                self.landings.pop();
+                flow
+            }
+            ExprKind::Sequence { content } => self.walk_seq(content.iter_mut(), state),
+            ExprKind::Trap {
+                code: _,
+                location: _,
+            } => Flow::unreachable(),
+        }
+    }
+
+    // Walks the exprs one after another, returning the flow of the last one.
+    fn walk_seq<'a, I: Iterator<Item = &'a mut Expr>>(&mut self, exprs: I, state: State) -> Flow {
+        let mut flow = Flow::plain(Some(state));
+        for expr in exprs {
+            match flow.out {
+                Some(state) => flow = self.walk(expr, state),
+                None => return Flow::unreachable(),
+            }
+        }
+        flow
+    }
+
+    fn walk_opt(&mut self, expr: &mut Expr, state: Option<State>) -> Flow {
+        match state {
+            Some(state) => self.walk(expr, state),
+            None => Flow::unreachable(), // the expr is never executed
+        }
+    }
+
+    fn vartype_of(&self, state: &State, localidx: usize) -> VarType {
+        state[localidx].vartype.unwrap_or(self.declared[localidx])
+    }
+
+    // Narrows the local and all its copies to the given type.
+    fn refine(&self, state: &mut State, localidx: usize, vartype: VarType) {
+        let root = root_of(state, localidx);
+        for i in 0..state.len() {
+            if root_of(state, i) == root {
+                if let Some(narrower) = narrowed(self.vartype_of(state, i), vartype) {
+                    state[i].vartype = Some(narrower);
+                }
+            }
+        }
+    }
+
+    // Returns the state with the refinements applied, or None if the refinements can't happen.
+    fn refined(&self, state: &State, refinements: &Option<Refinements>) -> Option<State> {
+        refinements.as_ref().map(|refinements| {
+            let mut state = state.clone();
+            for (localidx, vartype) in refinements {
+                self.refine(&mut state, *localidx, *vartype);
+            }
+            state
+        })
+    }
+
+    // Records that the local got assigned a value of the given type, so everything else we knew about it is stale.
+    fn assign(&self, state: &mut State, localidx: usize, vartype: VarType) {
+        state[localidx] = Local {
+            vartype: narrowed(self.declared[localidx], vartype),
+            copy_of: None,
+            implies: None,
+        };
+        for local in state.iter_mut() {
+            if local.copy_of == Some(localidx) {
+                local.copy_of = None;
+            }
+            if let Some((when_true, when_false)) = &mut local.implies {
+                for refinements in [when_true, when_false].iter_mut().flat_map(|r| r.as_mut()) {
+                    refinements.retain(|(i, _)| *i != localidx);
+                }
+            }
+        }
+    }
+
+    // Removes the locals that go out of scope, moving what we know about them to the locals that they are copies of.
+    fn truncate(&self, mut state: State, num_locals: usize) -> State {
+        while state.len() > num_locals {
+            let local = state.pop().unwrap();
+            let localidx = state.len();
+            if let (Some(vartype), Some(copy_of)) = (local.vartype, local.copy_of) {
+                self.refine(&mut state, copy_of, vartype);
+            }
+            for other in state.iter_mut() {
+                if let Some((when_true, when_false)) = &mut other.implies {
+                    for refinements in [when_true, when_false].iter_mut().flat_map(|r| r.as_mut()) {
+                        refinements.retain_mut(|(i, _)| {
+                            if *i != localidx {
+                                return true;
+                            }
+                            match local.copy_of {
+                                Some(copy_of) => {
+                                    *i = copy_of;
+                                    true
+                                }
+                                None => false,
+                            }
+                        });
+                    }
+                }
+            }
+        }
+        state
+    }
+}
+
+fn root_of(state: &State, mut localidx: usize) -> usize {
+    while let Some(copy_of) = state[localidx].copy_of {
+        localidx = copy_of;
+    }
+    localidx
+}
+
+// Returns `to` if it is strictly narrower than `from`.
+fn narrowed(from: VarType, to: VarType) -> Option<VarType> {
+    if from != to && from.superset(&to) {
+        Some(to)
+    } else {
+        None
+    }
+}
+
+// Returns the local that the expr reads, if the expr is just a read of a local.
+fn local_read(expr: &Expr) -> Option<usize> {
+    match &expr.kind {
+        ExprKind::VarName {
+            source:
+                TargetExpr::Local {
+                    localidx,
+                    next: None,
+                },
+        } => Some(*localidx),
+        _ => None,
+    }
+}
+
+// Returns the refinements that `narrow` has over `base`, or None if `narrow` can't happen.
+fn implied(narrow: &Option<State>, base: &State) -> Option<Refinements> {
+    narrow.as_ref().map(|narrow| {
+        narrow
+            .iter()
+            .zip(base.iter())
+            .enumerate()
+            .filter_map(|(i, (n, b))| match n.vartype {
+                Some(vartype) if n.vartype != b.vartype => Some((i, vartype)),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+fn merge(first: Option<State>, second: Option<State>) -> Option<State> {
+    match (first, second) {
+        (None, state) | (state, None) => state,
+        (Some(mut first), Some(second)) => {
+            for (a, b) in first.iter_mut().zip(second) {
+                if a.vartype != b.vartype {
+                    a.vartype = None;
+                }
+                if a.copy_of != b.copy_of {
+                    a.copy_of = None;
+                }
+                if a.implies != b.implies {
+                    a.implies = None;
+                }
+            }
+            Some(first)
+        }
+    }
+}
+
+fn merge_flows(first: Flow, second: Flow) -> Flow {
+    if first.cond.is_none() && second.cond.is_none() {
+        return Flow::plain(merge(first.out, second.out));
+    }
+    let out = merge(first.out.clone(), second.out.clone());
+    let (first_true, first_false) = first.split();
+    let (second_true, second_false) = second.split();
+    Flow {
+        out,
+        cond: Some((
+            merge(first_true, second_true),
+            merge(first_false, second_false),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrows_after_noreturn_branch() {
+        // local#0 must be a number after the if, because the other branch returns
+        let text = r#"
+            func#0 (any) -> any {
+              (seq:any
+                (if:undefined
+                  (prim:boolean boolean_not
+                    (typecast:boolean number nonarrow (var:any local#0) (boolean:boolean true) (boolean:boolean false)))
+                  (return:! (number:number 0.0))
+                  (undefined:undefined))
+                (var:any local#0))
+            }
+            func#1 () -> any {
+              (call:any func#0 (number:number 1.0))
+            }
+            entry func#1;
+        "#;
+        let mut program = text::parse_program(text).unwrap();
+        assert!(refine_locals(&mut program.funcs[0]));
+        match &program.funcs[0].expr.kind {
+            ExprKind::Sequence { content } => {
+                assert_eq!(content[1].vartype, Some(VarType::Number))
+            }
+            _ => panic!(),
+        }
+
+        // running it again doesn't change anything
+        assert!(!refine_locals(&mut program.funcs[0]));
+    }
+}
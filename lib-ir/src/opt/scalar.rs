@@ -0,0 +1,281 @@
+use super::walk::walk_expr;
+use super::*;
+
+/**
+ * Discretionary optimisation to replace a struct in a local by a separate local for each of its fields (i.e. scalar replacement),
+ * so that the struct doesn't need to be allocated on the heap.
+ * This is done for each Declaration that is initialised by a PrimStructT of the declared type,
+ * if the local is only ever accessed through its fields (so the struct can't escape).
+ * This happens when a func declares variables in a struct for closures that got inlined, or that are never used.
+ * The new locals are not initialised, just like the fields of a newly allocated struct.
+ *
+ * To find more of these, we first remove the Declarations of closures that are never used, i.e. Declarations of a local of Func type
+ * that is never accessed, whose init just creates the closure (so it has no side effects other than allocating it).
+ * Otherwise the PrimFunc would keep the struct alive.
+ * The second return value is true if the program got changed, or false otherwise.
+ */
+pub fn optimize(mut program: Program) -> (Program, bool) {
+    let struct_types = &program.struct_types;
+    let mut changed = false;
+    for func in &mut program.funcs {
+        walk_expr(
+            &mut func.expr,
+            false,
+            func.params.len(),
+            &mut |expr, _, num_locals| {
+                changed |= try_remove_unused_closure(expr, num_locals);
+            },
+        );
+        walk_expr(
+            &mut func.expr,
+            false,
+            func.params.len(),
+            &mut |expr, _, num_locals| {
+                changed |= try_replace_struct(expr, num_locals, struct_types);
+            },
+        );
+    }
+    (program, changed)
+}
+
+// Replaces the struct declared by the expr with its fields, if possible.
+// `num_locals` is the number of locals in scope at the expr, so the struct would be local #num_locals.
+fn try_replace_struct(expr: &mut Expr, num_locals: usize, struct_types: &[Box<[VarType]>]) -> bool {
+    let (typeidx, contained_expr) = match &mut expr.kind {
+        ExprKind::Declaration {
+            local: VarType::StructT { typeidx },
+            init: Some(init_expr),
+            contained_expr,
+        } if matches!(init_expr.kind, ExprKind::PrimStructT { typeidx: init_typeidx } if init_typeidx == *typeidx) => {
+            (*typeidx, contained_expr)
+        }
+        _ => return false,
+    };
+    if is_accessed(contained_expr, num_locals, true) {
+        return false;
+    }
+
+    let fields = &struct_types[typeidx];
+    let mut new_expr = std::mem::replace(&mut **contained_expr, dummy_expr());
+    relabel_fields(&mut new_expr, num_locals, fields.len());
+    for field in fields.iter().rev() {
+        new_expr = Expr {
+            vartype: new_expr.vartype,
+            kind: ExprKind::Declaration {
+                local: *field,
+                init: None,
+                contained_expr: Box::new(new_expr),
+            },
+        };
+    }
+    *expr = new_expr;
+    true
+}
+
+// Removes the closure declared by the expr, if it is never used.
+// `num_locals` is the number of locals in scope at the expr, so the closure would be local #num_locals.
+fn try_remove_unused_closure(expr: &mut Expr, num_locals: usize) -> bool {
+    let contained_expr = match &mut expr.kind {
+        ExprKind::Declaration {
+            local: VarType::Func,
+            init: Some(init_expr),
+            contained_expr,
+        } if creates_closure(init_expr, num_locals) => contained_expr,
+        _ => return false,
+    };
+    if is_accessed(contained_expr, num_locals, false) {
+        return false;
+    }
+    let mut new_expr = std::mem::replace(&mut **contained_expr, dummy_expr());
+    relabel_fields(&mut new_expr, num_locals, 0);
+    *expr = new_expr;
+    true
+}
+
+// Returns true if the expr just creates a closure, i.e. it has no side effects other than allocating structs,
+// and it only assigns to the fields of structs that it declares itself.
+// `num_locals` is the number of locals in scope outside the expr, so the structs that it declares are local #num_locals onwards.
+fn creates_closure(expr: &Expr, num_locals: usize) -> bool {
+    match &expr.kind {
+        ExprKind::PrimUndefined
+        | ExprKind::PrimNull
+        | ExprKind::PrimNumber { val: _ }
+        | ExprKind::PrimBoolean { val: _ }
+        | ExprKind::PrimString { val: _ }
+        | ExprKind::PrimStructT { typeidx: _ }
+        | ExprKind::VarName { source: _ } => true,
+        ExprKind::PrimFunc {
+            funcidxs: _,
+            closure,
+        } => creates_closure(closure, num_locals),
+        ExprKind::Sequence { content } => content
+            .iter()
+            .all(|inner_expr| creates_closure(inner_expr, num_locals)),
+        ExprKind::Declaration {
+            local: _,
+            init,
+            contained_expr,
+        } => {
+            init.as_ref()
+                .is_none_or(|init_expr| creates_closure(init_expr, num_locals))
+                && creates_closure(contained_expr, num_locals)
+        }
+        ExprKind::Assign {
+            target:
+                TargetExpr::Local {
+                    localidx,
+                    next: Some(_),
+                },
+            expr: inner_expr,
+        } => *localidx >= num_locals && creates_closure(inner_expr, num_locals),
+        _ => false,
+    }
+}
+
+// Returns true if the local is accessed in the expr.
+// If `whole_only` is true, then accesses to the fields of the local are ignored.
+fn is_accessed(expr: &mut Expr, localidx: usize, whole_only: bool) -> bool {
+    let mut ret = false;
+    walk_expr(expr, false, 0, &mut |expr, _, _| match &expr.kind {
+        ExprKind::VarName {
+            source:
+                TargetExpr::Local {
+                    localidx: target_localidx,
+                    next,
+                },
+        }
+        | ExprKind::Assign {
+            target:
+                TargetExpr::Local {
+                    localidx: target_localidx,
+                    next,
+                },
+            expr: _,
+        } if *target_localidx == localidx && (next.is_none() || !whole_only) => ret = true,
+        _ => {}
+    });
+    ret
+}
+
+// Replaces the fields of the struct in local #localidx by the new locals for them,
+// and renumbers the locals declared after the struct to make room for the new locals.
+// (If there are no fields, this just removes local #localidx, which must not be accessed.)
+fn relabel_fields(expr: &mut Expr, localidx: usize, num_fields: usize) {
+    walk_expr(expr, false, 0, &mut |expr, _, _| match &mut expr.kind {
+        ExprKind::VarName { source: target } | ExprKind::Assign { target, expr: _ } => {
+            if let TargetExpr::Local {
+                localidx: target_localidx,
+                next,
+            } = target
+            {
+                if *target_localidx == localidx {
+                    let field = next.take().unwrap();
+                    *target_localidx = localidx + field.fieldidx;
+                    *next = field.next;
+                } else if *target_localidx > localidx {
+                    *target_localidx = *target_localidx + num_fields - 1;
+                }
+            }
+        }
+        _ => {}
+    });
+}
+
+fn dummy_expr() -> Expr {
+    Expr {
+        vartype: Some(VarType::Undefined),
+        kind: ExprKind::PrimUndefined,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_struct_with_locals() {
+        // local#0 is only accessed through its fields, so it becomes local#0 and local#1, and local#1 becomes local#2
+        let text = r#"
+            struct#0 = (any number);
+            func#0 () -> any {
+              (decl:any struct#0
+                (struct:struct#0 struct#0)
+                (seq:any
+                  (assign:undefined local#0.0:1 (number:number 1.0))
+                  (decl:any any
+                    (var:number local#0.0:1)
+                    (seq:any
+                      (assign:undefined local#0.0:0 (var:any local#1))
+                      (var:any local#0.0:0)))))
+            }
+            entry func#0;
+        "#;
+        let expected = r#"
+            struct#0 = (any number);
+            func#0 () -> any {
+              (decl:any any _
+                (decl:any number _
+                  (seq:any
+                    (assign:undefined local#1 (number:number 1.0))
+                    (decl:any any
+                      (var:number local#1)
+                      (seq:any
+                        (assign:undefined local#0 (var:any local#2))
+                        (var:any local#0))))))
+            }
+            entry func#0;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(
+            program.to_string(),
+            text::parse_program(expected).unwrap().to_string()
+        );
+
+        // running it again doesn't change anything
+        let (_, changed) = optimize(program);
+        assert!(!changed);
+    }
+
+    #[test]
+    fn removes_unused_closure() {
+        // the closure in local#1 is never used, so it no longer keeps the struct in local#0 alive
+        let text = r#"
+            struct#0 = (number);
+            func#0 (struct#0) -> any {
+              (var:number local#0.0:0)
+            }
+            func#1 () -> any {
+              (decl:any struct#0
+                (struct:struct#0 struct#0)
+                (decl:any func
+                  (func:func [func#0:closure] (var:struct#0 local#0))
+                  (seq:any
+                    (assign:undefined local#0.0:0 (number:number 1.0))
+                    (var:number local#0.0:0))))
+            }
+            entry func#1;
+        "#;
+        let expected = r#"
+            struct#0 = (number);
+            func#0 (struct#0) -> any {
+              (var:number local#0.0:0)
+            }
+            func#1 () -> any {
+              (decl:any number _
+                (seq:any
+                  (assign:undefined local#0 (number:number 1.0))
+                  (var:number local#0)))
+            }
+            entry func#1;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(
+            program.to_string(),
+            text::parse_program(expected).unwrap().to_string()
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use super::superset::Superset;
+use super::walk::{assigned_params, retype_param_reads, walk_expr};
+use super::*;
+
+const MAX_ROUNDS: usize = 20;
+
+/**
+ * Discretionary optimisation that infers the result types of all funcs, and the param types of funcs that never escape,
+ * over the whole call graph at once.
+ * Propagate only narrows the result of a func from its body, using the current results of its callees,
+ * so a recursive func like `f(n) = n === 0 ? 0 : 1 + f(n - 1)` keeps returning Any because it calls itself.
+ *
+ * Instead, we start by guessing that every func never returns (i.e. its result is None),
+ * and that each param of a non-escaping func has the union of the types of the args passed to it.
+ * We then optimise a copy of the program assuming that the guesses are right (see propagate::optimize_assuming_results).
+ * If the result of every func and the args at every call site fit in the guesses, the guesses are consistent and we keep the copy.
+ * Otherwise, we widen the guesses to include what we saw and try again, until they stop changing (or MAX_ROUNDS is reached).
+ *
+ * A func escapes if we might not see all its call sites, i.e. if it is the entry point, if it is in a PrimFunc,
+ * or if it is the constrained func in a signature_filter (since the filter entry records its params).
+ * Params that are assigned to are never narrowed.
+ * The result of the entry point is never narrowed either, because the embedder reads it from the Any result slot.
+ * Entries of the signature_filter that no longer fit the narrowed params are removed.
+ * The second return value is true if the program got changed, or false otherwise.
+ */
+pub fn optimize(mut program: Program) -> (Program, bool) {
+    let num_imports = program.imports.len();
+
+    // find the funcs that escape
+    let mut escaped: Vec<bool> = vec![false; program.funcs.len()];
+    escaped[program.entry_point - num_imports] = true;
+    for func in &mut program.funcs {
+        for (_, _, constrained_funcidx) in &func.signature_filter {
+            if *constrained_funcidx >= num_imports {
+                escaped[*constrained_funcidx - num_imports] = true;
+            }
+        }
+        walk_expr(&mut func.expr, false, 0, &mut |expr, _, _| {
+            if let ExprKind::PrimFunc {
+                funcidxs,
+                closure: _,
+            } = &expr.kind
+            {
+                for oe in funcidxs.iter() {
+                    if oe.funcidx >= num_imports {
+                        escaped[oe.funcidx - num_imports] = true;
+                    }
+                }
+            }
+        });
+    }
+    let is_assigned: Vec<Box<[bool]>> = program.funcs.iter_mut().map(assigned_params).collect();
+
+    // the initial guesses
+    let mut result_guesses: Vec<Option<VarType>> = vec![None; program.funcs.len()];
+    result_guesses[program.entry_point - num_imports] =
+        program.get_func(program.entry_point).result;
+    let mut param_guesses: Vec<Box<[VarType]>> = program
+        .funcs
+        .iter()
+        .map(|func| func.params.clone())
+        .collect();
+    let arg_types = call_site_arg_types(&mut program);
+    for i in 0..program.funcs.len() {
+        if !escaped[i] {
+            for (p, param) in param_guesses[i].iter_mut().enumerate() {
+                if let (false, Some(arg_type)) = (is_assigned[i][p], arg_types[i][p]) {
+                    *param = arg_type;
+                }
+            }
+        }
+    }
+
+    for _ in 0..MAX_ROUNDS {
+        // optimise a copy of the program using the guesses
+        let mut candidate = program.clone();
+        for (func, params) in candidate.funcs.iter_mut().zip(param_guesses.iter()) {
+            if func.params != *params {
+                func.params = params.clone();
+                retype_param_reads(&mut func.expr, params);
+                // entries of the signature_filter must be subtypes of the params
+                func.signature_filter
+                    .retain(|(filter_params, _, _)| params.superset(filter_params));
+            }
+        }
+        let result_types: Box<[Option<VarType>]> = program
+            .imports
+            .iter()
+            .map(|import| Some(import.result.into()))
+            .chain(result_guesses.iter().copied())
+            .collect();
+        propagate::optimize_assuming_results(&mut candidate, &result_types);
+
+        // widen the guesses to include what actually happened
+        let mut consistent = true;
+        let arg_types = call_site_arg_types(&mut candidate);
+        for (i, func) in candidate.funcs.iter().enumerate() {
+            let result_guess = union_type(result_guesses[i], func.result);
+            consistent &= !useful_update(&mut result_guesses[i], result_guess);
+            if !escaped[i] {
+                for (param, arg_type) in param_guesses[i].iter_mut().zip(arg_types[i].iter()) {
+                    consistent &=
+                        !useful_update(param, union_type(Some(*param), *arg_type).unwrap());
+                }
+            }
+        }
+
+        if consistent {
+            // fix up the vartypes of the DirectAppls, which have the guessed result types
+            let (candidate, _) = propagate::optimize(candidate);
+            // only keep the copy if it is actually better
+            let fits = program
+                .funcs
+                .iter()
+                .zip(candidate.funcs.iter())
+                .all(|(old, new)| {
+                    union_type(old.result, new.result) == old.result
+                        && old.params.superset(&new.params)
+                });
+            let narrower = program
+                .funcs
+                .iter()
+                .zip(candidate.funcs.iter())
+                .any(|(old, new)| old.result != new.result || old.params != new.params);
+            if fits && narrower {
+                return (candidate, true);
+            }
+            return (program, false);
+        }
+    }
+
+    (program, false)
+}
+
+// Returns, for each param of each func (excluding imports), the union of the types of the args passed to it by DirectAppls.
+// The type is None if there are no such DirectAppls.
+fn call_site_arg_types(program: &mut Program) -> Vec<Box<[Option<VarType>]>> {
+    let num_imports = program.imports.len();
+    let mut ret: Vec<Box<[Option<VarType>]>> = program
+        .funcs
+        .iter()
+        .map(|func| vec![None; func.params.len()].into_boxed_slice())
+        .collect();
+    for func in &mut program.funcs {
+        walk_expr(&mut func.expr, false, 0, &mut |expr, _, _| {
+            if let ExprKind::DirectAppl { funcidx, args } = &expr.kind {
+                if *funcidx >= num_imports {
+                    for (arg_type, arg) in ret[*funcidx - num_imports].iter_mut().zip(args.iter()) {
+                        *arg_type = union_type(*arg_type, arg.vartype);
+                    }
+                }
+            }
+        });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_recursive_result() {
+        // func#0 returns either 0 or the result of calling itself, so it always returns a number
+        // (but func#1 is the entry point, so it keeps returning Any)
+        let text = r#"
+            func#0 (number) -> any {
+              (if:any (prim:boolean number_eq (var:number local#0) (number:number 0.0))
+                (number:number 0.0)
+                (call:any func#0 (prim:number number_sub (var:number local#0) (number:number 1.0))))
+            }
+            func#1 () -> any {
+              (call:any func#0 (number:number 3.0))
+            }
+            entry func#1;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(program.funcs[0].result, Some(VarType::Number));
+        assert_eq!(program.funcs[1].result, Some(VarType::Any));
+        assert_eq!(program.funcs[1].expr.vartype, Some(VarType::Number));
+
+        // running it again doesn't change anything
+        let (_, changed) = optimize(program);
+        assert!(!changed);
+    }
+}
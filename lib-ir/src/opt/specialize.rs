@@ -0,0 +1,249 @@
+use super::superset::Superset;
+use super::walk::{assigned_params, retype_param_reads, walk_expr};
+use super::*;
+
+const SPECIALIZATION_MAX_COST: usize = 500;
+const MAX_SPECIALIZATIONS_PER_FUNC: usize = 4;
+
+/**
+ * Discretionary optimisation to clone functions for the concrete argument types seen at their direct call sites.
+ * Each clone has the narrower param types (e.g. `fib(Number)`), and is registered in the signature_filter of the original func.
+ * Propagate then redirects matching direct calls (including the recursive calls inside the clone itself) to the clone,
+ * and removes the TypeCasts and boxing in the clone that are no longer necessary.
+ * (We run propagate at the end of this pass, so that the types in the clones are consistent.)
+ * The second return value is true if the program got changed, or false otherwise.
+ *
+ * Only params that are never assigned to in the func are narrowed.
+ *
+ * To limit code size, a func is only specialised for a call site if:
+ * 1. The call site is hot, i.e. it is in a loop, or the func calls itself.
+ * 2. The func has at most SPECIALIZATION_MAX_COST Expr nodes, and fewer than MAX_SPECIALIZATIONS_PER_FUNC clones.
+ * 3. The func is not itself a clone (since its params are already as narrow as the call sites that it was made for).
+ */
+pub fn optimize(mut program: Program) -> (Program, bool) {
+    let num_imports = program.imports.len();
+
+    // properties of each func
+    let mut is_clone: Vec<bool> = vec![false; program.funcs.len()];
+    for func in &program.funcs {
+        for (_, _, constrained_funcidx) in &func.signature_filter {
+            if *constrained_funcidx >= num_imports {
+                is_clone[*constrained_funcidx - num_imports] = true;
+            }
+        }
+    }
+    let mut costs: Vec<usize> = Vec::with_capacity(program.funcs.len());
+    let mut is_recursive: Vec<bool> = Vec::with_capacity(program.funcs.len());
+    let mut is_assigned: Vec<Box<[bool]>> = Vec::with_capacity(program.funcs.len());
+    for (i, func) in program.funcs.iter_mut().enumerate() {
+        let mut cost: usize = 0;
+        let mut recursive = false;
+        walk_expr(&mut func.expr, false, 0, &mut |expr, _, _| {
+            cost += 1;
+            if let ExprKind::DirectAppl { funcidx, args: _ } = &expr.kind {
+                recursive |= *funcidx == num_imports + i;
+            }
+        });
+        costs.push(cost);
+        is_recursive.push(recursive);
+        is_assigned.push(assigned_params(func));
+    }
+
+    // find the signatures that we want to specialise each func for
+    let mut new_signatures: Vec<(FuncIdx, Box<[VarType]>)> = Vec::new();
+    for func in &program.funcs {
+        find_signatures(&func.expr, false, &mut |funcidx, args, in_loop| {
+            if funcidx < num_imports || funcidx == program.entry_point {
+                return;
+            }
+            let i = funcidx - num_imports;
+            let callee = &program.funcs[i];
+            if is_clone[i]
+                || callee.result.is_none()
+                || costs[i] > SPECIALIZATION_MAX_COST
+                || !(in_loop || is_recursive[i])
+            {
+                return;
+            }
+            let signature: Box<[VarType]> = args
+                .iter()
+                .zip(callee.params.iter())
+                .zip(is_assigned[i].iter())
+                .map(|((arg, param), assigned)| {
+                    if *assigned {
+                        *param
+                    } else {
+                        arg.vartype.unwrap()
+                    }
+                })
+                .collect();
+            if signature == callee.params
+                || callee
+                    .signature_filter
+                    .iter()
+                    .any(|(params, _, _)| params.superset(&signature))
+                || new_signatures
+                    .iter()
+                    .any(|(f, params)| *f == funcidx && params.superset(&signature))
+            {
+                // nothing to specialise, or propagate will redirect this call to an existing clone
+                return;
+            }
+            let num_specializations = callee.signature_filter.len()
+                + new_signatures.iter().filter(|(f, _)| *f == funcidx).count();
+            if num_specializations < MAX_SPECIALIZATIONS_PER_FUNC {
+                new_signatures.push((funcidx, signature));
+            }
+        });
+    }
+
+    if new_signatures.is_empty() {
+        return (program, false);
+    }
+
+    // make the clones
+    for (funcidx, signature) in new_signatures {
+        let original = program.get_func(funcidx);
+        let result = original.result.unwrap();
+        let mut clone = Func {
+            params: signature.clone(),
+            result: original.result,
+            expr: original.expr.clone(),
+            signature_filter: Vec::new(),
+        };
+        retype_param_reads(&mut clone.expr, &signature);
+        let clone_funcidx = num_imports + program.funcs.len();
+        program.funcs.push(clone);
+        program
+            .get_func_mut(funcidx)
+            .signature_filter
+            .push((signature, result, clone_funcidx));
+    }
+
+    // propagate the narrower types through the clones
+    let (program, _) = propagate::optimize(program);
+    (program, true)
+}
+
+// Calls `f` with the funcidx and args of every DirectAppl in the given expr, and whether it is inside a loop.
+fn find_signatures(expr: &Expr, in_loop: bool, f: &mut dyn FnMut(FuncIdx, &[Expr], bool)) {
+    // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+    match &expr.kind {
+        ExprKind::PrimUndefined
+        | ExprKind::PrimNull
+        | ExprKind::PrimNumber { val: _ }
+        | ExprKind::PrimBoolean { val: _ }
+        | ExprKind::PrimString { val: _ }
+        | ExprKind::PrimStructT { typeidx: _ }
+        | ExprKind::VarName { source: _ }
+        | ExprKind::Trap {
+            code: _,
+            location: _,
+        } => {}
+        ExprKind::PrimFunc {
+            funcidxs: _,
+            closure: inner_expr,
+        }
+        | ExprKind::Return { expr: inner_expr }
+        | ExprKind::Break {
+            num_frames: _,
+            expr: inner_expr,
+        }
+        | ExprKind::Block { expr: inner_expr }
+        | ExprKind::Assign {
+            target: _,
+            expr: inner_expr,
+        } => find_signatures(inner_expr, in_loop, f),
+        ExprKind::Loop { expr: inner_expr } => find_signatures(inner_expr, true, f),
+        ExprKind::TypeCast {
+            test: first,
+            expected: _,
+            create_narrow_local: _,
+            true_expr: second,
+            false_expr: third,
+        }
+        | ExprKind::Conditional {
+            cond: first,
+            true_expr: second,
+            false_expr: third,
+        } => {
+            find_signatures(first, in_loop, f);
+            find_signatures(second, in_loop, f);
+            find_signatures(third, in_loop, f);
+        }
+        ExprKind::PrimAppl {
+            prim_inst: _,
+            args: exprs,
+        } => {
+            for inner_expr in exprs.iter() {
+                find_signatures(inner_expr, in_loop, f);
+            }
+        }
+        ExprKind::DirectAppl { funcidx, args } => {
+            for inner_expr in args.iter() {
+                find_signatures(inner_expr, in_loop, f);
+            }
+            f(*funcidx, args, in_loop);
+        }
+        ExprKind::Sequence { content } => {
+            for inner_expr in content.iter() {
+                find_signatures(inner_expr, in_loop, f);
+            }
+        }
+        ExprKind::Appl {
+            func,
+            args,
+            location: _,
+        } => {
+            find_signatures(func, in_loop, f);
+            for inner_expr in args.iter() {
+                find_signatures(inner_expr, in_loop, f);
+            }
+        }
+        ExprKind::Declaration {
+            local: _,
+            init,
+            contained_expr,
+        } => {
+            if let Some(init_expr) = init {
+                find_signatures(init_expr, in_loop, f);
+            }
+            find_signatures(contained_expr, in_loop, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specializes_recursive_func() {
+        // func#0 calls itself with a number, and is also called with a number from func#1
+        let text = r#"
+            func#0 (any) -> any {
+              (typecast:any number narrow (var:any local#0)
+                (call:any func#0 (var:number local#1))
+                (var:any local#0))
+            }
+            func#1 () -> any {
+              (call:any func#0 (number:number 3.0))
+            }
+            entry func#1;
+        "#;
+        let program = text::parse_program(text).unwrap();
+        let (program, changed) = optimize(program);
+        assert!(changed);
+        assert_eq!(program.funcs.len(), 3);
+        assert_eq!(&*program.funcs[2].params, &[VarType::Number]);
+        assert_eq!(
+            program.funcs[0].signature_filter,
+            vec![(vec![VarType::Number].into_boxed_slice(), VarType::Any, 2)]
+        );
+
+        // running it again doesn't make another clone
+        let (program, changed) = optimize(program);
+        assert!(!changed);
+        assert_eq!(program.funcs.len(), 3);
+    }
+}
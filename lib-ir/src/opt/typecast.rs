@@ -59,6 +59,7 @@ fn optimize_expr(expr: &mut Expr, local_map: &mut Relabeller) -> bool {
     // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
     match &mut expr.kind {
         ExprKind::PrimUndefined
+        | ExprKind::PrimNull
         | ExprKind::PrimNumber { val: _ }
         | ExprKind::PrimBoolean { val: _ }
         | ExprKind::PrimStructT { typeidx: _ }
@@ -169,6 +170,7 @@ fn optimize_expr(expr: &mut Expr, local_map: &mut Relabeller) -> bool {
             expr,
         } => optimize_expr(&mut **expr, local_map),
         ExprKind::Block { expr } => optimize_expr(&mut **expr, local_map),
+        ExprKind::Loop { expr } => optimize_expr(expr, local_map),
         ExprKind::Sequence { content } => content
             .iter_mut()
             .fold(false, |prev, expr| prev | optimize_expr(expr, local_map)),
@@ -27,6 +27,7 @@ fn optimize_expr(expr: &mut Expr) -> bool {
     // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
     match &mut expr.kind {
         ExprKind::PrimUndefined
+        | ExprKind::PrimNull
         | ExprKind::PrimNumber { val: _ }
         | ExprKind::PrimBoolean { val: _ }
         | ExprKind::PrimStructT { typeidx: _ }
@@ -134,6 +135,7 @@ fn optimize_expr(expr: &mut Expr) -> bool {
             }
         }
         ExprKind::Block { expr } => optimize_expr(&mut **expr),
+        ExprKind::Loop { expr } => optimize_expr(expr),
         ExprKind::Sequence { content } => {
             let tmp_content = std::mem::take(content);
             let mut changed = false;
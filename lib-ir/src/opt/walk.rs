@@ -0,0 +1,152 @@
+use super::*;
+
+/**
+ * Calls `f` on the given expr and then on all its subexprs, with whether the expr is in tail position and the number of locals in scope.
+ * (The expr itself is in tail position iff `tail` is true, and `num_locals` locals are in scope at the expr.)
+ * `f` may modify the expr before its subexprs are visited.
+ */
+pub(super) fn walk_expr(
+    expr: &mut Expr,
+    tail: bool,
+    num_locals: usize,
+    f: &mut dyn FnMut(&mut Expr, bool, usize),
+) {
+    f(expr, tail, num_locals);
+    // Note: we explicitly list out all possibilities so we will get a compile error if a new exprkind is added.
+    match &mut expr.kind {
+        ExprKind::PrimUndefined
+        | ExprKind::PrimNull
+        | ExprKind::PrimNumber { val: _ }
+        | ExprKind::PrimBoolean { val: _ }
+        | ExprKind::PrimString { val: _ }
+        | ExprKind::PrimStructT { typeidx: _ }
+        | ExprKind::VarName { source: _ }
+        | ExprKind::Trap {
+            code: _,
+            location: _,
+        } => {}
+        ExprKind::PrimFunc {
+            funcidxs: _,
+            closure: inner_expr,
+        }
+        | ExprKind::Break {
+            num_frames: _,
+            expr: inner_expr,
+        }
+        | ExprKind::Loop { expr: inner_expr }
+        | ExprKind::Assign {
+            target: _,
+            expr: inner_expr,
+        } => walk_expr(inner_expr, false, num_locals, f),
+        ExprKind::Return { expr: inner_expr } => walk_expr(inner_expr, true, num_locals, f),
+        ExprKind::Block { expr: inner_expr } => walk_expr(inner_expr, tail, num_locals, f),
+        ExprKind::TypeCast {
+            test,
+            expected: _,
+            create_narrow_local,
+            true_expr,
+            false_expr,
+        } => {
+            walk_expr(test, false, num_locals, f);
+            walk_expr(
+                true_expr,
+                tail,
+                num_locals + (*create_narrow_local as usize),
+                f,
+            );
+            walk_expr(false_expr, tail, num_locals, f);
+        }
+        ExprKind::Conditional {
+            cond,
+            true_expr,
+            false_expr,
+        } => {
+            walk_expr(cond, false, num_locals, f);
+            walk_expr(true_expr, tail, num_locals, f);
+            walk_expr(false_expr, tail, num_locals, f);
+        }
+        ExprKind::PrimAppl {
+            prim_inst: _,
+            args: exprs,
+        }
+        | ExprKind::DirectAppl {
+            funcidx: _,
+            args: exprs,
+        } => {
+            for inner_expr in exprs.iter_mut() {
+                walk_expr(inner_expr, false, num_locals, f);
+            }
+        }
+        ExprKind::Sequence { content } => {
+            let len = content.len();
+            for (i, inner_expr) in content.iter_mut().enumerate() {
+                walk_expr(inner_expr, tail && i + 1 == len, num_locals, f);
+            }
+        }
+        ExprKind::Appl {
+            func,
+            args,
+            location: _,
+        } => {
+            walk_expr(func, false, num_locals, f);
+            for inner_expr in args.iter_mut() {
+                walk_expr(inner_expr, false, num_locals, f);
+            }
+        }
+        ExprKind::Declaration {
+            local: _,
+            init,
+            contained_expr,
+        } => {
+            if let Some(init_expr) = init {
+                walk_expr(init_expr, false, num_locals, f);
+            }
+            walk_expr(contained_expr, tail, num_locals + 1, f);
+        }
+    }
+}
+
+/**
+ * Returns, for each param of the func, whether it is assigned to anywhere in the func.
+ */
+pub(super) fn assigned_params(func: &mut Func) -> Box<[bool]> {
+    let mut assigned: Box<[bool]> = vec![false; func.params.len()].into_boxed_slice();
+    walk_expr(&mut func.expr, false, 0, &mut |expr, _, _| {
+        if let ExprKind::Assign {
+            target:
+                TargetExpr::Local {
+                    localidx,
+                    next: None,
+                },
+            expr: _,
+        } = &expr.kind
+        {
+            if let Some(x) = assigned.get_mut(*localidx) {
+                *x = true;
+            }
+        }
+    });
+    assigned
+}
+
+/**
+ * Sets the vartype of every read of a param to the type of that param.
+ * This is used after narrowing the params of a func, so that the reads are not wider than the params.
+ * The narrowed params must not be assigned to anywhere in the expr.
+ */
+pub(super) fn retype_param_reads(expr: &mut Expr, params: &[VarType]) {
+    walk_expr(expr, false, 0, &mut |expr, _, _| {
+        if let ExprKind::VarName {
+            source:
+                TargetExpr::Local {
+                    localidx,
+                    next: None,
+                },
+        } = &expr.kind
+        {
+            if let Some(param) = params.get(*localidx) {
+                expr.vartype = Some(*param);
+            }
+        }
+    });
+}
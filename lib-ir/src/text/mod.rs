@@ -0,0 +1,364 @@
+/**
+ * Textual format of the IR, with a printer and a parser that round-trip.
+ * It is meant for inspecting the IR and for writing optimisation tests as text.
+ *
+ * Grammar (whitespace separates atoms, and `//` starts a comment until the end of the line):
+ *
+ * program := item*
+ * item := "struct#" N "=" "(" type* ")" ";"
+ *       | "import#" N "=" string string "(" importtype* ")" "->" importtype ";"
+ *       | "global#" N "=" type ";"
+ *       | "entry" funcref ";"
+ *       | "func#" N "(" type* ")" "->" opttype filter* "{" expr "}"
+ * filter := "filter" "(" type* ")" "->" type "=" funcref
+ *
+ * Structs, imports, globals and funcs must be declared in order of their indices, and all imports must be declared before any func
+ * (since funcs share the index space with imports).
+ *
+ * type := "any" | "unassigned" | "undefined" | "number" | "boolean" | "string" | "func" | "null" | "array" | "struct#" N
+ * opttype := type | "!" (a Void type, i.e. the expression never returns)
+ * importtype := "undefined" | "number" | "string"
+ * funcref := "func#" N (which is a ir::FuncIdx, so it may refer to an import)
+ *
+ * expr := "(" kind ":" opttype operands ")", where the operands depend on the kind:
+ * (undefined:T)
+ * (null:T)
+ * (number:T <f64>)
+ * (boolean:T true|false)
+ * (string:T <string>)
+ * (struct:T struct#N)
+ * (func:T "[" overload* "]" <closure>)  where overload := funcref | funcref ":closure"
+ * (typecast:T <expected type> narrow|nonarrow <test> <true_expr> <false_expr>)
+ * (var:T <target>)
+ * (prim:T <prim_inst> <args>*)
+ * (appl:T <location> <func> <args>*)
+ * (call:T funcref <args>*)
+ * (if:T <cond> <true_expr> <false_expr>)
+ * (decl:T <local type> <init>|_ <contained_expr>)
+ * (assign:T <target> <expr>)
+ * (return:T <expr>)
+ * (break:T <num_frames> <expr>)
+ * (block:T <expr>)
+ * (loop:T <expr>)
+ * (seq:T <content>*)
+ * (trap:T <code> <location>)
+ *
+ * target := ("local#" N | "global#" N) ("." typeidx ":" fieldidx)*
+ * location := file ":" line ":" column "-" line ":" column
+ * string := a double-quoted string, with the same escapes as Rust string literals
+ */
+mod parse;
+mod print;
+
+pub use parse::parse_expr;
+pub use parse::parse_program;
+pub use parse::ParseError;
+pub use print::print_expr;
+pub use print::print_program;
+
+use super::*;
+
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&print_program(self))
+    }
+}
+
+// Names of the PrimInsts in the textual format.
+const PRIM_INST_NAMES: [(PrimInst, &str); NUM_PRIM_INST as usize] = [
+    (PrimInst::NumberAdd, "number_add"),
+    (PrimInst::NumberSub, "number_sub"),
+    (PrimInst::NumberMul, "number_mul"),
+    (PrimInst::NumberDiv, "number_div"),
+    (PrimInst::NumberRem, "number_rem"),
+    (PrimInst::NumberEq, "number_eq"),
+    (PrimInst::NumberNeq, "number_neq"),
+    (PrimInst::NumberGt, "number_gt"),
+    (PrimInst::NumberLt, "number_lt"),
+    (PrimInst::NumberGe, "number_ge"),
+    (PrimInst::NumberLe, "number_le"),
+    (PrimInst::BooleanEq, "boolean_eq"),
+    (PrimInst::BooleanNeq, "boolean_neq"),
+    (PrimInst::BooleanAnd, "boolean_and"),
+    (PrimInst::BooleanOr, "boolean_or"),
+    (PrimInst::BooleanNot, "boolean_not"),
+    (PrimInst::NumberNegate, "number_negate"),
+    (PrimInst::StringAdd, "string_add"),
+    (PrimInst::StringEq, "string_eq"),
+    (PrimInst::StringNeq, "string_neq"),
+    (PrimInst::StringGt, "string_gt"),
+    (PrimInst::StringLt, "string_lt"),
+    (PrimInst::StringGe, "string_ge"),
+    (PrimInst::StringLe, "string_le"),
+    (PrimInst::ArrayNew, "array_new"),
+    (PrimInst::ArrayLength, "array_length"),
+    (PrimInst::ArrayGet, "array_get"),
+    (PrimInst::ArraySet, "array_set"),
+    (PrimInst::AnyEq, "any_eq"),
+    (PrimInst::AnyNeq, "any_neq"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(vartype: Option<VarType>, kind: ExprKind) -> Expr {
+        Expr { vartype, kind }
+    }
+
+    fn location() -> SourceLocation {
+        SourceLocation {
+            file: 1,
+            start: Position { line: 2, column: 3 },
+            end: Position { line: 4, column: 5 },
+        }
+    }
+
+    // A program that uses every ExprKind.
+    fn make_program() -> Program {
+        let local0 = TargetExpr::Local {
+            localidx: 0,
+            next: None,
+        };
+        let global0_field = TargetExpr::Global {
+            globalidx: 0,
+            next: Some(Box::new(StructField {
+                typeidx: 0,
+                fieldidx: 1,
+                next: None,
+            })),
+        };
+        let body = expr(
+            Some(VarType::Any),
+            ExprKind::Sequence {
+                content: vec![
+                    expr(
+                        Some(VarType::Undefined),
+                        ExprKind::Assign {
+                            target: global0_field.clone(),
+                            expr: Box::new(expr(
+                                Some(VarType::String),
+                                ExprKind::PrimString {
+                                    val: "a \"quoted\"\n\\ string \u{7f}".to_owned(),
+                                },
+                            )),
+                        },
+                    ),
+                    expr(
+                        Some(VarType::Number),
+                        ExprKind::Declaration {
+                            local: VarType::Number,
+                            init: Some(Box::new(expr(
+                                Some(VarType::Number),
+                                ExprKind::PrimNumber { val: -0.5e-300 },
+                            ))),
+                            contained_expr: Box::new(expr(
+                                Some(VarType::Number),
+                                ExprKind::PrimAppl {
+                                    prim_inst: PrimInst::NumberAdd,
+                                    args: Box::new([
+                                        expr(
+                                            Some(VarType::Number),
+                                            ExprKind::VarName {
+                                                source: TargetExpr::Local {
+                                                    localidx: 1,
+                                                    next: None,
+                                                },
+                                            },
+                                        ),
+                                        expr(
+                                            Some(VarType::Number),
+                                            ExprKind::PrimNumber { val: f64::INFINITY },
+                                        ),
+                                    ]),
+                                },
+                            )),
+                        },
+                    ),
+                    expr(
+                        Some(VarType::Undefined),
+                        ExprKind::Declaration {
+                            local: VarType::StructT { typeidx: 0 },
+                            init: None,
+                            contained_expr: Box::new(expr(
+                                Some(VarType::StructT { typeidx: 0 }),
+                                ExprKind::PrimStructT { typeidx: 0 },
+                            )),
+                        },
+                    ),
+                    expr(
+                        Some(VarType::Any),
+                        ExprKind::Block {
+                            expr: Box::new(expr(
+                                None,
+                                ExprKind::Loop {
+                                    expr: Box::new(expr(
+                                        None,
+                                        ExprKind::Break {
+                                            num_frames: 1,
+                                            expr: Box::new(expr(
+                                                Some(VarType::Null),
+                                                ExprKind::PrimNull,
+                                            )),
+                                        },
+                                    )),
+                                },
+                            )),
+                        },
+                    ),
+                    expr(
+                        Some(VarType::Any),
+                        ExprKind::TypeCast {
+                            test: Box::new(expr(
+                                Some(VarType::Any),
+                                ExprKind::VarName {
+                                    source: local0.clone(),
+                                },
+                            )),
+                            expected: VarType::Boolean,
+                            create_narrow_local: true,
+                            true_expr: Box::new(expr(
+                                Some(VarType::Boolean),
+                                ExprKind::Conditional {
+                                    cond: Box::new(expr(
+                                        Some(VarType::Boolean),
+                                        ExprKind::VarName {
+                                            source: TargetExpr::Local {
+                                                localidx: 1,
+                                                next: None,
+                                            },
+                                        },
+                                    )),
+                                    true_expr: Box::new(expr(
+                                        Some(VarType::Boolean),
+                                        ExprKind::PrimBoolean { val: false },
+                                    )),
+                                    false_expr: Box::new(expr(
+                                        Some(VarType::Boolean),
+                                        ExprKind::PrimBoolean { val: true },
+                                    )),
+                                },
+                            )),
+                            false_expr: Box::new(expr(
+                                None,
+                                ExprKind::Trap {
+                                    code: error::ERROR_CODE_IF_STATEMENT_CONDITION_TYPE,
+                                    location: location(),
+                                },
+                            )),
+                        },
+                    ),
+                    expr(
+                        None,
+                        ExprKind::Return {
+                            expr: Box::new(expr(
+                                Some(VarType::Any),
+                                ExprKind::Appl {
+                                    func: Box::new(expr(
+                                        Some(VarType::Func),
+                                        ExprKind::PrimFunc {
+                                            funcidxs: Box::new([
+                                                OverloadEntry {
+                                                    funcidx: 1,
+                                                    has_closure_param: false,
+                                                },
+                                                OverloadEntry {
+                                                    funcidx: 2,
+                                                    has_closure_param: true,
+                                                },
+                                            ]),
+                                            closure: Box::new(expr(
+                                                Some(VarType::Undefined),
+                                                ExprKind::PrimUndefined,
+                                            )),
+                                        },
+                                    )),
+                                    args: Box::new([expr(
+                                        Some(VarType::Number),
+                                        ExprKind::DirectAppl {
+                                            funcidx: 0,
+                                            args: Box::new([expr(
+                                                Some(VarType::String),
+                                                ExprKind::PrimString {
+                                                    val: "1".to_owned(),
+                                                },
+                                            )]),
+                                        },
+                                    )]),
+                                    location: location(),
+                                },
+                            )),
+                        },
+                    ),
+                ],
+            },
+        );
+        Program {
+            struct_types: vec![Box::new([VarType::Any, VarType::String])],
+            imports: Box::new([Import {
+                module_name: "misc".to_owned(),
+                entity_name: "parse_float".to_owned(),
+                params: Box::new([ImportValType::String]),
+                result: ImportValType::Number,
+            }]),
+            funcs: vec![
+                Func {
+                    params: Box::new([VarType::Any]),
+                    result: Some(VarType::Any),
+                    expr: body,
+                    signature_filter: vec![(Box::new([VarType::Number]), VarType::Number, 2)],
+                },
+                Func {
+                    params: Box::new([VarType::Undefined, VarType::Number]),
+                    result: None,
+                    expr: expr(
+                        None,
+                        ExprKind::Trap {
+                            code: error::ERROR_CODE_OUT_OF_MEMORY,
+                            location: Default::default(),
+                        },
+                    ),
+                    signature_filter: Vec::new(),
+                },
+            ],
+            globals: vec![VarType::StructT { typeidx: 0 }],
+            entry_point: 1,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = make_program();
+        let text = print_program(&program);
+        let parsed = parse_program(&text).unwrap_or_else(|e| panic!("{}\n{}", e, text));
+        assert_eq!(format!("{:?}", program), format!("{:?}", parsed));
+        assert_eq!(text, print_program(&parsed));
+    }
+
+    #[test]
+    fn parse_handwritten() {
+        let text = r#"
+            // increments the param
+            func#0 (number) -> number {
+              (return:! (prim:number number_add (var:number local#0) (number:number 1)))
+            }
+            entry func#0;
+        "#;
+        let program = parse_program(text).unwrap();
+        assert_eq!(program.funcs.len(), 1);
+        assert_eq!(program.entry_point, 0);
+        assert_eq!(
+            print_expr(&program.funcs[0].expr),
+            "(return:!\n  (prim:number number_add (var:number local#0) (number:number 1.0)))"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = parse_program("func#0 () -> any {\n  (seq:any (foo:any))\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 13));
+        assert!(parse_program("func#1 () -> any { (undefined:undefined) }").is_err());
+        assert!(parse_expr("(break:! x (null:null))").is_err());
+        assert!(parse_expr("(seq:any").is_err());
+    }
+}
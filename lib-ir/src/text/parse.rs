@@ -0,0 +1,655 @@
+use super::*;
+
+/**
+ * Error returned when the text is not a valid IR program (or expression).
+ * Lines and columns start from 1.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/**
+ * Parses a program in the textual format.
+ */
+pub fn parse_program(text: &str) -> Result<Program, ParseError> {
+    let mut parser = Parser::new(text)?;
+    let mut program = Program {
+        struct_types: Vec::new(),
+        imports: Box::new([]),
+        funcs: Vec::new(),
+        globals: Vec::new(),
+        entry_point: Default::default(),
+    };
+    let mut imports: Vec<Import> = Vec::new();
+    let mut has_entry_point = false;
+    while !parser.at_end() {
+        let pos = parser.pos();
+        let atom = parser.atom()?;
+        if atom == "entry" {
+            program.entry_point = parser.funcref()?;
+            parser.expect(&Token::Semicolon)?;
+            has_entry_point = true;
+        } else if let Some(idx) = atom.strip_prefix("struct#") {
+            parser.check_index(idx, program.struct_types.len(), pos)?;
+            parser.expect(&Token::Equals)?;
+            program.struct_types.push(parser.type_list()?);
+            parser.expect(&Token::Semicolon)?;
+        } else if let Some(idx) = atom.strip_prefix("import#") {
+            if !program.funcs.is_empty() {
+                return Err(pos.error("imports must be declared before funcs"));
+            }
+            parser.check_index(idx, imports.len(), pos)?;
+            parser.expect(&Token::Equals)?;
+            let module_name = parser.string()?;
+            let entity_name = parser.string()?;
+            parser.expect(&Token::LParen)?;
+            let mut params = Vec::new();
+            while !parser.eat(&Token::RParen) {
+                params.push(parser.import_type()?);
+            }
+            parser.expect_atom("->")?;
+            let result = parser.import_type()?;
+            parser.expect(&Token::Semicolon)?;
+            imports.push(Import {
+                module_name,
+                entity_name,
+                params: params.into_boxed_slice(),
+                result,
+            });
+        } else if let Some(idx) = atom.strip_prefix("global#") {
+            parser.check_index(idx, program.globals.len(), pos)?;
+            parser.expect(&Token::Equals)?;
+            program.globals.push(parser.vartype()?);
+            parser.expect(&Token::Semicolon)?;
+        } else if let Some(idx) = atom.strip_prefix("func#") {
+            parser.check_index(idx, imports.len() + program.funcs.len(), pos)?;
+            let params = parser.type_list()?;
+            parser.expect_atom("->")?;
+            let result = parser.opt_vartype()?;
+            let mut signature_filter = Vec::new();
+            while !parser.eat(&Token::LBrace) {
+                parser.expect_atom("filter")?;
+                let filter_params = parser.type_list()?;
+                parser.expect_atom("->")?;
+                let filter_result = parser.vartype()?;
+                parser.expect(&Token::Equals)?;
+                signature_filter.push((filter_params, filter_result, parser.funcref()?));
+            }
+            let expr = parser.expr()?;
+            parser.expect(&Token::RBrace)?;
+            program.funcs.push(Func {
+                params,
+                result,
+                expr,
+                signature_filter,
+            });
+        } else {
+            return Err(pos.error(&format!("unexpected `{}`", atom)));
+        }
+    }
+    if !has_entry_point {
+        return Err(parser.pos().error("missing entry point"));
+    }
+    program.imports = imports.into_boxed_slice();
+    Ok(program)
+}
+
+/**
+ * Parses a single expression in the textual format.
+ */
+pub fn parse_expr(text: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser::new(text)?;
+    let expr = parser.expr()?;
+    if !parser.at_end() {
+        return Err(parser.pos().error("expected end of input"));
+    }
+    Ok(expr)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Semicolon,
+    Equals,
+    Str(String),
+    Atom(String),
+}
+
+#[derive(Copy, Clone)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+impl Pos {
+    fn error(self, message: &str) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            message: message.to_owned(),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, Pos)>,
+    idx: usize,
+    end: Pos,
+}
+
+fn tokenize(text: &str) -> Result<(Vec<(Token, Pos)>, Pos), ParseError> {
+    let mut tokens = Vec::new();
+    let mut pos = Pos { line: 1, column: 1 };
+    let mut chars = text.chars().peekable();
+    // advances the position past `c`
+    let advance = |pos: &mut Pos, c: char| {
+        if c == '\n' {
+            pos.line += 1;
+            pos.column = 1;
+        } else {
+            pos.column += 1;
+        }
+    };
+    while let Some(&c) = chars.peek() {
+        let start = pos;
+        let punctuation = match c {
+            '(' => Some(Token::LParen),
+            ')' => Some(Token::RParen),
+            '[' => Some(Token::LBracket),
+            ']' => Some(Token::RBracket),
+            '{' => Some(Token::LBrace),
+            '}' => Some(Token::RBrace),
+            ';' => Some(Token::Semicolon),
+            '=' => Some(Token::Equals),
+            _ => None,
+        };
+        if let Some(token) = punctuation {
+            chars.next();
+            advance(&mut pos, c);
+            tokens.push((token, start));
+        } else if c.is_whitespace() {
+            chars.next();
+            advance(&mut pos, c);
+        } else if c == '"' {
+            chars.next();
+            advance(&mut pos, c);
+            let mut s = String::new();
+            loop {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| start.error("unterminated string"))?;
+                advance(&mut pos, c);
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        let e = chars
+                            .next()
+                            .ok_or_else(|| start.error("unterminated string"))?;
+                        advance(&mut pos, e);
+                        match e {
+                            'n' => s.push('\n'),
+                            'r' => s.push('\r'),
+                            't' => s.push('\t'),
+                            '0' => s.push('\0'),
+                            '\\' | '"' | '\'' => s.push(e),
+                            'u' => {
+                                let mut hex = String::new();
+                                if chars.next() != Some('{') {
+                                    return Err(pos.error("expected `{` in unicode escape"));
+                                }
+                                advance(&mut pos, '{');
+                                loop {
+                                    let h = chars
+                                        .next()
+                                        .ok_or_else(|| start.error("unterminated string"))?;
+                                    advance(&mut pos, h);
+                                    if h == '}' {
+                                        break;
+                                    }
+                                    hex.push(h);
+                                }
+                                s.push(
+                                    u32::from_str_radix(&hex, 16)
+                                        .ok()
+                                        .and_then(std::char::from_u32)
+                                        .ok_or_else(|| pos.error("invalid unicode escape"))?,
+                                );
+                            }
+                            _ => return Err(pos.error("invalid escape sequence")),
+                        }
+                    }
+                    _ => s.push(c),
+                }
+            }
+            tokens.push((Token::Str(s), start));
+        } else if c == '/' && chars.clone().nth(1) == Some('/') {
+            // comment until the end of the line
+            while let Some(&c) = chars.peek() {
+                if c == '\n' {
+                    break;
+                }
+                chars.next();
+                advance(&mut pos, c);
+            }
+        } else {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || "()[]{};=\"".contains(c) {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+                advance(&mut pos, c);
+            }
+            tokens.push((Token::Atom(s), start));
+        }
+    }
+    Ok((tokens, pos))
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Self, ParseError> {
+        let (tokens, end) = tokenize(text)?;
+        Ok(Parser {
+            tokens,
+            idx: 0,
+            end,
+        })
+    }
+
+    fn at_end(&self) -> bool {
+        self.idx == self.tokens.len()
+    }
+
+    // Position of the next token.
+    fn pos(&self) -> Pos {
+        self.tokens.get(self.idx).map_or(self.end, |(_, pos)| *pos)
+    }
+
+    fn next(&mut self) -> Result<(Token, Pos), ParseError> {
+        let ret = self
+            .tokens
+            .get(self.idx)
+            .cloned()
+            .ok_or_else(|| self.end.error("unexpected end of input"))?;
+        self.idx += 1;
+        Ok(ret)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.idx).map(|(token, _)| token)
+    }
+
+    // Consumes the next token if it is `token`, and returns whether it was consumed.
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.idx += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<(), ParseError> {
+        let pos = self.pos();
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(pos.error(&format!("expected {:?}", token)))
+        }
+    }
+
+    fn atom(&mut self) -> Result<String, ParseError> {
+        match self.next()? {
+            (Token::Atom(s), _) => Ok(s),
+            (token, pos) => Err(pos.error(&format!("expected atom, found {:?}", token))),
+        }
+    }
+
+    fn expect_atom(&mut self, expected: &str) -> Result<(), ParseError> {
+        let pos = self.pos();
+        if self.atom()? == expected {
+            Ok(())
+        } else {
+            Err(pos.error(&format!("expected `{}`", expected)))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        match self.next()? {
+            (Token::Str(s), _) => Ok(s),
+            (token, pos) => Err(pos.error(&format!("expected string, found {:?}", token))),
+        }
+    }
+
+    fn check_index(&self, idx: &str, expected: usize, pos: Pos) -> Result<(), ParseError> {
+        if idx.parse::<usize>().ok() == Some(expected) {
+            Ok(())
+        } else {
+            Err(pos.error(&format!("expected index {}", expected)))
+        }
+    }
+
+    fn parsed_atom<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, ParseError> {
+        let pos = self.pos();
+        parse_atom(&self.atom()?, pos, what)
+    }
+
+    fn funcref(&mut self) -> Result<FuncIdx, ParseError> {
+        let pos = self.pos();
+        let atom = self.atom()?;
+        parse_funcref(&atom, pos)
+    }
+
+    fn vartype(&mut self) -> Result<VarType, ParseError> {
+        let pos = self.pos();
+        parse_vartype(&self.atom()?, pos)
+    }
+
+    fn opt_vartype(&mut self) -> Result<Option<VarType>, ParseError> {
+        let pos = self.pos();
+        parse_opt_vartype(&self.atom()?, pos)
+    }
+
+    fn type_list(&mut self) -> Result<Box<[VarType]>, ParseError> {
+        self.expect(&Token::LParen)?;
+        let mut ret = Vec::new();
+        while !self.eat(&Token::RParen) {
+            ret.push(self.vartype()?);
+        }
+        Ok(ret.into_boxed_slice())
+    }
+
+    fn import_type(&mut self) -> Result<ImportValType, ParseError> {
+        let pos = self.pos();
+        match self.atom()?.as_str() {
+            "undefined" => Ok(ImportValType::Undefined),
+            "number" => Ok(ImportValType::Number),
+            "string" => Ok(ImportValType::String),
+            _ => Err(pos.error("expected import type")),
+        }
+    }
+
+    fn target(&mut self) -> Result<TargetExpr, ParseError> {
+        let pos = self.pos();
+        let atom = self.atom()?;
+        let mut parts = atom.split('.');
+        let first = parts.next().unwrap();
+        let mut fields: Vec<(usize, usize)> = Vec::new();
+        for part in parts {
+            let (typeidx, fieldidx) = part
+                .split_once(':')
+                .ok_or_else(|| pos.error("expected `typeidx:fieldidx` in target"))?;
+            fields.push((
+                parse_atom(typeidx, pos, "typeidx")?,
+                parse_atom(fieldidx, pos, "fieldidx")?,
+            ));
+        }
+        let next = fields
+            .into_iter()
+            .rev()
+            .fold(None, |next, (typeidx, fieldidx)| {
+                Some(Box::new(StructField {
+                    typeidx,
+                    fieldidx,
+                    next,
+                }))
+            });
+        if let Some(idx) = first.strip_prefix("local#") {
+            Ok(TargetExpr::Local {
+                localidx: parse_atom(idx, pos, "localidx")?,
+                next,
+            })
+        } else if let Some(idx) = first.strip_prefix("global#") {
+            Ok(TargetExpr::Global {
+                globalidx: parse_atom(idx, pos, "globalidx")?,
+                next,
+            })
+        } else {
+            Err(pos.error("expected target"))
+        }
+    }
+
+    fn location(&mut self) -> Result<SourceLocation, ParseError> {
+        let pos = self.pos();
+        let atom = self.atom()?;
+        let err = || pos.error("expected source location");
+        let (file, rest) = atom.split_once(':').ok_or_else(err)?;
+        let (start, end) = rest.split_once('-').ok_or_else(err)?;
+        let position = |s: &str| -> Result<Position, ParseError> {
+            let (line, column) = s.split_once(':').ok_or_else(err)?;
+            Ok(Position {
+                line: parse_atom(line, pos, "line")?,
+                column: parse_atom(column, pos, "column")?,
+            })
+        };
+        Ok(SourceLocation {
+            file: parse_atom(file, pos, "file")?,
+            start: position(start)?,
+            end: position(end)?,
+        })
+    }
+
+    // Parses exprs until the closing parenthesis (which is also consumed).
+    fn exprs_until_rparen(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut ret = Vec::new();
+        while !self.eat(&Token::RParen) {
+            ret.push(self.expr()?);
+        }
+        Ok(ret)
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        self.expect(&Token::LParen)?;
+        let pos = self.pos();
+        let head = self.atom()?;
+        let (keyword, type_str) = head
+            .split_once(':')
+            .ok_or_else(|| pos.error("expected `kind:type`"))?;
+        let vartype = parse_opt_vartype(type_str, pos)?;
+        let kind = match keyword {
+            "undefined" => ExprKind::PrimUndefined,
+            "null" => ExprKind::PrimNull,
+            "number" => ExprKind::PrimNumber {
+                val: self.parsed_atom("number")?,
+            },
+            "boolean" => ExprKind::PrimBoolean {
+                val: self.parsed_atom("boolean")?,
+            },
+            "string" => ExprKind::PrimString {
+                val: self.string()?,
+            },
+            "struct" => {
+                let pos = self.pos();
+                match self.vartype()? {
+                    VarType::StructT { typeidx } => ExprKind::PrimStructT { typeidx },
+                    _ => return Err(pos.error("expected struct type")),
+                }
+            }
+            "func" => {
+                self.expect(&Token::LBracket)?;
+                let mut funcidxs = Vec::new();
+                while !self.eat(&Token::RBracket) {
+                    let pos = self.pos();
+                    let atom = self.atom()?;
+                    funcidxs.push(match atom.strip_suffix(":closure") {
+                        Some(funcref) => OverloadEntry {
+                            funcidx: parse_funcref(funcref, pos)?,
+                            has_closure_param: true,
+                        },
+                        None => OverloadEntry {
+                            funcidx: parse_funcref(&atom, pos)?,
+                            has_closure_param: false,
+                        },
+                    });
+                }
+                ExprKind::PrimFunc {
+                    funcidxs: funcidxs.into_boxed_slice(),
+                    closure: Box::new(self.expr()?),
+                }
+            }
+            "typecast" => {
+                let expected = self.vartype()?;
+                let pos = self.pos();
+                let create_narrow_local = match self.atom()?.as_str() {
+                    "narrow" => true,
+                    "nonarrow" => false,
+                    _ => return Err(pos.error("expected `narrow` or `nonarrow`")),
+                };
+                ExprKind::TypeCast {
+                    test: Box::new(self.expr()?),
+                    expected,
+                    create_narrow_local,
+                    true_expr: Box::new(self.expr()?),
+                    false_expr: Box::new(self.expr()?),
+                }
+            }
+            "var" => ExprKind::VarName {
+                source: self.target()?,
+            },
+            "prim" => {
+                let pos = self.pos();
+                let name = self.atom()?;
+                let prim_inst = PRIM_INST_NAMES
+                    .iter()
+                    .find(|(_, n)| *n == name)
+                    .ok_or_else(|| pos.error(&format!("unknown prim_inst `{}`", name)))?
+                    .0;
+                ExprKind::PrimAppl {
+                    prim_inst,
+                    args: self.exprs_until_rparen()?.into_boxed_slice(),
+                }
+            }
+            "appl" => {
+                let location = self.location()?;
+                let func = Box::new(self.expr()?);
+                ExprKind::Appl {
+                    func,
+                    args: self.exprs_until_rparen()?.into_boxed_slice(),
+                    location,
+                }
+            }
+            "call" => ExprKind::DirectAppl {
+                funcidx: self.funcref()?,
+                args: self.exprs_until_rparen()?.into_boxed_slice(),
+            },
+            "if" => ExprKind::Conditional {
+                cond: Box::new(self.expr()?),
+                true_expr: Box::new(self.expr()?),
+                false_expr: Box::new(self.expr()?),
+            },
+            "decl" => {
+                let local = self.vartype()?;
+                let init = if self.peek() == Some(&Token::Atom("_".to_owned())) {
+                    self.idx += 1;
+                    None
+                } else {
+                    Some(Box::new(self.expr()?))
+                };
+                ExprKind::Declaration {
+                    local,
+                    init,
+                    contained_expr: Box::new(self.expr()?),
+                }
+            }
+            "assign" => ExprKind::Assign {
+                target: self.target()?,
+                expr: Box::new(self.expr()?),
+            },
+            "return" => ExprKind::Return {
+                expr: Box::new(self.expr()?),
+            },
+            "break" => ExprKind::Break {
+                num_frames: self.parsed_atom("num_frames")?,
+                expr: Box::new(self.expr()?),
+            },
+            "block" => ExprKind::Block {
+                expr: Box::new(self.expr()?),
+            },
+            "loop" => ExprKind::Loop {
+                expr: Box::new(self.expr()?),
+            },
+            "seq" => ExprKind::Sequence {
+                content: self.exprs_until_rparen()?,
+            },
+            "trap" => {
+                let pos = self.pos();
+                let code_atom = self.atom()?;
+                let code = code_atom
+                    .strip_prefix("0x")
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| pos.error("expected hexadecimal error code"))?;
+                ExprKind::Trap {
+                    code,
+                    location: self.location()?,
+                }
+            }
+            _ => return Err(pos.error(&format!("unknown expression kind `{}`", keyword))),
+        };
+        // the kinds with a variable number of subexprs have already consumed the closing parenthesis
+        match kind {
+            ExprKind::PrimAppl { .. }
+            | ExprKind::Appl { .. }
+            | ExprKind::DirectAppl { .. }
+            | ExprKind::Sequence { .. } => {}
+            _ => self.expect(&Token::RParen)?,
+        }
+        Ok(Expr { vartype, kind })
+    }
+}
+
+fn parse_atom<T: std::str::FromStr>(atom: &str, pos: Pos, what: &str) -> Result<T, ParseError> {
+    atom.parse()
+        .map_err(|_| pos.error(&format!("expected {}, found `{}`", what, atom)))
+}
+
+fn parse_funcref(atom: &str, pos: Pos) -> Result<FuncIdx, ParseError> {
+    let idx = atom
+        .strip_prefix("func#")
+        .ok_or_else(|| pos.error("expected `func#N`"))?;
+    parse_atom(idx, pos, "funcidx")
+}
+
+fn parse_vartype(atom: &str, pos: Pos) -> Result<VarType, ParseError> {
+    match atom {
+        "any" => Ok(VarType::Any),
+        "unassigned" => Ok(VarType::Unassigned),
+        "undefined" => Ok(VarType::Undefined),
+        "number" => Ok(VarType::Number),
+        "boolean" => Ok(VarType::Boolean),
+        "string" => Ok(VarType::String),
+        "func" => Ok(VarType::Func),
+        "null" => Ok(VarType::Null),
+        "array" => Ok(VarType::Array),
+        _ => match atom.strip_prefix("struct#") {
+            Some(idx) => Ok(VarType::StructT {
+                typeidx: parse_atom(idx, pos, "typeidx")?,
+            }),
+            None => Err(pos.error(&format!("expected type, found `{}`", atom))),
+        },
+    }
+}
+
+fn parse_opt_vartype(atom: &str, pos: Pos) -> Result<Option<VarType>, ParseError> {
+    if atom == "!" {
+        Ok(None)
+    } else {
+        parse_vartype(atom, pos).map(Some)
+    }
+}
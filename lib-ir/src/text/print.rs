@@ -0,0 +1,268 @@
+use super::*;
+
+use std::fmt::Write;
+
+/**
+ * Prints the program in the textual format.
+ */
+pub fn print_program(program: &Program) -> String {
+    let mut out = String::new();
+    for (typeidx, fields) in program.struct_types.iter().enumerate() {
+        writeln!(out, "struct#{} = ({});", typeidx, type_list_str(fields)).unwrap();
+    }
+    for (funcidx, import) in program.imports.iter().enumerate() {
+        writeln!(
+            out,
+            "import#{} = {:?} {:?} ({}) -> {};",
+            funcidx,
+            import.module_name,
+            import.entity_name,
+            import
+                .params
+                .iter()
+                .map(|param| import_type_str(*param))
+                .collect::<Vec<_>>()
+                .join(" "),
+            import_type_str(import.result)
+        )
+        .unwrap();
+    }
+    for (globalidx, vartype) in program.globals.iter().enumerate() {
+        writeln!(out, "global#{} = {};", globalidx, type_str(*vartype)).unwrap();
+    }
+    writeln!(out, "entry func#{};", program.entry_point).unwrap();
+    for (i, func) in program.funcs.iter().enumerate() {
+        write!(
+            out,
+            "func#{} ({}) -> {}",
+            program.imports.len() + i,
+            type_list_str(&func.params),
+            opt_type_str(func.result)
+        )
+        .unwrap();
+        for (params, result, funcidx) in &func.signature_filter {
+            write!(
+                out,
+                "\n  filter ({}) -> {} = func#{}",
+                type_list_str(params),
+                type_str(*result),
+                funcidx
+            )
+            .unwrap();
+        }
+        out.push_str(" {\n  ");
+        write_expr(&mut out, &func.expr, 2);
+        out.push_str("\n}\n");
+    }
+    out
+}
+
+/**
+ * Prints the expression in the textual format.
+ */
+pub fn print_expr(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, expr, 0);
+    out
+}
+
+pub(super) fn type_str(vartype: VarType) -> String {
+    match vartype {
+        VarType::Any => "any".to_owned(),
+        VarType::Unassigned => "unassigned".to_owned(),
+        VarType::Undefined => "undefined".to_owned(),
+        VarType::Number => "number".to_owned(),
+        VarType::Boolean => "boolean".to_owned(),
+        VarType::String => "string".to_owned(),
+        VarType::Func => "func".to_owned(),
+        VarType::Null => "null".to_owned(),
+        VarType::Array => "array".to_owned(),
+        VarType::StructT { typeidx } => format!("struct#{}", typeidx),
+    }
+}
+
+fn opt_type_str(opt_vartype: Option<VarType>) -> String {
+    match opt_vartype {
+        Some(vartype) => type_str(vartype),
+        None => "!".to_owned(),
+    }
+}
+
+fn type_list_str(vartypes: &[VarType]) -> String {
+    vartypes
+        .iter()
+        .map(|vartype| type_str(*vartype))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn import_type_str(import_type: ImportValType) -> &'static str {
+    match import_type {
+        ImportValType::Undefined => "undefined",
+        ImportValType::Number => "number",
+        ImportValType::String => "string",
+    }
+}
+
+fn target_str(target: &TargetExpr) -> String {
+    let (mut ret, mut next) = match target {
+        TargetExpr::Global { globalidx, next } => (format!("global#{}", globalidx), next),
+        TargetExpr::Local { localidx, next } => (format!("local#{}", localidx), next),
+    };
+    while let Some(field) = next {
+        write!(ret, ".{}:{}", field.typeidx, field.fieldidx).unwrap();
+        next = &field.next;
+    }
+    ret
+}
+
+fn location_str(location: &SourceLocation) -> String {
+    format!(
+        "{}:{}:{}-{}:{}",
+        location.file,
+        location.start.line,
+        location.start.column,
+        location.end.line,
+        location.end.column
+    )
+}
+
+/**
+ * Splits the expr into its keyword, the operands that are not exprs, and its subexprs.
+ */
+fn expr_parts(expr: &Expr) -> (&'static str, Vec<String>, Vec<&Expr>) {
+    match &expr.kind {
+        ExprKind::PrimUndefined => ("undefined", vec![], vec![]),
+        ExprKind::PrimNull => ("null", vec![], vec![]),
+        ExprKind::PrimNumber { val } => ("number", vec![format!("{:?}", val)], vec![]),
+        ExprKind::PrimBoolean { val } => ("boolean", vec![val.to_string()], vec![]),
+        ExprKind::PrimString { val } => ("string", vec![format!("{:?}", val)], vec![]),
+        ExprKind::PrimStructT { typeidx } => {
+            ("struct", vec![format!("struct#{}", typeidx)], vec![])
+        }
+        ExprKind::PrimFunc { funcidxs, closure } => (
+            "func",
+            vec![format!(
+                "[{}]",
+                funcidxs
+                    .iter()
+                    .map(|oe| if oe.has_closure_param {
+                        format!("func#{}:closure", oe.funcidx)
+                    } else {
+                        format!("func#{}", oe.funcidx)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )],
+            vec![closure],
+        ),
+        ExprKind::TypeCast {
+            test,
+            expected,
+            create_narrow_local,
+            true_expr,
+            false_expr,
+        } => (
+            "typecast",
+            vec![
+                type_str(*expected),
+                if *create_narrow_local {
+                    "narrow".to_owned()
+                } else {
+                    "nonarrow".to_owned()
+                },
+            ],
+            vec![test, true_expr, false_expr],
+        ),
+        ExprKind::VarName { source } => ("var", vec![target_str(source)], vec![]),
+        ExprKind::PrimAppl { prim_inst, args } => (
+            "prim",
+            vec![PRIM_INST_NAMES
+                .iter()
+                .find(|(p, _)| p == prim_inst)
+                .unwrap()
+                .1
+                .to_owned()],
+            args.iter().collect(),
+        ),
+        ExprKind::Appl {
+            func,
+            args,
+            location,
+        } => (
+            "appl",
+            vec![location_str(location)],
+            std::iter::once(&**func).chain(args.iter()).collect(),
+        ),
+        ExprKind::DirectAppl { funcidx, args } => (
+            "call",
+            vec![format!("func#{}", funcidx)],
+            args.iter().collect(),
+        ),
+        ExprKind::Conditional {
+            cond,
+            true_expr,
+            false_expr,
+        } => ("if", vec![], vec![cond, true_expr, false_expr]),
+        ExprKind::Declaration {
+            local,
+            init,
+            contained_expr,
+        } => match init {
+            Some(init_expr) => (
+                "decl",
+                vec![type_str(*local)],
+                vec![init_expr, contained_expr],
+            ),
+            None => (
+                "decl",
+                vec![type_str(*local), "_".to_owned()],
+                vec![contained_expr],
+            ),
+        },
+        ExprKind::Assign { target, expr } => ("assign", vec![target_str(target)], vec![expr]),
+        ExprKind::Return { expr } => ("return", vec![], vec![expr]),
+        ExprKind::Break { num_frames, expr } => ("break", vec![num_frames.to_string()], vec![expr]),
+        ExprKind::Block { expr } => ("block", vec![], vec![expr]),
+        ExprKind::Loop { expr } => ("loop", vec![], vec![expr]),
+        ExprKind::Sequence { content } => ("seq", vec![], content.iter().collect()),
+        ExprKind::Trap { code, location } => (
+            "trap",
+            vec![format!("{:#x}", code), location_str(location)],
+            vec![],
+        ),
+    }
+}
+
+fn is_leaf(expr: &Expr) -> bool {
+    expr_parts(expr).2.is_empty()
+}
+
+/**
+ * Writes the expr to `out`.
+ * Exprs whose subexprs are all leaves are written on a single line,
+ * otherwise each subexpr is written on its own line, indented by two more spaces than `indent`.
+ */
+fn write_expr(out: &mut String, expr: &Expr, indent: usize) {
+    let (keyword, operands, subexprs) = expr_parts(expr);
+    write!(out, "({}:{}", keyword, opt_type_str(expr.vartype)).unwrap();
+    for operand in operands {
+        out.push(' ');
+        out.push_str(&operand);
+    }
+    if subexprs.iter().copied().all(is_leaf) {
+        for subexpr in subexprs {
+            out.push(' ');
+            write_expr(out, subexpr, indent);
+        }
+    } else {
+        for subexpr in subexprs {
+            out.push('\n');
+            for _ in 0..indent + 2 {
+                out.push(' ');
+            }
+            write_expr(out, subexpr, indent + 2);
+        }
+    }
+    out.push(')');
+}
@@ -0,0 +1,611 @@
+/**
+ * Checks the structural invariants of a Program, most of which are only documented in the comments in lib.rs.
+ * In particular:
+ * * All typeidxs, funcidxs, localidxs and globalidxs are in range, and struct fields refer to the correct struct type.
+ * * Break::num_frames refers to an enclosing Block or Loop.
+ * * Types of subexpressions fit wherever they are used (e.g. Assign may widen to Any but not narrow).
+ * * Void expressions only appear where their value is not needed (Void exprs are allowed in the middle of a Sequence).
+ * * signature_filter entries are subtypes of the params, and refer to functions with the correct signature.
+ * * Where the backend requires two types to be identical (e.g. a Declaration and its contained expr), they are identical.
+ *
+ * It should accept every Program that the backend can correctly encode.
+ */
+use super::superset::Superset;
+use super::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub funcidx: Option<FuncIdx>, // the function containing the error (None if the error is not in any function)
+    pub message: String,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.funcidx {
+            Some(funcidx) => write!(f, "func#{}: {}", funcidx, self.message),
+            None => write!(f, "program: {}", self.message),
+        }
+    }
+}
+
+/**
+ * Checks all the invariants of the program, returning all the violations found.
+ */
+pub fn verify(program: &Program) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+    verify_program(program, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/**
+ * Verifies the program if the `verify` feature is enabled, and panics with all the violations if there are any.
+ * `stage` should describe the frontend or optimisation pass that produced this program.
+ */
+pub fn debug_verify(program: &Program, stage: &str) {
+    if cfg!(feature = "verify") {
+        if let Err(errors) = verify(program) {
+            let mut message = format!("ICE: IR verification failed after {}:", stage);
+            for error in errors {
+                message.push_str("\n  ");
+                message.push_str(&error.to_string());
+            }
+            panic!("{}", message);
+        }
+    }
+}
+
+enum Landing {
+    Block(Option<VarType>),
+    Loop,
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    funcidx: Option<FuncIdx>,
+    errors: &'a mut Vec<VerifyError>,
+    return_type: Option<VarType>,
+    locals: Vec<VarType>,   // params, followed by all the locals in scope
+    landings: Vec<Landing>, // innermost Block or Loop is at the back
+}
+
+fn verify_program(program: &Program, errors: &mut Vec<VerifyError>) {
+    let mut verifier = Verifier {
+        program,
+        funcidx: None,
+        errors,
+        return_type: None,
+        locals: Vec::new(),
+        landings: Vec::new(),
+    };
+
+    for fields in &program.struct_types {
+        for field in fields.iter() {
+            verifier.check_type(*field);
+        }
+    }
+    for global in &program.globals {
+        verifier.check_type(*global);
+    }
+    if program.entry_point < program.imports.len()
+        || program.entry_point >= program.imports.len() + program.funcs.len()
+    {
+        verifier.error(format!(
+            "entry point func#{} is not a function",
+            program.entry_point
+        ));
+    } else if !program.get_func(program.entry_point).params.is_empty() {
+        verifier.error("entry point must not have any params".to_owned());
+    }
+
+    for (i, func) in program.funcs.iter().enumerate() {
+        verifier.funcidx = Some(program.imports.len() + i);
+        verifier.verify_func(func);
+    }
+}
+
+// Returns true if a value of type `source` can be used where `target` is expected.
+// Void sources fit anywhere (since the value is never produced), but only Void fits into Void.
+fn fits(target: Option<VarType>, source: Option<VarType>) -> bool {
+    match (target, source) {
+        (_, None) => true,
+        (None, Some(_)) => false,
+        (Some(t), Some(s)) => t.superset(&s),
+    }
+}
+
+fn type_str(opt_vartype: Option<VarType>) -> String {
+    match opt_vartype {
+        Some(vartype) => format!("{:?}", vartype),
+        None => "Void".to_owned(),
+    }
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&mut self, message: String) {
+        self.errors.push(VerifyError {
+            funcidx: self.funcidx,
+            message,
+        });
+    }
+
+    fn check_type(&mut self, vartype: VarType) {
+        if let VarType::StructT { typeidx } = vartype {
+            if typeidx >= self.program.struct_types.len() {
+                self.error(format!("struct type #{} does not exist", typeidx));
+            }
+        }
+    }
+
+    // Returns the (params, result) of the given function or import, or None if it does not exist.
+    fn signature(&self, funcidx: FuncIdx) -> Option<(Box<[VarType]>, Option<VarType>)> {
+        if let Some(import) = self.program.imports.get(funcidx) {
+            Some((
+                import.params.iter().map(|p| VarType::from(*p)).collect(),
+                Some(import.result.into()),
+            ))
+        } else {
+            self.program
+                .funcs
+                .get(funcidx - self.program.imports.len())
+                .map(|func| (func.params.clone(), func.result))
+        }
+    }
+
+    fn verify_func(&mut self, func: &Func) {
+        for param in func.params.iter() {
+            self.check_type(*param);
+        }
+        if let Some(result) = func.result {
+            self.check_type(result);
+        }
+
+        for (params, result, constrained_funcidx) in &func.signature_filter {
+            if !func.params.superset(params) || !fits(func.result, Some(*result)) {
+                self.error(format!(
+                    "signature filter for func#{} is not a subtype of the function signature",
+                    constrained_funcidx
+                ));
+            }
+            match self.signature(*constrained_funcidx) {
+                Some((constrained_params, constrained_result))
+                    if *constrained_funcidx >= self.program.imports.len() =>
+                {
+                    if constrained_params != *params || !fits(Some(*result), constrained_result) {
+                        self.error(format!(
+                            "signature filter does not match the signature of func#{}",
+                            constrained_funcidx
+                        ));
+                    }
+                }
+                _ => self.error(format!(
+                    "signature filter refers to func#{}, which is not a function",
+                    constrained_funcidx
+                )),
+            }
+        }
+
+        self.return_type = func.result;
+        self.locals = func.params.to_vec();
+        self.landings.clear();
+        self.verify_expr(&func.expr);
+        if !fits(func.result, func.expr.vartype) {
+            self.error(format!(
+                "function body of type {} does not fit the result type {}",
+                type_str(func.expr.vartype),
+                type_str(func.result)
+            ));
+        }
+    }
+
+    // Verifies an expr whose value will be used, so it must not be Void.
+    fn verify_value(&mut self, expr: &Expr, what: &str) {
+        self.verify_expr(expr);
+        if expr.vartype.is_none() {
+            self.error(format!("{} must not be Void", what));
+        }
+    }
+
+    // Verifies the args of a call, and that they fit into the given params.
+    fn verify_args(&mut self, params: &[VarType], args: &[Expr], callee: &str) {
+        if params.len() != args.len() {
+            self.error(format!(
+                "{} expects {} args, but got {}",
+                callee,
+                params.len(),
+                args.len()
+            ));
+        }
+        for (i, arg) in args.iter().enumerate() {
+            self.verify_value(arg, "argument");
+            if let Some(param) = params.get(i) {
+                if !fits(Some(*param), arg.vartype) {
+                    self.error(format!(
+                        "argument #{} of type {} does not fit param of type {:?} of {}",
+                        i,
+                        type_str(arg.vartype),
+                        param,
+                        callee
+                    ));
+                }
+            }
+        }
+    }
+
+    // Returns the type of the target, or None if it is invalid.
+    fn target_type(&mut self, target: &TargetExpr) -> Option<VarType> {
+        let (mut vartype, mut next) = match target {
+            TargetExpr::Global { globalidx, next } => match self.program.globals.get(*globalidx) {
+                Some(vartype) => (*vartype, next),
+                None => {
+                    self.error(format!("global #{} does not exist", globalidx));
+                    return None;
+                }
+            },
+            TargetExpr::Local { localidx, next } => match self.locals.get(*localidx) {
+                Some(vartype) => (*vartype, next),
+                None => {
+                    self.error(format!(
+                        "local #{} is not in scope (there are {} locals in scope)",
+                        localidx,
+                        self.locals.len()
+                    ));
+                    return None;
+                }
+            },
+        };
+        while let Some(field) = next {
+            if vartype
+                != (VarType::StructT {
+                    typeidx: field.typeidx,
+                })
+            {
+                self.error(format!(
+                    "cannot access field of struct type #{} from a variable of type {:?}",
+                    field.typeidx, vartype
+                ));
+                return None;
+            }
+            match self.program.struct_types.get(field.typeidx) {
+                Some(fields) if field.fieldidx < fields.len() => {
+                    vartype = fields[field.fieldidx];
+                }
+                _ => {
+                    self.error(format!(
+                        "field #{} of struct type #{} does not exist",
+                        field.fieldidx, field.typeidx
+                    ));
+                    return None;
+                }
+            }
+            next = &field.next;
+        }
+        Some(vartype)
+    }
+
+    // Checks that the expr has exactly the given type.
+    fn expect_type(&mut self, expr: &Expr, expected: Option<VarType>, what: &str) {
+        if expr.vartype != expected {
+            self.error(format!(
+                "{} must have type {}, but has type {}",
+                what,
+                type_str(expected),
+                type_str(expr.vartype)
+            ));
+        }
+    }
+
+    fn verify_expr(&mut self, expr: &Expr) {
+        if let Some(vartype) = expr.vartype {
+            self.check_type(vartype);
+        }
+        match &expr.kind {
+            ExprKind::PrimUndefined => {
+                self.expect_type(expr, Some(VarType::Undefined), "PrimUndefined")
+            }
+            ExprKind::PrimNull => self.expect_type(expr, Some(VarType::Null), "PrimNull"),
+            ExprKind::PrimNumber { val: _ } => {
+                self.expect_type(expr, Some(VarType::Number), "PrimNumber")
+            }
+            ExprKind::PrimBoolean { val: _ } => {
+                self.expect_type(expr, Some(VarType::Boolean), "PrimBoolean")
+            }
+            ExprKind::PrimString { val: _ } => {
+                self.expect_type(expr, Some(VarType::String), "PrimString")
+            }
+            ExprKind::PrimStructT { typeidx } => {
+                self.check_type(VarType::StructT { typeidx: *typeidx });
+                self.expect_type(
+                    expr,
+                    Some(VarType::StructT { typeidx: *typeidx }),
+                    "PrimStructT",
+                );
+            }
+            ExprKind::PrimFunc { funcidxs, closure } => {
+                self.expect_type(expr, Some(VarType::Func), "PrimFunc");
+                self.verify_value(closure, "closure");
+                for oe in funcidxs.iter() {
+                    match self.signature(oe.funcidx) {
+                        Some((params, _)) => {
+                            if oe.has_closure_param
+                                && !params
+                                    .first()
+                                    .is_some_and(|p| fits(Some(*p), closure.vartype))
+                            {
+                                self.error(format!(
+                                    "closure of type {} does not fit the first param of func#{}",
+                                    type_str(closure.vartype),
+                                    oe.funcidx
+                                ));
+                            }
+                        }
+                        None => self.error(format!("func#{} does not exist", oe.funcidx)),
+                    }
+                }
+            }
+            ExprKind::TypeCast {
+                test,
+                expected,
+                create_narrow_local,
+                true_expr,
+                false_expr,
+            } => {
+                // the test may have a narrower type than Any, since propagation will resolve the cast statically
+                self.verify_value(test, "test expr of TypeCast");
+                self.check_type(*expected);
+                if *create_narrow_local {
+                    self.locals.push(*expected);
+                    self.verify_expr(true_expr);
+                    self.locals.pop();
+                } else {
+                    self.verify_expr(true_expr);
+                }
+                self.verify_expr(false_expr);
+                self.verify_branches(expr, true_expr, false_expr, "TypeCast");
+            }
+            ExprKind::VarName { source } => {
+                if let Some(source_type) = self.target_type(source) {
+                    if expr.vartype.is_none() {
+                        self.error("VarName must not be Void".to_owned());
+                    } else if !fits(Some(source_type), expr.vartype) {
+                        self.error(format!(
+                            "VarName of type {} cannot read from a variable of type {:?}",
+                            type_str(expr.vartype),
+                            source_type
+                        ));
+                    }
+                }
+            }
+            ExprKind::PrimAppl { prim_inst, args } => {
+                let (params, result) = prim_inst.signature();
+                self.verify_args(params, args, &format!("{:?}", prim_inst));
+                self.expect_type(expr, result, &format!("PrimAppl of {:?}", prim_inst));
+            }
+            ExprKind::Appl {
+                func,
+                args,
+                location: _,
+            } => {
+                self.verify_value(func, "function of Appl");
+                self.expect_type(func, Some(VarType::Func), "function of Appl");
+                for arg in args.iter() {
+                    self.verify_value(arg, "argument");
+                }
+                self.expect_type(expr, Some(VarType::Any), "Appl");
+            }
+            ExprKind::DirectAppl { funcidx, args } => match self.signature(*funcidx) {
+                Some((params, result)) => {
+                    self.verify_args(&params, args, &format!("func#{}", funcidx));
+                    self.expect_type(expr, result, &format!("DirectAppl of func#{}", funcidx));
+                }
+                None => self.error(format!("func#{} does not exist", funcidx)),
+            },
+            ExprKind::Conditional {
+                cond,
+                true_expr,
+                false_expr,
+            } => {
+                self.verify_value(cond, "condition");
+                self.expect_type(cond, Some(VarType::Boolean), "condition");
+                self.verify_expr(true_expr);
+                self.verify_expr(false_expr);
+                self.verify_branches(expr, true_expr, false_expr, "Conditional");
+            }
+            ExprKind::Declaration {
+                local,
+                init,
+                contained_expr,
+            } => {
+                self.check_type(*local);
+                if let Some(init_expr) = init {
+                    self.verify_value(init_expr, "initializer");
+                    if !fits(Some(*local), init_expr.vartype) {
+                        self.error(format!(
+                            "initializer of type {} does not fit local of type {:?}",
+                            type_str(init_expr.vartype),
+                            local
+                        ));
+                    }
+                }
+                self.locals.push(*local);
+                self.verify_expr(contained_expr);
+                self.locals.pop();
+                self.expect_type(expr, contained_expr.vartype, "Declaration");
+            }
+            ExprKind::Assign {
+                target,
+                expr: rhs_expr,
+            } => {
+                self.verify_value(rhs_expr, "assigned expr");
+                if let Some(target_type) = self.target_type(target) {
+                    if !fits(Some(target_type), rhs_expr.vartype) {
+                        self.error(format!(
+                            "cannot assign expr of type {} to a variable of type {:?}",
+                            type_str(rhs_expr.vartype),
+                            target_type
+                        ));
+                    }
+                }
+                self.expect_type(expr, Some(VarType::Undefined), "Assign");
+            }
+            ExprKind::Return { expr: inner_expr } => {
+                self.verify_value(inner_expr, "returned expr");
+                if !fits(self.return_type, inner_expr.vartype) {
+                    self.error(format!(
+                        "returned expr of type {} does not fit the result type {}",
+                        type_str(inner_expr.vartype),
+                        type_str(self.return_type)
+                    ));
+                }
+                self.expect_type(expr, None, "Return");
+            }
+            ExprKind::Break {
+                num_frames,
+                expr: inner_expr,
+            } => {
+                self.verify_value(inner_expr, "expr of Break");
+                let landing_type = if *num_frames < self.landings.len() {
+                    match &self.landings[self.landings.len() - 1 - num_frames] {
+                        Landing::Block(vartype) => *vartype,
+                        Landing::Loop => Some(VarType::Undefined),
+                    }
+                } else {
+                    self.error(format!(
+                        "Break out of {} frames, but there are only {} enclosing Blocks and Loops",
+                        num_frames + 1,
+                        self.landings.len()
+                    ));
+                    None
+                };
+                if landing_type.is_some() && !fits(landing_type, inner_expr.vartype) {
+                    self.error(format!(
+                        "expr of Break of type {} does not fit the target of type {}",
+                        type_str(inner_expr.vartype),
+                        type_str(landing_type)
+                    ));
+                }
+                self.expect_type(expr, None, "Break");
+            }
+            ExprKind::Block { expr: inner_expr } => {
+                self.landings.push(Landing::Block(expr.vartype));
+                self.verify_expr(inner_expr);
+                self.landings.pop();
+                // the backend does not widen the value of the inner expr
+                if inner_expr.vartype.is_some() {
+                    self.expect_type(expr, inner_expr.vartype, "Block");
+                }
+            }
+            ExprKind::Loop { expr: inner_expr } => {
+                self.landings.push(Landing::Loop);
+                self.verify_expr(inner_expr);
+                self.landings.pop();
+                // the backend does not widen the value of the inner expr
+                if inner_expr.vartype.is_some() {
+                    self.expect_type(expr, inner_expr.vartype, "Loop");
+                }
+            }
+            ExprKind::Sequence { content } => match content.split_last() {
+                Some((last, others)) => {
+                    for inner_expr in others {
+                        self.verify_expr(inner_expr);
+                    }
+                    self.verify_expr(last);
+                    // if a non-last expr is Void, then the rest is unreachable and the type of the Sequence is unconstrained
+                    if others.iter().all(|inner_expr| inner_expr.vartype.is_some())
+                        && !fits(expr.vartype, last.vartype)
+                    {
+                        self.error(format!(
+                            "last expr of type {} does not fit the Sequence of type {}",
+                            type_str(last.vartype),
+                            type_str(expr.vartype)
+                        ));
+                    }
+                }
+                None => self.expect_type(expr, Some(VarType::Undefined), "empty Sequence"),
+            },
+            ExprKind::Trap {
+                code: _,
+                location: _,
+            } => self.expect_type(expr, None, "Trap"),
+        }
+    }
+
+    fn verify_branches(&mut self, expr: &Expr, true_expr: &Expr, false_expr: &Expr, what: &str) {
+        for branch in &[true_expr, false_expr] {
+            // the backend elides a PrimUndefined false branch, so it is allowed even if the whole expr is Void
+            let elided = expr.vartype.is_none() && branch.is_prim_undefined();
+            if !elided && !fits(expr.vartype, branch.vartype) {
+                self.error(format!(
+                    "branch of type {} does not fit the {} of type {}",
+                    type_str(branch.vartype),
+                    what,
+                    type_str(expr.vartype)
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(text: &str) -> Vec<String> {
+        match verify(&text::parse_program(text).unwrap()) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_program() {
+        let text = r#"
+            struct#0 = (any);
+            func#0 (struct#0 any) -> any {
+              (seq:any
+                (assign:undefined local#0.0:0 (var:any local#1))
+                (block:any
+                  (loop:!
+                    (typecast:! number narrow (var:any local#1)
+                      (return:! (var:number local#2))
+                      (break:! 1 (var:any local#0.0:0))))))
+            }
+            func#1 () -> any {
+              (call:any func#0 (struct:struct#0 struct#0) (number:number 1.0))
+            }
+            entry func#1;
+        "#;
+        assert_eq!(messages(text), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_violations() {
+        let text = r#"
+            func#0 (number) -> number
+              filter (any) -> number = func#0 {
+              (seq:undefined
+                (assign:undefined local#0 (string:string "a"))
+                (var:number local#1)
+                (break:! 0 (undefined:undefined))
+                (decl:undefined any (trap:! 0x1 0:0:0-0:0) (undefined:undefined)))
+            }
+            entry func#0;
+        "#;
+        assert_eq!(
+            messages(text),
+            vec![
+                "program: entry point must not have any params",
+                "func#0: signature filter for func#0 is not a subtype of the function signature",
+                "func#0: signature filter does not match the signature of func#0",
+                "func#0: cannot assign expr of type String to a variable of type Number",
+                "func#0: local #1 is not in scope (there are 1 locals in scope)",
+                "func#0: Break out of 1 frames, but there are only 0 enclosing Blocks and Loops",
+                "func#0: initializer must not be Void",
+                "func#0: function body of type Undefined does not fit the result type Number",
+            ]
+        );
+    }
+}
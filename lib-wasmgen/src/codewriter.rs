@@ -7,6 +7,7 @@ use projstd::iter::SequentialCountAdapter;
 #[derive(Default)]
 pub struct ExprBuilder {
     bytecode: Vec<u8>,
+    multi_value_blocktypes: Vec<(usize, Box<[ValType]>)>, // the position of the placeholder typeidx of each block with more than one result, and its result types
 }
 
 pub struct LocalsManager {
@@ -22,6 +23,10 @@ pub struct CodeBuilder {
 
 impl ExprBuilder {
     pub fn build(self) -> Expr {
+        assert!(
+            self.multi_value_blocktypes.is_empty(),
+            "Blocks with more than one result are only allowed in functions"
+        );
         Expr {
             bytecode: self.bytecode.into_boxed_slice(),
         }
@@ -32,6 +37,19 @@ impl ExprBuilder {
     fn write_to_slice(self, out: &mut [u8]) {
         out.copy_from_slice(self.bytecode.as_slice());
     }
+    // Fills in the typeidx of each block with more than one result, using `get_typeidx` to get the typeidx of `[] -> [result types]`.
+    pub(crate) fn resolve_multi_value_blocktypes<F: FnMut(Box<[ValType]>) -> TypeIdx>(
+        &mut self,
+        mut get_typeidx: F,
+    ) {
+        for (pos, result_types) in std::mem::take(&mut self.multi_value_blocktypes) {
+            // the placeholder has exactly 5 bytes, so we write the typeidx as a non-minimal (but valid) s33
+            let typeidx: u32 = get_typeidx(result_types).idx;
+            for (i, b) in self.bytecode[pos..pos + 5].iter_mut().enumerate() {
+                *b = ((typeidx >> (7 * i)) & 127u32) as u8 | if i < 4 { 128u8 } else { 0u8 };
+            }
+        }
+    }
 }
 
 impl LocalsManager {
@@ -544,14 +562,16 @@ impl ExprBuilder {
         self.append_bytes(op_code.value());
     }
     fn append_result_type(&mut self, result_type: &[ValType]) {
-        assert!(
-            result_type.len() <= 1,
-            "Wasm 1.0 only allows at most one result type"
-        );
-        if result_type.is_empty() {
-            self.append_bytes(&[0x40]);
-        } else {
-            self.append_bytes(&[result_type[0].value()]);
+        match result_type.len() {
+            0 => self.append_bytes(&[0x40]),
+            1 => self.append_bytes(&[result_type[0].value()]),
+            _ => {
+                // Multi-value proposal: the block type is the typeidx of `[] -> [result types]`,
+                // which is only known when the function is committed (see WasmModule::commit_func()).
+                self.multi_value_blocktypes
+                    .push((self.bytecode.len(), result_type.into()));
+                self.append_bytes(&[0x80, 0x80, 0x80, 0x80, 0x00]);
+            }
         }
     }
     pub fn unreachable(&mut self) {
@@ -38,7 +38,14 @@ impl WasmModule {
         (typeidx, funcidx)
     }
     // Commit a function that has been previously registered
-    pub fn commit_func(&mut self, funcidx: FuncIdx, code_builder: CodeBuilder) {
+    // (this also adds the types of the blocks with more than one result to the type section)
+    pub fn commit_func(&mut self, funcidx: FuncIdx, mut code_builder: CodeBuilder) {
+        let type_section = &mut self.type_section;
+        code_builder
+            .expr_builder()
+            .resolve_multi_value_blocktypes(|result_types| {
+                type_section.insert(FuncType::new(Box::new([]), result_types))
+            });
         let (_functype, bytes) = code_builder.build();
         self.code_section.content[self.func_section.plain_index_without_offset(funcidx) as usize]
             .func = Some(bytes);
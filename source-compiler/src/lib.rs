@@ -77,6 +77,33 @@ impl projstd::log::Logger for MainLogger {
  */
 #[wasm_bindgen]
 pub async fn compile(context: i32, source_code: String) -> js_sys::Uint8Array {
+    compile_with_opt_level(context, source_code, "-O2".to_owned()).await
+}
+
+/**
+ * Like compile(), but with the optimisation pipeline given by `opt_level` (one of "-O0", "-O1" or "-O2").
+ * Statistics about each optimisation pass are logged at Info severity.
+ */
+#[wasm_bindgen]
+pub async fn compile_with_opt_level(
+    context: i32,
+    source_code: String,
+    opt_level: String,
+) -> js_sys::Uint8Array {
+    compile_with_options(context, source_code, opt_level, String::new()).await
+}
+
+/**
+ * Like compile_with_opt_level(), but also with the backend options given by `backend_flags`.
+ * `backend_flags` is a whitespace-separated list of flags (e.g. "--heap-manager=marksweep --heap-max-pages=1024"), see backend_wasm::OptionsBuilder::with_flag().
+ */
+#[wasm_bindgen]
+pub async fn compile_with_options(
+    context: i32,
+    source_code: String,
+    opt_level: String,
+    backend_flags: String,
+) -> js_sys::Uint8Array {
     // nice console errors in debug mode
     #[cfg(all(debug_assertions, target_arch = "wasm32"))]
     console_error_panic_hook::set_once();
@@ -84,6 +111,30 @@ pub async fn compile(context: i32, source_code: String) -> js_sys::Uint8Array {
     (|| async {
         use wasmgen::WasmSerialize;
 
+        let mut pass_manager = ir::opt::PassManager::from_name(&opt_level).ok_or_else(|| {
+            projstd::log::Logger::log(
+                &MainLogger::new(context),
+                projstd::log::CompileMessage::new_error(
+                    projstd::log::SourceLocation::default(),
+                    format!("Unknown optimisation level \"{}\"", opt_level),
+                ),
+            )
+        })?;
+        let backend_options = backend_flags
+            .split_whitespace()
+            .try_fold(backend_wasm::Options::new_builder(), |builder, flag| {
+                builder.with_flag(flag)
+            })
+            .and_then(|builder| builder.build())
+            .map_err(|e| {
+                projstd::log::Logger::log(
+                    &MainLogger::new(context),
+                    projstd::log::CompileMessage::new_error(
+                        projstd::log::SourceLocation::default(),
+                        format!("Invalid backend options: {}", e),
+                    ),
+                )
+            })?;
         //let ir_imports = frontend_estree::parse_imports(import_spec, MainLogger::new(context))?;
         let ir_program = frontend_estree::run_frontend(
             source_code,
@@ -91,9 +142,9 @@ pub async fn compile(context: i32, source_code: String) -> js_sys::Uint8Array {
             MainLogger::new(context),
         )
         .await?;
-        let ir_program_opt = ir::opt::optimize_all(ir_program);
-        let wasm_module =
-            backend_wasm::run_backend(&ir_program_opt, backend_wasm::Options::default());
+        let ir_program_opt = pass_manager.run(ir_program);
+        pass_manager.log_stats(&MainLogger::new(context));
+        let wasm_module = backend_wasm::run_backend(&ir_program_opt, backend_options);
         let mut receiver = std::vec::Vec::<u8>::new();
         wasm_module.wasm_serialize(&mut receiver);
         Ok(js_sys::Uint8Array::from(receiver.as_slice()))
@@ -65,7 +65,21 @@ fn main() {
         let _ = io::stdin().read(&mut [0u8]).unwrap();
     }
 
-    let _: () = futures::executor::block_on((|| async {
+    // optimisation level, e.g. "-O0", "-O1" or "-O2"
+    let opt_level = std::env::args().nth(1).unwrap_or_else(|| "-O2".to_owned());
+    let mut pass_manager = ir::opt::PassManager::from_name(&opt_level)
+        .unwrap_or_else(|| panic!("Unknown optimisation level \"{}\"", opt_level));
+
+    // backend options, e.g. "--heap-manager=marksweep" (see backend_wasm::OptionsBuilder::with_flag())
+    let backend_options = std::env::args()
+        .skip(2)
+        .try_fold(backend_wasm::Options::new_builder(), |builder, flag| {
+            builder.with_flag(&flag)
+        })
+        .and_then(|builder| builder.build())
+        .unwrap_or_else(|e| panic!("Invalid backend options: {}", e));
+
+    let _: () = futures::executor::block_on((move || async move {
         use wasmgen::WasmSerialize;
 
         //let ir_imports = frontend_estree::parse_imports(import_spec, MainLogger::new(context))?;
@@ -75,19 +89,19 @@ fn main() {
         {
             use std::io::prelude::*;
             let mut file = std::fs::File::create("out-noop.ir").unwrap();
-            file.write_all(format!("{:#?}", &ir_program).as_bytes())
+            file.write_all(format!("{}", &ir_program).as_bytes())
                 .unwrap();
         }
-        let ir_program_opt = ir::opt::optimize_all(ir_program);
-        println!("{:#?}", &ir_program_opt);
+        let ir_program_opt = pass_manager.run(ir_program);
+        pass_manager.log_stats(&MainLogger {});
+        println!("{}", &ir_program_opt);
         {
             use std::io::prelude::*;
             let mut file = std::fs::File::create("out.ir").unwrap();
-            file.write_all(format!("{:#?}", &ir_program_opt).as_bytes())
+            file.write_all(format!("{}", &ir_program_opt).as_bytes())
                 .unwrap();
         }
-        let wasm_module =
-            backend_wasm::run_backend(&ir_program_opt, backend_wasm::Options::default());
+        let wasm_module = backend_wasm::run_backend(&ir_program_opt, backend_options);
         let mut receiver = std::vec::Vec::<u8>::new();
         wasm_module.wasm_serialize(&mut receiver);
         {
@@ -146,10 +146,13 @@ export async function compile(
     });
 }
 
-function read_js_result(linear_memory: WebAssembly.Memory): any {
+function read_js_result(
+  linear_memory: WebAssembly.Memory,
+  result_ptr: number
+): any {
   const mem = new DataView(linear_memory.buffer);
-  const tag = mem.getUint32((1 << 20) - 12, true);
-  const data_offset = (1 << 20) - 8;
+  const tag = mem.getUint32(result_ptr, true);
+  const data_offset = result_ptr + 4;
   switch (tag) {
     case 0:
       return "(unassigned variable was returned)";
@@ -170,6 +173,10 @@ function read_js_result(linear_memory: WebAssembly.Memory): any {
     }
     case 5:
       return "(function was returned)";
+    case 6:
+      return null;
+    case 7:
+      return "(array was returned)";
     default:
       return "(struct or invalid type (" + tag + ") was returned)";
   }
@@ -184,6 +191,11 @@ function stringifySourcerorRuntimeErrorCode(code: number): [string, string] {
         "Out of memory",
         "Strings and objects are allocated on the heap.  You have exhausted the available heap space.  Try recompiling your program with increased heap space.",
       ];
+    case 0x2:
+      return [
+        "Heap verification failed",
+        "The garbage collector left the heap in an invalid state.  This is a bug in Sourceror; please report it.",
+      ];
     case 0x10:
       return ["General runtime type error", ""];
     case 0x11:
@@ -196,8 +208,16 @@ function stringifySourcerorRuntimeErrorCode(code: number): [string, string] {
       return ["Function call operator applied on a non-function", ""];
     case 0x17:
       return ["If statement has a non-boolean condition", ""];
+    case 0x18:
+      return ["Loop has a non-boolean condition", ""];
     case 0x1A:
       return ["Variable used before initialization", ""];
+    case 0x1B:
+      return ["head() applied to a non-pair", ""];
+    case 0x1C:
+      return ["tail() applied to a non-pair", ""];
+    case 0x1D:
+      return ["Array index is not a valid non-negative integer", ""];
     default:
       return [
         "Unknown runtime error",
@@ -206,6 +226,30 @@ function stringifySourcerorRuntimeErrorCode(code: number): [string, string] {
   }
 }
 
+function formatBytes(num_bytes: number): string {
+  const units = ["bytes", "KiB", "MiB", "GiB"];
+  let unit = 0;
+  while (num_bytes >= 1024 && unit + 1 < units.length) {
+    num_bytes /= 1024;
+    ++unit;
+  }
+  return (unit === 0 ? num_bytes.toString() : num_bytes.toFixed(1)) + " " + units[unit];
+}
+
+// Describes the gc stats struct that the exported gc_stats function fills in
+// (layout: [collections(u32), peak_heap_pages(u32), bytes_allocated(u64), bytes_surviving(u64)])
+function describeGcStats(exports: any): string {
+  const ptr: number = exports.gc_stats();
+  const mem = new DataView((exports.linear_memory as WebAssembly.Memory).buffer);
+  const read_u64 = (offset: number): number =>
+    mem.getUint32(ptr + offset, true) + mem.getUint32(ptr + offset + 4, true) * 4294967296;
+  const collections = mem.getUint32(ptr, true);
+  const peak_heap_pages = mem.getUint32(ptr + 4, true);
+  return "Your program allocated " + formatBytes(read_u64(8)) + " across " + collections +
+    " garbage collections, and " + formatBytes(read_u64(16)) + " was still in use after the last one.  The heap grew to " +
+    formatBytes(peak_heap_pages * 65536) + ".";
+}
+
 // Just a unique identifier used for throwing exceptions while running the webassembly code
 const propagationToken = {};
 
@@ -216,6 +260,7 @@ export async function run(
   context: Context,
 ): Promise<any> {
   const real_imports = Object.assign({}, platform);
+  let instance_exports: any = undefined; // so that the error handler can read the gc stats
   real_imports.core = {
     error: (
       code: number,
@@ -226,7 +271,10 @@ export async function run(
       end_line: number,
       end_column: number,
     ) => {
-      const [explain, elaborate] = stringifySourcerorRuntimeErrorCode(code);
+      let [explain, elaborate] = stringifySourcerorRuntimeErrorCode(code);
+      if (code === 0x1 && instance_exports !== undefined) {
+        elaborate = describeGcStats(instance_exports) + "  " + elaborate;
+      }
       context.errors.push({
         type: ErrorType.RUNTIME,
         severity: ErrorSeverity.ERROR,
@@ -268,12 +316,14 @@ export async function run(
     },
   };
   return WebAssembly.instantiate(wasm_module, real_imports).then((instance) => {
+    instance_exports = instance.exports;
     transcoder.setMem(new DataView((instance.exports.linear_memory as WebAssembly.Memory).buffer));
     transcoder.setAllocateStringFunc(instance.exports.allocate_string as (len: number) => number);
     try {
       (instance.exports.main as Function)();
       return read_js_result(
-        instance.exports.linear_memory as WebAssembly.Memory
+        instance.exports.linear_memory as WebAssembly.Memory,
+        (instance.exports.result_ptr as Function)()
       );
     } catch (e) {
       if (e === propagationToken) {
@@ -6,8 +6,9 @@ function init(module_bytecode, imports) {
         Object.assign(ret, instance.exports);
         ret.__read_js_result = (wasm_result) => {
             const mem = new DataView(ret.linear_memory.buffer);
-            const tag = mem.getUint32((1 << 20) - 12, true);
-            const data_offset = ((1 << 20) - 8);
+            const result_ptr = ret.result_ptr();
+            const tag = mem.getUint32(result_ptr, true);
+            const data_offset = result_ptr + 4;
             switch (tag) {
                 case 0:
                     return "(unassigned variable was returned)";